@@ -1,10 +1,10 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
 use daphne::{
-    hpke::HpkeKemId, testing::AggregationJobTest, DapLeaderTransition, DapMeasurement, DapVersion,
-    Prio3Config, VdafConfig,
+    hpke::HpkeKemId, testing::AggregationJobTest, DapHelperTransition, DapLeaderTransition,
+    DapMeasurement, DapVersion, Prio3Config, VdafConfig,
 };
 
 fn handle_agg_job_init_req(c: &mut Criterion) {
@@ -54,5 +54,69 @@ fn handle_agg_job_init_req(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, handle_agg_job_init_req);
+fn merge_agg_share(c: &mut Criterion) {
+    let batch_size = 10;
+    let dimension = 1000;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    for (vdaf, measurement) in [
+        (
+            VdafConfig::Prio3(Prio3Config::Count),
+            DapMeasurement::U64(1),
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 1,
+                length: dimension,
+                chunk_length: 32,
+            }),
+            DapMeasurement::U128Vec(vec![1; dimension]),
+        ),
+        (
+            VdafConfig::Prio2 { dimension },
+            DapMeasurement::U32Vec(vec![1; dimension]),
+        ),
+    ] {
+        let agg_job_test =
+            AggregationJobTest::new(&vdaf, HpkeKemId::X25519HkdfSha256, DapVersion::Draft07);
+
+        // Compute the Helper's aggregate share for a batch of reports.
+        let agg_share = rt.block_on(async {
+            let reports = agg_job_test.produce_reports(vec![measurement; batch_size]);
+            let DapLeaderTransition::Continue(leader_state, agg_job_init_req) =
+                agg_job_test.produce_agg_job_init_req(reports).await
+            else {
+                panic!("unexpected transition");
+            };
+            let DapHelperTransition::Continue(helper_state, agg_job_resp) = agg_job_test
+                .handle_agg_job_init_req(&agg_job_init_req)
+                .await
+            else {
+                panic!("unexpected transition");
+            };
+            let DapLeaderTransition::Uncommitted(_leader_uncommitted, agg_job_cont_req) =
+                agg_job_test.handle_agg_job_resp(leader_state, agg_job_resp)
+            else {
+                panic!("unexpected transition");
+            };
+            let (helper_share_span, _agg_job_resp) =
+                agg_job_test.handle_agg_job_cont_req(&helper_state, &agg_job_cont_req);
+            helper_share_span.collapsed()
+        });
+
+        c.bench_function(&format!("merge_agg_share {vdaf}"), |b| {
+            b.iter_batched(
+                || (agg_share.clone(), agg_share.clone()),
+                |(mut left, right)| left.merge(right).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+criterion_group!(benches, handle_agg_job_init_req, merge_agg_share);
 criterion_main!(benches);
@@ -0,0 +1,84 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use daphne::{
+    messages::{
+        AggregationJobInitReq, AggregationJobInitReqRef, HpkeCiphertext, PartialBatchSelector,
+        ReportId, ReportMetadata, ReportShare,
+    },
+    DapVersion,
+};
+use prio::codec::{ParameterizedDecode, ParameterizedEncode};
+use rand::prelude::*;
+
+/// Produce a request with the given number of report shares. The sizes of the public and input
+/// shares are roughly those of Prio3Count.
+fn agg_job_init_req(report_count: usize) -> AggregationJobInitReq {
+    let mut rng = thread_rng();
+    AggregationJobInitReq {
+        draft02_task_id: None,
+        draft02_agg_job_id: None,
+        agg_param: Vec::new(),
+        part_batch_sel: PartialBatchSelector::TimeInterval,
+        report_shares: (0..report_count)
+            .map(|_| ReportShare {
+                report_metadata: ReportMetadata {
+                    id: ReportId(rng.gen()),
+                    time: rng.gen(),
+                    extensions: Vec::new(),
+                },
+                public_share: vec![0; 32],
+                encrypted_input_share: HpkeCiphertext {
+                    config_id: rng.gen(),
+                    enc: vec![0; 32],
+                    payload: vec![0; 128],
+                },
+            })
+            .collect(),
+        extensions: Vec::new(),
+    }
+}
+
+fn agg_job_init_req_codec(c: &mut Criterion) {
+    let version = DapVersion::Draft07;
+    let mut group = c.benchmark_group("agg_job_init_req");
+
+    for report_count in [1_000, 10_000, 100_000] {
+        let req = agg_job_init_req(report_count);
+        let encoded = req.get_encoded_with_param(&version);
+        group.throughput(Throughput::Elements(report_count as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", report_count), &req, |b, req| {
+            b.iter(|| black_box(req.get_encoded_with_param(&version)))
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("decode", report_count),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| {
+                    black_box(
+                        AggregationJobInitReq::get_decoded_with_param(&version, encoded).unwrap(),
+                    )
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("decode_borrowed", report_count),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| {
+                    black_box(
+                        AggregationJobInitReqRef::get_decoded_borrowed(&version, encoded).unwrap(),
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, agg_job_init_req_codec);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use daphne::{
+    hpke::HpkeKemId, testing::AggregationJobTest, DapMeasurement, DapVersion, Prio3Config,
+    VdafConfig,
+};
+
+fn initialize_report(c: &mut Criterion) {
+    let dimension = 1000;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    for (vdaf, measurement) in [
+        (
+            VdafConfig::Prio3(Prio3Config::Count),
+            DapMeasurement::U64(1),
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::Sum { bits: 32 }),
+            DapMeasurement::U64(1337),
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 100,
+                chunk_length: 10,
+            }),
+            DapMeasurement::U64(42),
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 1,
+                length: dimension,
+                chunk_length: 32,
+            }),
+            DapMeasurement::U128Vec(vec![1; dimension]),
+        ),
+        (
+            VdafConfig::Prio2 { dimension },
+            DapMeasurement::U32Vec(vec![1; dimension]),
+        ),
+    ] {
+        let agg_job_test =
+            AggregationJobTest::new(&vdaf, HpkeKemId::X25519HkdfSha256, DapVersion::Draft07);
+        let reports = agg_job_test.produce_reports(vec![measurement]);
+
+        for is_leader in [true, false] {
+            let consumed_report = rt
+                .block_on(agg_job_test.consume_reports(is_leader, reports.clone()))
+                .pop()
+                .unwrap();
+            let role = if is_leader { "leader" } else { "helper" };
+
+            c.bench_function(&format!("initialize_report {vdaf} {role}"), |b| {
+                b.iter_batched(
+                    || consumed_report.clone(),
+                    |consumed_report| agg_job_test.initialize_report(is_leader, consumed_report),
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+}
+
+criterion_group!(benches, initialize_report);
+criterion_main!(benches);
@@ -1,13 +1,33 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use crate::{messages::TaskId, DapTaskConfig};
+//! Audit logging. The Aggregator reports events to an [`AuditLog`] as it processes requests.
+//! Recording an event must not block request handling, so a log that writes events somewhere
+//! expensive should buffer them, e.g., by wrapping an [`AuditLogSink`] in a [`BatchedAuditLog`].
+//! The host is expected to call [`AuditLog::flush_if_due`] once it is done handling a request.
 
+use crate::{
+    fatal_error,
+    messages::{TaskId, Time},
+    DapError, DapTaskConfig,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AggregationJobAuditAction {
     Init,
     Continue,
 }
 
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 pub trait AuditLog {
     fn on_aggregation_job(
         &self,
@@ -17,6 +37,10 @@ pub trait AuditLog {
         report_count: u64,
         action: AggregationJobAuditAction,
     );
+
+    /// Write out buffered events if enough of them have accumulated or if enough time has passed
+    /// since they were last written. The default implementation does nothing.
+    async fn flush_if_due(&self, _now: Time) {}
 }
 
 /// Default implementation of the trait, which is a no-op.
@@ -33,3 +57,255 @@ impl AuditLog for NoopAuditLog {
     ) {
     }
 }
+
+/// An event recorded by a [`BatchedAuditLog`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    pub host: String,
+    /// The task ID, base64url-encoded.
+    pub task_id: String,
+    pub vdaf: String,
+    pub report_count: u64,
+    pub action: AggregationJobAuditAction,
+}
+
+impl AuditEvent {
+    /// Encode a sequence of events as JSON lines, i.e., one JSON object per line.
+    pub fn to_json_lines(events: &[Self]) -> Result<String, DapError> {
+        let mut out = String::new();
+        for event in events {
+            out.push_str(&serde_json::to_string(event).map_err(|e| fatal_error!(err = ?e))?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+/// Destination for the events buffered by a [`BatchedAuditLog`].
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait AuditLogSink {
+    /// Write a batch of events. If this fails, then the events are dropped.
+    async fn write_batch(&self, events: &[AuditEvent]) -> Result<(), DapError>;
+}
+
+/// Parameters for a [`BatchedAuditLog`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditLogBatchConfig {
+    /// Maximum number of events to buffer. Events recorded while the buffer is full are dropped.
+    pub capacity: usize,
+
+    /// Flush once this many events are buffered.
+    pub max_batch_size: usize,
+
+    /// Flush once this many seconds have passed since the last flush.
+    pub flush_interval: u64,
+}
+
+impl Default for AuditLogBatchConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            max_batch_size: 64,
+            flush_interval: 60,
+        }
+    }
+}
+
+struct AuditLogBuffer {
+    events: Vec<AuditEvent>,
+    last_flush: Option<Time>,
+}
+
+/// An [`AuditLog`] that buffers events in memory and writes them to a sink in batches. Recording
+/// an event never waits on the sink.
+pub struct BatchedAuditLog<S> {
+    sink: S,
+    config: AuditLogBatchConfig,
+    buffer: Mutex<AuditLogBuffer>,
+    dropped: AtomicU64,
+}
+
+impl<S: AuditLogSink> BatchedAuditLog<S> {
+    pub fn new(sink: S, config: AuditLogBatchConfig) -> Self {
+        Self {
+            sink,
+            buffer: Mutex::new(AuditLogBuffer {
+                events: Vec::with_capacity(config.max_batch_size.min(config.capacity)),
+                last_flush: None,
+            }),
+            config,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of events dropped so far, either because the buffer was full or because the sink
+    /// failed to write them.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of events currently buffered.
+    pub fn buffered(&self) -> usize {
+        self.buffer.lock().unwrap().events.len()
+    }
+
+    /// Record an event, dropping it if the buffer is full.
+    pub fn enqueue(&self, event: AuditEvent) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.events.len() >= self.config.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        buffer.events.push(event);
+    }
+
+    /// Write out all buffered events, regardless of whether a flush is due.
+    pub async fn flush(&self, now: Time) {
+        let events = {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.last_flush = Some(now);
+            std::mem::take(&mut buffer.events)
+        };
+        self.write(events).await;
+    }
+
+    async fn write(&self, events: Vec<AuditEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        if let Err(e) = self.sink.write_batch(&events).await {
+            warn!(error = ?e, dropped = events.len(), "failed to write audit log events");
+            self.dropped
+                .fetch_add(events.len().try_into().unwrap(), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+impl<S: AuditLogSink + Send + Sync> AuditLog for BatchedAuditLog<S> {
+    fn on_aggregation_job(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        report_count: u64,
+        action: AggregationJobAuditAction,
+    ) {
+        self.enqueue(AuditEvent {
+            host: host.into(),
+            task_id: task_id.to_base64url(),
+            vdaf: task_config.vdaf.to_string(),
+            report_count,
+            action,
+        });
+    }
+
+    async fn flush_if_due(&self, now: Time) {
+        let events = {
+            let mut buffer = self.buffer.lock().unwrap();
+            let last_flush = *buffer.last_flush.get_or_insert(now);
+            let due = buffer.events.len() >= self.config.max_batch_size
+                || now.saturating_sub(last_flush) >= self.config.flush_interval;
+            if !due {
+                return;
+            }
+            buffer.last_flush = Some(now);
+            std::mem::take(&mut buffer.events)
+        };
+        self.write(events).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hpke::HpkeKemId, messages::TaskId, testing::AggregationJobTest, DapVersion, Prio3Config,
+        VdafConfig,
+    };
+    use std::sync::atomic::AtomicBool;
+
+    #[derive(Default)]
+    struct MockSink {
+        batches: Mutex<Vec<Vec<AuditEvent>>>,
+        fail: AtomicBool,
+    }
+
+    #[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+    #[cfg_attr(feature = "send-traits", async_trait)]
+    impl AuditLogSink for MockSink {
+        async fn write_batch(&self, events: &[AuditEvent]) -> Result<(), DapError> {
+            if self.fail.load(Ordering::Relaxed) {
+                return Err(fatal_error!(err = "sink unavailable"));
+            }
+            self.batches.lock().unwrap().push(events.to_vec());
+            Ok(())
+        }
+    }
+
+    fn record(log: &BatchedAuditLog<MockSink>, task_config: &DapTaskConfig, n: usize) {
+        for _ in 0..n {
+            log.on_aggregation_job(
+                "helper.example.com",
+                &TaskId([1; 32]),
+                task_config,
+                10,
+                AggregationJobAuditAction::Init,
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_audit_log() {
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            DapVersion::Draft07,
+        );
+        let log = BatchedAuditLog::new(
+            MockSink::default(),
+            AuditLogBatchConfig {
+                capacity: 4,
+                max_batch_size: 2,
+                flush_interval: 60,
+            },
+        );
+
+        // Nothing is written until the batch is full.
+        record(&log, &t.task_config, 1);
+        log.flush_if_due(1000).await;
+        assert!(log.sink.batches.lock().unwrap().is_empty());
+        record(&log, &t.task_config, 1);
+        log.flush_if_due(1001).await;
+        assert_eq!(log.sink.batches.lock().unwrap().len(), 1);
+        assert_eq!(log.sink.batches.lock().unwrap()[0].len(), 2);
+
+        // ... or until the flush interval has elapsed.
+        record(&log, &t.task_config, 1);
+        log.flush_if_due(1030).await;
+        assert_eq!(log.buffered(), 1);
+        log.flush_if_due(1061).await;
+        assert_eq!(log.buffered(), 0);
+        assert_eq!(log.sink.batches.lock().unwrap().len(), 2);
+
+        // Events are dropped when the buffer is full.
+        record(&log, &t.task_config, 5);
+        assert_eq!(log.buffered(), 4);
+        assert_eq!(log.dropped(), 1);
+
+        // ... or when the sink fails.
+        log.sink.fail.store(true, Ordering::Relaxed);
+        log.flush(1062).await;
+        assert_eq!(log.buffered(), 0);
+        assert_eq!(log.dropped(), 5);
+
+        let lines = AuditEvent::to_json_lines(&log.sink.batches.lock().unwrap()[0]).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        let event: serde_json::Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(event["action"], "init");
+        assert_eq!(event["report_count"], 10);
+    }
+}
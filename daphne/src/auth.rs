@@ -7,7 +7,7 @@ use crate::{
     constants::DapMediaType,
     fatal_error,
     messages::{constant_time_eq, TaskId},
-    DapError, DapRequest, DapSender, DapTaskConfig,
+    DapError, DapRequest, DapSender, DapTaskConfig, MaybeSendSync,
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
@@ -25,8 +25,11 @@ impl AsRef<str> for BearerToken {
     }
 }
 
-impl PartialEq for BearerToken {
-    fn eq(&self, other: &Self) -> bool {
+// NOTE `PartialEq` is deliberately not implemented, so that tokens can't be compared with `==`
+// by mistake. Use `ct_eq()` instead.
+impl BearerToken {
+    /// Compare two bearer tokens in constant time.
+    pub fn ct_eq(&self, other: &Self) -> bool {
         constant_time_eq(self.raw.as_bytes(), other.raw.as_bytes())
     }
 }
@@ -52,10 +55,11 @@ impl AsRef<BearerToken> for BearerToken {
 }
 
 /// A source of bearer tokens used for authorizing DAP requests.
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 pub trait BearerTokenProvider {
     /// A reference to a bearer token owned by the provider.
-    type WrappedBearerToken<'a>: AsRef<BearerToken>
+    type WrappedBearerToken<'a>: AsRef<BearerToken> + MaybeSendSync
     where
         Self: 'a;
 
@@ -101,7 +105,7 @@ pub trait BearerTokenProvider {
     ///
     /// Return `None` if the request is authorized. Otherwise return `Some(reason)`, where `reason`
     /// is the reason for the failure.
-    async fn bearer_token_authorized<T: AsRef<BearerToken>>(
+    async fn bearer_token_authorized<T: AsRef<BearerToken> + MaybeSendSync>(
         &self,
         task_config: &DapTaskConfig,
         req: &DapRequest<T>,
@@ -118,13 +122,13 @@ pub trait BearerTokenProvider {
         // following RFC 6750, Section 2.1. Note that we would also need to replace `From<String>
         // for BearerToken` with `TryFrom<String>` so that a `DapError` can be returned if the
         // token is not formatted properly.
-        if matches!(req.media_type.sender(), Some(DapSender::Leader)) {
+        if matches!(req.sender(), Some(DapSender::Leader)) {
             if let Some(ref got) = req.sender_auth {
                 if let Some(expected) = self
                     .get_leader_bearer_token_for(task_id, task_config)
                     .await?
                 {
-                    return Ok(if got.as_ref() == expected.as_ref() {
+                    return Ok(if got.as_ref().ct_eq(expected.as_ref()) {
                         None
                     } else {
                         Some("The indicated bearer token is incorrect for the Leader.".into())
@@ -133,13 +137,13 @@ pub trait BearerTokenProvider {
             }
         }
 
-        if matches!(req.media_type.sender(), Some(DapSender::Collector)) {
+        if matches!(req.sender(), Some(DapSender::Collector)) {
             if let Some(ref got) = req.sender_auth {
                 if let Some(expected) = self
                     .get_collector_bearer_token_for(task_id, task_config)
                     .await?
                 {
-                    return Ok(if got.as_ref() == expected.as_ref() {
+                    return Ok(if got.as_ref().ct_eq(expected.as_ref()) {
                         None
                     } else {
                         Some("The indicated bearer token is incorrect for the Collector.".into())
@@ -155,3 +159,16 @@ pub trait BearerTokenProvider {
         )))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::BearerToken;
+
+    #[test]
+    fn bearer_token_ct_eq() {
+        let token = BearerToken::from("this is a bearer token!");
+        assert!(token.ct_eq(&BearerToken::from("this is a bearer token!")));
+        assert!(!token.ct_eq(&BearerToken::from("this is another bearer token!")));
+        assert!(!token.ct_eq(&BearerToken::from("this is a bearer token?")));
+    }
+}
@@ -0,0 +1,805 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! DAP Client role. This module is intended for applications that want to submit measurements to
+//! a DAP deployment without having to implement the protocol themselves.
+//!
+//! A [`DapClient`] is parameterized by a [`DapClientTransport`] that carries out the HTTP requests
+//! on its behalf. An implementation based on `reqwest` is provided by [`DapHttpClient`] when the
+//! "http-client" feature is enabled.
+
+use std::cell::RefCell;
+
+use async_trait::async_trait;
+use prio::codec::{Decode, ParameterizedEncode};
+use url::Url;
+
+use crate::{
+    constants::DapMediaType,
+    error::aborts::ProblemDetails,
+    extensions::DapClientAuthKey,
+    fatal_error,
+    hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
+    messages::{HpkeConfigList, Report, ReportId, TaskId, Time},
+    DapError, DapMeasurement, DapVersion, VdafConfig,
+};
+
+/// Response to an HTTP request issued by a [`DapClient`].
+#[derive(Debug)]
+pub struct DapClientResponse {
+    /// HTTP status code.
+    pub status: u16,
+
+    /// Response body.
+    pub payload: Vec<u8>,
+
+    /// Delay requested by the server in the Retry-After header, if any (see
+    /// [`parse_retry_after`]).
+    pub retry_after: Option<std::time::Duration>,
+}
+
+impl DapClientResponse {
+    fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Returns `true` if the request may succeed if tried again later.
+    fn is_retryable(&self) -> bool {
+        self.status == 429 || self.status >= 500
+    }
+}
+
+/// Parse the value of a Retry-After header. Only the delay-seconds form is recognized; an
+/// HTTP-date is ignored.
+pub fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .map(std::time::Duration::from_secs)
+}
+
+/// HTTP backend used by a [`DapClient`] to talk to the Aggregators.
+///
+/// An error returned by any of the request methods is interpreted as a failure of the transport
+/// (e.g., a dropped connection) and is considered retryable.
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait DapClientTransport {
+    /// Send a GET request to `url`.
+    async fn get(&self, url: Url) -> Result<DapClientResponse, DapError>;
+
+    /// Send a POST request to `url` with the given content type and body.
+    async fn post(
+        &self,
+        url: Url,
+        content_type: &'static str,
+        payload: Vec<u8>,
+    ) -> Result<DapClientResponse, DapError>;
+
+    /// Send a PUT request to `url` with the given content type and body.
+    async fn put(
+        &self,
+        url: Url,
+        content_type: &'static str,
+        payload: Vec<u8>,
+    ) -> Result<DapClientResponse, DapError>;
+
+    /// Wait for the given duration before retrying a request.
+    async fn sleep(&self, duration: std::time::Duration);
+}
+
+/// Task parameters needed by the Client.
+#[derive(Clone, Debug)]
+pub struct DapClientConfig {
+    /// DAP version used for the task.
+    pub version: DapVersion,
+
+    /// The task ID.
+    pub task_id: TaskId,
+
+    /// Base URL of the Leader.
+    pub leader_url: Url,
+
+    /// Base URL of the Helper.
+    pub helper_url: Url,
+
+    /// Report timestamps are truncated to a multiple of this value.
+    pub time_precision: u64,
+
+    /// The VDAF used to shard measurements.
+    pub vdaf: VdafConfig,
+
+    /// If set, then the Leader's HPKE config must have this ID.
+    pub leader_hpke_config_id: Option<u8>,
+
+    /// If set, then the Helper's HPKE config must have this ID.
+    pub helper_hpke_config_id: Option<u8>,
+
+    /// Number of seconds for which fetched HPKE configs are cached.
+    pub hpke_config_ttl: u64,
+
+    /// Maximum number of attempts made for a single request.
+    pub max_attempts: usize,
+
+    /// Delay before the first retry. The delay is doubled after each subsequent attempt.
+    pub retry_delay: std::time::Duration,
+
+    /// Upper bound on the delay before a retry, including a longer delay requested by the
+    /// Aggregator with Retry-After.
+    pub max_retry_delay: std::time::Duration,
+
+    /// If set, then each report is authenticated with this key. This is required by tasks that
+    /// are configured with a client authentication key.
+    pub client_auth_key: Option<DapClientAuthKey>,
+}
+
+impl DapClientConfig {
+    /// Create a new config with reasonable defaults for caching and retries.
+    pub fn new(
+        version: DapVersion,
+        task_id: TaskId,
+        leader_url: Url,
+        helper_url: Url,
+        time_precision: u64,
+        vdaf: VdafConfig,
+    ) -> Self {
+        Self {
+            version,
+            task_id,
+            leader_url,
+            helper_url,
+            time_precision,
+            vdaf,
+            leader_hpke_config_id: None,
+            helper_hpke_config_id: None,
+            hpke_config_ttl: 3600,
+            max_attempts: 3,
+            retry_delay: std::time::Duration::from_millis(500),
+            max_retry_delay: std::time::Duration::from_secs(60),
+            client_auth_key: None,
+        }
+    }
+}
+
+struct CachedHpkeConfigs {
+    leader: HpkeConfig,
+    helper: HpkeConfig,
+    expires_at: Time,
+}
+
+/// A DAP Client.
+pub struct DapClient<T> {
+    transport: T,
+    config: DapClientConfig,
+    hpke_configs: RefCell<Option<CachedHpkeConfigs>>,
+}
+
+impl<T: DapClientTransport> DapClient<T> {
+    pub fn new(transport: T, config: DapClientConfig) -> Self {
+        Self {
+            transport,
+            config,
+            hpke_configs: RefCell::new(None),
+        }
+    }
+
+    /// The task parameters used by this Client.
+    pub fn config(&self) -> &DapClientConfig {
+        &self.config
+    }
+
+    /// Round `time` down to the nearest multiple of the task's time precision.
+    pub fn truncate_time(&self, time: Time) -> Time {
+        if self.config.time_precision == 0 {
+            return time;
+        }
+        time - (time % self.config.time_precision)
+    }
+
+    /// Drop the cached HPKE configs, forcing them to be fetched on the next upload.
+    pub fn clear_hpke_configs(&self) {
+        self.hpke_configs.borrow_mut().take();
+    }
+
+    /// Return the Leader's and Helper's HPKE configs, fetching them if they are not cached or
+    /// the cached configs have expired.
+    pub async fn get_hpke_configs(&self, now: Time) -> Result<[HpkeConfig; 2], DapError> {
+        if let Some(ref cached) = *self.hpke_configs.borrow() {
+            if now < cached.expires_at {
+                return Ok([cached.leader.clone(), cached.helper.clone()]);
+            }
+        }
+
+        let leader = self
+            .fetch_hpke_config(&self.config.leader_url, self.config.leader_hpke_config_id)
+            .await?;
+        let helper = self
+            .fetch_hpke_config(&self.config.helper_url, self.config.helper_hpke_config_id)
+            .await?;
+        *self.hpke_configs.borrow_mut() = Some(CachedHpkeConfigs {
+            leader: leader.clone(),
+            helper: helper.clone(),
+            expires_at: now.saturating_add(self.config.hpke_config_ttl),
+        });
+        Ok([leader, helper])
+    }
+
+    /// Shard `measurement` into a report and upload it to the Leader. The report's timestamp is
+    /// `now` truncated to the task's time precision. Returns the ID of the uploaded report.
+    pub async fn upload(
+        &self,
+        measurement: DapMeasurement,
+        now: Time,
+    ) -> Result<ReportId, DapError> {
+        let hpke_configs = self.get_hpke_configs(now).await?;
+        let time = self.truncate_time(now);
+        let report = match &self.config.client_auth_key {
+            Some(client_auth_key) => self.config.vdaf.produce_report_with_client_auth(
+                &hpke_configs,
+                time,
+                &self.config.task_id,
+                measurement,
+                client_auth_key,
+                self.config.version,
+            )?,
+            None => self.config.vdaf.produce_report(
+                &hpke_configs,
+                time,
+                &self.config.task_id,
+                measurement,
+                self.config.version,
+            )?,
+        };
+        let report_id = report.report_metadata.id.clone();
+        self.upload_report(&report).await?;
+        Ok(report_id)
+    }
+
+    /// Upload a report to the Leader, retrying if the request fails for a transient reason.
+    pub async fn upload_report(&self, report: &Report) -> Result<(), DapError> {
+        let version = self.config.version;
+        let content_type = DapMediaType::Report
+            .as_str_for_version(version)
+            .ok_or_else(|| fatal_error!(err = "unsupported version", ?version))?;
+        let url = match version {
+            DapVersion::Draft02 => self.config.leader_url.join("upload"),
+            DapVersion::Draft07 => self.config.leader_url.join(&format!(
+                "tasks/{}/reports",
+                self.config.task_id.to_base64url()
+            )),
+            _ => return Err(fatal_error!(err = "unsupported version", ?version)),
+        }
+        .map_err(|e| fatal_error!(err = ?e, "failed to construct upload URL"))?;
+        let payload = report.get_encoded_with_param(&version);
+
+        let resp = self
+            .send_with_retry(|| async {
+                match version {
+                    DapVersion::Draft02 => {
+                        self.transport
+                            .post(url.clone(), content_type, payload.clone())
+                            .await
+                    }
+                    _ => {
+                        self.transport
+                            .put(url.clone(), content_type, payload.clone())
+                            .await
+                    }
+                }
+            })
+            .await?;
+
+        if resp.is_success() {
+            return Ok(());
+        }
+
+        // The report may have been rejected because the HPKE config it was encrypted under is
+        // outdated. Drop the cache so that fresh configs are fetched for the next report.
+        self.clear_hpke_configs();
+        match serde_json::from_slice::<ProblemDetails>(&resp.payload) {
+            Ok(problem_details) => Err(fatal_error!(
+                err = "Leader rejected the report",
+                status = resp.status,
+                problem_type = ?problem_details.typ,
+                detail = ?problem_details.detail,
+            )),
+            Err(..) => Err(fatal_error!(
+                err = "unexpected response to upload request",
+                status = resp.status,
+            )),
+        }
+    }
+
+    async fn fetch_hpke_config(
+        &self,
+        base_url: &Url,
+        pinned_id: Option<u8>,
+    ) -> Result<HpkeConfig, DapError> {
+        let mut url = base_url
+            .join("hpke_config")
+            .map_err(|e| fatal_error!(err = ?e, "failed to construct HPKE config URL"))?;
+        url.query_pairs_mut()
+            .append_pair("task_id", &self.config.task_id.to_base64url());
+
+        let resp = self
+            .send_with_retry(|| self.transport.get(url.clone()))
+            .await?;
+        if !resp.is_success() {
+            return Err(fatal_error!(
+                err = "unexpected response to HPKE config request",
+                status = resp.status,
+                %url,
+            ));
+        }
+
+        let hpke_configs = match self.config.version {
+            DapVersion::Draft02 => vec![HpkeConfig::get_decoded(&resp.payload)
+                .map_err(|e| fatal_error!(err = ?e, "failed to decode HPKE config"))?],
+            _ => {
+                HpkeConfigList::get_decoded(&resp.payload)
+                    .map_err(|e| fatal_error!(err = ?e, "failed to decode HPKE config list"))?
+                    .hpke_configs
+            }
+        };
+
+        hpke_configs
+            .into_iter()
+            .find(|hpke_config| match pinned_id {
+                Some(id) => hpke_config.id == id,
+                None => is_supported_hpke_config(hpke_config),
+            })
+            .ok_or_else(|| {
+                fatal_error!(
+                    err = "Aggregator did not advertise a usable HPKE config",
+                    ?pinned_id,
+                    %url,
+                )
+            })
+    }
+
+    /// Call `f` until it succeeds, returns a non-retryable response, or the maximum number of
+    /// attempts is reached. If a response asks for a longer delay than the current backoff with
+    /// Retry-After, then the requested delay is used instead, up to `max_retry_delay`.
+    async fn send_with_retry<F, Fut>(&self, f: F) -> Result<DapClientResponse, DapError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<DapClientResponse, DapError>>,
+    {
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let result = f().await;
+            let (retryable, retry_after) = match result {
+                Ok(ref resp) => (resp.is_retryable(), resp.retry_after),
+                Err(..) => (true, None),
+            };
+            if !retryable || attempt >= self.config.max_attempts {
+                return result;
+            }
+            let wait = retry_after.map_or(delay, |retry_after| retry_after.max(delay));
+            self.transport
+                .sleep(wait.min(self.config.max_retry_delay))
+                .await;
+            delay = delay.saturating_mul(2);
+            attempt += 1;
+        }
+    }
+}
+
+pub(crate) fn is_supported_hpke_config(hpke_config: &HpkeConfig) -> bool {
+    !matches!(hpke_config.kem_id, HpkeKemId::NotImplemented(..))
+        && !matches!(hpke_config.kdf_id, HpkeKdfId::NotImplemented(..))
+        && !matches!(hpke_config.aead_id, HpkeAeadId::NotImplemented(..))
+}
+
+#[cfg(feature = "http-client")]
+mod http {
+    use async_trait::async_trait;
+    use url::Url;
+
+    use super::{
+        parse_retry_after, DapClient, DapClientConfig, DapClientResponse, DapClientTransport,
+    };
+    use crate::{fatal_error, DapError};
+
+    /// [`DapClientTransport`] implemented with `reqwest`.
+    #[derive(Clone, Default)]
+    pub struct ReqwestTransport {
+        http_client: reqwest::Client,
+    }
+
+    impl ReqwestTransport {
+        pub fn new(http_client: reqwest::Client) -> Self {
+            Self { http_client }
+        }
+
+        async fn send(&self, req: reqwest::RequestBuilder) -> Result<DapClientResponse, DapError> {
+            let resp = req
+                .send()
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Network, "request failed"))?;
+            let status = resp.status().as_u16();
+            let retry_after = resp
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            let payload = resp
+                .bytes()
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Network, "failed to read response"))?
+                .to_vec();
+            Ok(DapClientResponse {
+                status,
+                payload,
+                retry_after,
+            })
+        }
+    }
+
+    #[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+    #[cfg_attr(feature = "send-traits", async_trait)]
+    impl DapClientTransport for ReqwestTransport {
+        async fn get(&self, url: Url) -> Result<DapClientResponse, DapError> {
+            self.send(self.http_client.get(url)).await
+        }
+
+        async fn post(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> Result<DapClientResponse, DapError> {
+            self.send(
+                self.http_client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, content_type)
+                    .body(payload),
+            )
+            .await
+        }
+
+        async fn put(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> Result<DapClientResponse, DapError> {
+            self.send(
+                self.http_client
+                    .put(url)
+                    .header(reqwest::header::CONTENT_TYPE, content_type)
+                    .body(payload),
+            )
+            .await
+        }
+
+        async fn sleep(&self, duration: std::time::Duration) {
+            tokio::time::sleep(duration).await;
+        }
+    }
+
+    /// A [`DapClient`] that uses `reqwest` for HTTP.
+    pub type DapHttpClient = DapClient<ReqwestTransport>;
+
+    impl DapHttpClient {
+        /// Create a Client for the given task using a default `reqwest` client.
+        pub fn new_http(config: DapClientConfig) -> Self {
+            Self::new(ReqwestTransport::default(), config)
+        }
+    }
+}
+
+#[cfg(feature = "http-client")]
+pub use http::{DapHttpClient, ReqwestTransport};
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use async_trait::async_trait;
+    use prio::codec::{Encode, ParameterizedDecode};
+    use rand::prelude::*;
+    use url::Url;
+
+    use super::{
+        parse_retry_after, DapClient, DapClientConfig, DapClientResponse, DapClientTransport,
+    };
+    use crate::{
+        async_test_versions,
+        constants::DapMediaType,
+        hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId, HpkeReceiverConfig},
+        messages::{HpkeConfigList, Report, TaskId},
+        DapError, DapMeasurement, DapVersion, Prio3Config, VdafConfig,
+    };
+
+    /// Serves HPKE configs and records uploaded reports. The first `fail_count` uploads fail with
+    /// a 503.
+    struct MockTransport {
+        version: DapVersion,
+        leader_hpke: HpkeReceiverConfig,
+        helper_hpke: HpkeReceiverConfig,
+        /// Configs the Leader advertises ahead of `leader_hpke` (draft07 and later only).
+        leader_hpke_advertised_first: Vec<HpkeConfig>,
+        hpke_config_reqs: AtomicUsize,
+        fail_count: AtomicUsize,
+        /// Retry-After sent with each failed upload.
+        fail_retry_after: Mutex<Option<std::time::Duration>>,
+        uploads: Mutex<Vec<(Url, &'static str, Vec<u8>)>>,
+        sleeps: Mutex<Vec<std::time::Duration>>,
+    }
+
+    impl MockTransport {
+        fn new(version: DapVersion) -> Self {
+            let mut rng = thread_rng();
+            Self {
+                version,
+                leader_hpke: HpkeReceiverConfig::gen(rng.gen(), HpkeKemId::X25519HkdfSha256)
+                    .unwrap(),
+                helper_hpke: HpkeReceiverConfig::gen(rng.gen(), HpkeKemId::P256HkdfSha256).unwrap(),
+                leader_hpke_advertised_first: Vec::new(),
+                hpke_config_reqs: AtomicUsize::new(0),
+                fail_count: AtomicUsize::new(0),
+                fail_retry_after: Mutex::new(None),
+                uploads: Mutex::new(Vec::new()),
+                sleeps: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn handle_upload(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> Result<DapClientResponse, DapError> {
+            if self.fail_count.load(Ordering::Relaxed) > 0 {
+                self.fail_count.fetch_sub(1, Ordering::Relaxed);
+                return Ok(DapClientResponse {
+                    status: 503,
+                    payload: Vec::new(),
+                    retry_after: *self.fail_retry_after.lock().unwrap(),
+                });
+            }
+            self.uploads
+                .lock()
+                .unwrap()
+                .push((url, content_type, payload));
+            Ok(DapClientResponse {
+                status: 200,
+                payload: Vec::new(),
+                retry_after: None,
+            })
+        }
+    }
+
+    #[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+    #[cfg_attr(feature = "send-traits", async_trait)]
+    impl DapClientTransport for MockTransport {
+        async fn get(&self, url: Url) -> Result<DapClientResponse, DapError> {
+            self.hpke_config_reqs.fetch_add(1, Ordering::Relaxed);
+            let (hpke_config, mut hpke_configs) = match url.host_str() {
+                Some("leader.com") => (
+                    self.leader_hpke.config.clone(),
+                    self.leader_hpke_advertised_first.clone(),
+                ),
+                Some("helper.org") => (self.helper_hpke.config.clone(), Vec::new()),
+                host => panic!("unexpected host {host:?}"),
+            };
+            let payload = match self.version {
+                DapVersion::Draft02 => hpke_config.get_encoded(),
+                _ => {
+                    hpke_configs.push(hpke_config);
+                    HpkeConfigList { hpke_configs }.get_encoded()
+                }
+            };
+            Ok(DapClientResponse {
+                status: 200,
+                payload,
+                retry_after: None,
+            })
+        }
+
+        async fn post(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> Result<DapClientResponse, DapError> {
+            assert_eq!(self.version, DapVersion::Draft02);
+            self.handle_upload(url, content_type, payload)
+        }
+
+        async fn put(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> Result<DapClientResponse, DapError> {
+            assert_ne!(self.version, DapVersion::Draft02);
+            self.handle_upload(url, content_type, payload)
+        }
+
+        async fn sleep(&self, duration: std::time::Duration) {
+            self.sleeps.lock().unwrap().push(duration);
+        }
+    }
+
+    fn client(version: DapVersion) -> DapClient<MockTransport> {
+        let mut config = DapClientConfig::new(
+            version,
+            TaskId(thread_rng().gen()),
+            Url::parse("https://leader.com/v02/").unwrap(),
+            Url::parse("https://helper.org/v02/").unwrap(),
+            3600,
+            VdafConfig::Prio3(Prio3Config::Count),
+        );
+        config.retry_delay = std::time::Duration::ZERO;
+        DapClient::new(MockTransport::new(version), config)
+    }
+
+    async fn upload(version: DapVersion) {
+        let client = client(version);
+        let now = 1_637_364_244;
+
+        let report_id = client.upload(DapMeasurement::U64(1), now).await.unwrap();
+
+        let uploads = client.transport.uploads.lock().unwrap();
+        assert_eq!(uploads.len(), 1);
+        let (url, content_type, payload) = &uploads[0];
+        assert_eq!(
+            *content_type,
+            DapMediaType::Report.as_str_for_version(version).unwrap()
+        );
+        if version == DapVersion::Draft02 {
+            assert_eq!(url.path(), "/v02/upload");
+        } else {
+            assert!(url.path().ends_with("/reports"));
+        }
+        let report = Report::get_decoded_with_param(&version, payload).unwrap();
+        assert_eq!(report.report_metadata.id, report_id);
+        assert_eq!(report.report_metadata.time, now - (now % 3600));
+        assert_eq!(
+            report.encrypted_input_shares[0].config_id,
+            client.transport.leader_hpke.config.id
+        );
+        assert_eq!(
+            report.encrypted_input_shares[1].config_id,
+            client.transport.helper_hpke.config.id
+        );
+    }
+
+    async_test_versions! { upload }
+
+    async fn hpke_configs_are_cached(version: DapVersion) {
+        let client = client(version);
+        let now = 1_637_364_244;
+
+        client.upload(DapMeasurement::U64(1), now).await.unwrap();
+        client
+            .upload(DapMeasurement::U64(0), now + 1)
+            .await
+            .unwrap();
+        assert_eq!(client.transport.hpke_config_reqs.load(Ordering::Relaxed), 2);
+
+        // Configs are fetched again once they expire.
+        client
+            .upload(DapMeasurement::U64(1), now + client.config.hpke_config_ttl)
+            .await
+            .unwrap();
+        assert_eq!(client.transport.hpke_config_reqs.load(Ordering::Relaxed), 4);
+    }
+
+    async_test_versions! { hpke_configs_are_cached }
+
+    async fn hpke_config_id_pinning(version: DapVersion) {
+        let mut client = client(version);
+        client.config.leader_hpke_config_id =
+            Some(client.transport.leader_hpke.config.id.wrapping_add(1));
+
+        assert!(client.get_hpke_configs(0).await.is_err());
+
+        client.config.leader_hpke_config_id = Some(client.transport.leader_hpke.config.id);
+        assert!(client.get_hpke_configs(0).await.is_ok());
+    }
+
+    async_test_versions! { hpke_config_id_pinning }
+
+    // Test that the Client skips HPKE configs with ciphersuites it doesn't implement.
+    async fn hpke_ciphersuite_negotiation(version: DapVersion) {
+        if version == DapVersion::Draft02 {
+            // Only one HPKE config is advertised.
+            return;
+        }
+
+        let mut client = client(version);
+        let mut rng = thread_rng();
+        client.transport.leader_hpke = HpkeReceiverConfig::gen_with_suite(
+            rng.gen(),
+            HpkeKemId::P256HkdfSha256,
+            HpkeKdfId::HkdfSha512,
+            HpkeAeadId::ChaCha20Poly1305,
+        )
+        .unwrap();
+        let unsupported = HpkeConfig {
+            id: client.transport.leader_hpke.config.id.wrapping_add(1),
+            kdf_id: HpkeKdfId::NotImplemented(0x0002), // HKDF-SHA384
+            ..client.transport.leader_hpke.config.clone()
+        };
+        client.transport.leader_hpke_advertised_first = vec![unsupported];
+
+        let [leader_hpke_config, _] = client.get_hpke_configs(0).await.unwrap();
+        assert_eq!(leader_hpke_config, client.transport.leader_hpke.config);
+
+        let now = 1_637_364_244;
+        client.upload(DapMeasurement::U64(1), now).await.unwrap();
+        let uploads = client.transport.uploads.lock().unwrap();
+        let report = Report::get_decoded_with_param(&version, &uploads[0].2).unwrap();
+        assert_eq!(
+            report.encrypted_input_shares[0].config_id,
+            client.transport.leader_hpke.config.id
+        );
+    }
+
+    async_test_versions! { hpke_ciphersuite_negotiation }
+
+    async fn upload_retries(version: DapVersion) {
+        let client = client(version);
+
+        // Succeeds on the last attempt.
+        client
+            .transport
+            .fail_count
+            .store(client.config.max_attempts - 1, Ordering::Relaxed);
+        client.upload(DapMeasurement::U64(1), 0).await.unwrap();
+        assert_eq!(client.transport.uploads.lock().unwrap().len(), 1);
+        assert_eq!(
+            client.transport.sleeps.lock().unwrap().len(),
+            client.config.max_attempts - 1
+        );
+
+        // Gives up after the maximum number of attempts.
+        client
+            .transport
+            .fail_count
+            .store(client.config.max_attempts, Ordering::Relaxed);
+        assert!(client.upload(DapMeasurement::U64(1), 0).await.is_err());
+        assert_eq!(client.transport.uploads.lock().unwrap().len(), 1);
+    }
+
+    async_test_versions! { upload_retries }
+
+    async fn upload_honors_retry_after(version: DapVersion) {
+        let client = client(version);
+        let retry_after = std::time::Duration::from_secs(10);
+        *client.transport.fail_retry_after.lock().unwrap() = Some(retry_after);
+        client.transport.fail_count.store(1, Ordering::Relaxed);
+        client.upload(DapMeasurement::U64(1), 0).await.unwrap();
+        assert_eq!(*client.transport.sleeps.lock().unwrap(), [retry_after]);
+
+        // The requested delay is capped.
+        client.transport.sleeps.lock().unwrap().clear();
+        *client.transport.fail_retry_after.lock().unwrap() =
+            Some(std::time::Duration::from_secs(3600));
+        client.transport.fail_count.store(1, Ordering::Relaxed);
+        client.upload(DapMeasurement::U64(1), 0).await.unwrap();
+        assert_eq!(
+            *client.transport.sleeps.lock().unwrap(),
+            [client.config.max_retry_delay]
+        );
+    }
+
+    async_test_versions! { upload_honors_retry_after }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(
+            parse_retry_after(" 120"),
+            Some(std::time::Duration::from_secs(120))
+        );
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
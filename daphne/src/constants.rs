@@ -3,7 +3,7 @@
 
 //! Constants used in the DAP protocol.
 
-use crate::{DapSender, DapVersion};
+use crate::{error::DapAbort, DapSender, DapVersion};
 
 // Media types for HTTP requests.
 const DRAFT02_MEDIA_TYPE_AGG_CONT_REQ: &str = "application/dap-aggregate-continue-req";
@@ -13,6 +13,7 @@ const DRAFT02_MEDIA_TYPE_AGG_INIT_RESP: &str = "application/dap-aggregate-initia
 const DRAFT02_MEDIA_TYPE_AGG_SHARE_RESP: &str = "application/dap-aggregate-share-resp";
 const DRAFT02_MEDIA_TYPE_COLLECT_RESP: &str = "application/dap-collect-resp";
 const DRAFT02_MEDIA_TYPE_HPKE_CONFIG: &str = "application/dap-hpke-config";
+const MEDIA_TYPE_AGG_JOB_ABANDON: &str = "application/dap-aggregation-job-abandon";
 const MEDIA_TYPE_AGG_JOB_CONT_REQ: &str = "application/dap-aggregation-job-continue-req";
 const MEDIA_TYPE_AGG_JOB_INIT_REQ: &str = "application/dap-aggregation-job-init-req";
 const MEDIA_TYPE_AGG_JOB_RESP: &str = "application/dap-aggregation-job-resp";
@@ -22,6 +23,9 @@ const MEDIA_TYPE_COLLECTION: &str = "application/dap-collection";
 const MEDIA_TYPE_COLLECT_REQ: &str = "application/dap-collect-req";
 const MEDIA_TYPE_HPKE_CONFIG_LIST: &str = "application/dap-hpke-config-list";
 const MEDIA_TYPE_REPORT: &str = "application/dap-report";
+const MEDIA_TYPE_REPORT_BATCH: &str = "application/dap-report-batch";
+const MEDIA_TYPE_REPORT_BATCH_RESP: &str = "application/dap-report-batch-resp";
+const MEDIA_TYPE_UPLOAD_RECEIPT: &str = "application/dap-upload-receipt";
 
 /// Media type for each DAP request. This is included in the "content-type" HTTP header.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
@@ -38,8 +42,16 @@ pub enum DapMediaType {
     Collection,
     HpkeConfigList,
     Report,
-    /// The content-type does not match a known media type.
-    Invalid(String),
+    /// Not part of DAP: a batch of reports uploaded in a single request. Only supported for
+    /// draft07 and later.
+    ReportBatch,
+    /// Not part of DAP: the per-report results of a bulk upload.
+    ReportBatchResp,
+    /// Not part of DAP: the signed receipt sent in response to an upload, if the task enables it.
+    UploadReceipt,
+    /// Not part of DAP: sent by the Leader to tell the Helper that it has given up on an
+    /// aggregation job, so that the Helper can delete its state for the job.
+    AggregationJobAbandon,
     /// No content-type header found.
     #[default]
     Missing,
@@ -52,92 +64,70 @@ impl DapMediaType {
         match self {
             Self::AggregationJobInitReq
             | Self::AggregationJobContinueReq
+            | Self::AggregationJobAbandon
             | Self::AggregateShareReq
             | Self::Collection
-            | Self::HpkeConfigList => Some(DapSender::Leader),
+            | Self::HpkeConfigList
+            | Self::ReportBatchResp
+            | Self::UploadReceipt => Some(DapSender::Leader),
             Self::AggregationJobResp
             | Self::Draft02AggregateContinueResp
             | Self::AggregateShare => Some(DapSender::Helper),
-            Self::Report => Some(DapSender::Client),
+            Self::Report | Self::ReportBatch => Some(DapSender::Client),
             Self::CollectReq => Some(DapSender::Collector),
-            Self::Invalid(..) | Self::Missing => None,
+            Self::Missing => None,
         }
     }
 
-    /// Parse the media type from the content-type HTTP header.
-    pub fn from_str_for_version(version: DapVersion, content_type: Option<&str>) -> Self {
-        match (version, content_type) {
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_CONT_REQ))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_AGG_JOB_CONT_REQ)) => {
-                Self::AggregationJobContinueReq
-            }
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_CONT_RESP)) => {
-                Self::Draft02AggregateContinueResp
-            }
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_INIT_REQ))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_AGG_JOB_INIT_REQ)) => {
-                Self::AggregationJobInitReq
-            }
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_INIT_RESP))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_AGG_JOB_RESP)) => Self::AggregationJobResp,
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_SHARE_RESP))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_AGG_SHARE)) => Self::AggregateShare,
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_COLLECT_RESP))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_COLLECTION)) => Self::Collection,
-            (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_HPKE_CONFIG))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_HPKE_CONFIG_LIST)) => Self::HpkeConfigList,
-            (DapVersion::Draft02, Some(MEDIA_TYPE_AGG_SHARE_REQ))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_AGG_SHARE_REQ)) => Self::AggregateShareReq,
-            (DapVersion::Draft02, Some(MEDIA_TYPE_COLLECT_REQ))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_COLLECT_REQ)) => Self::CollectReq,
-            (DapVersion::Draft02, Some(MEDIA_TYPE_REPORT))
-            | (DapVersion::Draft07, Some(MEDIA_TYPE_REPORT)) => Self::Report,
-            (_, Some(content_type)) => Self::Invalid(content_type.to_string()),
-            (_, None) => Self::Missing,
+    /// Parse the media type from the value of the content-type HTTP header.
+    ///
+    /// The header is parsed as specified in RFC 9110, Section 8.3: the type and subtype are
+    /// matched case-insensitively, and parameters (e.g., "charset") must be well-formed but are
+    /// otherwise ignored. The return value is [`Self::Missing`] if there is no header. It is an
+    /// error if the header is malformed or if the media type is not defined for the given version.
+    pub fn parse(version: DapVersion, content_type: Option<&str>) -> Result<Self, DapAbort> {
+        let Some(content_type) = content_type else {
+            return Ok(Self::Missing);
+        };
+
+        let essence = parse_essence(content_type).ok_or_else(|| {
+            DapAbort::BadRequest(format!("malformed content-type: {content_type}"))
+        })?;
+
+        if let Some((media_type, _)) = media_types_for_version(version)
+            .iter()
+            .find(|(_, s)| *s == essence)
+        {
+            return Ok(media_type.clone());
         }
-    }
 
-    /// Get the content-type representation of the media type.
-    pub fn as_str_for_version(&self, version: DapVersion) -> Option<&str> {
-        match (version, self) {
-            (DapVersion::Draft02, Self::AggregationJobInitReq) => {
-                Some(DRAFT02_MEDIA_TYPE_AGG_INIT_REQ)
-            }
-            (DapVersion::Draft07, Self::AggregationJobInitReq) => Some(MEDIA_TYPE_AGG_JOB_INIT_REQ),
-            (DapVersion::Draft02, Self::AggregationJobResp) => {
-                Some(DRAFT02_MEDIA_TYPE_AGG_INIT_RESP)
-            }
-            (DapVersion::Draft07, Self::AggregationJobResp) => Some(MEDIA_TYPE_AGG_JOB_RESP),
-            (DapVersion::Draft02, Self::AggregationJobContinueReq) => {
-                Some(DRAFT02_MEDIA_TYPE_AGG_CONT_REQ)
-            }
-            (DapVersion::Draft07, Self::AggregationJobContinueReq) => {
-                Some(MEDIA_TYPE_AGG_JOB_CONT_REQ)
-            }
-            (DapVersion::Draft02, Self::Draft02AggregateContinueResp) => {
-                Some(DRAFT02_MEDIA_TYPE_AGG_CONT_RESP)
-            }
-            (_, Self::Draft02AggregateContinueResp) => None,
-            (DapVersion::Draft02, Self::AggregateShareReq)
-            | (DapVersion::Draft07, Self::AggregateShareReq) => Some(MEDIA_TYPE_AGG_SHARE_REQ),
-            (DapVersion::Draft02, Self::AggregateShare) => Some(DRAFT02_MEDIA_TYPE_AGG_SHARE_RESP),
-            (DapVersion::Draft07, Self::AggregateShare) => Some(MEDIA_TYPE_AGG_SHARE),
-            (DapVersion::Draft02, Self::CollectReq) | (DapVersion::Draft07, Self::CollectReq) => {
-                Some(MEDIA_TYPE_COLLECT_REQ)
-            }
-            (DapVersion::Draft02, Self::Collection) => Some(DRAFT02_MEDIA_TYPE_COLLECT_RESP),
-            (DapVersion::Draft07, Self::Collection) => Some(MEDIA_TYPE_COLLECTION),
-            (DapVersion::Draft02, Self::HpkeConfigList) => Some(DRAFT02_MEDIA_TYPE_HPKE_CONFIG),
-            (DapVersion::Draft07, Self::HpkeConfigList) => Some(MEDIA_TYPE_HPKE_CONFIG_LIST),
-            (DapVersion::Draft02, Self::Report) | (DapVersion::Draft07, Self::Report) => {
-                Some(MEDIA_TYPE_REPORT)
-            }
-            (_, Self::Invalid(ref content_type)) => Some(content_type),
-            (_, Self::Missing) => None,
-            (DapVersion::Unknown, _) => unreachable!("unhandled version {version:?}"),
+        if [DapVersion::Draft02, DapVersion::Draft07]
+            .into_iter()
+            .any(|other| {
+                media_types_for_version(other)
+                    .iter()
+                    .any(|(_, s)| *s == essence)
+            })
+        {
+            Err(DapAbort::BadRequest(format!(
+                "content-type {essence} is not defined for {version:?}"
+            )))
+        } else {
+            Err(DapAbort::BadRequest(format!(
+                "unrecognized content-type: {content_type}"
+            )))
         }
     }
 
+    /// Get the content-type representation of the media type, or `None` if the media type is not
+    /// defined for the given version.
+    pub fn as_str_for_version(&self, version: DapVersion) -> Option<&'static str> {
+        media_types_for_version(version)
+            .iter()
+            .find(|(media_type, _)| media_type == self)
+            .map(|(_, s)| *s)
+    }
+
     /// draft02 compatibility: Construct the media type for the response to an
     /// AggregatecontinueResp. This various depending upon the version used.
     pub(crate) fn agg_job_cont_resp_for_version(version: DapVersion) -> Self {
@@ -149,145 +139,285 @@ impl DapMediaType {
     }
 }
 
+/// Media types defined for draft02.
+const DRAFT02_MEDIA_TYPES: &[(DapMediaType, &str)] = &[
+    (
+        DapMediaType::AggregationJobInitReq,
+        DRAFT02_MEDIA_TYPE_AGG_INIT_REQ,
+    ),
+    (
+        DapMediaType::AggregationJobResp,
+        DRAFT02_MEDIA_TYPE_AGG_INIT_RESP,
+    ),
+    (
+        DapMediaType::AggregationJobContinueReq,
+        DRAFT02_MEDIA_TYPE_AGG_CONT_REQ,
+    ),
+    (
+        DapMediaType::Draft02AggregateContinueResp,
+        DRAFT02_MEDIA_TYPE_AGG_CONT_RESP,
+    ),
+    (
+        DapMediaType::AggregationJobAbandon,
+        MEDIA_TYPE_AGG_JOB_ABANDON,
+    ),
+    (DapMediaType::AggregateShareReq, MEDIA_TYPE_AGG_SHARE_REQ),
+    (
+        DapMediaType::AggregateShare,
+        DRAFT02_MEDIA_TYPE_AGG_SHARE_RESP,
+    ),
+    (DapMediaType::CollectReq, MEDIA_TYPE_COLLECT_REQ),
+    (DapMediaType::Collection, DRAFT02_MEDIA_TYPE_COLLECT_RESP),
+    (DapMediaType::HpkeConfigList, DRAFT02_MEDIA_TYPE_HPKE_CONFIG),
+    (DapMediaType::Report, MEDIA_TYPE_REPORT),
+    (DapMediaType::UploadReceipt, MEDIA_TYPE_UPLOAD_RECEIPT),
+];
+
+/// Media types defined for draft07.
+const DRAFT07_MEDIA_TYPES: &[(DapMediaType, &str)] = &[
+    (
+        DapMediaType::AggregationJobInitReq,
+        MEDIA_TYPE_AGG_JOB_INIT_REQ,
+    ),
+    (DapMediaType::AggregationJobResp, MEDIA_TYPE_AGG_JOB_RESP),
+    (
+        DapMediaType::AggregationJobContinueReq,
+        MEDIA_TYPE_AGG_JOB_CONT_REQ,
+    ),
+    (
+        DapMediaType::AggregationJobAbandon,
+        MEDIA_TYPE_AGG_JOB_ABANDON,
+    ),
+    (DapMediaType::AggregateShareReq, MEDIA_TYPE_AGG_SHARE_REQ),
+    (DapMediaType::AggregateShare, MEDIA_TYPE_AGG_SHARE),
+    (DapMediaType::CollectReq, MEDIA_TYPE_COLLECT_REQ),
+    (DapMediaType::Collection, MEDIA_TYPE_COLLECTION),
+    (DapMediaType::HpkeConfigList, MEDIA_TYPE_HPKE_CONFIG_LIST),
+    (DapMediaType::Report, MEDIA_TYPE_REPORT),
+    (DapMediaType::ReportBatch, MEDIA_TYPE_REPORT_BATCH),
+    (DapMediaType::ReportBatchResp, MEDIA_TYPE_REPORT_BATCH_RESP),
+    (DapMediaType::UploadReceipt, MEDIA_TYPE_UPLOAD_RECEIPT),
+];
+
+fn media_types_for_version(version: DapVersion) -> &'static [(DapMediaType, &'static str)] {
+    match version {
+        DapVersion::Draft02 => DRAFT02_MEDIA_TYPES,
+        DapVersion::Draft07 => DRAFT07_MEDIA_TYPES,
+        DapVersion::Unknown => &[],
+    }
+}
+
+/// Optional whitespace (RFC 9110, Section 5.6.3).
+fn is_ows(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Characters allowed in a token (RFC 9110, Section 5.6.2).
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(is_tchar)
+}
+
+/// Skip a quoted-string (RFC 9110, Section 5.6.4), not including the opening quote. Returns the
+/// remainder of the input following the closing quote.
+fn skip_quoted_string(s: &str) -> Option<&str> {
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some(&s[i + 1..]),
+            '\\' => {
+                chars.next()?;
+            }
+            c if c.is_ascii_control() && c != '\t' => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Parse a content-type header value and return its lower-cased essence, i.e., "type/subtype".
+/// Returns `None` if the value is malformed.
+fn parse_essence(content_type: &str) -> Option<String> {
+    let (essence, mut params) = content_type.split_once(';').unwrap_or((content_type, ""));
+    let (typ, subtype) = essence.trim_matches(is_ows).split_once('/')?;
+    if !is_token(typ) || !is_token(subtype) {
+        return None;
+    }
+
+    loop {
+        params = params.trim_start_matches(is_ows);
+        if params.is_empty() {
+            break;
+        }
+
+        // Parameters may be empty, e.g., "application/dap-report;;charset=utf-8".
+        if let Some(rest) = params.strip_prefix(';') {
+            params = rest;
+            continue;
+        }
+
+        let (name, value) = params.split_once('=')?;
+        if !is_token(name) {
+            return None;
+        }
+        params = if let Some(quoted) = value.strip_prefix('"') {
+            skip_quoted_string(quoted)?
+        } else {
+            let end = value.find(|c| !is_tchar(c)).unwrap_or(value.len());
+            if end == 0 {
+                return None;
+            }
+            &value[end..]
+        };
+
+        params = params.trim_start_matches(is_ows);
+        match params.strip_prefix(';') {
+            Some(rest) => params = rest,
+            None if params.is_empty() => break,
+            None => return None,
+        }
+    }
+
+    Some(format!("{typ}/{subtype}").to_ascii_lowercase())
+}
+
 #[cfg(test)]
 mod test {
-    use super::DapMediaType;
-    use crate::DapVersion;
+    use super::{media_types_for_version, DapMediaType};
+    use crate::{error::DapAbort, DapVersion};
+    use assert_matches::assert_matches;
 
     #[test]
-    fn from_str_for_version() {
+    fn parse() {
         // draft02, Section 8.1
         assert_eq!(
-            DapMediaType::from_str_for_version(
-                DapVersion::Draft02,
-                Some("application/dap-hpke-config")
-            ),
+            DapMediaType::parse(DapVersion::Draft02, Some("application/dap-hpke-config")).unwrap(),
             DapMediaType::HpkeConfigList
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft02,
                 Some("application/dap-aggregate-initialize-req")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregationJobInitReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft02,
                 Some("application/dap-aggregate-initialize-resp")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregationJobResp,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft02,
                 Some("application/dap-aggregate-continue-req")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregationJobContinueReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft02,
                 Some("application/dap-aggregate-continue-resp")
-            ),
+            )
+            .unwrap(),
             DapMediaType::Draft02AggregateContinueResp,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft02,
                 Some("application/dap-aggregate-share-req")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregateShareReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft02,
                 Some("application/dap-aggregate-share-resp")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregateShare,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
-                DapVersion::Draft02,
-                Some("application/dap-collect-req")
-            ),
+            DapMediaType::parse(DapVersion::Draft02, Some("application/dap-collect-req")).unwrap(),
             DapMediaType::CollectReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
-                DapVersion::Draft02,
-                Some("application/dap-collect-resp")
-            ),
+            DapMediaType::parse(DapVersion::Draft02, Some("application/dap-collect-resp")).unwrap(),
             DapMediaType::Collection,
         );
 
         // draft07, Section 8.1
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft07,
                 Some("application/dap-hpke-config-list")
-            ),
+            )
+            .unwrap(),
             DapMediaType::HpkeConfigList
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft07,
                 Some("application/dap-aggregation-job-init-req")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregationJobInitReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft07,
                 Some("application/dap-aggregation-job-resp")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregationJobResp,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft07,
                 Some("application/dap-aggregation-job-continue-req")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregationJobContinueReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
+            DapMediaType::parse(
                 DapVersion::Draft07,
                 Some("application/dap-aggregate-share-req")
-            ),
+            )
+            .unwrap(),
             DapMediaType::AggregateShareReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
-                DapVersion::Draft07,
-                Some("application/dap-aggregate-share")
-            ),
+            DapMediaType::parse(DapVersion::Draft07, Some("application/dap-aggregate-share"))
+                .unwrap(),
             DapMediaType::AggregateShare,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
-                DapVersion::Draft07,
-                Some("application/dap-collect-req")
-            ),
+            DapMediaType::parse(DapVersion::Draft07, Some("application/dap-collect-req")).unwrap(),
             DapMediaType::CollectReq,
         );
         assert_eq!(
-            DapMediaType::from_str_for_version(
-                DapVersion::Draft07,
-                Some("application/dap-collection")
-            ),
+            DapMediaType::parse(DapVersion::Draft07, Some("application/dap-collection")).unwrap(),
             DapMediaType::Collection,
         );
 
         // Invalid media type
-        assert_eq!(
-            DapMediaType::from_str_for_version(DapVersion::Draft07, Some("blah-blah-blah")),
-            DapMediaType::Invalid("blah-blah-blah".into()),
+        assert_matches!(
+            DapMediaType::parse(DapVersion::Draft07, Some("blah-blah-blah")),
+            Err(DapAbort::BadRequest(..))
         );
 
         // Missing media type
         assert_eq!(
-            DapMediaType::from_str_for_version(DapVersion::Draft07, None),
+            DapMediaType::parse(DapVersion::Draft07, None).unwrap(),
             DapMediaType::Missing,
         );
     }
@@ -319,11 +449,83 @@ mod test {
             (DapVersion::Draft07, DapMediaType::Report),
         ] {
             assert_eq!(
-                DapMediaType::from_str_for_version(version, media_type.as_str_for_version(version)),
+                DapMediaType::parse(version, media_type.as_str_for_version(version)).unwrap(),
                 media_type,
                 "round trip test failed for {version:?} and {media_type:?}"
             );
         }
+
+        // Every media type defined for a version round trips.
+        for version in [DapVersion::Draft02, DapVersion::Draft07] {
+            for (media_type, content_type) in media_types_for_version(version) {
+                assert_eq!(media_type.as_str_for_version(version), Some(*content_type));
+                assert_eq!(
+                    &DapMediaType::parse(version, Some(content_type)).unwrap(),
+                    media_type
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parse_with_parameters() {
+        for content_type in [
+            "application/dap-report;charset=utf-8",
+            "application/dap-report ; charset=utf-8",
+            "Application/DAP-Report",
+            "application/dap-report;",
+            "application/dap-report;;foo=bar",
+            r#"application/dap-report; foo="bar; baz"; charset=utf-8"#,
+            r#"application/dap-report; foo="\"quoted\"""#,
+        ] {
+            assert_eq!(
+                DapMediaType::parse(DapVersion::Draft07, Some(content_type)).unwrap(),
+                DapMediaType::Report,
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        for content_type in [
+            "",
+            "application",
+            "application/",
+            "/dap-report",
+            "application/dap report",
+            "application/dap-report; charset",
+            "application/dap-report; charset=",
+            "application/dap-report; charset=utf 8",
+            r#"application/dap-report; foo="bar"#,
+            "application/dap-report, application/dap-report",
+        ] {
+            assert_matches!(
+                DapMediaType::parse(DapVersion::Draft07, Some(content_type)),
+                Err(DapAbort::BadRequest(detail)) if detail.starts_with("malformed"),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_rejects_other_version() {
+        assert_matches!(
+            DapMediaType::parse(DapVersion::Draft02, Some("application/dap-aggregation-job-init-req")),
+            Err(DapAbort::BadRequest(detail)) if detail.contains("not defined for Draft02")
+        );
+        assert_matches!(
+            DapMediaType::parse(DapVersion::Draft07, Some("application/dap-collect-resp")),
+            Err(DapAbort::BadRequest(detail)) if detail.contains("not defined for Draft07")
+        );
+        assert_matches!(
+            DapMediaType::parse(DapVersion::Draft02, Some("application/dap-report-batch")),
+            Err(DapAbort::BadRequest(..))
+        );
+        assert_eq!(
+            DapMediaType::ReportBatch.as_str_for_version(DapVersion::Draft02),
+            None
+        );
     }
 
     // Issue #269: Ensure the media type included with the AggregateContinueResp in draft02 is not
@@ -0,0 +1,186 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! HTTP content codings for compressing request and response bodies.
+
+use std::io::{Read, Write};
+
+use flate2::{
+    read::{DeflateDecoder, GzDecoder},
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{fatal_error, DapAbort, DapError};
+
+/// Maximum size of a decompressed body. Bodies that would decompress to more than this are
+/// rejected in order to mitigate decompression bombs.
+pub const MAX_DECODED_BODY_SIZE: u64 = 1 << 28;
+
+/// Content coding applied to an HTTP body, as indicated by the "content-encoding" header.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DapContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl DapContentEncoding {
+    /// Return the value of the "content-encoding" header for this coding.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Parse the value of a "content-encoding" header. Returns `Ok(None)` if the header is absent
+    /// or indicates the identity coding.
+    pub fn from_header(header: Option<&str>) -> Result<Option<Self>, DapAbort> {
+        let Some(header) = header.map(str::trim) else {
+            return Ok(None);
+        };
+        if header.eq_ignore_ascii_case("gzip") || header.eq_ignore_ascii_case("x-gzip") {
+            Ok(Some(Self::Gzip))
+        } else if header.eq_ignore_ascii_case("deflate") {
+            Ok(Some(Self::Deflate))
+        } else if header.is_empty() || header.eq_ignore_ascii_case("identity") {
+            Ok(None)
+        } else {
+            Err(DapAbort::BadRequest(format!(
+                "unsupported content-encoding: {header}"
+            )))
+        }
+    }
+
+    /// Choose a coding for the response from the value of the "accept-encoding" header of the
+    /// request. Gzip is preferred over deflate; codings with a quality value of zero are ignored.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Option<Self> {
+        let mut deflate = false;
+        for item in accept_encoding?.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            let rejected = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            if rejected {
+                continue;
+            }
+            if coding.eq_ignore_ascii_case("gzip") || coding == "*" {
+                return Some(Self::Gzip);
+            }
+            if coding.eq_ignore_ascii_case("deflate") {
+                deflate = true;
+            }
+        }
+        deflate.then_some(Self::Deflate)
+    }
+
+    /// Compress `data`.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>, DapError> {
+        let map_err = |e: std::io::Error| fatal_error!(err = ?e, "failed to compress body");
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).map_err(map_err)?;
+                encoder.finish().map_err(map_err)
+            }
+            Self::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).map_err(map_err)?;
+                encoder.finish().map_err(map_err)
+            }
+        }
+    }
+
+    /// Decompress `data`. The result is at most [`MAX_DECODED_BODY_SIZE`] bytes long.
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DapAbort> {
+        let mut decoded = Vec::new();
+        let res = match self {
+            Self::Gzip => GzDecoder::new(data)
+                .take(MAX_DECODED_BODY_SIZE + 1)
+                .read_to_end(&mut decoded),
+            Self::Deflate => DeflateDecoder::new(data)
+                .take(MAX_DECODED_BODY_SIZE + 1)
+                .read_to_end(&mut decoded),
+        };
+        res.map_err(|e| {
+            DapAbort::BadRequest(format!("failed to decode {} body: {e}", self.as_str()))
+        })?;
+        if decoded.len() as u64 > MAX_DECODED_BODY_SIZE {
+            return Err(DapAbort::BadRequest(format!(
+                "decoded body exceeds {MAX_DECODED_BODY_SIZE} bytes"
+            )));
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DapContentEncoding;
+    use crate::DapAbort;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn roundtrip() {
+        let data = [0_u8; 4096];
+        for encoding in [DapContentEncoding::Gzip, DapContentEncoding::Deflate] {
+            let encoded = encoding.encode(&data).unwrap();
+            assert!(encoded.len() < data.len());
+            assert_eq!(encoding.decode(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decode_malformed() {
+        assert_matches!(
+            DapContentEncoding::Gzip.decode(b"not gzip"),
+            Err(DapAbort::BadRequest(..))
+        );
+    }
+
+    #[test]
+    fn from_header() {
+        assert_eq!(DapContentEncoding::from_header(None).unwrap(), None);
+        assert_eq!(
+            DapContentEncoding::from_header(Some("identity")).unwrap(),
+            None
+        );
+        assert_eq!(
+            DapContentEncoding::from_header(Some("GZIP")).unwrap(),
+            Some(DapContentEncoding::Gzip)
+        );
+        assert_eq!(
+            DapContentEncoding::from_header(Some("deflate")).unwrap(),
+            Some(DapContentEncoding::Deflate)
+        );
+        assert_matches!(
+            DapContentEncoding::from_header(Some("br")),
+            Err(DapAbort::BadRequest(..))
+        );
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(DapContentEncoding::negotiate(None), None);
+        assert_eq!(DapContentEncoding::negotiate(Some("identity")), None);
+        assert_eq!(
+            DapContentEncoding::negotiate(Some("deflate, gzip;q=0.5")),
+            Some(DapContentEncoding::Gzip)
+        );
+        assert_eq!(
+            DapContentEncoding::negotiate(Some("br, deflate, gzip;q=0")),
+            Some(DapContentEncoding::Deflate)
+        );
+        assert_eq!(
+            DapContentEncoding::negotiate(Some("*")),
+            Some(DapContentEncoding::Gzip)
+        );
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Diagnostics for debugging report processing. [`trace_report()`] walks a report through each of
+//! the steps an Aggregator takes before aggregating it and records the outcome of each step. This
+//! makes it possible to determine why a Client's report was rejected without instrumenting the
+//! Aggregator itself.
+
+use crate::{
+    extensions::DapExtensionRegistry,
+    hpke::HpkeReceiverConfig,
+    messages::{PlaintextInputShare, Report, ReportId, TaskId, TransitionFailure},
+    vdaf::{input_share_aad, input_share_info, prep_init},
+    DapTaskConfig, DapVersion,
+};
+use prio::codec::{Decode, ParameterizedDecode};
+use serde::Serialize;
+use std::fmt::Display;
+
+/// A step taken by an Aggregator to process a report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportTraceStep {
+    /// Decode the report.
+    DecodeReport,
+
+    /// Check that the report does not pertain to an expired task.
+    CheckExpiration,
+
+    /// Find the HPKE receiver config indicated by the encrypted input share.
+    FindHpkeConfig,
+
+    /// Decrypt the input share.
+    DecryptInputShare,
+
+    /// Decode the plaintext input share.
+    DecodeInputShare,
+
+    /// Check the report's extensions.
+    CheckExtensions,
+
+    /// Initialize VDAF preparation.
+    VdafPrepInit,
+}
+
+/// The outcome of a step.
+#[derive(Debug, Serialize)]
+pub struct ReportTraceEntry {
+    pub step: ReportTraceStep,
+
+    /// Description of why the step failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The result of [`trace_report()`]. Processing stops at the first step that fails.
+#[derive(Debug, Default, Serialize)]
+pub struct ReportTrace {
+    /// The report ID, if the report could be decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report_id: Option<ReportId>,
+    pub steps: Vec<ReportTraceEntry>,
+}
+
+impl ReportTrace {
+    /// Return the step that failed, if any. If no step failed, then the report would be accepted
+    /// for aggregation.
+    pub fn failed_step(&self) -> Option<ReportTraceStep> {
+        self.steps
+            .iter()
+            .find(|entry| entry.error.is_some())
+            .map(|entry| entry.step)
+    }
+
+    fn pass(&mut self, step: ReportTraceStep) {
+        self.steps.push(ReportTraceEntry { step, error: None });
+    }
+
+    fn fail(mut self, step: ReportTraceStep, error: impl Display) -> Self {
+        self.steps.push(ReportTraceEntry {
+            step,
+            error: Some(error.to_string()),
+        });
+        self
+    }
+}
+
+/// Trace the processing of an encoded report by the Leader (if `is_leader` is set) or Helper. The
+/// report is decrypted with whichever of `hpke_receiver_configs` it indicates. Extensions are
+/// checked against `extension_registry` and the task's unknown extension policy.
+///
+/// Checks that depend on Aggregator state, such as replay protection and whether the report's
+/// batch has been collected, are not performed.
+pub fn trace_report(
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    hpke_receiver_configs: &[HpkeReceiverConfig],
+    extension_registry: &DapExtensionRegistry,
+    is_leader: bool,
+    report: &[u8],
+) -> ReportTrace {
+    use ReportTraceStep::*;
+
+    let mut trace = ReportTrace::default();
+    let version = task_config.version;
+
+    let report = match Report::get_decoded_with_param(&version, report) {
+        Ok(report) => report,
+        Err(e) => return trace.fail(DecodeReport, e),
+    };
+    trace.report_id = Some(report.report_metadata.id.clone());
+    if report.encrypted_input_shares.len() != 2 {
+        return trace.fail(
+            DecodeReport,
+            format!(
+                "expected 2 encrypted input shares, got {}",
+                report.encrypted_input_shares.len()
+            ),
+        );
+    }
+    trace.pass(DecodeReport);
+
+    if report.report_metadata.time >= task_config.expiration {
+        return trace.fail(CheckExpiration, TransitionFailure::TaskExpired);
+    }
+    trace.pass(CheckExpiration);
+
+    let ciphertext = &report.encrypted_input_shares[usize::from(!is_leader)];
+    let Some(hpke_receiver_config) = hpke_receiver_configs
+        .iter()
+        .find(|receiver| receiver.config.id == ciphertext.config_id)
+    else {
+        return trace.fail(
+            FindHpkeConfig,
+            format!("no HPKE receiver config with ID {}", ciphertext.config_id),
+        );
+    };
+    trace.pass(FindHpkeConfig);
+
+    let info = match input_share_info(version, is_leader) {
+        Ok(info) => info,
+        Err(e) => return trace.fail(DecryptInputShare, e),
+    };
+    let aad = input_share_aad(
+        task_id,
+        &report.report_metadata,
+        &report.public_share,
+        version,
+    );
+    let encoded_input_share =
+        match hpke_receiver_config.decrypt(&info, &aad, &ciphertext.enc, &ciphertext.payload) {
+            Ok(encoded_input_share) => encoded_input_share,
+            Err(e) => return trace.fail(DecryptInputShare, e),
+        };
+    trace.pass(DecryptInputShare);
+
+    let input_share = match version {
+        DapVersion::Draft02 => PlaintextInputShare {
+            extensions: report.report_metadata.extensions.clone(),
+            payload: encoded_input_share,
+        },
+        _ => match PlaintextInputShare::get_decoded(&encoded_input_share) {
+            Ok(input_share) => input_share,
+            Err(e) => return trace.fail(DecodeInputShare, e),
+        },
+    };
+    trace.pass(DecodeInputShare);
+
+    if let Err(failure) = extension_registry.check(task_id, task_config, &input_share.extensions) {
+        return trace.fail(CheckExtensions, failure);
+    }
+    trace.pass(CheckExtensions);
+
+    let vdaf_verify_key = match task_config.active_vdaf_verify_key() {
+        Ok((_key_id, vdaf_verify_key)) => vdaf_verify_key,
+        Err(e) => return trace.fail(VdafPrepInit, e),
+    };
+    match prep_init(
+        is_leader,
+        vdaf_verify_key,
+        &task_config.vdaf,
+        &report.report_metadata.id,
+        &report.public_share,
+        &input_share.payload,
+    ) {
+        Ok(Ok(_)) => trace.pass(VdafPrepInit),
+        Ok(Err(e)) => return trace.fail(VdafPrepInit, e),
+        Err(e) => return trace.fail(VdafPrepInit, e),
+    }
+
+    trace
+}
+
+#[cfg(test)]
+mod test {
+    use super::{trace_report, ReportTraceStep};
+    use crate::{
+        extensions::DapExtensionRegistry, hpke::HpkeKemId, messages::Report, test_versions,
+        testing::AggregationJobTest, DapMeasurement, DapVersion, Prio3Config, VdafConfig,
+    };
+    use prio::codec::ParameterizedEncode;
+
+    fn trace(version: DapVersion) {
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            version,
+        );
+        let report = t
+            .task_config
+            .vdaf
+            .produce_report(
+                &t.client_hpke_config_list,
+                t.now,
+                &t.task_id,
+                DapMeasurement::U64(1),
+                version,
+            )
+            .unwrap();
+        let trace_for = |report: &Report, is_leader| {
+            let hpke_receiver_config = if is_leader {
+                &t.leader_hpke_receiver_config
+            } else {
+                &t.helper_hpke_receiver_config
+            };
+            trace_report(
+                &t.task_id,
+                &t.task_config,
+                std::slice::from_ref(hpke_receiver_config),
+                &DapExtensionRegistry::new(),
+                is_leader,
+                &report.get_encoded_with_param(&version),
+            )
+        };
+
+        // The report is accepted by both Aggregators.
+        for is_leader in [true, false] {
+            let trace = trace_for(&report, is_leader);
+            assert_eq!(trace.failed_step(), None);
+            assert_eq!(trace.report_id.as_ref(), Some(&report.report_metadata.id));
+            assert_eq!(trace.steps.len(), 7);
+        }
+
+        // The report is not decodable.
+        let trace = trace_report(
+            &t.task_id,
+            &t.task_config,
+            std::slice::from_ref(&t.leader_hpke_receiver_config),
+            &DapExtensionRegistry::new(),
+            true,
+            b"not a report",
+        );
+        assert_eq!(trace.failed_step(), Some(ReportTraceStep::DecodeReport));
+        assert!(trace.report_id.is_none());
+
+        // The HPKE config is not known to the Helper.
+        let trace = trace_report(
+            &t.task_id,
+            &t.task_config,
+            std::slice::from_ref(&t.leader_hpke_receiver_config),
+            &DapExtensionRegistry::new(),
+            false,
+            &report.get_encoded_with_param(&version),
+        );
+        assert_eq!(trace.failed_step(), Some(ReportTraceStep::FindHpkeConfig));
+
+        // The ciphertext is corrupted.
+        let mut corrupted = report.clone();
+        corrupted.encrypted_input_shares[0].payload[0] ^= 1;
+        let trace = trace_for(&corrupted, true);
+        assert_eq!(
+            trace.failed_step(),
+            Some(ReportTraceStep::DecryptInputShare)
+        );
+        assert_eq!(trace.steps.len(), 4);
+    }
+
+    test_versions! { trace }
+}
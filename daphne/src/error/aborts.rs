@@ -5,9 +5,12 @@
 
 use crate::{
     fatal_error,
-    messages::{BatchSelector, TaskId, TransitionFailure},
-    DapError, DapMediaType, DapRequest, DapVersion,
+    messages::{BatchSelector, Duration, TaskId, TransitionFailure},
+    metrics::ContextualizedDaphneMetrics,
+    DapError, DapMediaType, DapRequest, DapSender, DapVersion,
 };
+
+use super::{DapErrorCode, FatalDapError};
 use hex::FromHexError;
 use prio::codec::CodecError;
 use serde::{Deserialize, Serialize};
@@ -18,10 +21,25 @@ use serde::{Deserialize, Serialize};
 /// DAP aborts.
 #[derive(Debug, thiserror::Error)]
 pub enum DapAbort {
+    /// Not part of DAP: Aggregation job rejected. Sent by the Helper in response to an
+    /// AggregationJobInitReq in which too many report shares were rejected. This usually
+    /// indicates that the Aggregators' configurations for the task do not match.
+    #[error("aggregationJobRejected")]
+    AggregationJobRejected {
+        detail: String,
+        task_id: TaskId,
+        agg_job_id_base64url: String,
+    },
+
     /// Bad request. Sent in response to an HTTP request that couldn't be handled preoprly.
     #[error("bad request")]
     BadRequest(String),
 
+    /// Not part of DAP: Batch already collected. Sent in response to a CollectReq for a fixed-size
+    /// batch that the Leader has already collected.
+    #[error("batchCollected")]
+    BatchCollected { detail: String, task_id: TaskId },
+
     /// Invalid batch. Sent in response to a CollectReq or AggregateShareReq.
     #[error("batchInvalid")]
     BatchInvalid { detail: String, task_id: TaskId },
@@ -39,6 +57,12 @@ pub enum DapAbort {
     #[error("internal error")]
     Internal(#[source] Box<dyn std::error::Error + 'static + Send + Sync>),
 
+    /// Not part of DAP: Invalid aggregation parameter. Sent in response to an
+    /// AggregationJobInitReq, AggregateShareReq, or CollectReq whose aggregation parameter is not
+    /// valid for the task's VDAF.
+    #[error("invalidAggregationParameter")]
+    InvalidAggregationParameter { detail: String, task_id: TaskId },
+
     /// Invalid batch size (either too small or too large). Sent in response to a CollectReq or
     /// AggregateShareReq.
     #[error("invalidBatchSize")]
@@ -80,6 +104,16 @@ pub enum DapAbort {
         agg_job_id_base64url: String,
     },
 
+    /// Not part of DAP: Too many requests. Sent in response to a request from a peer that has
+    /// exceeded its quota, or when the Aggregator is too overloaded to handle the request.
+    /// `retry_after` is the number of seconds after which the peer may retry, if known; it is
+    /// sent in the Retry-After header of the response.
+    #[error("tooManyRequests")]
+    TooManyRequests {
+        detail: String,
+        retry_after: Option<Duration>,
+    },
+
     /// Unauthorized HTTP request.
     #[error("unauthorizedRequest")]
     UnauthorizedRequest { detail: String, task_id: TaskId },
@@ -109,15 +143,100 @@ pub enum DapAbort {
 }
 
 impl DapAbort {
+    /// Return the code of the fatal error that caused an internal error, if any.
+    pub fn error_code(&self) -> Option<DapErrorCode> {
+        let Self::Internal(e) = self else {
+            return None;
+        };
+        if let Some(e) = e.downcast_ref::<DapError>() {
+            e.code()
+        } else if let Some(e) = e.downcast_ref::<FatalDapError>() {
+            Some(e.code())
+        } else {
+            Some(DapErrorCode::Unspecified)
+        }
+    }
+
+    /// The number of seconds after which the request may be retried, if the abort indicates one.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::TooManyRequests { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Count the abort in the abort metric and log it along with the task and aggregation job it
+    /// pertains to, if any. `sender` is the role of the peer whose request is being aborted, if
+    /// known. This should be called once for each abort that is sent in response to a request.
+    pub fn log_and_count(
+        &self,
+        metrics: &ContextualizedDaphneMetrics<'_>,
+        sender: Option<DapSender>,
+    ) {
+        metrics.abort_inc(self, sender);
+
+        let (task_id, agg_job_id) = self.task_id_and_agg_job_id();
+        let task_id = task_id.map(TaskId::to_base64url);
+        tracing::error!(
+            abort = %self,
+            ?sender,
+            task_id = task_id.as_deref(),
+            agg_job_id,
+            error = ?self,
+            "request aborted"
+        );
+    }
+
+    /// Return the task and, if applicable, the (base64url-encoded) aggregation job the abort
+    /// pertains to.
+    fn task_id_and_agg_job_id(&self) -> (Option<&TaskId>, Option<&str>) {
+        match self {
+            Self::AggregationJobRejected {
+                task_id,
+                agg_job_id_base64url,
+                ..
+            }
+            | Self::RoundMismatch {
+                task_id,
+                agg_job_id_base64url,
+                ..
+            }
+            | Self::UnrecognizedAggregationJob {
+                task_id,
+                agg_job_id_base64url,
+            } => (Some(task_id), Some(agg_job_id_base64url)),
+            Self::BatchCollected { task_id, .. }
+            | Self::BatchInvalid { task_id, .. }
+            | Self::BatchMismatch { task_id, .. }
+            | Self::BatchOverlap { task_id, .. }
+            | Self::InvalidAggregationParameter { task_id, .. }
+            | Self::InvalidBatchSize { task_id, .. }
+            | Self::InvalidTask { task_id, .. }
+            | Self::QueryMismatch { task_id, .. }
+            | Self::UnauthorizedRequest { task_id, .. } => (Some(task_id), None),
+            Self::UnrecognizedMessage { task_id, .. } => (task_id.as_ref(), None),
+            Self::BadRequest(..)
+            | Self::Internal(..)
+            | Self::MissingTaskId
+            | Self::ReportRejected { .. }
+            | Self::ReportTooLate
+            | Self::TooManyRequests { .. }
+            | Self::UnrecognizedTask => (None, None),
+        }
+    }
+
     /// Construct a problem details JSON object for this abort. `url` is the URL to which the
     /// request was targeted and `task_id` is the associated TaskID.
     pub fn into_problem_details(self) -> ProblemDetails {
         let (title, typ) = self.title_and_type();
+        let error_code = self.error_code();
         let (task_id, detail, agg_job_id_base64url) = match self {
-            Self::BatchInvalid { detail, task_id }
+            Self::BatchCollected { detail, task_id }
+            | Self::BatchInvalid { detail, task_id }
             | Self::InvalidTask { detail, task_id }
             | Self::BatchMismatch { detail, task_id }
             | Self::BatchOverlap { detail, task_id }
+            | Self::InvalidAggregationParameter { detail, task_id }
             | Self::InvalidBatchSize { detail, task_id }
             | Self::QueryMismatch { detail, task_id }
             | Self::UnauthorizedRequest { detail, task_id } => (Some(task_id), Some(detail), None),
@@ -126,10 +245,15 @@ impl DapAbort {
                 Some("A task ID must be specified in the query parameter of the request.".into()),
                 None,
             ),
-            Self::BadRequest(detail) | Self::ReportRejected { detail } => {
-                (None, Some(detail), None)
+            Self::BadRequest(detail)
+            | Self::ReportRejected { detail }
+            | Self::TooManyRequests { detail, .. } => (None, Some(detail), None),
+            Self::AggregationJobRejected {
+                detail,
+                task_id,
+                agg_job_id_base64url,
             }
-            Self::RoundMismatch {
+            | Self::RoundMismatch {
                 detail,
                 task_id,
                 agg_job_id_base64url,
@@ -153,6 +277,28 @@ impl DapAbort {
             agg_job_id: agg_job_id_base64url,
             instance: None, // TODO interop: Implement as specified.
             detail,
+            error_code,
+        }
+    }
+
+    /// Like [`into_problem_details`](Self::into_problem_details), except that an internal error is
+    /// replaced by a generic document that reveals nothing about the error, not even its code.
+    /// Instead, the `instance` member is set to `correlation_id`, which the caller is expected to
+    /// log along with the error.
+    pub fn into_strict_problem_details(self, correlation_id: &str) -> ProblemDetails {
+        if !matches!(self, Self::Internal(..)) {
+            return self.into_problem_details();
+        }
+
+        let (_status, title, _is_dap_type) = self.http_mapping();
+        ProblemDetails {
+            title: title.into(),
+            typ: None,
+            task_id: None,
+            agg_job_id: None,
+            instance: Some(correlation_id.into()),
+            detail: None,
+            error_code: None,
         }
     }
 
@@ -179,10 +325,15 @@ impl DapAbort {
     }
 
     #[inline]
-    pub(crate) fn version_unknown() -> Self {
+    pub fn version_unknown() -> Self {
         DapAbort::BadRequest("DAP version of request is not recognized".into())
     }
 
+    #[inline]
+    pub fn version_disabled(version: DapVersion) -> Self {
+        DapAbort::BadRequest(format!("DAP version of request is not enabled: {version}"))
+    }
+
     #[inline]
     pub(crate) fn batch_overlap(task_id: &TaskId, batch_sel: &BatchSelector) -> Self {
         Self::BatchOverlap {
@@ -213,7 +364,7 @@ impl DapAbort {
                 "The report pertains to a batch that has already been collected."
             }
             TransitionFailure::ReportReplayed => {
-                "A report with the same ID was uploaded previously."
+                "The report is a duplicate: a report with the same ID was uploaded previously."
             }
             _ => return fatal_error!(
                 err = "Attempted to construct a \"reportRejected\" abort with unexpected transition failure",
@@ -226,55 +377,70 @@ impl DapAbort {
         }
     }
 
-    fn title_and_type(&self) -> (String, Option<String>) {
-        let (title, dap_abort_type) = match self {
-            Self::BatchInvalid { .. } => ("Batch boundary check failed", Some(self.to_string())),
+    /// Map the abort to the HTTP status code and problem title with which it is sent to the peer
+    /// and whether its problem type is defined by DAP. Every variant appears in this table, so
+    /// adding a variant forces a decision about how it is conveyed.
+    fn http_mapping(&self) -> (u16, &'static str, bool) {
+        match self {
+            Self::AggregationJobRejected { .. } => (
+                400,
+                "Too many reports in the aggregation job were rejected",
+                false,
+            ),
+            Self::BadRequest(..) => (400, "Bad request", false),
+            Self::BatchCollected { .. } => (400, "The selected batch was already collected", false),
+            Self::BatchInvalid { .. } => (400, "Batch boundary check failed", true),
             Self::BatchMismatch { .. } => (
+                400,
                 "Aggregators disagree on the set of reports in the batch",
-                Some(self.to_string()),
+                true,
             ),
             Self::BatchOverlap { .. } => (
+                400,
                 "The selected batch overlaps with a previous batch",
-                Some(self.to_string()),
+                true,
             ),
-            Self::InvalidBatchSize { .. } => ("Batch size is invalid", Some(self.to_string())),
-            Self::InvalidTask { .. } => ("Opted out of Taskprov task", Some(self.to_string())),
-            Self::QueryMismatch { .. } => {
-                ("Query type does not match the task", Some(self.to_string()))
+            Self::Internal(..) => (500, "Internal server error", false),
+            Self::InvalidAggregationParameter { .. } => {
+                (400, "Aggregation parameter is invalid for the VDAF", false)
             }
-            Self::RoundMismatch { .. } => (
-                "Aggregation round indicated by peer does not match host",
-                Some(self.to_string()),
-            ),
+            Self::InvalidBatchSize { .. } => (400, "Batch size is invalid", true),
+            Self::InvalidTask { .. } => (400, "Opted out of Taskprov task", true),
             Self::MissingTaskId => (
+                400,
                 "Request for HPKE configuration with unspecified task",
-                Some(self.to_string()),
+                true,
             ),
-            Self::ReportRejected { .. } => ("Report rejected", Some(self.to_string())),
+            Self::QueryMismatch { .. } => (400, "Query type does not match the task", true),
+            Self::ReportRejected { .. } => (400, "Report rejected", true),
             Self::ReportTooLate => (
+                400,
                 "The requested task expires after report timestamp",
-                Some(self.to_string()),
+                true,
             ),
-            Self::UnauthorizedRequest { .. } => {
-                ("Request authorization failed", Some(self.to_string()))
-            }
-            Self::UnrecognizedAggregationJob { .. } => {
-                ("Unrecognized aggregation job", Some(self.to_string()))
-            }
-            Self::UnrecognizedMessage { .. } => {
-                ("Malformed or invalid message", Some(self.to_string()))
-            }
-            Self::UnrecognizedTask => (
-                "Task indicated by request is not recognized",
-                Some(self.to_string()),
+            Self::RoundMismatch { .. } => (
+                400,
+                "Aggregation round indicated by peer does not match host",
+                true,
             ),
-            Self::BadRequest(..) => ("Bad request", None),
-            Self::Internal(..) => ("Internal server error", None),
-        };
+            Self::TooManyRequests { .. } => (429, "Request quota exceeded", false),
+            Self::UnauthorizedRequest { .. } => (400, "Request authorization failed", true),
+            Self::UnrecognizedAggregationJob { .. } => (400, "Unrecognized aggregation job", true),
+            Self::UnrecognizedMessage { .. } => (400, "Malformed or invalid message", true),
+            Self::UnrecognizedTask => (400, "Task indicated by request is not recognized", true),
+        }
+    }
+
+    /// The HTTP status code of the response carrying this abort.
+    pub fn http_status(&self) -> u16 {
+        self.http_mapping().0
+    }
 
+    fn title_and_type(&self) -> (String, Option<String>) {
+        let (_status, title, is_dap_type) = self.http_mapping();
         (
             title.to_string(),
-            dap_abort_type.map(|t| format!("urn:ietf:params:ppm:dap:error:{t}")),
+            is_dap_type.then(|| format!("urn:ietf:params:ppm:dap:error:{self}")),
         )
     }
 }
@@ -322,9 +488,17 @@ pub struct ProblemDetails {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) agg_job_id: Option<String>,
 
+    /// Identifies the occurrence of the problem. For internal errors this is the correlation ID
+    /// under which the error was logged (see [`DapAbort::into_strict_problem_details`]).
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) instance: Option<String>,
+    pub instance: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub detail: Option<String>,
+
+    /// Code of the fatal error that caused an internal error. Only the code is exposed, not the
+    /// error message.
+    #[serde(rename = "errorcode")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<DapErrorCode>,
 }
@@ -5,6 +5,8 @@ pub mod aborts;
 
 use std::fmt::{Debug, Display};
 
+use serde::{Deserialize, Serialize};
+
 use crate::{messages::TransitionFailure, vdaf::VdafError};
 pub use aborts::DapAbort;
 
@@ -28,10 +30,76 @@ pub enum DapError {
     Transition(#[from] TransitionFailure),
 }
 
+/// Classification of a fatal error. The code is meant for programmatic handling of errors by
+/// embedders and is safe to expose to peers. The string representation of each code is stable
+/// across versions.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum DapErrorCode {
+    /// The cause of the error was not classified.
+    #[default]
+    Unspecified,
+
+    /// Reading from or writing to storage failed.
+    Storage,
+
+    /// Communicating with a peer or other service failed.
+    Network,
+
+    /// A cryptographic operation failed.
+    Crypto,
+
+    /// Stored state is missing or inconsistent.
+    StateCorruption,
+
+    /// The configuration is missing or invalid.
+    Config,
+
+    /// Stored state was written in a format, or for a configuration, that is not supported. This
+    /// usually means the state was written by a different version of the software.
+    StateFormatMismatch,
+}
+
+impl DapErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "unspecified",
+            Self::Storage => "storage",
+            Self::Network => "network",
+            Self::Crypto => "crypto",
+            Self::StateCorruption => "state_corruption",
+            Self::Config => "config",
+            Self::StateFormatMismatch => "state_format_mismatch",
+        }
+    }
+}
+
+impl Display for DapErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl DapError {
+    /// Return the code of a fatal error, or `None` if this is not a fatal error.
+    pub fn code(&self) -> Option<DapErrorCode> {
+        match self {
+            Self::Fatal(e) => Some(e.code()),
+            Self::Abort(..) | Self::Transition(..) => None,
+        }
+    }
+}
+
 impl FatalDapError {
     #[doc(hidden)]
-    pub fn __use_the_macro(s: String) -> Self {
-        FatalDapError(s)
+    pub fn __use_the_macro(s: String, code: DapErrorCode) -> Self {
+        FatalDapError { message: s, code }
+    }
+
+    /// The classification of this error.
+    pub fn code(&self) -> DapErrorCode {
+        self.code
     }
 }
 
@@ -46,19 +114,22 @@ impl From<VdafError> for DapError {
 }
 
 #[derive(PartialEq, Eq)]
-pub struct FatalDapError(String);
+pub struct FatalDapError {
+    message: String,
+    code: DapErrorCode,
+}
 
 impl std::error::Error for FatalDapError {}
 
 impl Display for FatalDapError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.message)
     }
 }
 
 impl Debug for FatalDapError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.0)
+        write!(f, "{:?}", self.message)
     }
 }
 
@@ -89,11 +160,28 @@ impl Debug for FatalDapError {
 /// fatal_error!(err = ?e, id = %some_id, "operation failed");
 /// ```
 ///
+/// A [`DapErrorCode`] may be attached to the error by following the `err` field with a `code`
+/// field naming one of its variants:
+/// ```
+/// # use daphne::{error::DapErrorCode, fatal_error};
+/// let e = fatal_error!(err = "failed to read from storage", code = Storage);
+/// assert_eq!(e.code(), Some(DapErrorCode::Storage));
+/// ```
+///
 /// # Note
 /// If you have an error that caused the fatal error, it should be passed in the `err` field of the
 /// macro, pass a string only when there is no error value you can use.
 #[macro_export]
 macro_rules! fatal_error {
+    (err = ?$e:expr, code = $code:ident $(, $($rest:tt)*)?) => {
+        $crate::__fatal_error_impl!(@@ err = ?$e, code = $code $(, $($rest)*)?)
+    };
+    (err = %$e:expr, code = $code:ident $(, $($rest:tt)*)?) => {
+        $crate::__fatal_error_impl!(@@ err = %$e, code = $code $(, $($rest)*)?)
+    };
+    (err = $e:expr, code = $code:ident $(, $($rest:tt)*)?) => {
+        $crate::__fatal_error_impl!(@@ err = $e, code = $code $(, $($rest)*)?)
+    };
     (err = ?$e:expr) => {
         $crate::__fatal_error_impl!(@@ err = ?$e)
     };
@@ -118,6 +206,24 @@ macro_rules! fatal_error {
 #[macro_export]
 #[doc(hidden)]
 macro_rules! __fatal_error_impl {
+    (@@ err = ?$e:expr, code = $code:ident $(, $($rest:tt)*)?) => {{
+        let error = &$e;
+        let code = $crate::error::DapErrorCode::$code;
+        ::tracing::error!(?error, %code, $($($rest)*)*);
+        $crate::__fatal_error_impl!(@@@ error, code)
+    }};
+    (@@ err = %$e:expr, code = $code:ident $(, $($rest:tt)*)?) => {{
+        let error = &$e;
+        let code = $crate::error::DapErrorCode::$code;
+        ::tracing::error!(%error, %code, $($($rest)*)*);
+        $crate::__fatal_error_impl!(@@@ error, code)
+    }};
+    (@@ err = $e:expr, code = $code:ident $(, $($rest:tt)*)?) => {{
+        let error = &$e;
+        let code = $crate::error::DapErrorCode::$code;
+        ::tracing::error!(error, %code, $($($rest)*)*);
+        $crate::__fatal_error_impl!(@@@ error, code)
+    }};
     (@@ err = ?$e:expr $(, $($rest:tt)*)?) => {{
         let error = &$e;
         ::tracing::error!(?error, $($($rest)*)*);
@@ -134,9 +240,237 @@ macro_rules! __fatal_error_impl {
         $crate::__fatal_error_impl!(@@@ error)
     }};
     (@@@ $error:expr) => {{
+        $crate::__fatal_error_impl!(@@@ $error, $crate::error::DapErrorCode::Unspecified)
+    }};
+    (@@@ $error:expr, $code:expr) => {{
         let error = $error;
         $crate::error::DapError::Fatal(
-            $crate::error::FatalDapError::__use_the_macro(::std::format!("{error}"))
+            $crate::error::FatalDapError::__use_the_macro(::std::format!("{error}"), $code)
         )
     }}
 }
+
+#[cfg(test)]
+mod test {
+    use super::{DapAbort, DapError, DapErrorCode};
+    use crate::{
+        assert_metrics_include, assert_metrics_include_auxiliary_function, messages::TaskId,
+        metrics::DaphneMetrics, DapSender,
+    };
+
+    #[test]
+    fn fatal_error_code() {
+        assert_eq!(
+            fatal_error!(err = "something went wrong").code(),
+            Some(DapErrorCode::Unspecified)
+        );
+        assert_eq!(
+            fatal_error!(err = "something went wrong", code = Storage, key = "foo").code(),
+            Some(DapErrorCode::Storage)
+        );
+        assert_eq!(DapError::Abort(DapAbort::UnrecognizedTask).code(), None);
+    }
+
+    #[test]
+    fn internal_error_problem_details() {
+        let abort = DapAbort::from(fatal_error!(err = "secret", code = StateCorruption));
+        assert_eq!(abort.error_code(), Some(DapErrorCode::StateCorruption));
+
+        // Only the code is exposed, not the error message.
+        let problem_details = serde_json::to_string(&abort.into_problem_details()).unwrap();
+        assert!(problem_details.contains(r#""errorcode":"state_corruption""#));
+        assert!(!problem_details.contains("secret"));
+
+        assert_eq!(
+            DapAbort::UnrecognizedTask.into_problem_details().error_code,
+            None
+        );
+    }
+
+    #[test]
+    fn strict_problem_details() {
+        let abort = DapAbort::from(fatal_error!(err = "secret", code = StateCorruption));
+        let problem_details = abort.into_strict_problem_details("0123456789abcdef");
+        assert_eq!(problem_details.error_code, None);
+        assert_eq!(
+            problem_details.instance.as_deref(),
+            Some("0123456789abcdef")
+        );
+
+        let problem_details = serde_json::to_string(&problem_details).unwrap();
+        assert!(!problem_details.contains("secret"));
+        assert!(!problem_details.contains("state_corruption"));
+
+        // Other aborts are unaffected.
+        let problem_details = DapAbort::UnrecognizedTask.into_strict_problem_details("foo");
+        assert_eq!(problem_details.instance, None);
+        assert_eq!(
+            problem_details.typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:unrecognizedTask")
+        );
+    }
+
+    #[test]
+    fn abort_http_mapping() {
+        let task_id = TaskId([1; 32]);
+        let detail = String::from("detail");
+        for (abort, status, typ) in [
+            (
+                DapAbort::AggregationJobRejected {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                    agg_job_id_base64url: "AQID".into(),
+                },
+                400,
+                None,
+            ),
+            (DapAbort::BadRequest(detail.clone()), 400, None),
+            (
+                DapAbort::BatchCollected {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                None,
+            ),
+            (
+                DapAbort::BatchInvalid {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("batchInvalid"),
+            ),
+            (
+                DapAbort::BatchMismatch {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("batchMismatch"),
+            ),
+            (
+                DapAbort::BatchOverlap {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("batchOverlap"),
+            ),
+            (
+                DapAbort::from(fatal_error!(err = "something went wrong")),
+                500,
+                None,
+            ),
+            (
+                DapAbort::InvalidAggregationParameter {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                None,
+            ),
+            (
+                DapAbort::InvalidBatchSize {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("invalidBatchSize"),
+            ),
+            (
+                DapAbort::InvalidTask {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("invalidTask"),
+            ),
+            (DapAbort::MissingTaskId, 400, Some("missingTaskID")),
+            (
+                DapAbort::QueryMismatch {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("queryMismatch"),
+            ),
+            (
+                DapAbort::ReportRejected {
+                    detail: detail.clone(),
+                },
+                400,
+                Some("reportRejected"),
+            ),
+            (DapAbort::ReportTooLate, 400, Some("reportTooLate")),
+            (
+                DapAbort::RoundMismatch {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                    agg_job_id_base64url: "AQID".into(),
+                },
+                400,
+                Some("roundMismatch"),
+            ),
+            (
+                DapAbort::TooManyRequests {
+                    detail: detail.clone(),
+                    retry_after: Some(30),
+                },
+                429,
+                None,
+            ),
+            (
+                DapAbort::UnauthorizedRequest {
+                    detail: detail.clone(),
+                    task_id: task_id.clone(),
+                },
+                400,
+                Some("unauthorizedRequest"),
+            ),
+            (
+                DapAbort::UnrecognizedAggregationJob {
+                    task_id: task_id.clone(),
+                    agg_job_id_base64url: "AQID".into(),
+                },
+                400,
+                Some("unrecognizedAggregationJob"),
+            ),
+            (
+                DapAbort::UnrecognizedMessage {
+                    detail: detail.clone(),
+                    task_id: None,
+                },
+                400,
+                Some("unrecognizedMessage"),
+            ),
+            (DapAbort::UnrecognizedTask, 400, Some("unrecognizedTask")),
+        ] {
+            assert_eq!(abort.http_status(), status, "{abort:?}");
+            assert_eq!(
+                abort.into_problem_details().typ,
+                typ.map(|t| format!("urn:ietf:params:ppm:dap:error:{t}"))
+            );
+        }
+    }
+
+    #[test]
+    fn log_and_count_abort() {
+        let registry = prometheus::Registry::new();
+        let metrics = DaphneMetrics::register(&registry, Some("test")).unwrap();
+        let metrics = metrics.with_host("example.com");
+
+        let abort = DapAbort::UnrecognizedAggregationJob {
+            task_id: TaskId([1; 32]),
+            agg_job_id_base64url: "AQID".into(),
+        };
+        abort.log_and_count(&metrics, Some(DapSender::Leader));
+        abort.log_and_count(&metrics, Some(DapSender::Leader));
+        DapAbort::UnrecognizedTask.log_and_count(&metrics, None);
+
+        assert_metrics_include!(registry, {
+            r#"test_abort_counter{host="example.com",sender="leader",type="unrecognizedAggregationJob"}"#: 2,
+            r#"test_abort_counter{host="example.com",sender="unknown",type="unrecognizedTask"}"#: 1,
+        });
+    }
+}
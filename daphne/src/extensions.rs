@@ -0,0 +1,318 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Report extensions. Daphne handles the taskprov and client authentication extensions itself;
+//! deployments may register handlers for other (e.g., private) extensions in a
+//! [`DapExtensionRegistry`]. How extensions that are not registered are handled is decided by each
+//! task's [`DapUnknownExtensionPolicy`].
+
+use crate::{
+    fatal_error,
+    messages::{
+        Extension, ReportId, ReportMetadata, TaskId, Time, TransitionFailure,
+        EXTENSION_CLIENT_AUTH, EXTENSION_TASKPROV,
+    },
+    DapError, DapTaskConfig,
+};
+use prio::codec::{CodecError, Encode};
+use rand::prelude::*;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Domain separation tag for the message authenticated by the client authentication extension.
+const CLIENT_AUTH_CONTEXT: &[u8] = b"dap-client-auth";
+
+/// Handler for a report extension registered with a [`DapExtensionRegistry`].
+pub trait DapExtensionHandler: Send + Sync {
+    /// Decode the payload of the extension. If decoding fails, then the report is rejected as
+    /// an unrecognized message.
+    fn decode(&self, payload: &[u8]) -> Result<(), CodecError>;
+
+    /// Check that the extension is valid for the given task. If not, then the report is rejected
+    /// with the returned failure.
+    fn validate(
+        &self,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _payload: &[u8],
+    ) -> Result<(), TransitionFailure> {
+        Ok(())
+    }
+}
+
+/// What to do with a report that carries an extension that is neither built in nor registered.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub enum DapUnknownExtensionPolicy {
+    /// Reject the report.
+    #[default]
+    Reject,
+
+    /// Process the report as if the extension were absent.
+    Ignore,
+}
+
+/// The set of report extensions recognized by an Aggregator in addition to taskprov.
+#[derive(Clone, Default)]
+pub struct DapExtensionRegistry {
+    handlers: BTreeMap<u16, Arc<dyn DapExtensionHandler>>,
+    max_extensions_len: Option<u16>,
+}
+
+/// A registry with no extensions registered.
+pub(crate) static EMPTY_EXTENSION_REGISTRY: DapExtensionRegistry = DapExtensionRegistry::new();
+
+impl DapExtensionRegistry {
+    /// Create an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+            max_extensions_len: None,
+        }
+    }
+
+    /// Set the maximum length of the encoded extensions of a report. Reports whose encrypted input
+    /// share is too long to fit the input share and extensions of at most this length are
+    /// rejected before they are decrypted.
+    pub fn set_max_extensions_len(&mut self, max_extensions_len: u16) {
+        self.max_extensions_len = Some(max_extensions_len);
+    }
+
+    /// The maximum length of the encoded extensions of a report. If not set (see
+    /// [`Self::set_max_extensions_len`]), then this is the most that the encoding allows, as the
+    /// payloads of the built-in extensions and of ignored extensions are not otherwise bounded.
+    pub fn max_extensions_len(&self) -> u16 {
+        self.max_extensions_len.unwrap_or(u16::MAX)
+    }
+
+    /// Register a handler for the extension with type code `typ`. It is an error to register the
+    /// same type code twice or to register a type code handled by Daphne itself.
+    pub fn register(
+        &mut self,
+        typ: u16,
+        handler: impl DapExtensionHandler + 'static,
+    ) -> Result<(), DapError> {
+        if typ == EXTENSION_TASKPROV || typ == EXTENSION_CLIENT_AUTH {
+            return Err(fatal_error!(
+                err = "tried to register a built-in extension",
+                code = Config,
+                typ
+            ));
+        }
+        if self.handlers.contains_key(&typ) {
+            return Err(fatal_error!(
+                err = "tried to register an extension twice",
+                code = Config,
+                typ
+            ));
+        }
+        self.handlers.insert(typ, Arc::new(handler));
+        Ok(())
+    }
+
+    /// Return `true` if a handler is registered for the extension with type code `typ`.
+    pub fn is_registered(&self, typ: u16) -> bool {
+        self.handlers.contains_key(&typ)
+    }
+
+    /// Check the extensions of a report for the given task. If the report should be rejected,
+    /// then the return value indicates why.
+    pub fn check(
+        &self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        extensions: &[Extension],
+    ) -> Result<(), TransitionFailure> {
+        for extension in extensions {
+            let Extension::Unhandled { typ, payload } = extension else {
+                // Built-in extensions are handled elsewhere.
+                continue;
+            };
+
+            match self.handlers.get(typ) {
+                Some(handler) => {
+                    handler
+                        .decode(payload)
+                        .map_err(|_| TransitionFailure::UnrecognizedMessage)?;
+                    handler.validate(task_id, task_config, payload)?;
+                }
+                None => match task_config.unknown_extensions {
+                    DapUnknownExtensionPolicy::Reject => {
+                        return Err(TransitionFailure::UnrecognizedMessage)
+                    }
+                    DapUnknownExtensionPolicy::Ignore => (),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Secret shared by the Aggregators with the Clients of a task, used for lightweight Client
+/// attestation. If a task has a key, then each report must carry the client authentication
+/// extension, whose payload is an HMAC-SHA256 tag under the key over the task ID and the report's
+/// ID and timestamp. The key is hex-encoded.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapClientAuthKey(#[serde(with = "hex")] [u8; 32]);
+
+impl DapClientAuthKey {
+    /// Generate a fresh key.
+    pub fn gen() -> Self {
+        Self(thread_rng().gen())
+    }
+
+    /// Compute the client authentication extension for the report with the given ID and
+    /// timestamp.
+    pub fn extension(&self, task_id: &TaskId, report_id: &ReportId, time: Time) -> Extension {
+        let tag = hmac::sign(
+            &self.hmac_key(),
+            &authenticated_message(task_id, report_id, time),
+        );
+        Extension::ClientAuth {
+            payload: tag.as_ref().to_vec(),
+        }
+    }
+
+    fn verify(&self, task_id: &TaskId, metadata: &ReportMetadata, tag: &[u8]) -> bool {
+        hmac::verify(
+            &self.hmac_key(),
+            &authenticated_message(task_id, &metadata.id, metadata.time),
+            tag,
+        )
+        .is_ok()
+    }
+
+    fn hmac_key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, &self.0)
+    }
+}
+
+// The key is deliberately not printed.
+impl std::fmt::Debug for DapClientAuthKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DapClientAuthKey(..)")
+    }
+}
+
+fn authenticated_message(task_id: &TaskId, report_id: &ReportId, time: Time) -> Vec<u8> {
+    let mut message = CLIENT_AUTH_CONTEXT.to_vec();
+    task_id.encode(&mut message);
+    report_id.encode(&mut message);
+    time.encode(&mut message);
+    message
+}
+
+/// Check the client authentication extension of a report for the given task. Reports for tasks
+/// without a client authentication key are always accepted; the extension is ignored.
+pub(crate) fn check_client_auth(
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    metadata: &ReportMetadata,
+    extensions: &[Extension],
+) -> Result<(), TransitionFailure> {
+    let Some(key) = &task_config.client_auth_key else {
+        return Ok(());
+    };
+
+    // Duplicate extensions are rejected when the report is decoded, so there is at most one tag.
+    let tag = extensions.iter().find_map(|extension| match extension {
+        Extension::ClientAuth { payload } => Some(payload),
+        _ => None,
+    });
+    match tag {
+        Some(tag) if key.verify(task_id, metadata, tag) => Ok(()),
+        _ => Err(TransitionFailure::ClientAuthFailed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DapExtensionHandler, DapExtensionRegistry, DapUnknownExtensionPolicy};
+    use crate::{
+        hpke::HpkeKemId,
+        messages::{Extension, TaskId, TransitionFailure, EXTENSION_TASKPROV},
+        test_versions,
+        testing::AggregationJobTest,
+        DapTaskConfig, DapVersion, Prio3Config, VdafConfig,
+    };
+    use prio::codec::CodecError;
+
+    /// Extension whose payload must be a single byte no larger than the task's minimum batch
+    /// size.
+    struct TestExtension;
+
+    impl DapExtensionHandler for TestExtension {
+        fn decode(&self, payload: &[u8]) -> Result<(), CodecError> {
+            match payload {
+                [_] => Ok(()),
+                _ => Err(CodecError::UnexpectedValue),
+            }
+        }
+
+        fn validate(
+            &self,
+            _task_id: &TaskId,
+            task_config: &DapTaskConfig,
+            payload: &[u8],
+        ) -> Result<(), TransitionFailure> {
+            if u64::from(payload[0]) <= task_config.min_batch_size {
+                Ok(())
+            } else {
+                Err(TransitionFailure::ReportDropped)
+            }
+        }
+    }
+
+    fn check_extensions(version: DapVersion) {
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            version,
+        );
+        let mut task_config = t.task_config.clone();
+        task_config.min_batch_size = 10;
+
+        let mut registry = DapExtensionRegistry::new();
+        registry.register(0x1337, TestExtension).unwrap();
+        assert!(registry.is_registered(0x1337));
+        assert!(registry.register(0x1337, TestExtension).is_err());
+        assert!(registry
+            .register(EXTENSION_TASKPROV, TestExtension)
+            .is_err());
+
+        let ext = |typ, payload: &[u8]| Extension::Unhandled {
+            typ,
+            payload: payload.to_vec(),
+        };
+        let check = |task_config: &DapTaskConfig, extensions: &[Extension]| {
+            registry.check(&t.task_id, task_config, extensions)
+        };
+
+        assert_eq!(check(&task_config, &[]), Ok(()));
+        assert_eq!(check(&task_config, &[ext(0x1337, &[10])]), Ok(()));
+        assert_eq!(
+            check(&task_config, &[ext(0x1337, &[10, 10])]),
+            Err(TransitionFailure::UnrecognizedMessage)
+        );
+        assert_eq!(
+            check(&task_config, &[ext(0x1337, &[11])]),
+            Err(TransitionFailure::ReportDropped)
+        );
+
+        // Unknown extensions are rejected unless the task says otherwise.
+        assert_eq!(
+            check(&task_config, &[ext(0x1337, &[1]), ext(0x1338, b"hi")]),
+            Err(TransitionFailure::UnrecognizedMessage)
+        );
+        task_config.unknown_extensions = DapUnknownExtensionPolicy::Ignore;
+        assert_eq!(
+            check(&task_config, &[ext(0x1337, &[1]), ext(0x1338, b"hi")]),
+            Ok(())
+        );
+    }
+
+    test_versions! { check_extensions }
+}
@@ -13,8 +13,11 @@ use hpke_rs_rust_crypto::HpkeRustCrypto as ImplHpkeCrypto;
 
 use crate::{
     fatal_error,
-    messages::{decode_u16_bytes, encode_u16_bytes, HpkeCiphertext, TaskId, TransitionFailure},
-    DapError, DapVersion,
+    messages::{
+        decode_u16_bytes, encode_base64url, encode_u16_bytes, HpkeCiphertextRef, TaskId,
+        TransitionFailure,
+    },
+    DapError, DapVersion, MaybeSendSync,
 };
 use async_trait::async_trait;
 use prio::codec::{CodecError, Decode, Encode};
@@ -25,7 +28,10 @@ use std::io::Cursor;
 const KEM_ID_X25519_HKDF_SHA256: u16 = 0x0020;
 const KEM_ID_P256_HKDF_SHA256: u16 = 0x0010;
 const KDF_ID_HKDF_SHA256: u16 = 0x0001;
+const KDF_ID_HKDF_SHA512: u16 = 0x0003;
 const AEAD_ID_AES128GCM: u16 = 0x0001;
+const AEAD_ID_AES256GCM: u16 = 0x0002;
+const AEAD_ID_CHACHA20POLY1305: u16 = 0x0003;
 
 impl From<HpkeError> for DapError {
     fn from(_e: HpkeError) -> Self {
@@ -39,28 +45,59 @@ impl From<Error> for DapError {
     }
 }
 
+/// Map the ciphersuite to the corresponding algorithms, or return `None` if any of them is not
+/// implemented. Any combination of the implemented KEMs, KDFs, and AEADs is supported.
+fn suite_algorithms(
+    kem_id: HpkeKemId,
+    kdf_id: HpkeKdfId,
+    aead_id: HpkeAeadId,
+) -> Option<(KemAlgorithm, KdfAlgorithm, AeadAlgorithm)> {
+    let kem = match kem_id {
+        HpkeKemId::P256HkdfSha256 => KemAlgorithm::DhKemP256,
+        HpkeKemId::X25519HkdfSha256 => KemAlgorithm::DhKem25519,
+        HpkeKemId::NotImplemented(..) => return None,
+    };
+    let kdf = match kdf_id {
+        HpkeKdfId::HkdfSha256 => KdfAlgorithm::HkdfSha256,
+        HpkeKdfId::HkdfSha512 => KdfAlgorithm::HkdfSha512,
+        HpkeKdfId::NotImplemented(..) => return None,
+    };
+    let aead = match aead_id {
+        HpkeAeadId::Aes128Gcm => AeadAlgorithm::Aes128Gcm,
+        HpkeAeadId::Aes256Gcm => AeadAlgorithm::Aes256Gcm,
+        HpkeAeadId::ChaCha20Poly1305 => AeadAlgorithm::ChaCha20Poly1305,
+        HpkeAeadId::NotImplemented(..) => return None,
+    };
+    Some((kem, kdf, aead))
+}
+
 fn check_suite<T: HpkeCrypto>(
     kem_id: HpkeKemId,
     kdf_id: HpkeKdfId,
     aead_id: HpkeAeadId,
 ) -> Result<Hpke<T>, DapError> {
-    let s = format!(
-        "HPKE ciphersuite not implemented ({}, {}, {})",
-        u16::from(kem_id),
-        u16::from(kdf_id),
-        u16::from(aead_id)
-    );
-    let maperr = |_| fatal_error!(err = s);
-    let kem = KemAlgorithm::try_from(u16::from(kem_id)).map_err(maperr)?;
-    let kdf = KdfAlgorithm::try_from(u16::from(kdf_id)).map_err(maperr)?;
-    let aead = AeadAlgorithm::try_from(u16::from(aead_id)).map_err(maperr)?;
-    match (kem, kdf, aead) {
-        (KemAlgorithm::DhKemP256, KdfAlgorithm::HkdfSha256, AeadAlgorithm::Aes128Gcm)
-        | (KemAlgorithm::DhKem25519, KdfAlgorithm::HkdfSha256, AeadAlgorithm::Aes128Gcm) => {
-            Ok(Hpke::new(Mode::Base, kem, kdf, aead))
-        }
-        _ => Err(fatal_error!(err = s)),
-    }
+    let (kem, kdf, aead) = suite_algorithms(kem_id, kdf_id, aead_id).ok_or_else(|| {
+        fatal_error!(
+            err = format!(
+                "HPKE ciphersuite not implemented ({}, {}, {})",
+                u16::from(kem_id),
+                u16::from(kdf_id),
+                u16::from(aead_id)
+            ),
+            code = Crypto,
+        )
+    })?;
+    Ok(Hpke::new(Mode::Base, kem, kdf, aead))
+}
+
+/// Check that the given HPKE ciphersuite is supported. Returns an error naming the codepoints if it
+/// is not.
+pub fn validate_suite(
+    kem_id: HpkeKemId,
+    kdf_id: HpkeKdfId,
+    aead_id: HpkeAeadId,
+) -> Result<(), DapError> {
+    check_suite::<ImplHpkeCrypto>(kem_id, kdf_id, aead_id).map(|_| ())
 }
 
 /// Codepoint for KEM schemes compatible with HPKE.
@@ -99,6 +136,7 @@ impl From<u16> for HpkeKemId {
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum HpkeKdfId {
     HkdfSha256,
+    HkdfSha512,
     NotImplemented(u16),
 }
 
@@ -106,6 +144,7 @@ impl From<HpkeKdfId> for u16 {
     fn from(kdf_id: HpkeKdfId) -> Self {
         match kdf_id {
             HpkeKdfId::HkdfSha256 => KDF_ID_HKDF_SHA256,
+            HpkeKdfId::HkdfSha512 => KDF_ID_HKDF_SHA512,
             HpkeKdfId::NotImplemented(x) => x,
         }
     }
@@ -115,6 +154,7 @@ impl From<u16> for HpkeKdfId {
     fn from(value: u16) -> Self {
         match value {
             KDF_ID_HKDF_SHA256 => Self::HkdfSha256,
+            KDF_ID_HKDF_SHA512 => Self::HkdfSha512,
             x => Self::NotImplemented(x),
         }
     }
@@ -126,6 +166,8 @@ impl From<u16> for HpkeKdfId {
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum HpkeAeadId {
     Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
     NotImplemented(u16),
 }
 
@@ -133,6 +175,8 @@ impl From<HpkeAeadId> for u16 {
     fn from(aead_id: HpkeAeadId) -> Self {
         match aead_id {
             HpkeAeadId::Aes128Gcm => AEAD_ID_AES128GCM,
+            HpkeAeadId::Aes256Gcm => AEAD_ID_AES256GCM,
+            HpkeAeadId::ChaCha20Poly1305 => AEAD_ID_CHACHA20POLY1305,
             HpkeAeadId::NotImplemented(x) => x,
         }
     }
@@ -142,6 +186,8 @@ impl From<u16> for HpkeAeadId {
     fn from(value: u16) -> Self {
         match value {
             AEAD_ID_AES128GCM => Self::Aes128Gcm,
+            AEAD_ID_AES256GCM => Self::Aes256Gcm,
+            AEAD_ID_CHACHA20POLY1305 => Self::ChaCha20Poly1305,
             x => Self::NotImplemented(x),
         }
     }
@@ -205,10 +251,11 @@ impl HpkeConfig {
 }
 
 /// HPKE decrypter functionality.
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 pub trait HpkeDecrypter {
     /// Return type of `get_hpke_config_for()`, wraps a reference to an HPKE config.
-    type WrappedHpkeConfig<'a>: AsRef<HpkeConfig>
+    type WrappedHpkeConfig<'a>: AsRef<HpkeConfig> + MaybeSendSync
     where
         Self: 'a;
 
@@ -219,6 +266,22 @@ pub trait HpkeDecrypter {
         task_id: Option<&TaskId>,
     ) -> Result<Self::WrappedHpkeConfig<'s>, DapError>;
 
+    /// Look up the HPKE configurations to advertise for the given task ID (if specified), in
+    /// order of preference. This is used for versions of DAP that allow the Aggregator to advertise
+    /// more than one configuration, e.g., while a key is being rotated. By default, only the
+    /// configuration returned by `get_hpke_config_for()` is advertised.
+    async fn get_hpke_config_list_for(
+        &self,
+        version: DapVersion,
+        task_id: Option<&TaskId>,
+    ) -> Result<Vec<HpkeConfig>, DapError> {
+        Ok(vec![self
+            .get_hpke_config_for(version, task_id)
+            .await?
+            .as_ref()
+            .clone()])
+    }
+
     /// Returns `true` if a ciphertext with the HPKE config ID can be consumed in the current task.
     async fn can_hpke_decrypt(&self, task_id: &TaskId, config_id: u8) -> Result<bool, DapError>;
 
@@ -228,10 +291,107 @@ pub trait HpkeDecrypter {
         task_id: &TaskId,
         info: &[u8],
         aad: &[u8],
-        ciphertext: &HpkeCiphertext,
+        ciphertext: HpkeCiphertextRef<'_>,
+    ) -> Result<Vec<u8>, DapError>;
+}
+
+/// A store of HPKE private keys. Decryption is delegated to the store, so that a deployment can
+/// keep its private keys out of the Aggregator's storage, e.g., in an external key management
+/// service (KMS).
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait HpkePrivateKeyStore {
+    /// Decrypt `ciphertext` with info string `info` and associated data `aad` using the private
+    /// key corresponding to `config`. The return value is `None` if the store does not hold the
+    /// private key.
+    async fn hpke_decrypt_with(
+        &self,
+        config: &HpkeConfig,
+        info: &[u8],
+        aad: &[u8],
+        enc: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Option<Vec<u8>>, DapError>;
+}
+
+/// Private keys held in memory, e.g., after being loaded from the environment.
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+impl HpkePrivateKeyStore for Vec<HpkeReceiverConfig> {
+    async fn hpke_decrypt_with(
+        &self,
+        config: &HpkeConfig,
+        info: &[u8],
+        aad: &[u8],
+        enc: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Option<Vec<u8>>, DapError> {
+        self.iter()
+            .find(|receiver| &receiver.config == config)
+            .map(|receiver| receiver.decrypt(info, aad, enc, ciphertext))
+            .transpose()
+    }
+}
+
+/// Client of a KMS that holds HPKE private keys. The KMS performs the decryption itself, so the
+/// private keys never leave it.
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait HpkeKmsClient {
+    /// Decrypt `ciphertext` with the private key that the KMS stores under `key_id`. `config` is
+    /// the HPKE config that the key belongs to.
+    async fn hpke_decrypt(
+        &self,
+        key_id: &str,
+        config: &HpkeConfig,
+        info: &[u8],
+        aad: &[u8],
+        enc: &[u8],
+        ciphertext: &[u8],
     ) -> Result<Vec<u8>, DapError>;
 }
 
+/// Private keys held by a KMS, each referenced by the ID under which the KMS stores it.
+pub struct HpkeKmsKeyStore<C> {
+    client: C,
+    key_ids: Vec<(HpkeConfig, String)>,
+}
+
+impl<C> HpkeKmsKeyStore<C> {
+    /// Create a store for the given HPKE configs, each paired with the ID of its private key.
+    pub fn new(client: C, key_ids: impl IntoIterator<Item = (HpkeConfig, String)>) -> Self {
+        Self {
+            client,
+            key_ids: key_ids.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+impl<C: HpkeKmsClient + MaybeSendSync> HpkePrivateKeyStore for HpkeKmsKeyStore<C> {
+    async fn hpke_decrypt_with(
+        &self,
+        config: &HpkeConfig,
+        info: &[u8],
+        aad: &[u8],
+        enc: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Option<Vec<u8>>, DapError> {
+        let Some((_, key_id)) = self
+            .key_ids
+            .iter()
+            .find(|(key_config, _)| key_config == config)
+        else {
+            return Ok(None);
+        };
+        self.client
+            .hpke_decrypt(key_id, config, info, aad, enc, ciphertext)
+            .await
+            .map(Some)
+    }
+}
+
 /// Struct that combines HpkeConfig and HpkeSecretKey
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct HpkeReceiverConfig {
@@ -271,18 +431,73 @@ impl HpkeReceiverConfig {
             .decrypt(&self.private_key, info, aad, enc, ciphertext)
     }
 
-    /// Generate and return a new HPKE receiver context given a HPKE config ID and HPKE KEM.
+    /// Generate and return a new HPKE receiver context given a HPKE config ID and HPKE KEM. The
+    /// KDF is HKDF-SHA256 and the AEAD is AES-128-GCM.
     pub fn gen(id: u8, kem_id: HpkeKemId) -> Result<Self, DapError> {
-        let kem = match kem_id {
-            HpkeKemId::P256HkdfSha256 => KemAlgorithm::DhKemP256,
-            HpkeKemId::X25519HkdfSha256 => KemAlgorithm::DhKem25519,
-            HpkeKemId::NotImplemented(x) => {
-                return Err(fatal_error!(err = "Unsupported KEM", kem = ?x))
-            }
-        };
-        let kdf = KdfAlgorithm::HkdfSha256;
-        let aead = AeadAlgorithm::Aes128Gcm;
-        let generator = Hpke::<ImplHpkeCrypto>::new(Mode::Base, kem, kdf, aead);
+        Self::gen_for(id, kem_id, HpkeKdfId::HkdfSha256, HpkeAeadId::Aes128Gcm)
+    }
+
+    /// Generate a new HPKE receiver context for the given config ID and ciphersuite and check that
+    /// it is usable before returning it. This is the code path provisioning tools should use to
+    /// generate keys: the ciphersuite is validated, and the new key pair must pass
+    /// [`self_test`](Self::self_test).
+    pub fn gen_for(
+        id: u8,
+        kem_id: HpkeKemId,
+        kdf_id: HpkeKdfId,
+        aead_id: HpkeAeadId,
+    ) -> Result<Self, DapError> {
+        validate_suite(kem_id, kdf_id, aead_id)?;
+        let receiver_config = Self::gen_with_suite(id, kem_id, kdf_id, aead_id)?;
+        receiver_config.self_test()?;
+        Ok(receiver_config)
+    }
+
+    /// Check that this receiver context is usable: the ciphersuite is supported, the public key
+    /// corresponds to the private key, and a message encrypted to the public key decrypts to the
+    /// original plaintext.
+    pub fn self_test(&self) -> Result<(), DapError> {
+        validate_suite(self.config.kem_id, self.config.kdf_id, self.config.aead_id)?;
+        check_key_pair(&self.config, &self.private_key)?;
+
+        let info = b"daphne hpke self-test info";
+        let aad = b"daphne hpke self-test aad";
+        let plaintext = b"daphne hpke self-test plaintext";
+        let (enc, ciphertext) = self.encrypt(info, aad, plaintext)?;
+        let decrypted = self.decrypt(info, aad, &enc, &ciphertext).map_err(|e| {
+            fatal_error!(
+                err = ?e,
+                code = Crypto,
+                config_id = self.config.id,
+                "HPKE self-test failed to decrypt",
+            )
+        })?;
+        if decrypted != plaintext {
+            return Err(fatal_error!(
+                err = "HPKE self-test decrypted to the wrong plaintext",
+                code = Crypto,
+                config_id = self.config.id,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Export this receiver context for provisioning. See [`HpkeReceiverConfigExport`].
+    pub fn export(&self) -> HpkeReceiverConfigExport {
+        HpkeReceiverConfigExport {
+            receiver_config: self.clone(),
+            hpke_config: encode_base64url(self.config.get_encoded()),
+        }
+    }
+
+    /// Generate and return a new HPKE receiver context given a HPKE config ID and ciphersuite.
+    pub fn gen_with_suite(
+        id: u8,
+        kem_id: HpkeKemId,
+        kdf_id: HpkeKdfId,
+        aead_id: HpkeAeadId,
+    ) -> Result<Self, DapError> {
+        let generator: Hpke<ImplHpkeCrypto> = check_suite(kem_id, kdf_id, aead_id)?;
         match generator.generate_key_pair() {
             Ok(keypair) => {
                 let (private_key, public_key) = keypair.into_keys();
@@ -290,15 +505,16 @@ impl HpkeReceiverConfig {
                     config: HpkeConfig {
                         id,
                         kem_id,
-                        kdf_id: HpkeKdfId::HkdfSha256,
-                        aead_id: HpkeAeadId::Aes128Gcm,
+                        kdf_id,
+                        aead_id,
                         public_key,
                     },
                     private_key,
                 })
             }
             Err(e) => Err(fatal_error!(
-                err = format!("{e:?}"), // `HpkeError` doesn't implement Display or Error :(
+                err = format!("{e:?}"),
+                code = Crypto, // `HpkeError` doesn't implement Display or Error :(
                 ?kem_id,
                 "bad key generation for KEM",
             )),
@@ -311,24 +527,52 @@ impl TryFrom<(HpkeConfig, HpkePrivateKey)> for HpkeReceiverConfig {
     /// Create a new HPKE receiver context given an HpkeConfig and a corresponding private key.
     /// Returns an error if the public key does not correspond to the private_key.
     fn try_from((config, private_key): (HpkeConfig, HpkePrivateKey)) -> Result<Self, Self::Error> {
-        let kem_id_u16: u16 = config.kem_id.into();
-        let kem_id: KemAlgorithm = kem_id_u16.try_into().unwrap();
-        let public_key = HpkePublicKey::from(ImplHpkeCrypto::kem_derive_base(
-            kem_id,
-            private_key.as_slice(),
-        )?);
-        if public_key == config.public_key {
-            Ok(Self {
-                config,
-                private_key,
-            })
-        } else {
-            Err(fatal_error!(err = "public key does not match private key"))
-        }
+        check_key_pair(&config, &private_key)?;
+        Ok(Self {
+            config,
+            private_key,
+        })
     }
 }
 
-#[async_trait(?Send)]
+/// Check that the public key of `config` is derived from `private_key`.
+fn check_key_pair(config: &HpkeConfig, private_key: &HpkePrivateKey) -> Result<(), DapError> {
+    let kem_id_u16: u16 = config.kem_id.into();
+    let kem_id: KemAlgorithm = kem_id_u16.try_into().map_err(|_| {
+        fatal_error!(
+            err = "HPKE KEM not implemented",
+            code = Crypto,
+            kem_id = kem_id_u16
+        )
+    })?;
+    let public_key = HpkePublicKey::from(ImplHpkeCrypto::kem_derive_base(
+        kem_id,
+        private_key.as_slice(),
+    )?);
+    if public_key == config.public_key {
+        Ok(())
+    } else {
+        Err(fatal_error!(
+            err = "public key does not match private key",
+            code = Crypto
+        ))
+    }
+}
+
+/// Serialization of a newly generated HPKE receiver context for provisioning. The receiver context
+/// (including the private key) is kept by the Aggregator or Collector; the encoded public config is
+/// what gets distributed to peers, e.g., as the `collector_hpke_config` of a
+/// [`DapTaskDescription`](crate::provisioning::DapTaskDescription).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct HpkeReceiverConfigExport {
+    pub receiver_config: HpkeReceiverConfig,
+
+    /// The TLS-encoded [`HpkeConfig`], base64url-encoded.
+    pub hpke_config: String,
+}
+
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl HpkeDecrypter for HpkeReceiverConfig {
     type WrappedHpkeConfig<'a> = HpkeConfig;
 
@@ -349,12 +593,12 @@ impl HpkeDecrypter for HpkeReceiverConfig {
         _task_id: &TaskId,
         info: &[u8],
         aad: &[u8],
-        ciphertext: &HpkeCiphertext,
+        ciphertext: HpkeCiphertextRef<'_>,
     ) -> Result<Vec<u8>, DapError> {
         if ciphertext.config_id != self.config.id {
             return Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId));
         }
-        self.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)
+        self.decrypt(info, aad, ciphertext.enc, ciphertext.payload)
     }
 }
 
@@ -418,10 +662,19 @@ impl From<HpkePrivateKeySerde> for HpkePrivateKey {
 
 #[cfg(test)]
 mod test {
-    use crate::hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId, HpkeReceiverConfig};
+    use crate::{
+        hpke::{
+            HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId, HpkeKmsClient, HpkeKmsKeyStore,
+            HpkePrivateKeyStore, HpkeReceiverConfig, HpkeReceiverConfigExport,
+        },
+        messages::decode_base64url_vec,
+        DapError,
+    };
+    use async_trait::async_trait;
     use hpke_rs::{Hpke, HpkePrivateKey, HpkePublicKey, Mode};
     use hpke_rs_crypto::types::{AeadAlgorithm, KdfAlgorithm, KemAlgorithm};
     use hpke_rs_rust_crypto::HpkeRustCrypto as ImplHpkeCrypto;
+    use prio::codec::Decode;
 
     #[test]
     fn encrypt_roundtrip_x25519_hkdf_sha256() {
@@ -451,6 +704,99 @@ mod test {
         );
     }
 
+    #[test]
+    fn encrypt_roundtrip_all_suites() {
+        let info = b"info string";
+        let aad = b"associated data";
+        let plaintext = b"plaintext";
+        for kem_id in [HpkeKemId::P256HkdfSha256, HpkeKemId::X25519HkdfSha256] {
+            for kdf_id in [HpkeKdfId::HkdfSha256, HpkeKdfId::HkdfSha512] {
+                for aead_id in [
+                    HpkeAeadId::Aes128Gcm,
+                    HpkeAeadId::Aes256Gcm,
+                    HpkeAeadId::ChaCha20Poly1305,
+                ] {
+                    let config =
+                        HpkeReceiverConfig::gen_with_suite(23, kem_id, kdf_id, aead_id).unwrap();
+                    let (enc, ciphertext) = config.encrypt(info, aad, plaintext).unwrap();
+                    assert_eq!(
+                        config.decrypt(info, aad, &enc, &ciphertext).unwrap(),
+                        plaintext,
+                        "{kem_id:?}, {kdf_id:?}, {aead_id:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn suite_not_implemented() {
+        // HKDF-SHA384
+        assert_eq!(HpkeKdfId::from(0x0002), HpkeKdfId::NotImplemented(0x0002));
+        assert!(HpkeReceiverConfig::gen_with_suite(
+            23,
+            HpkeKemId::X25519HkdfSha256,
+            HpkeKdfId::from(0x0002),
+            HpkeAeadId::Aes128Gcm,
+        )
+        .is_err());
+
+        // Export-only AEAD
+        assert!(HpkeReceiverConfig::gen_with_suite(
+            23,
+            HpkeKemId::X25519HkdfSha256,
+            HpkeKdfId::HkdfSha256,
+            HpkeAeadId::from(0xffff),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn gen_for() {
+        let receiver_config = HpkeReceiverConfig::gen_for(
+            23,
+            HpkeKemId::P256HkdfSha256,
+            HpkeKdfId::HkdfSha512,
+            HpkeAeadId::ChaCha20Poly1305,
+        )
+        .unwrap();
+        assert_eq!(receiver_config.config.id, 23);
+        assert_eq!(receiver_config.config.kdf_id, HpkeKdfId::HkdfSha512);
+
+        assert!(HpkeReceiverConfig::gen_for(
+            23,
+            HpkeKemId::NotImplemented(0x0011),
+            HpkeKdfId::HkdfSha256,
+            HpkeAeadId::Aes128Gcm,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn self_test_mismatched_key_pair() {
+        let mut receiver_config = HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256).unwrap();
+        receiver_config.self_test().unwrap();
+
+        let other = HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256).unwrap();
+        receiver_config.config.public_key = other.config.public_key;
+        assert!(receiver_config.self_test().is_err());
+    }
+
+    #[test]
+    fn export_roundtrip() {
+        let receiver_config = HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256).unwrap();
+        let export = receiver_config.export();
+
+        let export: HpkeReceiverConfigExport =
+            serde_json::from_str(&serde_json::to_string(&export).unwrap()).unwrap();
+        assert_eq!(export.receiver_config, receiver_config);
+        export.receiver_config.self_test().unwrap();
+        assert_eq!(
+            HpkeConfig::get_decoded(&decode_base64url_vec(export.hpke_config).unwrap()).unwrap(),
+            receiver_config.config
+        );
+    }
+
     #[test]
     fn hpke_receiver_config_try_from() {
         let (private_key, public_key) = Hpke::<ImplHpkeCrypto>::new(
@@ -481,4 +827,54 @@ mod test {
         let bad_private_key = HpkePrivateKey::from(vec![0; 20]);
         assert!(HpkeReceiverConfig::try_from((config, bad_private_key)).is_err());
     }
+
+    struct MockKms(Vec<(String, HpkeReceiverConfig)>);
+
+    #[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+    #[cfg_attr(feature = "send-traits", async_trait)]
+    impl HpkeKmsClient for MockKms {
+        async fn hpke_decrypt(
+            &self,
+            key_id: &str,
+            _config: &HpkeConfig,
+            info: &[u8],
+            aad: &[u8],
+            enc: &[u8],
+            ciphertext: &[u8],
+        ) -> Result<Vec<u8>, DapError> {
+            let (_, receiver) = self.0.iter().find(|(id, _)| id == key_id).unwrap();
+            receiver.decrypt(info, aad, enc, ciphertext)
+        }
+    }
+
+    #[tokio::test]
+    async fn private_key_stores() {
+        let receiver = HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256).unwrap();
+        let other = HpkeReceiverConfig::gen(24, HpkeKemId::X25519HkdfSha256).unwrap();
+        let (enc, ciphertext) = receiver.encrypt(b"info", b"aad", b"plaintext").unwrap();
+
+        let in_memory = vec![receiver.clone()];
+        let kms = HpkeKmsKeyStore::new(
+            MockKms(vec![("key-23".into(), receiver.clone())]),
+            [(receiver.config.clone(), "key-23".to_string())],
+        );
+        let stores: [&dyn HpkePrivateKeyStore; 2] = [&in_memory, &kms];
+        for store in stores {
+            assert_eq!(
+                store
+                    .hpke_decrypt_with(&receiver.config, b"info", b"aad", &enc, &ciphertext)
+                    .await
+                    .unwrap()
+                    .unwrap(),
+                b"plaintext"
+            );
+
+            // The store does not hold the private key for the config.
+            assert!(store
+                .hpke_decrypt_with(&other.config, b"info", b"aad", &enc, &ciphertext)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
 }
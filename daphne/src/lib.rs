@@ -41,34 +41,50 @@
 
 pub mod audit_log;
 pub mod auth;
+pub mod client;
 pub mod constants;
+pub mod content_encoding;
+#[cfg(any(test, feature = "diagnostics"))]
+pub mod diagnostics;
 pub mod error;
+pub mod extensions;
 pub mod hpke;
+pub mod memory_budget;
 pub mod messages;
 pub mod metrics;
+pub mod postprocess;
+pub mod provisioning;
+pub mod receipt;
 pub mod roles;
 pub mod taskprov;
 #[cfg(any(test, feature = "test-utils"))]
 pub mod testing;
+pub mod time;
 pub mod vdaf;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 use crate::{
-    error::DapAbort,
+    error::{aborts::ProblemDetails, DapAbort},
+    extensions::{DapClientAuthKey, DapUnknownExtensionPolicy},
     hpke::HpkeReceiverConfig,
     messages::{
-        AggregationJobId, BatchId, BatchSelector, Collection, CollectionJobId,
-        Draft02AggregationJobId, Duration, Interval, PartialBatchSelector, ReportId, TaskId, Time,
+        decode_u16_bytes, encode_u16_bytes, AggregationJobId, BatchId, BatchSelector, Collection,
+        CollectionJobId, Draft02AggregationJobId, Duration, Interval, PartialBatchSelector, Report,
+        ReportId, ReportMetadata, TaskId, Time,
     },
-    taskprov::TaskprovVersion,
-    vdaf::{VdafAggregateShare, VdafPrepMessage, VdafPrepState, VdafVerifyKey},
+    receipt::UploadReceiptSigningKey,
+    taskprov::{TaskprovPolicy, TaskprovVersion},
+    vdaf::{VdafAggregateShare, VdafPrepMessage, VdafPrepState, VdafVerifyKey, VdafVerifyKeySet},
 };
 use constants::DapMediaType;
+use content_encoding::DapContentEncoding;
 #[cfg(test)]
 use criterion as _;
 pub use error::DapError;
 use hpke::{HpkeConfig, HpkeKemId};
 use prio::{
-    codec::{Decode, Encode, ParameterizedDecode},
+    codec::{decode_u32_items, encode_u32_items, CodecError, Decode, Encode, ParameterizedDecode},
     vdaf::Aggregatable as AggregatableTrait,
 };
 use rand::prelude::*;
@@ -76,12 +92,35 @@ use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
     cmp::{max, min},
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::{Debug, Display},
+    io::{Cursor, Read},
 };
 use url::Url;
 use vdaf::{EarlyReportState, EarlyReportStateConsumed};
 
+/// Bound on the types that the role traits (e.g., [`DapLeader`](crate::roles::DapLeader)) hold
+/// across `.await` points. By default, the futures returned by these traits are not `Send`, as is
+/// required for Workers, and every type satisfies this bound. With the `send-traits` feature, the
+/// futures are `Send`, so that the traits can be used with multi-threaded executors, and this bound
+/// requires `Send + Sync`.
+#[cfg(feature = "send-traits")]
+pub trait MaybeSendSync: Send + Sync {}
+
+#[cfg(feature = "send-traits")]
+impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+
+/// Bound on the types that the role traits (e.g., [`DapLeader`](crate::roles::DapLeader)) hold
+/// across `.await` points. By default, the futures returned by these traits are not `Send`, as is
+/// required for Workers, and every type satisfies this bound. With the `send-traits` feature, the
+/// futures are `Send`, so that the traits can be used with multi-threaded executors, and this bound
+/// requires `Send + Sync`.
+#[cfg(not(feature = "send-traits"))]
+pub trait MaybeSendSync {}
+
+#[cfg(not(feature = "send-traits"))]
+impl<T: ?Sized> MaybeSendSync for T {}
+
 /// DAP version used for a task.
 #[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
@@ -118,6 +157,20 @@ impl AsRef<str> for DapVersion {
     }
 }
 
+impl DapVersion {
+    /// The versions implemented by Daphne.
+    pub const IMPLEMENTED: [DapVersion; 2] = [DapVersion::Draft02, DapVersion::Draft07];
+
+    /// Return `true` if Clients may upload a report with `PUT /tasks/{task_id}/reports/{report_id}`,
+    /// i.e., with the report ID in the request path.
+    pub fn supports_report_id_in_upload_path(&self) -> bool {
+        match self {
+            DapVersion::Draft02 | DapVersion::Unknown => false,
+            DapVersion::Draft07 => true,
+        }
+    }
+}
+
 impl std::fmt::Display for DapVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.as_ref())
@@ -132,7 +185,8 @@ pub struct DapGlobalConfig {
     /// which an Aggregator guarantees storage of reports and/or report metadata.
     ///
     /// A report will be accepted if its timestamp is no more than the specified number of seconds
-    /// before the current time.
+    /// before the current time. This is the default for tasks that don't set their own duration
+    /// (see [`DapTaskConfig::report_storage_epoch_duration`]).
     pub report_storage_epoch_duration: Duration,
 
     /// The report storage maximum future time skew. Reports with timestamps greater than the
@@ -164,6 +218,117 @@ pub struct DapGlobalConfig {
     /// Is the taskprov extension allowed and which taskprov draft should be used?
     #[serde(default)]
     pub taskprov_version: Option<TaskprovVersion>,
+
+    /// If set, then the Leader compresses each AggregationJobInitReq whose encoding is larger
+    /// than this many bytes before sending it to the Helper.
+    #[serde(default)]
+    pub agg_job_init_req_compression_threshold: Option<usize>,
+
+    /// Policy for retrying idempotent requests from the Leader to the Helper.
+    #[serde(default)]
+    pub helper_request_retry: DapRetryConfig,
+
+    /// Key used by the Leader to sign upload receipts for tasks that enable them. If not set,
+    /// then no receipts are issued.
+    #[serde(default)]
+    pub upload_receipt_signing_key: Option<UploadReceiptSigningKey>,
+
+    /// Helper: If set, then an aggregation job is aborted if the fraction of report shares in the
+    /// AggregationJobInitReq that are accepted is less than this value. A high rejection rate
+    /// usually indicates a key or configuration mismatch between the Aggregators rather than
+    /// misbehaving Clients.
+    #[serde(default)]
+    pub agg_job_min_acceptance_ratio: Option<f64>,
+
+    /// DAP versions served by the Aggregator. If not set, then every version implemented by
+    /// Daphne is served.
+    #[serde(default)]
+    pub enabled_versions: Option<Vec<DapVersion>>,
+
+    /// Leader: If set, then uploaded reports whose timestamps are not a multiple of the task's
+    /// time precision are rejected. Clients are required to truncate timestamps so that the
+    /// Aggregators do not learn more about when a measurement was taken than necessary. Note that
+    /// the Leader cannot truncate the timestamp itself, since it is bound to the encrypted input
+    /// shares.
+    #[serde(default)]
+    pub reject_unquantized_report_times: bool,
+
+    /// The largest dimension permitted for tasks that use Prio2. Preparing a report allocates
+    /// memory linear in the dimension, so this bounds the resources an Aggregator spends on each
+    /// report. If not set, then the dimension is limited only by the capacity of the field.
+    #[serde(default)]
+    pub max_prio2_dimension: Option<usize>,
+
+    /// taskprov: Constraints on the tasks the Aggregator opts into. A task that violates the
+    /// policy is rejected before `DapAggregator::taskprov_opt_out_reason()` is consulted.
+    #[serde(default)]
+    pub taskprov_policy: TaskprovPolicy,
+
+    /// Leader: If set, then an upload that fails because the report could not be stored, e.g.,
+    /// because storage is overloaded, is rejected with a "tooManyRequests" abort that asks the
+    /// Client to retry after this many seconds. If not set, then the upload fails with an
+    /// internal error.
+    #[serde(default)]
+    pub upload_overload_retry_after: Option<Duration>,
+
+    /// Helper: If set, then the memory used by aggregation jobs that are initialized concurrently
+    /// is limited. A job that would exceed the limit is shed with a "tooManyRequests" abort.
+    #[serde(default)]
+    pub agg_job_memory_budget: Option<DapMemoryBudgetConfig>,
+}
+
+/// Limit on the estimated memory used by in-flight aggregation jobs. See
+/// [`memory_budget::MemoryBudget`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapMemoryBudgetConfig {
+    /// Maximum number of bytes reserved by in-flight aggregation jobs. This should leave headroom
+    /// below the memory limit of the platform, since the reservations are estimates.
+    pub limit_bytes: u64,
+
+    /// Number of seconds after which the Leader is asked to retry an aggregation job that was
+    /// shed.
+    pub retry_after: Duration,
+}
+
+/// Policy for retrying a request that failed due to a transient error, such as a network failure
+/// or a 5xx response from the peer. Requests that were rejected with a DAP abort are not retried.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapRetryConfig {
+    /// Maximum number of attempts, including the first. A value of 1 disables retries.
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, in milliseconds. The delay doubles with each retry.
+    pub initial_delay_ms: u64,
+
+    /// Upper bound on the delay between attempts, in milliseconds.
+    pub max_delay_ms: u64,
+}
+
+impl Default for DapRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay_ms: 100,
+            max_delay_ms: 2000,
+        }
+    }
+}
+
+impl DapRetryConfig {
+    /// Return the delay before retry number `retry` (starting from 0). The delay is chosen
+    /// uniformly at random from the upper half of the backoff interval in order to spread out
+    /// retries from concurrent requests.
+    pub fn delay_for_retry(&self, retry: u32) -> std::time::Duration {
+        let backoff = self
+            .initial_delay_ms
+            .saturating_mul(1 << retry.min(32))
+            .min(self.max_delay_ms);
+        let delay = thread_rng().gen_range(backoff / 2..=backoff);
+        std::time::Duration::from_millis(delay)
+    }
 }
 
 impl DapGlobalConfig {
@@ -181,11 +346,114 @@ impl DapGlobalConfig {
             HpkeReceiverConfig::gen(config_id, kem_id)
         })
     }
+
+    /// Return `true` if the Aggregator serves the given DAP version.
+    pub fn is_version_enabled(&self, version: DapVersion) -> bool {
+        DapVersion::IMPLEMENTED.contains(&version)
+            && self
+                .enabled_versions
+                .as_ref()
+                .map_or(true, |enabled_versions| enabled_versions.contains(&version))
+    }
+
+    /// Check that the VDAF configured for a task is valid and within the limits of this
+    /// configuration.
+    pub fn check_vdaf_config(&self, task_id: &TaskId, vdaf: &VdafConfig) -> Result<(), DapAbort> {
+        match vdaf {
+            VdafConfig::Prio2 { dimension } => {
+                if self
+                    .max_prio2_dimension
+                    .is_some_and(|max_dimension| *dimension > max_dimension)
+                {
+                    return Err(DapAbort::InvalidTask {
+                        detail: format!("Prio2 dimension {dimension} exceeds the maximum"),
+                        task_id: task_id.clone(),
+                    });
+                }
+                prio::vdaf::prio2::Prio2::new(*dimension).map_err(|e| DapAbort::InvalidTask {
+                    detail: format!("invalid Prio2 dimension {dimension}: {e}"),
+                    task_id: task_id.clone(),
+                })?;
+            }
+            VdafConfig::Prio3(..) => (),
+        }
+        Ok(())
+    }
+
+    /// Check that the configuration is usable. This is intended to be called when the
+    /// configuration is loaded so that a bad value is caught before it's used.
+    pub fn validate(&self) -> Result<(), DapError> {
+        if self.report_storage_epoch_duration == 0 {
+            return Err(fatal_error!(
+                err = "report storage epoch duration must be positive",
+                code = Config
+            ));
+        }
+        if let Some(ratio) = self.agg_job_min_acceptance_ratio {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(fatal_error!(
+                    err = "minimum acceptance ratio must be between 0 and 1",
+                    code = Config,
+                    agg_job_min_acceptance_ratio = ratio
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe the features supported by an Aggregator with this configuration.
+    pub fn capabilities(&self) -> DapCapabilities {
+        DapCapabilities {
+            versions: DapVersion::IMPLEMENTED
+                .into_iter()
+                .filter(|version| self.is_version_enabled(*version))
+                .collect(),
+            vdafs: [
+                "prio2",
+                "prio3_count",
+                "prio3_sum",
+                "prio3_histogram",
+                "prio3_sum_vec",
+            ]
+            .map(String::from)
+            .into(),
+            query_types: ["time_interval", "fixed_size"].map(String::from).into(),
+            hpke_kems: self.supported_hpke_kems.clone(),
+            max_batch_duration: self.max_batch_duration,
+            max_decoded_body_size: content_encoding::MAX_DECODED_BODY_SIZE,
+            taskprov_version: self.taskprov_version,
+        }
+    }
+}
+
+/// Machine-readable description of the features supported by an Aggregator. This is served as
+/// JSON so that peers can check compatibility before provisioning a task.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DapCapabilities {
+    /// Supported DAP versions.
+    pub versions: Vec<DapVersion>,
+
+    /// Supported VDAF types. The parameters of each VDAF are configured per task.
+    pub vdafs: Vec<String>,
+
+    /// Supported query types.
+    pub query_types: Vec<String>,
+
+    /// HPKE KEM types for which the Aggregator has a receiver config.
+    pub hpke_kems: Vec<HpkeKemId>,
+
+    /// Maximum interval duration permitted in a collection request.
+    pub max_batch_duration: Duration,
+
+    /// Maximum size in bytes of a request body after it is decompressed.
+    pub max_decoded_body_size: u64,
+
+    /// The taskprov draft supported, if any.
+    pub taskprov_version: Option<TaskprovVersion>,
 }
 
 /// DAP Query configuration.
 //
-// TODO(cjpatton) Once we implement maximum batch lifetime, put the parameter here.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
@@ -196,7 +464,15 @@ pub enum DapQueryConfig {
 
     /// The "fixed-size" query type. The Leader partitions the reports into arbitary batches of
     /// roughly the same size.
-    FixedSize { max_batch_size: u64 },
+    ///
+    /// If `batch_time_limit` is set, then the Leader stops filling a batch once it is older than
+    /// the limit (in seconds). The batch remains collectable if it contains at least
+    /// `min_batch_size` reports; otherwise it is discarded.
+    FixedSize {
+        max_batch_size: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        batch_time_limit: Option<Duration>,
+    },
 }
 
 impl DapQueryConfig {
@@ -242,15 +518,133 @@ impl std::fmt::Display for DapQueryConfig {
 /// queries, the bucket to which a report is assigned is determined by truncating its timestamp by
 /// the task's `time_precision` parameter; for fixed-size queries, the span consists of a single
 /// bucket, which is the batch determined by the batch ID (i.e., the partial batch selector).
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+///
+/// If the task has a [`sub_bucket_precision`](DapTaskConfig::sub_bucket_precision), then each
+/// time-interval bucket is further divided into sub-buckets, each of which is aggregated
+/// separately. The aggregate share of the bucket is then the sum of its sub-buckets.
+///
+/// The bucket is serialized as the string rendered by its [`Display`] implementation.
+#[derive(Debug, Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+#[serde(try_from = "String", into = "String")]
 pub enum DapBatchBucket {
-    FixedSize { batch_id: BatchId },
-    TimeInterval { batch_window: Time },
+    FixedSize {
+        batch_id: BatchId,
+    },
+    TimeInterval {
+        batch_window: Time,
+    },
+    TimeIntervalSub {
+        batch_window: Time,
+        sub_window: Time,
+    },
+}
+
+/// The bucket is rendered as "batch/<batch ID in hex>", "window/<batch window>", or
+/// "window/<batch window>/sub/<sub window>". This string is used to name storage for the bucket
+/// and is parsed back by the [`std::str::FromStr`] implementation, so it must not change.
+impl Display for DapBatchBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FixedSize { batch_id } => write!(f, "batch/{}", batch_id.to_hex()),
+            Self::TimeInterval { batch_window } => write!(f, "window/{batch_window}"),
+            Self::TimeIntervalSub {
+                batch_window,
+                sub_window,
+            } => write!(f, "window/{batch_window}/sub/{sub_window}"),
+        }
+    }
+}
+
+impl std::str::FromStr for DapBatchBucket {
+    type Err = DapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bucket = match s.split_once('/') {
+            Some(("batch", batch_id_hex)) => {
+                BatchId::try_from_hex(batch_id_hex).map(|batch_id| Self::FixedSize { batch_id })
+            }
+            Some(("window", rest)) => match rest.split_once("/sub/") {
+                Some((batch_window, sub_window)) => {
+                    batch_window.parse().ok().zip(sub_window.parse().ok()).map(
+                        |(batch_window, sub_window)| Self::TimeIntervalSub {
+                            batch_window,
+                            sub_window,
+                        },
+                    )
+                }
+                None => rest
+                    .parse()
+                    .ok()
+                    .map(|batch_window| Self::TimeInterval { batch_window }),
+            },
+            _ => None,
+        };
+        bucket.ok_or_else(|| fatal_error!(err = "malformed batch bucket", bucket = s))
+    }
+}
+
+impl TryFrom<String> for DapBatchBucket {
+    type Error = DapError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DapBatchBucket> for String {
+    fn from(bucket: DapBatchBucket) -> Self {
+        bucket.to_string()
+    }
+}
+
+/// A time-interval batch bucket computed for a report ahead of aggregation (see
+/// [`DapTaskConfig::precompute_batch_bucket`]), along with the task parameters it was computed
+/// with. The bucket is only valid as long as these parameters don't change.
+#[derive(Debug, Clone, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapPrecomputedBatchBucket {
+    pub bucket: DapBatchBucket,
+    pub time_precision: Duration,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_bucket_precision: Option<Duration>,
+}
+
+/// Digest of an aggregation parameter.
+///
+/// A batch may be collected once per aggregation parameter (e.g., once per level of the prefix
+/// tree for Poplar1-style heavy hitters). Aggregators record the digest of each parameter a batch
+/// has been collected with rather than the parameter itself, which may be large.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapAggregationParamHash(pub [u8; 32]);
+
+impl DapAggregationParamHash {
+    /// Compute the digest of the encoded aggregation parameter.
+    pub fn new(agg_param: &[u8]) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, agg_param);
+        Self(
+            digest
+                .as_ref()
+                .try_into()
+                .expect("SHA-256 digest has unexpected length"),
+        )
+    }
+
+    /// Return the hex encoding of the digest.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
 }
 
 /// A set of aggregate shares partitioned by bucket and the corresponding sequence of report IDs.
-#[derive(Debug, Default)]
+///
+/// Aggregators that store aggregate shares by bucket can iterate over the span (see
+/// [`iter`](Self::iter) and [`IntoIterator`]) and rebuild a span from what they stored with
+/// [`merge_bucket`](Self::merge_bucket). The span is serialized as a map from each bucket (see
+/// [`DapBatchBucket`]) to its aggregate share and report IDs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
 pub struct DapAggregateShareSpan {
     span: HashMap<DapBatchBucket, (DapAggregateShare, Vec<(ReportId, Time)>)>,
 }
@@ -276,6 +670,7 @@ impl DapAggregateShareSpan {
         report_id: ReportId,
         time: Time,
         data: VdafAggregateShare,
+        annotation: Option<String>,
     ) -> Result<(), DapError> {
         if !task_config.query.is_valid_part_batch_sel(part_batch_sel) {
             return Err(fatal_error!(
@@ -284,21 +679,20 @@ impl DapAggregateShareSpan {
         }
 
         let bucket = match part_batch_sel {
-            PartialBatchSelector::TimeInterval => DapBatchBucket::TimeInterval {
-                batch_window: task_config.quantized_time_lower_bound(time),
-            },
+            PartialBatchSelector::TimeInterval => task_config.time_interval_bucket(time),
             PartialBatchSelector::FixedSizeByBatchId { batch_id } => DapBatchBucket::FixedSize {
                 batch_id: batch_id.clone(),
             },
         };
 
         let (agg_share, reports) = self.span.entry(bucket).or_default();
-        agg_share.add_out_share(&report_id, time, data)?;
+        agg_share.add_out_share(&report_id, time, data, annotation)?;
         reports.push((report_id, time));
         Ok(())
     }
 
-    pub(crate) fn report_count(&self) -> usize {
+    /// Return the number of reports in the span.
+    pub fn report_count(&self) -> usize {
         self.span
             .iter()
             .map(|(_bucket, (_agg_share, reports))| reports.len())
@@ -310,7 +704,6 @@ impl DapAggregateShareSpan {
     /// # Panics
     ///
     /// Panics if two aggregates shares in the span have incompatible types.
-    #[cfg(any(test, feature = "test-utils"))]
     pub fn collapsed(self) -> DapAggregateShare {
         self.span
             .into_iter()
@@ -329,17 +722,116 @@ impl DapAggregateShareSpan {
         self.span.iter()
     }
 
+    /// Return an iterator over the IDs and timestamps of the reports in the span.
+    pub fn report_ids(&self) -> impl Iterator<Item = &(ReportId, Time)> {
+        self.span
+            .values()
+            .flat_map(|(_agg_share, reports)| reports.iter())
+    }
+
+    /// Return the aggregate share and reports for the given bucket, if any.
+    pub fn get(
+        &self,
+        bucket: &DapBatchBucket,
+    ) -> Option<&(DapAggregateShare, Vec<(ReportId, Time)>)> {
+        self.span.get(bucket)
+    }
+
+    /// Return the number of buckets in the span.
+    pub fn len(&self) -> usize {
+        self.span.len()
+    }
+
+    /// Return `true` if the span has no buckets.
+    pub fn is_empty(&self) -> bool {
+        self.span.is_empty()
+    }
+
+    /// Merge an aggregate share and its reports into the given bucket of the span.
+    pub fn merge_bucket(
+        &mut self,
+        bucket: DapBatchBucket,
+        other_agg_share: DapAggregateShare,
+        mut other_reports: Vec<(ReportId, Time)>,
+    ) -> Result<(), DapError> {
+        let (agg_share, reports) = self.span.entry(bucket).or_default();
+        agg_share.merge(other_agg_share)?;
+        reports.append(&mut other_reports);
+        Ok(())
+    }
+
     /// Merge the span with another.
     pub fn merge(&mut self, other: Self) -> Result<(), DapError> {
-        for (bucket, (other_agg_share, mut other_reports)) in other.into_iter() {
-            let (agg_share, reports) = self.span.entry(bucket).or_default();
-            agg_share.merge(other_agg_share)?;
-            reports.append(&mut other_reports);
+        for (bucket, (other_agg_share, other_reports)) in other {
+            self.merge_bucket(bucket, other_agg_share, other_reports)?;
         }
         Ok(())
     }
 }
 
+/// Conditions, in addition to the task's minimum batch size, that a batch must satisfy before it
+/// can be collected. The default policy imposes no additional conditions.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapBatchPolicy {
+    /// Minimum number of distinct report ID prefixes (i.e., the first byte of the report ID)
+    /// among the reports in the batch. This is used as a heuristic for the number of distinct
+    /// Clients that contributed to the batch; values greater than 256 can never be satisfied.
+    #[serde(default)]
+    pub min_distinct_report_id_prefixes: u16,
+
+    /// If set, the number of seconds that must elapse after the end of the batch before it may be
+    /// collected. For time-interval queries, the end of the batch is the end of the batch
+    /// interval; for fixed-size queries, it is the timestamp of the most recent report in the
+    /// batch.
+    #[serde(default)]
+    pub min_collect_delay: Option<Duration>,
+
+    /// If set, then a batch interval with no reports may be collected, resulting in a collection
+    /// with a report count of zero and an aggregate of zero. This allows the Collector to
+    /// distinguish "no data" from "not ready". Applies only to time-interval queries, and a batch
+    /// with no reports never satisfies `min_distinct_report_id_prefixes`.
+    #[serde(default)]
+    pub allow_empty_collection: bool,
+}
+
+/// A DAP version in which a task is offered in addition to its primary version, along with the
+/// Aggregators' endpoints for that version.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DapTaskAltVersion {
+    /// The protocol version.
+    pub version: DapVersion,
+
+    /// Base URL of the Leader for this version.
+    pub leader_url: Url,
+
+    /// Base URL of the Helper for this version.
+    pub helper_url: Url,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl deepsize::DeepSizeOf for DapTaskAltVersion {
+    fn deep_size_of_children(&self, context: &mut deepsize::Context) -> usize {
+        self.version.deep_size_of_children(context)
+            + std::mem::size_of_val(self.leader_url.as_str())
+            + std::mem::size_of_val(self.helper_url.as_str())
+    }
+}
+
+/// Migration of a task's report storage to a new epoch duration. Reports stored before the
+/// migration are assigned to epochs of the previous duration, so these are also checked for
+/// replays until the migration ends.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapReportStorageEpochMigration {
+    /// The epoch duration in effect before the migration.
+    pub previous_duration: Duration,
+
+    /// The time at which the migration ends. This should be no earlier than the time at which the
+    /// new duration takes effect, plus the previous duration and the maximum future time skew.
+    pub until: Time,
+}
+
 /// Per-task DAP parameters.
 #[derive(Clone, Deserialize, Serialize)]
 pub struct DapTaskConfig {
@@ -371,12 +863,75 @@ pub struct DapTaskConfig {
     /// VDAF verification key shared by the Aggregators. Used to aggregate reports.
     pub vdaf_verify_key: VdafVerifyKey,
 
+    /// Keyed VDAF verification keys. If set, then the Leader uses the active key of the set
+    /// instead of `vdaf_verify_key`, which allows the key to be rotated for long-lived tasks.
+    #[serde(default)]
+    pub vdaf_verify_key_set: Option<VdafVerifyKeySet>,
+
     /// The Collector's HPKE configuration for this task.
     pub collector_hpke_config: HpkeConfig,
 
     /// If true, then the taskprov extension was used to configure this task.
     #[serde(default)]
     pub taskprov: bool,
+
+    /// Additional conditions a batch must satisfy before it can be collected.
+    #[serde(default)]
+    pub batch_policy: DapBatchPolicy,
+
+    /// If true, then the Leader responds to each accepted upload with a signed receipt.
+    #[serde(default)]
+    pub upload_receipts: bool,
+
+    /// How to handle reports with extensions that the Aggregator does not recognize.
+    #[serde(default)]
+    pub unknown_extensions: DapUnknownExtensionPolicy,
+
+    /// If set, then the Leader drops reports that are still waiting to be aggregated this long
+    /// after they were uploaded. This bounds the storage used by tasks that never accumulate
+    /// enough reports to be processed.
+    #[serde(default)]
+    pub report_ttl: Option<Duration>,
+
+    /// Default priority class of the task's collect jobs. The Collector may override it for an
+    /// individual job.
+    #[serde(default)]
+    pub collect_priority: DapCollectPriority,
+
+    /// If set, then each report must be authenticated by the Client with this key. Reports
+    /// without a valid client authentication extension are rejected.
+    #[serde(default)]
+    pub client_auth_key: Option<DapClientAuthKey>,
+
+    /// Versions in which the task is offered in addition to `version`. This allows Clients to be
+    /// migrated to a new version gradually: reports uploaded in any of the task's versions are
+    /// aggregated with the same VDAF and verification key and are collected together. See
+    /// [`for_version`](Self::for_version).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alt_versions: Vec<DapTaskAltVersion>,
+
+    /// If set, then this overrides `report_storage_epoch_duration` of the global configuration for
+    /// this task. Tasks with high report rates may use shorter epochs than tasks with low report
+    /// rates. See [`epoch_duration`](Self::epoch_duration).
+    #[serde(default)]
+    pub report_storage_epoch_duration: Option<Duration>,
+
+    /// Set while the task's report storage is migrated to a new epoch duration, e.g., after
+    /// `report_storage_epoch_duration` is changed.
+    #[serde(default)]
+    pub report_storage_epoch_migration: Option<DapReportStorageEpochMigration>,
+
+    /// Time-interval tasks: If set, then each batch window is divided into sub-buckets of this
+    /// duration, which are aggregated separately and summed when the window is read. This allows
+    /// the Collector to query batch intervals aligned to the sub-bucket precision rather than the
+    /// time precision. Must evenly divide `time_precision`; see
+    /// [`sub_bucket_precision()`](Self::sub_bucket_precision).
+    ///
+    /// Sub-buckets are only useful if Clients report timestamps at a finer granularity than
+    /// `time_precision`. Reports aggregated before this was set are only included in batches that
+    /// cover their entire window.
+    #[serde(default)]
+    pub sub_bucket_precision: Option<Duration>,
 }
 
 #[cfg(any(test, feature = "test-utils"))]
@@ -391,7 +946,21 @@ impl deepsize::DeepSizeOf for DapTaskConfig {
             + self.query.deep_size_of_children(context)
             + self.vdaf.deep_size_of_children(context)
             + self.vdaf_verify_key.deep_size_of_children(context)
+            + self.vdaf_verify_key_set.deep_size_of_children(context)
             + self.collector_hpke_config.deep_size_of_children(context)
+            + self.batch_policy.deep_size_of_children(context)
+            + self.unknown_extensions.deep_size_of_children(context)
+            + self.report_ttl.deep_size_of_children(context)
+            + self.collect_priority.deep_size_of_children(context)
+            + self.client_auth_key.deep_size_of_children(context)
+            + self.alt_versions.deep_size_of_children(context)
+            + self
+                .report_storage_epoch_duration
+                .deep_size_of_children(context)
+            + self
+                .report_storage_epoch_migration
+                .deep_size_of_children(context)
+            + self.sub_bucket_precision.deep_size_of_children(context)
     }
 }
 
@@ -409,12 +978,206 @@ impl DapTaskConfig {
         }
     }
 
+    /// Return the versions in which the task is offered, starting with the primary version.
+    pub fn versions(&self) -> impl Iterator<Item = DapVersion> + '_ {
+        std::iter::once(self.version).chain(self.alt_versions.iter().map(|alt| alt.version))
+    }
+
+    /// Return the view of the task for the given version, or `None` if the task is not offered in
+    /// that version. The view has `version`, `leader_url` and `helper_url` set for the requested
+    /// version and lists every other version of the task, including the primary version, in
+    /// `alt_versions`. Requests sent and received for the task are handled with this view.
+    pub fn for_version(&self, version: DapVersion) -> Option<Cow<'_, Self>> {
+        if version == self.version {
+            return Some(Cow::Borrowed(self));
+        }
+        let alt = self
+            .alt_versions
+            .iter()
+            .find(|alt| alt.version == version)?;
+        let mut view = self.clone();
+        view.version = alt.version;
+        view.leader_url = alt.leader_url.clone();
+        view.helper_url = alt.helper_url.clone();
+        view.alt_versions = std::iter::once(DapTaskAltVersion {
+            version: self.version,
+            leader_url: self.leader_url.clone(),
+            helper_url: self.helper_url.clone(),
+        })
+        .chain(
+            self.alt_versions
+                .iter()
+                .filter(|other| other.version != version)
+                .cloned(),
+        )
+        .collect();
+        Some(Cow::Owned(view))
+    }
+
+    /// Check that the alternate versions are known and distinct from each other and from the
+    /// primary version.
+    pub fn validate_alt_versions(&self) -> Result<(), DapError> {
+        let mut seen = HashSet::new();
+        for version in self.versions() {
+            if version == DapVersion::Unknown || !seen.insert(version) {
+                return Err(fatal_error!(
+                    err = "invalid or duplicate version for task",
+                    code = Config,
+                    %version
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the time precision is positive.
+    pub fn validate_time_precision(&self) -> Result<(), DapError> {
+        if self.time_precision == 0 {
+            return Err(fatal_error!(
+                err = "time precision must be positive",
+                code = Config
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that the report storage epoch duration and the duration it is migrated from, if set,
+    /// are positive.
+    pub fn validate_epoch_duration(&self) -> Result<(), DapError> {
+        let previous_duration = self
+            .report_storage_epoch_migration
+            .as_ref()
+            .map(|migration| migration.previous_duration);
+        if self.report_storage_epoch_duration == Some(0) || previous_duration == Some(0) {
+            return Err(fatal_error!(
+                err = "report storage epoch duration must be positive",
+                code = Config
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that the sub-bucket precision, if set, is only used for a time-interval task and
+    /// evenly divides the time precision.
+    pub fn validate_sub_bucket_precision(&self) -> Result<(), DapError> {
+        if let Some(precision) = self.sub_bucket_precision {
+            if !matches!(self.query, DapQueryConfig::TimeInterval) {
+                return Err(fatal_error!(
+                    err = "sub-bucket precision is only supported for time-interval tasks",
+                    code = Config
+                ));
+            }
+            if precision >= self.time_precision
+                || self.time_precision.checked_rem(precision) != Some(0)
+            {
+                return Err(fatal_error!(
+                    err = "sub-bucket precision must evenly divide the time precision",
+                    code = Config,
+                    sub_bucket_precision = precision,
+                    time_precision = self.time_precision
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the duration of the sub-buckets into which each batch window is divided, or `None`
+    /// if the task does not use sub-buckets. An invalid `sub_bucket_precision` (see
+    /// [`validate_sub_bucket_precision`](Self::validate_sub_bucket_precision)) is ignored.
+    pub fn sub_bucket_precision(&self) -> Option<Duration> {
+        self.sub_bucket_precision
+            .filter(|_| self.validate_sub_bucket_precision().is_ok())
+    }
+
+    /// Return the granularity to which the boundaries of the batch interval of a time-interval
+    /// query must be aligned.
+    pub fn batch_interval_precision(&self) -> Duration {
+        self.sub_bucket_precision().unwrap_or(self.time_precision)
+    }
+
+    /// Return the bucket to which a report with the given timestamp is assigned by a time-interval
+    /// task.
+    pub(crate) fn time_interval_bucket(&self, time: Time) -> DapBatchBucket {
+        let batch_window = self.quantized_time_lower_bound(time);
+        match self.sub_bucket_precision() {
+            Some(precision) => DapBatchBucket::TimeIntervalSub {
+                batch_window,
+                sub_window: time - (time % precision),
+            },
+            None => DapBatchBucket::TimeInterval { batch_window },
+        }
+    }
+
+    /// Return the duration of the task's report storage epochs: `report_storage_epoch_duration` if
+    /// set, or else the global default.
+    pub fn epoch_duration(&self, global_config: &DapGlobalConfig) -> Duration {
+        self.report_storage_epoch_duration
+            .unwrap_or(global_config.report_storage_epoch_duration)
+    }
+
+    /// Return the epoch duration that was in effect before the ongoing migration of the task's
+    /// report storage, or `None` if there is no migration in progress at time `now`.
+    pub fn previous_epoch_duration(&self, now: Time) -> Option<Duration> {
+        self.report_storage_epoch_migration
+            .as_ref()
+            .filter(|migration| now < migration.until)
+            .map(|migration| migration.previous_duration)
+    }
+
+    /// Return the VDAF verification key the Leader uses for a new aggregation job, along with its
+    /// key ID if the task has a [`VdafVerifyKeySet`].
+    pub(crate) fn active_vdaf_verify_key(&self) -> Result<(Option<u8>, &VdafVerifyKey), DapError> {
+        let Some(ref key_set) = self.vdaf_verify_key_set else {
+            return Ok((None, &self.vdaf_verify_key));
+        };
+        let key = key_set.keys.get(&key_set.active_key_id).ok_or_else(|| {
+            fatal_error!(
+                err = "active VDAF verify key is missing from the key set",
+                code = Config,
+                key_id = key_set.active_key_id
+            )
+        })?;
+        Ok((Some(key_set.active_key_id), key))
+    }
+
+    /// Return the VDAF verification key with the given key ID, or `vdaf_verify_key` if no key ID
+    /// is given. Returns `None` if the key ID is not recognized.
+    pub(crate) fn vdaf_verify_key_for(&self, key_id: Option<u8>) -> Option<&VdafVerifyKey> {
+        match key_id {
+            Some(key_id) => self.vdaf_verify_key_set.as_ref()?.keys.get(&key_id),
+            None => Some(&self.vdaf_verify_key),
+        }
+    }
+
     /// Return the greatest multiple of the time_precision which is less than or equal to the
     /// specified time.
     pub fn quantized_time_lower_bound(&self, time: Time) -> Time {
         time - (time % self.time_precision)
     }
 
+    /// Return `true` if the specified time is a multiple of the time_precision.
+    pub fn is_quantized_time(&self, time: Time) -> bool {
+        time.checked_rem(self.time_precision) == Some(0)
+    }
+
+    /// Shard a measurement into a report for this task. The report's timestamp is `time`
+    /// truncated to the time_precision.
+    pub fn produce_report(
+        &self,
+        hpke_config_list: &[HpkeConfig],
+        time: Time,
+        task_id: &TaskId,
+        measurement: DapMeasurement,
+    ) -> Result<Report, DapError> {
+        self.vdaf.produce_report(
+            hpke_config_list,
+            self.quantized_time_lower_bound(time),
+            task_id,
+            measurement,
+            self.version,
+        )
+    }
+
     /// Return the least multiple of the time_precision which is greater than the specified time.
     pub fn quantized_time_upper_bound(&self, time: Time) -> Time {
         self.quantized_time_lower_bound(time) + self.time_precision
@@ -433,9 +1196,14 @@ impl DapTaskConfig {
         }
 
         match batch_sel {
-            BatchSelector::TimeInterval {
-                batch_interval: Interval { start, duration },
-            } => {
+            BatchSelector::TimeInterval { batch_interval } => {
+                if let Some(precision) = self.sub_bucket_precision() {
+                    return self
+                        .batch_span_for_interval_with_sub_buckets(batch_interval, precision);
+                }
+
+                batch_interval_end(batch_interval)?;
+                let Interval { start, duration } = batch_interval;
                 let windows = duration / self.time_precision;
                 let mut span = HashSet::with_capacity(windows as usize);
                 for i in 0..windows {
@@ -453,11 +1221,76 @@ impl DapTaskConfig {
         }
     }
 
-    /// Return the batch span of a set of reports.
-    pub fn batch_span_for_meta<'sel, 'rep>(
+    /// Return the buckets spanned by the batch interval of a task with sub-buckets: every
+    /// sub-bucket within the interval, plus every batch window covered entirely by the interval,
+    /// which holds the reports aggregated before the task had sub-buckets.
+    fn batch_span_for_interval_with_sub_buckets(
+        &self,
+        batch_interval: &Interval,
+        precision: Duration,
+    ) -> Result<HashSet<DapBatchBucket>, DapError> {
+        let start = batch_interval.start;
+        let end = batch_interval_end(batch_interval)?;
+        let mut span = HashSet::new();
+        let mut batch_window = self.quantized_time_lower_bound(start);
+        while batch_window < end {
+            let window_end = batch_window + self.time_precision;
+            if batch_window >= start && window_end <= end {
+                span.insert(DapBatchBucket::TimeInterval { batch_window });
+            }
+            let mut sub_window = batch_window.max(start);
+            while sub_window < window_end.min(end) {
+                span.insert(DapBatchBucket::TimeIntervalSub {
+                    batch_window,
+                    sub_window,
+                });
+                sub_window += precision;
+            }
+            batch_window = window_end;
+        }
+        Ok(span)
+    }
+
+    /// Return the batch span of a set of reports.
+    pub fn batch_span_for_meta<'sel, 'rep>(
+        &self,
+        part_batch_sel: &'sel PartialBatchSelector,
+        consumed_reports: impl Iterator<Item = &'rep EarlyReportStateConsumed<'rep>>,
+    ) -> Result<HashMap<DapBatchBucket, Vec<&'rep EarlyReportStateConsumed<'rep>>>, DapError> {
+        self.batch_span_for_meta_precomputed(part_batch_sel, consumed_reports, |_| None)
+    }
+
+    /// Return the bucket a report with the given timestamp belongs to, if this can be determined
+    /// at upload time. This is the case for time-interval tasks. For fixed-size tasks the bucket
+    /// is the batch the Leader later assigns the report to, so `None` is returned; there is
+    /// nothing to precompute, as [`batch_span_for_meta_precomputed`] derives the bucket once from
+    /// the partial batch selector rather than for each report.
+    ///
+    /// The result may be stored alongside the pending report and later passed to
+    /// [`batch_span_for_meta_precomputed`].
+    ///
+    /// [`batch_span_for_meta_precomputed`]: Self::batch_span_for_meta_precomputed
+    pub fn precompute_batch_bucket(&self, time: Time) -> Option<DapPrecomputedBatchBucket> {
+        match self.query {
+            DapQueryConfig::TimeInterval => Some(DapPrecomputedBatchBucket {
+                bucket: self.time_interval_bucket(time),
+                time_precision: self.time_precision,
+                sub_bucket_precision: self.sub_bucket_precision(),
+            }),
+            DapQueryConfig::FixedSize { .. } => None,
+        }
+    }
+
+    /// Like [`batch_span_for_meta`](Self::batch_span_for_meta), except that the bucket returned
+    /// by `precomputed` for a report, if any, is trusted instead of being recomputed. A
+    /// precomputed bucket is ignored if it was computed with a different `time_precision` or
+    /// sub-bucket precision than the task's current ones, or if it does not match the type of the
+    /// partial batch selector.
+    pub fn batch_span_for_meta_precomputed<'sel, 'rep>(
         &self,
         part_batch_sel: &'sel PartialBatchSelector,
         consumed_reports: impl Iterator<Item = &'rep EarlyReportStateConsumed<'rep>>,
+        precomputed: impl Fn(&ReportMetadata) -> Option<DapPrecomputedBatchBucket>,
     ) -> Result<HashMap<DapBatchBucket, Vec<&'rep EarlyReportStateConsumed<'rep>>>, DapError> {
         if !self.query.is_valid_part_batch_sel(part_batch_sel) {
             return Err(fatal_error!(
@@ -465,36 +1298,61 @@ impl DapTaskConfig {
             ));
         }
 
+        let consumed_reports =
+            consumed_reports.filter(|consumed_report| consumed_report.is_ready());
         let mut span: HashMap<_, Vec<_>> = HashMap::new();
-        for consumed_report in consumed_reports.filter(|consumed_report| consumed_report.is_ready())
-        {
-            let bucket = match part_batch_sel {
-                PartialBatchSelector::TimeInterval => DapBatchBucket::TimeInterval {
-                    batch_window: self.quantized_time_lower_bound(consumed_report.metadata().time),
-                },
-                PartialBatchSelector::FixedSizeByBatchId { batch_id } => {
-                    DapBatchBucket::FixedSize {
-                        batch_id: batch_id.clone(),
-                    }
+        match part_batch_sel {
+            PartialBatchSelector::TimeInterval => {
+                let sub_bucket_precision = self.sub_bucket_precision();
+                for consumed_report in consumed_reports {
+                    let bucket = match precomputed(consumed_report.metadata()) {
+                        Some(DapPrecomputedBatchBucket {
+                            bucket:
+                                bucket @ (DapBatchBucket::TimeInterval { .. }
+                                | DapBatchBucket::TimeIntervalSub { .. }),
+                            time_precision,
+                            sub_bucket_precision: precomputed_sub_bucket_precision,
+                        }) if time_precision == self.time_precision
+                            && precomputed_sub_bucket_precision == sub_bucket_precision =>
+                        {
+                            bucket
+                        }
+                        _ => self.time_interval_bucket(consumed_report.metadata().time),
+                    };
+                    span.entry(bucket).or_default().push(consumed_report);
                 }
-            };
-
-            let consumed_reports_per_bucket = span.entry(bucket).or_default();
-            consumed_reports_per_bucket.push(consumed_report);
+            }
+            PartialBatchSelector::FixedSizeByBatchId { batch_id } => {
+                // All reports belong to the batch chosen by the Leader.
+                let consumed_reports = consumed_reports.collect::<Vec<_>>();
+                if !consumed_reports.is_empty() {
+                    span.insert(
+                        DapBatchBucket::FixedSize {
+                            batch_id: batch_id.clone(),
+                        },
+                        consumed_reports,
+                    );
+                }
+            }
         }
 
         Ok(span)
     }
 
     /// Check if the batch size is too small. Returns an error if the report count is too large.
+    /// An empty batch is compatible if the task's [`DapBatchPolicy`] allows empty collections.
     pub(crate) fn is_report_count_compatible(
         &self,
         task_id: &TaskId,
         report_count: u64,
     ) -> Result<bool, DapAbort> {
         match self.query {
-            DapQueryConfig::TimeInterval => (),
-            DapQueryConfig::FixedSize { max_batch_size } => {
+            DapQueryConfig::TimeInterval => {
+                if report_count == 0 && self.batch_policy.allow_empty_collection {
+                    return Ok(true);
+                }
+            }
+            DapQueryConfig::FixedSize { max_batch_size, .. } => {
                 if report_count > max_batch_size {
                     return Err(DapAbort::InvalidBatchSize {
                         detail: format!(
@@ -508,6 +1366,81 @@ impl DapTaskConfig {
 
         Ok(report_count >= self.min_batch_size)
     }
+
+    /// Return the earliest time at which a batch with the given selector may be collected, if the
+    /// task's [`DapBatchPolicy`] constrains it. For fixed-size queries, this depends on the
+    /// timestamp of the latest report in the batch.
+    pub(crate) fn earliest_collect_time(
+        &self,
+        batch_sel: &BatchSelector,
+        max_time: Option<Time>,
+    ) -> Result<Option<Time>, DapAbort> {
+        let Some(delay) = self.batch_policy.min_collect_delay else {
+            return Ok(None);
+        };
+        let batch_end = match (batch_sel, max_time) {
+            (BatchSelector::TimeInterval { batch_interval }, _) => {
+                batch_interval_end(batch_interval)?
+            }
+            (BatchSelector::FixedSizeByBatchId { .. }, Some(max_time)) => max_time,
+            (BatchSelector::FixedSizeByBatchId { .. }, None) => return Ok(None),
+        };
+        Ok(Some(batch_end.saturating_add(delay)))
+    }
+
+    /// Check the conditions of [`is_batch_collectable`](Self::is_batch_collectable) that only
+    /// depend on the metadata of the aggregate share. This allows a batch that is not yet ready to
+    /// be skipped without fetching its aggregate share. Returns an error if the report count is
+    /// too large.
+    pub(crate) fn is_batch_meta_collectable(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        meta: &DapAggregateShareMeta,
+        now: Time,
+    ) -> Result<bool, DapAbort> {
+        if !self.is_report_count_compatible(task_id, meta.report_count)? {
+            return Ok(false);
+        }
+
+        match self.earliest_collect_time(batch_sel, Some(meta.max_time))? {
+            Some(earliest) => Ok(now >= earliest),
+            None => Ok(true),
+        }
+    }
+
+    /// Check if the batch is ready to be collected: its report count must be compatible with the
+    /// task and it must satisfy the task's [`DapBatchPolicy`]. Returns an error if the report
+    /// count is too large.
+    pub(crate) fn is_batch_collectable(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        agg_share: &DapAggregateShare,
+        now: Time,
+    ) -> Result<bool, DapAbort> {
+        if !self.is_report_count_compatible(task_id, agg_share.report_count)? {
+            return Ok(false);
+        }
+
+        if agg_share.distinct_report_id_prefixes()
+            < u32::from(self.batch_policy.min_distinct_report_id_prefixes)
+        {
+            return Ok(false);
+        }
+
+        match self.earliest_collect_time(batch_sel, Some(agg_share.max_time))? {
+            Some(earliest) => Ok(now >= earliest),
+            None => Ok(true),
+        }
+    }
+}
+
+/// Return the end of a batch interval supplied by a peer, or abort if it overflows.
+pub(crate) fn batch_interval_end(batch_interval: &Interval) -> Result<Time, DapAbort> {
+    batch_interval
+        .try_end()
+        .ok_or_else(|| DapAbort::BadRequest("batch interval overflows".into()))
 }
 
 impl AsRef<DapTaskConfig> for DapTaskConfig {
@@ -526,7 +1459,7 @@ pub enum DapMeasurement {
 }
 
 /// The aggregate result computed by the Collector.
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum DapAggregateResult {
     U32Vec(Vec<u32>),
@@ -538,7 +1471,13 @@ pub enum DapAggregateResult {
 /// The Leader's state after sending an AggregateInitReq.
 #[derive(Debug)]
 pub struct DapLeaderState {
-    pub(crate) seq: Vec<(VdafPrepState, VdafPrepMessage, Time, ReportId)>,
+    pub(crate) seq: Vec<(
+        VdafPrepState,
+        VdafPrepMessage,
+        Time,
+        ReportId,
+        Option<String>,
+    )>,
     part_batch_sel: PartialBatchSelector,
 }
 
@@ -568,9 +1507,75 @@ impl Encode for DapHelperState {
     }
 }
 
+/// Magic byte that prefixes the storage encoding of [`DapHelperState`]. The legacy encoding begins
+/// with the query type of the partial batch selector, which is never equal to this value.
+const DAP_HELPER_STATE_MAGIC: u8 = 0xdb;
+
+/// Version of the storage encoding of [`DapHelperState`].
+const DAP_HELPER_STATE_ENCODING_VERSION: u8 = 1;
+
+/// Length of the VDAF configuration fingerprint in the storage encoding of [`DapHelperState`].
+const DAP_HELPER_STATE_VDAF_FINGERPRINT_LEN: usize = 8;
+
+fn vdaf_config_fingerprint(
+    vdaf_config: &VdafConfig,
+) -> [u8; DAP_HELPER_STATE_VDAF_FINGERPRINT_LEN] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, vdaf_config.to_string().as_bytes());
+    let mut fingerprint = [0; DAP_HELPER_STATE_VDAF_FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&digest.as_ref()[..DAP_HELPER_STATE_VDAF_FINGERPRINT_LEN]);
+    fingerprint
+}
+
 impl DapHelperState {
-    /// Decode the Helper state from a byte string.
+    /// Encode the Helper state for storage.
+    ///
+    /// Unlike the [`Encode`] implementation, this encoding is explicitly versioned: it begins with
+    /// a magic byte, an encoding version, and a fingerprint of the VDAF configuration, followed by
+    /// the state itself. This allows state written by a different deployment to be detected
+    /// rather than misinterpreted.
+    pub fn get_encoded_versioned(&self, vdaf_config: &VdafConfig) -> Vec<u8> {
+        let mut bytes = vec![DAP_HELPER_STATE_MAGIC, DAP_HELPER_STATE_ENCODING_VERSION];
+        bytes.extend_from_slice(&vdaf_config_fingerprint(vdaf_config));
+        self.encode(&mut bytes);
+        bytes
+    }
+
+    /// Decode the Helper state from a byte string. Both the encoding produced by
+    /// [`Self::get_encoded_versioned`] and the legacy, unversioned encoding are accepted. If the
+    /// state was encoded with an unknown version or for a different VDAF configuration, then the
+    /// error has code [`DapErrorCode::StateFormatMismatch`](error::DapErrorCode).
     pub fn get_decoded(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
+        let Some(rest) = data.strip_prefix(&[DAP_HELPER_STATE_MAGIC]) else {
+            // TODO Remove support for the legacy encoding once all in-flight aggregation jobs
+            // have been written with the versioned encoding.
+            return Self::get_decoded_unversioned(vdaf_config, data);
+        };
+
+        let Some((&version, rest)) = rest.split_first() else {
+            return Err(fatal_error!(
+                err = "helper state encoding is too short",
+                code = StateCorruption
+            ));
+        };
+        if version != DAP_HELPER_STATE_ENCODING_VERSION {
+            return Err(fatal_error!(
+                err = "helper state encoding has unknown version",
+                code = StateFormatMismatch,
+                version
+            ));
+        }
+
+        let Some(rest) = rest.strip_prefix(&vdaf_config_fingerprint(vdaf_config)) else {
+            return Err(fatal_error!(
+                err = "helper state was encoded for a different VDAF configuration",
+                code = StateFormatMismatch,
+                %vdaf_config
+            ));
+        };
+        Self::get_decoded_unversioned(vdaf_config, rest)
+    }
+
+    fn get_decoded_unversioned(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
         let mut r = std::io::Cursor::new(data);
         let part_batch_sel = PartialBatchSelector::decode(&mut r)
             .map_err(|e| DapAbort::from_codec_error(e, None))?;
@@ -598,6 +1603,37 @@ pub struct DapOutputShare {
     pub report_id: ReportId, // Value from report
     pub time: u64,           // Value from the report
     pub(crate) data: VdafAggregateShare,
+    /// Deployment-specific annotation copied from the report (see [`Report::annotation`]).
+    pub annotation: Option<String>,
+}
+
+/// Magic byte that prefixes the storage encoding of [`DapAggregateShare`].
+const DAP_AGGREGATE_SHARE_MAGIC: u8 = 0xda;
+
+/// Version of the storage encoding of [`DapAggregateShare`]. Increment this whenever the encoding
+/// changes and keep decoding the previous versions.
+const DAP_AGGREGATE_SHARE_ENCODING_VERSION: u8 = 2;
+
+/// An entry of [`DapAggregateShare::annotations`] in the storage encoding.
+struct AnnotationCount {
+    annotation: String,
+    count: u64,
+}
+
+impl Encode for AnnotationCount {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        encode_u16_bytes(bytes, self.annotation.as_bytes());
+        self.count.encode(bytes);
+    }
+}
+
+impl Decode for AnnotationCount {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let annotation =
+            String::from_utf8(decode_u16_bytes(bytes)?).map_err(|e| CodecError::Other(e.into()))?;
+        let count = u64::decode(bytes)?;
+        Ok(Self { annotation, count })
+    }
 }
 
 /// An aggregate share computed by combining a set of output shares.
@@ -611,6 +1647,13 @@ pub struct DapAggregateShare {
     /// Batch checkusm.
     pub checksum: [u8; 32],
     pub(crate) data: Option<VdafAggregateShare>,
+    /// Bitmap of the first bytes of the IDs of the reports in the batch.
+    #[serde(default)]
+    pub(crate) report_id_prefixes: [u8; 32],
+    /// Number of reports in the batch with each annotation (see [`Report::annotation`]). Reports
+    /// without an annotation are not counted.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, u64>,
 }
 
 impl DapAggregateShare {
@@ -659,9 +1702,156 @@ impl DapAggregateShare {
         for (x, y) in self.checksum.iter_mut().zip(other.checksum) {
             *x ^= y;
         }
+        for (x, y) in self
+            .report_id_prefixes
+            .iter_mut()
+            .zip(other.report_id_prefixes)
+        {
+            *x |= y;
+        }
+        for (annotation, count) in other.annotations {
+            *self.annotations.entry(annotation).or_default() += count;
+        }
         Ok(())
     }
 
+    /// Return the number of distinct report ID prefixes among the reports in the batch.
+    pub fn distinct_report_id_prefixes(&self) -> u32 {
+        self.report_id_prefixes
+            .iter()
+            .map(|byte| byte.count_ones())
+            .sum()
+    }
+
+    /// Encode the aggregate share for long-term storage.
+    ///
+    /// Unlike the serde representation, this encoding is explicitly versioned: it begins with a
+    /// magic byte and an encoding version, followed by the fields of the aggregate share. Field
+    /// elements are tagged with the field they belong to.
+    pub fn get_encoded_versioned(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            DAP_AGGREGATE_SHARE_MAGIC,
+            DAP_AGGREGATE_SHARE_ENCODING_VERSION,
+        ];
+        self.report_count.encode(&mut bytes);
+        self.min_time.encode(&mut bytes);
+        self.max_time.encode(&mut bytes);
+        bytes.extend_from_slice(&self.checksum);
+        bytes.extend_from_slice(&self.report_id_prefixes);
+        match &self.data {
+            None => 0_u8.encode(&mut bytes),
+            Some(VdafAggregateShare::Field64(agg_share)) => {
+                1_u8.encode(&mut bytes);
+                encode_u32_items(&mut bytes, &(), agg_share.as_ref());
+            }
+            Some(VdafAggregateShare::Field128(agg_share)) => {
+                2_u8.encode(&mut bytes);
+                encode_u32_items(&mut bytes, &(), agg_share.as_ref());
+            }
+            Some(VdafAggregateShare::FieldPrio2(agg_share)) => {
+                3_u8.encode(&mut bytes);
+                encode_u32_items(&mut bytes, &(), agg_share.as_ref());
+            }
+        }
+        let annotations = self
+            .annotations
+            .iter()
+            .map(|(annotation, count)| AnnotationCount {
+                annotation: annotation.clone(),
+                count: *count,
+            })
+            .collect::<Vec<_>>();
+        encode_u32_items(&mut bytes, &(), &annotations);
+        bytes
+    }
+
+    /// Decode an aggregate share encoded with [`Self::get_encoded_versioned`].
+    pub fn get_decoded_versioned(bytes: &[u8]) -> Result<Self, DapError> {
+        let (magic, version) = match bytes {
+            [magic, version, ..] => (*magic, *version),
+            _ => {
+                return Err(fatal_error!(
+                    err = "aggregate share encoding is too short",
+                    code = StateCorruption
+                ))
+            }
+        };
+        if magic != DAP_AGGREGATE_SHARE_MAGIC {
+            return Err(fatal_error!(
+                err = "aggregate share encoding has unexpected magic byte",
+                code = StateCorruption,
+                magic
+            ));
+        }
+        match version {
+            1 | 2 => Self::decode_versioned(version, &bytes[2..]).map_err(|e| {
+                fatal_error!(
+                    err = ?e,
+                    code = StateCorruption,
+                    "failed to decode aggregate share"
+                )
+            }),
+            _ => Err(fatal_error!(
+                err = "aggregate share encoding has unknown version",
+                code = StateCorruption,
+                version
+            )),
+        }
+    }
+
+    fn decode_versioned(version: u8, bytes: &[u8]) -> Result<Self, CodecError> {
+        fn decode_agg_share<F: prio::field::FieldElement>(
+            r: &mut Cursor<&[u8]>,
+        ) -> Result<prio::vdaf::AggregateShare<F>, CodecError> {
+            let data: Vec<F> = decode_u32_items(&(), r)?;
+            Ok(prio::vdaf::OutputShare::from(data).into())
+        }
+
+        let mut r = Cursor::new(bytes);
+        let report_count = u64::decode(&mut r)?;
+        let min_time = Time::decode(&mut r)?;
+        let max_time = Time::decode(&mut r)?;
+        let mut checksum = [0; 32];
+        r.read_exact(&mut checksum)?;
+        let mut report_id_prefixes = [0; 32];
+        r.read_exact(&mut report_id_prefixes)?;
+        let data = match u8::decode(&mut r)? {
+            0 => None,
+            1 => Some(VdafAggregateShare::Field64(decode_agg_share(&mut r)?)),
+            2 => Some(VdafAggregateShare::Field128(decode_agg_share(&mut r)?)),
+            3 => Some(VdafAggregateShare::FieldPrio2(decode_agg_share(&mut r)?)),
+            tag => {
+                return Err(CodecError::Other(
+                    format!("unexpected aggregate share tag {tag}").into(),
+                ))
+            }
+        };
+        // Annotations were added in version 2.
+        let annotations = if version >= 2 {
+            decode_u32_items::<_, AnnotationCount>(&(), &mut r)?
+                .into_iter()
+                .map(|a| (a.annotation, a.count))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+        if (r.position() as usize) < bytes.len() {
+            return Err(CodecError::BytesLeftOver(
+                bytes.len() - r.position() as usize,
+            ));
+        }
+
+        Ok(Self {
+            report_count,
+            min_time,
+            max_time,
+            checksum,
+            data,
+            report_id_prefixes,
+            annotations,
+        })
+    }
+
     /// Return `true` if the aggregate share contains no reports.
     pub fn empty(&self) -> bool {
         self.report_count == 0
@@ -674,6 +1864,8 @@ impl DapAggregateShare {
         self.max_time = 0;
         self.checksum = [0; 32];
         self.data = None;
+        self.report_id_prefixes = [0; 32];
+        self.annotations.clear();
     }
 
     pub(crate) fn add_out_share(
@@ -681,17 +1873,98 @@ impl DapAggregateShare {
         report_id: &ReportId,
         time: Time,
         data: VdafAggregateShare,
+        annotation: Option<String>,
     ) -> Result<(), DapError> {
         let checksum = ring::digest::digest(&ring::digest::SHA256, report_id.as_ref());
+        let prefix = report_id.as_ref()[0];
+        let mut report_id_prefixes = [0; 32];
+        report_id_prefixes[usize::from(prefix / 8)] = 1 << (prefix % 8);
         self.merge(DapAggregateShare {
             report_count: 1,
             min_time: time,
             max_time: time,
             checksum: checksum.as_ref().try_into().unwrap(),
             data: Some(data),
+            report_id_prefixes,
+            annotations: annotation.into_iter().map(|a| (a, 1)).collect(),
         })?;
         Ok(())
     }
+
+    /// Return the metadata of the aggregate share. The collected flag is not known to the
+    /// aggregate share and is always `false`.
+    pub fn meta(&self) -> DapAggregateShareMeta {
+        DapAggregateShareMeta {
+            report_count: self.report_count,
+            min_time: self.min_time,
+            max_time: self.max_time,
+            checksum: self.checksum,
+            collected: false,
+        }
+    }
+}
+
+/// Metadata of an aggregate share, i.e., everything but the aggregated data. This is much smaller
+/// than the aggregate share and suffices for checks that only depend on the report count or the
+/// checksum.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DapAggregateShareMeta {
+    /// Number of reports in the batch.
+    pub report_count: u64,
+    /// Timestamp of the earliest report in the batch.
+    pub min_time: Time,
+    /// Timestamp of the latest report in the batch.
+    pub max_time: Time,
+    /// Batch checksum.
+    #[serde(default)]
+    pub checksum: [u8; 32],
+    /// Indicates if any bucket of the batch has been collected.
+    pub collected: bool,
+}
+
+impl DapAggregateShareMeta {
+    /// Merge the metadata of another set of buckets.
+    pub fn merge(&mut self, other: DapAggregateShareMeta) {
+        if self.report_count == 0 {
+            self.min_time = other.min_time;
+            self.max_time = other.max_time;
+        } else if other.report_count > 0 {
+            self.min_time = min(self.min_time, other.min_time);
+            self.max_time = max(self.max_time, other.max_time);
+        }
+        self.report_count += other.report_count;
+        for (x, y) in self.checksum.iter_mut().zip(other.checksum) {
+            *x ^= y;
+        }
+        self.collected |= other.collected;
+    }
+}
+
+/// Summary of the state deleted by [`DapAggregator::purge_task`](crate::roles::DapAggregator::purge_task).
+/// Each field counts the records deleted from the corresponding store.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DapTaskPurgeSummary {
+    /// Reports that were uploaded but not yet aggregated.
+    pub reports_pending: u64,
+    /// Report IDs that were recorded to prevent replays.
+    pub reports_processed: u64,
+    /// Aggregate shares, one per batch bucket.
+    pub agg_shares: u64,
+    /// Helper states kept between aggregation job requests.
+    pub helper_states: u64,
+    /// Collection jobs, pending or processed.
+    pub collect_jobs: u64,
+}
+
+impl DapTaskPurgeSummary {
+    /// Total number of records deleted.
+    pub fn total(&self) -> u64 {
+        self.reports_pending
+            + self.reports_processed
+            + self.agg_shares
+            + self.helper_states
+            + self.collect_jobs
+    }
 }
 
 /// Leader state transition during the aggregation flow.
@@ -802,7 +2075,7 @@ pub enum DapSender {
 }
 
 /// Types of resources associated with DAP tasks.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub enum DapResource {
     /// Aggregation job resource.
     AggregationJob(AggregationJobId),
@@ -810,6 +2083,9 @@ pub enum DapResource {
     /// Collection job resource.
     CollectionJob(CollectionJobId),
 
+    /// Report resource. Set when the report ID is indicated by the path of an upload request.
+    Report(ReportId),
+
     /// Undefined (or undetermined) resource.
     ///
     /// The resource of a DAP request is undefined if there is not a unique object (in the context
@@ -827,6 +2103,57 @@ pub enum DapResource {
     Undefined,
 }
 
+impl DapResource {
+    /// Parse the task ID and resource indicated by the path of a request, e.g.,
+    /// `/v07/tasks/{task_id}/aggregation_jobs/{agg_job_id}`. The first segment of the path (the
+    /// version) is ignored. The return value is `None` if the path does not pertain to a task
+    /// (e.g., the request for the HPKE configuration) or if it does not indicate a known resource.
+    ///
+    /// draft02 compatibility: In draft02, the task ID and resource are not indicated by the path
+    /// and this method always returns `None`.
+    pub fn parse_path(path: &str) -> Result<Option<(TaskId, Self)>, DapAbort> {
+        let mut segments = path.trim_start_matches('/').split('/').skip(1);
+        if segments.next() != Some("tasks") {
+            return Ok(None);
+        }
+        let Some(task_id) = segments.next() else {
+            return Ok(None);
+        };
+        let rest = (segments.next(), segments.next(), segments.next());
+
+        // Check that the path indicates a known resource before parsing any of the IDs so that
+        // unknown paths are left for the router to reject.
+        let (what, id) = match rest {
+            (Some("aggregation_jobs"), Some(id), None) => ("aggregation job", Some(id)),
+            (Some("collection_jobs"), Some(id), None) => ("collection job", Some(id)),
+            (Some("reports"), Some(id), None) => ("report", Some(id)),
+            (
+                Some("aggregate_shares" | "collection_jobs" | "reports" | "reports:batch"),
+                None,
+                None,
+            ) => ("", None),
+            _ => return Ok(None),
+        };
+
+        let task_id = TaskId::try_from_base64url(task_id)
+            .ok_or_else(|| DapAbort::BadRequest("malformed task ID in request path".into()))?;
+        let Some(id) = id else {
+            return Ok(Some((task_id, Self::Undefined)));
+        };
+        let malformed = || DapAbort::BadRequest(format!("malformed {what} ID in request path"));
+        let resource = match rest.0 {
+            Some("aggregation_jobs") => Self::AggregationJob(
+                AggregationJobId::try_from_base64url(id).ok_or_else(malformed)?,
+            ),
+            Some("collection_jobs") => {
+                Self::CollectionJob(CollectionJobId::try_from_base64url(id).ok_or_else(malformed)?)
+            }
+            _ => Self::Report(ReportId::try_from_base64url(id).ok_or_else(malformed)?),
+        };
+        Ok(Some((task_id, resource)))
+    }
+}
+
 /// DAP request.
 #[derive(Debug)]
 pub struct DapRequest<S> {
@@ -854,6 +2181,15 @@ pub struct DapRequest<S> {
 
     /// taskprov: The task advertisement, sent in the "dap-taskprov" header.
     pub taskprov: Option<String>,
+
+    /// Content coding of the request body on the wire, sent in the "content-encoding" header.
+    /// The payload is always stored decoded; for outbound requests, this field tells the sender
+    /// which coding to apply before sending.
+    pub content_encoding: Option<DapContentEncoding>,
+
+    /// Priority class of a collect job, sent in the "dap-collect-priority" header of the
+    /// collection request. If not set, then the task's default priority is used.
+    pub collect_priority: Option<DapCollectPriority>,
 }
 
 #[cfg(test)]
@@ -868,11 +2204,23 @@ impl<S> Default for DapRequest<S> {
             url: Url::parse("http://example.com").unwrap(),
             sender_auth: Default::default(),
             taskprov: Default::default(),
+            content_encoding: Default::default(),
+            collect_priority: Default::default(),
         }
     }
 }
 
 impl<S> DapRequest<S> {
+    /// Return the sender of the request, as indicated by its media type. A request without
+    /// content (i.e., a DELETE request for an aggregation job) is attributed to the sender that
+    /// manages the resource it targets.
+    pub fn sender(&self) -> Option<DapSender> {
+        match (&self.media_type, &self.resource) {
+            (DapMediaType::Missing, DapResource::AggregationJob(..)) => Some(DapSender::Leader),
+            (media_type, _) => media_type.sender(),
+        }
+    }
+
     /// Return the task ID, handling a missing ID as a user error.
     pub fn task_id(&self) -> Result<&TaskId, DapAbort> {
         if let Some(ref id) = self.task_id {
@@ -920,7 +2268,66 @@ impl<S> DapRequest<S> {
 pub struct DapResponse {
     pub version: DapVersion,
     pub media_type: DapMediaType,
-    pub payload: Vec<u8>,
+    pub payload: DapResponsePayload,
+}
+
+/// Body of a [`DapResponse`].
+#[derive(Debug)]
+pub enum DapResponsePayload {
+    /// The body in one piece.
+    Bytes(Vec<u8>),
+
+    /// The body as a sequence of chunks to be concatenated. This is used for responses that may be
+    /// very large (see [`AggregationJobRespWriter`](crate::messages::AggregationJobRespWriter)) so
+    /// that they can be streamed to the peer rather than copied all at once.
+    Chunks(Vec<Vec<u8>>),
+}
+
+impl DapResponsePayload {
+    /// Length of the body in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Bytes(bytes) => bytes.len(),
+            Self::Chunks(chunks) => chunks.iter().map(Vec::len).sum(),
+        }
+    }
+
+    /// Returns `true` if the body is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The body in one piece. The chunks of a chunked body are concatenated.
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Self::Bytes(bytes) => Cow::Borrowed(bytes),
+            Self::Chunks(chunks) => Cow::Owned(chunks.concat()),
+        }
+    }
+
+    /// Like [`Self::to_bytes`], but consumes the body.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Bytes(bytes) => bytes,
+            Self::Chunks(chunks) => chunks.concat(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for DapResponsePayload {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+/// State of a fixed-size batch in the Leader's batch queue.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DapBatchState {
+    /// Reports are still being assigned to the batch. It cannot be collected yet.
+    Filling,
+    /// The batch is closed and is waiting to be collected.
+    Ready,
 }
 
 /// Status of a collect job.
@@ -932,6 +2339,69 @@ pub enum DapCollectJob {
     Unknown,
 }
 
+/// Status of a collect job, as listed for the Collector.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DapCollectJobStatus {
+    Pending,
+    Done,
+}
+
+/// A collect job listed for the Collector. The job's collection can be fetched by polling it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DapCollectJobSummary {
+    pub collect_job_id: CollectionJobId,
+    pub status: DapCollectJobStatus,
+}
+
+/// Priority class of a collect job. The Leader processes pending collect jobs of a higher class
+/// first; jobs of the same class are processed in the order in which they were created.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub enum DapCollectPriority {
+    /// Jobs that can wait for others to finish, e.g., recomputing historical batches.
+    Backfill,
+
+    #[default]
+    Normal,
+
+    /// Operationally urgent jobs, e.g., those needed to meet a reporting deadline.
+    Urgent,
+}
+
+impl DapCollectPriority {
+    /// Parse the value of the "dap-collect-priority" header. A missing header means the task's
+    /// default priority applies.
+    pub fn from_header(header: Option<&str>) -> Result<Option<Self>, DapAbort> {
+        let Some(header) = header.map(str::trim) else {
+            return Ok(None);
+        };
+        if header.eq_ignore_ascii_case("backfill") {
+            Ok(Some(Self::Backfill))
+        } else if header.eq_ignore_ascii_case("normal") {
+            Ok(Some(Self::Normal))
+        } else if header.eq_ignore_ascii_case("urgent") {
+            Ok(Some(Self::Urgent))
+        } else {
+            Err(DapAbort::BadRequest(format!(
+                "unrecognized collect priority: {header}"
+            )))
+        }
+    }
+
+    /// Header value for this priority.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Backfill => "backfill",
+            Self::Normal => "normal",
+            Self::Urgent => "urgent",
+        }
+    }
+}
+
 /// Telemetry information for the leader's processing loop.
 //
 // TODO This is used for tests. Perhaps Prometheus metrics would be sufficient?
@@ -947,11 +2417,64 @@ pub struct DapLeaderProcessTelemetry {
     pub reports_processed: u64,
 }
 
+impl std::ops::AddAssign for DapLeaderProcessTelemetry {
+    fn add_assign(&mut self, other: Self) {
+        self.reports_collected += other.reports_collected;
+        self.reports_aggregated += other.reports_aggregated;
+        self.reports_processed += other.reports_processed;
+    }
+}
+
+/// Outcome of a dry run of the aggregation flow for a sample of a task's pending reports. See
+/// [`DapLeader::dry_run_agg_job`](crate::roles::DapLeader::dry_run_agg_job).
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DapAggregationDryRun {
+    /// The number of reports in the sample.
+    pub reports: u64,
+
+    /// The number of reports that both Aggregators were able to prepare. These reports would have
+    /// been aggregated, unless the Helper rejected them upon finishing the aggregation job.
+    pub accepted: u64,
+
+    /// The number of reports that would have been rejected, broken down by the reason for the
+    /// rejection.
+    pub rejected: BTreeMap<String, u64>,
+}
+
+impl std::ops::AddAssign for DapAggregationDryRun {
+    fn add_assign(&mut self, other: Self) {
+        self.reports += other.reports;
+        self.accepted += other.accepted;
+        for (reason, count) in other.rejected {
+            *self.rejected.entry(reason).or_default() += count;
+        }
+    }
+}
+
+/// Outcome of checking whether a collection job would be accepted. See
+/// [`DapLeader::handle_collect_job_precheck_req`](crate::roles::DapLeader::handle_collect_job_precheck_req).
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DapCollectPrecheck {
+    /// If the collection job would be rejected, then the problem details of the abort.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<ProblemDetails>,
+
+    /// The number of reports aggregated into the batch by the Leader so far. This is an estimate
+    /// of the size of the batch, since the Helper may have rejected some of them.
+    pub report_count: u64,
+
+    /// Whether the report count meets the task's minimum batch size.
+    pub min_batch_size_met: bool,
+
+    /// Whether the collection job would be accepted and its batch could be collected now.
+    pub collectable: bool,
+}
+
 /// draft02 compatibility: A logical aggregation job ID. In the latest draft, this is a 32-byte
 /// string included in the HTTP request payload; in draft07, this is a 16-byte string included in
 /// the HTTP request path. This type unifies these into one type so that any protocol logic that
 /// is agnostic to these details can use the same object.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum MetaAggregationJobId<'a> {
     Draft02(Cow<'a, Draft02AggregationJobId>),
     Draft07(Cow<'a, AggregationJobId>),
@@ -1004,4 +2527,196 @@ impl MetaAggregationJobId<'_> {
             Self::Draft07(agg_job_id) => agg_job_id.to_base64url(),
         }
     }
+
+    /// Parse the hex form of an aggregation job ID of the type required for the version.
+    pub fn try_from_hex(version: &DapVersion, agg_job_id_hex: &str) -> Option<Self> {
+        match version {
+            DapVersion::Draft02 => Draft02AggregationJobId::try_from_hex(agg_job_id_hex)
+                .map(|agg_job_id| Self::Draft02(Cow::Owned(agg_job_id))),
+            DapVersion::Draft07 => AggregationJobId::try_from_hex(agg_job_id_hex)
+                .map(|agg_job_id| Self::Draft07(Cow::Owned(agg_job_id))),
+            DapVersion::Unknown => None,
+        }
+    }
+
+    /// Return a copy of the ID that does not borrow.
+    pub fn into_owned(self) -> MetaAggregationJobId<'static> {
+        match self {
+            Self::Draft02(agg_job_id) => {
+                MetaAggregationJobId::Draft02(Cow::Owned(agg_job_id.into_owned()))
+            }
+            Self::Draft07(agg_job_id) => {
+                MetaAggregationJobId::Draft07(Cow::Owned(agg_job_id.into_owned()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn parse_resource_path() {
+        let task_id = TaskId([1; 32]);
+        let t = task_id.to_base64url();
+        let agg_job_id = AggregationJobId([2; 16]);
+        let collect_job_id = CollectionJobId([3; 16]);
+        let report_id = ReportId([4; 16]);
+
+        assert_matches!(
+            DapResource::parse_path(&format!("/v07/tasks/{t}/aggregation_jobs/{}", agg_job_id.to_base64url())),
+            Ok(Some((ref id, DapResource::AggregationJob(ref got)))) if *id == task_id && *got == agg_job_id
+        );
+        assert_matches!(
+            DapResource::parse_path(&format!("/v07/tasks/{t}/collection_jobs/{}", collect_job_id.to_base64url())),
+            Ok(Some((_, DapResource::CollectionJob(ref got)))) if *got == collect_job_id
+        );
+        assert_matches!(
+            DapResource::parse_path(&format!("/v07/tasks/{t}/reports/{}", report_id.to_base64url())),
+            Ok(Some((_, DapResource::Report(ref got)))) if *got == report_id
+        );
+        for sub in [
+            "reports",
+            "reports:batch",
+            "collection_jobs",
+            "aggregate_shares",
+        ] {
+            assert_matches!(
+                DapResource::parse_path(&format!("/v07/tasks/{t}/{sub}")),
+                Ok(Some((ref id, DapResource::Undefined))) if *id == task_id
+            );
+        }
+
+        // Paths that don't pertain to a task resource are left to the router.
+        for path in [
+            "/v07/hpke_config",
+            "/v02/upload",
+            "/v07/tasks",
+            &format!("/v07/tasks/{t}/unknown"),
+        ] {
+            assert_matches!(DapResource::parse_path(path), Ok(None));
+        }
+
+        // Malformed IDs are rejected.
+        assert_matches!(
+            DapResource::parse_path("/v07/tasks/not-a-task/reports"),
+            Err(DapAbort::BadRequest(..))
+        );
+        assert_matches!(
+            DapResource::parse_path(&format!("/v07/tasks/{t}/aggregation_jobs/xyz")),
+            Err(DapAbort::BadRequest(detail)) if detail.contains("aggregation job")
+        );
+        assert_matches!(
+            DapResource::parse_path(&format!("/v07/tasks/{t}/collection_jobs/xyz")),
+            Err(DapAbort::BadRequest(detail)) if detail.contains("collection job")
+        );
+    }
+
+    #[test]
+    fn global_config_validate_min_acceptance_ratio() {
+        let mut global_config: DapGlobalConfig = serde_json::from_value(serde_json::json!({
+            "report_storage_epoch_duration": 604800,
+            "report_storage_max_future_time_skew": 300,
+            "max_batch_duration": 360000,
+            "min_batch_interval_start": 259200,
+            "max_batch_interval_end": 259200,
+            "supported_hpke_kems": ["x25519_hkdf_sha256"],
+        }))
+        .unwrap();
+        assert_matches!(global_config.validate(), Ok(()));
+
+        for ratio in [0.0, 0.5, 1.0] {
+            global_config.agg_job_min_acceptance_ratio = Some(ratio);
+            assert_matches!(global_config.validate(), Ok(()), "ratio {ratio}");
+        }
+
+        for ratio in [-0.1, 1.5, f64::NAN, f64::INFINITY] {
+            global_config.agg_job_min_acceptance_ratio = Some(ratio);
+            assert_matches!(
+                global_config.validate(),
+                Err(DapError::Fatal(..)),
+                "ratio {ratio}"
+            );
+        }
+    }
+
+    #[test]
+    fn global_config_validate_epoch_duration() {
+        let mut global_config: DapGlobalConfig = serde_json::from_value(serde_json::json!({
+            "report_storage_epoch_duration": 0,
+            "report_storage_max_future_time_skew": 300,
+            "max_batch_duration": 360000,
+            "min_batch_interval_start": 259200,
+            "max_batch_interval_end": 259200,
+            "supported_hpke_kems": ["x25519_hkdf_sha256"],
+        }))
+        .unwrap();
+        assert_matches!(global_config.validate(), Err(DapError::Fatal(..)));
+
+        global_config.report_storage_epoch_duration = 1;
+        assert_matches!(global_config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn task_config_validate_epoch_duration() {
+        let vdaf = VdafConfig::Prio3(Prio3Config::Count);
+        let mut task_config = DapTaskConfig {
+            version: DapVersion::Draft07,
+            leader_url: "https://leader.com".parse().unwrap(),
+            helper_url: "https://helper.org".parse().unwrap(),
+            time_precision: 3600,
+            expiration: u64::MAX,
+            min_batch_size: 1,
+            query: DapQueryConfig::TimeInterval,
+            vdaf_verify_key: vdaf.gen_verify_key(),
+            vdaf,
+            vdaf_verify_key_set: None,
+            collector_hpke_config: HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+                .unwrap()
+                .config,
+            taskprov: false,
+            batch_policy: Default::default(),
+            upload_receipts: false,
+            unknown_extensions: Default::default(),
+            report_ttl: None,
+            collect_priority: Default::default(),
+            client_auth_key: None,
+            alt_versions: Vec::new(),
+            report_storage_epoch_duration: None,
+            report_storage_epoch_migration: None,
+            sub_bucket_precision: None,
+        };
+        assert_matches!(task_config.validate_epoch_duration(), Ok(()));
+
+        task_config.report_storage_epoch_duration = Some(0);
+        assert_matches!(
+            task_config.validate_epoch_duration(),
+            Err(DapError::Fatal(..))
+        );
+
+        task_config.report_storage_epoch_duration = Some(1000);
+        task_config.report_storage_epoch_migration = Some(DapReportStorageEpochMigration {
+            previous_duration: 0,
+            until: 2000,
+        });
+        assert_matches!(
+            task_config.validate_epoch_duration(),
+            Err(DapError::Fatal(..))
+        );
+
+        task_config.report_storage_epoch_migration = Some(DapReportStorageEpochMigration {
+            previous_duration: 500,
+            until: 2000,
+        });
+        assert_matches!(task_config.validate_epoch_duration(), Ok(()));
+
+        assert_matches!(task_config.validate_time_precision(), Ok(()));
+        task_config.time_precision = 0;
+        assert_matches!(
+            task_config.validate_time_precision(),
+            Err(DapError::Fatal(..))
+        );
+    }
 }
@@ -0,0 +1,118 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Accounting of the memory used by in-flight aggregation jobs. The Helper reserves an estimate of
+//! the memory needed to initialize an aggregation job before decrypting its report shares. If the
+//! reservation would exceed the configured budget, then the job is shed with a retryable abort
+//! instead of risking the process being killed for running out of memory.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    fatal_error,
+    messages::{ReportId, Time},
+    vdaf::{EarlyReportStateConsumed, EarlyReportStateInitialized, VdafPrepState},
+    DapError, VdafConfig,
+};
+
+/// Tracks the number of bytes reserved by in-flight aggregation jobs.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    in_use: AtomicU64,
+}
+
+impl MemoryBudget {
+    pub const fn new() -> Self {
+        Self {
+            in_use: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of bytes currently reserved.
+    pub fn in_use(&self) -> u64 {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `bytes`, unless this would bring the number of bytes reserved above `limit`. The
+    /// reservation is released when the return value is dropped.
+    ///
+    /// A reservation is always granted if nothing else is reserved, so that a job that is larger
+    /// than the budget by itself is not shed forever.
+    pub fn try_reserve(&self, bytes: u64, limit: u64) -> Option<MemoryReservation<'_>> {
+        self.in_use
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_use| {
+                let total = in_use.saturating_add(bytes);
+                (in_use == 0 || total <= limit).then_some(total)
+            })
+            .ok()?;
+        Some(MemoryReservation {
+            budget: self,
+            bytes,
+        })
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`].
+#[derive(Debug)]
+pub struct MemoryReservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for MemoryReservation<'_> {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Estimate the peak memory used to initialize an aggregation job: the request payload, plus, for
+/// each report share, the decrypted input share, the preparation state, and the Helper's state.
+///
+/// The sizes of the shares are bounded by [`VdafConfig::max_share_sizes`]. The preparation state
+/// holds the expanded input share, which is counted a second time.
+pub(crate) fn agg_job_init_memory_estimate(
+    vdaf: &VdafConfig,
+    payload_len: usize,
+    num_reports: usize,
+) -> Result<u64, DapError> {
+    let share_sizes = vdaf.max_share_sizes()?;
+    let per_report = std::mem::size_of::<EarlyReportStateConsumed<'_>>()
+        + std::mem::size_of::<EarlyReportStateInitialized<'_>>()
+        + std::mem::size_of::<(VdafPrepState, Time, ReportId)>()
+        + share_sizes.public_share
+        + 2 * share_sizes.input_share;
+    u64::try_from(
+        per_report
+            .saturating_mul(num_reports)
+            .saturating_add(payload_len),
+    )
+    .map_err(|e| fatal_error!(err = ?e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryBudget;
+
+    #[test]
+    fn reserve() {
+        let budget = MemoryBudget::new();
+
+        let first = budget.try_reserve(60, 100).unwrap();
+        assert_eq!(budget.in_use(), 60);
+        assert!(budget.try_reserve(50, 100).is_none());
+        assert_eq!(budget.in_use(), 60);
+
+        let second = budget.try_reserve(40, 100).unwrap();
+        assert_eq!(budget.in_use(), 100);
+
+        drop(first);
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+
+        // A job that exceeds the budget by itself is admitted if nothing else is in flight.
+        let big = budget.try_reserve(1000, 100).unwrap();
+        assert!(budget.try_reserve(1, 100).is_none());
+        drop(big);
+        assert_eq!(budget.in_use(), 0);
+    }
+}
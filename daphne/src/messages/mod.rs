@@ -2,10 +2,19 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 //! Messages in the DAP protocol.
+//!
+//! Every message type implements `Serialize` and `Deserialize` when the "serde-messages" feature
+//! is enabled, e.g., for JSON-based tooling. Byte strings are encoded in hex, with the exception
+//! of the types that implement these traits regardless of the feature ([`Extension`], [`Report`],
+//! [`ReportShare`] and [`CollectionReq`]): their encoding predates the feature, so their byte
+//! strings, other than IDs and the fields of [`HpkeCiphertext`], are encoded as arrays of numbers.
+//! Note that the JSON encoding is not part of DAP and is not stable across versions of this
+//! crate.
 
 pub mod taskprov;
 
 use crate::{
+    error::DapAbort,
     fatal_error,
     hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
     DapError, DapVersion,
@@ -18,6 +27,7 @@ use prio::codec::{
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
     collections::HashSet,
     convert::{TryFrom, TryInto},
     fmt,
@@ -33,7 +43,11 @@ const FIXED_SIZE_QUERY_TYPE_BY_BATCH_ID: u8 = 0x00;
 const FIXED_SIZE_QUERY_TYPE_CURRENT_BATCH: u8 = 0x01;
 
 // Known extension types.
-const EXTENSION_TASKPROV: u16 = 0xff00;
+pub(crate) const EXTENSION_TASKPROV: u16 = 0xff00;
+pub(crate) const EXTENSION_CLIENT_AUTH: u16 = 0xff01;
+
+// Known aggregation job initialization extension types.
+const AGG_JOB_INIT_EXTENSION_VDAF_VERIFY_KEY_ID: u16 = 0xff00;
 
 // Serde doesn't support derivations from const generics properly, so we have to use a macro.
 macro_rules! id_struct {
@@ -58,6 +72,13 @@ macro_rules! id_struct {
             pub fn try_from_base64url<T: AsRef<str>>(id_base64url: T) -> Option<Self> {
                 Some($sname(decode_base64url(id_base64url.as_ref())?))
             }
+
+            /// Decode from a hex string.
+            pub fn try_from_hex<T: AsRef<str>>(id_hex: T) -> Option<Self> {
+                let mut data = [0; $len];
+                hex::decode_to_slice(id_hex.as_ref(), &mut data).ok()?;
+                Some($sname(data))
+            }
         }
 
         impl Encode for $sname {
@@ -125,8 +146,18 @@ pub type Time = u64;
 #[serde(rename_all = "snake_case")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum Extension {
-    Taskprov { payload: Vec<u8> }, // Not a TaskConfig to make computing the expected task id more efficient
-    Unhandled { typ: u16, payload: Vec<u8> },
+    Taskprov {
+        payload: Vec<u8>,
+    }, // Not a TaskConfig to make computing the expected task id more efficient
+    /// Token authenticating the Client that generated the report. See
+    /// [`DapClientAuthKey`](crate::extensions::DapClientAuthKey).
+    ClientAuth {
+        payload: Vec<u8>,
+    },
+    Unhandled {
+        typ: u16,
+        payload: Vec<u8>,
+    },
 }
 
 impl Extension {
@@ -134,6 +165,7 @@ impl Extension {
     fn type_code(&self) -> u16 {
         match self {
             Self::Taskprov { .. } => EXTENSION_TASKPROV,
+            Self::ClientAuth { .. } => EXTENSION_CLIENT_AUTH,
             Self::Unhandled { typ, .. } => *typ,
         }
     }
@@ -146,6 +178,10 @@ impl Encode for Extension {
                 EXTENSION_TASKPROV.encode(bytes);
                 encode_u16_bytes(bytes, payload);
             }
+            Self::ClientAuth { payload } => {
+                EXTENSION_CLIENT_AUTH.encode(bytes);
+                encode_u16_bytes(bytes, payload);
+            }
             Self::Unhandled { typ, payload } => {
                 typ.encode(bytes);
                 encode_u16_bytes(bytes, payload);
@@ -160,6 +196,7 @@ impl Decode for Extension {
         let payload = decode_u16_bytes(bytes)?;
         match typ {
             EXTENSION_TASKPROV => Ok(Self::Taskprov { payload }),
+            EXTENSION_CLIENT_AUTH => Ok(Self::ClientAuth { payload }),
             _ => Ok(Self::Unhandled { typ, payload }),
         }
     }
@@ -202,16 +239,13 @@ impl ParameterizedDecode<DapVersion> for ReportMetadata {
                 _ => Vec::new(),
             },
         };
-        // Check for duplicate extensions and unknown extensions.
+        // Check for duplicate extensions. Whether unknown extensions are acceptable is decided
+        // later on by the Aggregator (see [`crate::extensions`]).
         let mut seen: HashSet<u16> = HashSet::new();
         for extension in &metadata.extensions {
             if !seen.insert(extension.type_code()) {
                 return Err(CodecError::UnexpectedValue);
             }
-            if matches!(extension, Extension::Unhandled { .. }) {
-                // Unrecognized extensions are an error.
-                return Err(CodecError::UnexpectedValue);
-            }
         }
         Ok(metadata)
     }
@@ -225,6 +259,12 @@ pub struct Report {
     pub report_metadata: ReportMetadata,
     pub public_share: Vec<u8>,
     pub encrypted_input_shares: Vec<HpkeCiphertext>,
+
+    /// Opaque, deployment-specific tag attached to the report by the Leader upon upload, e.g., the
+    /// Client's platform. This is not part of the wire encoding: the annotation is carried through
+    /// aggregation and counted in [`crate::DapAggregateShare::annotations`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<String>,
 }
 
 impl ParameterizedEncode<DapVersion> for Report {
@@ -257,6 +297,113 @@ impl ParameterizedDecode<DapVersion> for Report {
             report_metadata: ReportMetadata::decode_with_param(version, bytes)?,
             public_share: decode_u32_bytes(bytes)?,
             encrypted_input_shares: decode_u32_items(&(), bytes)?,
+            annotation: None,
+        })
+    }
+}
+
+/// Not part of DAP: A batch of reports uploaded to the Leader in a single request. The body is a
+/// sequence of encoded reports, each prefixed by its length. Reports are decoded one at a time so
+/// that a malformed report does not cause the rest of the batch to be rejected.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+pub struct ReportBatch {
+    #[cfg_attr(any(test, feature = "serde-messages"), serde(with = "hex_vec"))]
+    pub encoded_reports: Vec<Vec<u8>>,
+}
+
+impl ReportBatch {
+    /// Encode a sequence of reports as a batch.
+    pub fn from_reports<'a>(
+        version: &DapVersion,
+        reports: impl IntoIterator<Item = &'a Report>,
+    ) -> Self {
+        Self {
+            encoded_reports: reports
+                .into_iter()
+                .map(|report| report.get_encoded_with_param(version))
+                .collect(),
+        }
+    }
+}
+
+impl Encode for ReportBatch {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        for encoded_report in &self.encoded_reports {
+            encode_u32_bytes(bytes, encoded_report);
+        }
+    }
+}
+
+impl Decode for ReportBatch {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let mut encoded_reports = Vec::new();
+        while (bytes.position() as usize) < bytes.get_ref().len() {
+            encoded_reports.push(decode_u32_bytes(bytes)?);
+        }
+        Ok(Self { encoded_reports })
+    }
+}
+
+/// Not part of DAP: The outcome of uploading one report of a [`ReportBatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+#[cfg_attr(
+    any(test, feature = "serde-messages"),
+    serde(rename_all = "snake_case")
+)]
+pub enum ReportUploadResult {
+    Accepted,
+    /// The report was rejected. `reason` is the type of the abort the Leader would have sent had
+    /// the report been uploaded by itself, e.g., "reportRejected".
+    Rejected {
+        reason: String,
+    },
+}
+
+impl Encode for ReportUploadResult {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Accepted => 0_u8.encode(bytes),
+            Self::Rejected { reason } => {
+                1_u8.encode(bytes);
+                encode_u16_bytes(bytes, reason.as_bytes());
+            }
+        }
+    }
+}
+
+impl Decode for ReportUploadResult {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        match u8::decode(bytes)? {
+            0 => Ok(Self::Accepted),
+            1 => Ok(Self::Rejected {
+                reason: String::from_utf8(decode_u16_bytes(bytes)?)
+                    .map_err(|e| CodecError::Other(Box::new(e)))?,
+            }),
+            _ => Err(CodecError::UnexpectedValue),
+        }
+    }
+}
+
+/// Not part of DAP: The Leader's response to a [`ReportBatch`]. There is one result for each report
+/// in the batch, in the same order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+pub struct ReportBatchResp {
+    pub results: Vec<ReportUploadResult>,
+}
+
+impl Encode for ReportBatchResp {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        encode_u32_items(bytes, &(), &self.results);
+    }
+}
+
+impl Decode for ReportBatchResp {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            results: decode_u32_items(&(), bytes)?,
         })
     }
 }
@@ -292,6 +439,40 @@ impl ParameterizedDecode<DapVersion> for ReportShare {
     }
 }
 
+/// A view of a [`ReportShare`] that borrows the public share and encrypted input share from the
+/// buffer it was decoded from. Used by the Helper to avoid copying report shares when processing
+/// an [`AggregationJobInitReq`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct ReportShareRef<'a> {
+    pub report_metadata: Cow<'a, ReportMetadata>,
+    pub public_share: &'a [u8],
+    pub encrypted_input_share: HpkeCiphertextRef<'a>,
+}
+
+impl<'a> ReportShareRef<'a> {
+    fn decode_borrowed(
+        version: &DapVersion,
+        bytes: &mut Cursor<&'a [u8]>,
+    ) -> Result<Self, CodecError> {
+        Ok(Self {
+            report_metadata: Cow::Owned(ReportMetadata::decode_with_param(version, bytes)?),
+            public_share: decode_u32_bytes_borrowed(bytes)?,
+            encrypted_input_share: HpkeCiphertextRef::decode_borrowed(bytes)?,
+        })
+    }
+}
+
+impl<'a> From<&'a ReportShare> for ReportShareRef<'a> {
+    fn from(report_share: &'a ReportShare) -> Self {
+        Self {
+            report_metadata: Cow::Borrowed(&report_share.report_metadata),
+            public_share: &report_share.public_share,
+            encrypted_input_share: HpkeCiphertextRef::from(&report_share.encrypted_input_share),
+        }
+    }
+}
+
 /// Batch parameter conveyed to the Helper by the Leader in the aggregation sub-protocol. Used to
 /// identify which batch the reports in the [`AggregationJobInitReq`] are intended for.
 #[derive(Clone, Debug, Eq, Deserialize, Hash, PartialEq, Serialize)]
@@ -380,7 +561,7 @@ impl Decode for BatchSelector {
     fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
         match u8::decode(bytes)? {
             QUERY_TYPE_TIME_INTERVAL => Ok(Self::TimeInterval {
-                batch_interval: Interval::decode(bytes)?,
+                batch_interval: decode_batch_interval(bytes)?,
             }),
             QUERY_TYPE_FIXED_SIZE => Ok(Self::FixedSizeByBatchId {
                 batch_id: BatchId::decode(bytes)?,
@@ -412,14 +593,77 @@ impl TryFrom<Query> for BatchSelector {
     }
 }
 
+/// An extension of an [`AggregationJobInitReq`], by which the Leader tells the Helper how to
+/// initialize the aggregation job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+pub enum AggregationJobInitExtension {
+    /// ID of the VDAF verification key used for the aggregation job. See
+    /// [`VdafVerifyKeySet`](crate::vdaf::VdafVerifyKeySet).
+    VdafVerifyKeyId {
+        key_id: u8,
+    },
+    Unhandled {
+        typ: u16,
+        payload: Vec<u8>,
+    },
+}
+
+impl Encode for AggregationJobInitExtension {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::VdafVerifyKeyId { key_id } => {
+                AGG_JOB_INIT_EXTENSION_VDAF_VERIFY_KEY_ID.encode(bytes);
+                encode_u16_bytes(bytes, &[*key_id]);
+            }
+            Self::Unhandled { typ, payload } => {
+                typ.encode(bytes);
+                encode_u16_bytes(bytes, payload);
+            }
+        }
+    }
+}
+
+impl Decode for AggregationJobInitExtension {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let typ = u16::decode(bytes)?;
+        let payload = decode_u16_bytes(bytes)?;
+        match typ {
+            AGG_JOB_INIT_EXTENSION_VDAF_VERIFY_KEY_ID => Ok(Self::VdafVerifyKeyId {
+                key_id: u8::get_decoded(&payload)?,
+            }),
+            _ => Ok(Self::Unhandled { typ, payload }),
+        }
+    }
+}
+
 /// Aggregate initialization request.
+///
+/// The request may be followed by a list of [`AggregationJobInitExtension`]s. This is not part of
+/// DAP, so the list is only encoded if it is not empty; requests without extensions are
+/// compatible with other implementations.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 pub struct AggregationJobInitReq {
     pub draft02_task_id: Option<TaskId>, // Set in draft02
     pub draft02_agg_job_id: Option<Draft02AggregationJobId>, // Set in draft02
+    #[cfg_attr(any(test, feature = "serde-messages"), serde(with = "hex"))]
     pub agg_param: Vec<u8>,
     pub part_batch_sel: PartialBatchSelector,
     pub report_shares: Vec<ReportShare>,
+    #[cfg_attr(any(test, feature = "serde-messages"), serde(default))]
+    pub extensions: Vec<AggregationJobInitExtension>,
+}
+
+/// Decode the extensions that follow an [`AggregationJobInitReq`], if any.
+fn decode_agg_job_init_extensions(
+    bytes: &mut Cursor<&[u8]>,
+) -> Result<Vec<AggregationJobInitExtension>, CodecError> {
+    if bytes.position() < bytes.get_ref().len() as u64 {
+        decode_u16_items(&(), bytes)
+    } else {
+        Ok(Vec::new())
+    }
 }
 
 impl ParameterizedEncode<DapVersion> for AggregationJobInitReq {
@@ -441,6 +685,9 @@ impl ParameterizedEncode<DapVersion> for AggregationJobInitReq {
         };
         self.part_batch_sel.encode(bytes);
         encode_u32_items(bytes, version, &self.report_shares);
+        if !self.extensions.is_empty() {
+            encode_u16_items(bytes, &(), &self.extensions);
+        }
     }
 }
 
@@ -465,12 +712,85 @@ impl ParameterizedDecode<DapVersion> for AggregationJobInitReq {
             agg_param,
             part_batch_sel: PartialBatchSelector::decode(bytes)?,
             report_shares: decode_u32_items(version, bytes)?,
+            extensions: decode_agg_job_init_extensions(bytes)?,
+        })
+    }
+}
+
+/// A view of an [`AggregationJobInitReq`] whose report shares borrow from the buffer it was
+/// decoded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregationJobInitReqRef<'a> {
+    pub draft02_task_id: Option<TaskId>, // Set in draft02
+    pub draft02_agg_job_id: Option<Draft02AggregationJobId>, // Set in draft02
+    pub agg_param: &'a [u8],
+    pub part_batch_sel: PartialBatchSelector,
+    pub report_shares: Vec<ReportShareRef<'a>>,
+    pub extensions: Vec<AggregationJobInitExtension>,
+}
+
+impl<'a> AggregationJobInitReqRef<'a> {
+    /// Decode the request from `bytes` without copying the report shares. As with
+    /// [`ParameterizedDecode::get_decoded_with_param`], it is an error if any bytes are left over.
+    pub fn get_decoded_borrowed(version: &DapVersion, bytes: &'a [u8]) -> Result<Self, CodecError> {
+        let mut bytes = Cursor::new(bytes);
+        let (draft02_task_id, draft02_agg_job_id, agg_param) = match version {
+            DapVersion::Draft02 => (
+                Some(TaskId::decode(&mut bytes)?),
+                Some(Draft02AggregationJobId::decode(&mut bytes)?),
+                decode_u16_bytes_borrowed(&mut bytes)?,
+            ),
+            DapVersion::Draft07 => (None, None, decode_u32_bytes_borrowed(&mut bytes)?),
+            DapVersion::Unknown => unreachable!("unhandled version {version:?}"),
+        };
+        let part_batch_sel = PartialBatchSelector::decode(&mut bytes)?;
+
+        let mut report_shares_bytes = Cursor::new(decode_u32_bytes_borrowed(&mut bytes)?);
+        let mut report_shares = Vec::new();
+        while report_shares_bytes.position() < report_shares_bytes.get_ref().len() as u64 {
+            report_shares.push(ReportShareRef::decode_borrowed(
+                version,
+                &mut report_shares_bytes,
+            )?);
+        }
+        let extensions = decode_agg_job_init_extensions(&mut bytes)?;
+
+        let remaining = bytes.get_ref().len() as u64 - bytes.position();
+        if remaining > 0 {
+            return Err(CodecError::BytesLeftOver(remaining as usize));
+        }
+
+        Ok(Self {
+            draft02_task_id,
+            draft02_agg_job_id,
+            agg_param,
+            part_batch_sel,
+            report_shares,
+            extensions,
         })
     }
 }
 
+impl<'a> From<&'a AggregationJobInitReq> for AggregationJobInitReqRef<'a> {
+    fn from(agg_job_init_req: &'a AggregationJobInitReq) -> Self {
+        Self {
+            draft02_task_id: agg_job_init_req.draft02_task_id.clone(),
+            draft02_agg_job_id: agg_job_init_req.draft02_agg_job_id.clone(),
+            agg_param: &agg_job_init_req.agg_param,
+            part_batch_sel: agg_job_init_req.part_batch_sel.clone(),
+            report_shares: agg_job_init_req
+                .report_shares
+                .iter()
+                .map(ReportShareRef::from)
+                .collect(),
+            extensions: agg_job_init_req.extensions.clone(),
+        }
+    }
+}
+
 /// Aggregate continuation request.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 pub struct AggregationJobContinueReq {
     pub draft02_task_id: Option<TaskId>, // Set in draft02
     pub draft02_agg_job_id: Option<Draft02AggregationJobId>, // Set in draft02
@@ -526,11 +846,60 @@ impl ParameterizedDecode<DapVersion> for AggregationJobContinueReq {
     }
 }
 
+/// Not part of DAP: request to abandon an aggregation job. The message is empty in draft07 and
+/// later, since the task and aggregation job are identified by the request path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+pub struct AggregationJobAbandonReq {
+    pub draft02_task_id: Option<TaskId>, // Set in draft02
+    pub draft02_agg_job_id: Option<Draft02AggregationJobId>, // Set in draft02
+}
+
+impl ParameterizedEncode<DapVersion> for AggregationJobAbandonReq {
+    fn encode_with_param(&self, version: &DapVersion, bytes: &mut Vec<u8>) {
+        match version {
+            DapVersion::Draft02 => {
+                self.draft02_task_id
+                    .as_ref()
+                    .expect("draft02: missing task ID")
+                    .encode(bytes);
+                self.draft02_agg_job_id
+                    .as_ref()
+                    .expect("draft02: missing aggregation job ID")
+                    .encode(bytes);
+            }
+            DapVersion::Draft07 => (),
+            DapVersion::Unknown => unreachable!("unhandled version {version:?}"),
+        };
+    }
+}
+
+impl ParameterizedDecode<DapVersion> for AggregationJobAbandonReq {
+    fn decode_with_param(
+        version: &DapVersion,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<Self, CodecError> {
+        let (draft02_task_id, draft02_agg_job_id) = match version {
+            DapVersion::Draft02 => (
+                Some(TaskId::decode(bytes)?),
+                Some(Draft02AggregationJobId::decode(bytes)?),
+            ),
+            DapVersion::Draft07 => (None, None),
+            DapVersion::Unknown => unreachable!("unhandled version {version:?}"),
+        };
+        Ok(Self {
+            draft02_task_id,
+            draft02_agg_job_id,
+        })
+    }
+}
+
 /// Transition message. This conveyes a message sent from one Aggregator to another during the
 /// preparation phase of VDAF evaluation.
 //
 // TODO Consider renaming this to `PrepareStep` to align with draft07.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct Transition {
     pub report_id: ReportId,
@@ -555,9 +924,14 @@ impl Decode for Transition {
 
 /// Transition message variant.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+#[cfg_attr(
+    any(test, feature = "serde-messages"),
+    serde(rename_all = "snake_case")
+)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum TransitionVar {
-    Continued(Vec<u8>),
+    Continued(#[cfg_attr(any(test, feature = "serde-messages"), serde(with = "hex"))] Vec<u8>),
     Finished,
     Failed(TransitionFailure),
 }
@@ -606,6 +980,10 @@ pub enum TransitionFailure {
     TaskExpired = 7,
     UnrecognizedMessage = 8,
     ReportTooEarly = 9,
+    /// The report's client authentication token is missing or invalid. This failure is not
+    /// defined by DAP, so it is only used to label metrics and logs; it is encoded as
+    /// [`Self::VdafPrepError`].
+    ClientAuthFailed,
 }
 
 impl TryFrom<u8> for TransitionFailure {
@@ -630,7 +1008,11 @@ impl TryFrom<u8> for TransitionFailure {
 
 impl Encode for TransitionFailure {
     fn encode(&self, bytes: &mut Vec<u8>) {
-        (*self as u8).encode(bytes);
+        let failure = match self {
+            Self::ClientAuthFailed => Self::VdafPrepError,
+            failure => *failure,
+        };
+        (failure as u8).encode(bytes);
     }
 }
 
@@ -653,12 +1035,15 @@ impl std::fmt::Display for TransitionFailure {
             Self::TaskExpired => write!(f, "task_expired"),
             Self::UnrecognizedMessage => write!(f, "unrecognized_message"),
             Self::ReportTooEarly => write!(f, "report_too_early"),
+            Self::ClientAuthFailed => write!(f, "client_auth_failed"),
         }
     }
 }
 
 /// An aggregate response sent from the Helper to the Leader.
-#[derive(Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 #[allow(missing_docs)]
 pub struct AggregationJobResp {
     pub transitions: Vec<Transition>,
@@ -678,6 +1063,96 @@ impl Decode for AggregationJobResp {
     }
 }
 
+/// Size, in bytes, of each chunk of the encoding produced by [`AggregationJobRespWriter`].
+pub const AGGREGATION_JOB_RESP_CHUNK_SIZE: usize = 1 << 16;
+
+/// Incremental encoder for [`AggregationJobResp`]. Transitions are encoded as they are produced
+/// into a sequence of chunks of roughly [`AGGREGATION_JOB_RESP_CHUNK_SIZE`] bytes each, so that the
+/// response never has to be held in memory both as a sequence of transitions and as its encoding,
+/// and so that it can be streamed to the peer one chunk at a time. The concatenation of the chunks
+/// is identical to `AggregationJobResp::get_encoded()` for the same sequence of transitions.
+#[derive(Debug)]
+pub struct AggregationJobRespWriter {
+    chunks: Vec<Vec<u8>>,
+    chunk_size: usize,
+    encoded_len: usize,
+    num_transitions: usize,
+    num_failed: usize,
+}
+
+impl Default for AggregationJobRespWriter {
+    fn default() -> Self {
+        Self::with_chunk_size(AGGREGATION_JOB_RESP_CHUNK_SIZE)
+    }
+}
+
+impl AggregationJobRespWriter {
+    /// Create a writer that starts a new chunk once the current one has at least `chunk_size`
+    /// bytes.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        let mut bytes = Vec::with_capacity(chunk_size.max(4));
+        // Reserve space for the length prefix, which is written by `finish_chunks()`.
+        0_u32.encode(&mut bytes);
+        Self {
+            chunks: vec![bytes],
+            chunk_size,
+            encoded_len: 0,
+            num_transitions: 0,
+            num_failed: 0,
+        }
+    }
+
+    /// Encode the next transition.
+    pub fn push(&mut self, transition: &Transition) {
+        if self
+            .chunks
+            .last()
+            .map_or(true, |chunk| chunk.len() >= self.chunk_size)
+        {
+            self.chunks.push(Vec::with_capacity(self.chunk_size));
+        }
+        let chunk = self.chunks.last_mut().expect("no chunk to write to");
+        let chunk_len = chunk.len();
+        transition.encode(chunk);
+        self.encoded_len += chunk.len() - chunk_len;
+        self.num_transitions += 1;
+        if matches!(transition.var, TransitionVar::Failed(..)) {
+            self.num_failed += 1;
+        }
+    }
+
+    /// Number of transitions written so far.
+    pub fn len(&self) -> usize {
+        self.num_transitions
+    }
+
+    /// Returns `true` if no transitions have been written.
+    pub fn is_empty(&self) -> bool {
+        self.num_transitions == 0
+    }
+
+    /// Number of transitions written so far that indicate the report share was rejected.
+    pub fn num_failed(&self) -> usize {
+        self.num_failed
+    }
+
+    /// Write the length prefix and return the encoded response as a sequence of chunks.
+    ///
+    /// Panics if the encoded transitions are longer than `u32::MAX` bytes.
+    pub fn finish_chunks(mut self) -> Vec<Vec<u8>> {
+        let len = u32::try_from(self.encoded_len).expect("Length too large");
+        self.chunks[0][..4].copy_from_slice(&len.to_be_bytes());
+        self.chunks
+    }
+
+    /// Write the length prefix and return the encoded response.
+    ///
+    /// Panics if the encoded transitions are longer than `u32::MAX` bytes.
+    pub fn finish(self) -> Vec<u8> {
+        self.finish_chunks().concat()
+    }
+}
+
 /// A batch interval.
 #[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
@@ -687,10 +1162,26 @@ pub struct Interval {
 }
 
 impl Interval {
-    /// Return the end of the interval, i.e., `self.start + self.duration`.
+    /// Return the end of the interval, i.e., `self.start + self.duration`. The interval is assumed
+    /// to be valid; use [`Self::try_end`] for intervals that have not been checked.
     pub fn end(&self) -> Time {
         self.start + self.duration
     }
+
+    /// Return the end of the interval, or `None` if computing it would overflow.
+    pub fn try_end(&self) -> Option<Time> {
+        self.start.checked_add(self.duration)
+    }
+
+    /// Check that the interval is a valid batch interval for a task with the given time
+    /// precision: its start and duration must be multiples of the time precision, its duration
+    /// must not be zero, and its end must not overflow.
+    pub fn is_aligned_to(&self, time_precision: Duration) -> bool {
+        self.start.checked_rem(time_precision) == Some(0)
+            && self.duration.checked_rem(time_precision) == Some(0)
+            && self.duration != 0
+            && self.try_end().is_some()
+    }
 }
 
 impl Encode for Interval {
@@ -709,6 +1200,16 @@ impl Decode for Interval {
     }
 }
 
+/// Decode the batch interval of a query or batch selector. An interval whose end overflows is not
+/// valid for any task, so it is rejected as malformed.
+fn decode_batch_interval(bytes: &mut Cursor<&[u8]>) -> Result<Interval, CodecError> {
+    let batch_interval = Interval::decode(bytes)?;
+    if batch_interval.try_end().is_none() {
+        return Err(CodecError::UnexpectedValue);
+    }
+    Ok(batch_interval)
+}
+
 /// A query issued by the Collector in a collect request.
 #[derive(Clone, Debug, Deserialize, Hash, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -751,7 +1252,7 @@ impl ParameterizedDecode<DapVersion> for Query {
     ) -> Result<Self, CodecError> {
         match u8::decode(bytes)? {
             QUERY_TYPE_TIME_INTERVAL => Ok(Self::TimeInterval {
-                batch_interval: Interval::decode(bytes)?,
+                batch_interval: decode_batch_interval(bytes)?,
             }),
             QUERY_TYPE_FIXED_SIZE => {
                 if *decoding_parameter == DapVersion::Draft02 {
@@ -848,6 +1349,99 @@ pub struct Collection {
     pub encrypted_agg_shares: Vec<HpkeCiphertext>,
 }
 
+impl Collection {
+    /// Return a view of the fields of the collection that are not encrypted.
+    pub fn metadata(&self) -> CollectionMetadata<'_> {
+        CollectionMetadata {
+            part_batch_sel: &self.part_batch_sel,
+            report_count: self.report_count,
+            interval: self.interval.as_ref(),
+        }
+    }
+
+    /// Return the ID of the collected batch, if the task uses fixed-size queries.
+    pub fn batch_id(&self) -> Option<&BatchId> {
+        match &self.part_batch_sel {
+            PartialBatchSelector::TimeInterval => None,
+            PartialBatchSelector::FixedSizeByBatchId { batch_id } => Some(batch_id),
+        }
+    }
+
+    /// Check that the collection is a well-formed response to the given collection request, prior
+    /// to decrypting the aggregate shares:
+    ///
+    /// - the partial batch selector must match the query type (and, for fixed-size queries by
+    ///   batch ID, the batch ID) of the request;
+    /// - the interval, if present, must be valid and, for time-interval queries, must be contained
+    ///   in the batch interval of the request; and
+    /// - there must be exactly one encrypted aggregate share for each Aggregator.
+    pub fn validate_against_request(&self, req: &CollectionReq) -> Result<(), DapAbort> {
+        let invalid = |detail: String| DapAbort::UnrecognizedMessage {
+            detail,
+            task_id: req.draft02_task_id.clone(),
+        };
+
+        match (&req.query, &self.part_batch_sel) {
+            (Query::TimeInterval { .. }, PartialBatchSelector::TimeInterval)
+            | (Query::FixedSizeCurrentBatch, PartialBatchSelector::FixedSizeByBatchId { .. }) => {}
+            (
+                Query::FixedSizeByBatchId { batch_id },
+                PartialBatchSelector::FixedSizeByBatchId {
+                    batch_id: collected_batch_id,
+                },
+            ) => {
+                if batch_id != collected_batch_id {
+                    return Err(invalid(format!(
+                        "collection is for batch {collected_batch_id}, but batch {batch_id} \
+                        was requested"
+                    )));
+                }
+            }
+            (query, part_batch_sel) => {
+                return Err(invalid(format!(
+                    "collection has partial batch selector {part_batch_sel}, which does not \
+                    match the query {query:?}"
+                )));
+            }
+        }
+
+        if let Some(interval) = &self.interval {
+            let Some(end) = interval.try_end() else {
+                return Err(invalid("collection interval overflows".into()));
+            };
+            if let Query::TimeInterval { batch_interval } = &req.query {
+                if interval.start < batch_interval.start
+                    || batch_interval
+                        .try_end()
+                        .map_or(true, |batch_end| end > batch_end)
+                {
+                    return Err(invalid(
+                        "collection interval is not contained in the batch interval".into(),
+                    ));
+                }
+            }
+        }
+
+        if self.encrypted_agg_shares.len() != 2 {
+            return Err(invalid(format!(
+                "collection has {} encrypted aggregate shares, expected 2",
+                self.encrypted_agg_shares.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A view of the fields of a [`Collection`] that can be inspected before the aggregate shares are
+/// decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollectionMetadata<'a> {
+    pub part_batch_sel: &'a PartialBatchSelector,
+    pub report_count: u64,
+    pub interval: Option<&'a Interval>, // Not set in draft02
+}
+
 impl ParameterizedEncode<DapVersion> for Collection {
     fn encode_with_param(&self, version: &DapVersion, bytes: &mut Vec<u8>) {
         self.part_batch_sel.encode(bytes);
@@ -888,11 +1482,14 @@ impl ParameterizedDecode<DapVersion> for Collection {
 //
 // TODO Add serialization tests.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 pub struct AggregateShareReq {
     pub draft02_task_id: Option<TaskId>, // Set in draft02
     pub batch_sel: BatchSelector,
+    #[cfg_attr(any(test, feature = "serde-messages"), serde(with = "hex"))]
     pub agg_param: Vec<u8>,
     pub report_count: u64,
+    #[cfg_attr(any(test, feature = "serde-messages"), serde(with = "hex"))]
     pub checksum: [u8; 32],
 }
 
@@ -954,6 +1551,7 @@ impl ParameterizedDecode<DapVersion> for AggregateShareReq {
 //
 // TODO Add serialization tests.
 #[derive(Debug)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 pub struct AggregateShare {
     pub encrypted_agg_share: HpkeCiphertext,
 }
@@ -974,6 +1572,7 @@ impl Decode for AggregateShare {
 
 /// A list of HPKE public key configurations.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 pub struct HpkeConfigList {
     pub hpke_configs: Vec<HpkeConfig>,
 }
@@ -1080,11 +1679,42 @@ impl Decode for HpkeCiphertext {
     }
 }
 
+/// A view of an [`HpkeCiphertext`] that borrows the encapsulated key and payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct HpkeCiphertextRef<'a> {
+    pub config_id: u8,
+    pub enc: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> HpkeCiphertextRef<'a> {
+    fn decode_borrowed(bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            config_id: u8::decode(bytes)?,
+            enc: decode_u16_bytes_borrowed(bytes)?,
+            payload: decode_u32_bytes_borrowed(bytes)?,
+        })
+    }
+}
+
+impl<'a> From<&'a HpkeCiphertext> for HpkeCiphertextRef<'a> {
+    fn from(ciphertext: &'a HpkeCiphertext) -> Self {
+        Self {
+            config_id: ciphertext.config_id,
+            enc: &ciphertext.enc,
+            payload: &ciphertext.payload,
+        }
+    }
+}
+
 /// A plaintext input share.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde-messages"), derive(Deserialize, Serialize))]
 #[allow(missing_docs)]
 pub struct PlaintextInputShare {
     pub extensions: Vec<Extension>,
+    #[cfg_attr(any(test, feature = "serde-messages"), serde(with = "hex"))]
     pub payload: Vec<u8>,
 }
 
@@ -1101,15 +1731,13 @@ impl Decode for PlaintextInputShare {
             extensions: decode_u16_items(&(), bytes)?,
             payload: decode_u32_bytes(bytes)?,
         };
-        // Check for duplicate extensions and unknown extensions.
+        // Check for duplicate extensions. Whether unknown extensions are acceptable is decided
+        // later on by the Aggregator (see [`crate::extensions`]).
         let mut seen: HashSet<u16> = HashSet::new();
         for extension in &share.extensions {
             if !seen.insert(extension.type_code()) {
                 return Err(CodecError::UnexpectedValue);
             }
-            if matches!(extension, Extension::Unhandled { .. }) {
-                return Err(CodecError::UnexpectedValue);
-            }
         }
         Ok(share)
     }
@@ -1157,6 +1785,28 @@ pub(crate) fn decode_u32_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Vec<u8>, Cod
     Ok(out)
 }
 
+fn decode_u16_bytes_borrowed<'a>(bytes: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = u16::decode(bytes)? as usize;
+    take_borrowed(bytes, len)
+}
+
+fn decode_u32_bytes_borrowed<'a>(bytes: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = u32::decode(bytes)? as usize;
+    take_borrowed(bytes, len)
+}
+
+/// Return the next `len` bytes of the cursor without copying them.
+fn take_borrowed<'a>(bytes: &mut Cursor<&'a [u8]>, len: usize) -> Result<&'a [u8], CodecError> {
+    let buf: &'a [u8] = bytes.get_ref();
+    let start = bytes.position() as usize;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= buf.len())
+        .ok_or_else(|| CodecError::Io(std::io::ErrorKind::UnexpectedEof.into()))?;
+    bytes.set_position(end as u64);
+    Ok(&buf[start..end])
+}
+
 /// Encode the input bytes as a URL-safe, base64 string.
 pub fn encode_base64url<T: AsRef<[u8]>>(input: T) -> String {
     URL_SAFE_NO_PAD.encode(input)
@@ -1183,11 +1833,34 @@ pub fn decode_base64url_vec<T: AsRef<[u8]>>(input: T) -> Option<Vec<u8>> {
     URL_SAFE_NO_PAD.decode(input).ok()
 }
 
+/// Serde helpers for encoding a sequence of byte strings as a sequence of hex strings.
+#[cfg(any(test, feature = "serde-messages"))]
+mod hex_vec {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        vecs: &[Vec<u8>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(vecs.iter().map(hex::encode))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|s| hex::decode(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     use crate::test_versions;
+    use assert_matches::assert_matches;
     use hpke_rs::HpkePublicKey;
     use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
     use rand::prelude::*;
@@ -1200,6 +1873,102 @@ mod test {
         }
     }
 
+    #[test]
+    fn collection_validate_against_request() {
+        let batch_interval = Interval {
+            start: 1637359200,
+            duration: 7200,
+        };
+        let time_interval_req = CollectionReq {
+            draft02_task_id: None,
+            query: Query::TimeInterval {
+                batch_interval: batch_interval.clone(),
+            },
+            agg_param: Vec::default(),
+        };
+        let ciphertext = HpkeCiphertext {
+            config_id: 23,
+            enc: b"encapsulated key".to_vec(),
+            payload: b"ciphertext".to_vec(),
+        };
+        let collection = Collection {
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_count: 23,
+            interval: Some(Interval {
+                start: 1637359200,
+                duration: 3600,
+            }),
+            encrypted_agg_shares: vec![ciphertext.clone(), ciphertext],
+        };
+        assert_eq!(
+            collection.metadata(),
+            CollectionMetadata {
+                part_batch_sel: &PartialBatchSelector::TimeInterval,
+                report_count: 23,
+                interval: collection.interval.as_ref(),
+            }
+        );
+        assert_eq!(collection.batch_id(), None);
+        collection
+            .validate_against_request(&time_interval_req)
+            .unwrap();
+
+        // Interval not contained in the batch interval.
+        let mut invalid = collection.clone();
+        invalid.interval = Some(Interval {
+            start: 1637359200 + 3600,
+            duration: 7200,
+        });
+        assert_matches!(
+            invalid.validate_against_request(&time_interval_req),
+            Err(DapAbort::UnrecognizedMessage { .. })
+        );
+
+        // Wrong number of aggregate shares.
+        let mut invalid = collection.clone();
+        invalid.encrypted_agg_shares.pop();
+        assert_matches!(
+            invalid.validate_against_request(&time_interval_req),
+            Err(DapAbort::UnrecognizedMessage { .. })
+        );
+
+        // Partial batch selector does not match the query type.
+        let fixed_size_collection = Collection {
+            part_batch_sel: PartialBatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([1; 32]),
+            },
+            ..collection
+        };
+        assert_matches!(
+            fixed_size_collection.validate_against_request(&time_interval_req),
+            Err(DapAbort::UnrecognizedMessage { .. })
+        );
+
+        // Fixed-size queries.
+        assert_eq!(fixed_size_collection.batch_id(), Some(&BatchId([1; 32])));
+        let mut fixed_size_req = CollectionReq {
+            draft02_task_id: None,
+            query: Query::FixedSizeCurrentBatch,
+            agg_param: Vec::default(),
+        };
+        fixed_size_collection
+            .validate_against_request(&fixed_size_req)
+            .unwrap();
+        fixed_size_req.query = Query::FixedSizeByBatchId {
+            batch_id: BatchId([1; 32]),
+        };
+        fixed_size_collection
+            .validate_against_request(&fixed_size_req)
+            .unwrap();
+        fixed_size_req.query = Query::FixedSizeByBatchId {
+            batch_id: BatchId([2; 32]),
+        };
+        assert_matches!(
+            fixed_size_collection.validate_against_request(&fixed_size_req),
+            Err(DapAbort::UnrecognizedMessage { .. })
+        );
+    }
+
     fn read_report(version: DapVersion) {
         let report = Report {
             draft02_task_id: task_id_for_version(version),
@@ -1221,6 +1990,7 @@ mod test {
                     payload: b"helper ciphertext".to_vec(),
                 },
             ],
+            annotation: None,
         };
         assert_eq!(
             Report::get_decoded_with_param(&version, &report.get_encoded_with_param(&version))
@@ -1256,11 +2026,15 @@ mod test {
                     payload: b"helper ciphertext".to_vec(),
                 },
             ],
+            annotation: None,
         };
+        // Unknown extensions are not a decoding error: whether to accept them is decided by the
+        // Aggregator.
         let version = DapVersion::Draft02;
-        assert!(
+        assert_eq!(
             Report::get_decoded_with_param(&version, &report.get_encoded_with_param(&version))
-                .is_err()
+                .unwrap(),
+            report
         );
     }
 
@@ -1321,10 +2095,39 @@ mod test {
                         },
                     },
                 ],
+                extensions: Vec::new(),
             },
         );
     }
 
+    #[test]
+    fn roundtrip_report_batch() {
+        let batch = ReportBatch {
+            encoded_reports: vec![b"first report".to_vec(), Vec::new(), b"third".to_vec()],
+        };
+        assert_eq!(
+            ReportBatch::get_decoded(&batch.get_encoded()).unwrap(),
+            batch
+        );
+        assert_eq!(
+            ReportBatch::get_decoded(&[]).unwrap(),
+            ReportBatch::default()
+        );
+
+        let resp = ReportBatchResp {
+            results: vec![
+                ReportUploadResult::Accepted,
+                ReportUploadResult::Rejected {
+                    reason: "reportRejected".into(),
+                },
+            ],
+        };
+        assert_eq!(
+            ReportBatchResp::get_decoded(&resp.get_encoded()).unwrap(),
+            resp
+        );
+    }
+
     #[test]
     fn roundtrip_agg_job_init_req() {
         let want = AggregationJobInitReq {
@@ -1362,6 +2165,7 @@ mod test {
                     },
                 },
             ],
+            extensions: Vec::new(),
         };
 
         let got = AggregationJobInitReq::get_decoded_with_param(
@@ -1406,6 +2210,7 @@ mod test {
                     },
                 },
             ],
+            extensions: Vec::new(),
         };
 
         let got = AggregationJobInitReq::get_decoded_with_param(
@@ -1414,8 +2219,82 @@ mod test {
         )
         .unwrap();
         assert_eq!(got, want);
+
+        let mut want = want;
+        want.extensions = vec![
+            AggregationJobInitExtension::VdafVerifyKeyId { key_id: 7 },
+            AggregationJobInitExtension::Unhandled {
+                typ: 0x1337,
+                payload: b"some payload".to_vec(),
+            },
+        ];
+        let encoded = want.get_encoded_with_param(&DapVersion::Draft07);
+        let got =
+            AggregationJobInitReq::get_decoded_with_param(&DapVersion::Draft07, &encoded).unwrap();
+        assert_eq!(got, want);
+        let got =
+            AggregationJobInitReqRef::get_decoded_borrowed(&DapVersion::Draft07, &encoded).unwrap();
+        assert_eq!(got, AggregationJobInitReqRef::from(&want));
+    }
+
+    fn read_agg_job_init_req_borrowed(version: DapVersion) {
+        let want = AggregationJobInitReq {
+            draft02_task_id: (version == DapVersion::Draft02).then_some(TaskId([23; 32])),
+            draft02_agg_job_id: (version == DapVersion::Draft02)
+                .then_some(Draft02AggregationJobId([1; 32])),
+            agg_param: b"this is an aggregation parameter".to_vec(),
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_shares: vec![
+                ReportShare {
+                    report_metadata: ReportMetadata {
+                        id: ReportId([99; 16]),
+                        time: 1637361337,
+                        extensions: Vec::default(),
+                    },
+                    public_share: b"public share".to_vec(),
+                    encrypted_input_share: HpkeCiphertext {
+                        config_id: 23,
+                        enc: b"encapsulated key".to_vec(),
+                        payload: b"ciphertext".to_vec(),
+                    },
+                },
+                ReportShare {
+                    report_metadata: ReportMetadata {
+                        id: ReportId([17; 16]),
+                        time: 163736423,
+                        extensions: Vec::default(),
+                    },
+                    public_share: Vec::new(),
+                    encrypted_input_share: HpkeCiphertext {
+                        config_id: 0,
+                        enc: vec![],
+                        payload: b"ciphertext".to_vec(),
+                    },
+                },
+            ],
+            extensions: Vec::new(),
+        };
+        let mut encoded = want.get_encoded_with_param(&version);
+
+        let got = AggregationJobInitReqRef::get_decoded_borrowed(&version, &encoded).unwrap();
+        assert_eq!(got, AggregationJobInitReqRef::from(&want));
+
+        // Truncated request.
+        assert_matches!(
+            AggregationJobInitReqRef::get_decoded_borrowed(&version, &encoded[..encoded.len() - 1]),
+            Err(CodecError::Io(..))
+        );
+
+        // Trailing bytes after the (empty) extension list.
+        encoded.extend_from_slice(&[0, 0, 0]);
+        assert_matches!(
+            AggregationJobInitReqRef::get_decoded_borrowed(&version, &encoded),
+            Err(CodecError::BytesLeftOver(1))
+        );
     }
 
+    test_versions! { read_agg_job_init_req_borrowed }
+
     #[test]
     fn roundtrip_agg_job_cont_req() {
         let want = AggregationJobContinueReq {
@@ -1481,6 +2360,110 @@ mod test {
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn roundtrip_agg_job_abandon_req() {
+        let want = AggregationJobAbandonReq {
+            draft02_task_id: Some(TaskId([23; 32])),
+            draft02_agg_job_id: Some(Draft02AggregationJobId([1; 32])),
+        };
+        let got = AggregationJobAbandonReq::get_decoded_with_param(
+            &DapVersion::Draft02,
+            &want.get_encoded_with_param(&DapVersion::Draft02),
+        )
+        .unwrap();
+        assert_eq!(got, want);
+
+        let want = AggregationJobAbandonReq {
+            draft02_task_id: None,
+            draft02_agg_job_id: None,
+        };
+        let encoded = want.get_encoded_with_param(&DapVersion::Draft07);
+        assert!(encoded.is_empty());
+        let got = AggregationJobAbandonReq::get_decoded_with_param(&DapVersion::Draft07, &encoded)
+            .unwrap();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn json_agg_share_req() {
+        let want = AggregateShareReq {
+            draft02_task_id: None,
+            batch_sel: BatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([1; 32]),
+            },
+            agg_param: b"param".to_vec(),
+            report_count: 1337,
+            checksum: [2; 32],
+        };
+        let json = serde_json::to_string(&want).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"draft02_task_id":null,"#,
+                r#""batch_sel":{"fixed_size_by_batch_id":{"batch_id":"#,
+                r#""0101010101010101010101010101010101010101010101010101010101010101"}},"#,
+                r#""agg_param":"706172616d","report_count":1337,"#,
+                r#""checksum":"0202020202020202020202020202020202020202020202020202020202020202"}"#,
+            )
+        );
+        let got: AggregateShareReq = serde_json::from_str(&json).unwrap();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn json_report_share() {
+        let want = ReportShare {
+            report_metadata: ReportMetadata {
+                id: ReportId([1; 16]),
+                time: 1337,
+                extensions: Vec::new(),
+            },
+            public_share: b"ps".to_vec(),
+            encrypted_input_share: HpkeCiphertext {
+                config_id: 23,
+                enc: b"enc".to_vec(),
+                payload: b"ct".to_vec(),
+            },
+        };
+        let json = serde_json::to_string(&want).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"report_metadata":{"id":"01010101010101010101010101010101","#,
+                r#""time":1337,"extensions":[]},"public_share":[112,115],"#,
+                r#""encrypted_input_share":{"config_id":23,"enc":"656e63","payload":"6374"}}"#,
+            )
+        );
+        let got: ReportShare = serde_json::from_str(&json).unwrap();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn json_agg_job_cont_req() {
+        let want = AggregationJobContinueReq {
+            draft02_task_id: None,
+            draft02_agg_job_id: None,
+            round: Some(1),
+            transitions: vec![
+                Transition {
+                    report_id: ReportId([0; 16]),
+                    var: TransitionVar::Continued(b"VDAF message".to_vec()),
+                },
+                Transition {
+                    report_id: ReportId([1; 16]),
+                    var: TransitionVar::Finished,
+                },
+                Transition {
+                    report_id: ReportId([2; 16]),
+                    var: TransitionVar::Failed(TransitionFailure::ReportReplayed),
+                },
+            ],
+        };
+        let got: AggregationJobContinueReq =
+            serde_json::from_str(&serde_json::to_string(&want).unwrap()).unwrap();
+        assert_eq!(got, want);
+    }
+
     #[test]
     fn read_agg_job_resp_draft02() {
         const TEST_DATA: &[u8] = &[
@@ -1575,6 +2558,56 @@ mod test {
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn write_agg_job_resp() {
+        let want = AggregationJobResp {
+            transitions: vec![
+                Transition {
+                    report_id: ReportId([22; 16]),
+                    var: TransitionVar::Continued(b"this is a VDAF-specific message".to_vec()),
+                },
+                Transition {
+                    report_id: ReportId([33; 16]),
+                    var: TransitionVar::Failed(TransitionFailure::VdafPrepError),
+                },
+            ],
+        };
+
+        let mut writer = AggregationJobRespWriter::default();
+        for transition in &want.transitions {
+            writer.push(transition);
+        }
+        assert_eq!(writer.len(), 2);
+        assert_eq!(writer.num_failed(), 1);
+        assert_eq!(writer.finish(), want.get_encoded());
+
+        // Each transition starts a new chunk.
+        let mut writer = AggregationJobRespWriter::with_chunk_size(1);
+        for transition in &want.transitions {
+            writer.push(transition);
+        }
+        let chunks = writer.finish_chunks();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].len(), 4);
+        assert_eq!(chunks.concat(), want.get_encoded());
+
+        assert_eq!(
+            AggregationJobRespWriter::default().finish(),
+            AggregationJobResp::default().get_encoded()
+        );
+    }
+
+    #[test]
+    fn encode_client_auth_failed() {
+        // The failure is not defined by DAP, so it is sent as a VDAF preparation error.
+        assert_eq!(
+            TransitionFailure::get_decoded(&TransitionFailure::ClientAuthFailed.get_encoded())
+                .unwrap(),
+            TransitionFailure::VdafPrepError
+        );
+        assert!(TransitionFailure::get_decoded(&[10]).is_err());
+    }
+
     #[test]
     fn read_hpke_config() {
         let data = [
@@ -1691,6 +2724,54 @@ mod test {
     //     );
     // }
 
+    #[test]
+    fn interval_end_and_alignment() {
+        let interval = Interval {
+            start: 3600,
+            duration: 7200,
+        };
+        assert_eq!(interval.try_end(), Some(10800));
+        assert!(interval.is_aligned_to(3600));
+        assert!(!interval.is_aligned_to(7200));
+        assert!(!interval.is_aligned_to(0));
+
+        let interval = Interval {
+            start: 3600,
+            duration: 0,
+        };
+        assert!(!interval.is_aligned_to(3600));
+
+        let interval = Interval {
+            start: u64::MAX - u64::MAX % 3600,
+            duration: 7200,
+        };
+        assert_eq!(interval.try_end(), None);
+        assert!(!interval.is_aligned_to(3600));
+    }
+
+    #[test]
+    fn decode_batch_interval_overflow() {
+        let batch_interval = Interval {
+            start: u64::MAX - 1,
+            duration: 2,
+        };
+        let query = Query::TimeInterval {
+            batch_interval: batch_interval.clone(),
+        };
+        for version in [DapVersion::Draft02, DapVersion::Draft07] {
+            assert_matches!(
+                Query::get_decoded_with_param(&version, &query.get_encoded_with_param(&version)),
+                Err(CodecError::UnexpectedValue)
+            );
+        }
+
+        let batch_sel = BatchSelector::TimeInterval { batch_interval };
+        assert_matches!(
+            BatchSelector::get_decoded(&batch_sel.get_encoded()),
+            Err(CodecError::UnexpectedValue)
+        );
+    }
+
     #[test]
     fn test_base64url() {
         let mut rng = thread_rng();
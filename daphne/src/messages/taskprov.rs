@@ -5,10 +5,10 @@
 //! defined in draft-wang-ppm-dap-taskprov-02.
 
 use crate::messages::{
-    decode_u16_bytes, encode_u16_bytes, Duration, Time, QUERY_TYPE_FIXED_SIZE,
+    decode_u16_bytes, encode_u16_bytes, Duration, TaskId, Time, QUERY_TYPE_FIXED_SIZE,
     QUERY_TYPE_TIME_INTERVAL,
 };
-use crate::taskprov::TaskprovVersion;
+use crate::taskprov::{self, TaskprovVersion};
 use crate::vdaf::VDAF_VERIFY_KEY_SIZE_PRIO2;
 use prio::codec::{
     decode_u16_items, decode_u8_items, encode_u16_items, encode_u8_items, CodecError, Decode,
@@ -267,3 +267,11 @@ impl ParameterizedDecode<TaskprovVersion> for TaskConfig {
         })
     }
 }
+
+/// Compute the ID of the task described by `task_config`. This is the ID that an Aggregator derives
+/// when the task is advertised with the taskprov extension, so provisioning tools can use it to
+/// precompute task IDs. See [`compute_task_id`](crate::taskprov::compute_task_id) for deriving the
+/// ID from the serialized advertisement.
+pub fn compute_task_id(version: TaskprovVersion, task_config: &TaskConfig) -> TaskId {
+    taskprov::compute_task_id(version, &task_config.get_encoded_with_param(&version))
+}
@@ -3,11 +3,12 @@
 
 //! Daphne metrics.
 
-use crate::{fatal_error, DapError};
+use crate::{error::DapAbort, fatal_error, DapError, DapSender};
 use prometheus::{
-    exponential_buckets, register_histogram_vec_with_registry,
+    core::Collector, exponential_buckets, register_histogram_vec_with_registry,
     register_int_counter_vec_with_registry, HistogramVec, IntCounterVec, Registry,
 };
+use std::collections::BTreeMap;
 
 pub struct DaphneMetrics {
     /// Inbound request metrics: Successful requests served, broken down by type.
@@ -17,7 +18,8 @@ pub struct DaphneMetrics {
     /// a report is rejected, the failure type is recorded.
     report_counter: IntCounterVec,
 
-    /// Helper: Total number of aggregation jobs started and completed.
+    /// Helper: Total number of aggregation jobs started, completed, rejected, shed, and abandoned.
+    /// Leader: Total number of aggregation jobs abandoned.
     aggregation_job_counter: IntCounterVec,
 
     /// Helper: Number of records in an incoming AggregationJobInitReq.
@@ -25,6 +27,12 @@ pub struct DaphneMetrics {
 
     /// Helper: Number of times replays caused the aggregation to be retried.
     aggregation_job_continue_repeats_due_to_replays: IntCounterVec,
+
+    /// Leader: Number of times a request to the Helper was retried after a transient failure.
+    helper_request_retry_counter: IntCounterVec,
+
+    /// DAP aborts, broken down by type and by the role of the peer that sent the request.
+    abort_counter: IntCounterVec,
 }
 
 impl DaphneMetrics {
@@ -66,7 +74,7 @@ impl DaphneMetrics {
 
         let aggregation_job_counter = register_int_counter_vec_with_registry!(
             format!("{front}aggregation_job_counter"),
-            "Total number of aggregation jobs started and completed.",
+            "Total number of aggregation jobs started, completed, rejected, shed, and abandoned.",
             &["host", "status"],
             registry
         )
@@ -81,12 +89,30 @@ impl DaphneMetrics {
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to register aggregation_continuation_repeats_due_to_replays"))?;
 
+        let helper_request_retry_counter = register_int_counter_vec_with_registry!(
+            format!("{front}helper_request_retry_counter"),
+            "Total number of requests to the Helper that were retried after a transient failure.",
+            &["host"],
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register helper_request_retry_counter"))?;
+
+        let abort_counter = register_int_counter_vec_with_registry!(
+            format!("{front}abort_counter"),
+            "Total number of DAP aborts.",
+            &["host", "type", "sender"],
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register abort_counter"))?;
+
         Ok(Self {
             inbound_request_counter,
             report_counter,
             aggregation_job_counter,
             aggregation_job_batch_size_histogram,
             aggregation_job_continue_repeats_due_to_replays,
+            helper_request_retry_counter,
+            abort_counter,
         })
     }
 
@@ -96,6 +122,27 @@ impl DaphneMetrics {
             host,
         }
     }
+
+    /// Return the number of reports counted for each status, summed over all hosts.
+    pub(crate) fn report_counts(&self) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for metric in self
+            .report_counter
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+        {
+            if let Some(status) = metric
+                .get_label()
+                .iter()
+                .find(|label| label.get_name() == "status")
+            {
+                *counts.entry(status.get_value().to_string()).or_default() +=
+                    metric.get_counter().get_value() as u64;
+            }
+        }
+        counts
+    }
 }
 
 pub struct ContextualizedDaphneMetrics<'req> {
@@ -146,12 +193,56 @@ impl ContextualizedDaphneMetrics<'_> {
             .inc();
     }
 
+    pub fn agg_job_rejected_inc(&self) {
+        self.metrics
+            .aggregation_job_counter
+            .with_label_values(&[self.host, "rejected"])
+            .inc();
+    }
+
+    pub fn agg_job_shed_inc(&self) {
+        self.metrics
+            .aggregation_job_counter
+            .with_label_values(&[self.host, "shed"])
+            .inc();
+    }
+
+    pub fn agg_job_abandoned_inc(&self) {
+        self.metrics
+            .aggregation_job_counter
+            .with_label_values(&[self.host, "abandoned"])
+            .inc();
+    }
+
     pub fn agg_job_cont_restarted_inc(&self) {
         self.metrics
             .aggregation_job_continue_repeats_due_to_replays
             .with_label_values(&[self.host])
             .inc();
     }
+
+    pub fn helper_request_retry_inc(&self) {
+        self.metrics
+            .helper_request_retry_counter
+            .with_label_values(&[self.host])
+            .inc();
+    }
+
+    pub fn abort_inc(&self, abort: &DapAbort, sender: Option<DapSender>) {
+        let sender_str = match sender {
+            Some(DapSender::Client) => "client",
+            Some(DapSender::Collector) => "collector",
+            Some(DapSender::Helper) => "helper",
+            Some(DapSender::Leader) => "leader",
+            None => "unknown",
+        };
+
+        self.metrics
+            .abort_counter
+            // The display string is bounded by the number of variants.
+            .with_label_values(&[self.host, &abort.to_string(), sender_str])
+            .inc();
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -0,0 +1,416 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Post-processing of aggregate results. The Collector may register transforms for a given VDAF
+//! in a [`DapResultTransformRegistry`]. After the aggregate shares are unsharded, each transform
+//! registered for the task's VDAF is applied in order, producing a [`DapEnrichedAggregateResult`]
+//! for downstream analytics. An enriched result can be exported in a standard format with
+//! [`export_result`].
+
+use crate::{fatal_error, messages::TaskId, DapAggregateResult, DapError, Prio3Config, VdafConfig};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, str::FromStr, sync::Arc};
+
+/// An aggregate result along with the annotations added by post-processing.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DapEnrichedAggregateResult {
+    /// The unsharded aggregate result.
+    pub raw: DapAggregateResult,
+
+    /// A label for each entry of the result, e.g., the name of each histogram bucket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+
+    /// The result converted to floating point, e.g., for fixed-point encoded measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<f64>>,
+}
+
+impl From<DapAggregateResult> for DapEnrichedAggregateResult {
+    fn from(raw: DapAggregateResult) -> Self {
+        Self {
+            raw,
+            labels: None,
+            values: None,
+        }
+    }
+}
+
+impl DapAggregateResult {
+    /// Number of entries in the result. Scalar results have one entry.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::U64(..) | Self::U128(..) => 1,
+            Self::U32Vec(v) => v.len(),
+            Self::U128Vec(v) => v.len(),
+        }
+    }
+
+    /// Returns `true` if the result has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries of the result, in order.
+    fn entries(&self) -> Vec<u128> {
+        match self {
+            Self::U64(x) => vec![u128::from(*x)],
+            Self::U128(x) => vec![*x],
+            Self::U32Vec(v) => v.iter().map(|x| u128::from(*x)).collect(),
+            Self::U128Vec(v) => v.clone(),
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn to_f64_vec(&self) -> Vec<f64> {
+        match self {
+            Self::U64(x) => vec![*x as f64],
+            Self::U128(x) => vec![*x as f64],
+            Self::U32Vec(v) => v.iter().map(|x| f64::from(*x)).collect(),
+            Self::U128Vec(v) => v.iter().map(|x| *x as f64).collect(),
+        }
+    }
+}
+
+/// A transform applied to an aggregate result after unsharding.
+pub trait DapResultTransform: Send + Sync {
+    /// Annotate `result`. The raw result must not be modified.
+    fn apply(&self, result: &mut DapEnrichedAggregateResult) -> Result<(), DapError>;
+}
+
+/// Label each bucket of a histogram (or each entry of a vector) result.
+pub struct DapLabelBuckets(pub Vec<String>);
+
+impl DapResultTransform for DapLabelBuckets {
+    fn apply(&self, result: &mut DapEnrichedAggregateResult) -> Result<(), DapError> {
+        if self.0.len() != result.raw.len() {
+            return Err(fatal_error!(
+                err = "number of labels does not match the length of the aggregate result",
+                labels = self.0.len(),
+                len = result.raw.len(),
+            ));
+        }
+        result.labels = Some(self.0.clone());
+        Ok(())
+    }
+}
+
+/// Interpret each entry of the result as a fixed-point number with the given number of
+/// fractional bits and convert it to floating point.
+pub struct DapFixedPointToFloat {
+    pub frac_bits: u32,
+}
+
+impl DapResultTransform for DapFixedPointToFloat {
+    fn apply(&self, result: &mut DapEnrichedAggregateResult) -> Result<(), DapError> {
+        if self.frac_bits >= 128 {
+            return Err(fatal_error!(
+                err = "too many fractional bits",
+                frac_bits = self.frac_bits
+            ));
+        }
+        let scale = 2f64.powi(i32::try_from(self.frac_bits).unwrap());
+        result.values = Some(
+            result
+                .raw
+                .to_f64_vec()
+                .into_iter()
+                .map(|x| x / scale)
+                .collect(),
+        );
+        Ok(())
+    }
+}
+
+/// The transforms applied by the Collector to aggregate results, keyed by VDAF.
+#[derive(Clone, Default)]
+pub struct DapResultTransformRegistry {
+    transforms: Vec<(VdafConfig, Arc<dyn DapResultTransform>)>,
+}
+
+impl DapResultTransformRegistry {
+    /// Create an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            transforms: Vec::new(),
+        }
+    }
+
+    /// Register a transform for results of the given VDAF. Transforms registered for the same
+    /// VDAF are applied in the order in which they were registered.
+    pub fn register(&mut self, vdaf: VdafConfig, transform: impl DapResultTransform + 'static) {
+        self.transforms.push((vdaf, Arc::new(transform)));
+    }
+
+    /// Apply the transforms registered for `vdaf` to `result`.
+    pub fn apply(
+        &self,
+        vdaf: &VdafConfig,
+        result: DapAggregateResult,
+    ) -> Result<DapEnrichedAggregateResult, DapError> {
+        let mut enriched = DapEnrichedAggregateResult::from(result);
+        for (_, transform) in self.transforms.iter().filter(|(v, _)| v == vdaf) {
+            transform.apply(&mut enriched)?;
+        }
+        Ok(enriched)
+    }
+}
+
+/// Format in which an aggregate result is exported.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DapResultExportFormat {
+    /// One row per entry of the result, preceded by a header row.
+    Csv,
+    /// A JSON object with the task metadata and an array of entries.
+    #[default]
+    Json,
+}
+
+impl DapResultExportFormat {
+    /// The media type of the exported result.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+impl FromStr for DapResultExportFormat {
+    type Err = DapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(fatal_error!(err = "unrecognized export format", format = s)),
+        }
+    }
+}
+
+/// One entry of an exported result.
+#[derive(Serialize)]
+struct DapResultExportEntry<'a> {
+    index: usize,
+    label: &'a str,
+    value: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    float: Option<f64>,
+}
+
+/// An exported result in JSON format.
+#[derive(Serialize)]
+struct DapResultExportJson<'a> {
+    task_id: String,
+    vdaf: &'a VdafConfig,
+    report_count: u64,
+    entries: Vec<DapResultExportEntry<'a>>,
+}
+
+/// Label for each entry of a result that was not labeled by a transform. Histogram buckets are
+/// labeled "bucket_<index>" and vector entries "element_<index>".
+fn default_labels(vdaf: &VdafConfig, len: usize) -> Vec<String> {
+    match vdaf {
+        VdafConfig::Prio3(Prio3Config::Histogram { .. }) => {
+            (0..len).map(|i| format!("bucket_{i}")).collect()
+        }
+        VdafConfig::Prio3(Prio3Config::Count | Prio3Config::Sum { .. }) if len == 1 => {
+            vec!["value".into()]
+        }
+        _ => (0..len).map(|i| format!("element_{i}")).collect(),
+    }
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Render an aggregate result, along with the metadata of the task it was collected for, in the
+/// given format. Entries are labeled by the result's labels if it has any (see
+/// [`DapLabelBuckets`]) and by their position otherwise.
+pub fn export_result(
+    task_id: &TaskId,
+    vdaf: &VdafConfig,
+    report_count: u64,
+    result: &DapEnrichedAggregateResult,
+    format: DapResultExportFormat,
+) -> Result<String, DapError> {
+    let values = result.raw.entries();
+    let fallback_labels;
+    let labels = match result.labels {
+        Some(ref labels) => labels,
+        None => {
+            fallback_labels = default_labels(vdaf, values.len());
+            &fallback_labels
+        }
+    };
+    if labels.len() != values.len()
+        || result
+            .values
+            .as_ref()
+            .is_some_and(|f| f.len() != values.len())
+    {
+        return Err(fatal_error!(
+            err = "enriched result is inconsistent with the aggregate result"
+        ));
+    }
+    let entries = values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| DapResultExportEntry {
+            index,
+            label: &labels[index],
+            value,
+            float: result.values.as_ref().map(|f| f[index]),
+        })
+        .collect::<Vec<_>>();
+
+    match format {
+        DapResultExportFormat::Json => serde_json::to_string(&DapResultExportJson {
+            task_id: task_id.to_base64url(),
+            vdaf,
+            report_count,
+            entries,
+        })
+        .map_err(|e| fatal_error!(err = ?e)),
+        DapResultExportFormat::Csv => {
+            let task_id = task_id.to_base64url();
+            let vdaf = csv_field(&vdaf.to_string());
+            let mut out = String::from("task_id,vdaf,report_count,index,label,value");
+            if result.values.is_some() {
+                out.push_str(",float");
+            }
+            out.push('\n');
+            for entry in entries {
+                write!(
+                    out,
+                    "{task_id},{vdaf},{report_count},{},{},{}",
+                    entry.index,
+                    csv_field(entry.label),
+                    entry.value
+                )
+                .unwrap();
+                if let Some(float) = entry.float {
+                    write!(out, ",{float}").unwrap();
+                }
+                out.push('\n');
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Prio3Config;
+
+    #[test]
+    fn apply_transforms() {
+        let histogram = VdafConfig::Prio3(Prio3Config::Histogram {
+            length: 3,
+            chunk_length: 1,
+        });
+        let mut registry = DapResultTransformRegistry::new();
+        registry.register(
+            histogram.clone(),
+            DapLabelBuckets(vec!["low".into(), "mid".into(), "high".into()]),
+        );
+        registry.register(histogram.clone(), DapFixedPointToFloat { frac_bits: 1 });
+
+        let enriched = registry
+            .apply(&histogram, DapAggregateResult::U128Vec(vec![2, 0, 5]))
+            .unwrap();
+        assert_eq!(
+            enriched,
+            DapEnrichedAggregateResult {
+                raw: DapAggregateResult::U128Vec(vec![2, 0, 5]),
+                labels: Some(vec!["low".into(), "mid".into(), "high".into()]),
+                values: Some(vec![1.0, 0.0, 2.5]),
+            }
+        );
+
+        // Transforms registered for other VDAFs are not applied.
+        let count = VdafConfig::Prio3(Prio3Config::Count);
+        assert_eq!(
+            registry.apply(&count, DapAggregateResult::U64(7)).unwrap(),
+            DapAggregateResult::U64(7).into()
+        );
+
+        // Labels must match the shape of the result.
+        assert!(registry
+            .apply(&histogram, DapAggregateResult::U128Vec(vec![1]))
+            .is_err());
+    }
+
+    #[test]
+    fn export() {
+        let task_id = TaskId([0; 32]);
+        let t = task_id.to_base64url();
+        let histogram = VdafConfig::Prio3(Prio3Config::Histogram {
+            length: 2,
+            chunk_length: 1,
+        });
+
+        // Histogram buckets are labeled by their index unless labels are provided.
+        let result = DapEnrichedAggregateResult::from(DapAggregateResult::U128Vec(vec![3, 4]));
+        assert_eq!(
+            export_result(&task_id, &histogram, 7, &result, DapResultExportFormat::Csv).unwrap(),
+            format!(
+                "task_id,vdaf,report_count,index,label,value\n\
+                 {t},\"Prio3(Histogram(2,1))\",7,0,bucket_0,3\n\
+                 {t},\"Prio3(Histogram(2,1))\",7,1,bucket_1,4\n"
+            )
+        );
+
+        let mut registry = DapResultTransformRegistry::new();
+        registry.register(
+            histogram.clone(),
+            DapLabelBuckets(vec!["<10ms".into(), "10ms, or more".into()]),
+        );
+        registry.register(histogram.clone(), DapFixedPointToFloat { frac_bits: 1 });
+        let result = registry
+            .apply(&histogram, DapAggregateResult::U128Vec(vec![3, 4]))
+            .unwrap();
+        assert_eq!(
+            export_result(&task_id, &histogram, 7, &result, DapResultExportFormat::Csv).unwrap(),
+            format!(
+                "task_id,vdaf,report_count,index,label,value,float\n\
+                 {t},\"Prio3(Histogram(2,1))\",7,0,<10ms,3,1.5\n\
+                 {t},\"Prio3(Histogram(2,1))\",7,1,\"10ms, or more\",4,2\n"
+            )
+        );
+
+        let json: serde_json::Value = serde_json::from_str(
+            &export_result(
+                &task_id,
+                &histogram,
+                7,
+                &result,
+                DapResultExportFormat::Json,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["task_id"], t);
+        assert_eq!(json["report_count"], 7);
+        assert_eq!(json["entries"][1]["label"], "10ms, or more");
+        assert_eq!(json["entries"][1]["value"], 4);
+        assert_eq!(json["entries"][1]["float"], 2.0);
+
+        // Scalar results have a single entry.
+        let count = VdafConfig::Prio3(Prio3Config::Count);
+        let result = DapEnrichedAggregateResult::from(DapAggregateResult::U64(5));
+        assert!(
+            export_result(&task_id, &count, 5, &result, DapResultExportFormat::Csv)
+                .unwrap()
+                .ends_with(",5,0,value,5\n")
+        );
+    }
+}
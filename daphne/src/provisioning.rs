@@ -0,0 +1,605 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Importer for JSON task descriptions. A [`DapTaskDescription`] is the format consumed by the
+//! "add_task" test route of `daphne_worker` and by external provisioning pipelines. Importing it
+//! validates each parameter and yields the [`DapTaskConfig`], the bearer tokens, and the KV
+//! entries under which `daphne_worker` expects to find them.
+//!
+//! A [`DapUploadDescription`] is the format consumed by the "upload" route of the interop test API,
+//! through which a test harness asks the Client to upload a measurement.
+
+use prio::codec::Decode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    auth::BearerToken,
+    client::DapClientConfig,
+    fatal_error,
+    hpke::HpkeConfig,
+    messages::{decode_base64url_vec, Duration, TaskId, Time},
+    DapError, DapGlobalConfig, DapMeasurement, DapQueryConfig, DapTaskAltVersion, DapTaskConfig,
+    DapVersion, Prio3Config, VdafConfig,
+};
+
+/// KV key prefix of the Leader's bearer token for a task.
+pub const KV_KEY_PREFIX_BEARER_TOKEN_LEADER: &str = "bearer_token/leader/task";
+
+/// KV key prefix of the Collector's bearer token for a task.
+pub const KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR: &str = "bearer_token/collector/task";
+
+/// KV key prefix of a task's configuration.
+pub const KV_KEY_PREFIX_TASK_CONFIG: &str = "config/task";
+
+/// KV key prefix of the past revisions of a task's configuration (see
+/// [`DapTaskConfigRecord::history_kv_key`]).
+pub const KV_KEY_PREFIX_TASK_CONFIG_HISTORY: &str = "config/task_history";
+
+/// The role of the Aggregator for which a task is being provisioned.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DapRole {
+    Leader,
+    Helper,
+}
+
+/// VDAF of a task description. The parameters are encoded as strings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DapTaskDescriptionVdaf {
+    /// One of "Prio3Count", "Prio3Sum", "Prio3SumVec", or "Prio3Histogram".
+    #[serde(rename = "type")]
+    pub typ: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bits: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_length: Option<String>,
+}
+
+/// JSON description of a task.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DapTaskDescription {
+    /// The protocol version. If not set, then the version passed to
+    /// [`import`](Self::import) is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<DapVersion>,
+    pub task_id: String, // base64url
+    pub leader: Url,
+    pub helper: Url,
+    pub vdaf: DapTaskDescriptionVdaf,
+    pub leader_authentication_token: String,
+    /// Required if `role` is "leader" and forbidden otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collector_authentication_token: Option<String>,
+    pub role: DapRole,
+    pub vdaf_verify_key: String, // base64url
+    /// 1 for time-interval and 2 for fixed-size.
+    pub query_type: u8,
+    pub min_batch_size: u64,
+    /// Required if `query_type` is 2 and forbidden otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_batch_size: Option<u64>,
+    pub time_precision: Duration,
+    pub collector_hpke_config: String, // base64url
+    pub task_expiration: Time,
+    /// Versions in which the task is offered in addition to `version`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alt_versions: Vec<DapTaskAltVersion>,
+    /// Time-interval tasks: The duration of the sub-buckets into which each batch window is
+    /// divided. Must evenly divide `time_precision`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_bucket_precision: Option<Duration>,
+}
+
+/// JSON description of a measurement to be uploaded by the Client.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DapUploadDescription {
+    pub task_id: String, // base64url
+    pub leader: Url,
+    pub helper: Url,
+    pub vdaf: DapTaskDescriptionVdaf,
+    /// Encoded as described in [`DapTaskDescriptionVdaf::parse_measurement`].
+    pub measurement: serde_json::Value,
+    /// The report timestamp. If not set, then the current time is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<Time>,
+    pub time_precision: Duration,
+}
+
+/// A task that has been imported from a [`DapTaskDescription`].
+#[derive(Clone)]
+pub struct DapTaskImport {
+    pub task_id: TaskId,
+    pub task_config: DapTaskConfig,
+    pub leader_bearer_token: BearerToken,
+    pub collector_bearer_token: Option<BearerToken>,
+}
+
+/// An entry to be written to KV. The value is JSON.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DapKvEntry {
+    pub key: String,
+    pub value: String,
+}
+
+/// A revision of a task's configuration, as stored in KV under [`KV_KEY_PREFIX_TASK_CONFIG`].
+///
+/// The revision metadata is stored alongside the fields of the task config, so a record can be
+/// read as a plain [`DapTaskConfig`]. Conversely, a plain task config, such as the one written by
+/// [`DapTaskImport::kv_entries`], reads as revision 0.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DapTaskConfigRecord {
+    /// Incremented by each write of the task config.
+    #[serde(default)]
+    pub revision: u64,
+
+    /// Time at which the revision was written.
+    #[serde(default)]
+    pub updated_at: Time,
+
+    /// Identifies the writer of the revision, e.g., "taskprov" or "admin".
+    #[serde(default)]
+    pub updated_by: String,
+
+    #[serde(flatten)]
+    pub task_config: DapTaskConfig,
+}
+
+impl DapTaskConfigRecord {
+    /// Return the revision that follows `current`, or the first revision if the task config does
+    /// not exist yet.
+    pub fn next(
+        current: Option<&Self>,
+        task_config: DapTaskConfig,
+        updated_at: Time,
+        updated_by: impl Into<String>,
+    ) -> Self {
+        Self {
+            revision: current.map_or(1, |current| current.revision + 1),
+            updated_at,
+            updated_by: updated_by.into(),
+            task_config,
+        }
+    }
+
+    /// The KV key under which the revision is kept in the task's history. Keys sort in the order
+    /// of the revisions.
+    pub fn history_kv_key(&self, task_id: &TaskId) -> String {
+        format!(
+            "{}{:020}",
+            Self::history_kv_key_prefix(task_id),
+            self.revision
+        )
+    }
+
+    /// The common prefix of the KV keys of the task's history.
+    pub fn history_kv_key_prefix(task_id: &TaskId) -> String {
+        format!("{KV_KEY_PREFIX_TASK_CONFIG_HISTORY}/{task_id}/")
+    }
+}
+
+impl DapTaskDescription {
+    /// Parse and validate the description. The VDAF is checked against the limits of
+    /// `global_config`.
+    pub fn import(
+        &self,
+        default_version: DapVersion,
+        global_config: &DapGlobalConfig,
+    ) -> Result<DapTaskImport, DapError> {
+        let version = self.version.unwrap_or(default_version);
+        if version == DapVersion::Unknown {
+            return Err(fatal_error!(err = "unknown DAP version"));
+        }
+
+        let task_id = TaskId::try_from_base64url(&self.task_id)
+            .ok_or_else(|| fatal_error!(err = "task ID is not valid URL-safe base64"))?;
+
+        let vdaf = self.vdaf.parse()?;
+        global_config.check_vdaf_config(&task_id, &vdaf)?;
+
+        let vdaf_verify_key_data = decode_base64url_vec(self.vdaf_verify_key.as_bytes())
+            .ok_or_else(|| fatal_error!(err = "VDAF verify key is not valid URL-safe base64"))?;
+        let vdaf_verify_key = vdaf.get_decoded_verify_key(&vdaf_verify_key_data)?;
+
+        let collector_hpke_config_data =
+            decode_base64url_vec(self.collector_hpke_config.as_bytes()).ok_or_else(|| {
+                fatal_error!(err = "HPKE collector config is not valid URL-safe base64")
+            })?;
+        let collector_hpke_config = HpkeConfig::get_decoded(&collector_hpke_config_data)
+            .map_err(|e| fatal_error!(err = ?e, "failed to decode HPKE collector config"))?;
+
+        let collector_bearer_token = match (self.role, &self.collector_authentication_token) {
+            (DapRole::Leader, Some(token)) => Some(BearerToken::from(token.clone())),
+            (DapRole::Leader, None) => {
+                return Err(fatal_error!(err = "missing collector authentication token"))
+            }
+            (DapRole::Helper, None) => None,
+            (DapRole::Helper, Some(..)) => {
+                return Err(fatal_error!(
+                    err = "unexpected collector authentication token"
+                ))
+            }
+        };
+
+        let query = match (self.query_type, self.max_batch_size) {
+            (1, None) => DapQueryConfig::TimeInterval,
+            (1, Some(..)) => return Err(fatal_error!(err = "unexpected max batch size")),
+            (2, Some(max_batch_size)) => DapQueryConfig::FixedSize {
+                max_batch_size,
+                batch_time_limit: None,
+            },
+            (2, None) => return Err(fatal_error!(err = "missing max batch size")),
+            (query_type, _) => {
+                return Err(fatal_error!(err = "unrecognized query type", query_type))
+            }
+        };
+
+        let task_config = DapTaskConfig {
+            version,
+            leader_url: self.leader.clone(),
+            helper_url: self.helper.clone(),
+            time_precision: self.time_precision,
+            expiration: self.task_expiration,
+            min_batch_size: self.min_batch_size,
+            query,
+            vdaf,
+            vdaf_verify_key,
+            collector_hpke_config,
+            taskprov: false,
+            batch_policy: Default::default(),
+            upload_receipts: false,
+            unknown_extensions: Default::default(),
+            report_ttl: None,
+            collect_priority: Default::default(),
+            client_auth_key: None,
+            vdaf_verify_key_set: None,
+            alt_versions: self.alt_versions.clone(),
+            report_storage_epoch_duration: None,
+            report_storage_epoch_migration: None,
+            sub_bucket_precision: self.sub_bucket_precision,
+        };
+        task_config.validate_time_precision()?;
+        task_config.validate_epoch_duration()?;
+        task_config.validate_alt_versions()?;
+        task_config.validate_sub_bucket_precision()?;
+
+        Ok(DapTaskImport {
+            task_id,
+            task_config,
+            leader_bearer_token: BearerToken::from(self.leader_authentication_token.clone()),
+            collector_bearer_token,
+        })
+    }
+}
+
+impl DapUploadDescription {
+    /// Parse and validate the description. The return value is the config of the Client for the
+    /// task and the measurement to upload.
+    pub fn import(
+        &self,
+        version: DapVersion,
+    ) -> Result<(DapClientConfig, DapMeasurement), DapError> {
+        if version == DapVersion::Unknown {
+            return Err(fatal_error!(err = "unknown DAP version"));
+        }
+
+        let task_id = TaskId::try_from_base64url(&self.task_id)
+            .ok_or_else(|| fatal_error!(err = "task ID is not valid URL-safe base64"))?;
+
+        let config = DapClientConfig::new(
+            version,
+            task_id,
+            self.leader.clone(),
+            self.helper.clone(),
+            self.time_precision,
+            self.vdaf.parse()?,
+        );
+        let measurement = self.vdaf.parse_measurement(&self.measurement)?;
+        Ok((config, measurement))
+    }
+}
+
+impl DapTaskDescriptionVdaf {
+    /// Parse the VDAF and its parameters.
+    pub fn parse(&self) -> Result<VdafConfig, DapError> {
+        fn param<T: std::str::FromStr>(name: &'static str, val: &str) -> Result<T, DapError> {
+            val.parse()
+                .map_err(|_| fatal_error!(err = "invalid VDAF parameter", name, val))
+        }
+
+        match (
+            self.typ.as_ref(),
+            self.bits.as_deref(),
+            self.length.as_deref(),
+            self.chunk_length.as_deref(),
+        ) {
+            ("Prio3Count", None, None, None) => Ok(VdafConfig::Prio3(Prio3Config::Count)),
+            ("Prio3Sum", Some(bits), None, None) => Ok(VdafConfig::Prio3(Prio3Config::Sum {
+                bits: param("bits", bits)?,
+            })),
+            ("Prio3SumVec", Some(bits), Some(length), Some(chunk_length)) => {
+                Ok(VdafConfig::Prio3(Prio3Config::SumVec {
+                    bits: param("bits", bits)?,
+                    length: param("length", length)?,
+                    chunk_length: param("chunk_length", chunk_length)?,
+                }))
+            }
+            ("Prio3Histogram", None, Some(length), Some(chunk_length)) => {
+                Ok(VdafConfig::Prio3(Prio3Config::Histogram {
+                    length: param("length", length)?,
+                    chunk_length: param("chunk_length", chunk_length)?,
+                }))
+            }
+            _ => Err(fatal_error!(err = "unrecognized VDAF", typ = self.typ)),
+        }
+    }
+
+    /// Parse a measurement for the VDAF. Integers may be encoded either as JSON numbers or as
+    /// decimal strings. The measurement is an integer for "Prio3Count" and "Prio3Sum", the bucket
+    /// index for "Prio3Histogram", and an array of integers for "Prio3SumVec".
+    pub fn parse_measurement(
+        &self,
+        measurement: &serde_json::Value,
+    ) -> Result<DapMeasurement, DapError> {
+        fn int<T: std::str::FromStr + serde::de::DeserializeOwned>(
+            val: &serde_json::Value,
+        ) -> Result<T, DapError> {
+            match val {
+                serde_json::Value::String(s) => s.parse().ok(),
+                serde_json::Value::Number(_) => serde_json::from_value(val.clone()).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| fatal_error!(err = "invalid measurement", %val))
+        }
+
+        match self.parse()? {
+            VdafConfig::Prio3(
+                Prio3Config::Count | Prio3Config::Sum { .. } | Prio3Config::Histogram { .. },
+            ) => Ok(DapMeasurement::U64(int(measurement)?)),
+            VdafConfig::Prio3(Prio3Config::SumVec { .. }) => measurement
+                .as_array()
+                .ok_or_else(|| fatal_error!(err = "measurement is not an array"))?
+                .iter()
+                .map(int)
+                .collect::<Result<_, _>>()
+                .map(DapMeasurement::U128Vec),
+            VdafConfig::Prio2 { .. } => Err(fatal_error!(err = "unsupported VDAF")),
+        }
+    }
+}
+
+impl DapTaskImport {
+    /// The KV entries used by `daphne_worker` to store the task: the Leader bearer token, the
+    /// Collector bearer token (Leader only), and the task config.
+    pub fn kv_entries(&self) -> Result<Vec<DapKvEntry>, DapError> {
+        fn entry<V: Serialize>(
+            prefix: &str,
+            task_id: &TaskId,
+            value: &V,
+        ) -> Result<DapKvEntry, DapError> {
+            Ok(DapKvEntry {
+                key: format!("{prefix}/{task_id}"),
+                value: serde_json::to_string(value)
+                    .map_err(|e| fatal_error!(err = ?e, "failed to encode KV value"))?,
+            })
+        }
+
+        let mut entries = vec![entry(
+            KV_KEY_PREFIX_BEARER_TOKEN_LEADER,
+            &self.task_id,
+            &self.leader_bearer_token,
+        )?];
+        if let Some(ref token) = self.collector_bearer_token {
+            entries.push(entry(
+                KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR,
+                &self.task_id,
+                token,
+            )?);
+        }
+        entries.push(entry(
+            KV_KEY_PREFIX_TASK_CONFIG,
+            &self.task_id,
+            &self.task_config,
+        )?);
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prio::codec::Encode;
+
+    use super::*;
+    use crate::{
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::encode_base64url,
+        testing::global_config,
+    };
+
+    fn description(role: DapRole) -> DapTaskDescription {
+        let collector_hpke_config = HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        serde_json::from_value(serde_json::json!({
+            "task_id": encode_base64url([1; 32]),
+            "leader": "https://leader.example.com/",
+            "helper": "https://helper.example.com/",
+            "vdaf": {
+                "type": "Prio3Sum",
+                "bits": "8",
+            },
+            "leader_authentication_token": "leader token",
+            "collector_authentication_token": match role {
+                DapRole::Leader => Some("collector token"),
+                DapRole::Helper => None,
+            },
+            "role": role,
+            "vdaf_verify_key": encode_base64url([2; 16]),
+            "query_type": 2,
+            "min_batch_size": 10,
+            "max_batch_size": 100,
+            "time_precision": 3600,
+            "collector_hpke_config": encode_base64url(collector_hpke_config.get_encoded()),
+            "task_expiration": 1_700_000_000,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn import() {
+        let import = description(DapRole::Leader)
+            .import(DapVersion::Draft07, &global_config())
+            .unwrap();
+        assert_eq!(import.task_id, TaskId([1; 32]));
+        assert_eq!(import.task_config.version, DapVersion::Draft07);
+        assert_eq!(
+            import.task_config.vdaf,
+            VdafConfig::Prio3(Prio3Config::Sum { bits: 8 })
+        );
+        assert_eq!(
+            import.task_config.query,
+            DapQueryConfig::FixedSize {
+                max_batch_size: 100,
+                batch_time_limit: None,
+            }
+        );
+        assert_eq!(import.task_config.collector_hpke_config.id, 23);
+
+        let keys = import
+            .kv_entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+        let task_id = import.task_id.to_hex();
+        assert_eq!(
+            keys,
+            vec![
+                format!("{KV_KEY_PREFIX_BEARER_TOKEN_LEADER}/{task_id}"),
+                format!("{KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR}/{task_id}"),
+                format!("{KV_KEY_PREFIX_TASK_CONFIG}/{task_id}"),
+            ]
+        );
+
+        let import = description(DapRole::Helper)
+            .import(DapVersion::Draft02, &global_config())
+            .unwrap();
+        assert!(import.collector_bearer_token.is_none());
+        assert_eq!(import.kv_entries().unwrap().len(), 2);
+
+        let mut desc = description(DapRole::Leader);
+        desc.time_precision = 0;
+        assert!(desc.import(DapVersion::Draft07, &global_config()).is_err());
+    }
+
+    #[test]
+    fn task_config_record() {
+        let import = description(DapRole::Leader)
+            .import(DapVersion::Draft07, &global_config())
+            .unwrap();
+
+        // A task config written without revision metadata reads as revision 0.
+        let value = serde_json::to_string(&import.task_config).unwrap();
+        let record: DapTaskConfigRecord = serde_json::from_str(&value).unwrap();
+        assert_eq!(record.revision, 0);
+
+        let record = DapTaskConfigRecord::next(
+            Some(&record),
+            import.task_config.clone(),
+            1_700_000_000,
+            "admin",
+        );
+        assert_eq!(record.revision, 1);
+        let value = serde_json::to_string(&record).unwrap();
+
+        // A record reads as a plain task config.
+        let task_config: DapTaskConfig = serde_json::from_str(&value).unwrap();
+        assert_eq!(
+            serde_json::to_value(&task_config).unwrap(),
+            serde_json::to_value(&import.task_config).unwrap()
+        );
+
+        let record: DapTaskConfigRecord = serde_json::from_str(&value).unwrap();
+        assert_eq!(record.revision, 1);
+        assert_eq!(record.updated_at, 1_700_000_000);
+        assert_eq!(record.updated_by, "admin");
+        assert_eq!(
+            record.history_kv_key(&import.task_id),
+            format!(
+                "{KV_KEY_PREFIX_TASK_CONFIG_HISTORY}/{}/00000000000000000001",
+                import.task_id
+            )
+        );
+    }
+
+    #[test]
+    fn import_upload() {
+        let upload: DapUploadDescription = serde_json::from_value(serde_json::json!({
+            "task_id": encode_base64url([1; 32]),
+            "leader": "https://leader.example.com/",
+            "helper": "https://helper.example.com/",
+            "vdaf": {
+                "type": "Prio3SumVec",
+                "bits": "8",
+                "length": "3",
+                "chunk_length": "2",
+            },
+            "measurement": ["1", 2, "255"],
+            "time_precision": 3600,
+        }))
+        .unwrap();
+        let (config, measurement) = upload.import(DapVersion::Draft07).unwrap();
+        assert_eq!(config.task_id, TaskId([1; 32]));
+        assert_eq!(config.time_precision, 3600);
+        assert!(matches!(measurement, DapMeasurement::U128Vec(m) if m == [1, 2, 255]));
+
+        let vdaf = DapTaskDescriptionVdaf {
+            typ: "Prio3Count".into(),
+            bits: None,
+            length: None,
+            chunk_length: None,
+        };
+        assert!(matches!(
+            vdaf.parse_measurement(&serde_json::json!("1")).unwrap(),
+            DapMeasurement::U64(1)
+        ));
+        assert!(vdaf.parse_measurement(&serde_json::json!("one")).is_err());
+        assert!(vdaf.parse_measurement(&serde_json::json!([1])).is_err());
+    }
+
+    #[test]
+    fn import_invalid() {
+        let mut desc = description(DapRole::Helper);
+        desc.collector_authentication_token = Some("collector token".into());
+        assert!(desc.import(DapVersion::Draft07, &global_config()).is_err());
+
+        let mut desc = description(DapRole::Leader);
+        desc.max_batch_size = None;
+        assert!(desc.import(DapVersion::Draft07, &global_config()).is_err());
+
+        let mut desc = description(DapRole::Leader);
+        desc.vdaf.bits = Some("eight".into());
+        assert!(desc.import(DapVersion::Draft07, &global_config()).is_err());
+
+        let mut desc = description(DapRole::Leader);
+        desc.vdaf_verify_key = encode_base64url([2; 15]);
+        assert!(desc.import(DapVersion::Draft07, &global_config()).is_err());
+
+        let desc = description(DapRole::Leader);
+        assert!(desc.import(DapVersion::Unknown, &global_config()).is_err());
+
+        let mut desc = description(DapRole::Leader);
+        desc.alt_versions.push(DapTaskAltVersion {
+            version: DapVersion::Draft07,
+            leader_url: desc.leader.clone(),
+            helper_url: desc.helper.clone(),
+        });
+        assert!(desc.import(DapVersion::Draft02, &global_config()).is_ok());
+        assert!(desc.import(DapVersion::Draft07, &global_config()).is_err());
+    }
+}
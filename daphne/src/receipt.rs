@@ -0,0 +1,147 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Upload receipts. For tasks that enable them, the Leader responds to each accepted upload with
+//! a receipt signed by the Leader. The receipt allows the Client to prove that its report was
+//! accepted.
+
+use crate::{
+    fatal_error,
+    messages::{decode_u16_bytes, encode_u16_bytes, ReportId, TaskId, Time},
+    DapError,
+};
+use prio::codec::{CodecError, Decode, Encode};
+use rand::prelude::*;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+/// Domain separation tag for the message signed by the Leader.
+const UPLOAD_RECEIPT_CONTEXT: &[u8] = b"dap-upload-receipt";
+
+/// Ed25519 key used by the Leader to sign upload receipts. The key is represented by its 32-byte
+/// seed, hex-encoded.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct UploadReceiptSigningKey(#[serde(with = "hex")] [u8; 32]);
+
+impl UploadReceiptSigningKey {
+    /// Generate a fresh signing key.
+    pub fn gen() -> Self {
+        Self(thread_rng().gen())
+    }
+
+    fn key_pair(&self) -> Result<Ed25519KeyPair, DapError> {
+        Ed25519KeyPair::from_seed_unchecked(&self.0)
+            .map_err(|e| fatal_error!(err = ?e, code = Crypto))
+    }
+
+    /// Return the public key with which receipts signed by this key can be verified.
+    pub fn public_key(&self) -> Result<Vec<u8>, DapError> {
+        Ok(self.key_pair()?.public_key().as_ref().to_vec())
+    }
+
+    /// Sign a receipt for the report with the given ID, accepted at time `time`.
+    pub fn sign(
+        &self,
+        task_id: &TaskId,
+        report_id: &ReportId,
+        time: Time,
+    ) -> Result<UploadReceipt, DapError> {
+        let signature = self
+            .key_pair()?
+            .sign(&signed_message(task_id, report_id, time))
+            .as_ref()
+            .to_vec();
+        Ok(UploadReceipt {
+            task_id: task_id.clone(),
+            report_id: report_id.clone(),
+            time,
+            signature,
+        })
+    }
+}
+
+/// Proof that the Leader accepted a report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UploadReceipt {
+    pub task_id: TaskId,
+    pub report_id: ReportId,
+    /// The time at which the Leader accepted the report.
+    pub time: Time,
+    pub signature: Vec<u8>,
+}
+
+impl UploadReceipt {
+    /// Verify the Leader's signature on the receipt, given the Leader's public key.
+    pub fn verify(&self, public_key: &[u8]) -> bool {
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(
+                &signed_message(&self.task_id, &self.report_id, self.time),
+                &self.signature,
+            )
+            .is_ok()
+    }
+}
+
+impl Encode for UploadReceipt {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.task_id.encode(bytes);
+        self.report_id.encode(bytes);
+        self.time.encode(bytes);
+        encode_u16_bytes(bytes, &self.signature);
+    }
+}
+
+impl Decode for UploadReceipt {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            task_id: TaskId::decode(bytes)?,
+            report_id: ReportId::decode(bytes)?,
+            time: Time::decode(bytes)?,
+            signature: decode_u16_bytes(bytes)?,
+        })
+    }
+}
+
+fn signed_message(task_id: &TaskId, report_id: &ReportId, time: Time) -> Vec<u8> {
+    let mut message = UPLOAD_RECEIPT_CONTEXT.to_vec();
+    task_id.encode(&mut message);
+    report_id.encode(&mut message);
+    time.encode(&mut message);
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UploadReceipt, UploadReceiptSigningKey};
+    use crate::messages::{ReportId, TaskId};
+    use prio::codec::{Decode, Encode};
+    use rand::prelude::*;
+
+    #[test]
+    fn sign_verify() {
+        let mut rng = thread_rng();
+        let signing_key = UploadReceiptSigningKey::gen();
+        let public_key = signing_key.public_key().unwrap();
+        let task_id = TaskId(rng.gen());
+        let report_id = ReportId(rng.gen());
+
+        let receipt = signing_key.sign(&task_id, &report_id, 1337).unwrap();
+        let receipt = UploadReceipt::get_decoded(&receipt.get_encoded()).unwrap();
+        assert!(receipt.verify(&public_key));
+
+        // Wrong public key.
+        let other_public_key = UploadReceiptSigningKey::gen().public_key().unwrap();
+        assert!(!receipt.verify(&other_public_key));
+
+        // Tampered receipt.
+        let mut tampered = receipt.clone();
+        tampered.time += 1;
+        assert!(!tampered.verify(&public_key));
+
+        let mut tampered = receipt;
+        tampered.report_id = ReportId(rng.gen());
+        assert!(!tampered.verify(&public_key));
+    }
+}
@@ -10,39 +10,54 @@ use crate::{
     audit_log::AuditLog,
     constants::DapMediaType,
     error::DapAbort,
+    extensions::{DapExtensionRegistry, EMPTY_EXTENSION_REGISTRY},
     hpke::{HpkeConfig, HpkeDecrypter},
     messages::{
         decode_base64url, BatchId, BatchSelector, HpkeConfigList, PartialBatchSelector, ReportId,
         TaskId, Time,
     },
     metrics::{DaphneMetrics, DaphneRequestType},
-    vdaf::{EarlyReportStateConsumed, EarlyReportStateInitialized},
-    DapAggregateShare, DapAggregateShareSpan, DapError, DapGlobalConfig, DapRequest, DapResponse,
-    DapTaskConfig, DapVersion,
+    taskprov::TaskprovRejectionCache,
+    time::TimeSource,
+    vdaf::{EarlyReportStateConsumed, EarlyReportStateInitialized, VdafVerifyKey},
+    DapAggregateShare, DapAggregateShareMeta, DapAggregateShareSpan, DapAggregationParamHash,
+    DapBatchBucket, DapError, DapGlobalConfig, DapRequest, DapResponse, DapTaskConfig,
+    DapTaskPurgeSummary, DapVersion, MaybeSendSync,
 };
 
 /// Report initializer. Used by a DAP Aggregator [`DapAggregator`] when initializing an aggregation
 /// job.
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 pub trait DapReportInitializer {
     /// Initialize a sequence of reports that are in the "consumed" state by performing the early
     /// validation steps (check if the report was replayed, belongs to a batch that has been
-    /// collected) and initializing VDAF preparation.
+    /// collected) and initializing VDAF preparation. VDAF preparation uses `vdaf_verify_key`,
+    /// the verification key selected for the aggregation job. The extensions of each report that
+    /// is ready are available to implement extension-dependent policies.
     async fn initialize_reports<'req>(
         &self,
         is_leader: bool,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        vdaf_verify_key: &VdafVerifyKey,
         part_batch_sel: &PartialBatchSelector,
         consumed_reports: Vec<EarlyReportStateConsumed<'req>>,
     ) -> Result<Vec<EarlyReportStateInitialized<'req>>, DapError>;
+
+    /// The report extensions recognized by the Aggregator in addition to the ones built into
+    /// Daphne. Reports are checked against this registry before they are initialized.
+    fn extension_registry(&self) -> &DapExtensionRegistry {
+        &EMPTY_EXTENSION_REGISTRY
+    }
 }
 
 /// DAP Aggregator functionality.
-#[async_trait(?Send)]
-pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait DapAggregator<S: MaybeSendSync>: HpkeDecrypter + DapReportInitializer + Sized {
     /// A refernce to a task configuration stored by the Aggregator.
-    type WrappedDapTaskConfig<'a>: AsRef<DapTaskConfig>;
+    type WrappedDapTaskConfig<'a>: AsRef<DapTaskConfig> + MaybeSendSync;
 
     /// Decide whether the given DAP request is authorized.
     ///
@@ -66,6 +81,10 @@ pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
     /// extension.
     fn taskprov_collector_hpke_config(&self) -> Option<&HpkeConfig>;
 
+    /// taskprov: Cache of advertisements that were rejected as malformed. It should be shared by
+    /// all requests handled by the Aggregator.
+    fn taskprov_rejection_cache(&self) -> &TaskprovRejectionCache;
+
     /// taskprov: Decide whether to opt-in or out-out of a task provisioned via taskprov.
     ///
     /// If the return value is `None`, then the decision is to opt-in. If the return value is
@@ -91,15 +110,34 @@ pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
         task_id: Cow<'req, TaskId>,
     ) -> Result<Option<Self::WrappedDapTaskConfig<'req>>, DapError>;
 
+    /// Access the source of the current time.
+    fn time_source(&self) -> &dyn TimeSource;
+
     /// Get the current time (number of seconds since the beginning of UNIX time).
-    fn get_current_time(&self) -> Time;
+    fn get_current_time(&self) -> Time {
+        self.time_source().now()
+    }
+
+    /// Look up an override of the Collector's HPKE configuration for the given task. If the return
+    /// value is `None`, then aggregate shares are encrypted to the `collector_hpke_config` of the
+    /// task configuration. This allows the Collector to rotate its key without re-provisioning
+    /// the task.
+    async fn get_collector_hpke_config_for(
+        &self,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+    ) -> Result<Option<HpkeConfig>, DapError> {
+        Ok(None)
+    }
 
     /// Check whether the batch determined by the collect request would overlap with a previous
-    /// batch.
+    /// batch collected with the same aggregation parameter. Collecting the same batch with
+    /// distinct aggregation parameters is permitted.
     async fn is_batch_overlapping(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param_hash: &DapAggregationParamHash,
     ) -> Result<bool, DapError>;
 
     /// Check whether the given batch ID has been observed before. This is called by the Leader
@@ -130,13 +168,35 @@ pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
         batch_sel: &BatchSelector,
     ) -> Result<DapAggregateShare, DapError>;
 
-    /// Mark a batch as collected.
+    /// Fetch the metadata of the aggregate share for the given batch. This is cheaper than
+    /// [`get_agg_share`](Self::get_agg_share) when the aggregated data is not needed.
+    async fn get_agg_share_meta(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<DapAggregateShareMeta, DapError>;
+
+    /// Fetch the aggregate share of each bucket spanned by the given batch. Buckets to which no
+    /// report has been aggregated may be omitted.
+    async fn get_agg_share_per_bucket(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<(DapBatchBucket, DapAggregateShare)>, DapError>;
+
+    /// Mark a batch as collected with the given aggregation parameter.
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param_hash: &DapAggregationParamHash,
     ) -> Result<(), DapError>;
 
+    /// Delete all state stored for the given task: pending and processed reports, aggregate
+    /// shares, helper states and collection jobs. The task configuration itself is kept. Returns a
+    /// summary of what was deleted.
+    async fn purge_task(&self, task_id: &TaskId) -> Result<DapTaskPurgeSummary, DapError>;
+
     /// Handle request for the Aggregator's HPKE configuration.
     async fn handle_hpke_config_req(&self, req: &DapRequest<S>) -> Result<DapResponse, DapAbort> {
         // Check whether the DAP version indicated by the sender is supported.
@@ -160,11 +220,9 @@ pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
             id = Some(TaskId(bytes))
         }
 
-        let hpke_config = self.get_hpke_config_for(req.version, id.as_ref()).await?;
-
-        if let Some(task_id) = id {
+        if let Some(task_id) = &id {
             let task_config = self
-                .get_task_config_for(Cow::Owned(task_id))
+                .get_task_config_for(Cow::Borrowed(task_id))
                 .await?
                 .ok_or(DapAbort::UnrecognizedTask)?;
 
@@ -178,12 +236,17 @@ pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
         }
 
         let payload = match req.version {
-            DapVersion::Draft02 => hpke_config.as_ref().get_encoded(),
+            // draft02 compatibility: Only one HPKE config can be advertised.
+            DapVersion::Draft02 => self
+                .get_hpke_config_for(req.version, id.as_ref())
+                .await?
+                .as_ref()
+                .get_encoded(),
             DapVersion::Draft07 => {
-                let hpke_config_list = HpkeConfigList {
-                    hpke_configs: vec![hpke_config.as_ref().clone()],
-                };
-                hpke_config_list.get_encoded()
+                let hpke_configs = self
+                    .get_hpke_config_list_for(req.version, id.as_ref())
+                    .await?;
+                HpkeConfigList { hpke_configs }.get_encoded()
             }
             // This is just to keep the compiler happy as we excluded DapVersion::Unknown by
             // aborting at the top of the function.
@@ -194,7 +257,7 @@ pub trait DapAggregator<S>: HpkeDecrypter + DapReportInitializer + Sized {
         Ok(DapResponse {
             version: req.version,
             media_type: DapMediaType::HpkeConfigList,
-            payload,
+            payload: payload.into(),
         })
     }
 
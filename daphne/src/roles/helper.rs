@@ -8,24 +8,36 @@ use futures::TryFutureExt;
 use prio::codec::{Encode, ParameterizedDecode};
 use tracing::error;
 
-use super::{check_batch, check_request_content_type, resolve_taskprov, DapAggregator};
+use super::{
+    check_batch, check_request_content_type, resolve_taskprov, task_config_for_version,
+    DapAggregator,
+};
 use crate::{
     audit_log::AggregationJobAuditAction,
     constants::DapMediaType,
     error::DapAbort,
     fatal_error,
+    memory_budget::{agg_job_init_memory_estimate, MemoryBudget},
     messages::{
-        constant_time_eq, AggregateShare, AggregateShareReq, AggregationJobContinueReq,
-        AggregationJobInitReq, Draft02AggregationJobId, PartialBatchSelector, TaskId,
+        constant_time_eq, AggregateShare, AggregateShareReq, AggregationJobAbandonReq,
+        AggregationJobContinueReq, AggregationJobInitExtension, AggregationJobInitReqRef,
+        AggregationJobResp, Draft02AggregationJobId, PartialBatchSelector, TaskId,
     },
     metrics::{ContextualizedDaphneMetrics, DaphneRequestType},
-    DapError, DapHelperState, DapHelperTransition, DapRequest, DapResource, DapResponse,
-    DapTaskConfig, DapVersion, MetaAggregationJobId,
+    DapAggregateShare, DapAggregateShareMeta, DapAggregationParamHash, DapBatchBucket, DapError,
+    DapHelperState, DapHelperTransition, DapRequest, DapResource, DapResponse, DapResponsePayload,
+    DapTaskConfig, DapVersion, MaybeSendSync, MetaAggregationJobId,
 };
 
 /// DAP Helper functionality.
-#[async_trait(?Send)]
-pub trait DapHelper<S>: DapAggregator<S> {
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait DapHelper<S: MaybeSendSync>: DapAggregator<S> {
+    /// The tracker of memory reserved by in-flight aggregation jobs. The tracker is consulted if
+    /// the global config sets `agg_job_memory_budget`; it should be shared by all requests handled
+    /// by the process.
+    fn agg_job_memory_budget(&self) -> &MemoryBudget;
+
     /// Store the Helper's aggregation-flow state unless it already exists. Returns a boolean
     /// indicating if the operation succeeded.
     async fn put_helper_state_if_not_exists(
@@ -35,22 +47,87 @@ pub trait DapHelper<S>: DapAggregator<S> {
         helper_state: &DapHelperState,
     ) -> Result<bool, DapError>;
 
-    /// Fetch the Helper's aggregation-flow state. `None` is returned if the Helper has no state
-    /// associated with the given task and aggregation job.
-    async fn get_helper_state(
+    /// Claim the Helper's aggregation-flow state. `None` is returned if the Helper has no state
+    /// associated with the given task and aggregation job, or if the state has been acknowledged
+    /// by `ack_helper_state()`.
+    ///
+    /// The state is not deleted, but is marked as in-flight. While the claim is held, subsequent
+    /// claims fail with [`DapAbort::TooManyRequests`], asking the Leader to retry the request once
+    /// the claim has expired. If the claim is not acknowledged within an
+    /// implementation-defined timeout, e.g., because the request that made it was dropped, then
+    /// the state becomes available to claim again.
+    async fn claim_helper_state(
         &self,
         task_id: &TaskId,
         agg_job_id: &MetaAggregationJobId,
     ) -> Result<Option<DapHelperState>, DapError>;
 
+    /// Acknowledge that the state claimed by `claim_helper_state()` has been consumed. The state
+    /// is deleted, but the aggregation job is remembered so that the state can't be stored again
+    /// by `put_helper_state_if_not_exists()`.
+    async fn ack_helper_state(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError>;
+
+    /// Store the Helper's response to the last `AggregationJobContinueReq` it processed for the
+    /// given aggregation job, along with the round indicated by the request and the SHA-256 digest
+    /// of the request body. This is used to recover from round skew: if the Leader retransmits
+    /// the request, then the response is replayed rather than computed again.
+    ///
+    /// The response is stored before the output shares are committed, overwriting any response
+    /// stored previously for the aggregation job.
+    async fn put_agg_job_cont_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        round: u16,
+        req_digest: &[u8; 32],
+        agg_job_resp: &AggregationJobResp,
+    ) -> Result<(), DapError>;
+
+    /// Fetch the response stored by `put_agg_job_cont_resp()`, along with the round and request
+    /// digest it pertains to. `None` is returned if the Helper has not yet responded to an
+    /// `AggregationJobContinueReq` for the given task and aggregation job, or if the Helper's
+    /// state has not been acknowledged by `ack_helper_state()`, in which case the output shares
+    /// may not have been committed.
+    async fn get_agg_job_cont_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<(u16, [u8; 32], AggregationJobResp)>, DapError>;
+
+    /// Delete the Helper's aggregation-flow state and the response stored by
+    /// `put_agg_job_cont_resp()` for the given task and aggregation job. Like
+    /// `ack_helper_state()`, the aggregation job is remembered so that the state can't be stored
+    /// again by `put_helper_state_if_not_exists()`. This is a no-op if there is nothing stored.
+    async fn delete_helper_state(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError>;
+
+    /// Charge a request against the sender's quota, if one is enforced. `reports` is the number
+    /// of reports carried by the request. This is called once the task has been resolved, possibly
+    /// by taskprov, and the request has been authorized, so that a sender can't exhaust the quota
+    /// of a credential it does not hold. Abandoning an aggregation job only frees resources, so it
+    /// is not charged.
+    ///
+    /// The default implementation does not enforce a quota.
+    async fn check_quota(&self, _req: &DapRequest<S>, _reports: u64) -> Result<(), DapAbort> {
+        Ok(())
+    }
+
     async fn handle_agg_job_init_req<'req>(
         &self,
         req: &'req DapRequest<S>,
         metrics: ContextualizedDaphneMetrics<'req>,
         task_id: &TaskId,
     ) -> Result<DapResponse, DapAbort> {
+        // Report shares borrow from the request payload rather than being copied out of it.
         let agg_job_init_req =
-            AggregationJobInitReq::get_decoded_with_param(&req.version, &req.payload)
+            AggregationJobInitReqRef::get_decoded_borrowed(&req.version, &req.payload)
                 .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
 
         metrics.agg_job_observe_batch_size(agg_job_init_req.report_shares.len());
@@ -73,7 +150,7 @@ pub trait DapHelper<S>: DapAggregator<S> {
                     agg_job_init_req
                         .report_shares
                         .first()
-                        .map(|report_share| &report_share.report_metadata)
+                        .map(|report_share| report_share.report_metadata.as_ref())
                 }
                 _ => {
                     // It's not all taskprov or no taskprov, so it's an error.
@@ -101,20 +178,74 @@ pub trait DapHelper<S>: DapAggregator<S> {
             });
         }
 
+        self.check_quota(req, agg_job_init_req.report_shares.len() as u64)
+            .await?;
+
         let agg_job_id = resolve_agg_job_id(req, agg_job_init_req.draft02_agg_job_id.as_ref())?;
 
-        // Check whether the DAP version in the request matches the task config.
-        if task_config.version != req.version {
-            return Err(DapAbort::version_mismatch(req.version, task_config.version));
-        }
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config, req.version)?;
+        let task_config = task_config.as_ref();
+
+        // Check that the aggregation parameter is suitable for the task's VDAF before doing any
+        // work on the reports.
+        task_config
+            .vdaf
+            .check_agg_param(task_id, agg_job_init_req.agg_param)?;
 
         // Ensure we know which batch the request pertains to.
-        check_part_batch(
-            task_id,
-            task_config,
-            &agg_job_init_req.part_batch_sel,
-            &agg_job_init_req.agg_param,
-        )?;
+        check_part_batch(task_id, task_config, &agg_job_init_req.part_batch_sel)?;
+
+        // Refuse to prepare reports for a VDAF that is out of bounds.
+        self.get_global_config()
+            .check_vdaf_config(task_id, &task_config.vdaf)?;
+
+        // Resolve the VDAF verification key selected by the Leader.
+        let mut vdaf_verify_key_id = None;
+        for extension in &agg_job_init_req.extensions {
+            match extension {
+                AggregationJobInitExtension::VdafVerifyKeyId { key_id } => {
+                    vdaf_verify_key_id = Some(*key_id);
+                }
+                AggregationJobInitExtension::Unhandled { typ, .. } => {
+                    return Err(DapAbort::UnrecognizedMessage {
+                        detail: format!("unrecognized aggregation job extension ({typ})"),
+                        task_id: Some(task_id.clone()),
+                    });
+                }
+            }
+        }
+        let vdaf_verify_key = task_config
+            .vdaf_verify_key_for(vdaf_verify_key_id)
+            .ok_or_else(|| DapAbort::UnrecognizedMessage {
+                detail: format!("unrecognized VDAF verify key ID ({vdaf_verify_key_id:?})"),
+                task_id: Some(task_id.clone()),
+            })?;
+
+        // Shed the job if initializing it could exceed the memory budget. The reservation is held
+        // until the Helper's state is stored.
+        let _reservation = match &self.get_global_config().agg_job_memory_budget {
+            Some(budget_config) => {
+                let estimate = agg_job_init_memory_estimate(
+                    &task_config.vdaf,
+                    req.payload.len(),
+                    agg_job_init_req.report_shares.len(),
+                )?;
+                let reservation = self
+                    .agg_job_memory_budget()
+                    .try_reserve(estimate, budget_config.limit_bytes);
+                if reservation.is_none() {
+                    metrics.agg_job_shed_inc();
+                    return Err(DapAbort::TooManyRequests {
+                        detail: "The Helper is temporarily unable to accept aggregation jobs."
+                            .into(),
+                        retry_after: Some(budget_config.retry_after),
+                    });
+                }
+                reservation
+            }
+            None => None,
+        };
 
         let transition = task_config
             .vdaf
@@ -123,7 +254,10 @@ pub trait DapHelper<S>: DapAggregator<S> {
                 self,
                 task_id,
                 task_config,
+                vdaf_verify_key,
+                &agg_job_id,
                 &agg_job_init_req,
+                self.get_global_config().agg_job_min_acceptance_ratio,
                 &metrics,
             )
             .map_err(DapError::Abort)
@@ -143,7 +277,11 @@ pub trait DapHelper<S>: DapAggregator<S> {
                 agg_job_resp
             }
             DapHelperTransition::Finish(..) => {
-                return Err(fatal_error!(err = "unexpected transition (finished)").into());
+                return Err(fatal_error!(
+                    err = "unexpected transition (finished)",
+                    code = StateCorruption
+                )
+                .into());
             }
         };
 
@@ -160,7 +298,7 @@ pub trait DapHelper<S>: DapAggregator<S> {
         Ok(DapResponse {
             version: req.version,
             media_type: DapMediaType::AggregationJobResp,
-            payload: agg_job_resp.get_encoded(),
+            payload: DapResponsePayload::Chunks(agg_job_resp.finish_chunks()),
         })
     }
 
@@ -187,10 +325,11 @@ pub trait DapHelper<S>: DapAggregator<S> {
             });
         }
 
-        // Check whether the DAP version in the request matches the task config.
-        if task_config.version != req.version {
-            return Err(DapAbort::version_mismatch(req.version, task_config.version));
-        }
+        self.check_quota(req, 0).await?;
+
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config, req.version)?;
+        let task_config = task_config.as_ref();
 
         let agg_job_cont_req =
             AggregationJobContinueReq::get_decoded_with_param(&req.version, &req.payload)
@@ -198,8 +337,44 @@ pub trait DapHelper<S>: DapAggregator<S> {
 
         let agg_job_id = resolve_agg_job_id(req, agg_job_cont_req.draft02_agg_job_id.as_ref())?;
 
+        // Recover from round skew (draft07): If the request indicates the round we have already
+        // completed, then the Leader did not receive our response. Send it again instead of
+        // aggregating the reports a second time, which would cause them to be rejected as
+        // replays. This is checked before claiming the state, since the state is acknowledged
+        // (and thus deleted) once the output shares are committed.
+        let req_digest: [u8; 32] = ring::digest::digest(&ring::digest::SHA256, &req.payload)
+            .as_ref()
+            .try_into()
+            .map_err(|e| fatal_error!(err = ?e))?;
+        if let Some(round) = agg_job_cont_req.round {
+            if let Some((last_round, last_req_digest, agg_job_resp)) =
+                self.get_agg_job_cont_resp(task_id, &agg_job_id).await?
+            {
+                if round == last_round {
+                    if last_req_digest != req_digest {
+                        return Err(DapAbort::RoundMismatch {
+                            detail: format!(
+                                "The request indicates round {round}, which was completed by a different request."
+                            ),
+                            task_id: task_id.clone(),
+                            agg_job_id_base64url: agg_job_id.to_base64url(),
+                        });
+                    }
+
+                    metrics.inbound_req_inc(DaphneRequestType::Aggregate);
+                    return Ok(DapResponse {
+                        version: req.version,
+                        media_type: DapMediaType::agg_job_cont_resp_for_version(
+                            task_config.version,
+                        ),
+                        payload: agg_job_resp.get_encoded().into(),
+                    });
+                }
+            }
+        }
+
         let state = self
-            .get_helper_state(task_id, &agg_job_id)
+            .claim_helper_state(task_id, &agg_job_id)
             .await?
             .ok_or_else(|| DapAbort::UnrecognizedAggregationJob {
                 task_id: task_id.clone(),
@@ -239,6 +414,21 @@ pub trait DapHelper<S>: DapAggregator<S> {
                 )?;
 
                 let out_shares_count = agg_share_span.report_count().try_into().unwrap();
+
+                // Store the response before committing the output shares, so that the response
+                // can be replayed once they are. It is only replayed after the state has been
+                // acknowledged.
+                if let Some(round) = agg_job_cont_req.round {
+                    self.put_agg_job_cont_resp(
+                        task_id,
+                        &agg_job_id,
+                        round,
+                        &req_digest,
+                        &agg_job_resp,
+                    )
+                    .await?;
+                }
+
                 let replayed = self
                     .try_put_agg_share_span(task_id, task_config, agg_share_span)
                     .await?;
@@ -252,6 +442,8 @@ pub trait DapHelper<S>: DapAggregator<S> {
             }
         };
 
+        self.ack_helper_state(task_id, &agg_job_id).await?;
+
         self.audit_log().on_aggregation_job(
             req.host(),
             task_id,
@@ -266,10 +458,106 @@ pub trait DapHelper<S>: DapAggregator<S> {
         Ok(DapResponse {
             version: req.version,
             media_type: DapMediaType::agg_job_cont_resp_for_version(task_config.version),
-            payload: agg_job_resp.get_encoded(),
+            payload: agg_job_resp.get_encoded().into(),
+        })
+    }
+
+    /// Handle a request from the Leader to abandon an aggregation job. The Leader sends this on a
+    /// best-effort basis when it fails to complete the job, so that the Helper doesn't keep its
+    /// state for the job around until it expires.
+    async fn handle_agg_job_abandon_req<'req>(
+        &self,
+        req: &'req DapRequest<S>,
+        metrics: ContextualizedDaphneMetrics<'req>,
+        task_id: &TaskId,
+    ) -> Result<DapResponse, DapAbort> {
+        self.delete_agg_job(req, metrics, task_id).await?;
+        Ok(DapResponse {
+            version: req.version,
+            media_type: DapMediaType::AggregationJobAbandon,
+            payload: Vec::new().into(),
         })
     }
 
+    /// Handle a DELETE request for an aggregation job (draft07 and later). The Leader sends this
+    /// when it gives up on the job. The Helper deletes its state for the job, after which the job
+    /// can't be initialized again.
+    async fn handle_agg_job_delete_req(
+        &self,
+        req: &DapRequest<S>,
+    ) -> Result<DapResponse, DapAbort> {
+        let metrics = self.metrics().with_host(req.host());
+        let task_id = req.task_id()?;
+
+        match req.version {
+            DapVersion::Draft02 => {
+                return Err(DapAbort::BadRequest(
+                    "aggregation jobs can't be deleted in draft02".into(),
+                ))
+            }
+            DapVersion::Unknown => return Err(DapAbort::version_unknown()),
+            _ => (),
+        }
+
+        self.delete_agg_job(req, metrics, task_id).await?;
+        Ok(DapResponse {
+            version: req.version,
+            media_type: DapMediaType::Missing,
+            payload: Vec::new().into(),
+        })
+    }
+
+    /// Delete the Helper's state for the aggregation job targeted by an abandon or DELETE request.
+    /// In draft02, the aggregation job is identified by the `AggregationJobAbandonReq` carried in
+    /// the payload.
+    async fn delete_agg_job<'req>(
+        &self,
+        req: &'req DapRequest<S>,
+        metrics: ContextualizedDaphneMetrics<'req>,
+        task_id: &TaskId,
+    ) -> Result<(), DapAbort> {
+        if let Some(taskprov_version) = self.get_global_config().taskprov_version {
+            resolve_taskprov(self, task_id, req, None, taskprov_version).await?;
+        }
+        let wrapped_task_config = self
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+        let task_config = wrapped_task_config.as_ref();
+
+        if let Some(reason) = self.unauthorized_reason(task_config, req).await? {
+            error!("aborted unauthorized abandon request: {reason}");
+            return Err(DapAbort::UnauthorizedRequest {
+                detail: reason,
+                task_id: task_id.clone(),
+            });
+        }
+
+        // Check whether the task is offered in the DAP version of the request.
+        task_config_for_version(task_config, req.version)?;
+
+        let agg_job_abandon_req = if req.media_type == DapMediaType::AggregationJobAbandon {
+            Some(
+                AggregationJobAbandonReq::get_decoded_with_param(&req.version, &req.payload)
+                    .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?,
+            )
+        } else {
+            None
+        };
+
+        let agg_job_id = resolve_agg_job_id(
+            req,
+            agg_job_abandon_req
+                .as_ref()
+                .and_then(|agg_job_abandon_req| agg_job_abandon_req.draft02_agg_job_id.as_ref()),
+        )?;
+
+        self.delete_helper_state(task_id, &agg_job_id).await?;
+
+        metrics.agg_job_abandoned_inc();
+        Ok(())
+    }
+
     /// Handle a request pertaining to an aggregation job.
     async fn handle_agg_job_req(&self, req: &DapRequest<S>) -> Result<DapResponse, DapAbort> {
         let metrics = self.metrics().with_host(req.host());
@@ -287,6 +575,9 @@ pub trait DapHelper<S>: DapAggregator<S> {
             DapMediaType::AggregationJobContinueReq => {
                 self.handle_agg_job_cont_req(req, metrics, task_id).await
             }
+            DapMediaType::AggregationJobAbandon => {
+                self.handle_agg_job_abandon_req(req, metrics, task_id).await
+            }
             //TODO spec: Specify this behavior.
             _ => Err(DapAbort::BadRequest("unexpected media type".into())),
         }
@@ -324,14 +615,19 @@ pub trait DapHelper<S>: DapAggregator<S> {
             });
         }
 
-        // Check whether the DAP version in the request matches the task config.
-        if task_config.version != req.version {
-            return Err(DapAbort::version_mismatch(req.version, task_config.version));
-        }
+        self.check_quota(req, 0).await?;
+
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config, req.version)?;
+        let task_config = task_config.as_ref();
 
         let agg_share_req = AggregateShareReq::get_decoded_with_param(&req.version, &req.payload)
             .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
 
+        task_config
+            .vdaf
+            .check_agg_param(task_id, &agg_share_req.agg_param)?;
+
         // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
         // collected batches.
         check_batch(
@@ -344,22 +640,20 @@ pub trait DapHelper<S>: DapAggregator<S> {
         )
         .await?;
 
-        let agg_share = self
-            .get_agg_share(task_id, &agg_share_req.batch_sel)
+        // Check that we have aggregated the same set of reports as the Leader. The aggregate share
+        // of each bucket is fetched so that a mismatch can be narrowed down to the buckets.
+        let bucket_agg_shares = self
+            .get_agg_share_per_bucket(task_id, &agg_share_req.batch_sel)
             .await?;
+        let bucket_metas = bucket_agg_shares
+            .iter()
+            .map(|(bucket, bucket_agg_share)| (bucket.clone(), bucket_agg_share.meta()))
+            .collect::<Vec<_>>();
+        check_batch_checksum(task_id, &agg_share_req, &bucket_metas)?;
 
-        // Check that we have aggreagted the same set of reports as the Leader.
-        if agg_share_req.report_count != agg_share.report_count
-            || !constant_time_eq(&agg_share_req.checksum, &agg_share.checksum)
-        {
-            return Err(DapAbort::BatchMismatch{
-                detail: format!("Either the report count or checksum does not match: the Leader computed {} and {}; the Helper computed {} and {}.",
-                    agg_share_req.report_count,
-                    hex::encode(agg_share_req.checksum),
-                    agg_share.report_count,
-                    hex::encode(agg_share.checksum)),
-                task_id: task_id.clone(),
-            });
+        let mut agg_share = DapAggregateShare::default();
+        for (_bucket, bucket_agg_share) in bucket_agg_shares {
+            agg_share.merge(bucket_agg_share)?;
         }
 
         // Check the batch size.
@@ -376,12 +670,29 @@ pub trait DapHelper<S>: DapAggregator<S> {
             });
         }
 
+        // Check the task's batch policy.
+        if !task_config.is_batch_collectable(task_id, &agg_share_req.batch_sel, &agg_share, now)? {
+            return Err(DapAbort::InvalidBatchSize {
+                detail: "The batch does not satisfy the task's batch policy.".to_string(),
+                task_id: task_id.clone(),
+            });
+        }
+
         // Mark each aggregated report as collected.
-        self.mark_collected(task_id, &agg_share_req.batch_sel)
-            .await?;
+        self.mark_collected(
+            task_id,
+            &agg_share_req.batch_sel,
+            &DapAggregationParamHash::new(&agg_share_req.agg_param),
+        )
+        .await?;
 
+        let collector_hpke_config = self
+            .get_collector_hpke_config_for(task_id, task_config)
+            .await?;
         let encrypted_agg_share = task_config.vdaf.produce_helper_encrypted_agg_share(
-            &task_config.collector_hpke_config,
+            collector_hpke_config
+                .as_ref()
+                .unwrap_or(&task_config.collector_hpke_config),
             task_id,
             &agg_share_req.batch_sel,
             &agg_share,
@@ -397,16 +708,71 @@ pub trait DapHelper<S>: DapAggregator<S> {
         Ok(DapResponse {
             version: req.version,
             media_type: DapMediaType::AggregateShare,
-            payload: agg_share_resp.get_encoded(),
+            payload: agg_share_resp.get_encoded().into(),
+        })
+    }
+}
+
+/// Maximum number of buckets listed in the detail of a batch mismatch.
+pub(super) const MAX_BATCH_MISMATCH_BUCKETS: usize = 16;
+
+/// Check the report count and checksum sent by the Leader against the metadata of each bucket of
+/// the batch. If they don't match, then the abort lists the report count and checksum of each
+/// non-empty bucket (up to [`MAX_BATCH_MISMATCH_BUCKETS`] of them) so that the Leader can
+/// determine which buckets disagree.
+pub(super) fn check_batch_checksum(
+    task_id: &TaskId,
+    agg_share_req: &AggregateShareReq,
+    bucket_metas: &[(DapBatchBucket, DapAggregateShareMeta)],
+) -> Result<(), DapAbort> {
+    let mut meta = DapAggregateShareMeta::default();
+    for (_bucket, bucket_meta) in bucket_metas {
+        meta.merge(bucket_meta.clone());
+    }
+
+    if agg_share_req.report_count == meta.report_count
+        && constant_time_eq(&agg_share_req.checksum, &meta.checksum)
+    {
+        return Ok(());
+    }
+
+    let non_empty_buckets = bucket_metas
+        .iter()
+        .filter(|(_bucket, bucket_meta)| bucket_meta.report_count > 0);
+    let mut buckets = non_empty_buckets
+        .clone()
+        .take(MAX_BATCH_MISMATCH_BUCKETS)
+        .map(|(bucket, bucket_meta)| {
+            format!(
+                "{bucket} ({}, {})",
+                bucket_meta.report_count,
+                hex::encode(bucket_meta.checksum)
+            )
         })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let num_omitted = non_empty_buckets
+        .count()
+        .saturating_sub(MAX_BATCH_MISMATCH_BUCKETS);
+    if num_omitted > 0 {
+        buckets.push_str(&format!(", and {num_omitted} more"));
     }
+    Err(DapAbort::BatchMismatch {
+        detail: format!(
+            "Either the report count or checksum does not match: the Leader computed {} and {}; the Helper computed {} and {}. Report count and checksum of each bucket aggregated by the Helper: [{buckets}].",
+            agg_share_req.report_count,
+            hex::encode(agg_share_req.checksum),
+            meta.report_count,
+            hex::encode(meta.checksum),
+        ),
+        task_id: task_id.clone(),
+    })
 }
 
 fn check_part_batch(
     task_id: &TaskId,
     task_config: &DapTaskConfig,
     part_batch_sel: &PartialBatchSelector,
-    agg_param: &[u8],
 ) -> Result<(), DapAbort> {
     if !task_config.query.is_valid_part_batch_sel(part_batch_sel) {
         return Err(DapAbort::query_mismatch(
@@ -416,15 +782,6 @@ fn check_part_batch(
         ));
     }
 
-    // Check that the aggregation parameter is suitable for the given VDAF.
-    if !task_config.vdaf.is_valid_agg_param(agg_param) {
-        // TODO spec: Define this behavior.
-        return Err(DapAbort::UnrecognizedMessage {
-            detail: "invalid aggregation parameter".into(),
-            task_id: Some(task_id.clone()),
-        });
-    }
-
     Ok(())
 }
 
@@ -492,26 +849,34 @@ mod tests {
             .map(|r| r.report_metadata.id.clone())
             .collect::<Vec<_>>();
 
-        let req = test
-            .gen_test_agg_job_init_req(&task_id, DapVersion::Draft02, report_shares)
-            .await;
-
-        let meta_agg_job_id = MetaAggregationJobId::Draft02(Cow::Owned(
-            AggregationJobInitReq::get_decoded_with_param(&DapVersion::Draft02, &req.payload)
-                .unwrap()
-                .draft02_agg_job_id
-                .unwrap(),
-        ));
+        // The Helper's state is consumed by the first AggregationJobContinueReq, so each
+        // continuation is for a different aggregation job. Both jobs are initialized before
+        // either is continued, so that the replay is only detected when continuing.
+        let init_agg_job = |report_shares: Vec<ReportShare>| async {
+            let req = test
+                .gen_test_agg_job_init_req(&task_id, DapVersion::Draft02, report_shares)
+                .await;
+            helper
+                .handle_agg_job_init_req(&req, helper.metrics.with_host("test"), &task_id)
+                .await
+                .unwrap();
+            MetaAggregationJobId::Draft02(Cow::Owned(
+                AggregationJobInitReq::get_decoded_with_param(&DapVersion::Draft02, &req.payload)
+                    .unwrap()
+                    .draft02_agg_job_id
+                    .unwrap(),
+            ))
+        };
 
-        helper
-            .handle_agg_job_init_req(&req, helper.metrics.with_host("test"), &task_id)
-            .await
-            .unwrap();
+        let agg_job_ids = [
+            init_agg_job(report_shares[0..2].to_vec()).await,
+            init_agg_job(report_shares[1..3].to_vec()).await,
+        ];
 
         {
             let req = test
                 .gen_test_agg_job_cont_req(
-                    &meta_agg_job_id,
+                    &agg_job_ids[0],
                     report_ids[0..2]
                         .iter()
                         .map(|id| Transition {
@@ -528,9 +893,11 @@ mod tests {
                 .await
                 .unwrap();
 
-            let a_job_resp =
-                AggregationJobResp::get_decoded_with_param(&DapVersion::Draft02, &resp.payload)
-                    .unwrap();
+            let a_job_resp = AggregationJobResp::get_decoded_with_param(
+                &DapVersion::Draft02,
+                &resp.payload.to_bytes(),
+            )
+            .unwrap();
             assert_eq!(a_job_resp.transitions.len(), 2);
             assert!(a_job_resp
                 .transitions
@@ -540,7 +907,7 @@ mod tests {
         {
             let req = test
                 .gen_test_agg_job_cont_req(
-                    &meta_agg_job_id,
+                    &agg_job_ids[1],
                     report_ids[1..3]
                         .iter()
                         .map(|id| Transition {
@@ -557,9 +924,11 @@ mod tests {
                 .await
                 .unwrap();
 
-            let a_job_resp =
-                AggregationJobResp::get_decoded_with_param(&DapVersion::Draft02, &resp.payload)
-                    .unwrap();
+            let a_job_resp = AggregationJobResp::get_decoded_with_param(
+                &DapVersion::Draft02,
+                &resp.payload.to_bytes(),
+            )
+            .unwrap();
             assert_eq!(a_job_resp.transitions.len(), 2);
             assert_matches!(
                 a_job_resp.transitions[0].var,
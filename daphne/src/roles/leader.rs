@@ -1,28 +1,44 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+};
 
 use async_trait::async_trait;
-use prio::codec::{Decode, ParameterizedDecode, ParameterizedEncode};
-use tracing::{debug, error};
+use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+use prometheus::Registry;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
 use url::Url;
 
-use super::{check_batch, check_request_content_type, resolve_taskprov, DapAggregator};
+use super::{
+    check_batch, check_request_content_type, resolve_taskprov, task_config_for_version,
+    DapAggregator,
+};
 use crate::{
     constants::DapMediaType,
-    error::DapAbort,
+    content_encoding::DapContentEncoding,
+    error::{DapAbort, DapErrorCode},
+    extensions::check_client_auth,
     fatal_error,
     messages::{
-        AggregateShare, AggregateShareReq, AggregationJobResp, BatchSelector, Collection,
-        CollectionJobId, CollectionReq, Interval, PartialBatchSelector, Query, Report, TaskId,
+        AggregateShare, AggregateShareReq, AggregationJobAbandonReq, AggregationJobInitExtension,
+        AggregationJobResp, BatchId, BatchSelector, Collection, CollectionJobId, CollectionReq,
+        Interval, PartialBatchSelector, Query, Report, ReportBatch, ReportBatchResp, ReportId,
+        ReportMetadata, ReportUploadResult, TaskId, Time, TransitionFailure,
     },
-    metrics::DaphneRequestType,
-    DapCollectJob, DapError, DapLeaderProcessTelemetry, DapLeaderTransition, DapRequest,
-    DapResource, DapResponse, DapTaskConfig, DapVersion, MetaAggregationJobId,
+    metrics::{ContextualizedDaphneMetrics, DaphneMetrics, DaphneRequestType},
+    DapAggregationDryRun, DapAggregationParamHash, DapBatchState, DapCollectJob,
+    DapCollectJobSummary, DapCollectPrecheck, DapCollectPriority, DapError,
+    DapLeaderProcessTelemetry, DapLeaderTransition, DapQueryConfig, DapRequest, DapResource,
+    DapResponse, DapTaskConfig, DapVersion, MaybeSendSync, MetaAggregationJobId,
 };
 
 struct LeaderHttpRequestOptions<'p> {
+    metrics: &'p ContextualizedDaphneMetrics<'p>,
     path: &'p str,
     req_media_type: DapMediaType,
     resp_media_type: DapMediaType,
@@ -34,15 +50,25 @@ struct LeaderHttpRequestOptions<'p> {
 enum LeaderHttpRequestMethod {
     Post,
     Put,
+    Delete,
+}
+
+impl LeaderHttpRequestMethod {
+    /// Whether the request can be safely repeated. The Helper handles a repeated PUT or DELETE
+    /// request the same way as the original.
+    fn is_idempotent(&self) -> bool {
+        matches!(self, Self::Put | Self::Delete)
+    }
 }
 
-async fn leader_send_http_request<S>(
+async fn leader_send_http_request<S: MaybeSendSync>(
     role: &impl DapLeader<S>,
     task_id: &TaskId,
     task_config: &DapTaskConfig,
     opts: LeaderHttpRequestOptions<'_>,
 ) -> Result<DapResponse, DapError> {
     let LeaderHttpRequestOptions {
+        metrics,
         path,
         req_media_type,
         resp_media_type,
@@ -56,32 +82,269 @@ async fn leader_send_http_request<S>(
         .join(path)
         .map_err(|e| fatal_error!(err = ?e))?;
 
-    let req = DapRequest {
-        version: task_config.version,
-        media_type: req_media_type.clone(),
-        task_id: Some(task_id.clone()),
-        resource,
-        url,
-        sender_auth: Some(
-            role.authorize(task_id, task_config, &req_media_type, &req_data)
-                .await?,
-        ),
-        payload: req_data,
-        taskprov: None,
+    // Large aggregation job initialization requests are mostly ciphertext framing, which
+    // compresses well.
+    let content_encoding = match role
+        .get_global_config()
+        .agg_job_init_req_compression_threshold
+    {
+        Some(threshold)
+            if req_media_type == DapMediaType::AggregationJobInitReq
+                && req_data.len() > threshold =>
+        {
+            Some(DapContentEncoding::Gzip)
+        }
+        _ => None,
+    };
+
+    let retry_config = &role.get_global_config().helper_request_retry;
+    let max_attempts = if method.is_idempotent() {
+        retry_config.max_attempts.max(1)
+    } else {
+        1
     };
 
-    let resp = match method {
-        LeaderHttpRequestMethod::Put => role.send_http_put(req).await?,
-        LeaderHttpRequestMethod::Post => role.send_http_post(req).await?,
+    // A DELETE request has no content-type, so it is authorized like the request to abandon the
+    // aggregation job that it replaces.
+    let auth_media_type = match method {
+        LeaderHttpRequestMethod::Delete => DapMediaType::AggregationJobAbandon,
+        _ => req_media_type.clone(),
+    };
+
+    let mut attempt = 1;
+    let resp = loop {
+        let req = DapRequest {
+            version: task_config.version,
+            media_type: req_media_type.clone(),
+            task_id: Some(task_id.clone()),
+            resource: resource.clone(),
+            url: url.clone(),
+            sender_auth: Some(
+                role.authorize(task_id, task_config, &auth_media_type, &req_data)
+                    .await?,
+            ),
+            payload: req_data.clone(),
+            taskprov: None,
+            content_encoding,
+            collect_priority: None,
+        };
+
+        let res = match method {
+            LeaderHttpRequestMethod::Put => role.send_http_put(req).await,
+            LeaderHttpRequestMethod::Post => role.send_http_post(req).await,
+            LeaderHttpRequestMethod::Delete => role.send_http_delete(req).await,
+        };
+
+        match res {
+            // Only transport errors and 5xx responses are retried; DAP aborts are final.
+            Err(e) if e.code() == Some(DapErrorCode::Network) && attempt < max_attempts => {
+                let delay = retry_config.delay_for_retry(attempt - 1);
+                warn!(error = ?e, attempt, ?delay, "request to Helper failed; retrying");
+                metrics.helper_request_retry_inc();
+                role.sleep(delay).await;
+                attempt += 1;
+            }
+            res => break res?,
+        }
     };
 
-    check_response_content_type(&resp, resp_media_type)?;
+    // The response to a DELETE request has no content.
+    if !matches!(method, LeaderHttpRequestMethod::Delete) {
+        check_response_content_type(&resp, resp_media_type)?;
+    }
     Ok(resp)
 }
 
+/// Ask the Helper to abandon an aggregation job that the Leader failed to complete, so that the
+/// Helper can delete its state for the job. In draft07, the job is deleted with a DELETE request;
+/// in draft02, an `AggregationJobAbandonReq` is sent instead. This is best-effort: a failure is
+/// only logged, and the job is only counted as abandoned if the Helper received the request.
+async fn abandon_agg_job<S: MaybeSendSync>(
+    role: &impl DapLeader<S>,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    metrics: &ContextualizedDaphneMetrics<'_>,
+    path: &str,
+    agg_job_id: &MetaAggregationJobId<'_>,
+) {
+    let (req_media_type, req_data, method) = if task_config.version == DapVersion::Draft02 {
+        let agg_job_abandon_req = AggregationJobAbandonReq {
+            draft02_task_id: task_id.for_request_payload(&task_config.version),
+            draft02_agg_job_id: agg_job_id.for_request_payload(),
+        };
+        (
+            DapMediaType::AggregationJobAbandon,
+            agg_job_abandon_req.get_encoded_with_param(&task_config.version),
+            LeaderHttpRequestMethod::Post,
+        )
+    } else {
+        (
+            DapMediaType::Missing,
+            Vec::new(),
+            LeaderHttpRequestMethod::Delete,
+        )
+    };
+
+    match leader_send_http_request(
+        role,
+        task_id,
+        task_config,
+        LeaderHttpRequestOptions {
+            metrics,
+            path,
+            req_media_type,
+            resp_media_type: DapMediaType::AggregationJobAbandon,
+            resource: agg_job_id.for_request_path(),
+            req_data,
+            method,
+        },
+    )
+    .await
+    {
+        Ok(..) => metrics.agg_job_abandoned_inc(),
+        Err(e) => warn!(
+            error = ?e,
+            agg_job_id = agg_job_id.to_base64url(),
+            "failed to abandon aggregation job"
+        ),
+    }
+}
+
+/// Return the HTTP method and the path with which the AggregationJobInitReq for the given
+/// aggregation job is sent. Subsequent requests for the job are sent to the same path.
+fn agg_job_init_req_target(
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    agg_job_id: &MetaAggregationJobId<'_>,
+) -> (LeaderHttpRequestMethod, String) {
+    if task_config.version == DapVersion::Draft02 {
+        (LeaderHttpRequestMethod::Post, "aggregate".to_string())
+    } else {
+        (
+            LeaderHttpRequestMethod::Put,
+            format!(
+                "tasks/{}/aggregation_jobs/{}",
+                task_id.to_base64url(),
+                agg_job_id.to_base64url()
+            ),
+        )
+    }
+}
+
+/// Run the first round of an aggregation job for the given reports, then abandon the job. Nothing
+/// is committed by either Aggregator. Rejections are counted with a dedicated set of metrics so
+/// that they do not show up in the Leader's.
+async fn dry_run_agg_job_for_reports<S: MaybeSendSync>(
+    role: &impl DapLeader<S>,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    part_batch_sel: &PartialBatchSelector,
+    reports: Vec<Report>,
+    host: &str,
+) -> Result<DapAggregationDryRun, DapAbort> {
+    let dry_run_metrics = DaphneMetrics::register(&Registry::new(), None)?;
+    let metrics = dry_run_metrics.with_host(host);
+    let num_reports = reports.len() as u64;
+
+    role.get_global_config()
+        .check_vdaf_config(task_id, &task_config.vdaf)?;
+
+    let agg_job_id = MetaAggregationJobId::gen_for_version(&task_config.version);
+    let (vdaf_verify_key_id, vdaf_verify_key) = task_config.active_vdaf_verify_key()?;
+    let transition = task_config
+        .vdaf
+        .produce_agg_job_init_req(
+            role,
+            role,
+            task_id,
+            task_config,
+            vdaf_verify_key,
+            &agg_job_id,
+            part_batch_sel,
+            reports,
+            &metrics,
+        )
+        .await?;
+    let accepted = match transition {
+        DapLeaderTransition::Continue(state, mut agg_job_init_req) => {
+            agg_job_init_req.extensions.extend(
+                vdaf_verify_key_id
+                    .map(|key_id| AggregationJobInitExtension::VdafVerifyKeyId { key_id }),
+            );
+            let (method, url_path) = agg_job_init_req_target(task_id, task_config, &agg_job_id);
+            let res: Result<u64, DapAbort> = async {
+                let resp = leader_send_http_request(
+                    role,
+                    task_id,
+                    task_config,
+                    LeaderHttpRequestOptions {
+                        metrics: &metrics,
+                        path: &url_path,
+                        req_media_type: DapMediaType::AggregationJobInitReq,
+                        resp_media_type: DapMediaType::AggregationJobResp,
+                        resource: agg_job_id.for_request_path(),
+                        req_data: agg_job_init_req.get_encoded_with_param(&task_config.version),
+                        method,
+                    },
+                )
+                .await?;
+                let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes())
+                    .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
+                match task_config.vdaf.handle_agg_job_resp(
+                    task_id,
+                    &agg_job_id,
+                    state,
+                    agg_job_resp,
+                    task_config.version,
+                    &metrics,
+                )? {
+                    DapLeaderTransition::Uncommitted(uncommitted, _) => {
+                        Ok(uncommitted.seq.len() as u64)
+                    }
+                    DapLeaderTransition::Skip => Ok(0),
+                    DapLeaderTransition::Continue(..) => Err(fatal_error!(
+                        err = "unexpected state transition (continue)",
+                        code = StateCorruption
+                    )
+                    .into()),
+                }
+            }
+            .await;
+
+            // The Helper has stored state for the job. Ask it to delete the state rather than
+            // continue the job.
+            abandon_agg_job(role, task_id, task_config, &metrics, &url_path, &agg_job_id).await;
+            res?
+        }
+        DapLeaderTransition::Skip => 0,
+        DapLeaderTransition::Uncommitted(..) => {
+            return Err(fatal_error!(
+                err = "unexpected state transition (uncommitted)",
+                code = StateCorruption
+            )
+            .into())
+        }
+    };
+
+    Ok(DapAggregationDryRun {
+        reports: num_reports,
+        accepted,
+        rejected: dry_run_metrics
+            .report_counts()
+            .into_iter()
+            .filter_map(|(status, count)| {
+                status
+                    .strip_prefix("rejected_")
+                    .map(|reason| (reason.to_string(), count))
+            })
+            .collect(),
+    })
+}
+
 /// A party in the DAP protocol who is authorized to send requests to another party.
-#[async_trait(?Send)]
-pub trait DapAuthorizedSender<S> {
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait DapAuthorizedSender<S: MaybeSendSync> {
     /// Add authorization to an outbound DAP request with the given task ID, media type, and payload.
     async fn authorize(
         &self,
@@ -92,30 +355,369 @@ pub trait DapAuthorizedSender<S> {
     ) -> Result<S, DapError>;
 }
 
+/// Check that a report uploaded by a Client can be accepted for the given task.
+async fn check_report_for_upload<S: MaybeSendSync>(
+    role: &impl DapLeader<S>,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    report: &Report,
+) -> Result<(), DapAbort> {
+    // draft02: Extensions are carried in the report metadata, so they can be checked now.
+    // Later drafts encrypt the extensions; these are checked during aggregation.
+    let extensions = &report.report_metadata.extensions;
+    if let Err(failure) = role
+        .extension_registry()
+        .check(task_id, task_config, extensions)
+        .and_then(|()| check_client_auth(task_id, task_config, &report.report_metadata, extensions))
+    {
+        return Err(match failure {
+            TransitionFailure::UnrecognizedMessage => DapAbort::UnrecognizedMessage {
+                detail: "report has an unrecognized or malformed extension".into(),
+                task_id: Some(task_id.clone()),
+            },
+            _ => DapAbort::ReportRejected {
+                detail: format!("report extension was rejected: {failure}"),
+            },
+        });
+    }
+
+    if report.encrypted_input_shares.len() != 2 {
+        // TODO spec: Decide if this behavior should be specified.
+        return Err(DapAbort::UnrecognizedMessage {
+            detail: format!(
+                "expected exactly two encrypted input shares; got {}",
+                report.encrypted_input_shares.len()
+            ),
+            task_id: Some(task_id.clone()),
+        });
+    }
+
+    // Check that the indicated HpkeConfig is present.
+    //
+    // TODO spec: It's not clear if this behavior is MUST, SHOULD, or MAY.
+    if !role
+        .can_hpke_decrypt(task_id, report.encrypted_input_shares[0].config_id)
+        .await?
+    {
+        return Err(DapAbort::ReportRejected {
+            detail: "No current HPKE configuration matches the indicated ID.".into(),
+        });
+    }
+
+    // Check that the task has not expired.
+    if report.report_metadata.time >= task_config.expiration {
+        return Err(DapAbort::ReportTooLate);
+    }
+
+    // Check that the Client truncated the timestamp.
+    if role.get_global_config().reject_unquantized_report_times
+        && !task_config.is_quantized_time(report.report_metadata.time)
+    {
+        return Err(DapAbort::ReportRejected {
+            detail: "The report timestamp is not truncated to the task's time precision.".into(),
+        });
+    }
+
+    Ok(())
+}
+
+/// A unit of background work for the Leader.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderWorkItem {
+    /// Run an aggregation job for a set of reports uploaded in the given version.
+    AggregateJob {
+        task_id: TaskId,
+        version: DapVersion,
+        part_batch_sel: PartialBatchSelector,
+        reports: Vec<Report>,
+    },
+
+    /// Run a pending collection job.
+    CollectJob {
+        task_id: TaskId,
+        collect_id: CollectionJobId,
+        collect_req: CollectionReq,
+    },
+}
+
+impl LeaderWorkItem {
+    /// Return the priority of the work item. Items with higher priority should be run first.
+    ///
+    /// Aggregation jobs take priority over collection jobs. This is to prevent a race condition
+    /// involving an aggregate share computed during a collection job and any output shares
+    /// computed during an aggregation job for the same batch.
+    pub fn priority(&self) -> u8 {
+        match self {
+            Self::AggregateJob { .. } => 1,
+            Self::CollectJob { .. } => 0,
+        }
+    }
+
+    /// Return the ID of the task the work item pertains to.
+    pub fn task_id(&self) -> &TaskId {
+        match self {
+            Self::AggregateJob { task_id, .. } | Self::CollectJob { task_id, .. } => task_id,
+        }
+    }
+}
+
+/// A work item taken from a [`LeaderWorkQueue`].
+#[derive(Debug)]
+pub struct QueuedLeaderWorkItem {
+    pub item: LeaderWorkItem,
+
+    /// The number of times the item has been attempted, including the current attempt.
+    pub attempts: u32,
+}
+
+/// An in-memory queue of [`LeaderWorkItem`]s. Items are taken in order of priority; items with the
+/// same priority are taken in the order in which they were added. Deployments that drive the
+/// Leader from an external queue can instead run each item with [`DapLeader::run_work_item`].
+pub struct LeaderWorkQueue {
+    items: BTreeMap<u8, VecDeque<QueuedLeaderWorkItem>>,
+    max_attempts: u32,
+}
+
+impl LeaderWorkQueue {
+    /// Create an empty queue. Each item is attempted at most `max_attempts` times.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            items: BTreeMap::new(),
+            max_attempts,
+        }
+    }
+
+    /// Add a work item to the queue.
+    pub fn push(&mut self, item: LeaderWorkItem) {
+        self.push_queued(QueuedLeaderWorkItem { item, attempts: 0 });
+    }
+
+    fn push_queued(&mut self, queued: QueuedLeaderWorkItem) {
+        self.items
+            .entry(queued.item.priority())
+            .or_default()
+            .push_back(queued);
+    }
+
+    /// Take the highest priority work item from the queue.
+    pub fn pop(&mut self) -> Option<QueuedLeaderWorkItem> {
+        let mut entry = self.items.last_entry()?;
+        let mut queued = entry.get_mut().pop_front()?;
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        queued.attempts += 1;
+        Some(queued)
+    }
+
+    /// Put a work item back on the queue after a failed attempt. If the item has already been
+    /// attempted the maximum number of times, then it is dropped and the return value is `false`.
+    pub fn retry(&mut self, queued: QueuedLeaderWorkItem) -> bool {
+        if queued.attempts >= self.max_attempts {
+            return false;
+        }
+        self.push_queued(queued);
+        true
+    }
+
+    /// Return the number of work items in the queue.
+    pub fn len(&self) -> usize {
+        self.items.values().map(VecDeque::len).sum()
+    }
+
+    /// Return `true` if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl Extend<LeaderWorkItem> for LeaderWorkQueue {
+    fn extend<I: IntoIterator<Item = LeaderWorkItem>>(&mut self, iter: I) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+/// Check that a collection job for the batch queried by the Collector would be accepted and
+/// return the selector for the batch. If the Collector queries the current batch of a fixed-size
+/// task, then the query is resolved to the ID of that batch.
+async fn check_collect_req<S: MaybeSendSync>(
+    leader: &impl DapLeader<S>,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    collect_req: &mut CollectionReq,
+    now: Time,
+) -> Result<BatchSelector, DapAbort> {
+    if collect_req.query == Query::FixedSizeCurrentBatch {
+        // This is where we assign the current batch, and convert the
+        // Query::FixedSizeCurrentBatch into a Query::FixedSizeByBatchId.
+        //
+        // TODO(bhalleycf) Note that currently we are just looking at the
+        // head of the uncollected batch queue, so there is no parallelism
+        // possible for collectors on a given task.  To allow multiple
+        // batches for a task to be collected concurrently for the same task,
+        // we'd need a more complex DO state that allowed us to have batch
+        // state go from unassigned -> in-progress -> complete.
+        let batch_id = leader.current_batch(task_id).await?;
+        debug!("FixedSize batch id is {batch_id}");
+        collect_req.query = Query::FixedSizeByBatchId { batch_id };
+    }
+
+    // Check that a fixed-size batch is in a collectable state. This precedes the batch checks
+    // below so that the Collector learns why the batch cannot be collected.
+    if let Query::FixedSizeByBatchId { ref batch_id } = collect_req.query {
+        if matches!(task_config.query, DapQueryConfig::FixedSize { .. }) {
+            check_batch_state(leader, task_id, batch_id, &collect_req.agg_param).await?;
+        }
+    }
+
+    // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
+    // collected batches.
+    let batch_selector = BatchSelector::try_from(collect_req.query.clone())?;
+    check_batch(
+        leader,
+        task_config,
+        task_id,
+        &batch_selector,
+        &collect_req.agg_param,
+        now,
+    )
+    .await?;
+
+    Ok(batch_selector)
+}
+
+/// Check that a fixed-size batch requested by the Collector can be collected, i.e., that it was
+/// produced by the Leader's batch queue and is ready to be collected.
+async fn check_batch_state<S: MaybeSendSync>(
+    leader: &impl DapLeader<S>,
+    task_id: &TaskId,
+    batch_id: &BatchId,
+    agg_param: &[u8],
+) -> Result<(), DapAbort> {
+    let batch_id_base64url = batch_id.to_base64url();
+    let detail = match leader.batch_state(task_id, batch_id).await? {
+        Some(DapBatchState::Ready) => return Ok(()),
+        Some(DapBatchState::Filling) => {
+            format!("The queried batch ({batch_id_base64url}) is still being filled.")
+        }
+        // The batch is not in the queue. Either it was removed after being collected, it was
+        // discarded, or it never existed.
+        None => {
+            let batch_sel = BatchSelector::FixedSizeByBatchId {
+                batch_id: batch_id.clone(),
+            };
+            let agg_param_hash = DapAggregationParamHash::new(agg_param);
+            if leader
+                .is_batch_overlapping(task_id, &batch_sel, &agg_param_hash)
+                .await?
+            {
+                return Err(DapAbort::BatchCollected {
+                    detail: format!(
+                        "The queried batch ({batch_id_base64url}) has already been collected."
+                    ),
+                    task_id: task_id.clone(),
+                });
+            }
+
+            if leader.batch_exists(task_id, batch_id).await? {
+                format!("The queried batch ({batch_id_base64url}) is no longer collectable.")
+            } else {
+                format!("The queried batch ({batch_id_base64url}) was not produced by the Leader.")
+            }
+        }
+    };
+
+    Err(DapAbort::BatchInvalid {
+        detail,
+        task_id: task_id.clone(),
+    })
+}
+
 /// DAP Leader functionality.
-#[async_trait(?Send)]
-pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
+pub trait DapLeader<S: MaybeSendSync>: DapAuthorizedSender<S> + DapAggregator<S> {
     /// Data type used to guide selection of a set of reports for aggregation.
-    type ReportSelector;
+    type ReportSelector: MaybeSendSync;
 
-    /// Store a report for use later on.
+    /// Store a report for use later on. If a report with the same ID is already pending, then the
+    /// report is not stored and [`TransitionFailure::ReportReplayed`] is returned.
     async fn put_report(&self, report: &Report, task_id: &TaskId) -> Result<(), DapError>;
 
-    /// Fetch a sequence of reports to aggregate, grouped by task ID, then by partial batch
-    /// selector. The reports returned are removed from persistent storage.
+    /// Store a batch of reports for the same task. The return value has one result for each
+    /// report, in the same order. By default, the reports are stored one at a time; the Leader
+    /// may override this to store them more efficiently.
+    async fn put_report_many(
+        &self,
+        reports: &[Report],
+        task_id: &TaskId,
+    ) -> Result<Vec<Result<(), DapError>>, DapError> {
+        let mut results = Vec::with_capacity(reports.len());
+        for report in reports {
+            results.push(self.put_report(report, task_id).await);
+        }
+        Ok(results)
+    }
+
+    /// Return the IDs of the given reports that were already uploaded for the task, either
+    /// because they are pending aggregation or because they have been aggregated. The reports are
+    /// checked together, so that the cost of the check is amortized over a batch of uploads; it is
+    /// not performed for single uploads, which rely on `put_report()` to detect duplicates.
+    async fn find_duplicate_reports(
+        &self,
+        task_id: &TaskId,
+        reports: &[&ReportMetadata],
+    ) -> Result<HashSet<ReportId>, DapError>;
+
+    /// Return `true` if the report is pending aggregation and the stored copy is identical to it.
+    /// This is used to tell a repeated upload of the same report apart from a different report
+    /// that reuses its ID.
+    async fn is_identical_report_pending(
+        &self,
+        report: &Report,
+        task_id: &TaskId,
+    ) -> Result<bool, DapError>;
+
+    /// Fetch a sequence of reports to aggregate, grouped by task ID and the DAP version in which
+    /// they were uploaded, then by partial batch selector. The reports returned are removed from
+    /// persistent storage.
     async fn get_reports(
         &self,
         selector: &Self::ReportSelector,
-    ) -> Result<HashMap<TaskId, HashMap<PartialBatchSelector, Vec<Report>>>, DapError>;
+    ) -> Result<HashMap<(TaskId, DapVersion), HashMap<PartialBatchSelector, Vec<Report>>>, DapError>;
+
+    /// Fetch at most `max_reports` of the reports pending aggregation for the given task, grouped
+    /// by the DAP version in which they were uploaded. Unlike `get_reports()`, the reports are not
+    /// removed from persistent storage.
+    async fn peek_reports(
+        &self,
+        task_id: &TaskId,
+        max_reports: u64,
+    ) -> Result<HashMap<DapVersion, Vec<Report>>, DapError>;
+
+    /// Look up the state of a fixed-size batch in the batch queue. The return value is `None` if
+    /// the batch is not in the queue, either because it was removed from the queue after being
+    /// collected or because the queue never produced it.
+    async fn batch_state(
+        &self,
+        task_id: &TaskId,
+        batch_id: &BatchId,
+    ) -> Result<Option<DapBatchState>, DapError>;
 
     /// Create a collect job.
     //
     // TODO spec: Figure out if the hostname for the collect URI needs to match the Leader.
+    ///
+    /// Pending collect jobs of a higher `priority` are processed first.
     async fn init_collect_job(
         &self,
         task_id: &TaskId,
         collect_job_id: &Option<CollectionJobId>,
         collect_req: &CollectionReq,
+        priority: DapCollectPriority,
     ) -> Result<Url, DapError>;
 
     /// Check the status of a collect job.
@@ -125,6 +727,13 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
         collect_id: &CollectionJobId,
     ) -> Result<DapCollectJob, DapError>;
 
+    /// List the collect jobs for the given task that are either pending or whose collection is
+    /// still retained by the Leader.
+    async fn list_collect_jobs(
+        &self,
+        task_id: &TaskId,
+    ) -> Result<Vec<DapCollectJobSummary>, DapError>;
+
     /// Fetch the current collect job queue. The result is the sequence of collect ID and request
     /// pairs, in order of priority.
     async fn get_pending_collect_jobs(
@@ -145,8 +754,19 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
     /// Send an HTTP PUT request.
     async fn send_http_put(&self, req: DapRequest<S>) -> Result<DapResponse, DapError>;
 
-    /// Handle a report from a Client.
-    async fn handle_upload_req(&self, req: &DapRequest<S>) -> Result<(), DapAbort> {
+    /// Send an HTTP DELETE request.
+    async fn send_http_delete(&self, req: DapRequest<S>) -> Result<DapResponse, DapError>;
+
+    /// Wait for the given amount of time. Used to back off before retrying a request to the
+    /// Helper.
+    async fn sleep(&self, duration: std::time::Duration);
+
+    /// Handle a report from a Client. If the task enables upload receipts, then the response
+    /// carries a receipt for the report signed by the Leader.
+    async fn handle_upload_req(
+        &self,
+        req: &DapRequest<S>,
+    ) -> Result<Option<DapResponse>, DapAbort> {
         let metrics = self.metrics().with_host(req.host());
         let task_id = req.task_id()?;
         debug!("upload for task {task_id}");
@@ -162,6 +782,24 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
         debug!("report id is {}", report.report_metadata.id);
 
+        // If the report ID is indicated by the request path, then it must match the report.
+        let report_id_in_path = if let DapResource::Report(ref report_id) = req.resource {
+            if !req.version.supports_report_id_in_upload_path() {
+                return Err(DapAbort::BadRequest(format!(
+                    "report ID in upload path is not supported by {}",
+                    req.version
+                )));
+            }
+            if report_id != &report.report_metadata.id {
+                return Err(DapAbort::BadRequest(
+                    "report ID in upload path does not match the report".into(),
+                ));
+            }
+            true
+        } else {
+            false
+        };
+
         if let Some(taskprov_version) = self.get_global_config().taskprov_version {
             resolve_taskprov(
                 self,
@@ -177,49 +815,209 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             .await?
             .ok_or(DapAbort::UnrecognizedTask)?;
 
-        // Check whether the DAP version in the request matches the task config.
-        if task_config.as_ref().version != req.version {
-            return Err(DapAbort::version_mismatch(
-                req.version,
-                task_config.as_ref().version,
-            ));
-        }
-
-        if report.encrypted_input_shares.len() != 2 {
-            // TODO spec: Decide if this behavior should be specified.
-            return Err(DapAbort::UnrecognizedMessage {
-                detail: format!(
-                    "expected exactly two encrypted input shares; got {}",
-                    report.encrypted_input_shares.len()
-                ),
-                task_id: Some(task_id.clone()),
-            });
-        }
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config.as_ref(), req.version)?;
+
+        check_report_for_upload(self, task_id, task_config.as_ref(), &report).await?;
+
+        let receipt_signing_key = if task_config.as_ref().upload_receipts {
+            Some(
+                self.get_global_config()
+                    .upload_receipt_signing_key
+                    .as_ref()
+                    .ok_or_else(|| {
+                        fatal_error!(
+                            err = "task enables upload receipts, but no signing key is configured",
+                            code = Config
+                        )
+                    })?,
+            )
+        } else {
+            None
+        };
 
-        // Check that the indicated HpkeConfig is present.
+        // Store the report for future processing, unless it is already pending. At this point,
+        // the report may also be rejected if the Leader detects a replay or that the report
+        // pertains to a batch that has already been collected.
         //
-        // TODO spec: It's not clear if this behavior is MUST, SHOULD, or MAY.
-        if !self
-            .can_hpke_decrypt(req.task_id()?, report.encrypted_input_shares[0].config_id)
-            .await?
-        {
-            return Err(DapAbort::ReportRejected {
-                detail: "No current HPKE configuration matches the indicated ID.".into(),
-            });
+        // Uploads that indicate the report ID in the path are idempotent: repeating the upload of
+        // a report that is still pending succeeds, as long as the report is identical to the one
+        // that was stored.
+        let res = match self.put_report(&report, task_id).await {
+            Err(DapError::Transition(TransitionFailure::ReportReplayed)) if report_id_in_path => {
+                if self.is_identical_report_pending(&report, task_id).await? {
+                    debug!(
+                        "report {} was already uploaded; treating upload as a retry",
+                        report.report_metadata.id
+                    );
+                    Ok(())
+                } else {
+                    Err(DapError::Transition(TransitionFailure::ReportReplayed))
+                }
+            }
+            res => res,
+        };
+        match res {
+            Err(DapError::Transition(TransitionFailure::ReportReplayed)) => {
+                metrics.report_inc_by("rejected_duplicate_at_upload", 1);
+                return Err(DapAbort::report_rejected(TransitionFailure::ReportReplayed));
+            }
+            Err(e) if e.code() == Some(DapErrorCode::Storage) => {
+                let Some(retry_after) = self.get_global_config().upload_overload_retry_after else {
+                    return Err(e.into());
+                };
+                warn!("failed to store report; asking the Client to retry in {retry_after}s: {e}");
+                return Err(DapAbort::TooManyRequests {
+                    detail: "The Leader is temporarily unable to accept reports.".into(),
+                    retry_after: Some(retry_after),
+                });
+            }
+            res => res?,
+        }
+
+        metrics.inbound_req_inc(DaphneRequestType::Upload);
+
+        let Some(receipt_signing_key) = receipt_signing_key else {
+            return Ok(None);
+        };
+        let receipt = receipt_signing_key.sign(
+            task_id,
+            &report.report_metadata.id,
+            self.get_current_time(),
+        )?;
+        Ok(Some(DapResponse {
+            version: req.version,
+            media_type: DapMediaType::UploadReceipt,
+            payload: receipt.get_encoded().into(),
+        }))
+    }
+
+    /// Handle a batch of reports from a Client. Each report is checked and stored independently:
+    /// the response indicates, for each report in the batch, whether it was accepted. Upload
+    /// receipts are not issued for reports uploaded this way.
+    async fn handle_upload_batch_req(&self, req: &DapRequest<S>) -> Result<DapResponse, DapAbort> {
+        let metrics = self.metrics().with_host(req.host());
+        let task_id = req.task_id()?;
+        debug!("batch upload for task {task_id}");
+
+        // Check whether the DAP version indicated by the sender is supported.
+        if req.version == DapVersion::Unknown {
+            return Err(DapAbort::version_unknown());
         }
 
-        // Check that the task has not expired.
-        if report.report_metadata.time >= task_config.as_ref().expiration {
-            return Err(DapAbort::ReportTooLate);
+        check_request_content_type(req, DapMediaType::ReportBatch)?;
+
+        let batch = ReportBatch::get_decoded(req.payload.as_ref())
+            .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
+        debug!("batch has {} reports", batch.encoded_reports.len());
+
+        if let Some(taskprov_version) = self.get_global_config().taskprov_version {
+            resolve_taskprov(self, task_id, req, None, taskprov_version).await?;
+        }
+        let task_config = self
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config.as_ref(), req.version)?;
+
+        // Check each report. Those that pass are stored together.
+        let mut results = Vec::with_capacity(batch.encoded_reports.len());
+        let mut reports = Vec::with_capacity(batch.encoded_reports.len());
+        for encoded_report in &batch.encoded_reports {
+            let report = match Report::get_decoded_with_param(&req.version, encoded_report) {
+                Ok(report) => report,
+                Err(e) => {
+                    results.push(Err(DapAbort::from_codec_error(e, task_id.clone())));
+                    continue;
+                }
+            };
+            match check_report_for_upload(self, task_id, task_config.as_ref(), &report).await {
+                Ok(()) => {
+                    results.push(Ok(()));
+                    reports.push(report);
+                }
+                Err(e) => results.push(Err(e)),
+            }
         }
 
-        // Store the report for future processing. At this point, the report may be rejected if
-        // the Leader detects that the report was replayed or pertains to a batch that has already
+        // Reject the reports that were already uploaded, including those that are repeated within
+        // the batch.
+        let duplicates = self
+            .find_duplicate_reports(
+                task_id,
+                &reports
+                    .iter()
+                    .map(|report| &report.report_metadata)
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+        let mut seen = HashSet::with_capacity(reports.len());
+        let mut num_duplicates = 0;
+        let mut fresh_reports = Vec::with_capacity(reports.len());
+        let mut reports = reports.into_iter();
+        for result in results.iter_mut().filter(|result| result.is_ok()) {
+            let Some(report) = reports.next() else {
+                return Err(fatal_error!(err = "too few reports passed the checks").into());
+            };
+            let report_id = report.report_metadata.id.clone();
+            if duplicates.contains(&report_id) || !seen.insert(report_id) {
+                num_duplicates += 1;
+                *result = Err(DapAbort::report_rejected(TransitionFailure::ReportReplayed));
+            } else {
+                fresh_reports.push(report);
+            }
+        }
+        // Store the reports for future processing. As for single uploads, reports may be
+        // rejected at this point if the Leader detects a replay or that the batch has already
         // been collected.
-        self.put_report(&report, req.task_id()?).await?;
+        let mut put_results = self
+            .put_report_many(&fresh_reports, task_id)
+            .await?
+            .into_iter();
+        let mut upload_results = Vec::with_capacity(results.len());
+        for result in results {
+            let result = match result {
+                Ok(()) => {
+                    let Some(put_result) = put_results.next() else {
+                        return Err(
+                            fatal_error!(err = "too few results from put_report_many()").into()
+                        );
+                    };
+                    put_result.map_err(|e| {
+                        if matches!(e, DapError::Transition(TransitionFailure::ReportReplayed)) {
+                            num_duplicates += 1;
+                        }
+                        DapAbort::from(e)
+                    })
+                }
+                Err(e) => Err(e),
+            };
+            upload_results.push(match result {
+                Ok(()) => ReportUploadResult::Accepted,
+                Err(e) => {
+                    debug!("rejected report in batch: {e}");
+                    ReportUploadResult::Rejected {
+                        reason: e.to_string(),
+                    }
+                }
+            });
+        }
 
+        if num_duplicates > 0 {
+            metrics.report_inc_by("rejected_duplicate_at_upload", num_duplicates);
+        }
         metrics.inbound_req_inc(DaphneRequestType::Upload);
-        Ok(())
+        Ok(DapResponse {
+            version: req.version,
+            media_type: DapMediaType::ReportBatchResp,
+            payload: ReportBatchResp {
+                results: upload_results,
+            }
+            .get_encoded()
+            .into(),
+        })
     }
 
     /// Handle a collect job from the Collector. The response is the URI that the Collector will
@@ -259,38 +1057,12 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             CollectionReq::get_decoded_with_param(&req.version, req.payload.as_ref())
                 .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
 
-        // Check whether the DAP version in the request matches the task config.
-        if task_config.version != req.version {
-            return Err(DapAbort::version_mismatch(req.version, task_config.version));
-        }
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config, req.version)?;
+        let task_config = task_config.as_ref();
 
-        if collect_req.query == Query::FixedSizeCurrentBatch {
-            // This is where we assign the current batch, and convert the
-            // Query::FixedSizeCurrentBatch into a Query::FixedSizeByBatchId.
-            //
-            // TODO(bhalleycf) Note that currently we are just looking at the
-            // head of the uncollected batch queue, so there is no parallelism
-            // possible for collectors on a given task.  To allow multiple
-            // batches for a task to be collected concurrently for the same task,
-            // we'd need a more complex DO state that allowed us to have batch
-            // state go from unassigned -> in-progress -> complete.
-            let batch_id = self.current_batch(task_id).await?;
-            debug!("FixedSize batch id is {batch_id}");
-            collect_req.query = Query::FixedSizeByBatchId { batch_id };
-        }
-
-        // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
-        // collected batches.
-        let batch_selector = BatchSelector::try_from(collect_req.query.clone())?;
-        check_batch(
-            self,
-            task_config,
-            task_id,
-            &batch_selector,
-            &collect_req.agg_param,
-            now,
-        )
-        .await?;
+        // Ensure the batch is valid and that it doesn't overlap with previously collected batches.
+        check_collect_req(self, task_id, task_config, &mut collect_req, now).await?;
 
         // draft02 compatibility: In draft02, the collection job ID is generated as a result of the
         // initial collection request, whereas in the latest draft, the collection job ID is parsed
@@ -306,14 +1078,134 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             _ => unreachable!("unhandled resource {:?}", req.resource),
         };
 
+        // The Collector may override the task's default priority for this job.
+        let priority = req.collect_priority.unwrap_or(task_config.collect_priority);
+
         let collect_job_uri = self
-            .init_collect_job(task_id, &collect_job_id, &collect_req)
+            .init_collect_job(task_id, &collect_job_id, &collect_req, priority)
             .await?;
 
         metrics.inbound_req_inc(DaphneRequestType::Collect);
         Ok(collect_job_uri)
     }
 
+    /// Handle a request from the Collector to check whether a collection job would be accepted
+    /// and whether its batch is ready to be collected, without creating the job. The request
+    /// carries the same `CollectionReq` as a request to create a collection job. Nothing is
+    /// written to storage; in particular, tasks are not configured by taskprov.
+    ///
+    /// The request is rejected if it could not be authorized or if it is malformed. Otherwise, the
+    /// reason for which the collection job would be rejected, if any, is part of the response.
+    async fn handle_collect_job_precheck_req(
+        &self,
+        req: &DapRequest<S>,
+    ) -> Result<DapCollectPrecheck, DapAbort> {
+        let now = self.get_current_time();
+        let task_id = req.task_id()?;
+        debug!("collect precheck for task {task_id}");
+
+        // Check whether the DAP version indicated by the sender is supported.
+        if req.version == DapVersion::Unknown {
+            return Err(DapAbort::version_unknown());
+        }
+
+        check_request_content_type(req, DapMediaType::CollectReq)?;
+
+        let wrapped_task_config = self
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+        let task_config = wrapped_task_config.as_ref();
+
+        if let Some(reason) = self.unauthorized_reason(task_config, req).await? {
+            error!("aborted unauthorized collect precheck request: {reason}");
+            return Err(DapAbort::UnauthorizedRequest {
+                detail: reason,
+                task_id: task_id.clone(),
+            });
+        }
+
+        let mut collect_req =
+            CollectionReq::get_decoded_with_param(&req.version, req.payload.as_ref())
+                .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
+
+        // Check whether the task is offered in the DAP version of the request.
+        let task_config = task_config_for_version(task_config, req.version)?;
+        let task_config = task_config.as_ref();
+
+        let rejected = |e: DapAbort| match e {
+            DapAbort::Internal(..) => Err(e),
+            e => Ok(DapCollectPrecheck {
+                rejection: Some(e.into_problem_details()),
+                ..Default::default()
+            }),
+        };
+
+        let batch_sel =
+            match check_collect_req(self, task_id, task_config, &mut collect_req, now).await {
+                Ok(batch_sel) => batch_sel,
+                Err(e) => return rejected(e),
+            };
+
+        let meta = self.get_agg_share_meta(task_id, &batch_sel).await?;
+        let (min_batch_size_met, collectable) = match task_config
+            .is_report_count_compatible(task_id, meta.report_count)
+            .and_then(|compatible| {
+                Ok((
+                    compatible,
+                    task_config.is_batch_meta_collectable(task_id, &batch_sel, &meta, now)?,
+                ))
+            }) {
+            Ok(res) => res,
+            Err(e) => return rejected(e),
+        };
+
+        Ok(DapCollectPrecheck {
+            rejection: None,
+            report_count: meta.report_count,
+            min_batch_size_met,
+            collectable,
+        })
+    }
+
+    /// Handle a request from the Collector to list its collect jobs for a task. This allows a
+    /// Collector that lost track of its collection job IDs to resume polling.
+    ///
+    /// The request has no payload, so its media type does not indicate the sender. The caller is
+    /// expected to set the media type to [`DapMediaType::CollectReq`] so that the request is
+    /// authorized as coming from the Collector.
+    async fn handle_list_collect_jobs_req(
+        &self,
+        req: &DapRequest<S>,
+    ) -> Result<Vec<DapCollectJobSummary>, DapAbort> {
+        let task_id = req.task_id()?;
+        debug!("list collect jobs for task {task_id}");
+
+        // Check whether the DAP version indicated by the sender is supported.
+        if req.version == DapVersion::Unknown {
+            return Err(DapAbort::version_unknown());
+        }
+
+        let wrapped_task_config = self
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+        let task_config = wrapped_task_config.as_ref();
+
+        if let Some(reason) = self.unauthorized_reason(task_config, req).await? {
+            error!("aborted unauthorized collect job listing request: {reason}");
+            return Err(DapAbort::UnauthorizedRequest {
+                detail: reason,
+                task_id: task_id.clone(),
+            });
+        }
+
+        // Check whether the task is offered in the DAP version of the request.
+        task_config_for_version(task_config, req.version)?;
+
+        Ok(self.list_collect_jobs(task_id).await?)
+    }
+
     /// Run an aggregation job for a set of reports. Return the number of reports that were
     /// aggregated successfully.
     //
@@ -331,8 +1223,13 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
     ) -> Result<u64, DapAbort> {
         let metrics = self.metrics().with_host(host);
 
+        // Refuse to prepare reports for a VDAF that is out of bounds.
+        self.get_global_config()
+            .check_vdaf_config(task_id, &task_config.vdaf)?;
+
         // Prepare AggregationJobInitReq.
         let agg_job_id = MetaAggregationJobId::gen_for_version(&task_config.version);
+        let (vdaf_verify_key_id, vdaf_verify_key) = task_config.active_vdaf_verify_key()?;
         let transition = task_config
             .vdaf
             .produce_agg_job_init_req(
@@ -340,116 +1237,176 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
                 self,
                 task_id,
                 task_config,
+                vdaf_verify_key,
                 &agg_job_id,
                 part_batch_sel,
                 reports,
                 &metrics,
             )
             .await?;
-        let (state, agg_job_init_req) = match transition {
+        let (state, mut agg_job_init_req) = match transition {
             DapLeaderTransition::Continue(state, agg_job_init_req) => (state, agg_job_init_req),
             DapLeaderTransition::Skip => return Ok(0),
             DapLeaderTransition::Uncommitted(..) => {
-                return Err(fatal_error!(err = "unexpected state transition (uncommitted)").into())
+                return Err(fatal_error!(
+                    err = "unexpected state transition (uncommitted)",
+                    code = StateCorruption
+                )
+                .into())
             }
         };
-        let method = if task_config.version != DapVersion::Draft02 {
-            LeaderHttpRequestMethod::Put
-        } else {
-            LeaderHttpRequestMethod::Post
-        };
-        let url_path = if task_config.version == DapVersion::Draft02 {
-            "aggregate".to_string()
-        } else {
-            format!(
-                "tasks/{}/aggregation_jobs/{}",
-                task_id.to_base64url(),
-                agg_job_id.to_base64url()
+        // Tell the Helper which verification key to use.
+        agg_job_init_req.extensions.extend(
+            vdaf_verify_key_id
+                .map(|key_id| AggregationJobInitExtension::VdafVerifyKeyId { key_id }),
+        );
+        let (method, url_path) = agg_job_init_req_target(task_id, task_config, &agg_job_id);
+
+        // Once the AggregationJobInitReq is sent, the Helper may have stored state for the job. If
+        // the job fails from then on, then ask the Helper to abandon it.
+        let res: Result<u64, DapAbort> = async {
+            // Send AggregationJobInitReq and receive AggregationJobResp.
+            let resp = leader_send_http_request(
+                self,
+                task_id,
+                task_config,
+                LeaderHttpRequestOptions {
+                    metrics: &metrics,
+                    path: &url_path,
+                    req_media_type: DapMediaType::AggregationJobInitReq,
+                    resp_media_type: DapMediaType::AggregationJobResp,
+                    resource: agg_job_id.for_request_path(),
+                    req_data: agg_job_init_req.get_encoded_with_param(&task_config.version),
+                    method,
+                },
             )
-        };
+            .await?;
+            let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes())
+                .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
 
-        // Send AggregationJobInitReq and receive AggregationJobResp.
-        let resp = leader_send_http_request(
-            self,
-            task_id,
-            task_config,
-            LeaderHttpRequestOptions {
-                path: &url_path,
-                req_media_type: DapMediaType::AggregationJobInitReq,
-                resp_media_type: DapMediaType::AggregationJobResp,
-                resource: agg_job_id.for_request_path(),
-                req_data: agg_job_init_req.get_encoded_with_param(&task_config.version),
-                method,
-            },
-        )
-        .await?;
-        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload)
-            .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
+            // Prepare AggreagteContinueReq.
+            let transition = task_config.vdaf.handle_agg_job_resp(
+                task_id,
+                &agg_job_id,
+                state,
+                agg_job_resp,
+                task_config.version,
+                &metrics,
+            )?;
+            let (uncommited, agg_job_cont_req) = match transition {
+                DapLeaderTransition::Uncommitted(uncommited, agg_job_cont_req) => {
+                    (uncommited, agg_job_cont_req)
+                }
+                DapLeaderTransition::Skip => return Ok(0),
+                DapLeaderTransition::Continue(..) => {
+                    return Err(fatal_error!(
+                        err = "unexpected state transition (continue)",
+                        code = StateCorruption
+                    )
+                    .into())
+                }
+            };
 
-        // Prepare AggreagteContinueReq.
-        let transition = task_config.vdaf.handle_agg_job_resp(
-            task_id,
-            &agg_job_id,
-            state,
-            agg_job_resp,
-            task_config.version,
-            &metrics,
-        )?;
-        let (uncommited, agg_job_cont_req) = match transition {
-            DapLeaderTransition::Uncommitted(uncommited, agg_job_cont_req) => {
-                (uncommited, agg_job_cont_req)
-            }
-            DapLeaderTransition::Skip => return Ok(0),
-            DapLeaderTransition::Continue(..) => {
-                return Err(fatal_error!(err = "unexpected state transition (continue)").into())
-            }
-        };
+            // Send AggregationJobContinueReq and receive AggregationJobResp.
+            let resp = leader_send_http_request(
+                self,
+                task_id,
+                task_config,
+                LeaderHttpRequestOptions {
+                    metrics: &metrics,
+                    path: &url_path,
+                    req_media_type: DapMediaType::AggregationJobContinueReq,
+                    resp_media_type: DapMediaType::agg_job_cont_resp_for_version(
+                        task_config.version,
+                    ),
+                    resource: agg_job_id.for_request_path(),
+                    req_data: agg_job_cont_req.get_encoded_with_param(&task_config.version),
+                    method: LeaderHttpRequestMethod::Post,
+                },
+            )
+            .await?;
+            let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes())
+                .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
 
-        // Send AggregationJobContinueReq and receive AggregationJobResp.
-        let resp = leader_send_http_request(
-            self,
-            task_id,
-            task_config,
-            LeaderHttpRequestOptions {
-                path: &url_path,
-                req_media_type: DapMediaType::AggregationJobContinueReq,
-                resp_media_type: DapMediaType::agg_job_cont_resp_for_version(task_config.version),
-                resource: agg_job_id.for_request_path(),
-                req_data: agg_job_cont_req.get_encoded_with_param(&task_config.version),
-                method: LeaderHttpRequestMethod::Post,
-            },
-        )
-        .await?;
-        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload)
-            .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
+            // Commit the output shares.
+            let agg_share_span = task_config.vdaf.handle_final_agg_job_resp(
+                task_config,
+                uncommited,
+                agg_job_resp,
+                &metrics,
+            )?;
+            let out_shares_count = agg_share_span.report_count() as u64;
 
-        // Commit the output shares.
-        let agg_share_span = task_config.vdaf.handle_final_agg_job_resp(
-            task_config,
-            uncommited,
-            agg_job_resp,
-            &metrics,
-        )?;
-        let out_shares_count = agg_share_span.report_count() as u64;
+            // At this point we're committed to aggregating the reports: if we do detect a report
+            // was replayed at this stage, then we may end up with a batch mismatch. However, this
+            // should only happen if there are multiple aggregation jobs in-flight that include the
+            // same report.
 
-        // At this point we're committed to aggregating the reports: if we do detect a report was
-        // replayed at this stage, then we may end up with a batch mismatch. However, this should
-        // only happen if there are multiple aggregation jobs in-flight that include the same
-        // report.
+            let replayed = self
+                .try_put_agg_share_span(task_id, task_config, agg_share_span)
+                .await?;
 
-        let replayed = self
-            .try_put_agg_share_span(task_id, task_config, agg_share_span)
-            .await?;
+            if let Some(replayed) = replayed {
+                tracing::warn!(
+                    replay_count = replayed.len(),
+                    "tried to aggregate replayed reports"
+                );
+            }
 
-        if let Some(replayed) = replayed {
-            tracing::warn!(
-                replay_count = replayed.len(),
-                "tried to aggregate replayed reports"
-            );
+            metrics.report_inc_by("aggregated", out_shares_count);
+            Ok(out_shares_count)
+        }
+        .await;
+
+        if res.is_err() {
+            abandon_agg_job(self, task_id, task_config, &metrics, &url_path, &agg_job_id).await;
         }
+        res
+    }
+
+    /// Run the aggregation flow for a sample of at most `max_reports` of the task's pending
+    /// reports without committing anything, and return how many of the reports would have been
+    /// accepted or rejected. This is useful for validating the configuration of a new task (e.g.,
+    /// its HPKE and VDAF verification keys) against live traffic.
+    ///
+    /// The reports are left pending. The Helper is sent the AggregationJobInitReq, but the job is
+    /// abandoned instead of continued, so the Helper does not commit anything either. Reports of
+    /// a fixed-size task are not assigned to a batch; they are prepared as if they belonged to a
+    /// fresh batch.
+    async fn dry_run_agg_job(
+        &self,
+        task_id: &TaskId,
+        max_reports: u64,
+        host: &str,
+    ) -> Result<DapAggregationDryRun, DapAbort> {
+        let task_config = self
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
 
-        metrics.report_inc_by("aggregated", out_shares_count);
-        Ok(out_shares_count)
+        let mut dry_run = DapAggregationDryRun::default();
+        for (version, reports) in self.peek_reports(task_id, max_reports).await? {
+            if reports.is_empty() {
+                continue;
+            }
+            let task_config = task_config_for_version(task_config.as_ref(), version)?;
+            let part_batch_sel = match task_config.query {
+                DapQueryConfig::TimeInterval => PartialBatchSelector::TimeInterval,
+                DapQueryConfig::FixedSize { .. } => PartialBatchSelector::FixedSizeByBatchId {
+                    batch_id: BatchId(thread_rng().gen()),
+                },
+            };
+            dry_run += dry_run_agg_job_for_reports(
+                self,
+                task_id,
+                task_config.as_ref(),
+                &part_batch_sel,
+                reports,
+                host,
+            )
+            .await?;
+        }
+        Ok(dry_run)
     }
 
     /// Handle a pending collection job. If the results are ready, then compute the aggregate
@@ -467,20 +1424,43 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
 
         debug!("collecting id {collect_id}");
         let batch_selector = BatchSelector::try_from(collect_req.query.clone())?;
+
+        // Check whether the batch may be ready before fetching the aggregate share, which is much
+        // larger than its metadata.
+        let leader_agg_share_meta = self.get_agg_share_meta(task_id, &batch_selector).await?;
+        if !task_config.is_batch_meta_collectable(
+            task_id,
+            &batch_selector,
+            &leader_agg_share_meta,
+            self.get_current_time(),
+        )? {
+            return Ok(0);
+        }
+
         let leader_agg_share = self.get_agg_share(task_id, &batch_selector).await?;
 
-        // Check the batch size. If not not ready, then return early.
+        // Check the batch size and the task's batch policy. If not not ready, then return early.
         //
         // TODO Consider logging this error, as it should never happen.
-        if !task_config.is_report_count_compatible(task_id, leader_agg_share.report_count)? {
+        if !task_config.is_batch_collectable(
+            task_id,
+            &batch_selector,
+            &leader_agg_share,
+            self.get_current_time(),
+        )? {
             return Ok(0);
         }
 
         let batch_selector = BatchSelector::try_from(collect_req.query.clone())?;
 
         // Prepare the Leader's aggregate share.
+        let collector_hpke_config = self
+            .get_collector_hpke_config_for(task_id, task_config)
+            .await?;
         let leader_enc_agg_share = task_config.vdaf.produce_leader_encrypted_agg_share(
-            &task_config.collector_hpke_config,
+            collector_hpke_config
+                .as_ref()
+                .unwrap_or(&task_config.collector_hpke_config),
             task_id,
             &batch_selector,
             &leader_agg_share,
@@ -508,6 +1488,7 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             task_id,
             task_config,
             LeaderHttpRequestOptions {
+                metrics: &metrics,
                 path: &url_path,
                 req_media_type: DapMediaType::AggregateShareReq,
                 resp_media_type: DapMediaType::AggregateShare,
@@ -517,13 +1498,19 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             },
         )
         .await?;
-        let agg_share_resp = AggregateShare::get_decoded(&resp.payload)
+        let agg_share_resp = AggregateShare::get_decoded(&resp.payload.to_bytes())
             .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
         // For draft07 and later, the Collection message includes the smallest quantized time
         // interval containing all reports in the batch.
-        let interval = match task_config.version {
-            DapVersion::Draft02 => None,
-            DapVersion::Draft07 => {
+        let interval = match (task_config.version, &batch_selector) {
+            (DapVersion::Draft02, _) => None,
+            // A batch with no reports has no report timestamps to derive the interval from.
+            (DapVersion::Draft07, BatchSelector::TimeInterval { batch_interval })
+                if leader_agg_share.report_count == 0 =>
+            {
+                Some(batch_interval.clone())
+            }
+            (DapVersion::Draft07, _) => {
                 let low = task_config.quantized_time_lower_bound(leader_agg_share.min_time);
                 let high = task_config.quantized_time_upper_bound(leader_agg_share.max_time);
                 Some(Interval {
@@ -550,13 +1537,139 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
             .await?;
 
         // Mark reports as collected.
-        self.mark_collected(task_id, &agg_share_req.batch_sel)
-            .await?;
+        self.mark_collected(
+            task_id,
+            &agg_share_req.batch_sel,
+            &DapAggregationParamHash::new(&agg_share_req.agg_param),
+        )
+        .await?;
 
         metrics.report_inc_by("collected", agg_share_req.report_count);
         Ok(agg_share_req.report_count)
     }
 
+    /// Fetch a set of reports and group them into aggregation jobs, one for each task and
+    /// partial batch selector.
+    async fn get_agg_work_items(
+        &self,
+        selector: &Self::ReportSelector,
+    ) -> Result<Vec<LeaderWorkItem>, DapAbort> {
+        tracing::debug!("RUNNING get_reports");
+        let mut items = Vec::new();
+        for ((task_id, version), reports) in self.get_reports(selector).await?.into_iter() {
+            for (part_batch_sel, reports) in reports.into_iter() {
+                // TODO Consider splitting reports into smaller chunks.
+                debug!(
+                    "process {} reports for task {task_id} ({version}) with selector {part_batch_sel:?}",
+                    reports.len()
+                );
+                if !reports.is_empty() {
+                    items.push(LeaderWorkItem::AggregateJob {
+                        task_id: task_id.clone(),
+                        version,
+                        part_batch_sel,
+                        reports,
+                    });
+                }
+            }
+        }
+        Ok(items)
+    }
+
+    /// Fetch the pending collection jobs, in order of priority.
+    async fn get_collect_work_items(&self) -> Result<Vec<LeaderWorkItem>, DapAbort> {
+        tracing::debug!("GETTING get_pending_collect_jobs");
+        Ok(self
+            .get_pending_collect_jobs()
+            .await?
+            .into_iter()
+            .map(
+                |(task_id, collect_id, collect_req)| LeaderWorkItem::CollectJob {
+                    task_id,
+                    collect_id,
+                    collect_req,
+                },
+            )
+            .collect())
+    }
+
+    /// Run a work item. The telemetry returned accounts for this item only.
+    async fn run_work_item(
+        &self,
+        item: &LeaderWorkItem,
+        host: &str,
+    ) -> Result<DapLeaderProcessTelemetry, DapAbort> {
+        let mut telem = DapLeaderProcessTelemetry::default();
+        let task_id = item.task_id();
+        tracing::debug!("GETTING get_task_config_for {task_id}");
+        let task_config = self
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+
+        match item {
+            LeaderWorkItem::AggregateJob {
+                version,
+                part_batch_sel,
+                reports,
+                ..
+            } => {
+                tracing::debug!(
+                    "RUNNING run_agg_job FOR TID {task_id} AND {part_batch_sel:?} AND {host}"
+                );
+                // The reports are aggregated in the version in which they were uploaded, as their
+                // input shares are bound to it.
+                let task_config = task_config_for_version(task_config.as_ref(), *version)?;
+                telem.reports_processed = reports.len() as u64;
+                telem.reports_aggregated = self
+                    .run_agg_job(
+                        task_id,
+                        task_config.as_ref(),
+                        part_batch_sel,
+                        reports.clone(),
+                        host,
+                    )
+                    .await?;
+            }
+            LeaderWorkItem::CollectJob {
+                collect_id,
+                collect_req,
+                ..
+            } => {
+                tracing::debug!("RUNNING run_collect_job FOR TID {task_id} AND {collect_id} AND {collect_req:?} AND {host}");
+                telem.reports_collected = self
+                    .run_collect_job(task_id, collect_id, task_config.as_ref(), collect_req, host)
+                    .await?;
+            }
+        }
+
+        Ok(telem)
+    }
+
+    /// Run the work items in the queue until it is empty. An item that fails is put back on the
+    /// queue to be retried later. If an item fails on its last attempt, then the error is
+    /// returned and the remaining items are left on the queue.
+    async fn run_work_queue(
+        &self,
+        queue: &mut LeaderWorkQueue,
+        host: &str,
+    ) -> Result<DapLeaderProcessTelemetry, DapAbort> {
+        let mut telem = DapLeaderProcessTelemetry::default();
+        while let Some(queued) = queue.pop() {
+            match self.run_work_item(&queued.item, host).await {
+                Ok(item_telem) => telem += item_telem,
+                Err(e) => {
+                    let attempts = queued.attempts;
+                    if !queue.retry(queued) {
+                        return Err(e);
+                    }
+                    warn!(error = ?e, attempts, "work item failed; will retry");
+                }
+            }
+        }
+        Ok(telem)
+    }
+
     /// Fetch a set of reports grouped by task, then run an aggregation job for each task. Once all
     /// jobs are completed, process the collect job queue. It is not safe to run multiple instances
     /// of this function in parallel.
@@ -570,64 +1683,17 @@ pub trait DapLeader<S>: DapAuthorizedSender<S> + DapAggregator<S> {
         selector: &Self::ReportSelector,
         host: &str,
     ) -> Result<DapLeaderProcessTelemetry, DapAbort> {
-        let mut telem = DapLeaderProcessTelemetry::default();
+        let mut queue = LeaderWorkQueue::new(1);
 
-        tracing::debug!("RUNNING get_reports");
         // Fetch reports and run an aggregation job for each task.
-        for (task_id, reports) in self.get_reports(selector).await?.into_iter() {
-            tracing::debug!("RUNNING get_task_config_for {task_id}");
-            let task_config = self
-                .get_task_config_for(Cow::Owned(task_id.clone()))
-                .await?
-                .ok_or(DapAbort::UnrecognizedTask)?;
+        queue.extend(self.get_agg_work_items(selector).await?);
+        let mut telem = self.run_work_queue(&mut queue, host).await?;
 
-            for (part_batch_sel, reports) in reports.into_iter() {
-                // TODO Consider splitting reports into smaller chunks.
-                // TODO Consider handling tasks in parallel.
-                telem.reports_processed += reports.len() as u64;
-                debug!(
-                    "process {} reports for task {task_id} with selector {part_batch_sel:?}",
-                    reports.len()
-                );
-                if !reports.is_empty() {
-                    tracing::debug!(
-                        "RUNNING run_agg_job FOR TID {task_id} AND {part_batch_sel:?} AND {host}"
-                    );
-                    telem.reports_aggregated += self
-                        .run_agg_job(
-                            &task_id,
-                            task_config.as_ref(),
-                            &part_batch_sel,
-                            reports,
-                            host,
-                        )
-                        .await?;
-                }
-            }
-        }
         // Process pending collect jobs. We wait until all aggregation jobs are finished before
-        // proceeding to this step. This is to prevent a race condition involving an aggregate
-        // share computed during a collect job and any output shares computed during an aggregation
-        // job.
-        tracing::debug!("GETTING get_pending_collect_jobs");
-        for (task_id, collect_id, collect_req) in self.get_pending_collect_jobs().await? {
-            tracing::debug!("GETTING get_task_config_for {task_id}");
-            let task_config = self
-                .get_task_config_for(Cow::Owned(task_id.clone()))
-                .await?
-                .ok_or(DapAbort::UnrecognizedTask)?;
-
-            tracing::debug!("RUNNING run_collect_job FOR TID {task_id} AND {collect_id} AND {collect_req:?} AND {host}");
-            telem.reports_collected += self
-                .run_collect_job(
-                    &task_id,
-                    &collect_id,
-                    task_config.as_ref(),
-                    &collect_req,
-                    host,
-                )
-                .await?;
-        }
+        // fetching them so that collect jobs created in the meantime also see the aggregated
+        // reports.
+        queue.extend(self.get_collect_work_items().await?);
+        telem += self.run_work_queue(&mut queue, host).await?;
 
         Ok(telem)
     }
@@ -8,19 +8,34 @@ mod helper;
 mod leader;
 
 use crate::{
+    batch_interval_end,
     constants::DapMediaType,
     messages::{BatchSelector, ReportMetadata, TaskId, Time, TransitionFailure},
     taskprov::{self, TaskprovVersion},
-    DapAbort, DapError, DapQueryConfig, DapRequest, DapTaskConfig,
+    DapAbort, DapAggregationParamHash, DapError, DapQueryConfig, DapRequest, DapTaskConfig,
+    DapVersion, MaybeSendSync,
 };
 use std::borrow::Cow;
 use tracing::warn;
 
 pub use aggregator::{DapAggregator, DapReportInitializer};
 pub use helper::DapHelper;
-pub use leader::{DapAuthorizedSender, DapLeader};
+pub use leader::{
+    DapAuthorizedSender, DapLeader, LeaderWorkItem, LeaderWorkQueue, QueuedLeaderWorkItem,
+};
+
+/// Return the view of the task for the DAP version of a request (see
+/// [`DapTaskConfig::for_version`]). Abort if the task is not offered in that version.
+fn task_config_for_version(
+    task_config: &DapTaskConfig,
+    version: DapVersion,
+) -> Result<Cow<'_, DapTaskConfig>, DapAbort> {
+    task_config
+        .for_version(version)
+        .ok_or_else(|| DapAbort::version_mismatch(version, task_config.version))
+}
 
-async fn check_batch<S>(
+async fn check_batch<S: MaybeSendSync>(
     agg: &impl DapAggregator<S>,
     task_config: &DapTaskConfig,
     task_id: &TaskId,
@@ -29,26 +44,19 @@ async fn check_batch<S>(
     now: Time,
 ) -> Result<(), DapAbort> {
     let global_config = agg.get_global_config();
-    let batch_overlapping = agg.is_batch_overlapping(task_id, batch_sel);
+    let agg_param_hash = DapAggregationParamHash::new(agg_param);
+    let batch_overlapping = agg.is_batch_overlapping(task_id, batch_sel, &agg_param_hash);
 
     // Check that the aggregation parameter is suitable for the given VDAF.
-    if !task_config.vdaf.is_valid_agg_param(agg_param) {
-        // TODO spec: Define this behavior.
-        return Err(DapAbort::UnrecognizedMessage {
-            detail: "invalid aggregation parameter".into(),
-            task_id: Some(task_id.clone()),
-        });
-    }
+    task_config.vdaf.check_agg_param(task_id, agg_param)?;
 
     // Check that the batch boundaries are valid.
     match (&task_config.query, batch_sel) {
         (DapQueryConfig::TimeInterval { .. }, BatchSelector::TimeInterval { batch_interval }) => {
-            if batch_interval.start % task_config.time_precision != 0
-                || batch_interval.duration % task_config.time_precision != 0
-                || batch_interval.duration < task_config.time_precision
-            {
+            let precision = task_config.batch_interval_precision();
+            if !batch_interval.is_aligned_to(precision) {
                 return Err(DapAbort::BatchInvalid {
-                    detail: format!("The queried batch interval ({batch_interval:?}) is too small or its boundaries are misaligned. The time precision for this task is {}s.", task_config.time_precision),
+                    detail: format!("The queried batch interval ({batch_interval:?}) is empty, overflows, or its boundaries are misaligned. The time precision for this task is {precision}s."),
                     task_id: task_id.clone(),
                 });
             }
@@ -63,7 +71,9 @@ async fn check_batch<S>(
                 ));
             }
 
-            if now.abs_diff(batch_interval.end()) > global_config.max_batch_interval_end {
+            if now.abs_diff(batch_interval_end(batch_interval)?)
+                > global_config.max_batch_interval_end
+            {
                 return Err(DapAbort::BadRequest(
                     "batch interval too far into future".to_string(),
                 ));
@@ -95,6 +105,19 @@ async fn check_batch<S>(
         }
     };
 
+    // Check that the batch is old enough to be collected. For fixed-size queries, this can only
+    // be checked once the aggregate share is known.
+    if let Some(earliest) = task_config.earliest_collect_time(batch_sel, None)? {
+        if now < earliest {
+            return Err(DapAbort::BatchInvalid {
+                detail: format!(
+                    "The queried batch cannot be collected before {earliest}, as required by the task's batch policy."
+                ),
+                task_id: task_id.clone(),
+            });
+        }
+    }
+
     // Check that the batch does not overlap with any previously collected batch.
     if batch_overlapping.await? {
         return Err(DapAbort::batch_overlap(task_id, batch_sel));
@@ -131,7 +154,7 @@ pub fn early_metadata_check(
     }
 }
 
-fn check_request_content_type<S>(
+fn check_request_content_type<S: MaybeSendSync>(
     req: &DapRequest<S>,
     expected: DapMediaType,
 ) -> Result<(), DapAbort> {
@@ -142,7 +165,7 @@ fn check_request_content_type<S>(
     }
 }
 
-async fn resolve_taskprov<S>(
+async fn resolve_taskprov<S: MaybeSendSync>(
     agg: &impl DapAggregator<S>,
     task_id: &TaskId,
     req: &DapRequest<S>,
@@ -168,20 +191,58 @@ async fn resolve_taskprov<S>(
         return Ok(());
     };
 
-    let Some(task_config) = taskprov::resolve_advertised_task_config(
+    // The peer may advertise the task in every request. If we already rejected this
+    // advertisement, then don't bother decoding it again.
+    let now = agg.get_current_time();
+    let rejection_cache = agg.taskprov_rejection_cache();
+    let digest = taskprov::advertisement_digest(
+        req,
+        taskprov_version,
+        task_id,
+        report_metadata_advertisement,
+    );
+    if let Some(abort) = digest.and_then(|digest| rejection_cache.get(&digest, task_id, now)) {
+        return Err(DapError::Abort(abort));
+    }
+
+    let resolved = taskprov::resolve_advertised_task_config(
         req,
         taskprov_version,
         vdaf_verify_key_init,
         collector_hpke_config,
         task_id,
         report_metadata_advertisement,
-    )?
-    else {
+    )
+    .and_then(|task_config| {
+        if let Some(ref task_config) = task_config {
+            agg.get_global_config()
+                .check_vdaf_config(task_id, &task_config.vdaf)?;
+        }
+        Ok(task_config)
+    });
+    let task_config = match resolved {
+        Ok(Some(task_config)) => task_config,
         // No task configuration advertised, so nothing to do.
-        return Ok(());
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            if let Some(digest) = digest {
+                rejection_cache.insert(digest, &e, now);
+            }
+            return Err(e);
+        }
     };
 
     // This is the opt-in / opt-out decision point.
+    if let Some(reason) = agg
+        .get_global_config()
+        .taskprov_policy
+        .opt_out_reason(&task_config, agg.get_current_time())
+    {
+        return Err(DapError::Abort(DapAbort::InvalidTask {
+            detail: format!("taskprov policy: {reason}"),
+            task_id: task_id.clone(),
+        }));
+    }
     if let Some(reason) = agg.taskprov_opt_out_reason(&task_config)? {
         return Err(DapError::Abort(DapAbort::InvalidTask {
             detail: reason,
@@ -195,39 +256,58 @@ async fn resolve_taskprov<S>(
 
 #[cfg(test)]
 mod test {
-    use super::{early_metadata_check, DapAggregator, DapAuthorizedSender, DapHelper, DapLeader};
+    use super::{
+        early_metadata_check,
+        helper::{check_batch_checksum, MAX_BATCH_MISMATCH_BUCKETS},
+        DapAggregator, DapAuthorizedSender, DapHelper, DapLeader, LeaderWorkItem, LeaderWorkQueue,
+    };
     use crate::{
         assert_metrics_include, assert_metrics_include_auxiliary_function, async_test_version,
         async_test_versions,
         auth::BearerToken,
         constants::DapMediaType,
-        hpke::{HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
+        hpke::{HpkeConfig, HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
         messages::{
-            taskprov, AggregateShareReq, AggregationJobContinueReq, AggregationJobInitReq,
-            AggregationJobResp, BatchId, BatchSelector, Collection, CollectionJobId, CollectionReq,
-            Extension, Interval, PartialBatchSelector, Query, Report, ReportId, ReportMetadata,
-            ReportShare, TaskId, Time, Transition, TransitionFailure, TransitionVar,
+            taskprov, AggregateShareReq, AggregationJobAbandonReq, AggregationJobContinueReq,
+            AggregationJobInitReq, AggregationJobResp, BatchId, BatchSelector, Collection,
+            CollectionJobId, CollectionReq, Extension, HpkeConfigList, Interval,
+            PartialBatchSelector, Query, Report, ReportBatch, ReportBatchResp, ReportId,
+            ReportMetadata, ReportShare, ReportUploadResult, TaskId, Time, Transition,
+            TransitionFailure, TransitionVar,
         },
+        receipt::{UploadReceipt, UploadReceiptSigningKey},
         taskprov::TaskprovVersion,
         test_versions,
-        testing::{AggStore, MockAggregator, MockAggregatorReportSelector},
-        vdaf::VdafVerifyKey,
-        DapAbort, DapAggregateShare, DapBatchBucket, DapCollectJob, DapGlobalConfig,
-        DapMeasurement, DapQueryConfig, DapRequest, DapResource, DapTaskConfig, DapVersion,
-        MetaAggregationJobId, Prio3Config, VdafConfig,
+        testing::{
+            AggStore, MockAggregator, MockAggregatorReportSelector, MockClock,
+            MOCK_HELPER_STATE_CLAIM_TIMEOUT_SECS,
+        },
+        vdaf::{VdafVerifyKey, VdafVerifyKeySet},
+        DapAbort, DapAggregateResult, DapAggregateShare, DapAggregateShareMeta,
+        DapAggregationParamHash, DapBatchBucket, DapCollectJob, DapCollectJobStatus,
+        DapCollectJobSummary, DapCollectPriority, DapGlobalConfig, DapLeaderTransition,
+        DapMeasurement, DapMemoryBudgetConfig, DapQueryConfig, DapRequest, DapResource,
+        DapTaskAltVersion, DapTaskConfig, DapTaskPurgeSummary, DapVersion, MetaAggregationJobId,
+        Prio3Config, VdafConfig,
     };
     use assert_matches::assert_matches;
     use matchit::Router;
-    use prio::codec::{Decode, ParameterizedEncode};
+    use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
     use rand::{thread_rng, Rng};
-    use std::{borrow::Cow, collections::HashMap, sync::Arc, time::SystemTime, vec};
+    use std::{
+        borrow::Cow,
+        collections::{BTreeMap, HashMap, HashSet},
+        sync::{atomic::Ordering, Arc},
+        time::SystemTime,
+        vec,
+    };
     use url::Url;
 
     macro_rules! get_reports {
         ($leader:expr, $selector:expr) => {{
             let reports_per_task = $leader.get_reports($selector).await.unwrap();
             assert_eq!(reports_per_task.len(), 1);
-            let (task_id, reports_per_part_batch_sel) =
+            let ((task_id, _version), reports_per_part_batch_sel) =
                 reports_per_task.into_iter().next().unwrap();
             assert_eq!(reports_per_part_batch_sel.len(), 1);
             let (part_batch_sel, reports) = reports_per_part_batch_sel.into_iter().next().unwrap();
@@ -237,6 +317,7 @@ mod test {
 
     pub(super) struct TestData {
         pub now: Time,
+        clock: MockClock,
         global_config: DapGlobalConfig,
         collector_token: BearerToken,
         taskprov_collector_token: BearerToken,
@@ -272,6 +353,16 @@ mod test {
                 max_batch_interval_end: 259200,
                 supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
                 taskprov_version: Some(TaskprovVersion::Draft02),
+                agg_job_init_req_compression_threshold: None,
+                helper_request_retry: Default::default(),
+                upload_receipt_signing_key: None,
+                agg_job_min_acceptance_ratio: None,
+                enabled_versions: None,
+                reject_unquantized_report_times: false,
+                max_prio2_dimension: None,
+                taskprov_policy: Default::default(),
+                upload_overload_retry_after: None,
+                agg_job_memory_budget: None,
             };
 
             // Task Parameters that the Leader and Helper must agree on.
@@ -300,6 +391,17 @@ mod test {
                     vdaf: vdaf_config.clone(),
                     vdaf_verify_key: VdafVerifyKey::Prio3(rng.gen()),
                     taskprov: false,
+                    batch_policy: Default::default(),
+                    upload_receipts: false,
+                    unknown_extensions: Default::default(),
+                    report_ttl: None,
+                    collect_priority: Default::default(),
+                    client_auth_key: None,
+                    vdaf_verify_key_set: None,
+                    alt_versions: Vec::new(),
+                    report_storage_epoch_duration: None,
+                    report_storage_epoch_migration: None,
+                    sub_bucket_precision: None,
                 },
             );
             tasks.insert(
@@ -312,10 +414,24 @@ mod test {
                     time_precision: Self::TASK_TIME_PRECISION,
                     expiration: now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 1,
-                    query: DapQueryConfig::FixedSize { max_batch_size: 2 },
+                    query: DapQueryConfig::FixedSize {
+                        max_batch_size: 2,
+                        batch_time_limit: None,
+                    },
                     vdaf: vdaf_config.clone(),
                     vdaf_verify_key: VdafVerifyKey::Prio3(rng.gen()),
                     taskprov: false,
+                    batch_policy: Default::default(),
+                    upload_receipts: false,
+                    unknown_extensions: Default::default(),
+                    report_ttl: None,
+                    collect_priority: Default::default(),
+                    client_auth_key: None,
+                    vdaf_verify_key_set: None,
+                    alt_versions: Vec::new(),
+                    report_storage_epoch_duration: None,
+                    report_storage_epoch_migration: None,
+                    sub_bucket_precision: None,
                 },
             );
             tasks.insert(
@@ -332,6 +448,17 @@ mod test {
                     vdaf: vdaf_config,
                     vdaf_verify_key: VdafVerifyKey::Prio3(rng.gen()),
                     taskprov: false,
+                    batch_policy: Default::default(),
+                    upload_receipts: false,
+                    unknown_extensions: Default::default(),
+                    report_ttl: None,
+                    collect_priority: Default::default(),
+                    client_auth_key: None,
+                    vdaf_verify_key_set: None,
+                    alt_versions: Vec::new(),
+                    report_storage_epoch_duration: None,
+                    report_storage_epoch_migration: None,
+                    sub_bucket_precision: None,
                 },
             );
 
@@ -348,6 +475,7 @@ mod test {
 
             Self {
                 now,
+                clock: MockClock::frozen_at(now),
                 global_config,
                 collector_token,
                 taskprov_collector_token,
@@ -387,47 +515,65 @@ mod test {
                     },
                     vdaf,
                     taskprov: false,
+                    batch_policy: Default::default(),
+                    upload_receipts: false,
+                    unknown_extensions: Default::default(),
+                    report_ttl: None,
+                    collect_priority: Default::default(),
+                    client_auth_key: None,
+                    vdaf_verify_key_set: None,
+                    alt_versions: Vec::new(),
+                    report_storage_epoch_duration: None,
+                    report_storage_epoch_migration: None,
+                    sub_bucket_precision: None,
                 },
             );
             task_id
         }
 
         pub fn new_helper(&self) -> Arc<MockAggregator> {
-            Arc::new(MockAggregator::new_helper(
-                self.tasks.clone(),
-                self.global_config
-                    .gen_hpke_receiver_config_list(thread_rng().gen())
-                    .collect::<Result<Vec<HpkeReceiverConfig>, _>>()
-                    .expect("failed to generate HPKE receiver config"),
-                self.global_config.clone(),
-                self.leader_token.clone(),
-                self.collector_hpke_receiver_config.config.clone(),
-                &self.prometheus_registry,
-                self.taskprov_vdaf_verify_key_init,
-                self.taskprov_leader_token.clone(),
-            ))
+            Arc::new(
+                MockAggregator::new_helper(
+                    self.tasks.clone(),
+                    self.global_config
+                        .gen_hpke_receiver_config_list(thread_rng().gen())
+                        .collect::<Result<Vec<HpkeReceiverConfig>, _>>()
+                        .expect("failed to generate HPKE receiver config"),
+                    self.global_config.clone(),
+                    self.leader_token.clone(),
+                    self.collector_hpke_receiver_config.config.clone(),
+                    &self.prometheus_registry,
+                    self.taskprov_vdaf_verify_key_init,
+                    self.taskprov_leader_token.clone(),
+                )
+                .with_clock(self.clock.clone()),
+            )
         }
 
         pub fn with_leader(self, helper: Arc<MockAggregator>) -> Test {
-            let leader = Arc::new(MockAggregator::new_leader(
-                self.tasks,
-                self.global_config
-                    .gen_hpke_receiver_config_list(thread_rng().gen())
-                    .collect::<Result<Vec<HpkeReceiverConfig>, _>>()
-                    .expect("failed to generate HPKE receiver config"),
-                self.global_config,
-                self.leader_token,
-                self.collector_token.clone(),
-                self.collector_hpke_receiver_config.config.clone(),
-                &self.prometheus_registry,
-                self.taskprov_vdaf_verify_key_init,
-                self.taskprov_leader_token,
-                self.taskprov_collector_token.clone(),
-                Arc::clone(&helper),
-            ));
+            let leader = Arc::new(
+                MockAggregator::new_leader(
+                    self.tasks,
+                    self.global_config
+                        .gen_hpke_receiver_config_list(thread_rng().gen())
+                        .collect::<Result<Vec<HpkeReceiverConfig>, _>>()
+                        .expect("failed to generate HPKE receiver config"),
+                    self.global_config,
+                    self.leader_token,
+                    self.collector_token.clone(),
+                    self.collector_hpke_receiver_config.config.clone(),
+                    &self.prometheus_registry,
+                    self.taskprov_vdaf_verify_key_init,
+                    self.taskprov_leader_token,
+                    self.taskprov_collector_token.clone(),
+                    Arc::clone(&helper),
+                )
+                .with_clock(self.clock.clone()),
+            );
 
             Test {
                 now: self.now,
+                clock: self.clock,
                 leader,
                 helper,
                 collector_token: self.collector_token,
@@ -443,6 +589,7 @@ mod test {
 
     pub(super) struct Test {
         now: Time,
+        clock: MockClock,
         leader: Arc<MockAggregator>,
         helper: Arc<MockAggregator>,
         collector_token: BearerToken,
@@ -507,6 +654,7 @@ mod test {
                     agg_param: Vec::default(),
                     part_batch_sel,
                     report_shares,
+                    extensions: Vec::new(),
                 },
                 task_config.helper_url.join("aggregate").unwrap(),
             )
@@ -641,7 +789,11 @@ mod test {
             Ok(())
         }
 
-        pub async fn run_col_job(&self, task_id: &TaskId, query: &Query) -> Result<(), DapAbort> {
+        pub async fn run_col_job(
+            &self,
+            task_id: &TaskId,
+            query: &Query,
+        ) -> Result<CollectionJobId, DapAbort> {
             let wrapped = self
                 .leader
                 .get_task_config_for(Cow::Owned(task_id.clone()))
@@ -680,7 +832,7 @@ mod test {
                     task_config.leader_url.host_str().unwrap(),
                 )
                 .await?;
-            Ok(())
+            Ok(collect_id.clone())
         }
 
         pub async fn leader_authorized_req<M: ParameterizedEncode<DapVersion>>(
@@ -767,6 +919,7 @@ mod test {
                         batch_id: BatchId(rng.gen()),
                     },
                     report_shares: Vec::default(),
+                    extensions: Vec::new(),
                 },
                 task_config.helper_url.join("aggregate").unwrap(),
             )
@@ -821,7 +974,7 @@ mod test {
             .await;
 
         let resp = t.helper.handle_agg_job_req(&req).await.unwrap();
-        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes()).unwrap();
         assert_eq!(agg_job_resp.transitions.len(), 1);
         assert_matches!(
             agg_job_resp.transitions[0].var,
@@ -833,6 +986,101 @@ mod test {
 
     async_test_versions! { handle_agg_job_req_init_expired_task }
 
+    // Test that the Helper aborts the aggregation job if too many report shares are rejected.
+    async fn handle_agg_job_req_init_min_acceptance_ratio(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_min_acceptance_ratio = Some(0.6);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        let report_shares = vec![
+            t.gen_test_report(&t.time_interval_task_id).await,
+            t.gen_test_report(&t.time_interval_task_id).await,
+        ]
+        .into_iter()
+        .map(|report| ReportShare {
+            report_metadata: report.report_metadata,
+            public_share: report.public_share,
+            encrypted_input_share: report.encrypted_input_shares[1].clone(),
+        })
+        .enumerate()
+        .map(|(i, mut report_share)| {
+            if i == 0 {
+                // Corrupt the first report share so that it is rejected.
+                report_share.encrypted_input_share.payload[0] ^= 1;
+            }
+            report_share
+        })
+        .collect();
+        let req = t
+            .gen_test_agg_job_init_req(&t.time_interval_task_id, version, report_shares)
+            .await;
+
+        assert_matches!(
+            t.helper.handle_agg_job_req(&req).await,
+            Err(DapAbort::AggregationJobRejected { .. })
+        );
+
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_helper_aggregation_job_counter{host="helper.org",status="rejected"}"#: 1,
+        });
+
+        // None of the reports are counted, since the whole job was aborted.
+        use prometheus::Encoder;
+        let mut got = Vec::new();
+        prometheus::TextEncoder::new()
+            .encode(&t.prometheus_registry.gather(), &mut got)
+            .unwrap();
+        assert!(!String::from_utf8(got)
+            .unwrap()
+            .contains("status=\"rejected_"));
+    }
+
+    async_test_versions! { handle_agg_job_req_init_min_acceptance_ratio }
+
+    // Test that the Helper sheds an aggregation job that would exceed its memory budget and
+    // accepts it once the memory is released.
+    async fn handle_agg_job_req_init_memory_budget(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_memory_budget = Some(DapMemoryBudgetConfig {
+            limit_bytes: 1 << 20,
+            retry_after: 10,
+        });
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        let report = t.gen_test_report(&t.time_interval_task_id).await;
+        let report_share = ReportShare {
+            report_metadata: report.report_metadata,
+            public_share: report.public_share,
+            encrypted_input_share: report.encrypted_input_shares[1].clone(),
+        };
+        let req = t
+            .gen_test_agg_job_init_req(&t.time_interval_task_id, version, vec![report_share])
+            .await;
+
+        // Another job in flight holds all of the budget.
+        let in_flight = t
+            .helper
+            .agg_job_memory_budget
+            .try_reserve(1 << 20, 1 << 20)
+            .unwrap();
+        let abort = t.helper.handle_agg_job_req(&req).await.unwrap_err();
+        assert_matches!(abort, DapAbort::TooManyRequests { .. });
+        assert_eq!(abort.retry_after(), Some(10));
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_helper_aggregation_job_counter{host="helper.org",status="shed"}"#: 1,
+        });
+
+        // The job is accepted once the other job finishes, after which its reservation is
+        // released.
+        drop(in_flight);
+        t.helper.handle_agg_job_req(&req).await.unwrap();
+        assert_eq!(t.helper.agg_job_memory_budget.in_use(), 0);
+    }
+
+    async_test_versions! { handle_agg_job_req_init_memory_budget }
+
     // Test that the Helper rejects reports with a bad round number.
     async fn handle_agg_job_req_bad_round(version: DapVersion) {
         let t = Test::new(version);
@@ -859,7 +1107,7 @@ mod test {
         // AggregationJobInitReq succeeds
         assert_eq!(t.helper.audit_log.invocations(), 1);
 
-        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes()).unwrap();
         assert_eq!(agg_job_resp.transitions.len(), 1);
         assert_matches!(agg_job_resp.transitions[0].var, TransitionVar::Continued(_));
         // Test wrong round
@@ -907,7 +1155,7 @@ mod test {
         // AggregationJobInitReq succeeds
         assert_eq!(t.helper.audit_log.invocations(), 1);
 
-        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes()).unwrap();
         assert_eq!(agg_job_resp.transitions.len(), 1);
         assert_matches!(agg_job_resp.transitions[0].var, TransitionVar::Continued(_));
         // Test wrong round
@@ -929,6 +1177,149 @@ mod test {
 
     async_test_versions! { handle_agg_job_req_zero_round }
 
+    // Test that the Helper recovers from round skew by replaying its response when the Leader
+    // retransmits an AggregationJobContinueReq that has already been processed, and that the
+    // Helper's state is not lost if a previous attempt to process the request was dropped.
+    async fn handle_agg_job_req_cont_retransmitted(version: DapVersion) {
+        let t = Test::new(version);
+        if version == DapVersion::Draft02 {
+            // Nothing to test.
+            return;
+        }
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let metrics = t.leader.metrics().with_host("leader.com");
+        let agg_job_id = MetaAggregationJobId::gen_for_version(&version);
+
+        // Leader->Helper: AggregationJobInitReq
+        let report = t.gen_test_report(task_id).await;
+        let DapLeaderTransition::Continue(leader_state, agg_job_init_req) = task_config
+            .vdaf
+            .produce_agg_job_init_req(
+                &*t.leader,
+                &*t.leader,
+                task_id,
+                &task_config,
+                &task_config.vdaf_verify_key,
+                &agg_job_id,
+                &PartialBatchSelector::TimeInterval,
+                vec![report],
+                &metrics,
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("unexpected transition");
+        };
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                Some(&agg_job_id),
+                DapMediaType::AggregationJobInitReq,
+                agg_job_init_req,
+                task_config.helper_url.join("aggregate").unwrap(),
+            )
+            .await;
+        let init_req = req;
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &t.helper
+                .handle_agg_job_req(&init_req)
+                .await
+                .unwrap()
+                .payload
+                .to_bytes(),
+        )
+        .unwrap();
+
+        // A previous attempt to process the AggregationJobContinueReq claimed the state, then was
+        // dropped before it could finish.
+        assert!(t
+            .helper
+            .claim_helper_state(task_id, &agg_job_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // Leader->Helper: AggregationJobContinueReq
+        let DapLeaderTransition::Uncommitted(_, agg_job_cont_req) = task_config
+            .vdaf
+            .handle_agg_job_resp(
+                task_id,
+                &agg_job_id,
+                leader_state,
+                agg_job_resp,
+                version,
+                &metrics,
+            )
+            .unwrap()
+        else {
+            panic!("unexpected transition");
+        };
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                Some(&agg_job_id),
+                DapMediaType::AggregationJobContinueReq,
+                agg_job_cont_req,
+                task_config.helper_url.join("aggregate").unwrap(),
+            )
+            .await;
+        // The state can't be claimed again until the claim expires. The Leader is asked to retry
+        // the request once it does.
+        let abort = t.helper.handle_agg_job_req(&req).await.unwrap_err();
+        assert_matches!(abort, DapAbort::TooManyRequests { .. });
+        assert_eq!(
+            abort.retry_after(),
+            Some(MOCK_HELPER_STATE_CLAIM_TIMEOUT_SECS)
+        );
+        t.clock.advance(MOCK_HELPER_STATE_CLAIM_TIMEOUT_SECS);
+        let resp = t.helper.handle_agg_job_req(&req).await.unwrap();
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload.to_bytes()).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(agg_job_resp.transitions[0].var, TransitionVar::Finished);
+        assert_eq!(t.helper.audit_log.invocations(), 2);
+
+        // The response is lost and the Leader retransmits the request. The Helper replays its
+        // response rather than rejecting the report as replayed.
+        let replayed_resp = t.helper.handle_agg_job_req(&req).await.unwrap();
+        assert_eq!(replayed_resp.payload.to_bytes(), resp.payload.to_bytes());
+
+        // The reports are only aggregated once.
+        assert_eq!(t.helper.audit_log.invocations(), 2);
+
+        // A request for the same round that differs from the one that completed it is rejected.
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                Some(&agg_job_id),
+                DapMediaType::AggregationJobContinueReq,
+                AggregationJobContinueReq {
+                    draft02_task_id: None,
+                    draft02_agg_job_id: None,
+                    round: Some(1),
+                    transitions: Vec::new(),
+                },
+                task_config.helper_url.join("aggregate").unwrap(),
+            )
+            .await;
+        assert_matches!(
+            t.helper.handle_agg_job_req(&req).await,
+            Err(DapAbort::RoundMismatch { .. })
+        );
+
+        // The state was deleted once it was consumed, but it can't be stored again.
+        assert!(t.helper.helper_state_store.lock().unwrap().is_empty());
+        assert_matches!(
+            t.helper.handle_agg_job_req(&init_req).await,
+            Err(DapAbort::BadRequest(..))
+        );
+    }
+
+    async_test_versions! { handle_agg_job_req_cont_retransmitted }
+
     async fn handle_hpke_config_req_unrecognized_task(version: DapVersion) {
         let t = Test::new(version);
         let mut rng = thread_rng();
@@ -978,6 +1369,56 @@ mod test {
 
     async_test_versions! { handle_hpke_config_req_missing_task_id }
 
+    async fn handle_hpke_config_req_multiple_configs(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config
+            .supported_hpke_kems
+            .push(HpkeKemId::P256HkdfSha256);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::HpkeConfigList,
+            payload: Vec::new(),
+            task_id: Some(task_id.clone()),
+            resource: DapResource::Undefined,
+            url: Url::parse(&format!(
+                "http://aggregator.biz/{}/hpke_config?task_id={}",
+                version.as_ref(),
+                task_id.to_base64url()
+            ))
+            .unwrap(),
+            ..Default::default()
+        };
+
+        let resp = t.leader.handle_hpke_config_req(&req).await.unwrap();
+        let want = t
+            .leader
+            .hpke_receiver_config_list
+            .iter()
+            .map(|receiver| receiver.config.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(want.len(), 2);
+        match version {
+            // draft02 compatibility: Only the first config is advertised.
+            DapVersion::Draft02 => {
+                assert_eq!(
+                    HpkeConfig::get_decoded(&resp.payload.to_bytes()).unwrap(),
+                    want[0]
+                );
+            }
+            DapVersion::Draft07 => {
+                let hpke_config_list =
+                    HpkeConfigList::get_decoded(&resp.payload.to_bytes()).unwrap();
+                assert_eq!(hpke_config_list.hpke_configs, want);
+            }
+            _ => unreachable!("unhandled version {version:?}"),
+        }
+    }
+
+    async_test_versions! { handle_hpke_config_req_multiple_configs }
+
     async fn handle_agg_job_req_cont_unauthorized_request(version: DapVersion) {
         let t = Test::new(version);
         let agg_job_id = MetaAggregationJobId::gen_for_version(&version);
@@ -1089,6 +1530,141 @@ mod test {
 
     async_test_versions! { handle_agg_share_req_invalid_batch_sel }
 
+    // Test that the Helper rejects an aggregation parameter the task's VDAF does not take.
+    async fn handle_agg_share_req_invalid_agg_param(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                None,
+                DapMediaType::AggregateShareReq,
+                AggregateShareReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    batch_sel: BatchSelector::try_from(
+                        task_config.query_for_current_batch_window(t.now),
+                    )
+                    .unwrap(),
+                    agg_param: b"poplar1 level".to_vec(),
+                    report_count: 0,
+                    checksum: [0; 32],
+                },
+                task_config.helper_url.join("aggregate_share").unwrap(),
+            )
+            .await;
+        assert_matches!(
+            t.helper.handle_agg_share_req(&req).await.unwrap_err(),
+            DapAbort::InvalidAggregationParameter { .. }
+        );
+    }
+
+    async_test_versions! { handle_agg_share_req_invalid_agg_param }
+
+    // Test that the Helper identifies its buckets when the Leader's checksum doesn't match.
+    async fn handle_agg_share_req_batch_mismatch(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let batch_sel =
+            BatchSelector::try_from(task_config.query_for_current_batch_window(t.now)).unwrap();
+
+        for _ in 0..2 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            t.leader.handle_upload_req(&req).await.unwrap();
+            t.run_agg_job(task_id).await.unwrap();
+        }
+
+        let helper_meta = t
+            .helper
+            .get_agg_share_meta(task_id, &batch_sel)
+            .await
+            .unwrap();
+        assert_eq!(helper_meta.report_count, 2);
+        assert_eq!(
+            helper_meta.checksum,
+            t.leader
+                .get_agg_share(task_id, &batch_sel)
+                .await
+                .unwrap()
+                .checksum
+        );
+
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                None,
+                DapMediaType::AggregateShareReq,
+                AggregateShareReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    batch_sel: batch_sel.clone(),
+                    agg_param: Vec::default(),
+                    report_count: 2,
+                    checksum: [1; 32],
+                },
+                task_config.helper_url.join("aggregate_share").unwrap(),
+            )
+            .await;
+        let bucket = task_config
+            .batch_span_for_sel(&batch_sel)
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap();
+        assert_matches!(
+            t.helper.handle_agg_share_req(&req).await.unwrap_err(),
+            DapAbort::BatchMismatch { detail, .. } => {
+                assert!(detail.contains(&format!(
+                    "{bucket} (2, {})",
+                    hex::encode(helper_meta.checksum)
+                )));
+            }
+        );
+    }
+
+    async_test_versions! { handle_agg_share_req_batch_mismatch }
+
+    // Test that the buckets listed in a batch mismatch are capped.
+    #[test]
+    fn check_batch_checksum_caps_buckets() {
+        let task_id = TaskId([1; 32]);
+        let bucket_metas = (0..MAX_BATCH_MISMATCH_BUCKETS as u64 + 3)
+            .map(|i| {
+                let bucket = DapBatchBucket::TimeInterval {
+                    batch_window: i * 3600,
+                };
+                let meta = DapAggregateShareMeta {
+                    report_count: 1,
+                    ..Default::default()
+                };
+                (bucket, meta)
+            })
+            .collect::<Vec<_>>();
+        let agg_share_req = AggregateShareReq {
+            draft02_task_id: None,
+            batch_sel: BatchSelector::TimeInterval {
+                batch_interval: Interval {
+                    start: 0,
+                    duration: 3600,
+                },
+            },
+            agg_param: Vec::default(),
+            report_count: 0,
+            checksum: [0; 32],
+        };
+
+        assert_matches!(
+            check_batch_checksum(&task_id, &agg_share_req, &bucket_metas).unwrap_err(),
+            DapAbort::BatchMismatch { detail, .. } => {
+                assert_eq!(detail.matches(" (1, ").count(), MAX_BATCH_MISMATCH_BUCKETS);
+                assert!(detail.ends_with(", and 3 more]."));
+            }
+        );
+    }
+
     async fn handle_collect_job_req_unauthorized_request(version: DapVersion) {
         let mut rng = thread_rng();
         let t = Test::new(version);
@@ -1161,7 +1737,12 @@ mod test {
 
         // Get AggregationJobResp and then extract the transition data from inside.
         let agg_job_resp = AggregationJobResp::get_decoded(
-            &t.helper.handle_agg_job_req(&req).await.unwrap().payload,
+            &t.helper
+                .handle_agg_job_req(&req)
+                .await
+                .unwrap()
+                .payload
+                .to_bytes(),
         )
         .unwrap();
         let transition = &agg_job_resp.transitions[0];
@@ -1192,7 +1773,12 @@ mod test {
 
         // Get AggregationJobResp and then extract the transition data from inside.
         let agg_job_resp = AggregationJobResp::get_decoded(
-            &t.helper.handle_agg_job_req(&req).await.unwrap().payload,
+            &t.helper
+                .handle_agg_job_req(&req)
+                .await
+                .unwrap()
+                .payload
+                .to_bytes(),
         )
         .unwrap();
         let transition = &agg_job_resp.transitions[0];
@@ -1234,7 +1820,12 @@ mod test {
 
         // Get AggregationJobResp and then extract the transition data from inside.
         let agg_job_resp = AggregationJobResp::get_decoded(
-            &t.helper.handle_agg_job_req(&req).await.unwrap().payload,
+            &t.helper
+                .handle_agg_job_req(&req)
+                .await
+                .unwrap()
+                .payload
+                .to_bytes(),
         )
         .unwrap();
         let transition = &agg_job_resp.transitions[0];
@@ -1287,13 +1878,19 @@ mod test {
                 AggStore {
                     agg_share: DapAggregateShare::default(),
                     collected: true,
+                    collected_agg_params: HashSet::default(),
                 },
             );
         }
 
         // Get AggregationJobResp and then extract the transition data from inside.
         let agg_job_resp = AggregationJobResp::get_decoded(
-            &t.helper.handle_agg_job_req(&req).await.unwrap().payload,
+            &t.helper
+                .handle_agg_job_req(&req)
+                .await
+                .unwrap()
+                .payload
+                .to_bytes(),
         )
         .unwrap();
         let transition = &agg_job_resp.transitions[0];
@@ -1366,18 +1963,189 @@ mod test {
 
     async_test_versions! { handle_agg_job_req_fail_send_cont_req }
 
-    async fn handle_upload_req_fail_send_invalid_report(version: DapVersion) {
+    async fn handle_agg_job_abandon_req(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
-        let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
-        // Construct a report payload with an invalid task ID.
-        let report_invalid_task_id = t.gen_test_report(task_id).await;
-        let req = DapRequest {
-            version: task_config.version,
-            media_type: DapMediaType::Report,
-            task_id: Some(TaskId([0; 32])),
-            resource: DapResource::Undefined,
+        let report = t.gen_test_report(task_id).await;
+        let report_shares = vec![ReportShare {
+            report_metadata: report.report_metadata.clone(),
+            public_share: report.public_share,
+            encrypted_input_share: report.encrypted_input_shares[1].clone(),
+        }];
+        let init_req = t
+            .gen_test_agg_job_init_req(task_id, version, report_shares)
+            .await;
+        t.helper.handle_agg_job_req(&init_req).await.unwrap();
+        assert_eq!(t.helper.helper_state_store.lock().unwrap().len(), 1);
+
+        // draft02: The aggregation job ID is carried in the payload.
+        let draft02_agg_job_id =
+            AggregationJobInitReq::get_decoded_with_param(&version, &init_req.payload)
+                .unwrap()
+                .draft02_agg_job_id;
+        let payload = AggregationJobAbandonReq {
+            draft02_task_id: task_id.for_request_payload(&version),
+            draft02_agg_job_id,
+        }
+        .get_encoded_with_param(&version);
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::AggregationJobAbandon,
+            task_id: init_req.task_id.clone(),
+            resource: init_req.resource.clone(),
+            payload,
+            url: init_req.url.clone(),
+            sender_auth: init_req.sender_auth.clone(),
+            ..Default::default()
+        };
+
+        // The Helper's state for the aggregation job is deleted.
+        let resp = t.helper.handle_agg_job_req(&req).await.unwrap();
+        assert_eq!(resp.media_type, DapMediaType::AggregationJobAbandon);
+        assert!(t.helper.helper_state_store.lock().unwrap().is_empty());
+
+        // Abandoning the aggregation job again is a no-op.
+        t.helper.handle_agg_job_req(&req).await.unwrap();
+
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_helper_aggregation_job_counter{host="helper.org",status="abandoned"}"#: 2,
+        });
+    }
+
+    async_test_versions! { handle_agg_job_abandon_req }
+
+    async fn handle_agg_job_delete_req(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let report_shares = vec![ReportShare {
+            report_metadata: report.report_metadata.clone(),
+            public_share: report.public_share,
+            encrypted_input_share: report.encrypted_input_shares[1].clone(),
+        }];
+        let init_req = t
+            .gen_test_agg_job_init_req(task_id, version, report_shares)
+            .await;
+        t.helper.handle_agg_job_req(&init_req).await.unwrap();
+
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::Missing,
+            task_id: init_req.task_id.clone(),
+            resource: init_req.resource.clone(),
+            payload: Vec::new(),
+            url: init_req.url.clone(),
+            sender_auth: init_req.sender_auth.clone(),
+            ..Default::default()
+        };
+        if version == DapVersion::Draft02 {
+            assert_matches!(
+                t.helper.handle_agg_job_delete_req(&req).await,
+                Err(DapAbort::BadRequest(..))
+            );
+            return;
+        }
+
+        // The Helper's state for the aggregation job is deleted.
+        let resp = t.helper.handle_agg_job_delete_req(&req).await.unwrap();
+        assert_eq!(resp.media_type, DapMediaType::Missing);
+        assert!(t.helper.helper_state_store.lock().unwrap().is_empty());
+
+        // Deleting the aggregation job again is a no-op.
+        t.helper.handle_agg_job_delete_req(&req).await.unwrap();
+
+        // The aggregation job can't be initialized again.
+        assert_matches!(
+            t.helper.handle_agg_job_req(&init_req).await,
+            Err(DapAbort::BadRequest(..))
+        );
+        assert!(t.helper.helper_state_store.lock().unwrap().is_empty());
+    }
+
+    async_test_versions! { handle_agg_job_delete_req }
+
+    async fn leader_abandons_failed_agg_job(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // Every attempt to send the AggregationJobInitReq fails, but the request to abandon the
+        // aggregation job reaches the Helper.
+        let attempts = if version == DapVersion::Draft02 {
+            1
+        } else {
+            t.leader
+                .get_global_config()
+                .helper_request_retry
+                .max_attempts
+        };
+        t.leader
+            .peer_transient_failures
+            .store(attempts, Ordering::Relaxed);
+        assert_matches!(t.run_agg_job(task_id).await, Err(DapAbort::Internal(..)));
+
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_aggregation_job_counter{host="leader.com",status="abandoned"}"#: 1,
+            r#"test_helper_aggregation_job_counter{host="helper.org",status="abandoned"}"#: 1,
+        });
+    }
+
+    async_test_versions! { leader_abandons_failed_agg_job }
+
+    async fn leader_fails_to_abandon_agg_job(version: DapVersion) {
+        use prometheus::{Encoder, TextEncoder};
+
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // Every attempt to send the AggregationJobInitReq fails, and so does every attempt to
+        // abandon the aggregation job.
+        let attempts = if version == DapVersion::Draft02 {
+            1
+        } else {
+            t.leader
+                .get_global_config()
+                .helper_request_retry
+                .max_attempts
+        };
+        t.leader
+            .peer_transient_failures
+            .store(2 * attempts, Ordering::Relaxed);
+        assert_matches!(t.run_agg_job(task_id).await, Err(DapAbort::Internal(..)));
+
+        // The aggregation job is not counted as abandoned.
+        let mut got_buf = Vec::new();
+        TextEncoder::new()
+            .encode(&t.prometheus_registry.gather(), &mut got_buf)
+            .unwrap();
+        assert!(!String::from_utf8(got_buf)
+            .unwrap()
+            .contains(r#"status="abandoned""#));
+    }
+
+    async_test_versions! { leader_fails_to_abandon_agg_job }
+
+    async fn handle_upload_req_fail_send_invalid_report(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Construct a report payload with an invalid task ID.
+        let report_invalid_task_id = t.gen_test_report(task_id).await;
+        let req = DapRequest {
+            version: task_config.version,
+            media_type: DapMediaType::Report,
+            task_id: Some(TaskId([0; 32])),
+            resource: DapResource::Undefined,
             payload: report_invalid_task_id.get_encoded_with_param(&task_config.version),
             url: task_config.leader_url.join("upload").unwrap(),
             ..Default::default()
@@ -1429,6 +2197,70 @@ mod test {
 
     async_test_versions! { handle_upload_req_task_expired }
 
+    async fn handle_upload_batch_req(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let mut invalid_report = t.gen_test_report(task_id).await;
+        invalid_report.encrypted_input_shares.pop();
+        let mut batch = ReportBatch::from_reports(&version, [&report, &invalid_report, &report]);
+        batch.encoded_reports.insert(1, b"not a report".to_vec());
+
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::ReportBatch,
+            task_id: Some(task_id.clone()),
+            resource: DapResource::Undefined,
+            payload: batch.get_encoded(),
+            url: task_config.leader_url.join("reports:batch").unwrap(),
+            ..Default::default()
+        };
+
+        let resp = t.leader.handle_upload_batch_req(&req).await.unwrap();
+        assert_eq!(resp.media_type, DapMediaType::ReportBatchResp);
+        assert_eq!(
+            ReportBatchResp::get_decoded(&resp.payload.to_bytes())
+                .unwrap()
+                .results,
+            vec![
+                ReportUploadResult::Accepted,
+                ReportUploadResult::Rejected {
+                    reason: "unrecognizedMessage".into()
+                },
+                ReportUploadResult::Rejected {
+                    reason: "unrecognizedMessage".into()
+                },
+                ReportUploadResult::Rejected {
+                    reason: "reportRejected".into()
+                },
+            ]
+        );
+
+        // Uploading the report again in another batch is rejected as well.
+        let req = DapRequest {
+            payload: ReportBatch::from_reports(&version, [&report]).get_encoded(),
+            ..req
+        };
+        let resp = t.leader.handle_upload_batch_req(&req).await.unwrap();
+        assert_eq!(
+            ReportBatchResp::get_decoded(&resp.payload.to_bytes())
+                .unwrap()
+                .results,
+            vec![ReportUploadResult::Rejected {
+                reason: "reportRejected".into()
+            }]
+        );
+
+        // Only the valid report was stored, and only once.
+        let report_sel = MockAggregatorReportSelector(task_id.clone());
+        let (_, _, reports) = get_reports!(t.leader, &report_sel);
+        assert_eq!(reports, vec![report]);
+    }
+
+    async_test_version! { handle_upload_batch_req, Draft07 }
+
     async fn get_reports_empty_response(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
@@ -1460,6 +2292,66 @@ mod test {
 
     async_test_versions! { get_reports_empty_response }
 
+    async fn collect_job_priority(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Issue collection requests with different priorities. Requests without a
+        // priority use the task's default.
+        let mut collect_job_ids = HashMap::new();
+        for priority in [
+            None,
+            Some(DapCollectPriority::Backfill),
+            Some(DapCollectPriority::Urgent),
+        ] {
+            let mut req = t
+                .collector_authorized_req(
+                    task_id,
+                    &task_config,
+                    DapMediaType::CollectReq,
+                    CollectionReq {
+                        draft02_task_id: task_id.for_request_payload(&version),
+                        query: task_config.query_for_current_batch_window(t.now),
+                        agg_param: Vec::default(),
+                    },
+                    task_config.helper_url.join("collect").unwrap(),
+                )
+                .await;
+            req.collect_priority = priority;
+            t.leader.handle_collect_job_req(&req).await.unwrap();
+
+            let DapResource::CollectionJob(ref collect_job_id) = req.resource else {
+                panic!("unexpected resource");
+            };
+            collect_job_ids.insert(
+                collect_job_id.clone(),
+                priority.unwrap_or(task_config.collect_priority),
+            );
+        }
+
+        // Leader: Pending collection jobs are ordered by priority.
+        let priorities = t
+            .leader
+            .get_pending_collect_jobs()
+            .await
+            .unwrap()
+            .iter()
+            .map(|(_task_id, collect_job_id, _collect_req)| collect_job_ids[collect_job_id])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            priorities,
+            [
+                DapCollectPriority::Urgent,
+                DapCollectPriority::Normal,
+                DapCollectPriority::Backfill,
+            ]
+        );
+    }
+
+    // The collection job ID is only chosen by the Collector in the latest draft.
+    async_test_version! { collect_job_priority, Draft07 }
+
     async fn poll_collect_job_test_results(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
@@ -1537,6 +2429,95 @@ mod test {
 
     async_test_versions! { poll_collect_job_test_results }
 
+    async fn handle_list_collect_jobs_req(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq.
+        let req = t
+            .collector_authorized_req(
+                task_id,
+                &task_config,
+                DapMediaType::CollectReq,
+                CollectionReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    query: task_config.query_for_current_batch_window(t.now),
+                    agg_param: Vec::default(),
+                },
+                task_config.helper_url.join("collect").unwrap(),
+            )
+            .await;
+        t.leader.handle_collect_job_req(&req).await.unwrap();
+        let (_task_id, collect_id, _collect_req) =
+            t.leader.get_pending_collect_jobs().await.unwrap().remove(0);
+
+        // Collector: List the collect jobs for the task. The request has no payload.
+        let mut list_req = t
+            .collector_authorized_req(
+                task_id,
+                &task_config,
+                DapMediaType::CollectReq,
+                (),
+                task_config
+                    .leader_url
+                    .join(&format!("tasks/{}/collection_jobs", task_id.to_base64url()))
+                    .unwrap(),
+            )
+            .await;
+        list_req.resource = DapResource::Undefined;
+        list_req.payload = Vec::new();
+
+        assert_eq!(
+            t.leader
+                .handle_list_collect_jobs_req(&list_req)
+                .await
+                .unwrap(),
+            vec![DapCollectJobSummary {
+                collect_job_id: collect_id.clone(),
+                status: DapCollectJobStatus::Pending,
+            }]
+        );
+
+        // Leader: Complete the collect job.
+        t.leader
+            .finish_collect_job(
+                task_id,
+                &collect_id,
+                &Collection {
+                    part_batch_sel: PartialBatchSelector::TimeInterval,
+                    report_count: 0,
+                    interval: (version != DapVersion::Draft02).then_some(Interval {
+                        start: 0,
+                        duration: 2000000000,
+                    }),
+                    encrypted_agg_shares: Vec::default(),
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            t.leader
+                .handle_list_collect_jobs_req(&list_req)
+                .await
+                .unwrap(),
+            vec![DapCollectJobSummary {
+                collect_job_id: collect_id,
+                status: DapCollectJobStatus::Done,
+            }]
+        );
+
+        // Expect failure due to missing bearer token.
+        list_req.sender_auth = None;
+        assert_matches!(
+            t.leader.handle_list_collect_jobs_req(&list_req).await,
+            Err(DapAbort::UnauthorizedRequest { .. })
+        );
+    }
+
+    async_test_versions! { handle_list_collect_jobs_req }
+
     async fn handle_collect_job_req_fail_invalid_batch_interval(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
@@ -1624,6 +2605,67 @@ mod test {
 
         // Fails because the requested batch interval is too far into the future.
         assert_matches!(err, DapAbort::BadRequest(s) => assert_eq!(s, "batch interval too far into future".to_string()));
+
+        // Collector: Create CollectReqs with batch intervals that are empty or misaligned.
+        for batch_interval in [
+            Interval {
+                start: task_config.quantized_time_lower_bound(t.now),
+                duration: 0,
+            },
+            Interval {
+                start: task_config.quantized_time_lower_bound(t.now) + 1,
+                duration: task_config.time_precision,
+            },
+            Interval {
+                start: task_config.quantized_time_lower_bound(t.now),
+                duration: task_config.time_precision + 1,
+            },
+        ] {
+            let req = t
+                .collector_authorized_req(
+                    task_id,
+                    &task_config,
+                    DapMediaType::CollectReq,
+                    CollectionReq {
+                        draft02_task_id: task_id.for_request_payload(&version),
+                        query: Query::TimeInterval { batch_interval },
+                        agg_param: Vec::default(),
+                    },
+                    task_config.leader_url.join("collect").unwrap(),
+                )
+                .await;
+
+            // Leader: Handle the CollectReq received from Collector.
+            assert_matches!(
+                t.leader.handle_collect_job_req(&req).await.unwrap_err(),
+                DapAbort::BatchInvalid { .. }
+            );
+        }
+
+        // Collector: Create a CollectReq with a batch interval whose end overflows. The request
+        // is malformed.
+        let req = t
+            .collector_authorized_req(
+                task_id,
+                &task_config,
+                DapMediaType::CollectReq,
+                CollectionReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    query: Query::TimeInterval {
+                        batch_interval: Interval {
+                            start: task_config.quantized_time_lower_bound(u64::MAX),
+                            duration: task_config.time_precision * 2,
+                        },
+                    },
+                    agg_param: Vec::default(),
+                },
+                task_config.leader_url.join("collect").unwrap(),
+            )
+            .await;
+        assert_matches!(
+            t.leader.handle_collect_job_req(&req).await.unwrap_err(),
+            DapAbort::UnrecognizedMessage { .. }
+        );
     }
 
     async_test_versions! { handle_collect_job_req_fail_invalid_batch_interval }
@@ -1689,19 +2731,224 @@ mod test {
 
     async_test_versions! { handle_collect_job_req_fail_overlapping_batch_interval }
 
-    // Test a successful collect request submission.
-    // This checks that the Leader reponds with the collect ID with the ID associated to the request.
-    async fn handle_collect_job_req_success(version: DapVersion) {
+    async fn handle_collect_job_precheck_req(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
         let task_config = t.leader.unchecked_get_task_config(task_id).await;
-
-        // Collector: Create a CollectReq.
-        let collector_collect_req = CollectionReq {
-            draft02_task_id: task_id.for_request_payload(&version),
-            query: task_config.query_for_current_batch_window(t.now),
-            agg_param: Vec::default(),
-        };
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t
+            .collector_authorized_req(
+                task_id,
+                &task_config,
+                DapMediaType::CollectReq,
+                CollectionReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    query: query.clone(),
+                    agg_param: Vec::default(),
+                },
+                task_config.leader_url.join("collect").unwrap(),
+            )
+            .await;
+
+        // The batch is valid, but empty.
+        let precheck = t
+            .leader
+            .handle_collect_job_precheck_req(&req)
+            .await
+            .unwrap();
+        assert!(precheck.rejection.is_none());
+        assert_eq!(precheck.report_count, 0);
+        assert!(!precheck.min_batch_size_met);
+        assert!(!precheck.collectable);
+
+        let report = t.gen_test_report(task_id).await;
+        let upload_req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&upload_req).await.unwrap();
+        t.run_agg_job(task_id).await.unwrap();
+
+        let precheck = t
+            .leader
+            .handle_collect_job_precheck_req(&req)
+            .await
+            .unwrap();
+        assert!(precheck.rejection.is_none());
+        assert_eq!(precheck.report_count, 1);
+        assert!(precheck.min_batch_size_met);
+        assert!(precheck.collectable);
+
+        // No collection job was created.
+        assert!(t
+            .leader
+            .get_pending_collect_jobs()
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Once the batch is collected, a collection job for an overlapping batch is rejected.
+        t.run_col_job(task_id, &query).await.unwrap();
+        let precheck = t
+            .leader
+            .handle_collect_job_precheck_req(&req)
+            .await
+            .unwrap();
+        assert!(precheck
+            .rejection
+            .unwrap()
+            .typ
+            .unwrap()
+            .ends_with("batchOverlap"));
+        assert!(!precheck.collectable);
+    }
+
+    async_test_versions! { handle_collect_job_precheck_req }
+
+    // Check that a batch may be collected once per aggregation parameter.
+    async fn batch_overlapping_per_agg_param(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Client: Send upload request to Leader.
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // Leader: Run aggregation job and collect the batch with the empty aggregation parameter.
+        t.run_agg_job(task_id).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        t.run_col_job(task_id, &query).await.unwrap();
+
+        let batch_sel = BatchSelector::try_from(query).unwrap();
+        let empty = DapAggregationParamHash::new(&[]);
+        let level = DapAggregationParamHash::new(b"level 1");
+        assert_ne!(empty, level);
+        for agg in [&t.leader, &t.helper] {
+            assert!(agg
+                .is_batch_overlapping(task_id, &batch_sel, &empty)
+                .await
+                .unwrap());
+            assert!(!agg
+                .is_batch_overlapping(task_id, &batch_sel, &level)
+                .await
+                .unwrap());
+
+            agg.mark_collected(task_id, &batch_sel, &level)
+                .await
+                .unwrap();
+            assert!(agg
+                .is_batch_overlapping(task_id, &batch_sel, &level)
+                .await
+                .unwrap());
+        }
+    }
+
+    async_test_versions! { batch_overlapping_per_agg_param }
+
+    async fn get_agg_share_meta(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query = task_config.query_for_current_batch_window(t.now);
+        let batch_sel = BatchSelector::try_from(query.clone()).unwrap();
+
+        for _ in 0..2 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            t.leader.handle_upload_req(&req).await.unwrap();
+            t.run_agg_job(task_id).await.unwrap();
+        }
+
+        let agg_share = t.leader.get_agg_share(task_id, &batch_sel).await.unwrap();
+        let meta = t
+            .leader
+            .get_agg_share_meta(task_id, &batch_sel)
+            .await
+            .unwrap();
+        assert_eq!(meta.report_count, 2);
+        assert_eq!(meta, agg_share.meta());
+
+        t.run_col_job(task_id, &query).await.unwrap();
+        let meta = t
+            .leader
+            .get_agg_share_meta(task_id, &batch_sel)
+            .await
+            .unwrap();
+        assert_eq!(meta.report_count, 2);
+        assert!(meta.collected);
+    }
+
+    async_test_versions! { get_agg_share_meta }
+
+    async fn purge_task(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query = task_config.query_for_current_batch_window(t.now);
+        let batch_sel = BatchSelector::try_from(query.clone()).unwrap();
+
+        for _ in 0..2 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            t.leader.handle_upload_req(&req).await.unwrap();
+            t.run_agg_job(task_id).await.unwrap();
+        }
+
+        // Leave one report pending.
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        t.run_col_job(task_id, &query).await.unwrap();
+
+        let summary = t.leader.purge_task(task_id).await.unwrap();
+        assert_eq!(
+            summary,
+            DapTaskPurgeSummary {
+                reports_pending: 1,
+                reports_processed: 2,
+                agg_shares: 1,
+                helper_states: 0,
+                collect_jobs: 1,
+            }
+        );
+
+        let summary = t.helper.purge_task(task_id).await.unwrap();
+        assert_eq!(summary.reports_processed, 2);
+        assert_eq!(summary.agg_shares, 1);
+
+        // Nothing is left for the task.
+        let meta = t
+            .leader
+            .get_agg_share_meta(task_id, &batch_sel)
+            .await
+            .unwrap();
+        assert_eq!(meta, DapAggregateShareMeta::default());
+        assert_eq!(t.leader.purge_task(task_id).await.unwrap().total(), 0);
+        assert_eq!(t.helper.purge_task(task_id).await.unwrap().total(), 0);
+
+        // The task itself is kept.
+        assert!(t
+            .leader
+            .get_task_config_for(Cow::Borrowed(task_id))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    async_test_versions! { purge_task }
+
+    // Test a successful collect request submission.
+    // This checks that the Leader reponds with the collect ID with the ID associated to the request.
+    async fn handle_collect_job_req_success(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq.
+        let collector_collect_req = CollectionReq {
+            draft02_task_id: task_id.for_request_payload(&version),
+            query: task_config.query_for_current_batch_window(t.now),
+            agg_param: Vec::default(),
+        };
         let req = t
             .collector_authorized_req(
                 task_id,
@@ -1795,6 +3042,65 @@ mod test {
 
     async_test_versions! { handle_collect_job_req_invalid_query }
 
+    // Test that the Leader only accepts collection jobs for fixed-size batches that are ready.
+    async fn handle_collect_job_req_batch_state(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.fixed_size_task_id.clone();
+        data.tasks.get_mut(&task_id).unwrap().min_batch_size = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+
+        let collect_req = |batch_id| {
+            t.collector_authorized_req(
+                &task_id,
+                &task_config,
+                DapMediaType::CollectReq,
+                CollectionReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    query: Query::FixedSizeByBatchId { batch_id },
+                    agg_param: Vec::default(),
+                },
+                task_config.leader_url.join("collect").unwrap(),
+            )
+        };
+
+        // The batch is still being filled.
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        let batch_id = t.leader.current_batch_id(&task_id, &task_config).unwrap();
+        assert_matches!(
+            t.leader
+                .handle_collect_job_req(&collect_req(batch_id.clone()).await)
+                .await
+                .unwrap_err(),
+            DapAbort::BatchInvalid { .. }
+        );
+
+        // The batch is ready. The Leader aggregates one report per aggregation job.
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        t.run_agg_job(&task_id).await.unwrap();
+        t.run_agg_job(&task_id).await.unwrap();
+        let query = Query::FixedSizeByBatchId {
+            batch_id: batch_id.clone(),
+        };
+        t.run_col_job(&task_id, &query).await.unwrap();
+
+        // The batch has been collected.
+        assert_matches!(
+            t.leader
+                .handle_collect_job_req(&collect_req(batch_id).await)
+                .await
+                .unwrap_err(),
+            DapAbort::BatchCollected { .. }
+        );
+    }
+
+    async_test_versions! { handle_collect_job_req_batch_state }
+
     // Test HTTP POST requests with a wrong DAP version.
     async fn http_post_fail_unknown_version(version: DapVersion) {
         let t = Test::new(version);
@@ -1828,6 +3134,274 @@ mod test {
 
     async_test_versions! { handle_upload_req }
 
+    // Test that a report that can't be stored is rejected with "tooManyRequests" if the Leader is
+    // configured to signal backpressure, and with an internal error otherwise.
+    async fn handle_upload_req_overloaded(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.upload_overload_retry_after = Some(30);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.report_storage_failures.store(1, Ordering::Relaxed);
+        let abort = t.leader.handle_upload_req(&req).await.unwrap_err();
+        assert_matches!(abort, DapAbort::TooManyRequests { .. });
+        assert_eq!(abort.http_status(), 429);
+        assert_eq!(abort.retry_after(), Some(30));
+
+        // The Client's retry succeeds once storage recovers.
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.report_storage_failures.store(1, Ordering::Relaxed);
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await.unwrap_err(),
+            DapAbort::Internal(..)
+        );
+    }
+
+    async_test_versions! { handle_upload_req_overloaded }
+
+    // Test that the Leader rejects a report that was already uploaded, whether it is still pending
+    // or has been aggregated, before storing it again.
+    async fn handle_upload_req_duplicate(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // The report is pending aggregation.
+        let abort = t.leader.handle_upload_req(&req).await.unwrap_err();
+        assert_matches!(abort, DapAbort::ReportRejected { .. });
+        let problem_details = abort.into_problem_details();
+        assert!(problem_details.typ.unwrap().ends_with("reportRejected"));
+        assert!(problem_details.detail.unwrap().contains("duplicate"));
+
+        // The report has been aggregated.
+        t.run_agg_job(task_id).await.unwrap();
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await,
+            Err(DapAbort::ReportRejected { .. })
+        );
+
+        // Neither duplicate was stored.
+        let report_sel = MockAggregatorReportSelector(task_id.clone());
+        let (_, _, reports) = get_reports!(t.leader, &report_sel);
+        assert!(reports.is_empty());
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_report_counter{host="leader.com",status="rejected_duplicate_at_upload"}"#: 2,
+        });
+    }
+
+    async_test_versions! { handle_upload_req_duplicate }
+
+    #[tokio::test]
+    async fn handle_upload_req_alt_version() {
+        let mut data = TestData::new(DapVersion::Draft02);
+        let task_id = data.time_interval_task_id.clone();
+        let alt_leader_url = Url::parse("https://leader.com/v07/").unwrap();
+        data.tasks
+            .get_mut(&task_id)
+            .unwrap()
+            .alt_versions
+            .push(DapTaskAltVersion {
+                version: DapVersion::Draft07,
+                leader_url: alt_leader_url.clone(),
+                helper_url: Url::parse("http://helper.org:8788/v07/").unwrap(),
+            });
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        // The view for the alternate version uses the alternate endpoints.
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+        let alt_task_config = task_config.for_version(DapVersion::Draft07).unwrap();
+        assert_eq!(alt_task_config.version, DapVersion::Draft07);
+        assert_eq!(alt_task_config.leader_url, alt_leader_url);
+        assert_eq!(
+            alt_task_config.versions().collect::<Vec<_>>(),
+            [DapVersion::Draft07, DapVersion::Draft02]
+        );
+        assert!(task_config.for_version(DapVersion::Unknown).is_none());
+
+        // A report produced for the alternate version is accepted.
+        let hpke_config_list = [
+            t.leader
+                .get_hpke_config_for(DapVersion::Draft07, Some(&task_id))
+                .await
+                .unwrap()
+                .as_ref()
+                .clone(),
+            t.helper
+                .get_hpke_config_for(DapVersion::Draft07, Some(&task_id))
+                .await
+                .unwrap()
+                .as_ref()
+                .clone(),
+        ];
+        let report = alt_task_config
+            .produce_report(&hpke_config_list, t.now, &task_id, DapMeasurement::U64(1))
+            .unwrap();
+        let req = DapRequest {
+            version: DapVersion::Draft07,
+            media_type: DapMediaType::Report,
+            task_id: Some(task_id.clone()),
+            resource: DapResource::Undefined,
+            payload: report.get_encoded_with_param(&DapVersion::Draft07),
+            url: alt_leader_url.join("upload").unwrap(),
+            ..Default::default()
+        };
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // A task that is not offered in the version of the request is rejected.
+        let req = DapRequest {
+            task_id: Some(t.fixed_size_task_id.clone()),
+            ..req
+        };
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await,
+            Err(DapAbort::BadRequest(detail)) if detail.contains("does not match task")
+        );
+    }
+
+    async fn handle_upload_req_report_id_in_path(version: DapVersion) {
+        let mut rng = thread_rng();
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let report = t.gen_test_report(task_id).await;
+        let report_id = report.report_metadata.id.clone();
+
+        let mut req = t.gen_test_upload_req(report.clone(), task_id).await;
+        req.resource = DapResource::Report(report_id.clone());
+        if !version.supports_report_id_in_upload_path() {
+            assert_matches!(
+                t.leader.handle_upload_req(&req).await,
+                Err(DapAbort::BadRequest(..))
+            );
+            return;
+        }
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // The report ID in the path must match the report.
+        req.resource = DapResource::Report(ReportId(rng.gen()));
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await,
+            Err(DapAbort::BadRequest(..))
+        );
+
+        // Uploading the same report again while it's pending is a retry, unless the upload does
+        // not indicate the report ID in the path.
+        req.resource = DapResource::Report(report_id.clone());
+        t.leader.handle_upload_req(&req).await.unwrap();
+        req.resource = DapResource::Undefined;
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await,
+            Err(DapAbort::ReportRejected { .. })
+        );
+
+        // A different report with the same ID is a replay.
+        let mut other_report = report.clone();
+        other_report.public_share = b"some other public share".to_vec();
+        let mut other_req = t.gen_test_upload_req(other_report, task_id).await;
+        other_req.resource = DapResource::Report(report_id.clone());
+        assert_matches!(
+            t.leader.handle_upload_req(&other_req).await,
+            Err(DapAbort::ReportRejected { .. })
+        );
+
+        // Once the report has been aggregated, the stored copy is gone, so it can no longer be
+        // confirmed that a repeated upload is identical.
+        t.run_agg_job(task_id).await.unwrap();
+        req.resource = DapResource::Report(report_id);
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await,
+            Err(DapAbort::ReportRejected { .. })
+        );
+    }
+
+    async_test_versions! { handle_upload_req_report_id_in_path }
+
+    async fn handle_upload_req_unquantized_time(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.reject_unquantized_report_times = true;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let hpke_config_list = [
+            t.leader
+                .get_hpke_config_for(version, Some(task_id))
+                .await
+                .unwrap()
+                .clone(),
+            t.helper
+                .get_hpke_config_for(version, Some(task_id))
+                .await
+                .unwrap()
+                .clone(),
+        ];
+
+        // The report's timestamp is truncated to the time precision.
+        let report = task_config
+            .produce_report(
+                &hpke_config_list,
+                t.now + 1,
+                task_id,
+                DapMeasurement::U64(1),
+            )
+            .unwrap();
+        assert!(task_config.is_quantized_time(report.report_metadata.time));
+        let req = t.gen_test_upload_req(report.clone(), task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+
+        // A report with a fine-grained timestamp is rejected.
+        let mut report = report;
+        report.report_metadata.id = ReportId(thread_rng().gen());
+        report.report_metadata.time += 1;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        assert_matches!(
+            t.leader.handle_upload_req(&req).await.unwrap_err(),
+            DapAbort::ReportRejected { .. }
+        );
+    }
+
+    async_test_versions! { handle_upload_req_unquantized_time }
+
+    async fn handle_upload_req_receipt(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id.clone();
+        let signing_key = UploadReceiptSigningKey::gen();
+        data.global_config.upload_receipt_signing_key = Some(signing_key.clone());
+        data.tasks.get_mut(&task_id).unwrap().upload_receipts = true;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        let report = t.gen_test_report(&task_id).await;
+        let report_id = report.report_metadata.id.clone();
+        let req = t.gen_test_upload_req(report, &task_id).await;
+
+        let resp = t.leader.handle_upload_req(&req).await.unwrap().unwrap();
+        assert_eq!(resp.media_type, DapMediaType::UploadReceipt);
+        let receipt = UploadReceipt::get_decoded(&resp.payload.to_bytes()).unwrap();
+        assert_eq!(receipt.task_id, task_id);
+        assert_eq!(receipt.report_id, report_id);
+        assert!(receipt.verify(&signing_key.public_key().unwrap()));
+
+        // Receipts are not issued for tasks that don't enable them.
+        let task_id = &t.fixed_size_task_id;
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        assert!(t.leader.handle_upload_req(&req).await.unwrap().is_none());
+    }
+
+    async_test_versions! { handle_upload_req_receipt }
+
     async fn e2e_time_interval(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
@@ -1860,6 +3434,112 @@ mod test {
 
     async_test_versions! { e2e_time_interval }
 
+    async fn e2e_time_interval_sub_buckets(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id.clone();
+        let sub_bucket_precision = TestData::TASK_TIME_PRECISION / 4;
+        data.tasks.get_mut(&task_id).unwrap().sub_bucket_precision = Some(sub_bucket_precision);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+
+        // The batch window is read from its sub-buckets, as well as from the window itself in
+        // case it has reports aggregated before the task had sub-buckets.
+        let batch_window = task_config.quantized_time_lower_bound(t.now);
+        let span = task_config
+            .batch_span_for_sel(&BatchSelector::TimeInterval {
+                batch_interval: Interval {
+                    start: batch_window,
+                    duration: task_config.time_precision,
+                },
+            })
+            .unwrap();
+        assert_eq!(span.len(), 5);
+        assert!(span.contains(&DapBatchBucket::TimeInterval { batch_window }));
+
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        t.run_agg_job(&task_id).await.unwrap();
+
+        // Collector: Collect just the sub-bucket containing the report.
+        let batch_interval = Interval {
+            start: t.now - (t.now % sub_bucket_precision),
+            duration: sub_bucket_precision,
+        };
+        assert_eq!(
+            task_config
+                .batch_span_for_sel(&BatchSelector::TimeInterval {
+                    batch_interval: batch_interval.clone()
+                })
+                .unwrap(),
+            HashSet::from([task_config.time_interval_bucket(t.now)])
+        );
+        let collect_id = t
+            .run_col_job(&task_id, &Query::TimeInterval { batch_interval })
+            .await
+            .unwrap();
+        let DapCollectJob::Done(collection) = t
+            .leader
+            .poll_collect_job(&task_id, &collect_id)
+            .await
+            .unwrap()
+        else {
+            panic!("collection job is not done");
+        };
+        assert_eq!(collection.report_count, 1);
+    }
+
+    async_test_versions! { e2e_time_interval_sub_buckets }
+
+    async fn dry_run_agg_job(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        // Upload one valid report, one report the Leader can't decrypt, and one report the Helper
+        // can't decrypt.
+        for corrupt_share in [None, Some(0), Some(1)] {
+            let mut report = t.gen_test_report(task_id).await;
+            if let Some(i) = corrupt_share {
+                report.encrypted_input_shares[i].payload[0] ^= 1;
+            }
+            let req = t.gen_test_upload_req(report, task_id).await;
+            t.leader.handle_upload_req(&req).await.unwrap();
+        }
+
+        let dry_run = t
+            .leader
+            .dry_run_agg_job(task_id, 10, "leader.com")
+            .await
+            .unwrap();
+        assert_eq!(dry_run.reports, 3);
+        assert_eq!(dry_run.accepted, 1);
+        assert_eq!(
+            dry_run.rejected,
+            BTreeMap::from([("hpke_decrypt_error".to_string(), 2)])
+        );
+
+        // The reports are still pending and the Helper was asked to discard its state.
+        let pending = t
+            .leader
+            .report_store
+            .lock()
+            .unwrap()
+            .get(task_id)
+            .unwrap()
+            .pending
+            .values()
+            .map(|queue| queue.len())
+            .sum::<usize>();
+        assert_eq!(pending, 3);
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_helper_aggregation_job_counter{host="helper.org",status="started"}"#: 1,
+            r#"test_helper_aggregation_job_counter{host="helper.org",status="abandoned"}"#: 1,
+        });
+    }
+
+    async_test_versions! { dry_run_agg_job }
+
     async fn e2e_fixed_size(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.fixed_size_task_id;
@@ -1894,6 +3574,400 @@ mod test {
 
     async_test_versions! { e2e_fixed_size }
 
+    async fn collector_hpke_config_override(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Rotate the HPKE config for the task.
+        let collector_hpke_receiver_config =
+            HpkeReceiverConfig::gen(thread_rng().gen(), HpkeKemId::X25519HkdfSha256).unwrap();
+        for agg in [&t.leader, &t.helper] {
+            agg.collector_hpke_config_overrides.lock().unwrap().insert(
+                task_id.clone(),
+                collector_hpke_receiver_config.config.clone(),
+            );
+        }
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        t.run_agg_job(task_id).await.unwrap();
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        let collect_id = t.run_col_job(task_id, &query).await.unwrap();
+        let DapCollectJob::Done(collection) = t
+            .leader
+            .poll_collect_job(task_id, &collect_id)
+            .await
+            .unwrap()
+        else {
+            panic!("collection job is not done");
+        };
+
+        // Collector: Check the structure of the collection before decrypting it.
+        collection
+            .validate_against_request(&CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: query.clone(),
+                agg_param: Vec::default(),
+            })
+            .unwrap();
+
+        // Collector: The aggregate shares are encrypted to the new HPKE config.
+        assert!(collection
+            .encrypted_agg_shares
+            .iter()
+            .all(|ciphertext| ciphertext.config_id == collector_hpke_receiver_config.config.id));
+        let agg_res = task_config
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &collector_hpke_receiver_config,
+                task_id,
+                &BatchSelector::try_from(query).unwrap(),
+                collection.report_count,
+                collection.encrypted_agg_shares,
+                version,
+            )
+            .await
+            .unwrap();
+        assert_eq!(agg_res, DapAggregateResult::U64(1));
+    }
+
+    async_test_versions! { collector_hpke_config_override }
+
+    async fn leader_retries_transient_helper_failures(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        for _ in 0..2 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            t.leader.handle_upload_req(&req).await.unwrap();
+        }
+
+        // The first two attempts to send the AggregationJobInitReq fail.
+        t.leader.peer_transient_failures.store(2, Ordering::Relaxed);
+        if version == DapVersion::Draft02 {
+            // The draft02 AggregationJobInitReq is sent with POST, which is not retried.
+            assert_matches!(t.run_agg_job(task_id).await, Err(DapAbort::Internal(..)));
+            return;
+        }
+        t.run_agg_job(task_id).await.unwrap();
+
+        // Once the retries are exhausted, the aggregation job fails.
+        t.leader.peer_transient_failures.store(3, Ordering::Relaxed);
+        assert_matches!(t.run_agg_job(task_id).await, Err(DapAbort::Internal(..)));
+
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_helper_request_retry_counter{host="leader.com"}"#: 4,
+            r#"test_leader_report_counter{host="leader.com",status="aggregated"}"#: 1,
+        });
+    }
+
+    async_test_versions! { leader_retries_transient_helper_failures }
+
+    async fn batch_policy_min_distinct_report_id_prefixes(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.fixed_size_task_id.clone();
+        data.tasks
+            .get_mut(&task_id)
+            .unwrap()
+            .batch_policy
+            .min_distinct_report_id_prefixes = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        t.run_agg_job(&task_id).await.unwrap();
+
+        // The batch satisfies the minimum batch size, but it only has one distinct report ID
+        // prefix, so it is not ready to be collected.
+        let batch_id = t.leader.current_batch_id(&task_id, &task_config).unwrap();
+        let batch_sel = BatchSelector::FixedSizeByBatchId { batch_id };
+        let agg_share = t.leader.get_agg_share(&task_id, &batch_sel).await.unwrap();
+        assert_eq!(agg_share.report_count, 1);
+        assert_eq!(agg_share.distinct_report_id_prefixes(), 1);
+        assert!(!task_config
+            .is_batch_collectable(&task_id, &batch_sel, &agg_share, t.now)
+            .unwrap());
+    }
+
+    async_test_versions! { batch_policy_min_distinct_report_id_prefixes }
+
+    async fn report_annotations(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for annotation in [Some("android"), Some("android"), Some("ios"), None] {
+            let mut report = t.gen_test_report(task_id).await;
+            report.annotation = annotation.map(String::from);
+            t.leader.put_report(&report, task_id).await.unwrap();
+            t.run_agg_job(task_id).await.unwrap();
+        }
+
+        // Annotations are counted in the Leader's aggregate share, but not sent to the Helper.
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: task_config.quantized_time_lower_bound(t.now),
+                duration: task_config.time_precision,
+            },
+        };
+        let agg_share = t.leader.get_agg_share(task_id, &batch_sel).await.unwrap();
+        assert_eq!(agg_share.report_count, 4);
+        assert_eq!(
+            agg_share.annotations,
+            BTreeMap::from([("android".into(), 2), ("ios".into(), 1)])
+        );
+        let agg_share = t.helper.get_agg_share(task_id, &batch_sel).await.unwrap();
+        assert_eq!(agg_share.report_count, 4);
+        assert!(agg_share.annotations.is_empty());
+    }
+
+    async_test_versions! { report_annotations }
+
+    async fn vdaf_verify_key_rotation(version: DapVersion) {
+        let mut rng = thread_rng();
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id.clone();
+        let old_key = VdafVerifyKey::Prio3(rng.gen());
+        let new_key = VdafVerifyKey::Prio3(rng.gen());
+
+        // The Helper recognizes both the old and the new key.
+        data.tasks.get_mut(&task_id).unwrap().vdaf_verify_key_set = Some(VdafVerifyKeySet {
+            active_key_id: 1,
+            keys: BTreeMap::from([(1, old_key), (2, new_key.clone())]),
+        });
+        let helper = data.new_helper();
+
+        // The Leader has rotated to the new key.
+        data.tasks.get_mut(&task_id).unwrap().vdaf_verify_key_set = Some(VdafVerifyKeySet {
+            active_key_id: 2,
+            keys: BTreeMap::from([(2, new_key), (3, VdafVerifyKey::Prio3(rng.gen()))]),
+        });
+        let t = data.with_leader(helper);
+
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        t.run_agg_job(&task_id).await.unwrap();
+
+        // The report is only aggregated if both Aggregators used the same key.
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_report_counter{host="leader.com",status="aggregated"}"#: 1,
+            r#"test_helper_report_counter{host="helper.org",status="aggregated"}"#: 1,
+        });
+
+        // The Leader rotates to a key that the Helper does not recognize.
+        t.leader
+            .tasks
+            .lock()
+            .unwrap()
+            .get_mut(&task_id)
+            .unwrap()
+            .vdaf_verify_key_set
+            .as_mut()
+            .unwrap()
+            .active_key_id = 3;
+
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        t.leader.handle_upload_req(&req).await.unwrap();
+        assert_matches!(
+            t.run_agg_job(&task_id).await,
+            Err(DapAbort::UnrecognizedMessage { .. })
+        );
+    }
+
+    async_test_versions! { vdaf_verify_key_rotation }
+
+    // Test that the Leader checks a report against the batch bucket computed for it upon upload,
+    // unless the task's time precision has changed since.
+    async fn precomputed_batch_bucket(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Mark a bucket other than the one the report belongs to as collected and assign each
+        // report to it upon upload. A report is rejected only if the assignment is trusted.
+        let collected_bucket = DapBatchBucket::TimeInterval {
+            batch_window: task_config.quantized_time_lower_bound(t.now)
+                + task_config.time_precision,
+        };
+        t.leader
+            .agg_store
+            .lock()
+            .unwrap()
+            .entry(task_id.clone())
+            .or_default()
+            .insert(
+                collected_bucket.clone(),
+                AggStore {
+                    agg_share: DapAggregateShare::default(),
+                    collected: true,
+                    collected_agg_params: HashSet::default(),
+                },
+            );
+        let upload_to_collected_bucket = || async {
+            let report = t.gen_test_report(task_id).await;
+            let report_id = report.report_metadata.id.clone();
+            let req = t.gen_test_upload_req(report, task_id).await;
+            t.leader.handle_upload_req(&req).await.unwrap();
+
+            let mut guard = t.leader.report_store.lock().unwrap();
+            let precomputed = guard
+                .get_mut(task_id)
+                .unwrap()
+                .precomputed_batch_buckets
+                .get_mut(&report_id)
+                .unwrap();
+            assert_eq!(precomputed.time_precision, task_config.time_precision);
+            precomputed.bucket = collected_bucket.clone();
+        };
+
+        upload_to_collected_bucket().await;
+        t.run_agg_job(task_id).await.unwrap();
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_report_counter{host="leader.com",status="rejected_batch_collected"}"#: 1,
+        });
+
+        // The bucket of the next report was computed with the old time precision and is ignored.
+        upload_to_collected_bucket().await;
+        t.leader
+            .tasks
+            .lock()
+            .unwrap()
+            .get_mut(task_id)
+            .unwrap()
+            .time_precision *= 2;
+        t.run_agg_job(task_id).await.unwrap();
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_report_counter{host="leader.com",status="rejected_batch_collected"}"#: 1,
+            r#"test_leader_report_counter{host="leader.com",status="aggregated"}"#: 1,
+        });
+    }
+
+    async_test_versions! { precomputed_batch_bucket }
+
+    async fn batch_policy_min_collect_delay(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id.clone();
+        data.tasks
+            .get_mut(&task_id)
+            .unwrap()
+            .batch_policy
+            .min_collect_delay = Some(3600);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+
+        // Collector: Request the current batch window, which ends in the future.
+        let req = t
+            .collector_authorized_req(
+                &task_id,
+                &task_config,
+                DapMediaType::CollectReq,
+                CollectionReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    query: task_config.query_for_current_batch_window(t.now),
+                    agg_param: Vec::default(),
+                },
+                task_config.helper_url.join("collect").unwrap(),
+            )
+            .await;
+
+        // Fails because the batch cannot be collected until an hour after the window ends.
+        assert_matches!(
+            t.leader.handle_collect_job_req(&req).await,
+            Err(DapAbort::BatchInvalid { .. })
+        );
+
+        // Succeeds once the delay has elapsed.
+        t.clock.advance(
+            task_config.time_precision + task_config.batch_policy.min_collect_delay.unwrap(),
+        );
+        t.leader.handle_collect_job_req(&req).await.unwrap();
+    }
+
+    async_test_versions! { batch_policy_min_collect_delay }
+
+    async fn batch_policy_allow_empty_collection(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id.clone();
+        data.tasks
+            .get_mut(&task_id)
+            .unwrap()
+            .batch_policy
+            .allow_empty_collection = true;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+        let collector_hpke_receiver_config =
+            HpkeReceiverConfig::gen(thread_rng().gen(), HpkeKemId::X25519HkdfSha256).unwrap();
+        for agg in [&t.leader, &t.helper] {
+            agg.collector_hpke_config_overrides.lock().unwrap().insert(
+                task_id.clone(),
+                collector_hpke_receiver_config.config.clone(),
+            );
+        }
+
+        // Collector: Collect a batch window for which no reports were uploaded.
+        let query = task_config.query_for_current_batch_window(t.now);
+        let collect_id = t.run_col_job(&task_id, &query).await.unwrap();
+        let DapCollectJob::Done(collection) = t
+            .leader
+            .poll_collect_job(&task_id, &collect_id)
+            .await
+            .unwrap()
+        else {
+            panic!("collection job is not done");
+        };
+        assert_eq!(collection.report_count, 0);
+        let Query::TimeInterval { ref batch_interval } = query else {
+            unreachable!()
+        };
+        match version {
+            DapVersion::Draft02 => assert_eq!(collection.interval, None),
+            _ => assert_eq!(collection.interval.as_ref(), Some(batch_interval)),
+        }
+
+        // Collector: The aggregate is zero.
+        let agg_res = task_config
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &collector_hpke_receiver_config,
+                &task_id,
+                &BatchSelector::try_from(query).unwrap(),
+                collection.report_count,
+                collection.encrypted_agg_shares,
+                version,
+            )
+            .await
+            .unwrap();
+        assert_eq!(agg_res, DapAggregateResult::U64(0));
+    }
+
+    async_test_versions! { batch_policy_allow_empty_collection }
+
+    async fn empty_collection_not_ready_by_default(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        let collect_id = t.run_col_job(task_id, &query).await.unwrap();
+        assert_matches!(
+            t.leader.poll_collect_job(task_id, &collect_id).await,
+            Ok(DapCollectJob::Pending)
+        );
+    }
+
+    async_test_versions! { empty_collection_not_ready_by_default }
+
     async fn e2e_taskprov(version: DapVersion) {
         let t = Test::new(version);
         let vdaf = VdafConfig::Prio2 { dimension: 10 };
@@ -2069,4 +4143,81 @@ mod test {
     }
 
     test_versions! { early_metadata_checks }
+
+    #[test]
+    fn check_vdaf_config_prio2_dimension() {
+        let mut global_config = TestData::new(DapVersion::Draft07).global_config;
+        let task_id = TaskId([1; 32]);
+        let prio2 = |dimension| VdafConfig::Prio2 { dimension };
+
+        assert_matches!(
+            global_config.check_vdaf_config(&task_id, &prio2(10)),
+            Ok(())
+        );
+
+        // The dimension exceeds the capacity of the field.
+        assert_matches!(
+            global_config.check_vdaf_config(&task_id, &prio2(1 << 30)),
+            Err(DapAbort::InvalidTask { .. })
+        );
+
+        // The dimension exceeds the configured maximum.
+        global_config.max_prio2_dimension = Some(5);
+        assert_matches!(
+            global_config.check_vdaf_config(&task_id, &prio2(10)),
+            Err(DapAbort::InvalidTask { .. })
+        );
+        assert_matches!(global_config.check_vdaf_config(&task_id, &prio2(5)), Ok(()));
+        assert_matches!(
+            global_config.check_vdaf_config(&task_id, &VdafConfig::Prio3(Prio3Config::Count)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn leader_work_queue() {
+        let mut rng = thread_rng();
+        let agg_job = |task_id: &TaskId| LeaderWorkItem::AggregateJob {
+            task_id: task_id.clone(),
+            version: DapVersion::Draft07,
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            reports: Vec::new(),
+        };
+        let collect_job = |task_id: &TaskId| LeaderWorkItem::CollectJob {
+            task_id: task_id.clone(),
+            collect_id: CollectionJobId([0; 16]),
+            collect_req: CollectionReq {
+                draft02_task_id: None,
+                query: Query::FixedSizeCurrentBatch,
+                agg_param: Vec::new(),
+            },
+        };
+        let task_ids = [TaskId(rng.gen()), TaskId(rng.gen()), TaskId(rng.gen())];
+
+        // Aggregation jobs are taken before collection jobs, otherwise in the order added.
+        let mut queue = LeaderWorkQueue::new(2);
+        queue.push(collect_job(&task_ids[0]));
+        queue.extend([agg_job(&task_ids[1]), agg_job(&task_ids[2])]);
+        assert_eq!(queue.len(), 3);
+
+        let first = queue.pop().unwrap();
+        assert_eq!(first.item.task_id(), &task_ids[1]);
+        assert_eq!(first.attempts, 1);
+
+        // A failed item goes to the back of its priority class.
+        assert!(queue.retry(first));
+        let second = queue.pop().unwrap();
+        assert_eq!(second.item.task_id(), &task_ids[2]);
+        let third = queue.pop().unwrap();
+        assert_eq!(third.item.task_id(), &task_ids[1]);
+        assert_eq!(third.attempts, 2);
+
+        // The item has been attempted the maximum number of times.
+        assert!(!queue.retry(third));
+
+        let fourth = queue.pop().unwrap();
+        assert_matches!(fourth.item, LeaderWorkItem::CollectJob { .. });
+        assert!(queue.is_empty());
+        assert!(queue.pop().is_none());
+    }
 }
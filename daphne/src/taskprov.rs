@@ -4,12 +4,13 @@
 use crate::{
     hpke::HpkeConfig,
     messages::{
-        decode_base64url_vec,
+        self, decode_base64url_vec,
         taskprov::{QueryConfigVar, TaskConfig, VdafType, VdafTypeVar},
-        Extension, ReportMetadata, TaskId,
+        Duration, Extension, ReportMetadata, TaskId, Time,
     },
     vdaf::{VdafVerifyKey, VDAF_VERIFY_KEY_SIZE_PRIO2},
-    DapAbort, DapError, DapQueryConfig, DapRequest, DapTaskConfig, DapVersion, VdafConfig,
+    DapAbort, DapError, DapQueryConfig, DapRequest, DapTaskConfig, DapVersion, Prio3Config,
+    VdafConfig,
 };
 use prio::codec::ParameterizedDecode;
 use ring::{
@@ -17,7 +18,7 @@ use ring::{
     hkdf::{Prk, Salt, HKDF_SHA256},
 };
 use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, str};
+use std::{borrow::Cow, collections::HashMap, fmt, str, sync::Mutex};
 use url::Url;
 
 /// DAP taskprov version.
@@ -28,6 +29,145 @@ pub enum TaskprovVersion {
     Draft02,
 }
 
+/// The kind of VDAF configured for a task, without its parameters.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub enum TaskprovVdafKind {
+    Prio3Count,
+    Prio3Sum,
+    Prio3Histogram,
+    Prio3SumVec,
+    Prio2,
+}
+
+impl From<&VdafConfig> for TaskprovVdafKind {
+    fn from(vdaf: &VdafConfig) -> Self {
+        match vdaf {
+            VdafConfig::Prio3(Prio3Config::Count) => Self::Prio3Count,
+            VdafConfig::Prio3(Prio3Config::Sum { .. }) => Self::Prio3Sum,
+            VdafConfig::Prio3(Prio3Config::Histogram { .. }) => Self::Prio3Histogram,
+            VdafConfig::Prio3(Prio3Config::SumVec { .. }) => Self::Prio3SumVec,
+            VdafConfig::Prio2 { .. } => Self::Prio2,
+        }
+    }
+}
+
+/// The query type configured for a task, without its parameters.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub enum TaskprovQueryKind {
+    TimeInterval,
+    FixedSize,
+}
+
+impl From<&DapQueryConfig> for TaskprovQueryKind {
+    fn from(query: &DapQueryConfig) -> Self {
+        match query {
+            DapQueryConfig::TimeInterval => Self::TimeInterval,
+            DapQueryConfig::FixedSize { .. } => Self::FixedSize,
+        }
+    }
+}
+
+/// Constraints on the tasks an Aggregator is willing to opt into via taskprov. Each constraint
+/// that is not set is not enforced, so the default policy opts into every task.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct TaskprovPolicy {
+    /// Maximum time (in seconds) until the task expires, measured from when the task is
+    /// provisioned.
+    pub max_task_lifetime: Option<Duration>,
+
+    /// VDAFs that may be used.
+    pub allowed_vdafs: Option<Vec<TaskprovVdafKind>>,
+
+    /// Query types that may be used.
+    pub allowed_query_types: Option<Vec<TaskprovQueryKind>>,
+
+    /// Smallest minimum batch size that may be used.
+    pub min_batch_size_floor: Option<u64>,
+}
+
+/// The reason a task provisioned via taskprov was rejected by a [`TaskprovPolicy`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TaskprovOptOutReason {
+    TaskLifetimeTooLong { lifetime: Duration, max: Duration },
+    VdafNotAllowed(TaskprovVdafKind),
+    QueryTypeNotAllowed(TaskprovQueryKind),
+    MinBatchSizeTooSmall { min_batch_size: u64, floor: u64 },
+}
+
+impl fmt::Display for TaskprovOptOutReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TaskLifetimeTooLong { lifetime, max } => {
+                write!(
+                    f,
+                    "task lifetime ({lifetime}s) exceeds the maximum ({max}s)"
+                )
+            }
+            Self::VdafNotAllowed(vdaf) => write!(f, "VDAF {vdaf:?} is not allowed"),
+            Self::QueryTypeNotAllowed(query) => write!(f, "query type {query:?} is not allowed"),
+            Self::MinBatchSizeTooSmall {
+                min_batch_size,
+                floor,
+            } => write!(
+                f,
+                "minimum batch size ({min_batch_size}) is smaller than the floor ({floor})"
+            ),
+        }
+    }
+}
+
+impl TaskprovPolicy {
+    /// Decide whether to opt out of a task provisioned at time `now`. If the return value is
+    /// `None`, then the task satisfies the policy.
+    pub fn opt_out_reason(
+        &self,
+        task_config: &DapTaskConfig,
+        now: Time,
+    ) -> Option<TaskprovOptOutReason> {
+        if let Some(max) = self.max_task_lifetime {
+            let lifetime = task_config.expiration.saturating_sub(now);
+            if lifetime > max {
+                return Some(TaskprovOptOutReason::TaskLifetimeTooLong { lifetime, max });
+            }
+        }
+
+        let vdaf = TaskprovVdafKind::from(&task_config.vdaf);
+        if self
+            .allowed_vdafs
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&vdaf))
+        {
+            return Some(TaskprovOptOutReason::VdafNotAllowed(vdaf));
+        }
+
+        let query = TaskprovQueryKind::from(&task_config.query);
+        if self
+            .allowed_query_types
+            .as_ref()
+            .is_some_and(|allowed| !allowed.contains(&query))
+        {
+            return Some(TaskprovOptOutReason::QueryTypeNotAllowed(query));
+        }
+
+        if let Some(floor) = self.min_batch_size_floor {
+            if task_config.min_batch_size < floor {
+                return Some(TaskprovOptOutReason::MinBatchSizeTooSmall {
+                    min_batch_size: task_config.min_batch_size,
+                    floor,
+                });
+            }
+        }
+
+        None
+    }
+}
+
 /// SHA-256 of "dap-taskprov"
 #[allow(dead_code)]
 pub(crate) const TASK_PROV_SALT_DRAFT02: [u8; 32] = [
@@ -203,6 +343,16 @@ fn get_taskprov_task_config<S>(
     let task_config = TaskConfig::get_decoded_with_param(&taskprov_version, taskprov_data.as_ref())
         .map_err(|e| DapAbort::from_codec_error(e, task_id.clone()))?;
 
+    // Make sure the task ID derived from the parsed config matches the one derived from the
+    // advertisement. Otherwise tools that compute the task ID from a `TaskConfig` would disagree
+    // with us.
+    if messages::taskprov::compute_task_id(taskprov_version, &task_config) != *task_id {
+        return Err(DapAbort::UnrecognizedMessage {
+            detail: "taskprov task config is not canonically encoded".into(),
+            task_id: Some(task_id.clone()),
+        });
+    }
+
     Ok(Some(task_config))
 }
 
@@ -226,6 +376,7 @@ impl From<QueryConfigVar> for DapQueryConfig {
         match var {
             QueryConfigVar::FixedSize { max_batch_size } => DapQueryConfig::FixedSize {
                 max_batch_size: max_batch_size.into(),
+                batch_time_limit: None,
             },
             QueryConfigVar::TimeInterval => DapQueryConfig::TimeInterval,
         }
@@ -264,6 +415,12 @@ impl DapTaskConfig {
                 ),
             ));
         }
+        if task_config.query_config.time_precision == 0 {
+            return Err(malformed_task_config(
+                task_id,
+                "The task config indicates a time precision of 0".into(),
+            ));
+        }
         let vdaf_type = VdafType::from(task_config.vdaf_config.var.clone());
         Ok(DapTaskConfig {
             version: dap_version,
@@ -282,6 +439,17 @@ impl DapTaskConfig {
             ),
             collector_hpke_config: collector_hpke_config.clone(),
             taskprov: true,
+            batch_policy: Default::default(),
+            upload_receipts: false,
+            unknown_extensions: Default::default(),
+            report_ttl: None,
+            collect_priority: Default::default(),
+            client_auth_key: None,
+            vdaf_verify_key_set: None,
+            alt_versions: Vec::new(),
+            report_storage_epoch_duration: None,
+            report_storage_epoch_migration: None,
+            sub_bucket_precision: None,
         })
     }
 }
@@ -296,13 +464,149 @@ impl ReportMetadata {
     }
 }
 
+/// Digest of a taskprov advertisement, used to recognize an advertisement that was seen before.
+/// The digest binds the advertisement to the DAP version, taskprov version, and task ID of the
+/// request, since whether the advertisement is accepted depends on these.
+///
+/// Returns `None` if the request does not carry an advertisement.
+pub(crate) fn advertisement_digest<S>(
+    req: &DapRequest<S>,
+    taskprov_version: TaskprovVersion,
+    task_id: &TaskId,
+    report_metadata_advertisement: Option<&ReportMetadata>,
+) -> Option<[u8; 32]> {
+    let (source, data): (u8, &[u8]) = if let Some(ref taskprov_base64url) = req.taskprov {
+        (0, taskprov_base64url.as_bytes())
+    } else {
+        let payload = report_metadata_advertisement?
+            .extensions
+            .iter()
+            .find_map(|x| match x {
+                Extension::Taskprov { payload } => Some(payload),
+                _ => None,
+            })?;
+        (1, payload)
+    };
+
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(match req.version {
+        DapVersion::Draft02 => &[2],
+        DapVersion::Draft07 => &[7],
+        DapVersion::Unknown => &[0],
+    });
+    ctx.update(match taskprov_version {
+        TaskprovVersion::Draft02 => &[2],
+    });
+    ctx.update(&task_id.0);
+    ctx.update(&[source]);
+    ctx.update(data);
+    let mut d = [0; 32];
+    d.copy_from_slice(ctx.finish().as_ref());
+    Some(d)
+}
+
+/// Maximum number of advertisements held by a [`TaskprovRejectionCache`].
+const TASKPROV_REJECTION_CACHE_CAPACITY: usize = 10_000;
+
+/// Cache of taskprov advertisements that were rejected because they are malformed. The peer may
+/// advertise the task in every request, so this saves us from decoding the same bad advertisement
+/// over and over again.
+///
+/// Only rejections that depend on nothing but the advertisement itself are cached. Opt-out
+/// decisions are not, since they may depend on the current time.
+pub struct TaskprovRejectionCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<[u8; 32], (Time, TaskprovRejection)>>,
+}
+
+#[derive(Clone)]
+enum TaskprovRejection {
+    BadRequest(String),
+    InvalidTask(String),
+    UnrecognizedMessage(String),
+}
+
+impl TaskprovRejection {
+    fn from_error(e: &DapError) -> Option<Self> {
+        match e {
+            DapError::Abort(DapAbort::BadRequest(detail)) => Some(Self::BadRequest(detail.clone())),
+            DapError::Abort(DapAbort::InvalidTask { detail, .. }) => {
+                Some(Self::InvalidTask(detail.clone()))
+            }
+            DapError::Abort(DapAbort::UnrecognizedMessage { detail, .. }) => {
+                Some(Self::UnrecognizedMessage(detail.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn into_abort(self, task_id: &TaskId) -> DapAbort {
+        match self {
+            Self::BadRequest(detail) => DapAbort::BadRequest(detail),
+            Self::InvalidTask(detail) => DapAbort::InvalidTask {
+                detail,
+                task_id: task_id.clone(),
+            },
+            Self::UnrecognizedMessage(detail) => DapAbort::UnrecognizedMessage {
+                detail,
+                task_id: Some(task_id.clone()),
+            },
+        }
+    }
+}
+
+impl TaskprovRejectionCache {
+    /// Create a cache whose entries expire `ttl` seconds after they are inserted.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the abort with which the advertisement with the given digest was rejected, if the
+    /// rejection was cached no longer than the TTL ago.
+    pub(crate) fn get(&self, digest: &[u8; 32], task_id: &TaskId, now: Time) -> Option<DapAbort> {
+        let entries = self.entries.lock().ok()?;
+        let (expires_at, rejection) = entries.get(digest)?;
+        (now < *expires_at).then(|| rejection.clone().into_abort(task_id))
+    }
+
+    /// Cache the rejection of an advertisement. Errors other than aborts indicating the
+    /// advertisement is malformed are ignored.
+    pub(crate) fn insert(&self, digest: [u8; 32], e: &DapError, now: Time) {
+        let Some(rejection) = TaskprovRejection::from_error(e) else {
+            return;
+        };
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.len() >= TASKPROV_REJECTION_CACHE_CAPACITY {
+            entries.retain(|_, (expires_at, _)| now < *expires_at);
+            if entries.len() >= TASKPROV_REJECTION_CACHE_CAPACITY {
+                return;
+            }
+        }
+        entries.insert(digest, (now.saturating_add(self.ttl), rejection));
+    }
+
+    /// Forget all cached rejections.
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use prio::codec::ParameterizedEncode;
     use url::Url;
 
     use super::{
-        compute_task_id, compute_vdaf_verify_key, resolve_advertised_task_config, TaskprovVersion,
+        advertisement_digest, compute_task_id, compute_vdaf_verify_key,
+        resolve_advertised_task_config, TaskprovOptOutReason, TaskprovPolicy, TaskprovQueryKind,
+        TaskprovRejectionCache, TaskprovVdafKind, TaskprovVersion,
     };
     use crate::{
         auth::BearerToken,
@@ -317,8 +621,9 @@ mod test {
         },
         test_versions,
         vdaf::VdafVerifyKey,
-        DapError, DapRequest, DapResource, DapVersion,
+        DapError, DapRequest, DapResource, DapTaskConfig, DapVersion,
     };
+    use assert_matches::assert_matches;
 
     #[test]
     fn check_vdaf_key_computation() {
@@ -382,6 +687,10 @@ mod test {
             taskprov_task_config.get_encoded_with_param(&taskprov_version);
         let taskprov_task_config_base64url = encode_base64url(&taskprov_task_config_data);
         let task_id = compute_task_id(taskprov_version, &taskprov_task_config_data);
+        assert_eq!(
+            taskprov::compute_task_id(taskprov_version, &taskprov_task_config),
+            task_id
+        );
         let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
             .unwrap()
             .config;
@@ -493,6 +802,8 @@ mod test {
                 url: Url::parse("https://example.com/").unwrap(), // ignored by test
                 sender_auth: None,                // ignored by test
                 taskprov: Some(taskprov_task_config_base64url),
+                content_encoding: None,
+                collect_priority: None,
             };
 
             (req, task_id)
@@ -510,8 +821,32 @@ mod test {
             &task_id,
             None,
         ) {
-            Err(DapError::Abort(DapAbort::UnrecognizedMessage { detail, .. })) => {
-                assert_eq!(detail, "codec error: unexpected value")
+            Err(e @ DapError::Abort(DapAbort::UnrecognizedMessage { .. })) => {
+                assert_matches!(
+                    &e,
+                    DapError::Abort(DapAbort::UnrecognizedMessage { detail, .. })
+                        if detail == "codec error: unexpected value"
+                );
+
+                // Cache the rejection.
+                let cache = TaskprovRejectionCache::new(60);
+                let digest =
+                    advertisement_digest(&req, TaskprovVersion::Draft02, &task_id, None).unwrap();
+                assert!(cache.get(&digest, &task_id, 1000).is_none());
+                cache.insert(digest, &e, 1000);
+                assert_matches!(
+                    cache.get(&digest, &task_id, 1059),
+                    Some(DapAbort::UnrecognizedMessage { detail, task_id: Some(id) })
+                        if detail == "codec error: unexpected value" && id == task_id
+                );
+                assert!(cache.get(&digest, &task_id, 1060).is_none());
+
+                // The rejection is specific to the task ID and the DAP version.
+                let other_task_id = TaskId([1; 32]);
+                assert_ne!(
+                    advertisement_digest(&req, TaskprovVersion::Draft02, &other_task_id, None),
+                    Some(digest)
+                );
             }
             Err(e) => panic!("unexpected error: {e}"),
             Ok(..) => panic!("expected error"),
@@ -519,4 +854,101 @@ mod test {
     }
 
     test_versions! { resolve_advertised_task_config_expect_abort_unrecognized_vdaf }
+
+    #[test]
+    fn taskprov_policy() {
+        let taskprov_version = TaskprovVersion::Draft02;
+        let taskprov_task_config = TaskConfig {
+            task_info: "Hi".as_bytes().to_vec(),
+            aggregator_endpoints: vec![
+                UrlBytes {
+                    bytes: "https://leader.com".as_bytes().to_vec(),
+                },
+                UrlBytes {
+                    bytes: "https://helper.com".as_bytes().to_vec(),
+                },
+            ],
+            query_config: QueryConfig {
+                time_precision: 0x01,
+                max_batch_query_count: 128,
+                min_batch_size: 1024,
+                var: QueryConfigVar::TimeInterval,
+            },
+            task_expiration: 1_000_000,
+            vdaf_config: VdafConfig {
+                dp_config: DpConfig::None,
+                var: VdafTypeVar::Prio2 { dimension: 10 },
+            },
+        };
+        let task_id = taskprov::compute_task_id(taskprov_version, &taskprov_task_config);
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let task_config = DapTaskConfig::try_from_taskprov(
+            DapVersion::Draft02,
+            taskprov_version,
+            &task_id,
+            taskprov_task_config,
+            &[0; 32],
+            &collector_hpke_config,
+        )
+        .unwrap();
+        let now = 1_000_000 - 3600;
+
+        // The default policy opts into every task.
+        assert_eq!(
+            TaskprovPolicy::default().opt_out_reason(&task_config, now),
+            None
+        );
+
+        let policy = TaskprovPolicy {
+            max_task_lifetime: Some(3600),
+            allowed_vdafs: Some(vec![TaskprovVdafKind::Prio2]),
+            allowed_query_types: Some(vec![TaskprovQueryKind::TimeInterval]),
+            min_batch_size_floor: Some(1024),
+        };
+        assert_eq!(policy.opt_out_reason(&task_config, now), None);
+
+        assert_eq!(
+            policy.opt_out_reason(&task_config, now - 1),
+            Some(TaskprovOptOutReason::TaskLifetimeTooLong {
+                lifetime: 3601,
+                max: 3600
+            })
+        );
+
+        assert_eq!(
+            TaskprovPolicy {
+                allowed_vdafs: Some(vec![TaskprovVdafKind::Prio3Count]),
+                ..policy.clone()
+            }
+            .opt_out_reason(&task_config, now),
+            Some(TaskprovOptOutReason::VdafNotAllowed(
+                TaskprovVdafKind::Prio2
+            ))
+        );
+
+        assert_eq!(
+            TaskprovPolicy {
+                allowed_query_types: Some(vec![TaskprovQueryKind::FixedSize]),
+                ..policy.clone()
+            }
+            .opt_out_reason(&task_config, now),
+            Some(TaskprovOptOutReason::QueryTypeNotAllowed(
+                TaskprovQueryKind::TimeInterval
+            ))
+        );
+
+        assert_eq!(
+            TaskprovPolicy {
+                min_batch_size_floor: Some(2048),
+                ..policy
+            }
+            .opt_out_reason(&task_config, now),
+            Some(TaskprovOptOutReason::MinBatchSizeTooSmall {
+                min_batch_size: 1024,
+                floor: 2048
+            })
+        );
+    }
 }
@@ -2,31 +2,48 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 //! Mock backend functionality to test DAP protocol.
+//!
+//! [`MockAggregator`] is a complete, in-memory implementation of the Leader and Helper roles.
+//! Integration tests and downstream crates can use it to run a two-aggregator deployment
+//! in-process: construct the Helper, then construct the Leader with the Helper as its peer. Both
+//! aggregators can share a [`MockClock`] so that tests control the passage of time.
+//!
+//! [`DapSimulator`] runs the upload, aggregation, and collection flows without the roles and can
+//! inject faults at specified points.
 
 use crate::{
     audit_log::{AggregationJobAuditAction, AuditLog},
     auth::{BearerToken, BearerTokenProvider},
     constants::DapMediaType,
+    extensions::EMPTY_EXTENSION_REGISTRY,
     fatal_error,
     hpke::{HpkeConfig, HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
+    memory_budget::MemoryBudget,
     messages::{
-        AggregationJobContinueReq, AggregationJobId, AggregationJobInitReq, AggregationJobResp,
-        BatchId, BatchSelector, Collection, CollectionJobId, CollectionReq,
-        Draft02AggregationJobId, HpkeCiphertext, Interval, PartialBatchSelector, Report, ReportId,
-        ReportMetadata, TaskId, Time, TransitionFailure,
+        AggregationJobContinueReq, AggregationJobId, AggregationJobInitReq,
+        AggregationJobInitReqRef, AggregationJobResp, BatchId, BatchSelector, Collection,
+        CollectionJobId, CollectionReq, Draft02AggregationJobId, HpkeCiphertext, HpkeCiphertextRef,
+        Interval, PartialBatchSelector, Report, ReportId, ReportMetadata, TaskId, Time,
+        TransitionFailure,
     },
     metrics::DaphneMetrics,
     roles::{DapAggregator, DapAuthorizedSender, DapHelper, DapLeader, DapReportInitializer},
-    vdaf::{EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized},
-    DapAbort, DapAggregateResult, DapAggregateShare, DapAggregateShareSpan, DapBatchBucket,
-    DapCollectJob, DapError, DapGlobalConfig, DapHelperState, DapHelperTransition, DapLeaderState,
-    DapLeaderTransition, DapLeaderUncommitted, DapMeasurement, DapQueryConfig, DapRequest,
-    DapResponse, DapTaskConfig, DapVersion, MetaAggregationJobId, VdafConfig,
+    taskprov::TaskprovRejectionCache,
+    time::TimeSource,
+    vdaf::{
+        EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized, VdafVerifyKey,
+    },
+    DapAbort, DapAggregateResult, DapAggregateShare, DapAggregateShareMeta, DapAggregateShareSpan,
+    DapAggregationParamHash, DapBatchBucket, DapBatchState, DapCollectJob, DapCollectJobStatus,
+    DapCollectJobSummary, DapCollectPriority, DapError, DapGlobalConfig, DapHelperState,
+    DapHelperTransition, DapLeaderState, DapLeaderTransition, DapLeaderUncommitted, DapMeasurement,
+    DapPrecomputedBatchBucket, DapQueryConfig, DapRequest, DapResponse, DapTaskConfig,
+    DapTaskPurgeSummary, DapVersion, MetaAggregationJobId, VdafConfig,
 };
 use assert_matches::assert_matches;
 use async_trait::async_trait;
 use deepsize::DeepSizeOf;
-use prio::codec::Encode;
+use prio::codec::Decode;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::{
@@ -42,9 +59,33 @@ use std::{
 };
 use url::Url;
 
+/// A global config with typical values and no optional limits.
+pub(crate) fn global_config() -> DapGlobalConfig {
+    DapGlobalConfig {
+        report_storage_epoch_duration: 604800,    // one week
+        report_storage_max_future_time_skew: 300, // 5 minutes
+        max_batch_duration: 360000,
+        min_batch_interval_start: 259200,
+        max_batch_interval_end: 259200,
+        supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
+        taskprov_version: None,
+        agg_job_init_req_compression_threshold: None,
+        helper_request_retry: Default::default(),
+        upload_receipt_signing_key: None,
+        agg_job_min_acceptance_ratio: None,
+        enabled_versions: None,
+        reject_unquantized_report_times: false,
+        max_prio2_dimension: None,
+        taskprov_policy: Default::default(),
+        upload_overload_retry_after: None,
+        agg_job_memory_budget: None,
+    }
+}
+
 /// Scaffolding for testing the aggregation flow.
 pub struct AggregationJobTest {
-    // task parameters
+    // global and task parameters
+    pub(crate) global_config: DapGlobalConfig,
     pub(crate) task_id: TaskId,
     pub(crate) task_config: DapTaskConfig,
     pub(crate) leader_hpke_receiver_config: HpkeReceiverConfig,
@@ -70,13 +111,15 @@ pub struct AggregationJobTest {
 // NOTE(cjpatton) This implementation of the report initializer is not feature complete. Since
 // [`AggrregationJobTest`], is only used to test the aggregation flow, features that are not
 // directly relevant to the tests aren't implemented.
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl DapReportInitializer for AggregationJobTest {
     async fn initialize_reports<'req>(
         &self,
         is_leader: bool,
         _task_id: &TaskId,
         task_config: &DapTaskConfig,
+        vdaf_verify_key: &VdafVerifyKey,
         _part_batch_sel: &PartialBatchSelector,
         consumed_reports: Vec<EarlyReportStateConsumed<'req>>,
     ) -> Result<Vec<EarlyReportStateInitialized<'req>>, DapError> {
@@ -93,12 +136,15 @@ impl DapReportInitializer for AggregationJobTest {
                     Ok(EarlyReportStateInitialized::Rejected {
                         metadata: Cow::Owned(consumed.metadata().clone()),
                         failure: TransitionFailure::ReportReplayed,
+                        panicked: false,
                     })
                 } else {
                     reports_processed.insert(consumed.metadata().id.clone());
                     EarlyReportStateInitialized::initialize(
                         is_leader,
-                        &task_config.vdaf_verify_key,
+                        &self.global_config,
+                        &self.task_id,
+                        vdaf_verify_key,
                         &task_config.vdaf,
                         consumed,
                     )
@@ -134,6 +180,10 @@ impl AggregationJobTest {
 
         Self {
             now,
+            global_config: DapGlobalConfig {
+                supported_hpke_kems: vec![kem_id],
+                ..global_config()
+            },
             task_id,
             agg_job_id,
             leader_hpke_receiver_config,
@@ -152,6 +202,17 @@ impl AggregationJobTest {
                 vdaf_verify_key,
                 collector_hpke_config,
                 taskprov: false,
+                batch_policy: Default::default(),
+                upload_receipts: false,
+                unknown_extensions: Default::default(),
+                report_ttl: None,
+                collect_priority: Default::default(),
+                client_auth_key: None,
+                vdaf_verify_key_set: None,
+                alt_versions: Vec::new(),
+                report_storage_epoch_duration: None,
+                report_storage_epoch_migration: None,
+                sub_bucket_precision: None,
             },
             prometheus_registry,
             leader_metrics,
@@ -184,6 +245,60 @@ impl AggregationJobTest {
         reports
     }
 
+    /// Consume each report as the Leader (if `is_leader` is set) or Helper, i.e., decrypt and
+    /// decode the input share, so that VDAF preparation can be initialized.
+    ///
+    /// Panics if the Aggregator aborts.
+    pub async fn consume_reports(
+        &self,
+        is_leader: bool,
+        reports: Vec<Report>,
+    ) -> Vec<EarlyReportStateConsumed<'static>> {
+        let (decrypter, share_index) = if is_leader {
+            (&self.leader_hpke_receiver_config, 0)
+        } else {
+            (&self.helper_hpke_receiver_config, 1)
+        };
+        let mut consumed_reports = Vec::with_capacity(reports.len());
+        for mut report in reports {
+            let encrypted_input_share = report.encrypted_input_shares.swap_remove(share_index);
+            consumed_reports.push(
+                EarlyReportStateConsumed::consume(
+                    decrypter,
+                    &EMPTY_EXTENSION_REGISTRY,
+                    is_leader,
+                    &self.task_id,
+                    &self.task_config,
+                    Cow::Owned(report.report_metadata),
+                    Cow::Owned(report.public_share),
+                    HpkeCiphertextRef::from(&encrypted_input_share),
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        consumed_reports
+    }
+
+    /// Initialize VDAF preparation for a consumed report.
+    ///
+    /// Panics if the Aggregator aborts.
+    pub fn initialize_report<'req>(
+        &self,
+        is_leader: bool,
+        consumed_report: EarlyReportStateConsumed<'req>,
+    ) -> EarlyReportStateInitialized<'req> {
+        EarlyReportStateInitialized::initialize(
+            is_leader,
+            &self.global_config,
+            &self.task_id,
+            &self.task_config.vdaf_verify_key,
+            &self.task_config.vdaf,
+            consumed_report,
+        )
+        .unwrap()
+    }
+
     /// Leader: Produce AggregationJobInitReq.
     ///
     /// Panics if the Leader aborts.
@@ -201,6 +316,7 @@ impl AggregationJobTest {
                 self,
                 &self.task_id,
                 &self.task_config,
+                &self.task_config.vdaf_verify_key,
                 &self.agg_job_id,
                 &PartialBatchSelector::TimeInterval,
                 reports,
@@ -220,18 +336,32 @@ impl AggregationJobTest {
         let metrics = self
             .helper_metrics
             .with_host(self.task_config.helper_url.host_str().unwrap());
-        self.task_config
+        match self
+            .task_config
             .vdaf
             .handle_agg_job_init_req(
                 &self.helper_hpke_receiver_config,
                 self,
                 &self.task_id,
                 &self.task_config,
-                agg_job_init_req,
+                &self.task_config.vdaf_verify_key,
+                &self.agg_job_id,
+                &AggregationJobInitReqRef::from(agg_job_init_req),
+                None,
                 &metrics,
             )
             .await
             .unwrap()
+        {
+            DapHelperTransition::Continue(state, agg_job_resp) => DapHelperTransition::Continue(
+                state,
+                AggregationJobResp::get_decoded(&agg_job_resp.finish()).unwrap(),
+            ),
+            DapHelperTransition::Finish(out_shares, agg_job_resp) => DapHelperTransition::Finish(
+                out_shares,
+                AggregationJobResp::get_decoded(&agg_job_resp.finish()).unwrap(),
+            ),
+        }
     }
 
     /// Leader: Handle first AggregationJobResp, produce AggregationJobContinueReq.
@@ -430,8 +560,11 @@ impl AggregationJobTest {
         else {
             panic!("unexpected transition");
         };
-        let got = DapHelperState::get_decoded(&self.task_config.vdaf, &helper_state.get_encoded())
-            .expect("failed to decode helper state");
+        let got = DapHelperState::get_decoded(
+            &self.task_config.vdaf,
+            &helper_state.get_encoded_versioned(&self.task_config.vdaf),
+        )
+        .expect("failed to decode helper state");
         assert_eq!(got, helper_state);
 
         let DapLeaderTransition::Uncommitted(uncommitted, agg_cont) =
@@ -463,6 +596,257 @@ impl AggregationJobTest {
     }
 }
 
+/// A fault injected into the aggregation flow by a [`DapSimulator`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DapSimulatorFault {
+    /// The Helper handles the first AggregationJobInitReq, but its response is lost. The Leader
+    /// sends the same request again.
+    DropAggJobInitResp,
+
+    /// In the first AggregationJobResp, the Helper's transition for the report at the given index
+    /// indicates the wrong report ID.
+    CorruptTransition(usize),
+
+    /// The Client uploads the report at the given index a second time. The copy is aggregated in a
+    /// second aggregation job.
+    ReplayReport(usize),
+}
+
+/// The error returned when a fault refers to a report that doesn't exist.
+fn fault_out_of_range(fault: &DapSimulatorFault) -> DapAbort {
+    fatal_error!(err = format!("fault index out of range: {fault:?}")).into()
+}
+
+/// The outcome of a run of a [`DapSimulator`].
+#[derive(Debug)]
+pub struct DapSimulation {
+    /// The number of reports aggregated by both Aggregators.
+    pub report_count: u64,
+
+    /// The aggregate result obtained by the Collector. This is `None` if no reports were
+    /// aggregated.
+    pub result: Option<DapAggregateResult>,
+}
+
+/// Simulates the upload, aggregation, and collection flows end to end, optionally injecting
+/// faults along the way. This is intended for writing regression tests for failure handling: a
+/// test runs the simulator, then checks the outcome or abort along with the metrics recorded by
+/// each Aggregator.
+pub struct DapSimulator {
+    t: AggregationJobTest,
+    faults: Vec<DapSimulatorFault>,
+}
+
+impl DapSimulator {
+    /// Create a simulator with the given VDAF config, HPKE KEM algorithm, and DAP protocol
+    /// version.
+    pub fn new(vdaf: &VdafConfig, kem_id: HpkeKemId, version: DapVersion) -> Self {
+        Self {
+            t: AggregationJobTest::new(vdaf, kem_id, version),
+            faults: Vec::new(),
+        }
+    }
+
+    /// Inject a fault into each subsequent run.
+    pub fn with_fault(mut self, fault: DapSimulatorFault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// The registry to which the Leader's and Helper's metrics are written. Leader metrics are
+    /// prefixed with "test_leader" and Helper metrics with "test_helper".
+    pub fn prometheus_registry(&self) -> &prometheus::Registry {
+        &self.t.prometheus_registry
+    }
+
+    /// Generate a report for each measurement, aggregate the reports, and collect the result. If
+    /// either Aggregator aborts, then the abort is returned.
+    pub async fn run(&self, measurements: Vec<DapMeasurement>) -> Result<DapSimulation, DapAbort> {
+        let batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: self.t.task_config.quantized_time_lower_bound(self.t.now),
+                duration: self.t.task_config.time_precision,
+            },
+        };
+
+        // Clients: Upload
+        let reports = self.t.produce_reports(measurements);
+        let replayed = self
+            .faults
+            .iter()
+            .filter_map(|fault| match fault {
+                DapSimulatorFault::ReplayReport(index) => Some(
+                    reports
+                        .get(*index)
+                        .cloned()
+                        .ok_or_else(|| fault_out_of_range(fault)),
+                ),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Aggregators: Aggregation
+        let mut leader_agg_share = DapAggregateShare::default();
+        let mut helper_agg_share = DapAggregateShare::default();
+        let mut first_job = true;
+        for reports in [reports, replayed] {
+            if reports.is_empty() {
+                continue;
+            }
+            let faults = if first_job { &self.faults[..] } else { &[] };
+            first_job = false;
+            if let Some((leader_share_span, helper_share_span)) =
+                self.run_agg_job(reports, faults).await?
+            {
+                leader_agg_share.merge(leader_share_span.collapsed())?;
+                helper_agg_share.merge(helper_share_span.collapsed())?;
+            }
+        }
+
+        // Collector: Collection
+        let report_count = leader_agg_share.report_count;
+        if report_count != helper_agg_share.report_count {
+            return Err(fatal_error!(
+                err = "Aggregators disagree on the number of reports aggregated",
+                leader = report_count,
+                helper = helper_agg_share.report_count,
+            )
+            .into());
+        }
+        if report_count == 0 {
+            return Ok(DapSimulation {
+                report_count,
+                result: None,
+            });
+        }
+        let enc_agg_shares = vec![
+            self.t
+                .produce_leader_encrypted_agg_share(&batch_selector, &leader_agg_share),
+            self.t
+                .produce_helper_encrypted_agg_share(&batch_selector, &helper_agg_share),
+        ];
+        let result = self
+            .t
+            .consume_encrypted_agg_shares(&batch_selector, report_count, enc_agg_shares)
+            .await;
+        Ok(DapSimulation {
+            report_count,
+            result: Some(result),
+        })
+    }
+
+    /// Run an aggregation job for the given reports, injecting the given faults. If the job
+    /// completes, then the return value is the span of output shares committed by the Leader and
+    /// Helper respectively.
+    async fn run_agg_job(
+        &self,
+        reports: Vec<Report>,
+        faults: &[DapSimulatorFault],
+    ) -> Result<Option<(DapAggregateShareSpan, DapAggregateShareSpan)>, DapAbort> {
+        let t = &self.t;
+        let leader_metrics = t
+            .leader_metrics
+            .with_host(t.task_config.leader_url.host_str().unwrap());
+        let helper_metrics = t
+            .helper_metrics
+            .with_host(t.task_config.helper_url.host_str().unwrap());
+
+        let (leader_state, agg_job_init_req) = match t
+            .task_config
+            .vdaf
+            .produce_agg_job_init_req(
+                &t.leader_hpke_receiver_config,
+                t,
+                &t.task_id,
+                &t.task_config,
+                &t.task_config.vdaf_verify_key,
+                &t.agg_job_id,
+                &PartialBatchSelector::TimeInterval,
+                reports,
+                &leader_metrics,
+            )
+            .await?
+        {
+            DapLeaderTransition::Continue(leader_state, agg_job_init_req) => {
+                (leader_state, agg_job_init_req)
+            }
+            DapLeaderTransition::Skip => return Ok(None),
+            DapLeaderTransition::Uncommitted(..) => {
+                return Err(fatal_error!(err = "unexpected transition").into())
+            }
+        };
+
+        let helper_init = || async {
+            t.task_config
+                .vdaf
+                .handle_agg_job_init_req(
+                    &t.helper_hpke_receiver_config,
+                    t,
+                    &t.task_id,
+                    &t.task_config,
+                    &t.task_config.vdaf_verify_key,
+                    &t.agg_job_id,
+                    &AggregationJobInitReqRef::from(&agg_job_init_req),
+                    None,
+                    &helper_metrics,
+                )
+                .await
+        };
+        if faults.contains(&DapSimulatorFault::DropAggJobInitResp) {
+            helper_init().await?;
+        }
+        let DapHelperTransition::Continue(helper_state, agg_job_resp) = helper_init().await? else {
+            return Err(fatal_error!(err = "unexpected transition").into());
+        };
+        let mut agg_job_resp = AggregationJobResp::get_decoded(&agg_job_resp.finish())
+            .map_err(|e| DapAbort::from_codec_error(e, t.task_id.clone()))?;
+
+        for fault in faults {
+            if let DapSimulatorFault::CorruptTransition(index) = fault {
+                agg_job_resp
+                    .transitions
+                    .get_mut(*index)
+                    .ok_or_else(|| fault_out_of_range(fault))?
+                    .report_id = ReportId(thread_rng().gen());
+            }
+        }
+
+        let (leader_uncommitted, agg_job_cont_req) = match t.task_config.vdaf.handle_agg_job_resp(
+            &t.task_id,
+            &t.agg_job_id,
+            leader_state,
+            agg_job_resp,
+            t.task_config.version,
+            &leader_metrics,
+        )? {
+            DapLeaderTransition::Uncommitted(leader_uncommitted, agg_job_cont_req) => {
+                (leader_uncommitted, agg_job_cont_req)
+            }
+            DapLeaderTransition::Skip => return Ok(None),
+            DapLeaderTransition::Continue(..) => {
+                return Err(fatal_error!(err = "unexpected transition").into())
+            }
+        };
+
+        let (helper_share_span, agg_job_resp) = t.task_config.vdaf.handle_agg_job_cont_req(
+            &t.task_id,
+            &t.task_config,
+            &helper_state,
+            |_| false,
+            &t.agg_job_id,
+            &agg_job_cont_req,
+            &helper_metrics,
+        )?;
+        let leader_share_span = t.task_config.vdaf.handle_final_agg_job_resp(
+            &t.task_config,
+            leader_uncommitted,
+            agg_job_resp,
+            &leader_metrics,
+        )?;
+        Ok(Some((leader_share_span, helper_share_span)))
+    }
+}
+
 // These are declarative macros which let us generate a test point for
 // each DapVersion given a test which takes a version parameter.
 //
@@ -550,21 +934,23 @@ impl From<DapBatchBucket> for PartialBatchSelector {
     fn from(bucket: DapBatchBucket) -> Self {
         match bucket {
             DapBatchBucket::FixedSize { batch_id } => Self::FixedSizeByBatchId { batch_id },
-            DapBatchBucket::TimeInterval { .. } => Self::TimeInterval,
+            DapBatchBucket::TimeInterval { .. } | DapBatchBucket::TimeIntervalSub { .. } => {
+                Self::TimeInterval
+            }
         }
     }
 }
 
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
-pub struct MockAggregatorReportSelector(pub(crate) TaskId);
+pub struct MockAggregatorReportSelector(pub TaskId);
 
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct MockAuditLog(AtomicU32);
 
 impl MockAuditLog {
-    #[allow(dead_code)]
-    pub(crate) fn invocations(&self) -> u32 {
+    /// Number of audit log entries recorded so far.
+    pub fn invocations(&self) -> u32 {
         self.0.load(Ordering::Relaxed)
     }
 }
@@ -582,6 +968,46 @@ impl AuditLog for MockAuditLog {
     }
 }
 
+/// Clock used by [`MockAggregator`]. By default the clock follows the system time; once it is
+/// set, it only moves when told to. Clones share the same underlying time.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock(Arc<Mutex<Option<Time>>>);
+
+impl MockClock {
+    /// Create a clock stopped at `now`.
+    pub fn frozen_at(now: Time) -> Self {
+        Self(Arc::new(Mutex::new(Some(now))))
+    }
+
+    /// Return the current time.
+    pub fn now(&self) -> Time {
+        let frozen = *self.0.lock().expect("clock: failed to lock");
+        frozen.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        })
+    }
+
+    /// Stop the clock at `now`.
+    pub fn set(&self, now: Time) {
+        *self.0.lock().expect("clock: failed to lock") = Some(now);
+    }
+
+    /// Move the clock forward by `secs` seconds and stop it there.
+    pub fn advance(&self, secs: u64) {
+        let now = self.now();
+        self.set(now + secs);
+    }
+}
+
+impl TimeSource for MockClock {
+    fn now(&self) -> Time {
+        MockClock::now(self)
+    }
+}
+
 pub struct MockAggregator {
     pub global_config: DapGlobalConfig,
     pub tasks: Arc<Mutex<HashMap<TaskId, DapTaskConfig>>>,
@@ -591,15 +1017,30 @@ pub struct MockAggregator {
     pub report_store: Arc<Mutex<HashMap<TaskId, ReportStore>>>,
     pub leader_state_store: Arc<Mutex<HashMap<TaskId, LeaderState>>>,
     pub helper_state_store: Arc<Mutex<HashMap<HelperStateInfo, DapHelperState>>>,
+    #[allow(clippy::type_complexity)]
+    pub agg_job_cont_resp_store:
+        Arc<Mutex<HashMap<HelperStateInfo, (u16, [u8; 32], AggregationJobResp)>>>,
+    pub helper_state_claims: Arc<Mutex<HashMap<HelperStateInfo, HelperStateClaim>>>,
     pub agg_store: Arc<Mutex<HashMap<TaskId, HashMap<DapBatchBucket, AggStore>>>>,
     pub collector_hpke_config: HpkeConfig,
+    pub collector_hpke_config_overrides: Arc<Mutex<HashMap<TaskId, HpkeConfig>>>,
     pub metrics: DaphneMetrics,
     pub audit_log: MockAuditLog,
+    pub clock: MockClock,
+    pub agg_job_memory_budget: MemoryBudget,
+
+    // Leader: Number of upcoming requests to the peer that fail with a transient network error
+    // before reaching the peer.
+    pub peer_transient_failures: AtomicU32,
+
+    // Leader: Number of upcoming reports that fail to be stored with a storage error.
+    pub report_storage_failures: AtomicU32,
 
     // taskprov
     pub taskprov_vdaf_verify_key_init: [u8; 32],
     pub taskprov_leader_token: BearerToken,
     pub taskprov_collector_token: Option<BearerToken>, // Not set by Helper
+    pub taskprov_rejection_cache: TaskprovRejectionCache,
 
     // Leader: Reference to peer. Used to simulate HTTP requests from Leader to Helper, i.e.,
     // implement `DapLeader::send_http_post()` for `MockAggregator`. Not set by the Helper.
@@ -618,15 +1059,25 @@ impl DeepSizeOf for MockAggregator {
                 + self.report_store.deep_size_of_children(context)
                 + self.leader_state_store.deep_size_of_children(context)
                 + self.helper_state_store.deep_size_of_children(context)
+                + self.agg_job_cont_resp_store.deep_size_of_children(context)
+                + self.helper_state_claims.deep_size_of_children(context)
                 + self.agg_store.deep_size_of_children(context)
                 + self.collector_hpke_config.deep_size_of_children(context)
+                + self
+                    .collector_hpke_config_overrides
+                    .deep_size_of_children(context)
                 // + self.metrics.deep_size_of_children(context)
                 // + self.audit_log.deep_size_of_children(context)
+                // + self.clock.deep_size_of_children(context)
+                // + self.agg_job_memory_budget.deep_size_of_children(context)
+                // + self.peer_transient_failures.deep_size_of_children(context)
+                // + self.report_storage_failures.deep_size_of_children(context)
                 + self
                     .taskprov_vdaf_verify_key_init
                     .deep_size_of_children(context)
                 + self.taskprov_leader_token.deep_size_of_children(context)
                 + self.taskprov_collector_token.deep_size_of_children(context)
+                // + self.taskprov_rejection_cache.deep_size_of_children(context)
                 + self.peer.deep_size_of_children(context)
     }
 }
@@ -652,13 +1103,21 @@ impl MockAggregator {
             report_store: Default::default(),
             leader_state_store: Default::default(),
             helper_state_store: Default::default(),
+            agg_job_cont_resp_store: Default::default(),
+            helper_state_claims: Default::default(),
             agg_store: Default::default(),
             collector_hpke_config,
+            collector_hpke_config_overrides: Default::default(),
             metrics: DaphneMetrics::register(registry, Some("test_helper")).unwrap(),
             audit_log: MockAuditLog::default(),
+            clock: MockClock::default(),
+            agg_job_memory_budget: MemoryBudget::new(),
+            peer_transient_failures: AtomicU32::new(0),
+            report_storage_failures: AtomicU32::new(0),
             taskprov_vdaf_verify_key_init,
             taskprov_leader_token,
             taskprov_collector_token: None,
+            taskprov_rejection_cache: TaskprovRejectionCache::new(300),
             peer: None,
         }
     }
@@ -686,17 +1145,32 @@ impl MockAggregator {
             report_store: Default::default(),
             leader_state_store: Default::default(),
             helper_state_store: Default::default(),
+            agg_job_cont_resp_store: Default::default(),
+            helper_state_claims: Default::default(),
             agg_store: Default::default(),
             collector_hpke_config,
+            collector_hpke_config_overrides: Default::default(),
             metrics: DaphneMetrics::register(registry, Some("test_leader")).unwrap(),
             audit_log: MockAuditLog::default(),
+            clock: MockClock::default(),
+            agg_job_memory_budget: MemoryBudget::new(),
+            peer_transient_failures: AtomicU32::new(0),
+            report_storage_failures: AtomicU32::new(0),
             taskprov_vdaf_verify_key_init,
             taskprov_leader_token,
             taskprov_collector_token: taskprov_collector_token.into(),
+            taskprov_rejection_cache: TaskprovRejectionCache::new(300),
             peer: peer.into(),
         }
     }
 
+    /// Use `clock` as the source of time. To keep the Leader and Helper in sync, pass clones of
+    /// the same clock to both.
+    pub fn with_clock(mut self, clock: MockClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Conducts checks on a received report to see whether:
     /// 1) the report falls into a batch that has been already collected, or
     /// 2) the report has been submitted by the client in the past.
@@ -727,6 +1201,22 @@ impl MockAggregator {
         None
     }
 
+    fn peer(&self) -> Result<&Self, DapError> {
+        let failures = self.peer_transient_failures.load(Ordering::Relaxed);
+        if failures > 0 {
+            self.peer_transient_failures
+                .store(failures - 1, Ordering::Relaxed);
+            return Err(fatal_error!(
+                err = "simulated network failure",
+                code = Network
+            ));
+        }
+
+        self.peer
+            .as_deref()
+            .ok_or_else(|| fatal_error!(err = "peer not configured"))
+    }
+
     fn get_hpke_receiver_config_for(&self, hpke_config_id: u8) -> Option<&HpkeReceiverConfig> {
         self.hpke_receiver_config_list
             .iter()
@@ -741,12 +1231,12 @@ impl MockAggregator {
         report: &Report,
         task_id: &TaskId,
     ) -> Option<DapBatchBucket> {
-        let mut rng = thread_rng();
         let task_config = self
             .get_task_config_for(Cow::Borrowed(task_id))
             .await
             .unwrap()
             .expect("tasks: unrecognized task");
+        let mut rng = thread_rng();
 
         match task_config.query {
             // For fixed-size queries, the bucket corresponds to a single batch.
@@ -775,17 +1265,17 @@ impl MockAggregator {
                 Some(DapBatchBucket::FixedSize { batch_id })
             }
 
-            // For time-interval queries, the bucket is the batch window computed by truncating the
-            // report timestamp.
-            DapQueryConfig::TimeInterval => Some(DapBatchBucket::TimeInterval {
-                batch_window: task_config.quantized_time_lower_bound(report.report_metadata.time),
-            }),
+            // For time-interval queries, the bucket is the batch window (or sub-bucket) computed by
+            // truncating the report timestamp.
+            DapQueryConfig::TimeInterval => {
+                Some(task_config.time_interval_bucket(report.report_metadata.time))
+            }
         }
     }
 
     /// Return the ID of the batch currently being filled with reports. Panics unless the task is
     /// configured for fixed-size queries.
-    pub(crate) fn current_batch_id(
+    pub fn current_batch_id(
         &self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
@@ -808,7 +1298,8 @@ impl MockAggregator {
             .map(|(batch_id, _report_count)| batch_id)
     }
 
-    pub(crate) async fn unchecked_get_task_config(&self, task_id: &TaskId) -> DapTaskConfig {
+    /// Return the configuration of a task. Panics if the task is not known.
+    pub async fn unchecked_get_task_config(&self, task_id: &TaskId) -> DapTaskConfig {
         self.get_task_config_for(Cow::Borrowed(task_id))
             .await
             .expect("encountered unexpected error")
@@ -816,7 +1307,8 @@ impl MockAggregator {
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl BearerTokenProvider for MockAggregator {
     type WrappedBearerToken<'a> = &'a BearerToken;
 
@@ -849,7 +1341,8 @@ impl BearerTokenProvider for MockAggregator {
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl HpkeDecrypter for MockAggregator {
     type WrappedHpkeConfig<'a> = &'a HpkeConfig;
 
@@ -875,6 +1368,21 @@ impl HpkeDecrypter for MockAggregator {
         Ok(&self.hpke_receiver_config_list[0].config)
     }
 
+    async fn get_hpke_config_list_for(
+        &self,
+        version: DapVersion,
+        task_id: Option<&TaskId>,
+    ) -> Result<Vec<HpkeConfig>, DapError> {
+        // Check that the task ID is specified.
+        self.get_hpke_config_for(version, task_id).await?;
+
+        Ok(self
+            .hpke_receiver_config_list
+            .iter()
+            .map(|receiver| receiver.config.clone())
+            .collect())
+    }
+
     async fn can_hpke_decrypt(&self, _task_id: &TaskId, config_id: u8) -> Result<bool, DapError> {
         Ok(self.get_hpke_receiver_config_for(config_id).is_some())
     }
@@ -884,18 +1392,19 @@ impl HpkeDecrypter for MockAggregator {
         _task_id: &TaskId,
         info: &[u8],
         aad: &[u8],
-        ciphertext: &HpkeCiphertext,
+        ciphertext: HpkeCiphertextRef<'_>,
     ) -> Result<Vec<u8>, DapError> {
         if let Some(hpke_receiver_config) = self.get_hpke_receiver_config_for(ciphertext.config_id)
         {
-            Ok(hpke_receiver_config.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)?)
+            Ok(hpke_receiver_config.decrypt(info, aad, ciphertext.enc, ciphertext.payload)?)
         } else {
             Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId))
         }
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl DapAuthorizedSender<BearerToken> for MockAggregator {
     async fn authorize(
         &self,
@@ -911,20 +1420,36 @@ impl DapAuthorizedSender<BearerToken> for MockAggregator {
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl DapReportInitializer for MockAggregator {
     async fn initialize_reports<'req>(
         &self,
         is_leader: bool,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        vdaf_verify_key: &VdafVerifyKey,
         part_batch_sel: &PartialBatchSelector,
         consumed_reports: Vec<EarlyReportStateConsumed<'req>>,
     ) -> Result<Vec<EarlyReportStateInitialized<'req>>, DapError> {
-        let span = task_config.batch_span_for_meta(
-            part_batch_sel,
-            consumed_reports.iter().filter(|report| report.is_ready()),
-        )?;
+        let span = {
+            let guard = self
+                .report_store
+                .lock()
+                .expect("report_store: failed to lock");
+            let precomputed_batch_buckets = guard
+                .get(task_id)
+                .map(|report_store| &report_store.precomputed_batch_buckets);
+            task_config.batch_span_for_meta_precomputed(
+                part_batch_sel,
+                consumed_reports.iter().filter(|report| report.is_ready()),
+                |metadata| {
+                    precomputed_batch_buckets
+                        .and_then(|buckets| buckets.get(&metadata.id))
+                        .cloned()
+                },
+            )?
+        };
 
         let mut early_fails = HashMap::new();
         for (bucket, reports_consumed_per_bucket) in span.iter() {
@@ -949,11 +1474,14 @@ impl DapReportInitializer for MockAggregator {
                     Ok(EarlyReportStateInitialized::Rejected {
                         metadata: Cow::Owned(consumed.metadata().clone()),
                         failure: *failure,
+                        panicked: false,
                     })
                 } else {
                     EarlyReportStateInitialized::initialize(
                         is_leader,
-                        &task_config.vdaf_verify_key,
+                        &self.global_config,
+                        task_id,
+                        vdaf_verify_key,
                         &task_config.vdaf,
                         consumed,
                     )
@@ -963,7 +1491,8 @@ impl DapReportInitializer for MockAggregator {
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl DapAggregator<BearerToken> for MockAggregator {
     // The lifetimes on the traits ensure that we can return a reference to a task config stored by
     // the DapAggregator. (See DaphneWorkerConfig for an example.) For simplicity, MockAggregator
@@ -990,6 +1519,10 @@ impl DapAggregator<BearerToken> for MockAggregator {
         Some(&self.collector_hpke_config)
     }
 
+    fn taskprov_rejection_cache(&self) -> &TaskprovRejectionCache {
+        &self.taskprov_rejection_cache
+    }
+
     fn taskprov_opt_out_reason(
         &self,
         _task_config: &DapTaskConfig,
@@ -1017,17 +1550,27 @@ impl DapAggregator<BearerToken> for MockAggregator {
         Ok(tasks.get(task_id.as_ref()).cloned())
     }
 
-    fn get_current_time(&self) -> Time {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+    fn time_source(&self) -> &dyn TimeSource {
+        &self.clock
+    }
+
+    async fn get_collector_hpke_config_for(
+        &self,
+        task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+    ) -> Result<Option<HpkeConfig>, DapError> {
+        let overrides = self
+            .collector_hpke_config_overrides
+            .lock()
+            .expect("collector_hpke_config_overrides: failed to lock");
+        Ok(overrides.get(task_id).cloned())
     }
 
     async fn is_batch_overlapping(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param_hash: &DapAggregationParamHash,
     ) -> Result<bool, DapError> {
         let task_config = self
             .get_task_config_for(Cow::Borrowed(task_id))
@@ -1043,7 +1586,10 @@ impl DapAggregator<BearerToken> for MockAggregator {
 
         for bucket in task_config.batch_span_for_sel(batch_sel)? {
             if let Some(inner_agg_store) = agg_store.get(&bucket) {
-                if inner_agg_store.collected {
+                if inner_agg_store
+                    .collected_agg_params
+                    .contains(agg_param_hash)
+                {
                     return Ok(true);
                 }
             }
@@ -1103,6 +1649,9 @@ impl DapAggregator<BearerToken> for MockAggregator {
             .collect::<HashSet<_>>();
 
         if replayed.is_empty() {
+            for id in &all_ids {
+                report_store.precomputed_batch_buckets.remove(id);
+            }
             report_store.processed.extend(all_ids);
             for (bucket, out_share) in to_merge {
                 // Add to aggregate share.
@@ -1135,21 +1684,64 @@ impl DapAggregator<BearerToken> for MockAggregator {
         let mut agg_share = DapAggregateShare::default();
         for bucket in task_config.batch_span_for_sel(batch_sel)? {
             if let Some(inner_agg_store) = agg_store.get(&bucket) {
-                if inner_agg_store.collected {
-                    return Err(DapError::Abort(DapAbort::batch_overlap(task_id, batch_sel)));
-                } else {
-                    agg_share.merge(inner_agg_store.agg_share.clone())?;
-                }
+                agg_share.merge(inner_agg_store.agg_share.clone())?;
             }
         }
 
         Ok(agg_share)
     }
 
+    async fn get_agg_share_meta(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<DapAggregateShareMeta, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let guard = self.agg_store.lock().expect("agg_store: failed to lock");
+        let mut meta = DapAggregateShareMeta::default();
+        let Some(agg_store) = guard.get(task_id) else {
+            return Ok(meta);
+        };
+
+        for bucket in task_config.batch_span_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) = agg_store.get(&bucket) {
+                meta.merge(DapAggregateShareMeta {
+                    collected: inner_agg_store.collected,
+                    ..inner_agg_store.agg_share.meta()
+                });
+            }
+        }
+
+        Ok(meta)
+    }
+
+    async fn get_agg_share_per_bucket(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<(DapBatchBucket, DapAggregateShare)>, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let guard = self.agg_store.lock().expect("agg_store: failed to lock");
+        let Some(agg_store) = guard.get(task_id) else {
+            return Ok(Vec::new());
+        };
+
+        let mut agg_shares = Vec::new();
+        for bucket in task_config.batch_span_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) = agg_store.get(&bucket) {
+                let agg_share = inner_agg_store.agg_share.clone();
+                agg_shares.push((bucket, agg_share));
+            }
+        }
+
+        Ok(agg_shares)
+    }
+
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param_hash: &DapAggregationParamHash,
     ) -> Result<(), DapError> {
         let task_config = self.unchecked_get_task_config(task_id).await;
         let mut guard = self.agg_store.lock().expect("agg_store: failed to lock");
@@ -1158,12 +1750,71 @@ impl DapAggregator<BearerToken> for MockAggregator {
         for bucket in task_config.batch_span_for_sel(batch_sel)? {
             if let Some(inner_agg_store) = agg_store.get_mut(&bucket) {
                 inner_agg_store.collected = true;
+                inner_agg_store.collected_agg_params.insert(*agg_param_hash);
             }
         }
 
         Ok(())
     }
 
+    async fn purge_task(&self, task_id: &TaskId) -> Result<DapTaskPurgeSummary, DapError> {
+        let mut summary = DapTaskPurgeSummary::default();
+
+        if let Some(report_store) = self
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock")
+            .remove(task_id)
+        {
+            summary.reports_pending = report_store
+                .pending
+                .values()
+                .map(VecDeque::len)
+                .sum::<usize>() as u64;
+            summary.reports_processed = report_store.processed.len() as u64;
+        }
+
+        if let Some(agg_store) = self
+            .agg_store
+            .lock()
+            .expect("agg_store: failed to lock")
+            .remove(task_id)
+        {
+            summary.agg_shares = agg_store.len() as u64;
+        }
+
+        {
+            let mut helper_state_store = self
+                .helper_state_store
+                .lock()
+                .expect("helper_state_store: failed to lock");
+            let count_before = helper_state_store.len();
+            helper_state_store.retain(|info, _| &info.task_id != task_id);
+            summary.helper_states = (count_before - helper_state_store.len()) as u64;
+        }
+
+        self.agg_job_cont_resp_store
+            .lock()
+            .expect("agg_job_cont_resp_store: failed to lock")
+            .retain(|info, _| &info.task_id != task_id);
+
+        self.helper_state_claims
+            .lock()
+            .expect("helper_state_claims: failed to lock")
+            .retain(|info, _| &info.task_id != task_id);
+
+        if let Some(leader_state) = self
+            .leader_state_store
+            .lock()
+            .expect("leader_state_store: failed to lock")
+            .remove(task_id)
+        {
+            summary.collect_jobs = leader_state.collect_jobs.len() as u64;
+        }
+
+        Ok(summary)
+    }
+
     async fn current_batch(&self, task_id: &TaskId) -> std::result::Result<BatchId, DapError> {
         let task_config = self.unchecked_get_task_config(task_id).await;
         if let Some(id) = self.current_batch_id(task_id, &task_config) {
@@ -1184,8 +1835,13 @@ impl DapAggregator<BearerToken> for MockAggregator {
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl DapHelper<BearerToken> for MockAggregator {
+    fn agg_job_memory_budget(&self) -> &MemoryBudget {
+        &self.agg_job_memory_budget
+    }
+
     async fn put_helper_state_if_not_exists(
         &self,
         task_id: &TaskId,
@@ -1204,7 +1860,13 @@ impl DapHelper<BearerToken> for MockAggregator {
 
         let helper_state_store = helper_state_store_mutex_guard.deref_mut();
 
-        if helper_state_store.contains_key(&helper_state_info) {
+        if helper_state_store.contains_key(&helper_state_info)
+            || self
+                .helper_state_claims
+                .lock()
+                .map_err(|e| fatal_error!(err = ?e))?
+                .contains_key(&helper_state_info)
+        {
             return Ok(false);
         }
 
@@ -1215,7 +1877,7 @@ impl DapHelper<BearerToken> for MockAggregator {
         Ok(true)
     }
 
-    async fn get_helper_state(
+    async fn claim_helper_state(
         &self,
         task_id: &TaskId,
         agg_job_id: &MetaAggregationJobId,
@@ -1225,28 +1887,165 @@ impl DapHelper<BearerToken> for MockAggregator {
             agg_job_id_owned: agg_job_id.into(),
         };
 
-        let mut helper_state_store_mutex_guard = self
-            .helper_state_store
+        let now = self.clock.now();
+        let mut helper_state_claims = self
+            .helper_state_claims
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?;
-
-        let helper_state_store = helper_state_store_mutex_guard.deref_mut();
+        match helper_state_claims.get(&helper_state_info) {
+            Some(HelperStateClaim::Acked) => return Ok(None),
+            Some(HelperStateClaim::InFlight { claimed_at })
+                if now < claimed_at + MOCK_HELPER_STATE_CLAIM_TIMEOUT_SECS =>
+            {
+                return Err(DapAbort::TooManyRequests {
+                    detail: "The aggregation job is being processed by another request.".into(),
+                    retry_after: Some(claimed_at + MOCK_HELPER_STATE_CLAIM_TIMEOUT_SECS - now),
+                }
+                .into());
+            }
+            _ => (),
+        }
 
         // NOTE: This code is only correct for VDAFs with exactly one round of preparation.
         // For VDAFs with more rounds, the helper state blob will need to be updated here.
-        Ok(helper_state_store.get(&helper_state_info).cloned())
+        let helper_state = self
+            .helper_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .get(&helper_state_info)
+            .cloned();
+        if helper_state.is_some() {
+            helper_state_claims.insert(
+                helper_state_info,
+                HelperStateClaim::InFlight { claimed_at: now },
+            );
+        }
+        Ok(helper_state)
+    }
+
+    async fn ack_helper_state(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: task_id.clone(),
+            agg_job_id_owned: agg_job_id.into(),
+        };
+
+        self.helper_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .remove(&helper_state_info);
+        self.helper_state_claims
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .insert(helper_state_info, HelperStateClaim::Acked);
+        Ok(())
+    }
+
+    async fn put_agg_job_cont_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        round: u16,
+        req_digest: &[u8; 32],
+        agg_job_resp: &AggregationJobResp,
+    ) -> Result<(), DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: task_id.clone(),
+            agg_job_id_owned: agg_job_id.into(),
+        };
+
+        self.agg_job_cont_resp_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .insert(
+                helper_state_info,
+                (round, *req_digest, agg_job_resp.clone()),
+            );
+        Ok(())
+    }
+
+    async fn get_agg_job_cont_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<(u16, [u8; 32], AggregationJobResp)>, DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: task_id.clone(),
+            agg_job_id_owned: agg_job_id.into(),
+        };
+
+        // The output shares may not have been committed unless the state was acknowledged.
+        if !matches!(
+            self.helper_state_claims
+                .lock()
+                .map_err(|e| fatal_error!(err = ?e))?
+                .get(&helper_state_info),
+            Some(HelperStateClaim::Acked)
+        ) {
+            return Ok(None);
+        }
+
+        Ok(self
+            .agg_job_cont_resp_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .get(&helper_state_info)
+            .cloned())
+    }
+
+    async fn delete_helper_state(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: task_id.clone(),
+            agg_job_id_owned: agg_job_id.into(),
+        };
+
+        self.helper_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .remove(&helper_state_info);
+        self.agg_job_cont_resp_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .remove(&helper_state_info);
+        self.helper_state_claims
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .insert(helper_state_info, HelperStateClaim::Acked);
+        Ok(())
     }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send-traits"), async_trait(?Send))]
+#[cfg_attr(feature = "send-traits", async_trait)]
 impl DapLeader<BearerToken> for MockAggregator {
     type ReportSelector = MockAggregatorReportSelector;
 
     async fn put_report(&self, report: &Report, task_id: &TaskId) -> Result<(), DapError> {
+        let failures = self.report_storage_failures.load(Ordering::Relaxed);
+        if failures > 0 {
+            self.report_storage_failures
+                .store(failures - 1, Ordering::Relaxed);
+            return Err(fatal_error!(
+                err = "simulated storage failure",
+                code = Storage
+            ));
+        }
+
         let bucket = self
             .assign_report_to_bucket(report, task_id)
             .await
             .expect("could not determine batch for report");
+        let precomputed_batch_bucket = self
+            .unchecked_get_task_config(task_id)
+            .await
+            .precompute_batch_bucket(report.report_metadata.time);
 
         // Check whether Report has been collected or replayed.
         if let Some(transition_failure) = self
@@ -1256,25 +2055,79 @@ impl DapLeader<BearerToken> for MockAggregator {
             return Err(DapError::Transition(transition_failure));
         };
 
-        // Store Report for future processing.
+        // Store Report for future processing, unless a report with the same ID is pending.
         let mut guard = self
             .report_store
             .lock()
             .expect("report_store: failed to lock");
-        let queue = guard
-            .get_mut(task_id)
-            .expect("report_store: unrecognized task")
+        let report_store = guard.entry(task_id.clone()).or_default();
+        if report_store
             .pending
-            .entry(bucket)
-            .or_default();
+            .values()
+            .flatten()
+            .any(|pending| pending.report_metadata.id == report.report_metadata.id)
+        {
+            return Err(DapError::Transition(TransitionFailure::ReportReplayed));
+        }
+        if let Some(precomputed_batch_bucket) = precomputed_batch_bucket {
+            report_store
+                .precomputed_batch_buckets
+                .insert(report.report_metadata.id.clone(), precomputed_batch_bucket);
+        }
+        let queue = report_store.pending.entry(bucket).or_default();
         queue.push_back(report.clone());
         Ok(())
     }
 
+    async fn find_duplicate_reports(
+        &self,
+        task_id: &TaskId,
+        reports: &[&ReportMetadata],
+    ) -> Result<HashSet<ReportId>, DapError> {
+        let guard = self
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock");
+        let Some(report_store) = guard.get(task_id) else {
+            return Ok(HashSet::new());
+        };
+        Ok(reports
+            .iter()
+            .filter(|metadata| {
+                report_store.processed.contains(&metadata.id)
+                    || report_store
+                        .pending
+                        .values()
+                        .flatten()
+                        .any(|report| report.report_metadata.id == metadata.id)
+            })
+            .map(|metadata| metadata.id.clone())
+            .collect())
+    }
+
+    async fn is_identical_report_pending(
+        &self,
+        report: &Report,
+        task_id: &TaskId,
+    ) -> Result<bool, DapError> {
+        let guard = self
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock");
+        Ok(guard.get(task_id).is_some_and(|report_store| {
+            report_store
+                .pending
+                .values()
+                .flatten()
+                .any(|pending| pending == report)
+        }))
+    }
+
     async fn get_reports(
         &self,
         report_sel: &MockAggregatorReportSelector,
-    ) -> Result<HashMap<TaskId, HashMap<PartialBatchSelector, Vec<Report>>>, DapError> {
+    ) -> Result<HashMap<(TaskId, DapVersion), HashMap<PartialBatchSelector, Vec<Report>>>, DapError>
+    {
         let task_id = &report_sel.0;
         let task_config = self.unchecked_get_task_config(task_id).await;
         let mut guard = self
@@ -1284,6 +2137,7 @@ impl DapLeader<BearerToken> for MockAggregator {
         let report_store = guard.entry(task_id.clone()).or_default();
 
         // For the task indicated by the report selector, choose a single report to aggregate.
+        // Reports are assumed to have been uploaded in the task's primary version.
         match task_config.query {
             DapQueryConfig::TimeInterval { .. } => {
                 // Aggregate reports in any order.
@@ -1295,7 +2149,7 @@ impl DapLeader<BearerToken> for MockAggregator {
                     }
                 }
                 return Ok(HashMap::from([(
-                    task_id.clone(),
+                    (task_id.clone(), task_config.version),
                     HashMap::from([(PartialBatchSelector::TimeInterval, reports)]),
                 )]));
             }
@@ -1314,25 +2168,76 @@ impl DapLeader<BearerToken> for MockAggregator {
                     .expect("report_store: unknown bucket");
                 let reports = queue.drain(..1).collect();
                 return Ok(HashMap::from([(
-                    task_id.clone(),
+                    (task_id.clone(), task_config.version),
                     HashMap::from([(bucket.into(), reports)]),
                 )]));
             }
         }
     }
 
+    async fn peek_reports(
+        &self,
+        task_id: &TaskId,
+        max_reports: u64,
+    ) -> Result<HashMap<DapVersion, Vec<Report>>, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let guard = self
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock");
+
+        // Reports are assumed to have been uploaded in the task's primary version.
+        let reports = guard
+            .get(task_id)
+            .into_iter()
+            .flat_map(|report_store| report_store.pending.values().flatten())
+            .take(max_reports.try_into().unwrap())
+            .cloned()
+            .collect();
+        Ok(HashMap::from([(task_config.version, reports)]))
+    }
+
+    async fn batch_state(
+        &self,
+        task_id: &TaskId,
+        batch_id: &BatchId,
+    ) -> Result<Option<DapBatchState>, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let guard = self
+            .leader_state_store
+            .lock()
+            .expect("leader_state_store: failed to lock");
+        let Some(leader_state_store) = guard.get(task_id) else {
+            return Ok(None);
+        };
+
+        // A batch is filled until it reaches the minimum batch size.
+        Ok(leader_state_store
+            .batch_queue
+            .iter()
+            .find(|(id, _report_count)| id == batch_id)
+            .map(|(_id, report_count)| {
+                if *report_count < task_config.min_batch_size {
+                    DapBatchState::Filling
+                } else {
+                    DapBatchState::Ready
+                }
+            }))
+    }
+
     // Called after receiving a CollectReq from Collector.
     async fn init_collect_job(
         &self,
         task_id: &TaskId,
         collect_job_id: &Option<CollectionJobId>,
         collect_req: &CollectionReq,
+        priority: DapCollectPriority,
     ) -> Result<Url, DapError> {
-        let mut rng = thread_rng();
         let task_config = self
             .get_task_config_for(Cow::Borrowed(task_id))
             .await?
             .ok_or_else(|| fatal_error!(err = "task not found"))?;
+        let mut rng = thread_rng();
 
         let mut leader_state_store_mutex_guard = self
             .leader_state_store
@@ -1355,7 +2260,15 @@ impl DapLeader<BearerToken> for MockAggregator {
 
         // Store Collect ID and CollectReq into LeaderState.
         let leader_state = leader_state_store.entry(task_id.clone()).or_default();
-        leader_state.collect_ids.push_back(collect_id.clone());
+        // Jobs of the same priority are kept in order of arrival.
+        let index = leader_state
+            .collect_ids
+            .iter()
+            .position(|(other_priority, _)| *other_priority < priority)
+            .unwrap_or(leader_state.collect_ids.len());
+        leader_state
+            .collect_ids
+            .insert(index, (priority, collect_id.clone()));
         let collect_job_state = CollectJobState::Pending(collect_req.clone());
         leader_state
             .collect_jobs
@@ -1389,6 +2302,31 @@ impl DapLeader<BearerToken> for MockAggregator {
         }
     }
 
+    async fn list_collect_jobs(
+        &self,
+        task_id: &TaskId,
+    ) -> Result<Vec<DapCollectJobSummary>, DapError> {
+        let leader_state_store = self
+            .leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?;
+
+        let Some(leader_state) = leader_state_store.get(task_id) else {
+            return Ok(Vec::new());
+        };
+        Ok(leader_state
+            .collect_jobs
+            .iter()
+            .map(|(collect_job_id, collect_job_state)| DapCollectJobSummary {
+                collect_job_id: collect_job_id.clone(),
+                status: match collect_job_state {
+                    CollectJobState::Pending(_) => DapCollectJobStatus::Pending,
+                    CollectJobState::Processed(_) => DapCollectJobStatus::Done,
+                },
+            })
+            .collect())
+    }
+
     // Called to retrieve pending CollectReq.
     async fn get_pending_collect_jobs(
         &self,
@@ -1402,14 +2340,20 @@ impl DapLeader<BearerToken> for MockAggregator {
         let mut res = Vec::new();
         for (task_id, leader_state) in leader_state_store.iter() {
             // Iterate over collect IDs and copy them and their associated requests to the response.
-            for collect_id in leader_state.collect_ids.iter() {
+            for (priority, collect_id) in leader_state.collect_ids.iter() {
                 if let CollectJobState::Pending(collect_req) =
                     leader_state.collect_jobs.get(collect_id).unwrap()
                 {
-                    res.push((task_id.clone(), collect_id.clone(), collect_req.clone()));
+                    res.push((
+                        *priority,
+                        (task_id.clone(), collect_id.clone(), collect_req.clone()),
+                    ));
                 }
             }
         }
+        // Higher priority jobs first, regardless of task.
+        res.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+        let res = res.into_iter().map(|(_, job)| job).collect();
         Ok(res)
     }
 
@@ -1451,7 +2395,7 @@ impl DapLeader<BearerToken> for MockAggregator {
                 let index = leader_state
                     .collect_ids
                     .iter()
-                    .position(|r| r == collect_id)
+                    .position(|(_, r)| r == collect_id)
                     .unwrap();
                 leader_state.collect_ids.remove(index);
 
@@ -1464,40 +2408,43 @@ impl DapLeader<BearerToken> for MockAggregator {
     }
 
     async fn send_http_post(&self, req: DapRequest<BearerToken>) -> Result<DapResponse, DapError> {
+        let peer = self.peer()?;
         match req.media_type {
-            DapMediaType::AggregationJobInitReq | DapMediaType::AggregationJobContinueReq => {
-                Ok(self
-                    .peer
-                    .as_ref()
-                    .expect("peer not configured")
-                    .handle_agg_job_req(&req)
-                    .await
-                    .expect("peer aborted unexpectedly"))
+            DapMediaType::AggregationJobInitReq
+            | DapMediaType::AggregationJobContinueReq
+            | DapMediaType::AggregationJobAbandon => {
+                peer.handle_agg_job_req(&req).await.map_err(DapError::Abort)
             }
-            DapMediaType::AggregateShareReq => Ok(self
-                .peer
-                .as_ref()
-                .expect("peer not configured")
+            DapMediaType::AggregateShareReq => peer
                 .handle_agg_share_req(&req)
                 .await
-                .expect("peer aborted unexpectedly")),
-            _ => unreachable!("unhandled media type: {:?}", req.media_type),
+                .map_err(DapError::Abort),
+            _ => Err(fatal_error!(err = "unhandled media type", media_type = ?req.media_type)),
         }
     }
 
     async fn send_http_put(&self, req: DapRequest<BearerToken>) -> Result<DapResponse, DapError> {
+        let peer = self.peer()?;
         if req.media_type == DapMediaType::AggregationJobInitReq {
-            Ok(self
-                .peer
-                .as_ref()
-                .expect("peer not configured")
-                .handle_agg_job_req(&req)
-                .await
-                .expect("peer aborted unexpectedly"))
+            peer.handle_agg_job_req(&req).await.map_err(DapError::Abort)
         } else {
-            unreachable!("unhandled media type: {:?}", req.media_type)
+            Err(fatal_error!(err = "unhandled media type", media_type = ?req.media_type))
         }
     }
+
+    async fn send_http_delete(
+        &self,
+        req: DapRequest<BearerToken>,
+    ) -> Result<DapResponse, DapError> {
+        self.peer()?
+            .handle_agg_job_delete_req(&req)
+            .await
+            .map_err(DapError::Abort)
+    }
+
+    async fn sleep(&self, _duration: std::time::Duration) {
+        // Return immediately so that tests don't have to wait out the backoff.
+    }
 }
 
 /// Information associated to a certain helper state for a given task ID and aggregate job ID.
@@ -1508,12 +2455,29 @@ pub struct HelperStateInfo {
     agg_job_id_owned: MetaAggregationJobIdOwned,
 }
 
+/// Number of seconds after which a claim on the Helper's state by [`MockAggregator`] expires.
+pub const MOCK_HELPER_STATE_CLAIM_TIMEOUT_SECS: u64 = 60;
+
+/// Status of the Helper's state for a given task ID and aggregation job ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub enum HelperStateClaim {
+    /// The state was claimed at the given time and has not yet been acknowledged.
+    InFlight { claimed_at: Time },
+
+    /// The state was consumed or abandoned and deleted.
+    Acked,
+}
+
 /// Stores the reports received from Clients.
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct ReportStore {
     pub(crate) pending: HashMap<DapBatchBucket, VecDeque<Report>>,
     pub(crate) processed: HashSet<ReportId>,
+    /// Batch buckets computed for pending reports upon upload (see
+    /// [`DapTaskConfig::precompute_batch_bucket`]).
+    pub(crate) precomputed_batch_buckets: HashMap<ReportId, DapPrecomputedBatchBucket>,
 }
 
 /// Stores the state of the collect job.
@@ -1524,12 +2488,12 @@ pub enum CollectJobState {
 }
 
 /// LeaderState keeps track of the following:
-/// * Collect IDs in their order of arrival.
+/// * Collect IDs in order of priority, then arrival.
 /// * The state of the collect job associated to the Collect ID.
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct LeaderState {
-    collect_ids: VecDeque<CollectionJobId>,
+    collect_ids: VecDeque<(DapCollectPriority, CollectionJobId)>,
     collect_jobs: HashMap<CollectionJobId, CollectJobState>,
     batch_queue: VecDeque<(BatchId, u64)>, // Batch ID, batch size
 }
@@ -1537,11 +2501,13 @@ pub struct LeaderState {
 /// AggStore keeps track of the following:
 /// * Aggregate share
 /// * Whether this aggregate share has been collected
+/// * The aggregation parameters with which this aggregate share has been collected
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct AggStore {
     pub(crate) agg_share: DapAggregateShare,
     pub(crate) collected: bool,
+    pub(crate) collected_agg_params: HashSet<DapAggregationParamHash>,
 }
 
 /// Helper macro used by `assert_metrics_include`.
@@ -1596,3 +2562,27 @@ macro_rules! assert_metrics_include {
         }
     }}
 }
+
+#[cfg(test)]
+mod test {
+    use super::{MockAggregator, MockClock};
+
+    #[test]
+    fn mock_aggregator_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MockAggregator>();
+    }
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::frozen_at(1337);
+        let shared = clock.clone();
+        assert_eq!(clock.now(), 1337);
+
+        shared.advance(10);
+        assert_eq!(clock.now(), 1347);
+
+        clock.set(0);
+        assert_eq!(shared.now(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Sources of the current time. The Aggregator uses its [`TimeSource`] to validate reports and to
+//! decide when tasks, batches, and collection jobs expire. Injecting the source allows tests and
+//! replays to run deterministically and lets a deployment guard against clock jumps of the
+//! platform it runs on.
+
+use crate::messages::Time;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+/// A source of the current time.
+pub trait TimeSource: Send + Sync {
+    /// Get the current time (number of seconds since the beginning of UNIX time).
+    fn now(&self) -> Time;
+}
+
+/// The system's wall clock. This is not available on `wasm32-unknown-unknown`, where the host
+/// environment is expected to provide its own source.
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> Time {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+}
+
+/// Wraps another source so that time never goes backwards. If the underlying clock jumps
+/// backwards, then the latest time observed so far is returned until the clock catches up.
+pub struct MonotonicTimeSource<T> {
+    inner: T,
+    latest: AtomicU64,
+    backward_jumps: AtomicU64,
+}
+
+impl<T> MonotonicTimeSource<T> {
+    pub const fn new(inner: T) -> Self {
+        Self {
+            inner,
+            latest: AtomicU64::new(0),
+            backward_jumps: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of times the underlying clock was observed to go backwards.
+    pub fn backward_jumps(&self) -> u64 {
+        self.backward_jumps.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: TimeSource> TimeSource for MonotonicTimeSource<T> {
+    fn now(&self) -> Time {
+        let now = self.inner.now();
+        let latest = self.latest.fetch_max(now, Ordering::Relaxed);
+        if now < latest {
+            self.backward_jumps.fetch_add(1, Ordering::Relaxed);
+            return latest;
+        }
+        now
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MockClock;
+
+    #[test]
+    fn monotonic_time_source() {
+        let clock = MockClock::frozen_at(1000);
+        let time_source = MonotonicTimeSource::new(clock.clone());
+        assert_eq!(time_source.now(), 1000);
+
+        clock.advance(10);
+        assert_eq!(time_source.now(), 1010);
+
+        // The clock jumps backwards.
+        clock.set(900);
+        assert_eq!(time_source.now(), 1010);
+        assert_eq!(time_source.backward_jumps(), 1);
+
+        // ... and catches up again.
+        clock.set(1020);
+        assert_eq!(time_source.now(), 1020);
+        assert_eq!(time_source.backward_jumps(), 1);
+    }
+}
@@ -6,32 +6,37 @@
 
 pub mod prio2;
 pub mod prio3;
+#[cfg(feature = "shadow-vdaf")]
+pub mod shadow;
 
 use crate::{
     error::DapAbort,
+    extensions::{check_client_auth, DapClientAuthKey, DapExtensionRegistry},
     fatal_error,
     hpke::{HpkeConfig, HpkeDecrypter},
     messages::{
-        encode_u32_bytes, AggregationJobContinueReq, AggregationJobInitReq, AggregationJobResp,
-        BatchSelector, Extension, HpkeCiphertext, PartialBatchSelector, PlaintextInputShare,
+        encode_u32_bytes, AggregationJobContinueReq, AggregationJobInitReq,
+        AggregationJobInitReqRef, AggregationJobResp, AggregationJobRespWriter, BatchSelector,
+        Extension, HpkeCiphertext, HpkeCiphertextRef, PartialBatchSelector, PlaintextInputShare,
         Report, ReportId, ReportMetadata, ReportShare, TaskId, Time, Transition, TransitionFailure,
         TransitionVar,
     },
     metrics::ContextualizedDaphneMetrics,
+    postprocess::{DapEnrichedAggregateResult, DapResultTransformRegistry},
     roles::DapReportInitializer,
     vdaf::{
         prio2::{
             prio2_decode_prep_state, prio2_prep_finish, prio2_prep_finish_from_shares,
-            prio2_prep_init, prio2_shard, prio2_unshard,
+            prio2_prep_init, prio2_shard, prio2_share_sizes, prio2_unshard, prio2_zero_agg_share,
         },
         prio3::{
             prio3_decode_prep_state, prio3_prep_finish, prio3_prep_finish_from_shares,
-            prio3_prep_init, prio3_shard, prio3_unshard,
+            prio3_prep_init, prio3_shard, prio3_share_sizes, prio3_unshard, prio3_zero_agg_share,
         },
     },
-    DapAggregateResult, DapAggregateShare, DapAggregateShareSpan, DapError, DapHelperState,
-    DapHelperTransition, DapLeaderState, DapLeaderTransition, DapLeaderUncommitted, DapMeasurement,
-    DapOutputShare, DapTaskConfig, DapVersion, MetaAggregationJobId, VdafConfig,
+    DapAggregateResult, DapAggregateShare, DapAggregateShareSpan, DapError, DapGlobalConfig,
+    DapHelperState, DapHelperTransition, DapLeaderState, DapLeaderTransition, DapLeaderUncommitted,
+    DapMeasurement, DapOutputShare, DapTaskConfig, DapVersion, MetaAggregationJobId, VdafConfig,
 };
 use prio::{
     codec::{CodecError, Decode, Encode, ParameterizedDecode, ParameterizedEncode},
@@ -43,7 +48,16 @@ use prio::{
 };
 use rand::prelude::*;
 use serde::{Deserialize, Serialize, Serializer};
-use std::{borrow::Cow, collections::HashSet};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+};
+use tracing::error;
 
 const CTX_INPUT_SHARE_DRAFT02: &[u8] = b"dap-02 input share";
 const CTX_INPUT_SHARE_DRAFT07: &[u8] = b"dap-07 input share";
@@ -83,6 +97,19 @@ impl AsRef<[u8]> for VdafVerifyKey {
     }
 }
 
+/// A set of VDAF verification keys, each identified by a key ID. A task configured with a key set
+/// can have its verification key rotated: the Leader uses the active key for new aggregation jobs
+/// and tells the Helper which key it used.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct VdafVerifyKeySet {
+    /// ID of the key used for new aggregation jobs.
+    pub active_key_id: u8,
+
+    /// The keys recognized by the Aggregators, indexed by key ID.
+    pub keys: BTreeMap<u8, VdafVerifyKey>,
+}
+
 /// Report state during aggregation initialization.
 pub trait EarlyReportState {
     fn metadata(&self) -> &ReportMetadata;
@@ -100,6 +127,10 @@ pub enum EarlyReportStateConsumed<'req> {
         public_share: Cow<'req, [u8]>,
         #[serde(with = "serialize_bytes")]
         input_share: Vec<u8>,
+        /// The report's extensions. These are carried in the report metadata in draft02 and in
+        /// the plaintext input share in later drafts.
+        #[serde(default)]
+        extensions: Vec<Extension>,
     },
     Rejected {
         metadata: Cow<'req, ReportMetadata>,
@@ -107,16 +138,44 @@ pub enum EarlyReportStateConsumed<'req> {
     },
 }
 
+/// Upper bounds on the encoded sizes of the shares of a report for a given VDAF. See
+/// [`VdafConfig::max_share_sizes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VdafShareSizes {
+    /// Size of the public share.
+    pub public_share: usize,
+
+    /// Size of the larger of the Leader's and Helper's input shares, not including the HPKE
+    /// ciphertext expansion or (in the latest draft) the plaintext input share's framing.
+    pub input_share: usize,
+}
+
+impl VdafShareSizes {
+    /// Bound the size of the payload of an encrypted input share. Besides the input share itself,
+    /// the payload includes the AEAD tag and, in the latest draft, the report extensions, whose
+    /// encoding is at most `max_extensions_len` bytes long.
+    fn encrypted_input_share(&self, version: DapVersion, max_extensions_len: u16) -> usize {
+        const AEAD_TAG_SIZE: usize = 16;
+        let framing = match version {
+            DapVersion::Draft02 => 0,
+            // Length-prefixed extensions and payload.
+            _ => 2 + usize::from(max_extensions_len) + 4,
+        };
+        self.input_share + framing + AEAD_TAG_SIZE
+    }
+}
+
 impl<'req> EarlyReportStateConsumed<'req> {
     #[allow(clippy::too_many_arguments)]
     pub(crate) async fn consume(
         decrypter: &impl HpkeDecrypter,
+        extension_registry: &DapExtensionRegistry,
         is_leader: bool,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
         metadata: Cow<'req, ReportMetadata>,
         public_share: Cow<'req, [u8]>,
-        encrypted_input_share: &HpkeCiphertext,
+        encrypted_input_share: HpkeCiphertextRef<'_>,
     ) -> Result<EarlyReportStateConsumed<'req>, DapError> {
         if metadata.time >= task_config.expiration {
             return Ok(Self::Rejected {
@@ -125,27 +184,28 @@ impl<'req> EarlyReportStateConsumed<'req> {
             });
         }
 
-        let input_share_text = match task_config.version {
-            DapVersion::Draft02 => CTX_INPUT_SHARE_DRAFT02,
-            DapVersion::Draft07 => CTX_INPUT_SHARE_DRAFT07,
-            _ => return Err(unimplemented_version()),
+        // Reject oversized shares before spending any effort on them. Such shares would otherwise
+        // only be caught after decryption, deep inside VDAF preparation.
+        let max_share_sizes = match task_config.vdaf.max_share_sizes() {
+            Ok(max_share_sizes) => max_share_sizes,
+            Err(DapError::Transition(failure)) => return Ok(Self::Rejected { metadata, failure }),
+            Err(e) => return Err(e),
         };
-        let n: usize = input_share_text.len();
-        let mut info = Vec::new();
-        info.reserve(n + 2);
-        info.extend_from_slice(input_share_text);
-        info.push(CTX_ROLE_CLIENT); // Sender role (receiver role set below)
-        info.push(if is_leader {
-            CTX_ROLE_LEADER
-        } else {
-            CTX_ROLE_HELPER
-        }); // Receiver role
+        if public_share.len() > max_share_sizes.public_share
+            || encrypted_input_share.payload.len()
+                > max_share_sizes.encrypted_input_share(
+                    task_config.version,
+                    extension_registry.max_extensions_len(),
+                )
+        {
+            return Ok(Self::Rejected {
+                metadata,
+                failure: TransitionFailure::UnrecognizedMessage,
+            });
+        }
 
-        let mut aad = Vec::with_capacity(58);
-        task_id.encode(&mut aad);
-        metadata.encode_with_param(&task_config.version, &mut aad);
-        // TODO spec: Consider folding the public share into a field called "header".
-        encode_u32_bytes(&mut aad, public_share.as_ref());
+        let info = input_share_info(task_config.version, is_leader)?;
+        let aad = input_share_aad(task_id, &metadata, &public_share, task_config.version);
 
         let encoded_input_share = match decrypter
             .hpke_decrypt(task_id, &info, &aad, encrypted_input_share)
@@ -176,14 +236,63 @@ impl<'req> EarlyReportStateConsumed<'req> {
             _ => return Err(unimplemented_version()),
         };
 
+        // Draft02 carries extensions in the report metadata; later drafts carry them in the
+        // plaintext input share.
+        let extensions = match task_config.version {
+            DapVersion::Draft02 => metadata.extensions.clone(),
+            _ => input_share.extensions,
+        };
+        if let Err(failure) = extension_registry
+            .check(task_id, task_config, &extensions)
+            .and_then(|()| check_client_auth(task_id, task_config, &metadata, &extensions))
+        {
+            return Ok(Self::Rejected { metadata, failure });
+        }
+
         Ok(Self::Ready {
             metadata,
             public_share,
             input_share: input_share.payload,
+            extensions,
         })
     }
 }
 
+/// Construct the HPKE info string used to encrypt an input share to the Leader or Helper.
+pub(crate) fn input_share_info(version: DapVersion, is_leader: bool) -> Result<Vec<u8>, DapError> {
+    let input_share_text = match version {
+        DapVersion::Draft02 => CTX_INPUT_SHARE_DRAFT02,
+        DapVersion::Draft07 => CTX_INPUT_SHARE_DRAFT07,
+        _ => return Err(unimplemented_version()),
+    };
+    let n: usize = input_share_text.len();
+    let mut info = Vec::new();
+    info.reserve(n + 2);
+    info.extend_from_slice(input_share_text);
+    info.push(CTX_ROLE_CLIENT); // Sender role (receiver role set below)
+    info.push(if is_leader {
+        CTX_ROLE_LEADER
+    } else {
+        CTX_ROLE_HELPER
+    }); // Receiver role
+    Ok(info)
+}
+
+/// Construct the HPKE associated data used to encrypt an input share.
+pub(crate) fn input_share_aad(
+    task_id: &TaskId,
+    metadata: &ReportMetadata,
+    public_share: &[u8],
+    version: DapVersion,
+) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(58);
+    task_id.encode(&mut aad);
+    metadata.encode_with_param(&version, &mut aad);
+    // TODO spec: Consider folding the public share into a field called "header".
+    encode_u32_bytes(&mut aad, public_share);
+    aad
+}
+
 impl EarlyReportState for EarlyReportStateConsumed<'_> {
     fn metadata(&self) -> &ReportMetadata {
         match self {
@@ -213,6 +322,10 @@ pub enum EarlyReportStateInitialized<'req> {
     Rejected {
         metadata: Cow<'req, ReportMetadata>,
         failure: TransitionFailure,
+        /// Set if VDAF preparation panicked. The report is rejected like any other, but is
+        /// counted separately so that such reports stand out.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        panicked: bool,
     },
 }
 
@@ -248,48 +361,87 @@ where
 impl<'req> EarlyReportStateInitialized<'req> {
     /// Initialize VDAF preparation for a report. This method is meant to be called by
     /// [`DapReportInitializer`].
+    ///
+    /// If preparation panics, then the report is rejected with [`TransitionFailure::VdafPrepError`]
+    /// and marked as `panicked` so that one malformed report share can't take down the entire
+    /// aggregation job. This is only possible on targets that unwind: see [`catch_panic`].
+    ///
+    /// The VDAF config is checked against the limits of `global_config` before preparation begins,
+    /// so that an unsupported config (e.g., a Prio2 dimension that is too large) is never passed
+    /// to [`prio2_prep_init`].
     pub fn initialize(
         is_leader: bool,
+        global_config: &DapGlobalConfig,
+        task_id: &TaskId,
         vdaf_verify_key: &VdafVerifyKey,
         vdaf_config: &VdafConfig,
         early_report_state_consumed: EarlyReportStateConsumed<'req>,
     ) -> Result<Self, DapError> {
+        global_config.check_vdaf_config(task_id, vdaf_config)?;
+
         let (metadata, public_share, input_share) = match early_report_state_consumed {
             EarlyReportStateConsumed::Ready {
                 metadata,
                 public_share,
                 input_share,
+                ..
             } => (metadata, public_share, input_share),
             EarlyReportStateConsumed::Rejected { metadata, failure } => {
-                return Ok(Self::Rejected { metadata, failure })
+                return Ok(Self::Rejected {
+                    metadata,
+                    failure,
+                    panicked: false,
+                })
             }
         };
 
-        let agg_id = usize::from(!is_leader);
-        let res = match (vdaf_config, vdaf_verify_key) {
-            (VdafConfig::Prio3(ref prio3_config), VdafVerifyKey::Prio3(ref verify_key)) => {
-                prio3_prep_init(
-                    prio3_config,
-                    verify_key,
-                    agg_id,
-                    &metadata.as_ref().id.0,
-                    public_share.as_ref(),
-                    input_share.as_ref(),
-                )
+        let res = match catch_panic(|| {
+            #[cfg(test)]
+            if test::PANIC_ON_PREP_INIT.with(std::cell::Cell::get) {
+                panic!("injected panic");
             }
-            (VdafConfig::Prio2 { dimension }, VdafVerifyKey::Prio2(ref verify_key)) => {
-                prio2_prep_init(
-                    *dimension,
-                    verify_key,
-                    agg_id,
-                    &metadata.as_ref().id.0,
-                    public_share.as_ref(),
-                    input_share.as_ref(),
-                )
+            prep_init(
+                is_leader,
+                vdaf_verify_key,
+                vdaf_config,
+                &metadata.as_ref().id,
+                public_share.as_ref(),
+                input_share.as_ref(),
+            )
+        }) {
+            Ok(res) => res?,
+            Err(panic_message) => {
+                error!(
+                    report_id = %metadata.id,
+                    panic_message,
+                    "VDAF preparation panicked; rejecting report"
+                );
+                return Ok(Self::Rejected {
+                    metadata,
+                    failure: TransitionFailure::VdafPrepError,
+                    panicked: true,
+                });
             }
-            _ => return Err(fatal_error!(err = "VDAF verify key does not match config")),
         };
 
+        #[cfg(feature = "shadow-vdaf")]
+        if shadow::is_enabled() {
+            shadow::compare(
+                vdaf_config,
+                usize::from(!is_leader),
+                &metadata.id,
+                shadow::ShadowStep::Init {
+                    verify_key: vdaf_verify_key.as_ref(),
+                    public_share: public_share.as_ref(),
+                    input_share: input_share.as_ref(),
+                    output: res
+                        .as_ref()
+                        .ok()
+                        .map(|(state, message)| (state.get_encoded(), message.get_encoded())),
+                },
+            );
+        }
+
         let early_report_state_initialized = match res {
             Ok((state, message)) => Self::Ready {
                 metadata,
@@ -300,12 +452,91 @@ impl<'req> EarlyReportStateInitialized<'req> {
             Err(..) => Self::Rejected {
                 metadata,
                 failure: TransitionFailure::VdafPrepError,
+                panicked: false,
             },
         };
         Ok(early_report_state_initialized)
     }
 }
 
+/// Run `f`, returning the panic message if it panics.
+///
+/// Catching a panic requires unwinding, which wasm32 does not support: there a panic aborts the
+/// instance, so this function just runs `f`. Malformed shares are therefore rejected before they
+/// are passed to prio (see [`prep_init`]); this is only a backstop on native targets.
+#[cfg(not(target_arch = "wasm32"))]
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload: Box<dyn Any + Send>| {
+        if let Some(s) = payload.downcast_ref::<&str>() {
+            (*s).to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown panic".into()
+        }
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    Ok(f())
+}
+
+/// The status under which a rejected report is counted.
+fn rejected_status(failure: TransitionFailure, panicked: bool) -> String {
+    if panicked {
+        "rejected_vdaf_prep_panic".into()
+    } else {
+        format!("rejected_{failure}")
+    }
+}
+
+/// Run the VDAF preparation initialization algorithm for a report share. The outer error indicates
+/// a misconfiguration; the inner error indicates that the report share is invalid.
+///
+/// The lengths of the shares are checked against those determined by the VDAF parameters before
+/// they are decoded, since prio may panic on shares of the wrong length.
+pub(crate) fn prep_init(
+    is_leader: bool,
+    vdaf_verify_key: &VdafVerifyKey,
+    vdaf_config: &VdafConfig,
+    report_id: &ReportId,
+    public_share: &[u8],
+    input_share: &[u8],
+) -> Result<Result<(VdafPrepState, VdafPrepMessage), VdafError>, DapError> {
+    let agg_id = usize::from(!is_leader);
+    match vdaf_config.share_sizes() {
+        Ok((public_share_size, input_share_sizes))
+            if public_share.len() == public_share_size
+                && input_share.len() == input_share_sizes[agg_id] => {}
+        Ok(..) => return Ok(Err(VdafError::Codec(CodecError::UnexpectedValue))),
+        Err(e) => return Ok(Err(e)),
+    }
+    match (vdaf_config, vdaf_verify_key) {
+        (VdafConfig::Prio3(ref prio3_config), VdafVerifyKey::Prio3(ref verify_key)) => {
+            Ok(prio3_prep_init(
+                prio3_config,
+                verify_key,
+                agg_id,
+                &report_id.0,
+                public_share,
+                input_share,
+            ))
+        }
+        (VdafConfig::Prio2 { dimension }, VdafVerifyKey::Prio2(ref verify_key)) => {
+            Ok(prio2_prep_init(
+                *dimension,
+                verify_key,
+                agg_id,
+                &report_id.0,
+                public_share,
+                input_share,
+            ))
+        }
+        _ => Err(fatal_error!(err = "VDAF verify key does not match config")),
+    }
+}
+
 impl EarlyReportState for EarlyReportStateInitialized<'_> {
     fn metadata(&self) -> &ReportMetadata {
         match self {
@@ -468,6 +699,50 @@ impl VdafConfig {
         }
     }
 
+    /// Like [`Self::is_valid_agg_param`], except that an invalid aggregation parameter results in
+    /// an abort.
+    pub fn check_agg_param(&self, task_id: &TaskId, agg_param: &[u8]) -> Result<(), DapAbort> {
+        if self.is_valid_agg_param(agg_param) {
+            Ok(())
+        } else {
+            Err(DapAbort::InvalidAggregationParameter {
+                detail: format!(
+                    "{} does not take an aggregation parameter of length {}",
+                    self,
+                    agg_param.len()
+                ),
+                task_id: task_id.clone(),
+            })
+        }
+    }
+
+    /// Upper bounds on the encoded sizes of a report's public share and input shares. The sizes
+    /// are determined by the VDAF parameters, so shares that exceed them are malformed.
+    pub fn max_share_sizes(&self) -> Result<VdafShareSizes, DapError> {
+        let (public_share, [leader_input_share, helper_input_share]) = self.share_sizes()?;
+        Ok(VdafShareSizes {
+            public_share,
+            input_share: leader_input_share.max(helper_input_share),
+        })
+    }
+
+    /// The encoded sizes of a report's public share and of the Leader's and Helper's input shares.
+    fn share_sizes(&self) -> Result<(usize, [usize; 2]), VdafError> {
+        match self {
+            Self::Prio3(prio3_config) => prio3_share_sizes(prio3_config),
+            Self::Prio2 { dimension } => prio2_share_sizes(*dimension),
+        }
+    }
+
+    /// Return the aggregate share of a batch with no reports.
+    pub(crate) fn zero_agg_share(&self) -> Result<VdafAggregateShare, DapError> {
+        match self {
+            Self::Prio3(prio3_config) => prio3_zero_agg_share(prio3_config),
+            Self::Prio2 { dimension } => prio2_zero_agg_share(*dimension),
+        }
+        .map_err(|e| fatal_error!(err = ?e, "failed to produce empty aggregate share"))
+    }
+
     /// Generate the Aggregators' shared verification parameters.
     pub fn gen_verify_key(&self) -> VdafVerifyKey {
         let mut rng = thread_rng();
@@ -605,6 +880,7 @@ impl VdafConfig {
             report_metadata: metadata,
             public_share,
             encrypted_input_shares,
+            annotation: None,
         })
     }
 
@@ -655,6 +931,33 @@ impl VdafConfig {
         )
     }
 
+    /// Generate a report for a measurement, authenticated with the task's client authentication
+    /// key. The inputs are the same as for [`produce_report`](Self::produce_report).
+    pub fn produce_report_with_client_auth(
+        &self,
+        hpke_config_list: &[HpkeConfig],
+        time: Time,
+        task_id: &TaskId,
+        measurement: DapMeasurement,
+        client_auth_key: &DapClientAuthKey,
+        version: DapVersion,
+    ) -> Result<Report, DapError> {
+        let mut rng = thread_rng();
+        let report_id = ReportId(rng.gen());
+        let (public_share, input_shares) = self.produce_input_shares(measurement, &report_id.0)?;
+        let extensions = vec![client_auth_key.extension(task_id, &report_id, time)];
+        self.produce_report_with_extensions_for_shares(
+            public_share,
+            input_shares,
+            hpke_config_list,
+            time,
+            task_id,
+            &report_id,
+            extensions,
+            version,
+        )
+    }
+
     /// Initialize the aggregation flow for a sequence of reports. The outputs are the Leader's
     /// state for the aggregation flow and the initial aggregate request to be sent to the Helper.
     /// This method is called by the Leader.
@@ -665,6 +968,7 @@ impl VdafConfig {
         initializer: &impl DapReportInitializer,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        vdaf_verify_key: &VdafVerifyKey,
         agg_job_id: &MetaAggregationJobId<'_>,
         part_batch_sel: &PartialBatchSelector,
         reports: Vec<Report>,
@@ -693,24 +997,32 @@ impl VdafConfig {
             consumed_reports.push(
                 EarlyReportStateConsumed::consume(
                     decrypter,
+                    initializer.extension_registry(),
                     true,
                     task_id,
                     task_config,
                     Cow::Owned(report.report_metadata),
                     Cow::Owned(report.public_share),
-                    &leader_share,
+                    HpkeCiphertextRef::from(&leader_share),
                 )
                 .await?,
             );
-            helper_shares.push(helper_share);
+            helper_shares.push((helper_share, report.annotation));
         }
 
         let initialized_reports = initializer
-            .initialize_reports(true, task_id, task_config, part_batch_sel, consumed_reports)
+            .initialize_reports(
+                true,
+                task_id,
+                task_config,
+                vdaf_verify_key,
+                part_batch_sel,
+                consumed_reports,
+            )
             .await?;
 
         assert_eq!(initialized_reports.len(), helper_shares.len());
-        for (initialized_report, helper_share) in initialized_reports
+        for (initialized_report, (helper_share, annotation)) in initialized_reports
             .into_iter()
             .zip(helper_shares.into_iter())
         {
@@ -721,7 +1033,13 @@ impl VdafConfig {
                     state,
                     message,
                 } => {
-                    states.push((state, message, metadata.time, metadata.id.clone()));
+                    states.push((
+                        state,
+                        message,
+                        metadata.time,
+                        metadata.id.clone(),
+                        annotation,
+                    ));
                     seq.push(ReportShare {
                         report_metadata: metadata.into_owned(),
                         public_share: public_share.into_owned(),
@@ -729,9 +1047,11 @@ impl VdafConfig {
                     });
                 }
 
-                EarlyReportStateInitialized::Rejected { failure, .. } => {
+                EarlyReportStateInitialized::Rejected {
+                    failure, panicked, ..
+                } => {
                     // Skip report that can't be processed any further.
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(&rejected_status(failure, panicked), 1);
                     continue;
                 }
             }
@@ -752,6 +1072,7 @@ impl VdafConfig {
                 agg_param: Vec::default(),
                 part_batch_sel: part_batch_sel.clone(),
                 report_shares: seq,
+                extensions: Vec::new(),
             },
         ))
     }
@@ -760,6 +1081,9 @@ impl VdafConfig {
     /// for the aggregation flow and the aggregate response to send to the Leader.  This method is
     /// run by the Helper.
     ///
+    /// The aggregate response is encoded incrementally as each report share is prepared, rather
+    /// than being built in memory and then serialized.
+    ///
     /// Note: The helper state parameter of the aggregate response is left empty. The caller may
     /// wish to encrypt the state and insert it into the aggregate response structure.
     ///
@@ -770,26 +1094,34 @@ impl VdafConfig {
     ///
     /// * `decrypter` is used to decrypt the Helper's report shares.
     ///
-    /// * `verify_key` is the secret VDAF verification key shared by the Aggregators.
-    ///
     /// * `task_id` indicates the DAP task for which the reports are being processed.
     ///
+    /// * `vdaf_verify_key` is the secret VDAF verification key shared by the Aggregators, as
+    ///   selected by the Leader for this aggregation job.
+    ///
     /// * `agg_job_init_req` is the request sent by the Leader.
     ///
+    /// * `min_acceptance_ratio` is the fraction of report shares that must be accepted in order
+    ///   for the aggregation job to proceed, if any.
+    ///
     /// * `version` is the DapVersion to use.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn handle_agg_job_init_req(
         &self,
         decrypter: &impl HpkeDecrypter,
         initializer: &impl DapReportInitializer,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
-        agg_job_init_req: &AggregationJobInitReq,
+        vdaf_verify_key: &VdafVerifyKey,
+        agg_job_id: &MetaAggregationJobId<'_>,
+        agg_job_init_req: &AggregationJobInitReqRef<'_>,
+        min_acceptance_ratio: Option<f64>,
         metrics: &ContextualizedDaphneMetrics<'_>,
-    ) -> Result<DapHelperTransition<AggregationJobResp>, DapAbort> {
+    ) -> Result<DapHelperTransition<AggregationJobRespWriter>, DapAbort> {
         let num_reports = agg_job_init_req.report_shares.len();
         let mut processed = HashSet::with_capacity(num_reports);
         let mut states = Vec::with_capacity(num_reports);
-        let mut transitions = Vec::with_capacity(num_reports);
+        let mut agg_job_resp = AggregationJobRespWriter::default();
         let mut consumed_reports = Vec::with_capacity(num_reports);
         for report_share in agg_job_init_req.report_shares.iter() {
             if processed.contains(&report_share.report_metadata.id) {
@@ -806,12 +1138,13 @@ impl VdafConfig {
             consumed_reports.push(
                 EarlyReportStateConsumed::consume(
                     decrypter,
+                    initializer.extension_registry(),
                     false,
                     task_id,
                     task_config,
-                    Cow::Borrowed(&report_share.report_metadata),
-                    Cow::Borrowed(&report_share.public_share),
-                    &report_share.encrypted_input_share,
+                    Cow::Borrowed(report_share.report_metadata.as_ref()),
+                    Cow::Borrowed(report_share.public_share),
+                    report_share.encrypted_input_share,
                 )
                 .await?,
             );
@@ -822,11 +1155,26 @@ impl VdafConfig {
                 false,
                 task_id,
                 task_config,
+                vdaf_verify_key,
                 &agg_job_init_req.part_batch_sel,
                 consumed_reports,
             )
             .await?;
 
+        // Abort if too many report shares were rejected, before any of them are counted.
+        let num_rejected = initialized_reports
+            .iter()
+            .filter(|report| matches!(report, EarlyReportStateInitialized::Rejected { .. }))
+            .count();
+        if !is_acceptance_ratio_sufficient(min_acceptance_ratio, num_reports, num_rejected) {
+            metrics.agg_job_rejected_inc();
+            return Err(DapAbort::AggregationJobRejected {
+                detail: format!("{num_rejected} of {num_reports} report shares were rejected"),
+                task_id: task_id.clone(),
+                agg_job_id_base64url: agg_job_id.to_base64url(),
+            });
+        }
+
         for initialized_report in initialized_reports.into_iter() {
             let transition = match initialized_report {
                 EarlyReportStateInitialized::Ready {
@@ -842,8 +1190,12 @@ impl VdafConfig {
                     }
                 }
 
-                EarlyReportStateInitialized::Rejected { metadata, failure } => {
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                EarlyReportStateInitialized::Rejected {
+                    metadata,
+                    failure,
+                    panicked,
+                } => {
+                    metrics.report_inc_by(&rejected_status(failure, panicked), 1);
                     Transition {
                         report_id: metadata.into_owned().id,
                         var: TransitionVar::Failed(failure),
@@ -851,7 +1203,7 @@ impl VdafConfig {
                 }
             };
 
-            transitions.push(transition);
+            agg_job_resp.push(&transition);
         }
 
         Ok(DapHelperTransition::Continue(
@@ -859,7 +1211,7 @@ impl VdafConfig {
                 part_batch_sel: agg_job_init_req.part_batch_sel.clone(),
                 seq: states,
             },
-            AggregationJobResp { transitions },
+            agg_job_resp,
         ))
     }
 
@@ -886,10 +1238,11 @@ impl VdafConfig {
 
         let mut seq = Vec::with_capacity(state.seq.len());
         let mut states = Vec::with_capacity(state.seq.len());
-        for (helper, (leader_step, leader_message, leader_time, leader_report_id)) in agg_job_resp
-            .transitions
-            .into_iter()
-            .zip(state.seq.into_iter())
+        for (helper, (leader_step, leader_message, leader_time, leader_report_id, annotation)) in
+            agg_job_resp
+                .transitions
+                .into_iter()
+                .zip(state.seq.into_iter())
         {
             // TODO spec: Consider removing the report ID from the AggregationJobResp.
             if helper.report_id != leader_report_id {
@@ -920,6 +1273,10 @@ impl VdafConfig {
                 }
             };
 
+            #[cfg(feature = "shadow-vdaf")]
+            let shadow_inputs = shadow::is_enabled()
+                .then(|| (leader_step.get_encoded(), leader_message.get_encoded()));
+
             let res = match self {
                 Self::Prio3(prio3_config) => prio3_prep_finish_from_shares(
                     prio3_config,
@@ -936,6 +1293,24 @@ impl VdafConfig {
                 ),
             };
 
+            #[cfg(feature = "shadow-vdaf")]
+            if let Some((state, share)) = shadow_inputs {
+                shadow::compare(
+                    self,
+                    0,
+                    &leader_report_id,
+                    shadow::ShadowStep::FinishFromShares {
+                        state,
+                        share,
+                        peer_share: helper_message,
+                        output: res
+                            .as_ref()
+                            .ok()
+                            .map(|(data, message)| (data.get_encoded(), message.clone())),
+                    },
+                );
+            }
+
             match res {
                 Ok((data, message)) => {
                     states.push((
@@ -943,6 +1318,7 @@ impl VdafConfig {
                             report_id: leader_report_id.clone(),
                             time: leader_time,
                             data,
+                            annotation,
                         },
                         leader_report_id.clone(),
                     ));
@@ -1012,8 +1388,9 @@ impl VdafConfig {
                     task_id: Some(task_id.clone()),
                 })
             }
-            // TODO(bhalleycf) For now, there is only ever one round, and we don't try to do
-            // aggregation-round-skew-recovery.
+            // For now, there is only ever one round. Retransmissions of a round the Helper has
+            // already completed are handled by replaying its response (see
+            // `DapHelper::handle_agg_job_cont_req()`), so they never get here.
             Some(r) => {
                 return Err(DapAbort::RoundMismatch {
                     detail: format!("The request indicates round {r}; round 1 was expected."),
@@ -1098,6 +1475,20 @@ impl VdafConfig {
                     }
                 };
 
+                #[cfg(feature = "shadow-vdaf")]
+                if shadow::is_enabled() {
+                    shadow::compare(
+                        self,
+                        1,
+                        helper_report_id,
+                        shadow::ShadowStep::Finish {
+                            state: helper_step.get_encoded(),
+                            peer_message: leader_message,
+                            output: res.as_ref().ok().map(Encode::get_encoded),
+                        },
+                    );
+                }
+
                 match res {
                     Ok(data) => {
                         agg_share_span.add_out_share(
@@ -1106,6 +1497,7 @@ impl VdafConfig {
                             helper_report_id.clone(),
                             *helper_time,
                             data,
+                            None,
                         )?;
                         TransitionVar::Finished
                     }
@@ -1182,6 +1574,7 @@ impl VdafConfig {
                     out_share.report_id.clone(),
                     out_share.time,
                     out_share.data,
+                    out_share.annotation,
                 )?,
             };
         }
@@ -1199,7 +1592,15 @@ impl VdafConfig {
         agg_share: &DapAggregateShare,
         version: DapVersion,
     ) -> Result<HpkeCiphertext, DapAbort> {
-        produce_encrypted_agg_share(true, hpke_config, task_id, batch_sel, agg_share, version)
+        produce_encrypted_agg_share(
+            self,
+            true,
+            hpke_config,
+            task_id,
+            batch_sel,
+            agg_share,
+            version,
+        )
     }
 
     /// Like [`produce_leader_encrypted_agg_share`](Self::produce_leader_encrypted_agg_share) but run by the Helper in response to an
@@ -1212,7 +1613,15 @@ impl VdafConfig {
         agg_share: &DapAggregateShare,
         version: DapVersion,
     ) -> Result<HpkeCiphertext, DapAbort> {
-        produce_encrypted_agg_share(false, hpke_config, task_id, batch_sel, agg_share, version)
+        produce_encrypted_agg_share(
+            self,
+            false,
+            hpke_config,
+            task_id,
+            batch_sel,
+            agg_share,
+            version,
+        )
     }
 
     /// Decrypt and unshard a sequence of aggregate shares. This method is run by the Collector
@@ -1266,7 +1675,7 @@ impl VdafConfig {
             };
 
             let agg_share_data = decrypter
-                .hpke_decrypt(task_id, &info, &aad, agg_share_ciphertext)
+                .hpke_decrypt(task_id, &info, &aad, agg_share_ciphertext.into())
                 .await?;
             agg_shares.push(agg_share_data);
         }
@@ -1287,9 +1696,51 @@ impl VdafConfig {
             }
         }
     }
+
+    /// Like [`Self::consume_encrypted_agg_shares`], except that the transforms registered for this
+    /// VDAF in `transforms` are applied to the aggregate result.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn consume_encrypted_agg_shares_enriched(
+        &self,
+        transforms: &DapResultTransformRegistry,
+        decrypter: &impl HpkeDecrypter,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        report_count: u64,
+        encrypted_agg_shares: Vec<HpkeCiphertext>,
+        version: DapVersion,
+    ) -> Result<DapEnrichedAggregateResult, DapError> {
+        let agg_res = self
+            .consume_encrypted_agg_shares(
+                decrypter,
+                task_id,
+                batch_sel,
+                report_count,
+                encrypted_agg_shares,
+                version,
+            )
+            .await?;
+        transforms.apply(self, agg_res)
+    }
+}
+
+/// Check whether enough of the report shares in an aggregation job were accepted, given the
+/// minimum acceptance ratio configured for the Helper (if any).
+fn is_acceptance_ratio_sufficient(
+    min_acceptance_ratio: Option<f64>,
+    num_reports: usize,
+    num_rejected: usize,
+) -> bool {
+    match min_acceptance_ratio {
+        Some(min_acceptance_ratio) if num_reports > 0 => {
+            (num_reports - num_rejected) as f64 / num_reports as f64 >= min_acceptance_ratio
+        }
+        _ => true,
+    }
 }
 
 fn produce_encrypted_agg_share(
+    vdaf_config: &VdafConfig,
     is_leader: bool,
     hpke_config: &HpkeConfig,
     task_id: &TaskId,
@@ -1297,11 +1748,13 @@ fn produce_encrypted_agg_share(
     agg_share: &DapAggregateShare,
     version: DapVersion,
 ) -> Result<HpkeCiphertext, DapAbort> {
-    let agg_share_data = agg_share
-        .data
-        .as_ref()
-        .ok_or_else(|| fatal_error!(err = "empty aggregate share"))?
-        .get_encoded();
+    let agg_share_data = match agg_share.data {
+        Some(ref data) => data.get_encoded(),
+        // A batch with no reports is only collectable if the task allows it (see
+        // `DapBatchPolicy::allow_empty_collection`).
+        None if agg_share.report_count == 0 => vdaf_config.zero_agg_share()?.get_encoded(),
+        None => return Err(fatal_error!(err = "empty aggregate share").into()),
+    };
 
     let agg_share_text = match version {
         DapVersion::Draft02 => CTX_AGG_SHARE_DRAFT02,
@@ -1337,33 +1790,50 @@ fn produce_encrypted_agg_share(
 #[cfg(test)]
 mod test {
     use crate::{
-        assert_metrics_include, async_test_versions,
-        error::DapAbort,
+        assert_metrics_include, assert_metrics_include_auxiliary_function, async_test_versions,
+        error::{DapAbort, DapErrorCode},
+        extensions::{DapClientAuthKey, DapExtensionRegistry, DapUnknownExtensionPolicy},
         hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
         messages::{
-            AggregationJobInitReq, BatchSelector, Interval, PartialBatchSelector, Report, ReportId,
-            ReportShare, Transition, TransitionFailure, TransitionVar,
+            AggregationJobInitReq, BatchSelector, Extension, HpkeCiphertextRef, Interval,
+            PartialBatchSelector, Report, ReportId, ReportShare, Transition, TransitionFailure,
+            TransitionVar,
         },
+        roles::DapReportInitializer,
         test_versions,
-        testing::AggregationJobTest,
-        DapAggregateResult, DapAggregateShare, DapError, DapHelperState, DapHelperTransition,
-        DapLeaderState, DapLeaderTransition, DapLeaderUncommitted, DapMeasurement, DapOutputShare,
-        DapVersion, Prio3Config, VdafAggregateShare, VdafConfig, VdafPrepMessage, VdafPrepState,
+        testing::{AggregationJobTest, DapSimulator, DapSimulatorFault},
+        vdaf::EarlyReportState,
+        DapAggregateResult, DapAggregateShare, DapAggregateShareSpan, DapBatchBucket, DapError,
+        DapHelperState, DapHelperTransition, DapLeaderState, DapLeaderTransition,
+        DapLeaderUncommitted, DapMeasurement, DapOutputShare, DapPrecomputedBatchBucket,
+        DapTaskConfig, DapVersion, Prio3Config, VdafAggregateShare, VdafConfig, VdafPrepMessage,
+        VdafPrepState,
     };
     use assert_matches::assert_matches;
     use hpke_rs::HpkePublicKey;
     use prio::{
-        codec::Encode,
-        field::Field64,
+        codec::{CodecError, Encode},
+        field::{Field128, Field64, FieldPrio2},
         vdaf::{
             prio3::Prio3, AggregateShare, Aggregator as VdafAggregator, Collector as VdafCollector,
             OutputShare, PrepareTransition,
         },
     };
     use rand::prelude::*;
-    use std::{borrow::Cow, fmt::Debug};
+    use std::{
+        borrow::Cow,
+        cell::Cell,
+        collections::{BTreeMap, HashMap},
+        fmt::Debug,
+    };
+
+    use super::{prep_init, EarlyReportStateConsumed, EarlyReportStateInitialized, VdafError};
 
-    use super::{EarlyReportStateConsumed, EarlyReportStateInitialized};
+    thread_local! {
+        // Make VDAF preparation panic for reports initialized by the current test only, since
+        // other tests may run concurrently.
+        pub(super) static PANIC_ON_PREP_INIT: Cell<bool> = const { Cell::new(false) };
+    }
 
     impl<M: Debug> DapLeaderTransition<M> {
         pub(crate) fn unwrap_continue(self) -> (DapLeaderState, M) {
@@ -1425,12 +1895,13 @@ mod test {
 
         let early_report_state_consumed = EarlyReportStateConsumed::consume(
             &t.leader_hpke_receiver_config,
+            t.extension_registry(),
             true, // is_leader
             &t.task_id,
             &t.task_config,
             Cow::Borrowed(&report.report_metadata),
             Cow::Borrowed(&report.public_share),
-            &report.encrypted_input_shares[0],
+            HpkeCiphertextRef::from(&report.encrypted_input_shares[0]),
         )
         .await
         .unwrap();
@@ -1440,6 +1911,8 @@ mod test {
             ..
         } = EarlyReportStateInitialized::initialize(
             true,
+            &t.global_config,
+            &t.task_id,
             &t.task_config.vdaf_verify_key,
             &t.task_config.vdaf,
             early_report_state_consumed,
@@ -1451,12 +1924,13 @@ mod test {
 
         let early_report_state_consumed = EarlyReportStateConsumed::consume(
             &t.helper_hpke_receiver_config,
+            t.extension_registry(),
             false, // is_helper
             &t.task_id,
             &t.task_config,
             Cow::Borrowed(&report.report_metadata),
             Cow::Borrowed(&report.public_share),
-            &report.encrypted_input_shares[1],
+            HpkeCiphertextRef::from(&report.encrypted_input_shares[1]),
         )
         .await
         .unwrap();
@@ -1466,6 +1940,8 @@ mod test {
             ..
         } = EarlyReportStateInitialized::initialize(
             false,
+            &t.global_config,
+            &t.task_id,
             &t.task_config.vdaf_verify_key,
             &t.task_config.vdaf,
             early_report_state_consumed,
@@ -1513,6 +1989,144 @@ mod test {
 
     async_test_versions! { roundtrip_report }
 
+    async fn consume_client_auth(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let client_auth_key = DapClientAuthKey::gen();
+        let mut task_config = t.task_config.clone();
+        task_config.client_auth_key = Some(client_auth_key.clone());
+
+        let consume = |report: Report, task_config: DapTaskConfig| {
+            let t = &t;
+            async move {
+                let consumed = EarlyReportStateConsumed::consume(
+                    &t.leader_hpke_receiver_config,
+                    t.extension_registry(),
+                    true, // is_leader
+                    &t.task_id,
+                    &task_config,
+                    Cow::Owned(report.report_metadata.clone()),
+                    Cow::Owned(report.public_share.clone()),
+                    HpkeCiphertextRef::from(&report.encrypted_input_shares[0]),
+                )
+                .await
+                .unwrap();
+                match consumed {
+                    EarlyReportStateConsumed::Ready { .. } => None,
+                    EarlyReportStateConsumed::Rejected { failure, .. } => Some(failure),
+                }
+            }
+        };
+        let produce_report = |extensions: Vec<Extension>| {
+            t.task_config
+                .vdaf
+                .produce_report_with_extensions(
+                    &t.client_hpke_config_list,
+                    t.now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    extensions,
+                    version,
+                )
+                .unwrap()
+        };
+
+        // Authenticated report.
+        let report = t
+            .task_config
+            .vdaf
+            .produce_report_with_client_auth(
+                &t.client_hpke_config_list,
+                t.now,
+                &t.task_id,
+                DapMeasurement::U64(1),
+                &client_auth_key,
+                version,
+            )
+            .unwrap();
+        assert_eq!(consume(report.clone(), task_config.clone()).await, None);
+
+        // The extension is ignored by tasks that don't require client authentication.
+        assert_eq!(consume(report, t.task_config.clone()).await, None);
+
+        // Missing token.
+        assert_eq!(
+            consume(produce_report(Vec::new()), task_config.clone()).await,
+            Some(TransitionFailure::ClientAuthFailed)
+        );
+
+        // Token computed with the wrong key.
+        let extension =
+            DapClientAuthKey::gen().extension(&t.task_id, &ReportId(thread_rng().gen()), t.now);
+        assert_eq!(
+            consume(produce_report(vec![extension]), task_config.clone()).await,
+            Some(TransitionFailure::ClientAuthFailed)
+        );
+
+        // Token computed for a different report.
+        let extension = client_auth_key.extension(&t.task_id, &ReportId(thread_rng().gen()), t.now);
+        assert_eq!(
+            consume(produce_report(vec![extension]), task_config).await,
+            Some(TransitionFailure::ClientAuthFailed)
+        );
+    }
+
+    async_test_versions! { consume_client_auth }
+
+    async fn simulate_faults(version: DapVersion) {
+        let measurements = || {
+            vec![
+                DapMeasurement::U64(1),
+                DapMeasurement::U64(1),
+                DapMeasurement::U64(0),
+            ]
+        };
+        let simulator = || DapSimulator::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+
+        let sim = simulator();
+        let outcome = sim.run(measurements()).await.unwrap();
+        assert_eq!(outcome.report_count, 3);
+        assert_eq!(outcome.result, Some(DapAggregateResult::U64(2)));
+
+        // The Helper treats the retried request as a replay of each report.
+        let sim = simulator().with_fault(DapSimulatorFault::DropAggJobInitResp);
+        let outcome = sim.run(measurements()).await.unwrap();
+        assert_eq!(outcome.report_count, 0);
+        assert_eq!(outcome.result, None);
+        assert_metrics_include!(sim.prometheus_registry(), {
+            r#"test_helper_report_counter{host="helper.org",status="rejected_report_replayed"}"#: 3,
+        });
+
+        // The Leader rejects the replayed report.
+        let sim = simulator().with_fault(DapSimulatorFault::ReplayReport(0));
+        let outcome = sim.run(measurements()).await.unwrap();
+        assert_eq!(outcome.report_count, 3);
+        assert_eq!(outcome.result, Some(DapAggregateResult::U64(2)));
+        assert_metrics_include!(sim.prometheus_registry(), {
+            r#"test_leader_report_counter{host="leader.com",status="rejected_report_replayed"}"#: 1,
+        });
+
+        // The Leader aborts if the Helper's response doesn't match its request.
+        let sim = simulator().with_fault(DapSimulatorFault::CorruptTransition(1));
+        assert_matches!(
+            sim.run(measurements()).await,
+            Err(DapAbort::UnrecognizedMessage { .. })
+        );
+
+        // A fault that refers to a report that doesn't exist is an error rather than a panic.
+        for fault in [
+            DapSimulatorFault::ReplayReport(3),
+            DapSimulatorFault::CorruptTransition(3),
+        ] {
+            let sim = simulator().with_fault(fault);
+            assert_matches!(
+                sim.run(measurements()).await,
+                Err(DapAbort::Internal(e)) if e.to_string().contains(&format!("{fault:?}"))
+            );
+        }
+    }
+
+    async_test_versions! { simulate_faults }
+
     fn roundtrip_report_unsupported_hpke_suite(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
 
@@ -1543,6 +2157,198 @@ mod test {
 
     test_versions! { roundtrip_report_unsupported_hpke_suite }
 
+    #[test]
+    fn catch_panic() {
+        assert_eq!(super::catch_panic(|| 23), Ok(23));
+        assert_eq!(
+            super::catch_panic(|| -> u8 { panic!("malformed share") }),
+            Err("malformed share".to_string())
+        );
+        assert_eq!(
+            super::catch_panic(|| -> u8 { panic!("malformed share {}", 23) }),
+            Err("malformed share 23".to_string())
+        );
+    }
+
+    async fn produce_agg_job_init_req_skip_prep_panic(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let reports = t.produce_reports(vec![DapMeasurement::U64(1)]);
+
+        PANIC_ON_PREP_INIT.with(|panic| panic.set(true));
+        let transition = t.produce_agg_job_init_req(reports).await;
+        PANIC_ON_PREP_INIT.with(|panic| panic.set(false));
+        assert_matches!(transition, DapLeaderTransition::Skip);
+
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_leader_report_counter{host="leader.com",status="rejected_vdaf_prep_panic"}"#: 1,
+        });
+    }
+
+    async_test_versions! { produce_agg_job_init_req_skip_prep_panic }
+
+    async fn handle_agg_job_init_req_prep_panic(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let reports = t.produce_reports(vec![DapMeasurement::U64(1), DapMeasurement::U64(0)]);
+        let (_, agg_job_init_req) = t.produce_agg_job_init_req(reports).await.unwrap_continue();
+
+        PANIC_ON_PREP_INIT.with(|panic| panic.set(true));
+        let transition = t.handle_agg_job_init_req(&agg_job_init_req).await;
+        PANIC_ON_PREP_INIT.with(|panic| panic.set(false));
+        let (_, agg_job_resp) = transition.unwrap_continue();
+        assert_eq!(agg_job_resp.transitions.len(), 2);
+        for transition in agg_job_resp.transitions {
+            assert_matches!(
+                transition.var,
+                TransitionVar::Failed(TransitionFailure::VdafPrepError)
+            );
+        }
+
+        assert_metrics_include!(t.prometheus_registry, {
+            r#"test_helper_report_counter{host="helper.org",status="rejected_vdaf_prep_panic"}"#: 2,
+        });
+    }
+
+    async_test_versions! { handle_agg_job_init_req_prep_panic }
+
+    async fn initialize_prio2_dimension_exceeds_max(version: DapVersion) {
+        let mut t = AggregationJobTest::new(
+            &VdafConfig::Prio2 { dimension: 10 },
+            HpkeKemId::X25519HkdfSha256,
+            version,
+        );
+        let reports = t.produce_reports(vec![DapMeasurement::U32Vec(vec![1; 10])]);
+        let consumed_report = t.consume_reports(true, reports).await.remove(0);
+
+        t.global_config.max_prio2_dimension = Some(5);
+        assert_matches!(
+            EarlyReportStateInitialized::initialize(
+                true,
+                &t.global_config,
+                &t.task_id,
+                &t.task_config.vdaf_verify_key,
+                &t.task_config.vdaf,
+                consumed_report,
+            )
+            .err(),
+            Some(DapError::Abort(DapAbort::InvalidTask { .. }))
+        );
+    }
+
+    async_test_versions! { initialize_prio2_dimension_exceeds_max }
+
+    #[test]
+    fn max_share_sizes() {
+        for (vdaf, measurement) in [
+            (
+                VdafConfig::Prio3(Prio3Config::Count),
+                DapMeasurement::U64(1),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::Sum { bits: 32 }),
+                DapMeasurement::U64(1337),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::Histogram {
+                    length: 10,
+                    chunk_length: 3,
+                }),
+                DapMeasurement::U64(7),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::SumVec {
+                    bits: 8,
+                    length: 10,
+                    chunk_length: 3,
+                }),
+                DapMeasurement::U128Vec(vec![1; 10]),
+            ),
+            (
+                VdafConfig::Prio2 { dimension: 10 },
+                DapMeasurement::U32Vec(vec![1; 10]),
+            ),
+        ] {
+            let max_share_sizes = vdaf.max_share_sizes().unwrap();
+            let (public_share, input_shares) =
+                vdaf.produce_input_shares(measurement, &[0; 16]).unwrap();
+
+            // The bounds are tight: the Leader's input share is the larger of the two.
+            assert_eq!(public_share.len(), max_share_sizes.public_share, "{vdaf}");
+            assert_eq!(input_shares[0].len(), max_share_sizes.input_share, "{vdaf}");
+            assert!(
+                input_shares[1].len() <= max_share_sizes.input_share,
+                "{vdaf}"
+            );
+
+            // The sizes are exact.
+            let (public_share_size, input_share_sizes) = vdaf.share_sizes().unwrap();
+            assert_eq!(public_share.len(), public_share_size, "{vdaf}");
+            assert_eq!(input_shares[0].len(), input_share_sizes[0], "{vdaf}");
+            assert_eq!(input_shares[1].len(), input_share_sizes[1], "{vdaf}");
+
+            // Shares of any other length are rejected before they are decoded.
+            let verify_key = vdaf.gen_verify_key();
+            for (is_leader, input_share) in [(true, &input_shares[0]), (false, &input_shares[1])] {
+                for input_share in [
+                    input_share[..input_share.len() - 1].to_vec(),
+                    [input_share.as_slice(), &[0]].concat(),
+                ] {
+                    assert_matches!(
+                        prep_init(
+                            is_leader,
+                            &verify_key,
+                            &vdaf,
+                            &ReportId([0; 16]),
+                            &public_share,
+                            &input_share,
+                        ),
+                        Ok(Err(VdafError::Codec(CodecError::UnexpectedValue))),
+                        "{vdaf}"
+                    );
+                }
+            }
+        }
+
+        assert_matches!(
+            VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 10,
+                chunk_length: 0,
+            })
+            .max_share_sizes(),
+            Err(DapError::Transition(TransitionFailure::VdafPrepError))
+        );
+    }
+
+    async fn handle_agg_job_init_req_oversized_shares(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let report = t.produce_reports(vec![DapMeasurement::U64(1)]).remove(0);
+
+        // A public share that is far too long for the VDAF is rejected without being decrypted.
+        let agg_job_init_req = AggregationJobInitReq {
+            draft02_task_id: t.task_id.for_request_payload(&version),
+            draft02_agg_job_id: t.agg_job_id.for_request_payload(),
+            agg_param: Vec::new(),
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_shares: vec![ReportShare {
+                report_metadata: report.report_metadata,
+                public_share: vec![0; 1 << 20],
+                encrypted_input_share: report.encrypted_input_shares[1].clone(),
+            }],
+            extensions: Vec::new(),
+        };
+
+        let (_, agg_job_resp) = t
+            .handle_agg_job_init_req(&agg_job_init_req)
+            .await
+            .unwrap_continue();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Failed(TransitionFailure::UnrecognizedMessage)
+        );
+    }
+
+    async_test_versions! { handle_agg_job_init_req_oversized_shares }
+
     async fn produce_agg_job_init_req(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let reports = t.produce_reports(vec![
@@ -1629,8 +2435,11 @@ mod test {
             DapLeaderTransition::Skip
         );
 
+        // The public share with a spurious byte is too long for the VDAF and so is rejected before
+        // it is decoded.
         assert_metrics_include!(t.prometheus_registry, {
-            r#"test_leader_report_counter{host="leader.com",status="rejected_vdaf_prep_error"}"#: 2,
+            r#"test_leader_report_counter{host="leader.com",status="rejected_unrecognized_message"}"#: 1,
+            r#"test_leader_report_counter{host="leader.com",status="rejected_vdaf_prep_error"}"#: 1,
         });
     }
 
@@ -1718,6 +2527,7 @@ mod test {
                     encrypted_input_share: report1.encrypted_input_shares[1].clone(),
                 },
             ],
+            extensions: Vec::new(),
         };
 
         let (_, agg_job_resp) = t
@@ -1725,10 +2535,12 @@ mod test {
             .await
             .unwrap_continue();
 
+        // The public share with a spurious byte is too long for the VDAF and so is rejected before
+        // it is decoded.
         assert_eq!(agg_job_resp.transitions.len(), 2);
         assert_matches!(
             agg_job_resp.transitions[0].var,
-            TransitionVar::Failed(TransitionFailure::VdafPrepError)
+            TransitionVar::Failed(TransitionFailure::UnrecognizedMessage)
         );
         assert_matches!(
             agg_job_resp.transitions[1].var,
@@ -1736,12 +2548,140 @@ mod test {
         );
 
         assert_metrics_include!(t.prometheus_registry, {
-            r#"test_helper_report_counter{host="helper.org",status="rejected_vdaf_prep_error"}"#: 2,
+            r#"test_helper_report_counter{host="helper.org",status="rejected_unrecognized_message"}"#: 1,
+            r#"test_helper_report_counter{host="helper.org",status="rejected_vdaf_prep_error"}"#: 1,
         });
     }
 
     async_test_versions! { handle_agg_job_init_req_vdaf_prep_error }
 
+    async fn handle_agg_job_init_req_unknown_extension(version: DapVersion) {
+        let mut t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let agg_job_init_req_for = |t: &AggregationJobTest| {
+            let report = t
+                .task_config
+                .vdaf
+                .produce_report_with_extensions(
+                    &t.client_hpke_config_list,
+                    t.now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    vec![Extension::Unhandled {
+                        typ: 0x1337,
+                        payload: b"private extension".to_vec(),
+                    }],
+                    version,
+                )
+                .unwrap();
+            AggregationJobInitReq {
+                draft02_task_id: t.task_id.for_request_payload(&version),
+                draft02_agg_job_id: t.agg_job_id.for_request_payload(),
+                agg_param: Vec::new(),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                report_shares: vec![ReportShare {
+                    report_metadata: report.report_metadata,
+                    public_share: report.public_share,
+                    encrypted_input_share: report.encrypted_input_shares[1].clone(),
+                }],
+                extensions: Vec::new(),
+            }
+        };
+
+        // By default, reports with unknown extensions are rejected.
+        let (_, agg_job_resp) = t
+            .handle_agg_job_init_req(&agg_job_init_req_for(&t))
+            .await
+            .unwrap_continue();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Failed(TransitionFailure::UnrecognizedMessage)
+        );
+
+        // The task may choose to ignore them instead.
+        t.task_config.unknown_extensions = DapUnknownExtensionPolicy::Ignore;
+        let (_, agg_job_resp) = t
+            .handle_agg_job_init_req(&agg_job_init_req_for(&t))
+            .await
+            .unwrap_continue();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Continued(..)
+        );
+    }
+
+    async_test_versions! { handle_agg_job_init_req_unknown_extension }
+
+    async fn consume_report_extensions(version: DapVersion) {
+        let mut t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        t.task_config.unknown_extensions = DapUnknownExtensionPolicy::Ignore;
+        let extensions = vec![Extension::Unhandled {
+            typ: 0x1337,
+            payload: b"private extension".to_vec(),
+        }];
+        let report = t
+            .task_config
+            .vdaf
+            .produce_report_with_extensions(
+                &t.client_hpke_config_list,
+                t.now,
+                &t.task_id,
+                DapMeasurement::U64(1),
+                extensions.clone(),
+                version,
+            )
+            .unwrap();
+
+        // The extensions are passed on to the report initializer.
+        let consumed = EarlyReportStateConsumed::consume(
+            &t.helper_hpke_receiver_config,
+            t.extension_registry(),
+            false, // is_leader
+            &t.task_id,
+            &t.task_config,
+            Cow::Borrowed(&report.report_metadata),
+            Cow::Borrowed(&report.public_share),
+            HpkeCiphertextRef::from(&report.encrypted_input_shares[1]),
+        )
+        .await
+        .unwrap();
+        let EarlyReportStateConsumed::Ready {
+            extensions: got, ..
+        } = consumed
+        else {
+            panic!("rejected unexpectedly");
+        };
+        assert_eq!(got, extensions);
+
+        // In later drafts, the extensions are carried in the encrypted input share, whose size is
+        // bounded by the maximum length of the extensions accepted by the Aggregator.
+        let mut extension_registry = DapExtensionRegistry::new();
+        extension_registry.set_max_extensions_len(4);
+        let consumed = EarlyReportStateConsumed::consume(
+            &t.helper_hpke_receiver_config,
+            &extension_registry,
+            false, // is_leader
+            &t.task_id,
+            &t.task_config,
+            Cow::Borrowed(&report.report_metadata),
+            Cow::Borrowed(&report.public_share),
+            HpkeCiphertextRef::from(&report.encrypted_input_shares[1]),
+        )
+        .await
+        .unwrap();
+        let failure = match consumed {
+            EarlyReportStateConsumed::Ready { .. } => None,
+            EarlyReportStateConsumed::Rejected { failure, .. } => Some(failure),
+        };
+        match version {
+            DapVersion::Draft02 => assert_eq!(failure, None),
+            _ => assert_eq!(failure, Some(TransitionFailure::UnrecognizedMessage)),
+        }
+    }
+
+    async_test_versions! { consume_report_extensions }
+
     async fn agg_job_resp_abort_transition_out_of_order(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let reports = t.produce_reports(vec![DapMeasurement::U64(1), DapMeasurement::U64(1)]);
@@ -1884,6 +2824,131 @@ mod test {
 
     async_test_versions! { agg_job_cont_req }
 
+    async fn agg_share_span_persist_by_bucket(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let reports = t.produce_reports(vec![
+            DapMeasurement::U64(1),
+            DapMeasurement::U64(0),
+            DapMeasurement::U64(1),
+        ]);
+        let (leader_state, agg_job_init_req) =
+            t.produce_agg_job_init_req(reports).await.unwrap_continue();
+        let (helper_state, agg_job_resp) = t
+            .handle_agg_job_init_req(&agg_job_init_req)
+            .await
+            .unwrap_continue();
+        let (_, agg_job_cont_req) = t
+            .handle_agg_job_resp(leader_state, agg_job_resp)
+            .unwrap_uncommitted();
+        let (helper_agg_share_span, _) =
+            t.handle_agg_job_cont_req(&helper_state, &agg_job_cont_req);
+
+        // Persist each bucket separately, then rebuild the span from what was persisted.
+        let persisted = helper_agg_share_span
+            .iter()
+            .map(|(bucket, agg_share_and_reports)| {
+                (
+                    serde_json::to_string(bucket).unwrap(),
+                    serde_json::to_vec(agg_share_and_reports).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut rebuilt = DapAggregateShareSpan::default();
+        for (bucket, agg_share_and_reports) in persisted {
+            let (agg_share, reports) = serde_json::from_slice(&agg_share_and_reports).unwrap();
+            rebuilt
+                .merge_bucket(serde_json::from_str(&bucket).unwrap(), agg_share, reports)
+                .unwrap();
+        }
+        assert_eq!(rebuilt.len(), helper_agg_share_span.len());
+        assert_eq!(rebuilt.report_count(), 3);
+
+        let mut expected_ids = helper_agg_share_span.report_ids().collect::<Vec<_>>();
+        let mut got_ids = rebuilt.report_ids().collect::<Vec<_>>();
+        expected_ids.sort();
+        got_ids.sort();
+        assert_eq!(got_ids, expected_ids);
+
+        // The whole span round-trips too.
+        let decoded: DapAggregateShareSpan =
+            serde_json::from_str(&serde_json::to_string(&rebuilt).unwrap()).unwrap();
+        assert_eq!(decoded.report_count(), 3);
+        assert_eq!(
+            decoded.collapsed().checksum,
+            helper_agg_share_span.collapsed().checksum
+        );
+    }
+
+    async_test_versions! { agg_share_span_persist_by_bucket }
+
+    async fn batch_span_for_meta_trusts_precomputed_bucket(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let reports = t.produce_reports(vec![DapMeasurement::U64(1), DapMeasurement::U64(0)]);
+        let mut consumed_reports = Vec::with_capacity(reports.len());
+        for report in &reports {
+            consumed_reports.push(
+                EarlyReportStateConsumed::consume(
+                    &t.leader_hpke_receiver_config,
+                    t.extension_registry(),
+                    true, // is_leader
+                    &t.task_id,
+                    &t.task_config,
+                    Cow::Borrowed(&report.report_metadata),
+                    Cow::Borrowed(&report.public_share),
+                    HpkeCiphertextRef::from(&report.encrypted_input_shares[0]),
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        let computed = t.task_config.precompute_batch_bucket(t.now).unwrap().bucket;
+        let next_bucket = DapBatchBucket::TimeInterval {
+            batch_window: t.task_config.quantized_time_lower_bound(t.now)
+                + t.task_config.time_precision,
+        };
+
+        // The first report's bucket was computed with the current time precision and is trusted;
+        // the second report's was computed with a stale one and is recomputed.
+        let precomputed = HashMap::from([
+            (
+                reports[0].report_metadata.id.clone(),
+                DapPrecomputedBatchBucket {
+                    bucket: next_bucket.clone(),
+                    time_precision: t.task_config.time_precision,
+                    sub_bucket_precision: None,
+                },
+            ),
+            (
+                reports[1].report_metadata.id.clone(),
+                DapPrecomputedBatchBucket {
+                    bucket: next_bucket.clone(),
+                    time_precision: t.task_config.time_precision * 2,
+                    sub_bucket_precision: None,
+                },
+            ),
+        ]);
+
+        let span = t
+            .task_config
+            .batch_span_for_meta_precomputed(
+                &PartialBatchSelector::TimeInterval,
+                consumed_reports.iter(),
+                |metadata| precomputed.get(&metadata.id).cloned(),
+            )
+            .unwrap();
+        assert_eq!(span.len(), 2);
+        assert_eq!(
+            span[&next_bucket][0].metadata().id,
+            reports[0].report_metadata.id
+        );
+        assert_eq!(
+            span[&computed][0].metadata().id,
+            reports[1].report_metadata.id
+        );
+    }
+
+    async_test_versions! { batch_span_for_meta_trusts_precomputed_bucket }
+
     async fn agg_job_cont_req_skip_vdaf_prep_error(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let mut reports = t.produce_reports(vec![DapMeasurement::U64(1), DapMeasurement::U64(1)]);
@@ -2016,6 +3081,8 @@ mod test {
             data: Some(VdafAggregateShare::Field64(AggregateShare::from(
                 OutputShare::from(vec![Field64::from(23)]),
             ))),
+            report_id_prefixes: [0; 32],
+            annotations: BTreeMap::new(),
         };
         let helper_agg_share = DapAggregateShare {
             report_count: 50,
@@ -2025,6 +3092,8 @@ mod test {
             data: Some(VdafAggregateShare::Field64(AggregateShare::from(
                 OutputShare::from(vec![Field64::from(9)]),
             ))),
+            report_id_prefixes: [0; 32],
+            annotations: BTreeMap::new(),
         };
 
         let batch_selector = BatchSelector::TimeInterval {
@@ -2065,14 +3134,129 @@ mod test {
             .await
             .unwrap_continue();
 
+        let encoded = want.get_encoded_versioned(TEST_VDAF);
+        let got = DapHelperState::get_decoded(TEST_VDAF, &encoded).unwrap();
+        assert_eq!(got, want);
+
+        // State written with the legacy encoding can still be decoded.
         let got = DapHelperState::get_decoded(TEST_VDAF, &want.get_encoded()).unwrap();
         assert_eq!(got, want);
 
+        // State written for a different VDAF or with an unknown encoding version is rejected.
+        let other_vdaf = VdafConfig::Prio3(Prio3Config::Sum { bits: 23 });
+        assert_eq!(
+            DapHelperState::get_decoded(&other_vdaf, &encoded)
+                .unwrap_err()
+                .code(),
+            Some(DapErrorCode::StateFormatMismatch)
+        );
+        let mut unknown_version = encoded;
+        unknown_version[1] += 1;
+        assert_eq!(
+            DapHelperState::get_decoded(TEST_VDAF, &unknown_version)
+                .unwrap_err()
+                .code(),
+            Some(DapErrorCode::StateFormatMismatch)
+        );
+
         assert!(DapHelperState::get_decoded(TEST_VDAF, b"invalid helper state").is_err())
     }
 
     async_test_versions! { helper_state_serialization }
 
+    #[test]
+    fn agg_share_versioned_roundtrip() {
+        let agg_shares = [
+            DapAggregateShare::default(),
+            DapAggregateShare {
+                report_count: 50,
+                min_time: 1637359200,
+                max_time: 1637362800,
+                checksum: [1; 32],
+                data: Some(VdafAggregateShare::Field64(AggregateShare::from(
+                    OutputShare::from(vec![Field64::from(23), Field64::from(1337)]),
+                ))),
+                report_id_prefixes: [2; 32],
+                annotations: BTreeMap::from([("android".into(), 30), ("ios".into(), 20)]),
+            },
+            DapAggregateShare {
+                report_count: 1,
+                data: Some(VdafAggregateShare::Field128(AggregateShare::from(
+                    OutputShare::from(vec![Field128::from(23)]),
+                ))),
+                ..Default::default()
+            },
+            DapAggregateShare {
+                report_count: 1,
+                data: Some(VdafAggregateShare::FieldPrio2(AggregateShare::from(
+                    OutputShare::from(vec![FieldPrio2::from(23)]),
+                ))),
+                ..Default::default()
+            },
+        ];
+
+        for want in agg_shares {
+            let encoded = want.get_encoded_versioned();
+            let got = DapAggregateShare::get_decoded_versioned(&encoded).unwrap();
+            assert_eq!(got.get_encoded_versioned(), encoded);
+        }
+    }
+
+    // Aggregate shares encoded by a previous deployment must remain readable. If this test fails,
+    // then the encoding has changed: bump the encoding version and keep decoding this one.
+    #[test]
+    fn agg_share_versioned_migration() {
+        let encoded = hex::decode(concat!(
+            "da01",
+            "0000000000000032",
+            "0000000061981e60",
+            "0000000061982c70",
+            "0101010101010101010101010101010101010101010101010101010101010101",
+            "0202020202020202020202020202020202020202020202020202020202020202",
+            "01",
+            "00000010",
+            "17000000000000003905000000000000",
+        ))
+        .unwrap();
+        let got = DapAggregateShare::get_decoded_versioned(&encoded).unwrap();
+        assert_eq!(got.report_count, 50);
+        assert_eq!(got.min_time, 1637359200);
+        assert_eq!(got.max_time, 1637362800);
+        assert_eq!(got.checksum, [1; 32]);
+        assert_eq!(got.report_id_prefixes, [2; 32]);
+        assert_matches!(got.data, Some(VdafAggregateShare::Field64(ref agg_share))
+            if agg_share.as_ref() == [Field64::from(23), Field64::from(1337)]);
+        assert!(got.annotations.is_empty());
+
+        // Re-encoding upgrades to the current version, which appends the (empty) annotations.
+        let mut want = encoded.clone();
+        want[1] = 2;
+        want.extend_from_slice(&[0; 4]);
+        assert_eq!(got.get_encoded_versioned(), want);
+
+        // Aggregate shares stored before the versioned encoding was introduced.
+        let legacy: DapAggregateShare = serde_json::from_str(concat!(
+            r#"{"report_count":50,"min_time":1637359200,"max_time":1637362800,"#,
+            r#""checksum":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"#,
+            r#""data":{"field64":[[23,0,0,0,0,0,0,0],[57,5,0,0,0,0,0,0]]},"#,
+            r#""report_id_prefixes":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2]}"#,
+        )).unwrap();
+        assert_eq!(legacy.get_encoded_versioned(), want);
+    }
+
+    #[test]
+    fn agg_share_versioned_decode_err() {
+        let mut encoded = DapAggregateShare::default().get_encoded_versioned();
+        assert!(DapAggregateShare::get_decoded_versioned(&encoded[..1]).is_err());
+        assert!(DapAggregateShare::get_decoded_versioned(&encoded[..encoded.len() - 1]).is_err());
+
+        encoded[1] = 0xff; // Unknown version
+        assert!(DapAggregateShare::get_decoded_versioned(&encoded).is_err());
+
+        encoded[0] = 0x00; // Bad magic
+        assert!(DapAggregateShare::get_decoded_versioned(&encoded).is_err());
+    }
+
     impl AggregationJobTest {
         // Tweak the Helper's share so that decoding succeeds but preparation fails.
         fn produce_invalid_report_vdaf_prep_failure(
@@ -2142,8 +3326,10 @@ mod test {
                 .vdaf
                 .produce_input_shares(measurement, &report_id.0)
                 .unwrap();
-            invalid_input_shares[0].push(1); // Add a spurious byte to the Leader's share
-            invalid_input_shares[1].push(1); // Add a spurious byte to the Helper's share
+            // Truncate the shares. (Shares with spurious bytes at the end may be rejected before
+            // decoding for being too long.)
+            invalid_input_shares[0].pop();
+            invalid_input_shares[1].pop();
             self.task_config
                 .vdaf
                 .produce_report_with_extensions_for_shares(
@@ -10,7 +10,7 @@ use crate::{
 };
 use prio::{
     codec::{Decode, Encode, ParameterizedDecode},
-    field::FieldPrio2,
+    field::{FieldElement, FieldPrio2},
     vdaf::{
         prio2::{Prio2, Prio2PrepareShare, Prio2PrepareState},
         AggregateShare, Aggregator, Client, Collector, PrepareTransition, Share, Vdaf,
@@ -18,6 +18,19 @@ use prio::{
 };
 use std::io::Cursor;
 
+/// Return the encoded sizes of the public share and of the larger of the two input shares.
+pub(crate) fn prio2_share_sizes(dimension: usize) -> Result<(usize, [usize; 2]), VdafError> {
+    // Reject dimensions the VDAF does not support.
+    Prio2::new(dimension)?;
+    // The Leader's share is the data followed by the proof, which has three more elements plus
+    // the evaluations of a polynomial at a power-of-two number of points. The Helper's share is
+    // derived from a seed.
+    let proof_len = dimension + 3 + (dimension + 1).next_power_of_two();
+    let leader_input_share = proof_len * FieldPrio2::ENCODED_SIZE;
+    let helper_input_share = 32;
+    Ok((0, [leader_input_share, helper_input_share]))
+}
+
 /// Split the given measurement into a sequence of encoded input shares.
 pub(crate) fn prio2_shard(
     dimension: usize,
@@ -118,6 +131,12 @@ pub(crate) fn prio2_decode_prep_state(
     )?))
 }
 
+/// Return the aggregate share of an empty batch, i.e., the aggregate of no output shares.
+pub(crate) fn prio2_zero_agg_share(dimension: usize) -> Result<VdafAggregateShare, VdafError> {
+    let vdaf = Prio2::new(dimension)?;
+    Ok(VdafAggregateShare::FieldPrio2(vdaf.aggregate(&(), [])?))
+}
+
 /// Interpret `encoded_agg_shares` as a sequence of encoded aggregate shares and unshard them.
 pub(crate) fn prio2_unshard<M: IntoIterator<Item = Vec<u8>>>(
     dimension: usize,
@@ -9,6 +9,12 @@ use crate::{
 };
 use prio::{
     codec::{Encode, ParameterizedDecode},
+    field::{Field128, Field64, FieldElement},
+    flp::{
+        gadgets::{Mul, ParallelSum},
+        types::{Count, Histogram, Sum, SumVec},
+        Type,
+    },
     vdaf::{
         prio3::{
             Prio3, Prio3InputShare, Prio3PrepareMessage, Prio3PrepareShare, Prio3PrepareState,
@@ -84,6 +90,46 @@ pub(crate) fn prio3_shard(
     }
 }
 
+/// Return the encoded sizes of the public share and of the larger of the two input shares.
+pub(crate) fn prio3_share_sizes(config: &Prio3Config) -> Result<(usize, [usize; 2]), VdafError> {
+    type Gadget = ParallelSum<Field128, Mul<Field128>>;
+    let sizes = match config {
+        Prio3Config::Count => Ok(share_sizes(&Count::<Field64>::new())),
+        Prio3Config::Sum { bits } => Sum::<Field128>::new(*bits).map(|typ| share_sizes(&typ)),
+        Prio3Config::Histogram {
+            length,
+            chunk_length,
+        } => {
+            Histogram::<Field128, Gadget>::new(*length, *chunk_length).map(|typ| share_sizes(&typ))
+        }
+        Prio3Config::SumVec {
+            bits,
+            length,
+            chunk_length,
+        } => SumVec::<Field128, Gadget>::new(*bits, *length, *chunk_length)
+            .map(|typ| share_sizes(&typ)),
+    };
+    return sizes.map_err(|e| VdafError::Vdaf(e.into()));
+
+    fn share_sizes<T: Type>(typ: &T) -> (usize, [usize; 2]) {
+        const SEED_SIZE: usize = 16;
+        // The joint randomness blind and the public share's joint randomness parts (one per
+        // Aggregator) are only present if the FLP uses joint randomness.
+        let joint_rand_size = if typ.joint_rand_len() > 0 {
+            SEED_SIZE
+        } else {
+            0
+        };
+        let public_share = 2 * joint_rand_size;
+        // The Leader's measurement and proof shares are vectors of field elements; the Helper's
+        // are each derived from a seed.
+        let leader_input_share =
+            (typ.input_len() + typ.proof_len()) * T::Field::ENCODED_SIZE + joint_rand_size;
+        let helper_input_share = 2 * SEED_SIZE + joint_rand_size;
+        (public_share, [leader_input_share, helper_input_share])
+    }
+}
+
 /// Consume an input share and return the corresponding VDAF step and message.
 pub(crate) fn prio3_prep_init(
     config: &Prio3Config,
@@ -206,11 +252,9 @@ pub(crate) fn prio3_prep_finish_from_shares(
             VdafPrepState::Prio3Field64(state),
             VdafPrepMessage::Prio3ShareField64(share),
         ) => {
-            let vdaf = Prio3::new_count(2)?;
             let (out_share, outbound) =
-                prep_finish_from_shares(&vdaf, agg_id, state, share, peer_share_data)?;
-            let agg_share = VdafAggregateShare::Field64(vdaf.aggregate(&(), [out_share])?);
-            (agg_share, outbound)
+                count_prep_finish_from_shares(agg_id, state, share, peer_share_data)?;
+            (VdafAggregateShare::Field64(out_share.into()), outbound)
         }
         (
             Prio3Config::Histogram {
@@ -300,9 +344,8 @@ pub(crate) fn prio3_prep_finish(
 ) -> Result<VdafAggregateShare, VdafError> {
     let agg_share = match (&config, host_state) {
         (Prio3Config::Count, VdafPrepState::Prio3Field64(state)) => {
-            let vdaf = Prio3::new_count(2)?;
-            let out_share = prep_finish(&vdaf, state, peer_message_data)?;
-            VdafAggregateShare::Field64(vdaf.aggregate(&(), [out_share])?)
+            let out_share = count_prep_finish(state, peer_message_data)?;
+            VdafAggregateShare::Field64(out_share.into())
         }
         (
             Prio3Config::Histogram {
@@ -361,6 +404,63 @@ pub(crate) fn prio3_prep_finish(
     }
 }
 
+// Count is the only Prio3 type in this module that is instantiated over `Field64`, and it is the
+// hot path for most deployments. Its FLP uses no joint randomness, so the prep message is always
+// empty: there is nothing to encode on the way out or decode on the way in. The output share of a
+// single report is also its aggregate share, so we convert it in place rather than going through
+// `Aggregator::aggregate()`, which would allocate a zeroed share and accumulate into it.
+
+/// Count-specific version of `prio3_prep_finish_from_shares()`.
+fn count_prep_finish_from_shares(
+    agg_id: usize,
+    host_state: Prio3PrepareState<Field64, 16>,
+    host_share: Prio3PrepareShare<Field64, 16>,
+    peer_share_data: &[u8],
+) -> Result<(OutputShare<Field64>, Vec<u8>), VdafError> {
+    let vdaf = Prio3::new_count(2)?;
+
+    // Decode the peer's prep share.
+    let peer_share = Prio3PrepareShare::get_decoded_with_param(&host_state, peer_share_data)?;
+
+    // Check the proof. The resulting message carries no joint randomness seed, so its encoding is
+    // empty.
+    let message = vdaf.prepare_shares_to_prepare_message(
+        &(),
+        if agg_id == 0 {
+            [host_share, peer_share]
+        } else {
+            [peer_share, host_share]
+        },
+    )?;
+    debug_assert_eq!(message.encoded_len(), Some(0));
+
+    match vdaf.prepare_next(host_state, message)? {
+        PrepareTransition::Continue(..) => {
+            panic!("prio3_prep_finish_from_shares: {ERR_EXPECT_FINISH}")
+        }
+        PrepareTransition::Finish(out_share) => Ok((out_share, Vec::new())),
+    }
+}
+
+/// Count-specific version of `prio3_prep_finish()`.
+fn count_prep_finish(
+    helper_state: Prio3PrepareState<Field64, 16>,
+    leader_message_data: &[u8],
+) -> Result<OutputShare<Field64>, VdafError> {
+    let vdaf = Prio3::new_count(2)?;
+
+    // The message is empty, so decoding it amounts to checking that there are no trailing bytes.
+    let leader_message =
+        Prio3PrepareMessage::get_decoded_with_param(&helper_state, leader_message_data)?;
+
+    match vdaf.prepare_next(helper_state, leader_message)? {
+        PrepareTransition::Continue(..) => {
+            panic!("prio3_prep_finish: {ERR_EXPECT_FINISH}")
+        }
+        PrepareTransition::Finish(out_share) => Ok(out_share),
+    }
+}
+
 /// Parse our prep state.
 pub(crate) fn prio3_decode_prep_state(
     config: &Prio3Config,
@@ -402,6 +502,36 @@ pub(crate) fn prio3_decode_prep_state(
     }
 }
 
+/// Return the aggregate share of an empty batch, i.e., the aggregate of no output shares.
+pub(crate) fn prio3_zero_agg_share(config: &Prio3Config) -> Result<VdafAggregateShare, VdafError> {
+    let agg_share = match config {
+        Prio3Config::Count => {
+            let vdaf = Prio3::new_count(2)?;
+            VdafAggregateShare::Field64(vdaf.aggregate(&(), [])?)
+        }
+        Prio3Config::Histogram {
+            length,
+            chunk_length,
+        } => {
+            let vdaf = Prio3::new_histogram(2, *length, *chunk_length)?;
+            VdafAggregateShare::Field128(vdaf.aggregate(&(), [])?)
+        }
+        Prio3Config::Sum { bits } => {
+            let vdaf = Prio3::new_sum(2, *bits)?;
+            VdafAggregateShare::Field128(vdaf.aggregate(&(), [])?)
+        }
+        Prio3Config::SumVec {
+            bits,
+            length,
+            chunk_length,
+        } => {
+            let vdaf = Prio3::new_sum_vec(2, *bits, *length, *chunk_length)?;
+            VdafAggregateShare::Field128(vdaf.aggregate(&(), [])?)
+        }
+    };
+    Ok(agg_share)
+}
+
 /// Interpret `agg_shares` as a sequence of encoded aggregate shares and unshard them.
 pub(crate) fn prio3_unshard<M: IntoIterator<Item = Vec<u8>>>(
     config: &Prio3Config,
@@ -482,6 +612,51 @@ mod test {
         .unwrap();
     }
 
+    #[test]
+    fn prep_count_fast_path() {
+        test_prep(
+            &Prio3Config::Count,
+            DapMeasurement::U64(1),
+            DapAggregateResult::U64(1),
+        )
+        .unwrap();
+
+        // The prep message for Count is empty, and the Helper rejects anything else.
+        let config = Prio3Config::Count;
+        let verify_key = thread_rng().gen();
+        let nonce = [0; 16];
+        let (public_share, input_shares) =
+            prio3_shard(&config, DapMeasurement::U64(1), &nonce).unwrap();
+        let (leader_state, leader_share) = prio3_prep_init(
+            &config,
+            &verify_key,
+            0,
+            &nonce,
+            &public_share,
+            &input_shares[0],
+        )
+        .unwrap();
+        let (helper_state, helper_share) = prio3_prep_init(
+            &config,
+            &verify_key,
+            1,
+            &nonce,
+            &public_share,
+            &input_shares[1],
+        )
+        .unwrap();
+        let (_leader_agg_share, message_data) = prio3_prep_finish_from_shares(
+            &config,
+            0,
+            leader_state,
+            leader_share,
+            &helper_share.get_encoded(),
+        )
+        .unwrap();
+        assert!(message_data.is_empty());
+        assert!(prio3_prep_finish(&config, helper_state, &[0]).is_err());
+    }
+
     #[test]
     fn prep_sum() {
         test_prep(
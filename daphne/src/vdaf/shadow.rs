@@ -0,0 +1,296 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Differential testing of VDAF implementations. A deployment may register an alternate
+//! implementation of the VDAFs (e.g., one built against a newer version of the `prio` crate) with
+//! [`set_shadow_vdaf`]. Each report that goes through preparation is then also run through the
+//! alternate implementation and any divergence from the primary implementation is logged. The
+//! results of the alternate implementation are never returned to peers.
+//!
+//! Inputs and outputs are exchanged with the alternate implementation in their encoded form so
+//! that it need not share any types with this crate.
+
+use super::catch_panic;
+use crate::{fatal_error, messages::ReportId, DapError, VdafConfig};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+use tracing::warn;
+
+/// An alternate implementation of the VDAFs supported by Daphne. Each method returns `None` if
+/// the alternate implementation rejects the report.
+pub trait DapShadowVdaf: Send + Sync {
+    /// Run the preparation initialization algorithm. The return value is the encoded prep state
+    /// and prep share.
+    fn prep_init(
+        &self,
+        vdaf_config: &VdafConfig,
+        verify_key: &[u8],
+        agg_id: usize,
+        nonce: &[u8; 16],
+        public_share: &[u8],
+        input_share: &[u8],
+    ) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Combine the prep shares and finish preparation. The return value is the encoded aggregate
+    /// share of the report and the prep message.
+    fn prep_finish_from_shares(
+        &self,
+        vdaf_config: &VdafConfig,
+        agg_id: usize,
+        state: &[u8],
+        share: &[u8],
+        peer_share: &[u8],
+    ) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Finish preparation given the prep message. The return value is the encoded aggregate share
+    /// of the report.
+    fn prep_finish(
+        &self,
+        vdaf_config: &VdafConfig,
+        agg_id: usize,
+        state: &[u8],
+        peer_message: &[u8],
+    ) -> Option<Vec<u8>>;
+}
+
+static SHADOW_VDAF: OnceLock<Box<dyn DapShadowVdaf>> = OnceLock::new();
+
+static DIVERGENCES: AtomicU64 = AtomicU64::new(0);
+
+/// Register the alternate VDAF implementation. This may only be done once per process.
+pub fn set_shadow_vdaf(shadow: impl DapShadowVdaf + 'static) -> Result<(), DapError> {
+    SHADOW_VDAF
+        .set(Box::new(shadow))
+        .map_err(|_| fatal_error!(err = "shadow VDAF already set", code = Config))
+}
+
+/// Number of divergences between the primary and alternate implementations observed so far.
+pub fn divergences() -> u64 {
+    DIVERGENCES.load(Ordering::Relaxed)
+}
+
+pub(crate) fn is_enabled() -> bool {
+    SHADOW_VDAF.get().is_some()
+}
+
+/// The inputs and primary output of a preparation step, all encoded.
+pub(crate) enum ShadowStep<'a> {
+    Init {
+        verify_key: &'a [u8],
+        public_share: &'a [u8],
+        input_share: &'a [u8],
+        /// Encoded prep state and prep share, if the report was accepted.
+        output: Option<(Vec<u8>, Vec<u8>)>,
+    },
+    FinishFromShares {
+        state: Vec<u8>,
+        share: Vec<u8>,
+        peer_share: &'a [u8],
+        /// Encoded aggregate share and prep message, if the report was accepted.
+        output: Option<(Vec<u8>, Vec<u8>)>,
+    },
+    Finish {
+        state: Vec<u8>,
+        peer_message: &'a [u8],
+        /// Encoded aggregate share, if the report was accepted.
+        output: Option<Vec<u8>>,
+    },
+}
+
+/// Run a preparation step through the alternate implementation, if there is one, and log a
+/// divergence from the primary output.
+pub(crate) fn compare(
+    vdaf_config: &VdafConfig,
+    agg_id: usize,
+    report_id: &ReportId,
+    step: ShadowStep<'_>,
+) {
+    let Some(shadow) = SHADOW_VDAF.get() else {
+        return;
+    };
+
+    let (stage, diverged) = match catch_panic(|| match step {
+        ShadowStep::Init {
+            verify_key,
+            public_share,
+            input_share,
+            output,
+        } => (
+            "prep_init",
+            shadow.prep_init(
+                vdaf_config,
+                verify_key,
+                agg_id,
+                &report_id.0,
+                public_share,
+                input_share,
+            ) != output,
+        ),
+        ShadowStep::FinishFromShares {
+            state,
+            share,
+            peer_share,
+            output,
+        } => (
+            "prep_finish_from_shares",
+            shadow.prep_finish_from_shares(vdaf_config, agg_id, &state, &share, peer_share)
+                != output,
+        ),
+        ShadowStep::Finish {
+            state,
+            peer_message,
+            output,
+        } => (
+            "prep_finish",
+            shadow.prep_finish(vdaf_config, agg_id, &state, peer_message) != output,
+        ),
+    }) {
+        Ok(res) => res,
+        Err(panic_message) => {
+            DIVERGENCES.fetch_add(1, Ordering::Relaxed);
+            warn!(%report_id, %vdaf_config, panic_message, "shadow VDAF panicked");
+            return;
+        }
+    };
+
+    if diverged {
+        DIVERGENCES.fetch_add(1, Ordering::Relaxed);
+        warn!(%report_id, %vdaf_config, stage, "shadow VDAF diverged");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        hpke::HpkeKemId,
+        testing::AggregationJobTest,
+        vdaf::{
+            prep_init,
+            prio2::{prio2_prep_finish, prio2_prep_finish_from_shares},
+            prio3::{prio3_prep_finish, prio3_prep_finish_from_shares},
+            VdafPrepMessage, VdafPrepState,
+        },
+        DapAggregateResult, DapMeasurement, DapVersion, Prio3Config,
+    };
+    use prio::codec::{Encode, ParameterizedDecode};
+    use std::cell::Cell;
+
+    thread_local! {
+        // Only reports aggregated by the current test are tampered with, since other tests may
+        // run concurrently.
+        static TAMPER: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Shadow that forwards to the primary implementation, optionally tampering with its output.
+    struct ForwardingShadow;
+
+    impl ForwardingShadow {
+        fn tamper(mut out: Vec<u8>) -> Vec<u8> {
+            if TAMPER.with(Cell::get) {
+                out.push(0);
+            }
+            out
+        }
+    }
+
+    impl DapShadowVdaf for ForwardingShadow {
+        fn prep_init(
+            &self,
+            vdaf_config: &VdafConfig,
+            verify_key: &[u8],
+            agg_id: usize,
+            nonce: &[u8; 16],
+            public_share: &[u8],
+            input_share: &[u8],
+        ) -> Option<(Vec<u8>, Vec<u8>)> {
+            let verify_key = vdaf_config.get_decoded_verify_key(verify_key).ok()?;
+            let (state, share) = prep_init(
+                agg_id == 0,
+                &verify_key,
+                vdaf_config,
+                &ReportId(*nonce),
+                public_share,
+                input_share,
+            )
+            .ok()?
+            .ok()?;
+            Some((Self::tamper(state.get_encoded()), share.get_encoded()))
+        }
+
+        fn prep_finish_from_shares(
+            &self,
+            vdaf_config: &VdafConfig,
+            agg_id: usize,
+            state: &[u8],
+            share: &[u8],
+            peer_share: &[u8],
+        ) -> Option<(Vec<u8>, Vec<u8>)> {
+            let state =
+                VdafPrepState::get_decoded_with_param(&(vdaf_config, agg_id == 0), state).ok()?;
+            let share = VdafPrepMessage::get_decoded_with_param(&state, share).ok()?;
+            let (agg_share, message) = match vdaf_config {
+                VdafConfig::Prio3(prio3_config) => {
+                    prio3_prep_finish_from_shares(prio3_config, agg_id, state, share, peer_share)
+                }
+                VdafConfig::Prio2 { dimension } => {
+                    prio2_prep_finish_from_shares(*dimension, state, share, peer_share)
+                }
+            }
+            .ok()?;
+            Some((Self::tamper(agg_share.get_encoded()), message))
+        }
+
+        fn prep_finish(
+            &self,
+            vdaf_config: &VdafConfig,
+            agg_id: usize,
+            state: &[u8],
+            peer_message: &[u8],
+        ) -> Option<Vec<u8>> {
+            let state =
+                VdafPrepState::get_decoded_with_param(&(vdaf_config, agg_id == 0), state).ok()?;
+            let agg_share = match vdaf_config {
+                VdafConfig::Prio3(prio3_config) => {
+                    prio3_prep_finish(prio3_config, state, peer_message)
+                }
+                VdafConfig::Prio2 { dimension } => {
+                    prio2_prep_finish(*dimension, state, peer_message)
+                }
+            }
+            .ok()?;
+            Some(Self::tamper(agg_share.get_encoded()))
+        }
+    }
+
+    #[tokio::test]
+    async fn shadow_divergence() {
+        set_shadow_vdaf(ForwardingShadow).unwrap();
+        assert!(set_shadow_vdaf(ForwardingShadow).is_err());
+
+        let mut t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            DapVersion::Draft07,
+        );
+        let measurements = vec![DapMeasurement::U64(1), DapMeasurement::U64(0)];
+
+        // An implementation that agrees with the primary one causes no divergences.
+        let before = divergences();
+        assert_eq!(
+            t.roundtrip(measurements.clone()).await,
+            DapAggregateResult::U64(1)
+        );
+        assert_eq!(divergences(), before);
+
+        // Divergences are counted, but the result is unaffected.
+        TAMPER.with(|tamper| tamper.set(true));
+        assert_eq!(t.roundtrip(measurements).await, DapAggregateResult::U64(1));
+        TAMPER.with(|tamper| tamper.set(false));
+        // Each of the two reports diverges in prep_init (both Aggregators), in
+        // prep_finish_from_shares (Leader), and in prep_finish (Helper).
+        assert!(divergences() >= before + 8);
+    }
+}
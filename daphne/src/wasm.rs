@@ -0,0 +1,171 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! JavaScript bindings for report generation. This module is enabled by the "wasm" feature and is
+//! intended for Clients running in a browser or other JavaScript runtime that cannot use
+//! [`DapClient`](crate::client::DapClient) directly: the application fetches each Aggregator's HPKE
+//! config itself, decodes it with [`decode_hpke_config`], and passes the result to
+//! [`produce_report`]. The returned bytes are the body of the upload request.
+//!
+//! Task parameters that have a structured representation elsewhere in this crate are passed as
+//! JSON: the VDAF is encoded as a [`VdafConfig`] and the measurement as a [`DapMeasurement`], e.g.,
+//! `{"prio3":{"sum":{"bits":8}}}` and `{"u64":23}`. Errors are surfaced to JavaScript as `Error`
+//! objects whose message is the `Display` output of the underlying [`DapError`].
+//!
+//! The "wasm" feature also enables the "js" backend of `getrandom`, which is required for
+//! `wasm32-unknown-unknown`.
+
+use prio::codec::{Decode, Encode, ParameterizedEncode};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    client::is_supported_hpke_config,
+    fatal_error,
+    hpke::HpkeConfig,
+    messages::{HpkeConfigList, TaskId, Time},
+    DapError, DapMeasurement, DapVersion, VdafConfig,
+};
+
+/// The largest integer a JavaScript `number` can represent exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// An Aggregator's HPKE config, as decoded by [`decode_hpke_config`].
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct WasmHpkeConfig(HpkeConfig);
+
+#[wasm_bindgen]
+impl WasmHpkeConfig {
+    /// The config ID.
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> u8 {
+        self.0.id
+    }
+
+    /// Encode the config as a single `HpkeConfig`, e.g., for storing it client-side.
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.get_encoded()
+    }
+
+    /// Decode a config previously encoded with `toBytes()`.
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<WasmHpkeConfig, JsError> {
+        HpkeConfig::get_decoded(bytes)
+            .map(Self)
+            .map_err(|e| js_error(fatal_error!(err = ?e, "failed to decode HPKE config")))
+    }
+}
+
+/// Decode the body of a response to an Aggregator's `hpke_config` endpoint.
+///
+/// For draft02 the body is a single config. For later versions it is a list of configs: if `id`
+/// is provided, the config with that ID is selected; otherwise the first config whose algorithms
+/// are supported is selected.
+#[wasm_bindgen(js_name = decodeHpkeConfig)]
+pub fn decode_hpke_config(
+    version: &str,
+    bytes: &[u8],
+    id: Option<u8>,
+) -> Result<WasmHpkeConfig, JsError> {
+    select_hpke_config(parse_version(version).map_err(js_error)?, bytes, id)
+        .map(WasmHpkeConfig)
+        .map_err(js_error)
+}
+
+/// Generate a report and return it encoded for upload.
+///
+/// * `task_id` is the base64url encoding of the task ID.
+///
+/// * `vdaf` is the JSON encoding of the task's VDAF config.
+///
+/// * `measurement` is the JSON encoding of the measurement.
+///
+/// * `time` is the number of seconds since the UNIX epoch. As with
+///   [`VdafConfig::produce_report`], it is the caller's responsibility to truncate it to the
+///   task's time precision.
+#[wasm_bindgen(js_name = produceReport)]
+pub fn produce_report(
+    version: &str,
+    task_id: &str,
+    vdaf: &str,
+    measurement: &str,
+    leader_hpke_config: &WasmHpkeConfig,
+    helper_hpke_config: &WasmHpkeConfig,
+    time: f64,
+) -> Result<Vec<u8>, JsError> {
+    encode_report(
+        version,
+        task_id,
+        vdaf,
+        measurement,
+        [leader_hpke_config.0.clone(), helper_hpke_config.0.clone()],
+        time,
+    )
+    .map_err(js_error)
+}
+
+fn encode_report(
+    version: &str,
+    task_id: &str,
+    vdaf: &str,
+    measurement: &str,
+    hpke_config_list: [HpkeConfig; 2],
+    time: f64,
+) -> Result<Vec<u8>, DapError> {
+    let version = parse_version(version)?;
+    let task_id = TaskId::try_from_base64url(task_id)
+        .ok_or_else(|| fatal_error!(err = "task ID is not valid base64url"))?;
+    let vdaf: VdafConfig = serde_json::from_str(vdaf)
+        .map_err(|e| fatal_error!(err = ?e, "failed to parse VDAF config"))?;
+    let measurement: DapMeasurement = serde_json::from_str(measurement)
+        .map_err(|e| fatal_error!(err = ?e, "failed to parse measurement"))?;
+    let time = parse_time(time)?;
+
+    let report = vdaf.produce_report(&hpke_config_list, time, &task_id, measurement, version)?;
+    Ok(report.get_encoded_with_param(&version))
+}
+
+fn select_hpke_config(
+    version: DapVersion,
+    bytes: &[u8],
+    id: Option<u8>,
+) -> Result<HpkeConfig, DapError> {
+    let hpke_configs = match version {
+        DapVersion::Draft02 => vec![HpkeConfig::get_decoded(bytes)
+            .map_err(|e| fatal_error!(err = ?e, "failed to decode HPKE config"))?],
+        _ => {
+            HpkeConfigList::get_decoded(bytes)
+                .map_err(|e| fatal_error!(err = ?e, "failed to decode HPKE config list"))?
+                .hpke_configs
+        }
+    };
+
+    hpke_configs
+        .into_iter()
+        .find(|hpke_config| match id {
+            Some(id) => hpke_config.id == id,
+            None => is_supported_hpke_config(hpke_config),
+        })
+        .ok_or_else(|| fatal_error!(err = "no usable HPKE config", ?id))
+}
+
+fn parse_version(version: &str) -> Result<DapVersion, DapError> {
+    match DapVersion::from(version) {
+        DapVersion::Unknown => Err(fatal_error!(err = "unknown DAP version", version)),
+        version => Ok(version),
+    }
+}
+
+fn parse_time(time: f64) -> Result<Time, DapError> {
+    if !time.is_finite() || !(0.0..=MAX_SAFE_INTEGER).contains(&time) {
+        return Err(fatal_error!(err = "time is out of range", time));
+    }
+    // The range check above ensures the conversion is lossless.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(time.trunc() as Time)
+}
+
+fn js_error(e: DapError) -> JsError {
+    JsError::new(&e.to_string())
+}
@@ -0,0 +1,81 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Tests for the JavaScript bindings. Run with
+//!
+//! ```text
+//! wasm-pack test --node daphne -- --features wasm
+//! ```
+
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use daphne::{
+    hpke::{HpkeKemId, HpkeReceiverConfig},
+    messages::{HpkeConfigList, Report, TaskId},
+    wasm::{decode_hpke_config, produce_report, WasmHpkeConfig},
+    DapVersion,
+};
+use prio::codec::{Encode, ParameterizedDecode};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+fn hpke_config_list(id: u8) -> Vec<u8> {
+    let config = HpkeReceiverConfig::gen(id, HpkeKemId::X25519HkdfSha256)
+        .unwrap()
+        .config;
+    HpkeConfigList {
+        hpke_configs: vec![config],
+    }
+    .get_encoded()
+}
+
+#[wasm_bindgen_test]
+fn decode_hpke_config_pinned_id() {
+    let leader = decode_hpke_config("v07", &hpke_config_list(1), Some(1))
+        .ok()
+        .unwrap();
+    assert_eq!(leader.id(), 1);
+
+    let leader = WasmHpkeConfig::from_bytes(&leader.to_bytes()).ok().unwrap();
+    assert_eq!(leader.id(), 1);
+
+    assert!(decode_hpke_config("v07", &hpke_config_list(1), Some(2)).is_err());
+    assert!(decode_hpke_config("v99", &hpke_config_list(1), None).is_err());
+}
+
+#[wasm_bindgen_test]
+fn produce_report_decodes() {
+    let leader = decode_hpke_config("v07", &hpke_config_list(1), None)
+        .ok()
+        .unwrap();
+    let helper = decode_hpke_config("v07", &hpke_config_list(2), None)
+        .ok()
+        .unwrap();
+    let task_id = TaskId([23; 32]);
+
+    let report = produce_report(
+        "v07",
+        &task_id.to_base64url(),
+        r#"{"prio3":{"sum":{"bits":8}}}"#,
+        r#"{"u64":23}"#,
+        &leader,
+        &helper,
+        1_637_361_337.0,
+    )
+    .ok()
+    .unwrap();
+
+    let report = Report::get_decoded_with_param(&DapVersion::Draft07, &report).unwrap();
+    assert_eq!(report.report_metadata.time, 1_637_361_337);
+    assert_eq!(report.encrypted_input_shares.len(), 2);
+
+    assert!(produce_report(
+        "v07",
+        "not base64url!",
+        r#"{"prio3":{"sum":{"bits":8}}}"#,
+        r#"{"u64":23}"#,
+        &leader,
+        &helper,
+        1_637_361_337.0,
+    )
+    .is_err());
+}
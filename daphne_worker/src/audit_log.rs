@@ -0,0 +1,61 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Daphne-Worker audit log sinks.
+
+use daphne::{
+    audit_log::{AuditEvent, AuditLogSink},
+    fatal_error, DapError,
+};
+use reqwest_wasm::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use worker::Url;
+
+/// Audit log sink that POSTs each batch of events to an HTTP endpoint as JSON lines. Wrap it in a
+/// [`BatchedAuditLog`](daphne::audit_log::BatchedAuditLog) to use it as the audit log of a
+/// [`DaphneWorkerRouter`](crate::DaphneWorkerRouter).
+pub struct HttpAuditLogSink {
+    client: reqwest_wasm::Client,
+    url: Url,
+    bearer_token: Option<String>,
+}
+
+impl HttpAuditLogSink {
+    /// Send events to `url`. If `bearer_token` is set, then it is sent in the Authorization
+    /// header of each request.
+    pub fn new(url: Url, bearer_token: Option<String>) -> Self {
+        Self {
+            client: reqwest_wasm::Client::new(),
+            url,
+            bearer_token,
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl AuditLogSink for HttpAuditLogSink {
+    async fn write_batch(&self, events: &[AuditEvent]) -> Result<(), DapError> {
+        let mut req = self
+            .client
+            .post(self.url.as_str())
+            .header(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/x-ndjson"),
+            )
+            .body(AuditEvent::to_json_lines(events)?);
+        if let Some(ref bearer_token) = self.bearer_token {
+            req = req.header(AUTHORIZATION, format!("Bearer {bearer_token}"));
+        }
+
+        let resp = req
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e, "failed to send audit log events"))?;
+        if !resp.status().is_success() {
+            return Err(fatal_error!(
+                err = "audit log endpoint rejected events",
+                status = resp.status().as_u16(),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@
 //! Authorization methods for Daphne-Worker.
 
 use daphne::auth::BearerToken;
+use ring::digest;
 use serde::{Deserialize, Serialize};
 use worker::TlsClientAuth;
 
@@ -44,6 +45,35 @@ pub(crate) struct DaphneWorkerAuth {
     pub(crate) cf_tls_client_auth: Option<TlsClientAuth>,
 }
 
+impl DaphneWorkerAuth {
+    /// Identity of the sender, used to look up its quota (see
+    /// [`DaphneWorkerQuotaConfig`](crate::config::DaphneWorkerQuotaConfig)). A verified TLS client
+    /// certificate is identified by its subject, e.g., `"tls_client_cert:CN=leader.example.com"`.
+    /// Otherwise a bearer token is identified by the hex-encoded SHA-256 hash of the token, e.g.,
+    /// `"bearer_token:<hash>"`, so that tokens never appear in the configuration.
+    ///
+    /// The identity is only meaningful once the request has been authorized, since until then the
+    /// sender may present a credential it does not hold.
+    pub(crate) fn peer_identity(&self) -> Option<String> {
+        if let Some(ref cf_tls_client_auth) = self.cf_tls_client_auth {
+            if cf_tls_client_auth.cert_verified() == "SUCCESS" {
+                return Some(format!(
+                    "tls_client_cert:{}",
+                    cf_tls_client_auth.cert_subject_dn_rfc2253()
+                ));
+            }
+        }
+
+        self.bearer_token.as_ref().map(|bearer_token| {
+            let hash = digest::digest(
+                &digest::SHA256,
+                AsRef::<str>::as_ref(bearer_token).as_bytes(),
+            );
+            format!("bearer_token:{}", hex::encode(hash))
+        })
+    }
+}
+
 impl AsRef<BearerToken> for DaphneWorkerAuth {
     fn as_ref(&self) -> &BearerToken {
         if let Some(ref bearer_token) = self.bearer_token {
@@ -8,16 +8,19 @@
 use crate::{
     auth::{DaphneWorkerAuth, DaphneWorkerAuthMethod},
     durable::{
-        durable_name_report_store, durable_name_task,
+        collect_job_queue_shard, durable_name_queue, durable_name_report_store, durable_name_task,
+        garbage_collector::DURABLE_GARBAGE_COLLECTOR_DELETE_MATCHING,
+        helper_state_store::{HelperStateKey, HelperStateKeys},
         leader_batch_queue::{LeaderBatchQueueResult, DURABLE_LEADER_BATCH_QUEUE_CURRENT},
-        DurableConnector, BINDING_DAP_GARBAGE_COLLECTOR, BINDING_DAP_LEADER_BATCH_QUEUE,
-        DURABLE_DELETE_ALL,
+        report_shard, report_store_epochs, DurableConnector, DurableReferenceFilter,
+        DurableRetryPolicy, ReportShardScheme, BINDING_DAP_GARBAGE_COLLECTOR,
+        BINDING_DAP_LEADER_BATCH_QUEUE, DURABLE_ALARM_IF_DUE, DURABLE_DELETE_ALL,
     },
     error_reporting::ErrorReporter,
-    int_err,
     metrics::DaphneWorkerMetrics,
+    now,
     router::{
-        test_routes::{InternalTestAddTask, InternalTestEndpointForTask},
+        test_routes::{InternalTestBustTaskConfigCache, InternalTestEndpointForTask},
         Role,
     },
 };
@@ -25,23 +28,28 @@ use daphne::{
     audit_log::AuditLog,
     auth::BearerToken,
     constants::DapMediaType,
+    content_encoding::DapContentEncoding,
     error::DapAbort,
+    extensions::DapExtensionRegistry,
     fatal_error,
-    hpke::{HpkeConfig, HpkeReceiverConfig},
-    messages::{
-        decode_base64url_vec, AggregationJobId, BatchId, CollectionJobId, ReportId, TaskId, Time,
+    hpke::{HpkeConfig, HpkePrivateKeyStore, HpkeReceiverConfig},
+    messages::{BatchId, ReportId, TaskId, Time},
+    provisioning::{
+        DapTaskConfigRecord, DapTaskDescription, KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR,
+        KV_KEY_PREFIX_BEARER_TOKEN_LEADER, KV_KEY_PREFIX_TASK_CONFIG,
     },
-    DapError, DapGlobalConfig, DapQueryConfig, DapRequest, DapResource, DapResponse, DapTaskConfig,
-    DapVersion, Prio3Config, VdafConfig,
+    taskprov::TaskprovRejectionCache,
+    time::TimeSource,
+    DapCollectPriority, DapError, DapGlobalConfig, DapPrecomputedBatchBucket, DapQueryConfig,
+    DapRequest, DapResource, DapResponse, DapSender, DapTaskConfig, DapVersion,
 };
 use futures::TryFutureExt;
-use matchit::Router;
-use prio::codec::Decode;
 use prometheus::{Encoder, Registry};
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
     fmt::Display,
     io::Cursor,
     sync::{Arc, RwLock},
@@ -51,13 +59,18 @@ use tracing::{error, info, trace};
 use worker::{kv::KvStore, *};
 
 const KV_KEY_PREFIX_HPKE_RECEIVER_CONFIG_SET: &str = "hpke_receiver_config_set";
-pub(crate) const KV_KEY_PREFIX_BEARER_TOKEN_LEADER: &str = "bearer_token/leader/task";
-pub(crate) const KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR: &str = "bearer_token/collector/task";
-pub(crate) const KV_KEY_PREFIX_TASK_CONFIG: &str = "config/task";
+pub(crate) const KV_KEY_PREFIX_HPKE_COLLECTOR_CONFIG: &str = "hpke_collector_config/task";
 pub(crate) const KV_BINDING_DAP_CONFIG: &str = "DAP_CONFIG";
 
 const DAP_BASE_URL: &str = "DAP_BASE_URL";
 
+/// Maximum length of a report annotation. Longer values of the annotation header are ignored.
+const MAX_REPORT_ANNOTATION_LEN: usize = 64;
+
+/// Annotation that stands in for each value of the annotation header that is not in
+/// `DaphneWorkerConfig::report_annotation_values`.
+pub(crate) const REPORT_ANNOTATION_OTHER: &str = "other";
+
 const INT_ERR_PEER_ABORT: &str = "request aborted by peer";
 const INT_ERR_PEER_RESP_MISSING_MEDIA_TYPE: &str = "peer response is missing media type";
 
@@ -85,6 +98,73 @@ struct MetricsPushConfig {
     bearer_token: BearerToken,
 }
 
+/// A previous assignment of reports to report storage shards. While a migration is in progress,
+/// reports are also checked for replays in the shard the previous assignment maps them to.
+#[derive(Deserialize)]
+pub(crate) struct ReportShardMigration {
+    /// Method used by the previous assignment.
+    #[serde(default = "ReportShardMigration::default_previous_scheme")]
+    previous_scheme: ReportShardScheme,
+
+    /// Shard count of the previous assignment.
+    previous_shard_count: u64,
+
+    /// Time at which the migration ends. This should be at least one report storage epoch after
+    /// the new assignment was deployed, after which no report accepted under the previous
+    /// assignment can be replayed.
+    until: Time,
+}
+
+impl ReportShardMigration {
+    /// Reports were assigned with the modulo scheme before rendezvous hashing was introduced.
+    fn default_previous_scheme() -> ReportShardScheme {
+        ReportShardScheme::Modulo
+    }
+}
+
+/// Helper: Limits on the load a peer may put on the aggregation endpoints, enforced over fixed
+/// one-minute windows. A request that would exceed either limit is rejected with HTTP status 429.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DaphneWorkerQuota {
+    /// Maximum number of requests per minute. If not set, the number of requests is not limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) requests_per_minute: Option<u64>,
+
+    /// Maximum number of reports per minute, i.e., the number of report shares across all
+    /// `AggregationJobInitReq`s. If not set, the number of reports is not limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reports_per_minute: Option<u64>,
+}
+
+impl DaphneWorkerQuota {
+    fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none() && self.reports_per_minute.is_none()
+    }
+}
+
+/// Helper: Quotas for the aggregation endpoints, keyed by the identity of the peer (see
+/// [`DaphneWorkerAuth::peer_identity`]).
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DaphneWorkerQuotaConfig {
+    /// Quota for peers that are not listed in `peers`.
+    #[serde(default)]
+    default: DaphneWorkerQuota,
+
+    /// Quota for specific peers.
+    #[serde(default)]
+    peers: HashMap<String, DaphneWorkerQuota>,
+}
+
+impl DaphneWorkerQuotaConfig {
+    /// Return the quota for the given peer, or `None` if the peer is not limited.
+    pub(crate) fn for_peer(&self, peer_identity: &str) -> Option<&DaphneWorkerQuota> {
+        let quota = self.peers.get(peer_identity).unwrap_or(&self.default);
+        (!quota.is_unlimited()).then_some(quota)
+    }
+}
+
 /// Daphne-Worker configuration, including long-lived parameters used across DAP tasks.
 pub(crate) struct DaphneWorkerConfig {
     /// Indicates if DaphneWorker is used as the Leader.
@@ -103,9 +183,19 @@ pub(crate) struct DaphneWorkerConfig {
     /// to (based on the report ID).
     report_shard_key: [u8; 32],
 
-    /// Shard count, the number of report storage shards. This should be a power of 2.
+    /// Shard count, the number of report storage shards.
     report_shard_count: u64,
 
+    /// Optional: Method for assigning reports to shards (default: `modulo`). With `rendezvous`,
+    /// the shard count can be changed without reassigning most reports.
+    report_shard_scheme: ReportShardScheme,
+
+    /// Optional: The previous assignment of reports to shards, if the shard count or assignment
+    /// method was changed recently. Deployments switching from the modulo assignment to
+    /// rendezvous hashing must configure this for at least one report storage epoch in order to
+    /// preserve replay protection.
+    report_shard_migration: Option<ReportShardMigration>,
+
     /// draft-dcook-ppm-dap-interop-test-design: Base URL of the Aggregator (unversioned). If set,
     /// this field is used for endpoint configuration for interop testing.
     base_url: Option<Url>,
@@ -121,49 +211,403 @@ pub(crate) struct DaphneWorkerConfig {
     /// configured by the Leader.
     pub(crate) helper_state_store_garbage_collect_after_secs: Option<Duration>,
 
-    /// Additional time to wait before deletng an instance of ReportsProcessed. Added to the value
-    /// of the `report_storage_epoch_duration` field of the global DAP configuration.
+    /// Additional time to wait before deletng an instance of ReportsProcessed. Added to the task's
+    /// report storage epoch duration (see [`DapTaskConfig::epoch_duration`]).
     pub(crate) processed_alarm_safety_interval: Duration,
 
+    /// Leader: How long to retain the result of a completed collection job. If not set, results
+    /// are retained indefinitely.
+    pub(crate) collection_result_ttl: Option<Duration>,
+
+    /// Leader: Number of shards of the collection job queue. Each task is assigned to one shard
+    /// (see [`collect_job_queue_shard`]). Changing the shard count moves some tasks to other
+    /// shards, so it should only be changed while no collection jobs are pending.
+    collect_job_queue_shard_count: u64,
+
+    /// How requests to DOs are retried on failure. Requests that are not idempotent are never
+    /// retried.
+    pub(crate) durable_retry_policy: DurableRetryPolicy,
+
+    /// How long a task config (or the absence of one) read from KV is cached by the isolate.
+    pub(crate) task_config_cache_ttl: Duration,
+
     /// Metrics push configuration.
     metrics_push_config: Option<MetricsPushConfig>,
+
+    /// Leader: Name of the HTTP header, set on upload requests (e.g., by a proxy that infers the
+    /// Client's platform), whose value is attached to each uploaded report as its annotation. If
+    /// not set, then reports are not annotated.
+    pub(crate) report_annotation_header: Option<String>,
+
+    /// Leader: Values of the annotation header that are recorded as is. The header is set by the
+    /// Client's request path, so any other value is recorded as [`REPORT_ANNOTATION_OTHER`] in
+    /// order to bound the number of distinct annotations.
+    pub(crate) report_annotation_values: HashSet<String>,
+
+    /// If set, then the response to a request that fails with an internal error is a generic
+    /// problem document that identifies the error only by a correlation ID. The error itself is
+    /// logged along with the correlation ID.
+    pub(crate) strict_errors: bool,
+
+    /// Report extensions recognized by the Aggregator. Only the maximum length of a report's
+    /// extensions is configured, with the optional `max_report_extensions_len` field; if not set,
+    /// then the length is bounded only by the encoding.
+    pub(crate) extension_registry: DapExtensionRegistry,
+
+    /// Optional: Token that authorizes administrative requests, such as deleting the data of a
+    /// task. If not set, then administrative requests are rejected.
+    pub(crate) admin_bearer_token: Option<BearerToken>,
+
+    /// Optional: Keys for authenticating internal commands, i.e., requests to the internal test
+    /// and administrative endpoints, indexed by key ID. If set, then every such request must be
+    /// authenticated with one of these keys. See [`crate::router::test_routes`].
+    pub(crate) internal_command_keys: Option<HashMap<String, Vec<u8>>>,
+
+    /// Helper: Optional quotas for the aggregation endpoints. If not set, then requests are not
+    /// limited. This field is not configured by the Leader.
+    pub(crate) helper_quotas: Option<DaphneWorkerQuotaConfig>,
+
+    /// Optional: HPKE private keys loaded from the `DAP_HPKE_PRIVATE_KEYS` secret, a JSON list of
+    /// receiver configs. These are used to decrypt ciphertexts for HPKE configs whose entry in
+    /// KV omits the private key.
+    pub(crate) hpke_private_keys: Vec<HpkeReceiverConfig>,
+
+    /// Helper: Optional keys for encrypting the Helper's state at rest, loaded from the
+    /// `DAP_HELPER_STATE_KEY` and `DAP_HELPER_STATE_KEY_PREVIOUS` secrets. If not set, then the
+    /// state is stored unencrypted. This field is not configured by the Leader.
+    pub(crate) helper_state_keys: Option<HelperStateKeys>,
+}
+
+/// Name of the environment variable that holds the configuration document. See
+/// [`DaphneWorkerConfig::from_json`].
+const DAP_CONFIG_JSON: &str = "DAP_CONFIG_JSON";
+
+/// How the value of an environment variable is layered onto the configuration document.
+#[derive(Clone, Copy)]
+enum EnvOverride {
+    /// The value is used as a string.
+    String,
+    /// The value is parsed as JSON (e.g., a number or an object).
+    Json,
+}
+
+/// Environment variables that override fields of the configuration document, along with the path
+/// of the field each one overrides.
+const ENV_OVERRIDES: &[(&str, &[&str], EnvOverride)] = &[
+    (
+        "DAP_AGGREGATOR_ROLE",
+        &["aggregator_role"],
+        EnvOverride::String,
+    ),
+    ("DAP_GLOBAL_CONFIG", &["global"], EnvOverride::Json),
+    (
+        "DAP_DEFAULT_VERSION",
+        &["default_version"],
+        EnvOverride::String,
+    ),
+    (DAP_BASE_URL, &["base_url"], EnvOverride::String),
+    ("DAP_DEPLOYMENT", &["deployment"], EnvOverride::String),
+    (
+        "DAP_REPORT_SHARD_COUNT",
+        &["report_shard_count"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_REPORT_SHARD_SCHEME",
+        &["report_shard_scheme"],
+        EnvOverride::String,
+    ),
+    (
+        "DAP_REPORT_SHARD_MIGRATION",
+        &["report_shard_migration"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_TASKPROV_HPKE_COLLECTOR_CONFIG",
+        &["taskprov", "hpke_collector_config"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_TASKPROV_LEADER_AUTH",
+        &["taskprov", "leader_auth"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_TASKPROV_COLLECTOR_AUTH",
+        &["taskprov", "collector_auth"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_HELPER_STATE_STORE_GARBAGE_COLLECT_AFTER_SECS",
+        &["durable", "helper_state_store_garbage_collect_after_secs"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_PROCESSED_ALARM_SAFETY_INTERVAL",
+        &["durable", "processed_alarm_safety_interval"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_COLLECTION_RESULT_TTL_SECS",
+        &["durable", "collection_result_ttl_secs"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_COLLECT_JOB_QUEUE_SHARD_COUNT",
+        &["durable", "collect_job_queue_shard_count"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_DURABLE_RETRY_POLICY",
+        &["durable", "retry"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_TASK_CONFIG_CACHE_TTL_SECS",
+        &["task_config_cache_ttl_secs"],
+        EnvOverride::Json,
+    ),
+    (
+        "DAP_METRICS_PUSH_SERVER_URL",
+        &["metrics", "push_server_url"],
+        EnvOverride::String,
+    ),
+    (
+        "DAP_REPORT_ANNOTATION_HEADER",
+        &["report_annotation_header"],
+        EnvOverride::String,
+    ),
+    (
+        "DAP_REPORT_ANNOTATION_VALUES",
+        &["report_annotation_values"],
+        EnvOverride::Json,
+    ),
+    ("DAP_STRICT_ERRORS", &["strict_errors"], EnvOverride::Json),
+    (
+        "DAP_MAX_REPORT_EXTENSIONS_LEN",
+        &["max_report_extensions_len"],
+        EnvOverride::Json,
+    ),
+    ("DAP_HELPER_QUOTAS", &["helper_quotas"], EnvOverride::Json),
+];
+
+/// Parse the keys for authenticating internal commands. They are encoded as a JSON object that
+/// maps each key ID to the hex-encoded key.
+fn parse_internal_command_keys(
+    keys: &str,
+) -> std::result::Result<HashMap<String, Vec<u8>>, String> {
+    let keys: HashMap<String, String> =
+        serde_json::from_str(keys).map_err(|e| format!("error while parsing JSON: {e}"))?;
+    keys.into_iter()
+        .map(|(key_id, key)| {
+            let key = hex::decode(key)
+                .map_err(|e| format!("error while parsing hex for key {key_id}: {e}"))?;
+            Ok((key_id, key))
+        })
+        .collect()
+}
+
+/// Replace each field of the configuration document for which the corresponding environment
+/// variable, as looked up by `var`, is set.
+fn apply_env_overrides(
+    doc: &mut serde_json::Value,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    for (name, path, kind) in ENV_OVERRIDES {
+        let Some(value) = var(name) else {
+            continue;
+        };
+        let value = match kind {
+            EnvOverride::String => serde_json::Value::String(value),
+            EnvOverride::Json => serde_json::from_str(&value)
+                .map_err(|e| Error::RustError(format!("Failed to parse {name}: {e}")))?,
+        };
+
+        let mut field = &mut *doc;
+        for key in *path {
+            if field.is_null() {
+                *field = serde_json::Value::Object(serde_json::Map::new());
+            }
+            let serde_json::Value::Object(fields) = field else {
+                return Err(Error::RustError(format!(
+                    "Failed to apply {name}: the field containing {key} is not an object"
+                )));
+            };
+            field = fields.entry(*key).or_insert(serde_json::Value::Null);
+        }
+        *field = value;
+    }
+    Ok(())
+}
+
+/// An object in the configuration document. Fields are removed as they are parsed so that any
+/// that remain can be reported as unknown.
+struct ConfigSection {
+    /// Path of this object in the document, used to name the offending field in errors.
+    path: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ConfigSection {
+    fn new(path: String, value: serde_json::Value) -> Result<Self> {
+        match value {
+            serde_json::Value::Object(fields) => Ok(Self { path, fields }),
+            _ if path.is_empty() => Err(Error::RustError(
+                "Invalid configuration document: expected an object".into(),
+            )),
+            _ => Err(Error::RustError(format!(
+                "Invalid value for {path}: expected an object"
+            ))),
+        }
+    }
+
+    fn field_path(&self, name: &str) -> String {
+        if self.path.is_empty() {
+            name.into()
+        } else {
+            format!("{}.{name}", self.path)
+        }
+    }
+
+    /// Parse an optional field. A field set to `null` is treated as absent.
+    fn get_opt<T: serde::de::DeserializeOwned>(&mut self, name: &str) -> Result<Option<T>> {
+        match self.fields.remove(name) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => serde_json::from_value(value).map(Some).map_err(|e| {
+                Error::RustError(format!("Invalid value for {}: {e}", self.field_path(name)))
+            }),
+        }
+    }
+
+    /// Parse a required field.
+    fn get<T: serde::de::DeserializeOwned>(&mut self, name: &str) -> Result<T> {
+        self.get_opt(name)?
+            .ok_or_else(|| Error::RustError(format!("Missing value for {}", self.field_path(name))))
+    }
+
+    /// Take an optional nested object.
+    fn section_opt(&mut self, name: &str) -> Result<Option<Self>> {
+        match self.fields.remove(name) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => Self::new(self.field_path(name), value).map(Some),
+        }
+    }
+
+    /// Check that every field of the object was parsed.
+    fn finish(self) -> Result<()> {
+        if let Some(name) = self.fields.keys().next() {
+            return Err(Error::RustError(format!(
+                "Unknown configuration field {}",
+                self.field_path(name)
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl DaphneWorkerConfig {
+    /// Load the configuration from the document stored in `DAP_CONFIG_JSON` (if set), layering
+    /// the individual environment variables on top. See [`Self::from_json`].
     pub(crate) fn from_worker_env(env: &Env) -> Result<Self> {
+        let json = match env.var(DAP_CONFIG_JSON) {
+            Ok(json) => json.to_string(),
+            Err(..) => "{}".into(),
+        };
+        Self::from_json(&json, env)
+    }
+
+    /// Load the configuration from a JSON document. For example:
+    ///
+    /// ```text
+    /// {
+    ///   "aggregator_role": "leader",
+    ///   "default_version": "v07",
+    ///   "deployment": "dev",
+    ///   "base_url": "http://127.0.0.1:8787/",
+    ///   "global": { ... },
+    ///   "report_shard_count": 2,
+    ///   "report_shard_scheme": "rendezvous",
+    ///   "report_shard_migration": { "previous_shard_count": 1, "until": 1700000000 },
+    ///   "taskprov": {
+    ///     "hpke_collector_config": { ... },
+    ///     "leader_auth": { "bearer_token": "..." },
+    ///     "collector_auth": { "bearer_token": "..." }
+    ///   },
+    ///   "durable": {
+    ///     "helper_state_store_garbage_collect_after_secs": 10,
+    ///     "processed_alarm_safety_interval": 300,
+    ///     "collection_result_ttl_secs": 604800,
+    ///     "collect_job_queue_shard_count": 4,
+    ///     "retry": { "max_retries": 4, "base_delay_ms": 250, "max_jitter_ms": 100 }
+    ///   },
+    ///   "task_config_cache_ttl_secs": 300,
+    ///   "metrics": { "push_server_url": "https://metrics.example.com/push" },
+    ///   "report_annotation_header": "x-report-annotation",
+    ///   "report_annotation_values": ["android", "ios"],
+    ///   "strict_errors": true,
+    ///   "max_report_extensions_len": 1024,
+    ///   "helper_quotas": {
+    ///     "default": { "requests_per_minute": 600, "reports_per_minute": 100000 },
+    ///     "peers": { "tls_client_cert:CN=leader.example.com": { "reports_per_minute": 1000000 } }
+    ///   }
+    /// }
+    /// ```
+    ///
+    /// Each environment variable listed in [`ENV_OVERRIDES`] that is set replaces the
+    /// corresponding field of the document. Secrets (`DAP_COLLECTION_JOB_ID_KEY`,
+    /// `DAP_REPORT_SHARD_KEY`, `DAP_TASKPROV_VDAF_VERIFY_KEY_INIT`,
+    /// `DAP_METRICS_PUSH_BEARER_TOKEN`, `DAP_ADMIN_BEARER_TOKEN`, `DAP_INTERNAL_COMMAND_KEYS`,
+    /// `DAP_HPKE_PRIVATE_KEYS`, `DAP_HELPER_STATE_KEY`, and `DAP_HELPER_STATE_KEY_PREVIOUS`) are
+    /// never read from the document.
+    pub(crate) fn from_json(json: &str, env: &Env) -> Result<Self> {
+        let mut doc: serde_json::Value = serde_json::from_str(json).map_err(|e| {
+            Error::RustError(format!("Failed to parse configuration document: {e}"))
+        })?;
+        apply_env_overrides(&mut doc, |name| {
+            env.var(name).ok().map(|value| value.to_string())
+        })?;
+        Self::from_document(doc, |name| {
+            env.secret(name).ok().map(|value| value.to_string())
+        })
+    }
+
+    /// Build the configuration from the (possibly overridden) document, resolving secrets with
+    /// `secret`.
+    fn from_document(
+        doc: serde_json::Value,
+        secret: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
         let load_key = |name| {
-            let key = env
-                .secret(name)
-                .map_err(|e| format!("failed to load {name}: {e}"))?
-                .to_string();
+            let key = secret(name).ok_or_else(|| format!("failed to load {name}: not set"))?;
             let key = hex::decode(key)
                 .map_err(|e| format!("failed to load {name}: error while parsing hex: {e}"))?;
             key.try_into()
                 .map_err(|_| format!("failed to load {name}: unexpected length"))
         };
 
-        let is_leader = match env.var("DAP_AGGREGATOR_ROLE")?.to_string().as_str() {
+        let mut doc = ConfigSection::new(String::new(), doc)?;
+
+        let is_leader = match doc.get::<String>("aggregator_role")?.as_str() {
             "leader" => true,
             "helper" => false,
             other => {
                 return Err(Error::RustError(format!(
-                    "Invalid value for DAP_AGGREGATOR_ROLE: '{other}'",
+                    "Invalid value for aggregator_role: '{other}'",
                 )))
             }
         };
 
-        let global: DapGlobalConfig =
-            serde_json::from_str(env.var("DAP_GLOBAL_CONFIG")?.to_string().as_ref())
-                .map_err(|e| Error::RustError(format!("Failed to parse DAP_GLOBAL_CONFIG: {e}")))?;
+        let global: DapGlobalConfig = doc.get("global")?;
+        global
+            .validate()
+            .map_err(|e| Error::RustError(format!("Invalid value for global: {e}")))?;
 
-        let default_version =
-            DapVersion::from(env.var("DAP_DEFAULT_VERSION")?.to_string().as_ref());
+        let default_version = DapVersion::from(doc.get::<String>("default_version")?.as_str());
 
-        let base_url = if let Ok(base_url) = env.var(DAP_BASE_URL) {
+        let base_url = if let Some(base_url) = doc.get_opt::<String>("base_url")? {
             let base_url: Url = base_url
-                .to_string()
                 .parse()
-                .map_err(|e| Error::RustError(format!("failed to parse {DAP_BASE_URL}: {e}")))?;
+                .map_err(|e| Error::RustError(format!("Invalid value for base_url: {e}")))?;
             Some(base_url)
         } else {
             None
@@ -177,77 +621,70 @@ impl DaphneWorkerConfig {
 
         let report_shard_key = load_key("DAP_REPORT_SHARD_KEY")?;
 
-        let report_shard_count: u64 = env
-            .var("DAP_REPORT_SHARD_COUNT")?
-            .to_string()
-            .parse()
-            .map_err(|err| {
-                Error::RustError(format!("Failed to parse DAP_REPORT_SHARD_COUNT: {err}"))
-            })?;
+        let report_shard_count: u64 = doc.get("report_shard_count")?;
+        if report_shard_count == 0 {
+            return Err(Error::RustError(
+                "Invalid value for report_shard_count: shard count must be positive".into(),
+            ));
+        }
 
-        let deployment = if let Ok(deployment) = env.var("DAP_DEPLOYMENT") {
-            match deployment.to_string().as_str() {
-                "prod" => DaphneWorkerDeployment::Prod,
-                "dev" => DaphneWorkerDeployment::Dev,
-                s => {
-                    return Err(Error::RustError(format!(
-                        "Invalid value for DAP_DEPLOYMENT: {s}",
-                    )))
-                }
+        let report_shard_scheme = doc
+            .get_opt::<ReportShardScheme>("report_shard_scheme")?
+            .unwrap_or_default();
+
+        let report_shard_migration: Option<ReportShardMigration> =
+            doc.get_opt("report_shard_migration")?;
+        if matches!(&report_shard_migration, Some(migration) if migration.previous_shard_count == 0)
+        {
+            return Err(Error::RustError(
+                "Invalid value for report_shard_migration: shard count must be positive".into(),
+            ));
+        }
+
+        let deployment = match doc.get_opt::<String>("deployment")?.as_deref() {
+            Some("prod") | None => DaphneWorkerDeployment::Prod,
+            Some("dev") => DaphneWorkerDeployment::Dev,
+            Some(s) => {
+                return Err(Error::RustError(format!(
+                    "Invalid value for deployment: {s}",
+                )))
             }
-        } else {
-            DaphneWorkerDeployment::default()
         };
         if !matches!(deployment, DaphneWorkerDeployment::Prod) {
             trace!("DAP deployment override applied: {deployment:?}");
         }
 
+        let taskprov_section = doc.section_opt("taskprov")?;
         let taskprov = if global.taskprov_version.is_some() {
-            let hpke_collector_config = serde_json::from_str(
-                env.var("DAP_TASKPROV_HPKE_COLLECTOR_CONFIG")?
-                    .to_string()
-                    .as_ref(),
-            )?;
+            let mut section = taskprov_section
+                .ok_or_else(|| Error::RustError("Missing value for taskprov".into()))?;
+            let hpke_collector_config = section.get("hpke_collector_config")?;
 
             const DAP_TASKPROV_VDAF_VERIFY_KEY_INIT: &str = "DAP_TASKPROV_VDAF_VERIFY_KEY_INIT";
             let vdaf_verify_key_init =
-                hex::decode(env.secret(DAP_TASKPROV_VDAF_VERIFY_KEY_INIT)?.to_string())
-                    .map_err(|e| {
-                        Error::RustError(format!(
-                            "{DAP_TASKPROV_VDAF_VERIFY_KEY_INIT}: Failed to decode hex: {e}"
-                        ))
-                    })?
-                    .try_into()
-                    .map_err(|_| {
-                        Error::RustError(format!(
-                            "{DAP_TASKPROV_VDAF_VERIFY_KEY_INIT}: Incorrect length"
-                        ))
-                    })?;
+                hex::decode(secret(DAP_TASKPROV_VDAF_VERIFY_KEY_INIT).ok_or_else(|| {
+                    Error::RustError(format!("{DAP_TASKPROV_VDAF_VERIFY_KEY_INIT}: Not set"))
+                })?)
+                .map_err(|e| {
+                    Error::RustError(format!(
+                        "{DAP_TASKPROV_VDAF_VERIFY_KEY_INIT}: Failed to decode hex: {e}"
+                    ))
+                })?
+                .try_into()
+                .map_err(|_| {
+                    Error::RustError(format!(
+                        "{DAP_TASKPROV_VDAF_VERIFY_KEY_INIT}: Incorrect length"
+                    ))
+                })?;
 
-            const DAP_TASKPROV_LEADER_AUTH: &str = "DAP_TASKPROV_LEADER_AUTH";
-            let leader_auth =
-                serde_json::from_str(env.var(DAP_TASKPROV_LEADER_AUTH)?.to_string().as_ref())
-                    .map_err(|e| {
-                        error!("DaphneWorkerConfig: error parsing {DAP_TASKPROV_LEADER_AUTH}: {e}");
-                        e
-                    })?;
+            let leader_auth = section.get("leader_auth")?;
 
             let collector_auth = if is_leader {
-                const DAP_TASKPROV_COLLECTOR_AUTH: &str = "DAP_TASKPROV_COLLECTOR_AUTH";
-                Some(
-                    serde_json::from_str(
-                        env.var(DAP_TASKPROV_COLLECTOR_AUTH)?.to_string().as_ref(),
-                    )
-                    .map_err(|e| {
-                        error!(
-                            "DaphneWorkerConfig: error parsing {DAP_TASKPROV_COLLECTOR_AUTH}: {e}"
-                        );
-                        e
-                    })?,
-                )
+                Some(section.get("collector_auth")?)
             } else {
                 None
             };
+            section.finish()?;
 
             Some(TaskprovConfig {
                 hpke_collector_config,
@@ -259,96 +696,410 @@ impl DaphneWorkerConfig {
             None
         };
 
-        let helper_state_store_garbage_collect_after_secs = if !is_leader {
-            Some(Duration::from_secs(
-                env.var("DAP_HELPER_STATE_STORE_GARBAGE_COLLECT_AFTER_SECS")?
-                    .to_string()
-                    .parse()
-                    .map_err(|err| {
-                        Error::RustError(format!(
-                            "Failed to parse DAP_HELPER_STATE_STORE_GARBAGE_COLLECT_AFTER_SECS: {err}"
-                        ))
-                    })?,
-            ))
-        } else {
-            None
-        };
-
-        let processed_alarm_safety_interval = Duration::from_secs(
-            env.var("DAP_PROCESSED_ALARM_SAFETY_INTERVAL")?
-                .to_string()
-                .parse()
-                .map_err(|err| {
-                    Error::RustError(format!(
-                        "Failed to parse DAP_PROCESSED_ALARM_SAFETY_INTERVAL: {err}"
+        let mut durable = doc
+            .section_opt("durable")?
+            .ok_or_else(|| Error::RustError("Missing value for durable".into()))?;
+
+        // Only the Helper uses this field, but the Leader accepts it so that both roles can share
+        // a document.
+        let helper_state_store_garbage_collect_after_secs: Option<u64> =
+            durable.get_opt("helper_state_store_garbage_collect_after_secs")?;
+        let helper_state_store_garbage_collect_after_secs =
+            match (is_leader, helper_state_store_garbage_collect_after_secs) {
+                (true, _) => None,
+                (false, Some(secs)) => Some(Duration::from_secs(secs)),
+                (false, None) => {
+                    return Err(Error::RustError(
+                        "Missing value for durable.helper_state_store_garbage_collect_after_secs"
+                            .into(),
                     ))
-                })?,
-        );
+                }
+            };
+
+        let processed_alarm_safety_interval =
+            Duration::from_secs(durable.get("processed_alarm_safety_interval")?);
+
+        let collection_result_ttl = durable
+            .get_opt("collection_result_ttl_secs")?
+            .map(Duration::from_secs);
+
+        let collect_job_queue_shard_count: u64 = durable
+            .get_opt("collect_job_queue_shard_count")?
+            .unwrap_or(1);
+        if collect_job_queue_shard_count == 0 {
+            return Err(Error::RustError(
+                "Invalid value for durable.collect_job_queue_shard_count: must be positive".into(),
+            ));
+        }
+
+        let durable_retry_policy = durable.get_opt("retry")?.unwrap_or_default();
+        durable.finish()?;
+
+        let task_config_cache_ttl = doc
+            .get_opt("task_config_cache_ttl_secs")?
+            .map_or(DEFAULT_TASK_CONFIG_CACHE_TTL, Duration::from_secs);
 
-        const DAP_METRICS_PUSH_SERVER_URL: &str = "DAP_METRICS_PUSH_SERVER_URL";
         const DAP_METRICS_PUSH_BEARER_TOKEN: &str = "DAP_METRICS_PUSH_BEARER_TOKEN";
-        let metrics_push_config = match (
-            env.var(DAP_METRICS_PUSH_SERVER_URL),
-            env.var(DAP_METRICS_PUSH_BEARER_TOKEN),
-        ) {
-            (Ok(server_str), Ok(bearer_token_str)) => Some(MetricsPushConfig {
-                server: server_str.to_string().parse().map_err(|err| {
+        let push_server_url: Option<String> = match doc.section_opt("metrics")? {
+            Some(mut metrics) => {
+                let push_server_url = metrics.get_opt("push_server_url")?;
+                metrics.finish()?;
+                push_server_url
+            }
+            None => None,
+        };
+        let metrics_push_config = match (push_server_url, secret(DAP_METRICS_PUSH_BEARER_TOKEN)) {
+            (Some(server), Some(bearer_token)) => Some(MetricsPushConfig {
+                server: server.parse().map_err(|err| {
                     Error::RustError(format!(
-                        "Failed to parse {DAP_METRICS_PUSH_SERVER_URL}: {err:?}"
+                        "Invalid value for metrics.push_server_url: {err:?}"
                     ))
                 })?,
-                bearer_token: BearerToken::from(bearer_token_str.to_string()),
+                bearer_token: BearerToken::from(bearer_token),
             }),
-            (Err(..), Err(..)) => None,
-            (Ok(..), Err(..)) => {
+            (None, None) => None,
+            (Some(..), None) => {
                 return Err(Error::RustError(
                     "failed to configure metrics push: missing bearer token".into(),
                 ))
             }
-            (Err(..), Ok(..)) => {
+            (None, Some(..)) => {
                 return Err(Error::RustError(
                     "failed to configure metrics push: missing server URL".into(),
                 ))
             }
         };
 
+        let report_annotation_header = doc.get_opt("report_annotation_header")?;
+        let report_annotation_values = doc
+            .get_opt::<HashSet<String>>("report_annotation_values")?
+            .unwrap_or_default();
+
+        let strict_errors = doc.get_opt("strict_errors")?.unwrap_or(false);
+
+        let mut extension_registry = DapExtensionRegistry::new();
+        if let Some(max_extensions_len) = doc.get_opt("max_report_extensions_len")? {
+            extension_registry.set_max_extensions_len(max_extensions_len);
+        }
+
+        // Only the Helper enforces quotas, but the Leader accepts the field so that both roles can
+        // share a document.
+        let helper_quotas: Option<DaphneWorkerQuotaConfig> = doc.get_opt("helper_quotas")?;
+        let helper_quotas = if is_leader { None } else { helper_quotas };
+
+        let admin_bearer_token = secret("DAP_ADMIN_BEARER_TOKEN").map(BearerToken::from);
+
+        let internal_command_keys = secret("DAP_INTERNAL_COMMAND_KEYS")
+            .map(|keys| parse_internal_command_keys(&keys))
+            .transpose()
+            .map_err(|e| format!("failed to load DAP_INTERNAL_COMMAND_KEYS: {e}"))?;
+
+        let hpke_private_keys = secret("DAP_HPKE_PRIVATE_KEYS")
+            .map(|keys| serde_json::from_str(&keys))
+            .transpose()
+            .map_err(|e| format!("failed to load DAP_HPKE_PRIVATE_KEYS: {e}"))?
+            .unwrap_or_default();
+
+        let helper_state_keys = if is_leader {
+            None
+        } else {
+            match (
+                secret("DAP_HELPER_STATE_KEY").is_some(),
+                secret("DAP_HELPER_STATE_KEY_PREVIOUS").is_some(),
+            ) {
+                (true, has_previous) => Some(HelperStateKeys {
+                    current: HelperStateKey::new(load_key("DAP_HELPER_STATE_KEY")?),
+                    previous: if has_previous {
+                        Some(HelperStateKey::new(load_key(
+                            "DAP_HELPER_STATE_KEY_PREVIOUS",
+                        )?))
+                    } else {
+                        None
+                    },
+                }),
+                (false, true) => {
+                    return Err(Error::RustError(
+                        "DAP_HELPER_STATE_KEY_PREVIOUS is set, but DAP_HELPER_STATE_KEY is not"
+                            .into(),
+                    ))
+                }
+                (false, false) => None,
+            }
+        };
+
+        doc.finish()?;
+
         Ok(Self {
             global,
             deployment,
             collection_job_id_key,
             report_shard_key,
             report_shard_count,
+            report_shard_scheme,
+            report_shard_migration,
             base_url,
             is_leader,
             taskprov,
             default_version,
             helper_state_store_garbage_collect_after_secs,
             processed_alarm_safety_interval,
+            collection_result_ttl,
+            collect_job_queue_shard_count,
+            durable_retry_policy,
+            task_config_cache_ttl,
             metrics_push_config,
+            report_annotation_header,
+            report_annotation_values,
+            strict_errors,
+            extension_registry,
+            admin_bearer_token,
+            internal_command_keys,
+            helper_quotas,
+            hpke_private_keys,
+            helper_state_keys,
         })
     }
 
+    /// Derive the name of the collection job queue shard to which the task is assigned.
+    pub(crate) fn durable_name_collect_job_queue(&self, task_id: &TaskId) -> String {
+        durable_name_queue(collect_job_queue_shard(
+            task_id,
+            self.collect_job_queue_shard_count,
+        ))
+    }
+
+    /// Derive the names of all shards of the collection job queue.
+    pub(crate) fn durable_names_collect_job_queue(&self) -> impl Iterator<Item = String> {
+        (0..self.collect_job_queue_shard_count).map(durable_name_queue)
+    }
+
     /// Derive the batch name for a report for the given task and with the given report ID.
     pub(crate) fn durable_name_report_store(
         &self,
         task_config: &DapTaskConfig,
-        task_id_hex: &str,
+        task_id: &TaskId,
         report_id: &ReportId,
         report_time: Time,
     ) -> String {
-        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &self.report_shard_key);
-        let tag = ring::hmac::sign(&key, report_id.as_ref());
-        let shard = u64::from_be_bytes(
-            tag.as_ref()[..std::mem::size_of::<u64>()]
-                .try_into()
-                .unwrap(),
-        ) % self.report_shard_count;
-        let epoch = report_time - (report_time % self.global.report_storage_epoch_duration);
-        durable_name_report_store(&task_config.version, task_id_hex, epoch, shard)
+        let (epoch, _) =
+            self.report_store_epochs(task_config.epoch_duration(&self.global), report_time);
+        durable_name_report_store(
+            &task_config.version,
+            task_id,
+            epoch,
+            self.report_shard(report_id),
+        )
+    }
+
+    /// Map an annotation to itself if it is one of the configured values and to
+    /// [`REPORT_ANNOTATION_OTHER`] otherwise.
+    pub(crate) fn report_annotation_label<'a>(&self, annotation: &'a str) -> &'a str {
+        if self.report_annotation_values.contains(annotation) {
+            annotation
+        } else {
+            REPORT_ANNOTATION_OTHER
+        }
+    }
+
+    /// Derive the names of the report stores, other than the report's own, that must also be
+    /// checked for replays of the report. These are:
+    ///
+    /// - the stores in the epochs adjacent to the report's own, if the report's timestamp is close
+    ///   to an epoch boundary; and
+    /// - the stores the previous shard assignment maps the report to, if a shard migration is in
+    ///   progress at time `now`; and
+    /// - the stores of the epochs of the previous epoch duration, if a migration of the task's
+    ///   report storage is in progress at time `now`.
+    pub(crate) fn durable_names_other_report_stores(
+        &self,
+        task_config: &DapTaskConfig,
+        task_id: &TaskId,
+        report_id: &ReportId,
+        report_time: Time,
+        now: Time,
+    ) -> Vec<String> {
+        let epoch_duration = task_config.epoch_duration(&self.global);
+        let (epoch, _) = self.report_store_epochs(epoch_duration, report_time);
+        let shard = self.report_shard(report_id);
+        let own_name = durable_name_report_store(&task_config.version, task_id, epoch, shard);
+
+        let mut shards = vec![shard];
+        if let Some(previous_shard) = self.previous_report_shard(report_id, now) {
+            if previous_shard != shard {
+                shards.push(previous_shard);
+            }
+        }
+
+        let mut epoch_durations = vec![epoch_duration];
+        if let Some(previous_epoch_duration) = task_config.previous_epoch_duration(now) {
+            if previous_epoch_duration != epoch_duration {
+                epoch_durations.push(previous_epoch_duration);
+            }
+        }
+
+        let mut names = Vec::new();
+        for epoch_duration in epoch_durations {
+            let (epoch, adjacent_epochs) = self.report_store_epochs(epoch_duration, report_time);
+            for epoch in std::iter::once(epoch).chain(adjacent_epochs) {
+                for shard in &shards {
+                    // Epochs of different durations may start at the same time.
+                    let name =
+                        durable_name_report_store(&task_config.version, task_id, epoch, *shard);
+                    if name != own_name && !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    /// Derive the names of the report stores in which the report is marked as aggregated: the
+    /// report's own store followed by the stores returned by
+    /// [`durable_names_other_report_stores`](Self::durable_names_other_report_stores).
+    pub(crate) fn durable_names_report_stores_to_mark(
+        &self,
+        task_config: &DapTaskConfig,
+        task_id: &TaskId,
+        report_id: &ReportId,
+        report_time: Time,
+        now: Time,
+    ) -> Vec<String> {
+        let mut names =
+            vec![self.durable_name_report_store(task_config, task_id, report_id, report_time)];
+        names.extend(self.durable_names_other_report_stores(
+            task_config,
+            task_id,
+            report_id,
+            report_time,
+            now,
+        ));
+        names
+    }
+
+    /// Derive the names of all report stores of the given task that may still hold data at time
+    /// `now`. A report store expires some time after its epoch has passed, so this covers every
+    /// epoch from two epochs (plus the alarm safety interval) before `now` up to the latest epoch
+    /// for which reports are accepted, every shard of the current and previous assignments, every
+    /// version of the task, and the epochs of the previous epoch duration if the task's report
+    /// storage is being migrated.
+    pub(crate) fn durable_names_report_stores_for_task(
+        &self,
+        task_config: &DapTaskConfig,
+        task_id: &TaskId,
+        now: Time,
+    ) -> Vec<String> {
+        let max_future_time_skew = self.global.report_storage_max_future_time_skew;
+        let end = now.saturating_add(max_future_time_skew);
+        let shard_count = self
+            .report_shard_migration
+            .as_ref()
+            .map_or(self.report_shard_count, |migration| {
+                self.report_shard_count.max(migration.previous_shard_count)
+            });
+
+        // Stores of the previous epoch duration may outlive the migration, so they are included
+        // for as long as the migration is configured.
+        let epoch_durations = std::iter::once(task_config.epoch_duration(&self.global)).chain(
+            task_config
+                .report_storage_epoch_migration
+                .as_ref()
+                .map(|migration| migration.previous_duration),
+        );
+
+        let mut names = Vec::new();
+        for epoch_duration in epoch_durations {
+            let start = now.saturating_sub(
+                2 * epoch_duration
+                    + max_future_time_skew
+                    + self.processed_alarm_safety_interval.as_secs(),
+            );
+            let mut epoch = start - (start % epoch_duration);
+            while epoch <= end {
+                for version in task_config.versions() {
+                    names.extend(
+                        (0..shard_count).map(|shard| {
+                            durable_name_report_store(&version, task_id, epoch, shard)
+                        }),
+                    );
+                }
+                epoch += epoch_duration;
+            }
+        }
+        // Epochs of different durations may start at the same time.
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    fn report_store_epochs(&self, epoch_duration: u64, report_time: Time) -> (u64, Vec<u64>) {
+        // A report may be replayed with any timestamp the Aggregator would accept, so the window
+        // around each epoch boundary is the permitted clock skew.
+        report_store_epochs(
+            report_time,
+            epoch_duration,
+            self.global.report_storage_max_future_time_skew,
+        )
+    }
+
+    fn report_shard(&self, report_id: &ReportId) -> u64 {
+        report_shard(
+            self.report_shard_scheme,
+            &self.report_shard_key,
+            self.report_shard_count,
+            report_id,
+        )
+    }
+
+    /// Return the shard the previous assignment maps the report to, if a shard migration is in
+    /// progress at time `now`.
+    fn previous_report_shard(&self, report_id: &ReportId, now: Time) -> Option<u64> {
+        self.report_shard_migration
+            .as_ref()
+            .filter(|migration| now < migration.until)
+            .map(|migration| {
+                report_shard(
+                    migration.previous_scheme,
+                    &self.report_shard_key,
+                    migration.previous_shard_count,
+                    report_id,
+                )
+            })
+    }
+}
+
+pub(crate) type HpkeRecieverConfigList = Vec<HpkeReceiverConfigEntry>;
+
+/// An entry of the HPKE receiver config list stored in KV. The private key may be omitted, in
+/// which case decryption is delegated to an [`HpkePrivateKeyStore`]: either the one configured
+/// for the router or the keys loaded from `DAP_HPKE_PRIVATE_KEYS`.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum HpkeReceiverConfigEntry {
+    WithPrivateKey(HpkeReceiverConfig),
+    PublicOnly { config: HpkeConfig },
+}
+
+impl HpkeReceiverConfigEntry {
+    pub(crate) fn config(&self) -> &HpkeConfig {
+        match self {
+            Self::WithPrivateKey(receiver) => &receiver.config,
+            Self::PublicOnly { config } => config,
+        }
     }
 }
 
-pub(crate) type HpkeRecieverConfigList = Vec<HpkeReceiverConfig>;
+/// Override of the Collector's HPKE configuration for a task, stored in KV. This allows the
+/// Collector to rotate its key without re-provisioning the task.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct CollectorHpkeConfigOverride {
+    /// ID of the HPKE config to which aggregate shares are encrypted.
+    pub(crate) active_config_id: u8,
+
+    /// The Collector's HPKE configs. During a key rotation this list may contain both the old and
+    /// new configs, so that the Collector can switch by updating `active_config_id` only.
+    pub(crate) configs: Vec<HpkeConfig>,
+}
 
 /// Daphne-Worker per-isolate state, which may be used by multiple requests. Includes long-lived configuration,
 /// cached responses from KV, etc.
@@ -368,8 +1119,11 @@ pub(crate) struct DaphneWorkerIsolateState {
     /// Collector bearer token per task.
     collector_bearer_tokens: Arc<RwLock<HashMap<TaskId, BearerToken>>>,
 
-    /// Task list.
-    pub(crate) tasks: Arc<RwLock<HashMap<TaskId, DapTaskConfig>>>,
+    /// Cached task configs.
+    pub(crate) tasks: TaskConfigCache,
+
+    /// Cached rejections of malformed taskprov advertisements.
+    pub(crate) taskprov_rejections: TaskprovRejectionCache,
 }
 
 impl DaphneWorkerIsolateState {
@@ -379,13 +1133,18 @@ impl DaphneWorkerIsolateState {
         // TODO Configure this client to use HTTPS only, except if running in a test environment.
         let client = reqwest_wasm::Client::new();
 
+        let tasks = TaskConfigCache::new(config.task_config_cache_ttl);
+        let taskprov_rejections =
+            TaskprovRejectionCache::new(config.task_config_cache_ttl.as_secs());
+
         Ok(Self {
             config,
             client,
             hpke_receiver_configs: Arc::new(RwLock::new(HashMap::new())),
             leader_bearer_tokens: Arc::new(RwLock::new(HashMap::new())),
             collector_bearer_tokens: Arc::new(RwLock::new(HashMap::new())),
-            tasks: Arc::new(RwLock::new(HashMap::new())),
+            tasks,
+            taskprov_rejections,
         })
     }
 
@@ -402,7 +1161,10 @@ impl DaphneWorkerIsolateState {
         clear_guarded_map!(hpke_receiver_configs);
         clear_guarded_map!(leader_bearer_tokens);
         clear_guarded_map!(collector_bearer_tokens);
-        clear_guarded_map!(tasks);
+        self.tasks
+            .clear()
+            .map_err(|e| fatal_error!(err = ?e, "failed to clear task config cache"))?;
+        self.taskprov_rejections.clear();
         Ok(())
     }
 }
@@ -427,6 +1189,31 @@ pub(crate) struct DaphneWorkerRequestState<'srv> {
 
     /// Audit logging
     pub(crate) audit_log: &'srv dyn AuditLog,
+
+    /// Source of the current time.
+    pub(crate) time_source: &'srv dyn TimeSource,
+
+    /// Store of the HPKE private keys that are not stored in KV, if any.
+    pub(crate) hpke_private_key_store: Option<&'srv dyn HpkePrivateKeyStore>,
+
+    /// Role of the sender of the request. Set once the request has been parsed.
+    pub(crate) sender: Cell<Option<DapSender>>,
+
+    /// Leader: Annotation for the reports uploaded in the request. Set once the request has been
+    /// parsed (see `DaphneWorkerConfig::report_annotation_header`).
+    pub(crate) report_annotation: RefCell<Option<String>>,
+
+    /// Leader: Batch buckets precomputed for the reports drained in the request, if any. Set by
+    /// `DapLeader::get_reports()` and trusted by `DapReportInitializer::initialize_reports()`.
+    pub(crate) precomputed_batch_buckets: RefCell<HashMap<ReportId, DapPrecomputedBatchBucket>>,
+
+    /// DAP version of the request. Set once the request has been parsed. Task configs are looked
+    /// up with the view of the task for this version (see `DapTaskConfig::for_version`).
+    pub(crate) version: Cell<Option<DapVersion>>,
+
+    /// Content coding of the response, negotiated from the "accept-encoding" header of the
+    /// request. Set once the request has been parsed.
+    pub(crate) response_encoding: Cell<Option<DapContentEncoding>>,
 }
 
 impl<'srv> DaphneWorkerRequestState<'srv> {
@@ -435,6 +1222,8 @@ impl<'srv> DaphneWorkerRequestState<'srv> {
         req: &Request,
         error_reporter: &'srv dyn ErrorReporter,
         audit_log: &'srv dyn AuditLog,
+        time_source: &'srv dyn TimeSource,
+        hpke_private_key_store: Option<&'srv dyn HpkePrivateKeyStore>,
     ) -> Result<Self> {
         let prometheus_registry = Registry::new();
         let metrics = DaphneWorkerMetrics::register(&prometheus_registry, None)
@@ -455,6 +1244,13 @@ impl<'srv> DaphneWorkerRequestState<'srv> {
             host,
             error_reporter,
             audit_log,
+            time_source,
+            hpke_private_key_store,
+            sender: Cell::new(None),
+            report_annotation: RefCell::new(None),
+            precomputed_batch_buckets: RefCell::new(HashMap::new()),
+            version: Cell::new(None),
+            response_encoding: Cell::new(None),
         })
     }
 
@@ -505,28 +1301,50 @@ impl<'srv> DaphneWorkerRequestState<'srv> {
     }
 
     pub(crate) fn dap_abort_to_worker_response(&self, e: DapAbort) -> Result<Response> {
-        let status = if matches!(e, DapAbort::Internal(..)) {
+        let status = e.http_status();
+        let retry_after = e.retry_after();
+        if matches!(e, DapAbort::Internal(..)) {
             self.error_reporter.report_abort(&e);
-            500
-        } else {
-            400
-        };
+        }
         self.metrics
             .dap_abort_counter
             // this to string is bounded by the
             // number of variants in the enum
             .with_label_values(&[&self.host, &e.to_string()])
             .inc();
-        error!(error = ?e, "request aborted");
-        let problem_details = e.into_problem_details();
+        e.log_and_count(
+            &self.metrics.daphne.with_host(&self.host),
+            self.sender.get(),
+        );
+        let problem_details =
+            if self.isolate_state.config.strict_errors && matches!(e, DapAbort::Internal(..)) {
+                let correlation_id = hex::encode(rand::random::<[u8; 16]>());
+                error!(correlation_id = %correlation_id, error = ?e, "internal error");
+                e.into_strict_problem_details(&correlation_id)
+            } else {
+                e.into_problem_details()
+            };
         let mut headers = Headers::new();
         headers.set("Content-Type", "application/problem+json")?;
+        if let Some(retry_after) = retry_after {
+            headers.set("Retry-After", &retry_after.to_string())?;
+        }
         Ok(Response::from_json(&problem_details)?
             .with_status(status)
             .with_headers(headers))
     }
 }
 
+/// Result of [`DaphneWorker::put_task_config`].
+pub(crate) enum TaskConfigPutResult {
+    /// The task config was written as the given revision.
+    Ok(DapTaskConfigRecord),
+
+    /// The current revision of the task config, if any, is not the expected one. Nothing was
+    /// written.
+    Conflict(Option<DapTaskConfigRecord>),
+}
+
 /// Daphne-Worker, used to handle a DAP request. Constructed from `DaphneWorkerState::handler()`.
 pub(crate) struct DaphneWorker<'srv> {
     pub(crate) state: &'srv DaphneWorkerRequestState<'srv>,
@@ -536,6 +1354,8 @@ pub(crate) struct DaphneWorker<'srv> {
 impl<'srv> DaphneWorker<'srv> {
     pub(crate) fn durable(&self) -> DurableConnector<'_> {
         DurableConnector::new(self.env)
+            .with_metrics(&self.state.metrics, &self.state.host)
+            .with_retry(&self.state.isolate_state.config.durable_retry_policy)
     }
 
     pub(crate) fn kv(&self) -> Result<KvStore> {
@@ -745,27 +1565,190 @@ impl<'srv> DaphneWorker<'srv> {
         .await
     }
 
-    /// Retrieve from KV the configuration for the given task.
+    /// Retrieve from KV the configuration for the given task. The result is cached by the
+    /// isolate, including if the task is not found.
+    ///
+    /// When handling a request, the view of the task for the DAP version of the request is
+    /// returned, so that the endpoints and the names of durable objects pertain to that version.
+    /// If the task is not offered in that version, then the task's primary configuration is
+    /// returned and the request is rejected by the caller.
     pub(crate) async fn get_task_config<'req>(
         &self,
         task_id: Cow<'req, TaskId>,
     ) -> Result<Option<DapTaskConfigKvPair<'req>>> {
-        self.kv_get_cached(
-            &self.isolate_state().tasks,
-            KV_KEY_PREFIX_TASK_CONFIG,
-            task_id,
-        )
-        .await
+        let cache = &self.isolate_state().tasks;
+        let task_config = if let Some(task_config) = cache.get(&task_id)? {
+            tracing::debug!(%task_id, "found task config in cache");
+            task_config
+        } else {
+            let kv_key = format!("{KV_KEY_PREFIX_TASK_CONFIG}/{task_id}");
+            tracing::debug!(%kv_key, "looking up key in kv");
+            let task_config = self.kv()?.get(&kv_key).json::<DapTaskConfig>().await?;
+            if let Some(ref task_config) = task_config {
+                task_config
+                    .validate_time_precision()
+                    .and_then(|()| task_config.validate_epoch_duration())
+                    .map_err(|e| {
+                        Error::RustError(format!("Invalid configuration for task {task_id}: {e}"))
+                    })?;
+            }
+            cache.insert(task_id.clone().into_owned(), task_config.clone())?;
+            task_config
+        };
+
+        let task_config = task_config.map(|task_config| {
+            let view = self.state.version.get().and_then(|version| {
+                match task_config.for_version(version)? {
+                    Cow::Owned(view) => Some(view),
+                    Cow::Borrowed(..) => None,
+                }
+            });
+            view.unwrap_or(task_config)
+        });
+
+        Ok(task_config.map(|value| KvPair {
+            key: task_id,
+            value,
+        }))
     }
 
-    /// Define a task in KV
-    pub(crate) async fn set_task_config(
+    /// Retrieve from KV the current revision of the configuration for the given task. The result
+    /// is not cached.
+    pub(crate) async fn get_task_config_record(
         &self,
         task_id: &TaskId,
-        task_config: &DapTaskConfig,
-    ) -> Result<Option<DapTaskConfig>> {
-        self.kv_set_if_not_exists(KV_KEY_PREFIX_TASK_CONFIG, task_id, task_config.clone())
+    ) -> Result<Option<DapTaskConfigRecord>> {
+        self.kv()?
+            .get(&format!("{KV_KEY_PREFIX_TASK_CONFIG}/{task_id}"))
+            .json()
             .await
+            .map_err(Error::from)
+    }
+
+    /// Write a new revision of the task's configuration to KV, provided that the current
+    /// revision is `expected_revision`, or that the task does not exist if `expected_revision`
+    /// is not set. The revision is also added to the task's history.
+    ///
+    /// KV is eventually consistent, so two writers racing within KV's propagation delay may both
+    /// succeed. Otherwise a writer whose view of the task is stale gets a conflict rather than
+    /// overwriting the other's revision.
+    pub(crate) async fn put_task_config(
+        &self,
+        task_id: &TaskId,
+        task_config: DapTaskConfig,
+        expected_revision: Option<u64>,
+        updated_by: &str,
+    ) -> Result<TaskConfigPutResult> {
+        task_config
+            .validate_time_precision()
+            .and_then(|()| task_config.validate_epoch_duration())
+            .map_err(|e| {
+                Error::RustError(format!("Invalid configuration for task {task_id}: {e}"))
+            })?;
+        self.config()
+            .global
+            .check_vdaf_config(task_id, &task_config.vdaf)
+            .map_err(|e| {
+                Error::RustError(format!("Invalid configuration for task {task_id}: {e:?}"))
+            })?;
+        let current = self.get_task_config_record(task_id).await?;
+        if current.as_ref().map(|current| current.revision) != expected_revision {
+            return Ok(TaskConfigPutResult::Conflict(current));
+        }
+
+        let record = DapTaskConfigRecord::next(current.as_ref(), task_config, now(), updated_by);
+        let encode = |record: &DapTaskConfigRecord| {
+            serde_json::to_string(record)
+                .map_err(|e| Error::RustError(format!("failed to encode task config: {e}")))
+        };
+        let kv_store = self.kv()?;
+        if let Some(current) = current.filter(|current| current.revision == 0) {
+            // Record the revision written before history was kept.
+            kv_store
+                .put(&current.history_kv_key(task_id), encode(&current)?)?
+                .execute()
+                .await?;
+        }
+        let value = encode(&record)?;
+        kv_store
+            .put(&record.history_kv_key(task_id), &value)?
+            .execute()
+            .await?;
+        kv_store
+            .put(&format!("{KV_KEY_PREFIX_TASK_CONFIG}/{task_id}"), value)?
+            .execute()
+            .await?;
+
+        // Make sure the cache doesn't hide the new revision from this isolate.
+        self.isolate_state().tasks.remove(task_id)?;
+        Ok(TaskConfigPutResult::Ok(record))
+    }
+
+    /// Retrieve from KV the revisions of the task's configuration, oldest first. Revisions that
+    /// predate the history are not included, except for the one that was current when the task
+    /// was first updated.
+    pub(crate) async fn get_task_config_history(
+        &self,
+        task_id: &TaskId,
+    ) -> Result<Vec<DapTaskConfigRecord>> {
+        let kv_store = self.kv()?;
+        let prefix = DapTaskConfigRecord::history_kv_key_prefix(task_id);
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut list = kv_store.list().prefix(prefix.clone());
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+            let resp = list.execute().await?;
+            keys.extend(resp.keys.into_iter().map(|key| key.name));
+            if resp.list_complete {
+                break;
+            }
+            cursor = resp.cursor;
+        }
+
+        let mut history = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(record) = kv_store.get(&key).json().await? {
+                history.push(record);
+            }
+        }
+        Ok(history)
+    }
+
+    /// Retrieve from KV the override of the Collector's HPKE config for the given task, if any.
+    /// The value is not cached so that key rotations take effect immediately.
+    pub(crate) async fn get_collector_hpke_config_override(
+        &self,
+        task_id: &TaskId,
+    ) -> std::result::Result<Option<HpkeConfig>, DapError> {
+        let kv_key = format!("{KV_KEY_PREFIX_HPKE_COLLECTOR_CONFIG}/{task_id}");
+        let kv_store = self
+            .kv()
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+        let config_override: Option<CollectorHpkeConfigOverride> = kv_store
+            .get(&kv_key)
+            .json()
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage, %kv_key))?;
+        let Some(config_override) = config_override else {
+            return Ok(None);
+        };
+
+        config_override
+            .configs
+            .into_iter()
+            .find(|config| config.id == config_override.active_config_id)
+            .map(Some)
+            .ok_or_else(|| {
+                fatal_error!(
+                    err = "active collector HPKE config not found",
+                    code = Config,
+                    %task_id,
+                    active_config_id = config_override.active_config_id,
+                )
+            })
     }
 
     /// Try retrieving from KV the configuration for the given task. Return an error if the
@@ -779,26 +1762,49 @@ impl<'srv> DaphneWorker<'srv> {
     {
         self.get_task_config(Cow::Borrowed(task_id))
             .await
-            .map_err(|e| fatal_error!(err = ?e, "getting task config"))?
+            .map_err(|e| fatal_error!(err = ?e, code = Storage, "getting task config"))?
             .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))
     }
 
+    /// Evict the given task from the isolate's task config cache, or every task if none is given.
+    /// This is used to make changes to task configs in KV take effect before the cache TTL
+    /// elapses. Note that only the cache of the isolate handling the request is affected.
+    pub(crate) fn internal_bust_task_config_cache(
+        &self,
+        cmd: InternalTestBustTaskConfigCache,
+    ) -> std::result::Result<(), DapError> {
+        let tasks = &self.isolate_state().tasks;
+        match cmd.task_id {
+            Some(task_id) => {
+                let task_id = TaskId::try_from_base64url(&task_id)
+                    .ok_or_else(|| DapAbort::BadRequest("malformed task ID".into()))?;
+                tasks.remove(&task_id)
+            }
+            None => tasks.clear(),
+        }
+        .map_err(|e| fatal_error!(err = ?e, "failed to bust task config cache"))
+    }
+
     /// Clear all persistant durable objects storage.
     ///
     /// TODO(cjpatton) Gate this to non-prod deployments. (Prod should do migration.)
     pub(crate) async fn internal_delete_all(&self) -> std::result::Result<(), DapError> {
         // Clear KV storage.
-        let kv_store = self.kv().map_err(|e| fatal_error!(err = ?e))?;
+        let kv_store = self
+            .kv()
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         let kv_task = async {
             for kv_key in kv_store
                 .list()
                 .execute()
                 .await
-                .map_err(|e| fatal_error!(err = ?e, "failed to list all keys from kv"))?
+                .map_err(
+                    |e| fatal_error!(err = ?e, code = Storage, "failed to list all keys from kv"),
+                )?
                 .keys
             {
                 kv_store.delete(kv_key.name.as_str()).await.map_err(
-                    |e| fatal_error!(err = ?e, name = %kv_key.name, "failed to delete key from kv"),
+                    |e| fatal_error!(err = ?e, code = Storage, name = %kv_key.name, "failed to delete key from kv"),
                 )?;
                 trace!("deleted KV item {}", kv_key.name);
             }
@@ -815,16 +1821,58 @@ impl<'srv> DaphneWorker<'srv> {
                 "garbage_collector".to_string(),
                 &(),
             )
-            .map_err(|e| fatal_error!(err = ?e));
+            .map_err(|e| fatal_error!(err = ?e, code = Storage));
 
         futures::try_join!(kv_task, future_delete_durable)?;
 
         // Clear the isolate state.
         self.isolate_state().delete_all()?;
 
+        // Set the virtual clock back. The durable objects follow on their next request.
+        crate::virtual_clock::reset();
+
         Ok(())
     }
 
+    /// Clear the durable objects storage associated with a task and/or DAP version. Unlike
+    /// [`Self::internal_delete_all`], KV storage (e.g., task configs) is left untouched. Returns the
+    /// number of durable object instances that were deleted.
+    pub(crate) async fn internal_delete_matching(
+        &self,
+        filter: DurableReferenceFilter,
+    ) -> std::result::Result<u64, DapError> {
+        self.durable()
+            .post(
+                BINDING_DAP_GARBAGE_COLLECTOR,
+                DURABLE_GARBAGE_COLLECTOR_DELETE_MATCHING,
+                "garbage_collector".to_string(),
+                &filter,
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
+    }
+
+    /// Move the virtual clock forward by `secs` seconds and run the alarm handlers of the durable
+    /// objects whose alarms are then due. Returns the new time and the number of alarm handlers
+    /// that were run.
+    pub(crate) async fn internal_advance_time(
+        &self,
+        secs: u64,
+    ) -> std::result::Result<(Time, u64), DapError> {
+        crate::virtual_clock::advance(secs);
+        let alarms_fired = self
+            .durable()
+            .post(
+                BINDING_DAP_GARBAGE_COLLECTOR,
+                DURABLE_ALARM_IF_DUE,
+                "garbage_collector".to_string(),
+                &(),
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+        Ok((now(), alarms_fired))
+    }
+
     /// Get the batch ID for the oldest batch that has not been collected. This method is only
     /// applicable to fixed-size tasks.
     pub(crate) async fn internal_current_batch(
@@ -841,10 +1889,10 @@ impl<'srv> DaphneWorker<'srv> {
             .get(
                 BINDING_DAP_LEADER_BATCH_QUEUE,
                 DURABLE_LEADER_BATCH_QUEUE_CURRENT,
-                durable_name_task(&task_config.as_ref().version, &task_id.to_hex()),
+                durable_name_task(&task_config.as_ref().version, task_id),
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
         match res {
             LeaderBatchQueueResult::Ok(batch_id) => Ok(batch_id),
@@ -862,14 +1910,11 @@ impl<'srv> DaphneWorker<'srv> {
         &self,
         version: DapVersion,
         cmd: InternalTestEndpointForTask,
-    ) -> Result<Response> {
+    ) -> Result<String> {
         if self.config().is_leader && !matches!(cmd.role, Role::Leader)
             || !self.config().is_leader && !matches!(cmd.role, Role::Helper)
         {
-            return Response::from_json(&serde_json::json!({
-                "status": "error",
-                "error": "role mismatch",
-            }));
+            return Err(Error::RustError("role mismatch".into()));
         }
 
         let path = self
@@ -883,140 +1928,36 @@ impl<'srv> DaphneWorker<'srv> {
             })?
             .path();
 
-        Response::from_json(&serde_json::json!({
-            "status": "success",
-            "endpoint": format!("{path}{}/", version.as_ref()),
-        }))
+        Ok(format!("{path}{}/", version.as_ref()))
     }
 
     /// Configure Daphne-Worker a task, as required by draft-dcook-ppm-dap-interop-test-design-02.
     pub(crate) async fn internal_add_task(
         &self,
         version: DapVersion,
-        cmd: InternalTestAddTask,
+        cmd: DapTaskDescription,
     ) -> Result<()> {
-        // Task ID.
-        let task_id = TaskId::try_from_base64url(&cmd.task_id)
-            .ok_or_else(|| int_err("task ID is not valid URL-safe base64"))?;
-
-        // VDAF config.
-        let vdaf = match (
-            cmd.vdaf.typ.as_ref(),
-            cmd.vdaf.bits,
-            cmd.vdaf.length,
-            cmd.vdaf.chunk_length,
-        ) {
-            ("Prio3Count", None, None, None) => VdafConfig::Prio3(Prio3Config::Count),
-            ("Prio3Sum", Some(bits), None, None) => VdafConfig::Prio3(Prio3Config::Sum {
-                bits: bits.parse().map_err(int_err)?,
-            }),
-            ("Prio3SumVec", Some(bits), Some(length), Some(chunk_length)) => {
-                VdafConfig::Prio3(Prio3Config::SumVec {
-                    bits: bits.parse().map_err(int_err)?,
-                    length: length.parse().map_err(int_err)?,
-                    chunk_length: chunk_length.parse().map_err(int_err)?,
-                })
-            }
-            ("Prio3Histogram", None, Some(length), Some(chunk_length)) => {
-                VdafConfig::Prio3(Prio3Config::Histogram {
-                    length: length.parse().map_err(int_err)?,
-                    chunk_length: chunk_length.parse().map_err(int_err)?,
-                })
-            }
-            _ => return Err(int_err("command failed: unrecognized VDAF")),
-        };
-
-        // VDAF verification key.
-        let vdaf_verify_key_data = decode_base64url_vec(cmd.vdaf_verify_key.as_bytes())
-            .ok_or_else(|| int_err("VDAF verify key is not valid URL-safe base64"))?;
-        let vdaf_verify_key = vdaf
-            .get_decoded_verify_key(&vdaf_verify_key_data)
-            .map_err(int_err)?;
-
-        // Collector HPKE config.
-        let collector_hpke_config_data = decode_base64url_vec(cmd.collector_hpke_config.as_bytes())
-            .ok_or_else(|| int_err("HPKE collector config is not valid URL-safe base64"))?;
-        let collector_hpke_config =
-            HpkeConfig::get_decoded(&collector_hpke_config_data).map_err(int_err)?;
-
-        // Leader authentication token.
-        let token = BearerToken::from(cmd.leader_authentication_token);
-        if self
-            .kv_set_if_not_exists(KV_KEY_PREFIX_BEARER_TOKEN_LEADER, &task_id, token)
-            .await?
-            .is_some()
-        {
-            return Err(int_err(format!(
-                "command failed: token already exists for the given task ({}) and bearer role (leader)",
-                cmd.task_id
-            )));
-        }
+        let import = cmd
+            .import(version, &self.config().global)
+            .map_err(|e| Error::RustError(e.to_string()))?;
+        let kv_entries = import
+            .kv_entries()
+            .map_err(|e| Error::RustError(e.to_string()))?;
 
-        // Collector authentication token.
-        match (cmd.role, cmd.collector_authentication_token) {
-            (Role::Leader, Some(token_string)) => {
-                let token = BearerToken::from(token_string);
-                if self
-                    .kv_set_if_not_exists(KV_KEY_PREFIX_BEARER_TOKEN_COLLECTOR, &task_id, token)
-                    .await?
-                    .is_some()
-                {
-                    return Err(int_err(format!(
-                        "command failed: token already exists for the given task ({}) and bearer role (collector)",
-                        cmd.task_id
-                    )));
-                }
-            }
-            (Role::Leader, None) => {
-                return Err(int_err(
-                    "command failed: missing collector authentication token",
-                ))
-            }
-            (Role::Helper, None) => (),
-            (Role::Helper, Some(..)) => {
-                return Err(int_err(
-                    "command failed: unexpected collector authentication token",
-                ));
+        let kv_store = self.kv()?;
+        for entry in &kv_entries {
+            if kv_store.get(&entry.key).text().await?.is_some() {
+                return Err(Error::RustError(format!(
+                    "command failed: KV entry already exists for the given task ({}): {}",
+                    cmd.task_id, entry.key
+                )));
             }
-        };
-
-        // Query configuraiton.
-        let query = match (cmd.query_type, cmd.max_batch_size) {
-            (1, None) => DapQueryConfig::TimeInterval,
-            (1, Some(..)) => return Err(int_err("command failed: unexpected max batch size")),
-            (2, Some(max_batch_size)) => DapQueryConfig::FixedSize { max_batch_size },
-            (2, None) => return Err(int_err("command failed: missing max batch size")),
-            _ => return Err(int_err("command failed: unrecognized query type")),
-        };
+        }
 
-        if self
-            .kv_set_if_not_exists(
-                KV_KEY_PREFIX_TASK_CONFIG,
-                &task_id,
-                DapTaskConfig {
-                    version,
-                    leader_url: cmd.leader,
-                    helper_url: cmd.helper,
-                    time_precision: cmd.time_precision,
-                    expiration: cmd.task_expiration,
-                    min_batch_size: cmd.min_batch_size,
-                    query,
-                    vdaf,
-                    vdaf_verify_key,
-                    collector_hpke_config,
-                    taskprov: false,
-                },
-            )
-            .await?
-            .is_some()
-        {
-            Err(int_err(format!(
-                "command failed: config already exists for the given task ({})",
-                cmd.task_id
-            )))
-        } else {
-            Ok(())
+        for entry in kv_entries {
+            kv_store.put(&entry.key, entry.value)?.execute().await?;
         }
+        Ok(())
     }
 
     pub(crate) async fn internal_add_hpke_config(
@@ -1031,15 +1972,15 @@ impl<'srv> DaphneWorker<'srv> {
 
         if config_list
             .iter()
-            .any(|receiver| new_receiver.config.id == receiver.config.id)
+            .any(|receiver| new_receiver.config.id == receiver.config().id)
         {
-            return Err(int_err(format!(
+            return Err(Error::RustError(format!(
                 "receiver config with id {} already exists",
                 new_receiver.config.id
             )));
         }
 
-        config_list.push(new_receiver);
+        config_list.push(HpkeReceiverConfigEntry::WithPrivateKey(new_receiver));
 
         self.kv()?
             .put(
@@ -1060,21 +2001,17 @@ impl<'srv> DaphneWorker<'srv> {
     pub(crate) fn extract_version_parameter(&self, req: &Request) -> Result<DapVersion> {
         let url = req.url()?;
         let path = url.path();
-        let mut router: Router<bool> = Router::new();
-        router.insert("/:version/*remaining", true).unwrap();
-        let url_match = router.at(path).unwrap();
-        let version = url_match
-            .params
-            .get("version")
-            .ok_or_else(|| Error::RustError(format!("Failed to parse path: {path}")))?;
-        Ok(DapVersion::from(version))
+        crate::router::version_from_path(path)
+            .ok_or_else(|| Error::RustError(format!("Failed to parse path: {path}")))
     }
 
+    /// Parse a DAP request. The inner error is the abort to respond with if the request is
+    /// malformed.
     pub(crate) async fn worker_request_to_dap<D>(
         &self,
         mut req: Request,
         ctx: &RouteContext<D>,
-    ) -> Result<DapRequest<DaphneWorkerAuth>> {
+    ) -> Result<std::result::Result<DapRequest<DaphneWorkerAuth>, DapAbort>> {
         let version = self.extract_version_parameter(&req)?;
 
         // Determine the authorization method used by the sender.
@@ -1090,10 +2027,52 @@ impl<'srv> DaphneWorker<'srv> {
                 .filter(|auth| auth.cert_presented() == "1"),
         });
 
+        // The content-type was validated before the request was routed (see
+        // `router::reject_invalid_content_type`).
         let content_type = req.headers().get("Content-Type")?;
-        let media_type = DapMediaType::from_str_for_version(version, content_type.as_deref());
+        let media_type = DapMediaType::parse(version, content_type.as_deref())
+            .map_err(|e| Error::RustError(e.to_string()))?;
+        self.state.sender.set(media_type.sender());
+        self.state.version.set(Some(version));
 
-        let payload = req.bytes().await?;
+        if let Some(ref header) = self.config().report_annotation_header {
+            *self.state.report_annotation.borrow_mut() = req
+                .headers()
+                .get(header)?
+                .filter(|annotation| annotation.len() <= MAX_REPORT_ANNOTATION_LEN)
+                .map(|annotation| {
+                    self.config()
+                        .report_annotation_label(&annotation)
+                        .to_string()
+                });
+        }
+
+        self.state
+            .response_encoding
+            .set(DapContentEncoding::negotiate(
+                req.headers().get("Accept-Encoding")?.as_deref(),
+            ));
+
+        let content_encoding = match DapContentEncoding::from_header(
+            req.headers().get("Content-Encoding")?.as_deref(),
+        ) {
+            Ok(content_encoding) => content_encoding,
+            Err(e) => return Ok(Err(e)),
+        };
+        let payload = match content_encoding {
+            Some(encoding) => match encoding.decode(&req.bytes().await?) {
+                Ok(payload) => payload,
+                Err(e) => return Ok(Err(e)),
+            },
+            None => req.bytes().await?,
+        };
+
+        let collect_priority = match DapCollectPriority::from_header(
+            req.headers().get("dap-collect-priority")?.as_deref(),
+        ) {
+            Ok(collect_priority) => collect_priority,
+            Err(e) => return Ok(Err(e)),
+        };
 
         let (task_id, resource) = match version {
             DapVersion::Draft02 => {
@@ -1109,42 +2088,22 @@ impl<'srv> DaphneWorker<'srv> {
                 (TaskId::decode(&mut r).ok(), DapResource::Undefined)
             }
             DapVersion::Draft07 => {
-                let task_id = ctx.param("task_id").and_then(TaskId::try_from_base64url);
-                let resource = match media_type {
-                    DapMediaType::AggregationJobInitReq
-                    | DapMediaType::AggregationJobContinueReq => {
-                        if let Some(agg_job_id) = ctx
-                            .param("agg_job_id")
-                            .and_then(AggregationJobId::try_from_base64url)
-                        {
-                            DapResource::AggregationJob(agg_job_id)
-                        } else {
-                            // Missing or invalid agg job ID. This should be handled as a bad
-                            // request (undefined resource) by the caller.
-                            DapResource::Undefined
-                        }
-                    }
-                    DapMediaType::CollectReq => {
-                        if let Some(collect_job_id) = ctx
-                            .param("collect_job_id")
-                            .and_then(CollectionJobId::try_from_base64url)
-                        {
-                            DapResource::CollectionJob(collect_job_id)
-                        } else {
-                            // Missing or invalid agg job ID. This should be handled as a bad
-                            // request (undefined resource) by the caller.
-                            DapResource::Undefined
-                        }
-                    }
-                    _ => DapResource::Undefined,
-                };
-
-                (task_id, resource)
+                // Requests with malformed IDs in the path were rejected before routing (see
+                // `router::reject_malformed_resource`).
+                match DapResource::parse_path(req.url()?.path())
+                    .map_err(|e| Error::RustError(e.to_string()))?
+                {
+                    Some((task_id, resource)) => (Some(task_id), resource),
+                    None => (
+                        ctx.param("task_id").and_then(TaskId::try_from_base64url),
+                        DapResource::Undefined,
+                    ),
+                }
             }
             DapVersion::Unknown => unreachable!("unhandled version {version:?}"),
         };
 
-        Ok(DapRequest {
+        Ok(Ok(DapRequest {
             version,
             task_id,
             resource,
@@ -1153,43 +2112,59 @@ impl<'srv> DaphneWorker<'srv> {
             media_type,
             sender_auth,
             taskprov: req.headers().get("dap-taskprov")?,
-        })
+            content_encoding,
+            collect_priority,
+        }))
     }
 
-    pub(crate) fn least_valid_report_time(&self, now: u64) -> u64 {
-        now.saturating_sub(self.config().global.report_storage_epoch_duration)
+    pub(crate) fn least_valid_report_time(&self, task_config: &DapTaskConfig, now: u64) -> u64 {
+        now.saturating_sub(task_config.epoch_duration(&self.config().global))
     }
 
     pub(crate) fn greatest_valid_report_time(&self, now: u64) -> u64 {
         now.saturating_add(self.config().global.report_storage_max_future_time_skew)
     }
 
-    // Generic HTTP POST/PUT
+    // Generic HTTP POST/PUT/DELETE
     pub(crate) async fn send_http(
         &self,
         req: DapRequest<DaphneWorkerAuth>,
-        is_put: bool,
+        method: reqwest_wasm::Method,
     ) -> std::result::Result<DapResponse, DapError> {
-        let (payload, url) = (req.payload, req.url);
+        let (payload, url) = match req.content_encoding {
+            Some(encoding) => (encoding.encode(&req.payload)?, req.url),
+            None => (req.payload, req.url),
+        };
 
         let mut headers = reqwest_wasm::header::HeaderMap::new();
 
-        let content_type = req
-            .media_type
-            .as_str_for_version(req.version)
-            .ok_or_else(|| {
-                fatal_error!(
-                    err = "failed to construct content-type",
-                    ?req.media_type,
-                    ?req.version,
-                )
-            })?;
+        // A DELETE request has no content.
+        if req.media_type != DapMediaType::Missing {
+            let content_type = req
+                .media_type
+                .as_str_for_version(req.version)
+                .ok_or_else(|| {
+                    fatal_error!(
+                        err = "failed to construct content-type",
+                        ?req.media_type,
+                        ?req.version,
+                    )
+                })?;
 
-        headers.insert(
-            reqwest_wasm::header::CONTENT_TYPE,
-            reqwest_wasm::header::HeaderValue::from_str(content_type)
-                .map_err(|e| fatal_error!(err = ?e, "failed to construct content-type header"))?,
-        );
+            headers.insert(
+                reqwest_wasm::header::CONTENT_TYPE,
+                reqwest_wasm::header::HeaderValue::from_str(content_type).map_err(
+                    |e| fatal_error!(err = ?e, "failed to construct content-type header"),
+                )?,
+            );
+        }
+
+        if let Some(encoding) = req.content_encoding {
+            headers.insert(
+                reqwest_wasm::header::CONTENT_ENCODING,
+                reqwest_wasm::header::HeaderValue::from_static(encoding.as_str()),
+            );
+        }
 
         if let Some(bearer_token) = req.sender_auth.and_then(|auth| auth.bearer_token) {
             headers.insert(
@@ -1201,41 +2176,54 @@ impl<'srv> DaphneWorker<'srv> {
         }
 
         let client = &self.isolate_state().client;
-        let reqwest_req = if is_put {
-            client.put(url.as_str())
-        } else {
-            client.post(url.as_str())
-        }
-        .body(payload)
-        .headers(headers);
+        let is_delete = method == reqwest_wasm::Method::DELETE;
+        let reqwest_req = client
+            .request(method, url.as_str())
+            .body(payload)
+            .headers(headers);
 
         let start = Date::now().as_millis();
         let reqwest_resp = reqwest_req
             .send()
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Network))?;
         let end = Date::now().as_millis();
         info!("request to {} completed in {}ms", url, end - start);
         let status = reqwest_resp.status();
-        if status == 200 {
+        if is_delete && (status == 200 || status == 204) {
+            return Ok(DapResponse {
+                version: req.version,
+                payload: Vec::new().into(),
+                media_type: DapMediaType::Missing,
+            });
+        } else if status == 200 {
             // Translate the reqwest response into a Worker response.
             let content_type = reqwest_resp
                 .headers()
                 .get(reqwest_wasm::header::CONTENT_TYPE)
-                .ok_or_else(|| fatal_error!(err = INT_ERR_PEER_RESP_MISSING_MEDIA_TYPE))?
+                .ok_or_else(|| {
+                    fatal_error!(err = INT_ERR_PEER_RESP_MISSING_MEDIA_TYPE, code = Network)
+                })?
                 .to_str()
-                .map_err(|e| fatal_error!(err = ?e))?;
-            let media_type = DapMediaType::from_str_for_version(req.version, Some(content_type));
+                .map_err(|e| fatal_error!(err = ?e, code = Network))?;
+            let media_type = DapMediaType::parse(req.version, Some(content_type)).map_err(|e| {
+                fatal_error!(
+                    err = "response from peer has invalid content-type",
+                    code = Network,
+                    content_type,
+                    abort = ?e
+                )
+            })?;
 
             let payload = reqwest_resp
                 .bytes()
                 .await
-                .map_err(|e| fatal_error!(err = ?e))?
+                .map_err(|e| fatal_error!(err = ?e, code = Network))?
                 .to_vec();
 
             Ok(DapResponse {
                 version: req.version,
-                payload,
+                payload: payload.into(),
                 media_type,
             })
         } else {
@@ -1251,12 +2239,18 @@ impl<'srv> DaphneWorker<'srv> {
                             reqwest_resp
                                 .text()
                                 .await
-                                .map_err(|e| fatal_error!(err = ?e))?
+                                .map_err(|e| fatal_error!(err = ?e, code = Network))?
                         );
                     }
                 }
             }
-            Err(fatal_error!(err = INT_ERR_PEER_ABORT))
+            // Server errors and rate limiting are likely transient, so the request may be retried.
+            // Other errors indicate that the peer aborted.
+            if status.is_server_error() || status == 429 {
+                Err(fatal_error!(err = INT_ERR_PEER_ABORT, code = Network, %status))
+            } else {
+                Err(fatal_error!(err = INT_ERR_PEER_ABORT, %status))
+            }
         }
     }
 }
@@ -1296,6 +2290,116 @@ impl AsRef<BearerToken> for BearerTokenKvPair<'_> {
 
 pub(crate) type DapTaskConfigKvPair<'a> = KvPair<'a, TaskId, DapTaskConfig>;
 
+/// Default value of [`DaphneWorkerConfig::task_config_cache_ttl`].
+const DEFAULT_TASK_CONFIG_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Maximum number of unknown task IDs in the [`TaskConfigCache`].
+const MAX_TASK_CONFIG_CACHE_NEGATIVE_ENTRIES: usize = 1024;
+
+/// Isolate-level cache of task configs read from KV.
+///
+/// Entries expire after a TTL so that changes to task configs in KV eventually take effect. Task
+/// IDs that are not found in KV are cached as well (negative caching), so that requests for
+/// unknown tasks don't hit KV every time. Since task IDs are chosen by the client, at most
+/// `MAX_TASK_CONFIG_CACHE_NEGATIVE_ENTRIES` of them are kept; once the limit is reached, the
+/// oldest one is evicted.
+///
+/// Each isolate has its own cache, so evicting an entry only affects the current isolate.
+pub(crate) struct TaskConfigCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<TaskId, TaskConfigCacheEntry>>,
+}
+
+struct TaskConfigCacheEntry {
+    /// The task config, or `None` if the task is known not to exist.
+    task_config: Option<DapTaskConfig>,
+
+    /// Time (in milliseconds since the UNIX epoch) at which the entry becomes stale.
+    expires_at: u64,
+}
+
+impl TaskConfigCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Look up a task. The outer `Option` is `None` if there is no fresh entry for the task; the
+    /// inner `Option` is `None` if the task is known not to exist.
+    pub(crate) fn get(&self, task_id: &TaskId) -> Result<Option<Option<DapTaskConfig>>> {
+        let now = Date::now().as_millis();
+        let guarded_entries = self
+            .entries
+            .read()
+            .map_err(|e| Error::RustError(format!("Failed to lock tasks for reading: {e}")))?;
+        Ok(guarded_entries
+            .get(task_id)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| entry.task_config.clone()))
+    }
+
+    /// Cache a task config, or the absence of one if `task_config` is `None`.
+    pub(crate) fn insert(&self, task_id: TaskId, task_config: Option<DapTaskConfig>) -> Result<()> {
+        let ttl_millis = u64::try_from(self.ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = Date::now().as_millis().saturating_add(ttl_millis);
+        let mut guarded_entries = self
+            .entries
+            .write()
+            .map_err(|e| Error::RustError(format!("Failed to lock tasks for writing: {e}")))?;
+        if task_config.is_none() {
+            let is_negative = |entry: &TaskConfigCacheEntry| entry.task_config.is_none();
+            if guarded_entries.values().filter(|e| is_negative(e)).count()
+                >= MAX_TASK_CONFIG_CACHE_NEGATIVE_ENTRIES
+            {
+                // Drop the expired entries first. If that is not enough, evict the oldest
+                // negative entry.
+                let now = Date::now().as_millis();
+                guarded_entries.retain(|_, entry| entry.expires_at > now);
+                if guarded_entries.values().filter(|e| is_negative(e)).count()
+                    >= MAX_TASK_CONFIG_CACHE_NEGATIVE_ENTRIES
+                {
+                    if let Some(oldest) = guarded_entries
+                        .iter()
+                        .filter(|(_, entry)| is_negative(entry))
+                        .min_by_key(|(_, entry)| entry.expires_at)
+                        .map(|(task_id, _)| task_id.clone())
+                    {
+                        guarded_entries.remove(&oldest);
+                    }
+                }
+            }
+        }
+        guarded_entries.insert(
+            task_id,
+            TaskConfigCacheEntry {
+                task_config,
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, task_id: &TaskId) -> Result<()> {
+        let mut guarded_entries = self
+            .entries
+            .write()
+            .map_err(|e| Error::RustError(format!("Failed to lock tasks for writing: {e}")))?;
+        guarded_entries.remove(task_id);
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self) -> Result<()> {
+        let mut guarded_entries = self
+            .entries
+            .write()
+            .map_err(|e| Error::RustError(format!("Failed to lock tasks for writing: {e}")))?;
+        guarded_entries.clear();
+        Ok(())
+    }
+}
+
 impl AsRef<DapTaskConfig> for DapTaskConfigKvPair<'_> {
     fn as_ref(&self) -> &DapTaskConfig {
         self.value()
@@ -1314,3 +2418,309 @@ pub(crate) enum DaphneWorkerDeployment {
     /// internal test API.
     Dev,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        apply_env_overrides, DaphneWorkerConfig, DaphneWorkerQuota, DurableRetryPolicy,
+        ReportShardScheme, REPORT_ANNOTATION_OTHER,
+    };
+    use daphne::{
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{ReportId, TaskId},
+        DapQueryConfig, DapTaskConfig, DapVersion, VdafConfig,
+    };
+    use std::time::Duration;
+
+    const HELPER_CONFIG: &str = r#"{
+        "aggregator_role": "helper",
+        "default_version": "v07",
+        "global": {
+            "report_storage_epoch_duration": 604800,
+            "report_storage_max_future_time_skew": 300,
+            "max_batch_duration": 360000,
+            "min_batch_interval_start": 259200,
+            "max_batch_interval_end": 259200,
+            "supported_hpke_kems": ["x25519_hkdf_sha256"]
+        },
+        "report_shard_count": 2,
+        "durable": {
+            "helper_state_store_garbage_collect_after_secs": 10,
+            "processed_alarm_safety_interval": 300
+        }
+    }"#;
+
+    fn from_json(
+        json: &str,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> worker::Result<DaphneWorkerConfig> {
+        let mut doc = serde_json::from_str(json).unwrap();
+        apply_env_overrides(&mut doc, var)?;
+        DaphneWorkerConfig::from_document(doc, |name| {
+            (name == "DAP_REPORT_SHARD_KEY").then(|| hex::encode([1; 32]))
+        })
+    }
+
+    #[test]
+    fn config_from_json() {
+        let config = from_json(HELPER_CONFIG, |_| None).unwrap();
+        assert!(!config.is_leader);
+        assert_eq!(config.report_shard_count, 2);
+        assert_eq!(config.report_shard_scheme, ReportShardScheme::Modulo);
+        assert_eq!(
+            config.helper_state_store_garbage_collect_after_secs,
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            config.processed_alarm_safety_interval,
+            Duration::from_secs(300)
+        );
+        assert!(config.taskprov.is_none());
+        assert!(config.report_annotation_header.is_none());
+        assert!(config.report_annotation_values.is_empty());
+        assert!(!config.strict_errors);
+        assert_eq!(config.extension_registry.max_extensions_len(), u16::MAX);
+        assert!(config.helper_quotas.is_none());
+        assert_eq!(config.durable_retry_policy, DurableRetryPolicy::default());
+    }
+
+    #[test]
+    fn config_from_json_helper_state_keys() {
+        let config = |secrets: &[(&str, [u8; 32])]| {
+            let doc = serde_json::from_str(HELPER_CONFIG).unwrap();
+            DaphneWorkerConfig::from_document(doc, |name| {
+                (name == "DAP_REPORT_SHARD_KEY")
+                    .then(|| [1; 32])
+                    .or_else(|| {
+                        secrets
+                            .iter()
+                            .find(|(secret, _)| *secret == name)
+                            .map(|(_, key)| *key)
+                    })
+                    .map(hex::encode)
+            })
+        };
+
+        assert!(config(&[]).unwrap().helper_state_keys.is_none());
+
+        let keys = config(&[("DAP_HELPER_STATE_KEY", [2; 32])])
+            .unwrap()
+            .helper_state_keys
+            .unwrap();
+        assert!(keys.previous.is_none());
+
+        let keys = config(&[
+            ("DAP_HELPER_STATE_KEY", [2; 32]),
+            ("DAP_HELPER_STATE_KEY_PREVIOUS", [3; 32]),
+        ])
+        .unwrap()
+        .helper_state_keys
+        .unwrap();
+        assert!(keys.previous.is_some());
+
+        assert!(config(&[("DAP_HELPER_STATE_KEY_PREVIOUS", [3; 32])]).is_err());
+    }
+
+    #[test]
+    fn config_from_json_helper_quotas() {
+        let config = from_json(HELPER_CONFIG, |name| {
+            (name == "DAP_HELPER_QUOTAS").then(|| {
+                r#"{
+                    "default": { "requests_per_minute": 10 },
+                    "peers": {
+                        "bearer_token:1234": { "reports_per_minute": 100 },
+                        "tls_client_cert:CN=leader": {}
+                    }
+                }"#
+                .into()
+            })
+        })
+        .unwrap();
+        let quotas = config.helper_quotas.unwrap();
+        assert_eq!(
+            quotas.for_peer("bearer_token:5678"),
+            Some(&DaphneWorkerQuota {
+                requests_per_minute: Some(10),
+                reports_per_minute: None,
+            })
+        );
+        assert_eq!(
+            quotas.for_peer("bearer_token:1234"),
+            Some(&DaphneWorkerQuota {
+                requests_per_minute: None,
+                reports_per_minute: Some(100),
+            })
+        );
+        assert_eq!(quotas.for_peer("tls_client_cert:CN=leader"), None);
+
+        let err = from_json(HELPER_CONFIG, |name| {
+            (name == "DAP_HELPER_QUOTAS").then(|| r#"{"default": {"requests": 10}}"#.into())
+        })
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("helper_quotas"));
+    }
+
+    #[test]
+    fn config_from_json_with_env_overrides() {
+        let config = from_json(HELPER_CONFIG, |name| match name {
+            "DAP_REPORT_SHARD_COUNT" => Some("4".into()),
+            "DAP_REPORT_SHARD_SCHEME" => Some("rendezvous".into()),
+            "DAP_COLLECTION_RESULT_TTL_SECS" => Some("60".into()),
+            "DAP_REPORT_ANNOTATION_HEADER" => Some("x-annotation".into()),
+            "DAP_REPORT_ANNOTATION_VALUES" => Some(r#"["android", "ios"]"#.into()),
+            "DAP_COLLECT_JOB_QUEUE_SHARD_COUNT" => Some("3".into()),
+            "DAP_DURABLE_RETRY_POLICY" => Some(r#"{"max_retries": 2}"#.into()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.report_shard_count, 4);
+        assert_eq!(config.report_shard_scheme, ReportShardScheme::Rendezvous);
+        assert_eq!(config.collection_result_ttl, Some(Duration::from_secs(60)));
+        assert_eq!(config.collect_job_queue_shard_count, 3);
+        assert_eq!(
+            config.durable_retry_policy,
+            DurableRetryPolicy {
+                max_retries: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            config.report_annotation_header.as_deref(),
+            Some("x-annotation")
+        );
+        assert_eq!(config.report_annotation_label("ios"), "ios");
+        assert_eq!(
+            config.report_annotation_label("ios; build=1234"),
+            REPORT_ANNOTATION_OTHER
+        );
+
+        // The document is not required if every field is set by an environment variable.
+        let config = from_json("{}", |name| match name {
+            "DAP_AGGREGATOR_ROLE" => Some("helper".into()),
+            "DAP_DEFAULT_VERSION" => Some("v07".into()),
+            "DAP_GLOBAL_CONFIG" => {
+                let doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+                Some(doc["global"].to_string())
+            }
+            "DAP_REPORT_SHARD_COUNT" => Some("2".into()),
+            "DAP_HELPER_STATE_STORE_GARBAGE_COLLECT_AFTER_SECS" => Some("10".into()),
+            "DAP_PROCESSED_ALARM_SAFETY_INTERVAL" => Some("300".into()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.report_shard_count, 2);
+    }
+
+    #[test]
+    fn config_from_json_names_offending_field() {
+        let err = from_json(HELPER_CONFIG, |name| {
+            (name == "DAP_PROCESSED_ALARM_SAFETY_INTERVAL").then(|| r#""soon""#.into())
+        })
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .contains("durable.processed_alarm_safety_interval"));
+
+        let mut doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+        doc["durable"]["processed_alarm_safety_intreval"] = 300.into();
+        let err = from_json(&doc.to_string(), |_| None).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("durable.processed_alarm_safety_intreval"));
+
+        let mut doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+        doc.as_object_mut().unwrap().remove("report_shard_count");
+        let err = from_json(&doc.to_string(), |_| None).err().unwrap();
+        assert!(err.to_string().contains("report_shard_count"));
+    }
+
+    // Test that a report and a replay of it with a timestamp on the other side of an epoch boundary
+    // are both marked as aggregated in a common report store, so that the replay is caught by that
+    // store's ReportsProcessed instance even if the two are aggregated concurrently.
+    #[test]
+    fn report_stores_to_mark_overlap_across_epoch_boundary() {
+        let config = from_json(HELPER_CONFIG, |_| None).unwrap();
+        let vdaf = VdafConfig::Prio2 { dimension: 10 };
+        let task_config = DapTaskConfig {
+            version: DapVersion::Draft07,
+            leader_url: "https://leader.com".parse().unwrap(),
+            helper_url: "https://helper.org".parse().unwrap(),
+            time_precision: 3600,
+            expiration: u64::MAX,
+            min_batch_size: 1,
+            query: DapQueryConfig::TimeInterval,
+            vdaf_verify_key: vdaf.gen_verify_key(),
+            vdaf,
+            vdaf_verify_key_set: None,
+            collector_hpke_config: HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+                .unwrap()
+                .config,
+            taskprov: false,
+            batch_policy: Default::default(),
+            upload_receipts: false,
+            unknown_extensions: Default::default(),
+            report_ttl: None,
+            collect_priority: Default::default(),
+            client_auth_key: None,
+            alt_versions: Vec::new(),
+            report_storage_epoch_duration: Some(1000),
+            report_storage_epoch_migration: None,
+            sub_bucket_precision: None,
+        };
+        let task_id = TaskId([1; 32]);
+        let report_id = ReportId([2; 16]);
+        let now = 5000;
+
+        let names = |report_time| {
+            config.durable_names_report_stores_to_mark(
+                &task_config,
+                &task_id,
+                &report_id,
+                report_time,
+                now,
+            )
+        };
+
+        // Far from an epoch boundary, only the report's own store is marked.
+        assert_eq!(names(5500).len(), 1);
+
+        // Near the boundary, the report and its replay have their own stores in common.
+        let orig = names(5995);
+        let replay = names(6005);
+        assert_ne!(orig[0], replay[0]);
+        assert!(orig.contains(&replay[0]));
+        assert!(replay.contains(&orig[0]));
+    }
+
+    #[test]
+    fn config_from_json_rejects_invalid_min_acceptance_ratio() {
+        let mut doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+        doc["global"]["agg_job_min_acceptance_ratio"] = 1.5.into();
+        let err = from_json(&doc.to_string(), |_| None).err().unwrap();
+        assert!(err.to_string().contains("acceptance ratio"));
+    }
+
+    #[test]
+    fn config_from_json_rejects_zero_epoch_duration() {
+        let mut doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+        doc["global"]["report_storage_epoch_duration"] = 0.into();
+        let err = from_json(&doc.to_string(), |_| None).err().unwrap();
+        assert!(err.to_string().contains("epoch duration"));
+    }
+
+    #[test]
+    fn config_from_json_rejects_zero_shard_count() {
+        let mut doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+        doc["report_shard_count"] = 0.into();
+        let err = from_json(&doc.to_string(), |_| None).err().unwrap();
+        assert!(err.to_string().contains("report_shard_count"));
+
+        let mut doc: serde_json::Value = serde_json::from_str(HELPER_CONFIG).unwrap();
+        doc["report_shard_migration"] =
+            serde_json::json!({ "previous_shard_count": 0, "until": 1_700_000_000 });
+        let err = from_json(&doc.to_string(), |_| None).err().unwrap();
+        assert!(err.to_string().contains("report_shard_migration"));
+    }
+}
@@ -5,38 +5,99 @@ use std::ops::ControlFlow;
 
 use crate::{
     config::DaphneWorkerConfig,
-    durable::{create_span_from_request, state_get_or_default, BINDING_DAP_AGGREGATE_STORE},
+    durable::{
+        create_span_from_request, state_get, state_get_or_default, DurableConnector,
+        DurableStorageStats, BINDING_DAP_AGGREGATE_STORE,
+    },
     initialize_tracing, int_err,
 };
-use daphne::DapAggregateShare;
+use daphne::{DapAggregateShare, DapAggregateShareMeta, DapAggregationParamHash};
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
 use tracing::Instrument;
 use worker::*;
 
-use super::{req_parse, DapDurableObject, GarbageCollectable};
+use super::{
+    durable_task, req_parse, DapDurableObject, GarbageCollectable, SchemaVersionState,
+    SchemaVersioned,
+};
 
 pub(crate) const DURABLE_AGGREGATE_STORE_GET: &str = "/internal/do/aggregate_store/get";
+pub(crate) const DURABLE_AGGREGATE_STORE_GET_META: &str = "/internal/do/aggregate_store/get_meta";
 pub(crate) const DURABLE_AGGREGATE_STORE_MERGE: &str = "/internal/do/aggregate_store/merge";
+pub(crate) const DURABLE_AGGREGATE_STORE_MERGE_MANY: &str =
+    "/internal/do/aggregate_store/merge_many";
 pub(crate) const DURABLE_AGGREGATE_STORE_MARK_COLLECTED: &str =
     "/internal/do/aggregate_store/mark_collected";
 pub(crate) const DURABLE_AGGREGATE_STORE_CHECK_COLLECTED: &str =
     "/internal/do/aggregate_store/check_collected";
+pub(crate) const DURABLE_AGGREGATE_STORE_CHECK_COLLECTED_AGG_PARAM: &str =
+    "/internal/do/aggregate_store/check_collected_agg_param";
+
+/// Input of `DURABLE_AGGREGATE_STORE_MERGE_MANY`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AggregateStoreMergeManyReq {
+    /// The delta to merge into the aggregate share stored by the receiving instance.
+    pub(crate) agg_share_delta: DapAggregateShare,
+    /// The deltas for other buckets of the same task, keyed by the name of the instance that
+    /// stores the bucket.
+    pub(crate) forward: Vec<(String, DapAggregateShare)>,
+}
+
+/// An aggregate share as stored by the [`AggregateStore`].
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredAggregateShare {
+    /// Hex-encoded output of [`DapAggregateShare::get_encoded_versioned`].
+    Versioned(String),
+    /// Legacy encoding: the aggregate share serialized directly with serde.
+    Legacy(DapAggregateShare),
+}
 
 /// Durable Object (DO) for storing aggregate shares for a bucket of reports.
 ///
 /// This object defines the following API endpoints:
 ///
 /// - `DURABLE_AGGREGATE_STORE_GET`: Return the current value of the aggregate share.
+/// - `DURABLE_AGGREGATE_STORE_GET_META`: Return the report count, the report time range, the
+///   checksum, and the collected flag, but not the aggregated data.
 /// - `DURABLE_AGGREGATE_STORE_MERGE`: Update the aggregate share.
-/// - `DURABLE_AGGREGATE_STORE_MARK_COLLECTED`: Mark the bucket as having been collected.
+/// - `DURABLE_AGGREGATE_STORE_MERGE_MANY`: Update the aggregate share and forward the deltas for
+///   the other buckets of the task to their respective instances. This allows the Worker to make
+///   a single request per aggregation job rather than one per bucket.
+/// - `DURABLE_AGGREGATE_STORE_MARK_COLLECTED`: Mark the bucket as having been collected with the
+///   given aggregation parameter.
 /// - `DURABLE_AGGREGATE_STORE_CHECK_COLLECTED`: Return a boolean indicating if the bucket has been
 ///   collected.
+/// - `DURABLE_AGGREGATE_STORE_CHECK_COLLECTED_AGG_PARAM`: Return a boolean indicating if the bucket
+///   has been collected with the given aggregation parameter.
+///
+/// The merge endpoints respond with the estimated storage usage after merging (see
+/// [`DurableStorageStats`]), which only accounts for the aggregate share and its metadata. For
+/// `DURABLE_AGGREGATE_STORE_MERGE_MANY`, this is the maximum over the receiving instance and the
+/// instances the deltas were forwarded to.
 ///
 /// The schema for the data stored by this DO is as follows:
 ///
 /// ```text
-/// [Aggregate share] agg_share -> DapAggregateShare
-/// [Collected flag]  collected -> bool
+/// [Aggregate share]      agg_share -> StoredAggregateShare
+/// [Metadata]             meta -> DapAggregateShareMeta
+/// [Collected flag]       collected -> bool
+/// [Collected agg params] collected_agg_params -> Vec<String> (hex-encoded agg param hashes)
+/// [Schema version]       schema_version -> u32
 /// ```
+///
+/// Buckets collected before the aggregation parameters were recorded have the collected flag set
+/// but no list of aggregation parameters. Such buckets are treated as having been collected with
+/// every aggregation parameter.
+///
+/// The aggregate share is stored with [`DapAggregateShare::get_encoded_versioned`] so that changes
+/// to the structure do not corrupt state written by a previous deployment. Aggregate shares
+/// written before the versioned encoding was introduced are still accepted.
+///
+/// The metadata (report count, time range, and checksum) is updated with each merge so that it can
+/// be read without loading the aggregate share. If it is missing, e.g., because the aggregate
+/// share was written by a previous deployment, then it is derived from the aggregate share.
 #[durable_object]
 pub struct AggregateStore {
     #[allow(dead_code)]
@@ -45,6 +106,7 @@ pub struct AggregateStore {
     config: DaphneWorkerConfig,
     touched: bool,
     collected: Option<bool>,
+    schema_version_state: SchemaVersionState,
 }
 
 #[durable_object]
@@ -59,12 +121,18 @@ impl DurableObject for AggregateStore {
             config,
             touched: false,
             collected: None,
+            schema_version_state: SchemaVersionState::Unchecked,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        let task = durable_task(&req)?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version_for_task(task, BINDING_DAP_AGGREGATE_STORE)
+            .await?;
+        resp
     }
 }
 
@@ -76,7 +144,7 @@ impl AggregateStore {
         {
             ControlFlow::Continue(req) => req,
             // This req was a GC request and as such we must return from this function.
-            ControlFlow::Break(_) => return Response::from_json(&()),
+            ControlFlow::Break(resp) => return Ok(resp),
         };
 
         match (req.path().as_ref(), req.method()) {
@@ -84,20 +152,41 @@ impl AggregateStore {
             //
             // Non-idempotent (do not retry)
             // Input: `agg_share_dellta: DapAggregateShare`
-            // Output: `()`
+            // Output: `DurableStorageStats`
             (DURABLE_AGGREGATE_STORE_MERGE, Method::Post) => {
                 let agg_share_delta = req_parse(&mut req).await?;
+                let storage_stats = self.merge(agg_share_delta).await?;
+                Response::from_json(&storage_stats)
+            }
 
-                // To keep this pair of get and put operations atomic, there should be no await
-                // points between them. See the note below `transaction()` on
-                // https://developers.cloudflare.com/workers/runtime-apis/durable-objects/#transactional-storage-api.
-                // See issue #109.
-                let mut agg_share: DapAggregateShare =
-                    state_get_or_default(&self.state, "agg_share").await?;
-                agg_share.merge(agg_share_delta).map_err(int_err)?;
-                self.state.storage().put("agg_share", agg_share).await?;
+            // Merge an aggregate share into the stored aggregate and forward the remaining deltas
+            // to the instances that store them.
+            //
+            // Non-idempotent (do not retry)
+            // Input: `AggregateStoreMergeManyReq`
+            // Output: `DurableStorageStats`
+            (DURABLE_AGGREGATE_STORE_MERGE_MANY, Method::Post) => {
+                let AggregateStoreMergeManyReq {
+                    agg_share_delta,
+                    forward,
+                } = req_parse(&mut req).await?;
+                let storage_stats = self.merge(agg_share_delta).await?;
 
-                Response::from_json(&())
+                let durable = DurableConnector::new(&self.env);
+                let storage_stats =
+                    try_join_all(forward.into_iter().map(|(durable_name, agg_share_delta)| {
+                        durable.post::<_, DurableStorageStats>(
+                            BINDING_DAP_AGGREGATE_STORE,
+                            DURABLE_AGGREGATE_STORE_MERGE,
+                            durable_name,
+                            agg_share_delta,
+                        )
+                    }))
+                    .await?
+                    .into_iter()
+                    .fold(storage_stats, DurableStorageStats::max);
+
+                Response::from_json(&storage_stats)
             }
 
             // Get the current aggregate share.
@@ -105,16 +194,43 @@ impl AggregateStore {
             // Idempotent
             // Output: `DapAggregateShare`
             (DURABLE_AGGREGATE_STORE_GET, Method::Get) => {
-                let agg_share: DapAggregateShare =
-                    state_get_or_default(&self.state, "agg_share").await?;
+                let agg_share = self.get_agg_share().await?;
                 Response::from_json(&agg_share)
             }
 
-            // Mark this bucket as collected.
+            // Get the metadata of the current aggregate share.
+            //
+            // Idempotent
+            // Output: `DapAggregateShareMeta`
+            (DURABLE_AGGREGATE_STORE_GET_META, Method::Get) => {
+                let meta = match state_get(&self.state, "meta").await? {
+                    Some(meta) => meta,
+                    None => self.get_agg_share().await?.meta(),
+                };
+                let meta = DapAggregateShareMeta {
+                    collected: self.is_collected().await?,
+                    ..meta
+                };
+                Response::from_json(&meta)
+            }
+
+            // Mark this bucket as collected with the given aggregation parameter.
             //
             // Non-idempotent (do not retry)
+            // Input: `agg_param_hash: DapAggregationParamHash`
             // Output: `()`
             (DURABLE_AGGREGATE_STORE_MARK_COLLECTED, Method::Post) => {
+                let agg_param_hash: DapAggregationParamHash = req_parse(&mut req).await?;
+                let mut collected_agg_params: Vec<String> =
+                    state_get_or_default(&self.state, "collected_agg_params").await?;
+                let agg_param_hash = agg_param_hash.to_hex();
+                if !collected_agg_params.contains(&agg_param_hash) {
+                    collected_agg_params.push(agg_param_hash);
+                }
+                self.state
+                    .storage()
+                    .put("collected_agg_params", collected_agg_params)
+                    .await?;
                 self.state.storage().put("collected", true).await?;
                 self.collected = Some(true);
                 Response::from_json(&())
@@ -125,12 +241,25 @@ impl AggregateStore {
             // Idempotent
             // Output: `bool`
             (DURABLE_AGGREGATE_STORE_CHECK_COLLECTED, Method::Get) => {
-                let collected = if let Some(collected) = self.collected {
-                    collected
-                } else {
-                    let collected = state_get_or_default(&self.state, "collected").await?;
-                    self.collected = Some(collected);
-                    collected
+                Response::from_json(&self.is_collected().await?)
+            }
+
+            // Check whether this bucket has been collected with the given aggregation parameter.
+            //
+            // Idempotent
+            // Input: `agg_param_hash: DapAggregationParamHash`
+            // Output: `bool`
+            (DURABLE_AGGREGATE_STORE_CHECK_COLLECTED_AGG_PARAM, Method::Post) => {
+                let agg_param_hash: DapAggregationParamHash = req_parse(&mut req).await?;
+                let collected_agg_params: Option<Vec<String>> =
+                    state_get(&self.state, "collected_agg_params").await?;
+                let collected = match collected_agg_params {
+                    Some(collected_agg_params) => {
+                        collected_agg_params.contains(&agg_param_hash.to_hex())
+                    }
+                    // Legacy: The bucket was collected before aggregation parameters were
+                    // recorded.
+                    None => state_get_or_default(&self.state, "collected").await?,
                 };
                 Response::from_json(&collected)
             }
@@ -142,6 +271,58 @@ impl AggregateStore {
             ))),
         }
     }
+
+    /// Merge a delta into the stored aggregate share and return the estimated storage usage.
+    async fn merge(&mut self, agg_share_delta: DapAggregateShare) -> Result<DurableStorageStats> {
+        // To keep this pair of get and put operations atomic, there should be no await points
+        // between them. See the note below `transaction()` on
+        // https://developers.cloudflare.com/workers/runtime-apis/durable-objects/#transactional-storage-api.
+        // See issue #109.
+        let mut agg_share = self.get_agg_share().await?;
+        let mut meta = match state_get::<DapAggregateShareMeta>(&self.state, "meta").await? {
+            Some(meta) => meta,
+            None => agg_share.meta(),
+        };
+        meta.merge(agg_share_delta.meta());
+        agg_share.merge(agg_share_delta).map_err(int_err)?;
+        let stored =
+            StoredAggregateShare::Versioned(hex::encode(agg_share.get_encoded_versioned()));
+
+        // The aggregate share and its metadata are the only pairs that are accounted for, so there
+        // is no need to keep track of the estimate in storage.
+        let mut storage_stats = DurableStorageStats::default();
+        storage_stats.add(DurableStorageStats::entry_size("agg_share", &stored));
+        storage_stats.add(DurableStorageStats::entry_size("meta", &meta));
+        self.state.storage().put("agg_share", stored).await?;
+        self.state.storage().put("meta", meta).await?;
+        Ok(storage_stats)
+    }
+
+    /// Return the value of the flag indicating whether this bucket has been collected.
+    async fn is_collected(&mut self) -> Result<bool> {
+        if let Some(collected) = self.collected {
+            return Ok(collected);
+        }
+        let collected = state_get_or_default(&self.state, "collected").await?;
+        self.collected = Some(collected);
+        Ok(collected)
+    }
+
+    /// Load the aggregate share, decoding whichever format it was stored in.
+    async fn get_agg_share(&self) -> Result<DapAggregateShare> {
+        Self::decode_agg_share(state_get(&self.state, "agg_share").await?)
+    }
+
+    fn decode_agg_share(stored: Option<StoredAggregateShare>) -> Result<DapAggregateShare> {
+        match stored {
+            None => Ok(DapAggregateShare::default()),
+            Some(StoredAggregateShare::Versioned(agg_share_hex)) => {
+                let encoded = hex::decode(agg_share_hex).map_err(int_err)?;
+                DapAggregateShare::get_decoded_versioned(&encoded).map_err(int_err)
+            }
+            Some(StoredAggregateShare::Legacy(agg_share)) => Ok(agg_share),
+        }
+    }
 }
 
 impl DapDurableObject for AggregateStore {
@@ -156,6 +337,16 @@ impl DapDurableObject for AggregateStore {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for AggregateStore {
+    const CLASS: &'static str = "AggregateStore";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl GarbageCollectable for AggregateStore {
     #[inline(always)]
@@ -0,0 +1,185 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::ops::ControlFlow;
+
+use crate::{
+    config::DaphneWorkerConfig,
+    durable::{create_span_from_request, state_get, BINDING_DAP_COMMAND_NONCE_STORE, MAX_KEYS},
+    initialize_tracing, int_err, runtime_now,
+};
+use daphne::messages::Time;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, Instrument};
+use worker::*;
+
+use super::{req_parse, DapDurableObject, GarbageCollectable, SchemaVersionState, SchemaVersioned};
+
+pub(crate) const DURABLE_COMMAND_NONCE_STORE_CLAIM: &str = "/internal/do/command_nonce_store/claim";
+
+const NONCE_PREFIX: &str = "nonce/";
+
+/// Input of `DURABLE_COMMAND_NONCE_STORE_CLAIM`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct CommandNonceClaimRequest {
+    /// The nonce carried by the command.
+    pub(crate) nonce: [u8; 16],
+
+    /// Time after which the command would be rejected as stale, and thus after which the nonce
+    /// no longer needs to be remembered.
+    pub(crate) expires: Time,
+}
+
+/// Durable Object (DO) for remembering the nonces of authenticated internal commands so that a
+/// command cannot be replayed. There is a single instance of this DO.
+///
+/// This object implements the following API endpoints:
+///
+/// - `DURABLE_COMMAND_NONCE_STORE_CLAIM`: Record a nonce. The result is `true` if the nonce was
+///   not yet recorded and `false` otherwise. Nonces whose expiration time has passed are forgotten.
+///
+/// The schema for data stored in instances of this DO is as follows:
+///
+/// ```text
+/// [Nonce]          nonce/<nonce> -> Time
+/// [Schema version] schema_version -> u32
+/// ```
+///
+/// where `<nonce>` is the hex-encoded nonce and the value is its expiration time.
+#[durable_object]
+pub struct CommandNonceStore {
+    state: State,
+    env: Env,
+    config: DaphneWorkerConfig,
+    touched: bool,
+    schema_version_state: SchemaVersionState,
+}
+
+#[durable_object]
+impl DurableObject for CommandNonceStore {
+    fn new(state: State, env: Env) -> Self {
+        initialize_tracing(&env);
+        let config =
+            DaphneWorkerConfig::from_worker_env(&env).expect("failed to load configuration");
+        Self {
+            state,
+            env,
+            config,
+            touched: false,
+            schema_version_state: SchemaVersionState::Unchecked,
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        let span = create_span_from_request(&req);
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version().await?;
+        resp
+    }
+}
+
+impl CommandNonceStore {
+    async fn handle(&mut self, req: Request) -> Result<Response> {
+        let mut req = match self
+            .schedule_for_garbage_collection(req, BINDING_DAP_COMMAND_NONCE_STORE)
+            .await?
+        {
+            ControlFlow::Continue(req) => req,
+            // This req was a GC request and as such we must return from this function.
+            ControlFlow::Break(resp) => return Ok(resp),
+        };
+
+        match (req.path().as_ref(), req.method()) {
+            // Record the nonce of a command.
+            //
+            // Non-idempotent
+            // Input: `CommandNonceClaimRequest`
+            // Output: `bool`
+            (DURABLE_COMMAND_NONCE_STORE_CLAIM, Method::Post) => {
+                let CommandNonceClaimRequest { nonce, expires } = req_parse(&mut req).await?;
+                self.forget_expired().await?;
+
+                let key = format!("{NONCE_PREFIX}{}", hex::encode(nonce));
+                let claimed = if state_get::<Time>(&self.state, &key).await?.is_some() {
+                    debug!(
+                        "CommandNonceStore: nonce {} was already used",
+                        hex::encode(nonce)
+                    );
+                    false
+                } else {
+                    self.state.storage().put(&key, expires).await?;
+                    true
+                };
+                Response::from_json(&claimed)
+            }
+
+            _ => Err(int_err(format!(
+                "CommandNonceStore: unexpected request: method={:?}; path={:?}",
+                req.method(),
+                req.path()
+            ))),
+        }
+    }
+
+    /// Delete (some of) the nonces that have expired.
+    async fn forget_expired(&mut self) -> Result<()> {
+        // Expiration times are derived from command timestamps, so ignore the virtual clock.
+        let now = runtime_now();
+        let iter = self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix(NONCE_PREFIX).limit(MAX_KEYS))
+            .await?
+            .entries();
+        let mut expired = Vec::new();
+        let mut js_item = iter.next()?;
+        while !js_item.done() {
+            let (key, expires): (String, Time) =
+                serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
+            if expires < now {
+                expired.push(key);
+            }
+            js_item = iter.next()?;
+        }
+        if !expired.is_empty() {
+            self.state.storage().delete_multiple(expired).await?;
+        }
+        Ok(())
+    }
+}
+
+impl DapDurableObject for CommandNonceStore {
+    #[inline(always)]
+    fn state(&self) -> &State {
+        &self.state
+    }
+
+    #[inline(always)]
+    fn deployment(&self) -> crate::config::DaphneWorkerDeployment {
+        self.config.deployment
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for CommandNonceStore {
+    const CLASS: &'static str = "CommandNonceStore";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl GarbageCollectable for CommandNonceStore {
+    #[inline(always)]
+    fn touched(&mut self) -> &mut bool {
+        &mut self.touched
+    }
+
+    #[inline(always)]
+    fn env(&self) -> &Env {
+        &self.env
+    }
+}
@@ -5,15 +5,31 @@ use crate::{
     durable,
     durable::{
         create_span_from_request, req_parse, DurableConnector, DurableOrdered, DurableReference,
+        DurableReferenceFilter,
     },
-    initialize_tracing, int_err,
+    initialize_tracing, int_err, virtual_clock,
 };
+use daphne::DapTaskPurgeSummary;
 use tracing::{error, trace, Instrument};
 use worker::*;
 
 pub(crate) const DURABLE_GARBAGE_COLLECTOR_PUT: &str = "/internal/do/garbage_collector/put";
+pub(crate) const DURABLE_GARBAGE_COLLECTOR_FORGET: &str = "/internal/do/garbage_collector/forget";
+pub(crate) const DURABLE_GARBAGE_COLLECTOR_DELETE_MATCHING: &str =
+    "/internal/do/garbage_collector/delete_matching";
+pub(crate) const DURABLE_GARBAGE_COLLECTOR_PURGE_TASK: &str =
+    "/internal/do/garbage_collector/purge_task";
 
-/// Durable Object (DO) for keeping track of all persistent DO storage.
+/// Durable Object (DO) for keeping track of all persistent DO storage. This is only used in the
+/// "dev" deployment.
+///
+/// Every DO instance is registered with the singleton instance (see
+/// [`StorageKey::GarbageCollector`](crate::durable::StorageKey::GarbageCollector)) so that tests
+/// can delete all storage. In addition, each instance that holds the data of a task is registered
+/// with the task's own instance (named by [`durable_name_task`](crate::durable::durable_name_task))
+/// once it is first written to, so that `DURABLE_GARBAGE_COLLECTOR_PURGE_TASK` can find the data
+/// of instances whose names cannot be derived from the task, such as Helper states. Instances are
+/// registered by their ID, so each is registered at most once.
 #[durable_object]
 pub struct GarbageCollector {
     #[allow(dead_code)]
@@ -48,7 +64,9 @@ impl GarbageCollector {
                     | durable::BINDING_DAP_LEADER_AGG_JOB_QUEUE
                     | durable::BINDING_DAP_LEADER_BATCH_QUEUE
                     | durable::BINDING_DAP_LEADER_COL_JOB_QUEUE
-                    | durable::BINDING_DAP_HELPER_STATE_STORE => (),
+                    | durable::BINDING_DAP_HELPER_STATE_STORE
+                    | durable::BINDING_DAP_RATE_LIMITER
+                    | durable::BINDING_DAP_COMMAND_NONCE_STORE => (),
                     s => {
                         let message = format!("GarbageCollector: unrecognized binding: {s}");
                         error!("{}", message);
@@ -56,7 +74,8 @@ impl GarbageCollector {
                     }
                 };
 
-                let queued = DurableOrdered::new_roughly_ordered(durable_ref, "object");
+                let key = registry_key(&durable_ref);
+                let queued = DurableOrdered::new_keyed(durable_ref, "object", &key);
                 queued.put(&self.state).await?;
                 trace!(
                     "scheduled {} instance {} for deletion",
@@ -66,6 +85,45 @@ impl GarbageCollector {
                 Response::from_json(&())
             }
 
+            // Remove a DO instance whose storage has been deleted from the registry. If the
+            // instance was registered with a task, then it is also removed from the task's
+            // registry.
+            //
+            // Input: `durable_ref: DurableReference`
+            // Output: `()`
+            (DURABLE_GARBAGE_COLLECTOR_FORGET, Method::Post) => {
+                let durable_ref: DurableReference = req_parse(&mut req).await?;
+                let key = registry_key(&durable_ref);
+                let Some(queued) =
+                    DurableOrdered::<DurableReference>::get_keyed(&self.state, "object", &key)
+                        .await?
+                else {
+                    return Response::from_json(&());
+                };
+                queued.delete(&self.state).await?;
+                let registered = queued.into_item();
+                if let (None, Some(task_id), Some(version)) = (
+                    &durable_ref.task_id,
+                    &registered.task_id,
+                    &registered.version,
+                ) {
+                    durable
+                        .post::<_, ()>(
+                            durable::BINDING_DAP_GARBAGE_COLLECTOR,
+                            DURABLE_GARBAGE_COLLECTOR_FORGET,
+                            durable::durable_name_task(version, task_id),
+                            &registered,
+                        )
+                        .await?;
+                }
+                trace!(
+                    "forgot {} instance {}",
+                    registered.binding,
+                    registered.id_hex
+                );
+                Response::from_json(&())
+            }
+
             // Delete all DO instances.
             //
             // NOTE This method is likely to hit memory and/or time limits when run in a production
@@ -100,6 +158,118 @@ impl GarbageCollector {
                 Response::from_json(&())
             }
 
+            // Delete the DO instances associated with a task and/or DAP version. Like
+            // `DURABLE_DELETE_ALL`, this method is not intended for production use.
+            //
+            // Input: `filter: DurableReferenceFilter`
+            // Output: `u64` (number of deleted instances)
+            (DURABLE_GARBAGE_COLLECTOR_DELETE_MATCHING, Method::Post) => {
+                let filter: DurableReferenceFilter = req_parse(&mut req).await?;
+                let queued: Vec<DurableOrdered<DurableReference>> =
+                    DurableOrdered::get_all(&self.state, "object").await?;
+                let mut count = 0_u64;
+                for queued in queued
+                    .iter()
+                    .filter(|queued| filter.matches(queued.as_ref()))
+                {
+                    let durable_ref = queued.as_ref();
+                    durable
+                        .post_by_id_hex(
+                            &durable_ref.binding,
+                            durable::DURABLE_DELETE_ALL,
+                            durable_ref.id_hex.clone(),
+                            &(),
+                        )
+                        .await?;
+                    queued.delete(&self.state).await?;
+                    count += 1;
+                    trace!(
+                        "deleted {} instance {}",
+                        durable_ref.binding,
+                        durable_ref.id_hex
+                    );
+                }
+                Response::from_json(&count)
+            }
+
+            // Delete the data of the task whose instances are registered with this instance. The
+            // reports and aggregate shares are counted in the summary; the registrations are
+            // deleted along with the data.
+            //
+            // Output: `DapTaskPurgeSummary`
+            (DURABLE_GARBAGE_COLLECTOR_PURGE_TASK, Method::Post) => {
+                let queued: Vec<DurableOrdered<DurableReference>> =
+                    DurableOrdered::get_all(&self.state, "object").await?;
+                let mut summary = DapTaskPurgeSummary::default();
+                for queued in &queued {
+                    let durable_ref = queued.as_ref();
+                    let prefix = match durable_ref.binding.as_ref() {
+                        durable::BINDING_DAP_REPORTS_PENDING => "pending/",
+                        durable::BINDING_DAP_REPORTS_PROCESSED => "processed/",
+                        _ => "",
+                    };
+                    let count: u64 = durable
+                        .post_by_id_hex(
+                            &durable_ref.binding,
+                            durable::DURABLE_PURGE,
+                            durable_ref.id_hex.clone(),
+                            &prefix,
+                        )
+                        .await?;
+                    match durable_ref.binding.as_ref() {
+                        durable::BINDING_DAP_REPORTS_PENDING => summary.reports_pending += count,
+                        durable::BINDING_DAP_REPORTS_PROCESSED => {
+                            summary.reports_processed += count;
+                        }
+                        durable::BINDING_DAP_AGGREGATE_STORE if count > 0 => {
+                            summary.agg_shares += 1;
+                        }
+                        durable::BINDING_DAP_HELPER_STATE_STORE if count > 0 => {
+                            summary.helper_states += 1;
+                        }
+                        _ => (),
+                    }
+                    queued.delete(&self.state).await?;
+                    trace!(
+                        "purged {} instance {}",
+                        durable_ref.binding,
+                        durable_ref.id_hex
+                    );
+                }
+                Response::from_json(&summary)
+            }
+
+            // Move the virtual clock of this isolate to that of the caller and run the alarm
+            // handler of each DO instance whose alarm is due. Like `DURABLE_DELETE_ALL`, this
+            // method is not intended for production use.
+            //
+            // Output: `u64` (number of instances whose alarm handler was run)
+            (durable::DURABLE_ALARM_IF_DUE, Method::Post) => {
+                virtual_clock::observe_header(&req.headers())?;
+                let queued: Vec<DurableOrdered<DurableReference>> =
+                    DurableOrdered::get_all(&self.state, "object").await?;
+                let mut count = 0_u64;
+                for durable_ref in queued.iter().map(|queued| queued.as_ref()) {
+                    let fired: bool = durable
+                        .post_by_id_hex(
+                            &durable_ref.binding,
+                            durable::DURABLE_ALARM_IF_DUE,
+                            durable_ref.id_hex.clone(),
+                            &(),
+                        )
+                        .await?;
+                    if fired {
+                        count += 1;
+                        trace!(
+                            "ran alarm of {} instance {}",
+                            durable_ref.binding,
+                            durable_ref.id_hex
+                        );
+                    }
+                }
+                Response::from_json(&count)
+            }
+
             _ => {
                 let message = format!(
                     "unexpected request: method={:?}; path={:?}",
@@ -112,3 +282,8 @@ impl GarbageCollector {
         }
     }
 }
+
+/// Key under which a DO instance is registered. The ID of an instance is unique within its binding.
+fn registry_key(durable_ref: &DurableReference) -> String {
+    format!("{}/{}", durable_ref.binding, durable_ref.id_hex)
+}
@@ -3,31 +3,249 @@
 
 use crate::{
     config::DaphneWorkerConfig,
-    durable::{create_span_from_request, state_get, state_set_if_not_exists},
-    initialize_tracing, int_err,
+    durable::{create_span_from_request, state_get, StorageKey, BINDING_DAP_HELPER_STATE_STORE},
+    initialize_tracing, int_err, now,
 };
-use daphne::{messages::TaskId, DapVersion, MetaAggregationJobId};
+use daphne::{fatal_error, messages::TaskId, DapError, DapVersion, MetaAggregationJobId};
+use rand::prelude::*;
+use ring::{aead, digest};
+use serde::{Deserialize, Serialize};
+use std::ops::ControlFlow;
 use tracing::{trace, Instrument};
 use worker::*;
 
-use super::{req_parse, Alarmed, DapDurableObject};
+use super::{
+    durable_task, req_parse, Alarmed, DapDurableObject, GarbageCollectable, SchemaVersionState,
+    SchemaVersioned,
+};
 
 pub(crate) fn durable_helper_state_name(
     version: &DapVersion,
     task_id: &TaskId,
     agg_job_id: &MetaAggregationJobId,
 ) -> String {
-    format!(
-        "{}/task/{}/agg_job/{}",
-        version.as_ref(),
-        task_id.to_hex(),
-        agg_job_id.to_hex()
-    )
+    StorageKey::HelperState {
+        version: *version,
+        task_id: task_id.clone(),
+        agg_job_id: agg_job_id.clone().into_owned(),
+    }
+    .to_string()
 }
 
 pub(crate) const DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS: &str =
     "/internal/do/helper_state/put_if_not_exists";
-pub(crate) const DURABLE_HELPER_STATE_GET: &str = "/internal/do/helper_state/get";
+pub(crate) const DURABLE_HELPER_STATE_CLAIM: &str = "/internal/do/helper_state/claim";
+pub(crate) const DURABLE_HELPER_STATE_ACK: &str = "/internal/do/helper_state/ack";
+pub(crate) const DURABLE_HELPER_STATE_PUT_AGG_JOB_CONT_RESP: &str =
+    "/internal/do/helper_state/put_agg_job_cont_resp";
+pub(crate) const DURABLE_HELPER_STATE_GET_AGG_JOB_CONT_RESP: &str =
+    "/internal/do/helper_state/get_agg_job_cont_resp";
+pub(crate) const DURABLE_HELPER_STATE_DELETE: &str = "/internal/do/helper_state/delete";
+
+/// Key under which the state was stored before it was split into chunks. This is only read, so
+/// that aggregation jobs started before the storage format changed can still be completed.
+const LEGACY_HELPER_STATE_KEY: &str = "helper_state";
+
+const HELPER_STATE_MANIFEST_KEY: &str = "helper_state/manifest";
+
+/// Maximum length of each chunk of the hex-encoded state. This keeps each value well below the
+/// DO's per-value size limit (128 KiB).
+const HELPER_STATE_CHUNK_LEN: usize = 64 * 1024;
+
+const AGG_JOB_CONT_RESP_MANIFEST_KEY: &str = "agg_job_cont_resp/manifest";
+
+/// Time at which the state was last claimed. The key is deleted when the claim is acknowledged.
+const HELPER_STATE_CLAIMED_AT_KEY: &str = "helper_state/claimed_at";
+
+/// Set once the state has been acknowledged (and deleted), so that it can't be stored again.
+const HELPER_STATE_ACKED_KEY: &str = "helper_state/acked";
+
+/// Number of seconds after which an unacknowledged claim expires and the state can be claimed
+/// again.
+pub(crate) const HELPER_STATE_CLAIM_TIMEOUT_SECS: u64 = 60;
+
+fn helper_state_chunk_key(n: usize) -> String {
+    format!("helper_state/chunk/{n}")
+}
+
+fn agg_job_cont_resp_chunk_key(generation: u64, n: usize) -> String {
+    format!("agg_job_cont_resp/{generation}/chunk/{n}")
+}
+
+/// Prefix of a state that was encrypted before it was stored. See [`HelperStateKeys`].
+const HELPER_STATE_SEALED_PREFIX: &str = "sealed:";
+
+/// Length of the key ID that identifies the key with which a state was encrypted.
+const HELPER_STATE_KEY_ID_LEN: usize = 8;
+
+/// A key for encrypting the Helper's state at rest.
+pub(crate) struct HelperStateKey {
+    /// Truncated SHA-256 hash of the key.
+    id: [u8; HELPER_STATE_KEY_ID_LEN],
+    key: [u8; 32],
+}
+
+impl HelperStateKey {
+    pub(crate) fn new(key: [u8; 32]) -> Self {
+        let mut id = [0; HELPER_STATE_KEY_ID_LEN];
+        id.copy_from_slice(
+            &digest::digest(&digest::SHA256, &key).as_ref()[..HELPER_STATE_KEY_ID_LEN],
+        );
+        Self { id, key }
+    }
+
+    fn aead_key(&self) -> aead::LessSafeKey {
+        aead::LessSafeKey::new(
+            aead::UnboundKey::new(&aead::AES_256_GCM, &self.key)
+                .expect("AES-256-GCM key has the wrong length"),
+        )
+    }
+}
+
+/// Keys for encrypting the Helper's state at rest, so that the secrets it contains (e.g., the
+/// Helper's prep shares) are not revealed to someone who can read DO storage.
+///
+/// The state is encrypted with AES-256-GCM under the current key, using the name of the DO
+/// instance as associated data so that the ciphertext can't be moved to another aggregation job.
+/// It is stored as `sealed:<key ID>:<nonce and ciphertext>`, where both fields are hex-encoded.
+/// To rotate keys, the current key becomes the previous key, so that the states stored before the
+/// rotation can still be decrypted.
+pub(crate) struct HelperStateKeys {
+    pub(crate) current: HelperStateKey,
+    pub(crate) previous: Option<HelperStateKey>,
+}
+
+impl HelperStateKeys {
+    /// Encrypt the encoded state of the aggregation job with the given DO instance name.
+    pub(crate) fn seal(&self, durable_name: &str, helper_state: &[u8]) -> String {
+        let nonce_bytes = thread_rng().gen::<[u8; aead::NONCE_LEN]>();
+        let mut in_out = helper_state.to_vec();
+        self.current
+            .aead_key()
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce_bytes),
+                aead::Aad::from(durable_name.as_bytes()),
+                &mut in_out,
+            )
+            .expect("AES-256-GCM plaintext is too long");
+        format!(
+            "{HELPER_STATE_SEALED_PREFIX}{}:{}{}",
+            hex::encode(self.current.id),
+            hex::encode(nonce_bytes),
+            hex::encode(in_out)
+        )
+    }
+
+    /// Decrypt a state stored by [`Self::seal`] under the current or previous key.
+    pub(crate) fn open(&self, durable_name: &str, sealed: &str) -> Result<Vec<u8>, DapError> {
+        let (key_id, ciphertext) = sealed
+            .strip_prefix(HELPER_STATE_SEALED_PREFIX)
+            .and_then(|sealed| sealed.split_once(':'))
+            .ok_or_else(|| fatal_error!(err = "helper state is not sealed", code = Storage))?;
+        let key_id = hex::decode(key_id)
+            .map_err(|e| fatal_error!(err = ?e, code = Storage, "malformed helper state key ID"))?;
+        let key = std::iter::once(&self.current)
+            .chain(self.previous.as_ref())
+            .find(|key| key.id[..] == key_id[..])
+            .ok_or_else(|| {
+                fatal_error!(
+                    err = "helper state was sealed with an unknown key",
+                    code = Storage,
+                    key_id = %hex::encode(&key_id)
+                )
+            })?;
+        let mut ciphertext = hex::decode(ciphertext).map_err(
+            |e| fatal_error!(err = ?e, code = Storage, "malformed helper state ciphertext"),
+        )?;
+        if ciphertext.len() < aead::NONCE_LEN {
+            return Err(fatal_error!(
+                err = "helper state ciphertext is too short",
+                code = Storage
+            ));
+        }
+        let mut in_out = ciphertext.split_off(aead::NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(&ciphertext)
+            .map_err(|_| fatal_error!(err = "malformed helper state nonce", code = Storage))?;
+        let helper_state = key
+            .aead_key()
+            .open_in_place(nonce, aead::Aad::from(durable_name.as_bytes()), &mut in_out)
+            .map_err(|_| fatal_error!(err = "failed to decrypt helper state", code = Storage))?;
+        Ok(helper_state.to_vec())
+    }
+}
+
+/// Encode the Helper's state for storage, encrypting it if `keys` is set.
+pub(crate) fn encode_helper_state(
+    keys: Option<&HelperStateKeys>,
+    durable_name: &str,
+    helper_state: &[u8],
+) -> String {
+    match keys {
+        Some(keys) => keys.seal(durable_name, helper_state),
+        None => hex::encode(helper_state),
+    }
+}
+
+/// Decode a stored state. States stored before encryption was enabled are hex-encoded and are
+/// accepted as is.
+pub(crate) fn decode_helper_state(
+    keys: Option<&HelperStateKeys>,
+    durable_name: &str,
+    stored: &str,
+) -> Result<Vec<u8>, DapError> {
+    if !stored.starts_with(HELPER_STATE_SEALED_PREFIX) {
+        return hex::decode(stored)
+            .map_err(|e| fatal_error!(err = ?e, code = Storage, "malformed helper state"));
+    }
+    keys.ok_or_else(|| {
+        fatal_error!(
+            err = "helper state is sealed, but no helper state key is configured",
+            code = Config
+        )
+    })?
+    .open(durable_name, stored)
+}
+
+/// Describes how the state is split into chunks. The manifest is written after all of the chunks,
+/// so its presence indicates that the state is complete.
+#[derive(Deserialize, Serialize)]
+struct HelperStateManifest {
+    chunk_count: usize,
+}
+
+/// Result of `DURABLE_HELPER_STATE_CLAIM`.
+#[derive(Deserialize, Serialize)]
+pub(crate) enum HelperStateClaim {
+    /// The hex-encoded or sealed state.
+    Claimed(String),
+
+    /// The state is claimed by another request and the claim has not yet expired.
+    InFlight,
+
+    /// There is no state, either because it was never stored or because it was acknowledged.
+    NotFound,
+}
+
+/// The Helper's response to the last `AggregationJobContinueReq` it processed, kept so that it can
+/// be replayed if the Leader retransmits the request.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AggJobContResp {
+    pub(crate) round: u16,
+    /// Hex-encoded SHA-256 digest of the body of the request.
+    pub(crate) req_digest_hex: String,
+    pub(crate) agg_job_resp_hex: String,
+}
+
+/// Describes how the stored response is split into chunks. Each response is stored under a new
+/// generation, and the manifest is written after all of its chunks, so replacing the response
+/// never leaves a mix of old and new chunks.
+#[derive(Deserialize, Serialize)]
+struct AggJobContRespManifest {
+    round: u16,
+    req_digest_hex: String,
+    generation: u64,
+    chunk_count: usize,
+}
 
 /// Durable Object (DO) for storing the Helper's state for a given aggregation job.
 ///
@@ -35,14 +253,34 @@ pub(crate) const DURABLE_HELPER_STATE_GET: &str = "/internal/do/helper_state/get
 ///
 /// - `DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS`: Stores Helper's hex-encoded state unless the state
 ///    already exists. Returns a boolean indicating whether the operation succeeded.
-/// - `DURABLE_HELPER_STATE_GET`: Drains the Helper's hex-encoded state.
+/// - `DURABLE_HELPER_STATE_CLAIM`: Returns the Helper's hex-encoded state and marks it as
+///    in-flight. The state can't be claimed again until `HELPER_STATE_CLAIM_TIMEOUT_SECS` have
+///    passed.
+/// - `DURABLE_HELPER_STATE_ACK`: Deletes the Helper's state once it has been consumed. The state
+///    can't be stored again afterwards.
+/// - `DURABLE_HELPER_STATE_PUT_AGG_JOB_CONT_RESP`: Stores the Helper's response to the last
+///    `AggregationJobContinueReq` it processed, overwriting the previous one.
+/// - `DURABLE_HELPER_STATE_GET_AGG_JOB_CONT_RESP`: Returns the stored response, if any. The
+///    response is stored before the output shares are committed, so it is only returned once the
+///    state has been acknowledged.
+/// - `DURABLE_HELPER_STATE_DELETE`: Deletes the state and the stored response. This is used when
+///    the Leader abandons or deletes the aggregation job. Like acknowledging the state, this
+///    prevents the state from being stored again.
 ///
-/// The state blob is stored in `helper_state`.
+/// The state is hex-encoded, or sealed if the Helper is configured with [`HelperStateKeys`]. The
+/// stored string is split into chunks of at most `HELPER_STATE_CHUNK_LEN` characters,
+/// which are stored in `helper_state/chunk/<n>`. The number of chunks is stored in
+/// `helper_state/manifest`. States stored in the legacy format, where the entire blob is stored in
+/// `helper_state`, can still be read. The stored response is chunked the same way, in
+/// `agg_job_cont_resp/<generation>/chunk/<n>` and `agg_job_cont_resp/manifest`.
 #[durable_object]
 pub struct HelperStateStore {
     state: State,
+    env: Env,
     config: DaphneWorkerConfig,
+    touched: bool,
     alarmed: bool,
+    schema_version_state: SchemaVersionState,
 }
 
 #[durable_object]
@@ -53,12 +291,17 @@ impl DurableObject for HelperStateStore {
             DaphneWorkerConfig::from_worker_env(&env).expect("failed to load configuration");
         Self {
             state,
+            env,
             config,
+            touched: false,
             alarmed: false,
+            schema_version_state: SchemaVersionState::Unchecked,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.observe_virtual_clock(&req)?;
+
         // Ensure this DO instance is garbage collected eventually.
         self.ensure_alarmed(
             self.config
@@ -67,13 +310,21 @@ impl DurableObject for HelperStateStore {
         )
         .await?;
 
+        self.ensure_schema_version().await?;
+        let task = durable_task(&req)?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version_for_task(task, BINDING_DAP_HELPER_STATE_STORE)
+            .await?;
+        resp
     }
 
     async fn alarm(&mut self) -> Result<Response> {
         self.state.storage().delete_all().await?;
+        self.unregister(BINDING_DAP_HELPER_STATE_STORE).await?;
+        self.touched = false;
         self.alarmed = false;
+        self.schema_version_state = SchemaVersionState::Unrecorded;
         trace!(
             "HelperStateStore: deleted instance {}",
             self.state.id().to_string()
@@ -83,29 +334,82 @@ impl DurableObject for HelperStateStore {
 }
 
 impl HelperStateStore {
-    async fn handle(&mut self, mut req: Request) -> Result<Response> {
+    async fn handle(&mut self, req: Request) -> Result<Response> {
+        let mut req = match self
+            .schedule_for_garbage_collection(req, BINDING_DAP_HELPER_STATE_STORE)
+            .await?
+        {
+            ControlFlow::Continue(req) => req,
+            // This req was a GC request and as such we must return from this function.
+            ControlFlow::Break(resp) => return Ok(resp),
+        };
+
         match (req.path().as_ref(), req.method()) {
             // Store the Helper's state.
             //
             // Non-idempotent
-            // Input: `helper_state_hex: String` (hex-encoded state)
+            // Input: `helper_state_hex: String` (hex-encoded or sealed state)
             // Output: `bool`
             (DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS, Method::Post) => {
                 let helper_state_hex: String = req_parse(&mut req).await?;
-                let success =
-                    state_set_if_not_exists(&self.state, "helper_state", &helper_state_hex)
-                        .await?
-                        .is_none();
+                let success = self.put_if_not_exists(&helper_state_hex).await?;
                 Response::from_json(&success)
             }
 
-            // Get the Helper's state.
+            // Claim the Helper's state.
+            //
+            // Non-idempotent
+            // Output: `HelperStateClaim`
+            (DURABLE_HELPER_STATE_CLAIM, Method::Post) => {
+                let claim = self.claim().await?;
+                Response::from_json(&claim)
+            }
+
+            // Acknowledge that the Helper's state has been consumed.
+            //
+            // Idempotent
+            // Output: `()`
+            (DURABLE_HELPER_STATE_ACK, Method::Post) => {
+                self.ack().await?;
+                Response::from_json(&())
+            }
+
+            // Store the Helper's response to an `AggregationJobContinueReq`.
             //
             // Idempotent
-            // Output: `String` (hex-encoded state)
-            (DURABLE_HELPER_STATE_GET, Method::Get) => {
-                let helper_state: Option<String> = state_get(&self.state, "helper_state").await?;
-                Response::from_json(&helper_state)
+            // Input: `AggJobContResp`
+            // Output: `()`
+            (DURABLE_HELPER_STATE_PUT_AGG_JOB_CONT_RESP, Method::Post) => {
+                let agg_job_cont_resp: AggJobContResp = req_parse(&mut req).await?;
+                self.put_agg_job_cont_resp(agg_job_cont_resp).await?;
+                Response::from_json(&())
+            }
+
+            // Get the Helper's response to the last `AggregationJobContinueReq`.
+            //
+            // Idempotent
+            // Output: `Option<AggJobContResp>`
+            (DURABLE_HELPER_STATE_GET_AGG_JOB_CONT_RESP, Method::Get) => {
+                let agg_job_cont_resp = self.get_agg_job_cont_resp().await?;
+                Response::from_json(&agg_job_cont_resp)
+            }
+
+            // Delete the Helper's state and the stored response.
+            //
+            // Idempotent
+            // Output: `()`
+            (DURABLE_HELPER_STATE_DELETE, Method::Post) => {
+                self.state.storage().delete_all().await?;
+                self.state
+                    .storage()
+                    .put(HELPER_STATE_ACKED_KEY, true)
+                    .await?;
+                self.schema_version_state = SchemaVersionState::Unrecorded;
+                trace!(
+                    "HelperStateStore: deleted instance {}",
+                    self.state.id().to_string()
+                );
+                Response::from_json(&())
             }
 
             _ => Err(int_err(format!(
@@ -115,6 +419,177 @@ impl HelperStateStore {
             ))),
         }
     }
+
+    async fn put_if_not_exists(&self, helper_state_hex: &str) -> Result<bool> {
+        let manifest: Option<HelperStateManifest> =
+            state_get(&self.state, HELPER_STATE_MANIFEST_KEY).await?;
+        let legacy: Option<String> = state_get(&self.state, LEGACY_HELPER_STATE_KEY).await?;
+        let acked: Option<bool> = state_get(&self.state, HELPER_STATE_ACKED_KEY).await?;
+        if manifest.is_some() || legacy.is_some() || acked.is_some() {
+            return Ok(false);
+        }
+
+        let chunk_count = self
+            .put_chunks(helper_state_hex, helper_state_chunk_key)
+            .await?;
+        self.state
+            .storage()
+            .put(
+                HELPER_STATE_MANIFEST_KEY,
+                &HelperStateManifest { chunk_count },
+            )
+            .await?;
+        Ok(true)
+    }
+
+    async fn claim(&self) -> Result<HelperStateClaim> {
+        let acked: Option<bool> = state_get(&self.state, HELPER_STATE_ACKED_KEY).await?;
+        if acked.is_some() {
+            return Ok(HelperStateClaim::NotFound);
+        }
+
+        let now = now();
+        let claimed_at: Option<u64> = state_get(&self.state, HELPER_STATE_CLAIMED_AT_KEY).await?;
+        if let Some(claimed_at) = claimed_at {
+            if now < claimed_at + HELPER_STATE_CLAIM_TIMEOUT_SECS {
+                return Ok(HelperStateClaim::InFlight);
+            }
+        }
+
+        let Some(helper_state_hex) = self.get().await? else {
+            return Ok(HelperStateClaim::NotFound);
+        };
+        self.state
+            .storage()
+            .put(HELPER_STATE_CLAIMED_AT_KEY, now)
+            .await?;
+        Ok(HelperStateClaim::Claimed(helper_state_hex))
+    }
+
+    async fn ack(&self) -> Result<()> {
+        let mut keys = vec![
+            HELPER_STATE_MANIFEST_KEY.to_string(),
+            LEGACY_HELPER_STATE_KEY.to_string(),
+            HELPER_STATE_CLAIMED_AT_KEY.to_string(),
+        ];
+        if let Some(manifest) =
+            state_get::<HelperStateManifest>(&self.state, HELPER_STATE_MANIFEST_KEY).await?
+        {
+            keys.extend((0..manifest.chunk_count).map(helper_state_chunk_key));
+        }
+
+        // Write the tombstone first, so that the state can't be claimed or stored again if
+        // deleting it fails part way through.
+        self.state
+            .storage()
+            .put(HELPER_STATE_ACKED_KEY, true)
+            .await?;
+        self.state.storage().delete_multiple(keys).await?;
+        Ok(())
+    }
+
+    async fn get(&self) -> Result<Option<String>> {
+        let Some(manifest) =
+            state_get::<HelperStateManifest>(&self.state, HELPER_STATE_MANIFEST_KEY).await?
+        else {
+            // Fall back to the legacy format.
+            return state_get(&self.state, LEGACY_HELPER_STATE_KEY).await;
+        };
+
+        let helper_state_hex = self
+            .get_chunks(manifest.chunk_count, helper_state_chunk_key)
+            .await?;
+        Ok(Some(helper_state_hex))
+    }
+
+    async fn put_agg_job_cont_resp(&self, agg_job_cont_resp: AggJobContResp) -> Result<()> {
+        let prev_manifest: Option<AggJobContRespManifest> =
+            state_get(&self.state, AGG_JOB_CONT_RESP_MANIFEST_KEY).await?;
+        let generation = prev_manifest
+            .as_ref()
+            .map_or(0, |manifest| manifest.generation + 1);
+
+        let chunk_count = self
+            .put_chunks(&agg_job_cont_resp.agg_job_resp_hex, |n| {
+                agg_job_cont_resp_chunk_key(generation, n)
+            })
+            .await?;
+        self.state
+            .storage()
+            .put(
+                AGG_JOB_CONT_RESP_MANIFEST_KEY,
+                &AggJobContRespManifest {
+                    round: agg_job_cont_resp.round,
+                    req_digest_hex: agg_job_cont_resp.req_digest_hex,
+                    generation,
+                    chunk_count,
+                },
+            )
+            .await?;
+
+        // Delete the chunks of the response that was replaced.
+        if let Some(prev_manifest) = prev_manifest {
+            let keys = (0..prev_manifest.chunk_count)
+                .map(|n| agg_job_cont_resp_chunk_key(prev_manifest.generation, n))
+                .collect::<Vec<_>>();
+            self.state.storage().delete_multiple(keys).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_agg_job_cont_resp(&self) -> Result<Option<AggJobContResp>> {
+        // The output shares are committed before the state is acknowledged.
+        let acked: Option<bool> = state_get(&self.state, HELPER_STATE_ACKED_KEY).await?;
+        if acked.is_none() {
+            return Ok(None);
+        }
+
+        let Some(manifest) =
+            state_get::<AggJobContRespManifest>(&self.state, AGG_JOB_CONT_RESP_MANIFEST_KEY)
+                .await?
+        else {
+            return Ok(None);
+        };
+        let agg_job_resp_hex = self
+            .get_chunks(manifest.chunk_count, |n| {
+                agg_job_cont_resp_chunk_key(manifest.generation, n)
+            })
+            .await?;
+        Ok(Some(AggJobContResp {
+            round: manifest.round,
+            req_digest_hex: manifest.req_digest_hex,
+            agg_job_resp_hex,
+        }))
+    }
+
+    /// Store `value` in chunks of at most `HELPER_STATE_CHUNK_LEN` characters under the keys
+    /// returned by `chunk_key`. Returns the number of chunks.
+    async fn put_chunks(&self, value: &str, chunk_key: impl Fn(usize) -> String) -> Result<usize> {
+        // The value is ASCII, so splitting on byte boundaries always yields valid UTF-8.
+        let mut chunk_count = 0;
+        for (n, chunk) in value.as_bytes().chunks(HELPER_STATE_CHUNK_LEN).enumerate() {
+            let chunk = std::str::from_utf8(chunk).map_err(int_err)?;
+            self.state.storage().put(&chunk_key(n), chunk).await?;
+            chunk_count += 1;
+        }
+        Ok(chunk_count)
+    }
+
+    /// Reassemble a value stored by [`Self::put_chunks`].
+    async fn get_chunks(
+        &self,
+        chunk_count: usize,
+        chunk_key: impl Fn(usize) -> String,
+    ) -> Result<String> {
+        let mut value = String::new();
+        for n in 0..chunk_count {
+            let chunk: String = state_get(&self.state, &chunk_key(n))
+                .await?
+                .ok_or_else(|| int_err(format!("HelperStateStore: missing chunk {n}")))?;
+            value.push_str(&chunk);
+        }
+        Ok(value)
+    }
 }
 
 impl DapDurableObject for HelperStateStore {
@@ -129,6 +604,16 @@ impl DapDurableObject for HelperStateStore {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for HelperStateStore {
+    const CLASS: &'static str = "HelperStateStore";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl Alarmed for HelperStateStore {
     #[inline(always)]
@@ -136,3 +621,66 @@ impl Alarmed for HelperStateStore {
         &mut self.alarmed
     }
 }
+
+#[async_trait::async_trait(?Send)]
+impl GarbageCollectable for HelperStateStore {
+    #[inline(always)]
+    fn touched(&mut self) -> &mut bool {
+        &mut self.touched
+    }
+
+    #[inline(always)]
+    fn env(&self) -> &Env {
+        &self.env
+    }
+
+    async fn virtual_alarm(&mut self) -> Result<bool> {
+        self.alarm_if_due().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_helper_state, encode_helper_state, HelperStateKey, HelperStateKeys};
+
+    #[test]
+    fn seal_and_open_with_rotation() {
+        let name = "v07/task/11/agg_job/22";
+        let keys = HelperStateKeys {
+            current: HelperStateKey::new([1; 32]),
+            previous: None,
+        };
+        let sealed = encode_helper_state(Some(&keys), name, b"helper state");
+        assert!(!sealed.contains(&hex::encode(b"helper state")));
+        assert_eq!(
+            decode_helper_state(Some(&keys), name, &sealed).unwrap(),
+            b"helper state"
+        );
+
+        // The state can't be opened for another aggregation job.
+        assert!(decode_helper_state(Some(&keys), "v07/task/11/agg_job/33", &sealed).is_err());
+
+        // After rotation, the state is opened with the previous key.
+        let rotated = HelperStateKeys {
+            current: HelperStateKey::new([2; 32]),
+            previous: Some(HelperStateKey::new([1; 32])),
+        };
+        assert_eq!(
+            decode_helper_state(Some(&rotated), name, &sealed).unwrap(),
+            b"helper state"
+        );
+        let rotated_twice = HelperStateKeys {
+            current: HelperStateKey::new([3; 32]),
+            previous: Some(HelperStateKey::new([2; 32])),
+        };
+        assert!(decode_helper_state(Some(&rotated_twice), name, &sealed).is_err());
+        assert!(decode_helper_state(None, name, &sealed).is_err());
+
+        // States stored before encryption was enabled are still accepted.
+        let legacy = encode_helper_state(None, name, b"helper state");
+        assert_eq!(
+            decode_helper_state(Some(&keys), name, &legacy).unwrap(),
+            b"helper state"
+        );
+    }
+}
@@ -1,43 +1,91 @@
 // Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::ops::ControlFlow;
+use std::{collections::VecDeque, ops::ControlFlow};
 
 use crate::{
     config::DaphneWorkerConfig,
     durable::{
-        create_span_from_request, req_parse, DurableOrdered, BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+        create_span_from_request, req_parse, state_get, DurableOrdered,
+        BINDING_DAP_LEADER_AGG_JOB_QUEUE, MAX_KEYS,
     },
     initialize_tracing, int_err,
 };
+use daphne::messages::TaskId;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, Instrument};
 use worker::*;
 
-use super::{DapDurableObject, GarbageCollectable};
+use super::{DapDurableObject, GarbageCollectable, SchemaVersionState, SchemaVersioned};
 
 pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_PUT: &str = "/internal/do/agg_job_queue/put";
 pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_GET: &str = "/internal/do/agg_job_queue/get";
 pub(crate) const DURABLE_LEADER_AGG_JOB_QUEUE_FINISH: &str = "/internal/do/agg_job_queue/finish";
 
+const TASK_PREFIX: &str = "task/";
+const CURSOR_KEY: &str = "cursor";
+
+/// Prefix of the queue of aggregation jobs for the given task.
+pub(crate) fn agg_job_queue_prefix(task_id: &TaskId) -> String {
+    task_queue_prefix(&task_id.to_hex())
+}
+
+fn task_queue_prefix(task_id_hex: &str) -> String {
+    format!("agg_job/task/{task_id_hex}")
+}
+
+/// Input of `DURABLE_LEADER_AGG_JOB_QUEUE_PUT`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AggJobQueuePutRequest {
+    /// The task for which the job was created.
+    pub(crate) task_id: TaskId,
+
+    /// The job, created with the prefix returned by [`agg_job_queue_prefix`]. The item is the name
+    /// of the `ReportsPending` instance.
+    pub(crate) agg_job: DurableOrdered<String>,
+}
+
+/// Input of `DURABLE_LEADER_AGG_JOB_QUEUE_GET`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AggJobQueueGetRequest {
+    /// Maximum number of jobs to fetch.
+    pub(crate) max_agg_jobs: usize,
+
+    /// Maximum number of jobs to fetch for any one task by this request. If not set, then a single
+    /// task may take up to `max_agg_jobs`. Jobs fetched by previous requests are not counted.
+    #[serde(alias = "max_agg_jobs_per_task")]
+    pub(crate) max_agg_jobs_per_task_per_fetch: Option<usize>,
+}
+
 /// Durable Object (DO) representing an aggregation job queue.
 ///
 /// This object defines the following API endpoints:
 ///
 /// - `DURABLE_LEADER_AGG_JOB_QUEUE_PUT`: Adds a job to the queue. This is called by an instance of
 ///   `ReportsPending`.
-/// - `DURABLE_LEADER_AGG_JOB_QUEUE_PUT`: Fetches the desired number of jobs from the front of the
-///    queue.
+/// - `DURABLE_LEADER_AGG_JOB_QUEUE_GET`: Fetches the desired number of jobs from the front of the
+///    queue. Jobs are taken from each task's queue in turn so that a task with many jobs does not
+///    starve the others. Each call starts with the task after the last one to get a job in the
+///    first turn of the previous call.
 /// - `DURABLE_LEADER_AGG_JOB_QUEUE_FINISH`: Removes the indicated job from the queue.
 ///
 /// The schemea for data stored in instances of this DO is as follows:
 ///
 /// ```text
+///     agg_job/task/<task_id>/item/time/<time>/nonce/<nonce> -> String
 ///     agg_job/item/time/<time>/nonce/<nonce> -> String
+///     task/<task_id> -> bool
+///     cursor -> String
+///     schema_version -> u32
 /// ```
 ///
-/// where `<time>` and `<nonce>` were generated by the `ReportsPending` instance at creation time.
-/// The value stored is the unique name of the `ReportsPending` instance. Note that this schema
-/// matches the ordinal generated by [`DurableOrdered::new_roughly_ordered`].
+/// where `<task_id>` is the hex-encoded task ID and `<time>` and `<nonce>` were generated by the
+/// `ReportsPending` instance at creation time. The value stored is the unique name of the
+/// `ReportsPending` instance. Note that this schema matches the ordinal generated by
+/// [`DurableOrdered::new_roughly_ordered`]. Jobs without a task were queued before jobs were
+/// grouped by task and are fetched first. The `task/` keys index the tasks that may have jobs
+/// and are removed once the task's queue is found to be empty. The cursor is the last task to get
+/// a job in the first turn of the last fetch.
 #[durable_object]
 pub struct LeaderAggregationJobQueue {
     #[allow(dead_code)]
@@ -45,6 +93,7 @@ pub struct LeaderAggregationJobQueue {
     env: Env,
     config: DaphneWorkerConfig,
     touched: bool,
+    schema_version_state: SchemaVersionState,
 }
 
 #[durable_object]
@@ -58,12 +107,16 @@ impl DurableObject for LeaderAggregationJobQueue {
             env,
             config,
             touched: false,
+            schema_version_state: SchemaVersionState::Unchecked,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version().await?;
+        resp
     }
 }
 
@@ -75,16 +128,19 @@ impl LeaderAggregationJobQueue {
         {
             ControlFlow::Continue(req) => req,
             // This req was a GC request and as such we must return from this function.
-            ControlFlow::Break(_) => return Response::from_json(&()),
+            ControlFlow::Break(resp) => return Ok(resp),
         };
         match (req.path().as_ref(), req.method()) {
             // Put a job (near) the back of the queue.
             //
-            // Input: `agg_job: DurableOrdered<String>` (the `String` is the name of the
-            // `ReportsPending` instance)
+            // Input: `AggJobQueuePutRequest`
             (DURABLE_LEADER_AGG_JOB_QUEUE_PUT, Method::Post) => {
-                let agg_job: DurableOrdered<String> = req_parse(&mut req).await?;
+                let AggJobQueuePutRequest { task_id, agg_job } = req_parse(&mut req).await?;
                 agg_job.put(&self.state).await?;
+                self.state
+                    .storage()
+                    .put(&format!("{TASK_PREFIX}{}", task_id.to_hex()), true)
+                    .await?;
                 debug!(
                     "LeaderAggregationJobQueue: {} has been scheduled",
                     agg_job.as_ref(),
@@ -92,20 +148,14 @@ impl LeaderAggregationJobQueue {
                 Response::from_json(&())
             }
 
-            // Fetch the aggregation jobs at the fron tf the queue.
+            // Fetch the aggregation jobs at the front of each task's queue.
             //
-            // Input: `max_agg_jobs: usize`,
+            // Input: `AggJobQueueGetRequest`
             // Output: `Vec<String>` (the names of the `ReportsPending` instances from which to
             // drain reports)
             (DURABLE_LEADER_AGG_JOB_QUEUE_GET, Method::Post) => {
-                let max_agg_jobs: usize = req_parse(&mut req).await?;
-                let res: Vec<String> =
-                    DurableOrdered::get_front(&self.state, "agg_job", max_agg_jobs)
-                        .await?
-                        .into_iter()
-                        .map(|agg_job| agg_job.into_item())
-                        .collect();
-
+                let get_req: AggJobQueueGetRequest = req_parse(&mut req).await?;
+                let res = self.get_round_robin(get_req).await?;
                 debug!("agg job queue: {:?}", res);
                 Response::from_json(&res)
             }
@@ -127,6 +177,139 @@ impl LeaderAggregationJobQueue {
             ))),
         }
     }
+
+    /// Fetch jobs from the front of each task's queue in turn (see `take_round_robin`).
+    async fn get_round_robin(&mut self, get_req: AggJobQueueGetRequest) -> Result<Vec<String>> {
+        let max_agg_jobs = get_req.max_agg_jobs;
+        let max_agg_jobs_per_task = get_req
+            .max_agg_jobs_per_task_per_fetch
+            .unwrap_or(max_agg_jobs);
+
+        // Jobs queued before jobs were grouped by task are the oldest, so handle them first.
+        let mut res: Vec<String> = DurableOrdered::get_front(&self.state, "agg_job", max_agg_jobs)
+            .await?
+            .into_iter()
+            .map(DurableOrdered::into_item)
+            .collect();
+        if res.len() >= max_agg_jobs {
+            return Ok(res);
+        }
+
+        // Visit the tasks in order, starting after the cursor.
+        let cursor = state_get::<String>(&self.state, CURSOR_KEY).await?;
+        let tasks = self.list_tasks(cursor.as_deref()).await?;
+
+        let mut queues = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            let jobs: VecDeque<String> = DurableOrdered::get_front(
+                &self.state,
+                &task_queue_prefix(&task),
+                max_agg_jobs_per_task,
+            )
+            .await?
+            .into_iter()
+            .map(DurableOrdered::into_item)
+            .collect();
+            if jobs.is_empty() {
+                self.state
+                    .storage()
+                    .delete(&format!("{TASK_PREFIX}{task}"))
+                    .await?;
+            } else {
+                queues.push((task, jobs));
+            }
+        }
+
+        if let Some(cursor) = take_round_robin(&mut res, queues, max_agg_jobs) {
+            self.state.storage().put(CURSOR_KEY, cursor).await?;
+        }
+        Ok(res)
+    }
+
+    /// List the (hex-encoded) IDs of up to [`MAX_KEYS`] tasks that may have jobs, in order,
+    /// starting after the cursor and wrapping around to the first task. This way every task is
+    /// eventually visited, no matter how many there are.
+    async fn list_tasks(&self, cursor: Option<&str>) -> Result<Vec<String>> {
+        let Some(cursor) = cursor else {
+            return self
+                .list_task_keys(ListOptions::new().prefix(TASK_PREFIX).limit(MAX_KEYS))
+                .await;
+        };
+
+        // The start key is inclusive, so the cursor itself may be listed.
+        let start = format!("{TASK_PREFIX}{cursor}");
+        let mut tasks = self
+            .list_task_keys(
+                ListOptions::new()
+                    .prefix(TASK_PREFIX)
+                    .start(&start)
+                    .limit(MAX_KEYS + 1),
+            )
+            .await?;
+        tasks.retain(|task| task.as_str() > cursor);
+        tasks.truncate(MAX_KEYS);
+
+        if tasks.len() < MAX_KEYS {
+            let wrapped = self
+                .list_task_keys(
+                    ListOptions::new()
+                        .prefix(TASK_PREFIX)
+                        .limit(MAX_KEYS - tasks.len()),
+                )
+                .await?;
+            tasks.extend(
+                wrapped
+                    .into_iter()
+                    .take_while(|task| task.as_str() <= cursor),
+            );
+        }
+        Ok(tasks)
+    }
+
+    async fn list_task_keys(&self, opts: ListOptions<'_>) -> Result<Vec<String>> {
+        let iter = self
+            .state
+            .storage()
+            .list_with_options(opts)
+            .await?
+            .entries();
+        let mut tasks = Vec::new();
+        let mut js_item = iter.next()?;
+        while !js_item.done() {
+            let (key, _): (String, bool) =
+                serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
+            tasks.push(key[TASK_PREFIX.len()..].to_string());
+            js_item = iter.next()?;
+        }
+        Ok(tasks)
+    }
+}
+
+/// Move jobs from the queues to `res`, one from each queue in turn, until `res` has `max_agg_jobs`
+/// jobs or the queues are empty. Return the last task to get a job in the first turn, if any.
+fn take_round_robin(
+    res: &mut Vec<String>,
+    mut queues: Vec<(String, VecDeque<String>)>,
+    max_agg_jobs: usize,
+) -> Option<String> {
+    let mut cursor = None;
+    let mut first_turn = true;
+    while res.len() < max_agg_jobs && !queues.is_empty() {
+        for (task, jobs) in &mut queues {
+            if res.len() >= max_agg_jobs {
+                break;
+            }
+            if let Some(job) = jobs.pop_front() {
+                res.push(job);
+                if first_turn {
+                    cursor = Some(task.clone());
+                }
+            }
+        }
+        queues.retain(|(_task, jobs)| !jobs.is_empty());
+        first_turn = false;
+    }
+    cursor
 }
 
 impl DapDurableObject for LeaderAggregationJobQueue {
@@ -141,6 +324,16 @@ impl DapDurableObject for LeaderAggregationJobQueue {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for LeaderAggregationJobQueue {
+    const CLASS: &'static str = "LeaderAggregationJobQueue";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl GarbageCollectable for LeaderAggregationJobQueue {
     #[inline(always)]
@@ -153,3 +346,59 @@ impl GarbageCollectable for LeaderAggregationJobQueue {
         &self.env
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::take_round_robin;
+
+    fn queues(jobs_per_task: &[(&str, &[&str])]) -> Vec<(String, VecDeque<String>)> {
+        jobs_per_task
+            .iter()
+            .map(|(task, jobs)| {
+                (
+                    task.to_string(),
+                    jobs.iter().map(|job| job.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn take_round_robin_interleaves_tasks() {
+        let mut res = Vec::new();
+        let cursor = take_round_robin(
+            &mut res,
+            queues(&[
+                ("a", &["a1", "a2", "a3"]),
+                ("b", &["b1"]),
+                ("c", &["c1", "c2"]),
+            ]),
+            5,
+        );
+        assert_eq!(res, ["a1", "b1", "c1", "a2", "c2"]);
+        assert_eq!(cursor.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn take_round_robin_resumes_after_cursor() {
+        // Only the first two tasks get a job, so the next fetch starts with the third.
+        let mut res = Vec::new();
+        let cursor = take_round_robin(
+            &mut res,
+            queues(&[("a", &["a1", "a2"]), ("b", &["b1"]), ("c", &["c1"])]),
+            2,
+        );
+        assert_eq!(res, ["a1", "b1"]);
+        assert_eq!(cursor.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn take_round_robin_after_legacy_jobs() {
+        let mut res = vec!["legacy".to_string()];
+        let cursor = take_round_robin(&mut res, queues(&[("a", &["a1", "a2"])]), 2);
+        assert_eq!(res, ["legacy", "a1"]);
+        assert_eq!(cursor.as_deref(), Some("a"));
+    }
+}
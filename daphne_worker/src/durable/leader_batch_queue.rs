@@ -8,28 +8,91 @@ use crate::{
     durable::{
         create_span_from_request, state_get, DurableOrdered, BINDING_DAP_LEADER_BATCH_QUEUE,
     },
-    initialize_tracing, int_err,
+    initialize_tracing, int_err, now,
+};
+use daphne::{
+    messages::{BatchId, Duration, Time},
+    DapBatchState,
 };
-use daphne::messages::BatchId;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, Instrument};
+use tracing::{debug, info, Instrument};
 use worker::*;
 
-use super::{req_parse, DapDurableObject, GarbageCollectable};
+use super::{
+    durable_task, req_parse, Alarmed, DapDurableObject, GarbageCollectable, SchemaVersionState,
+    SchemaVersioned,
+};
 
 pub(crate) const DURABLE_LEADER_BATCH_QUEUE_ASSIGN: &str = "/internal/do/leader_batch_queue/assign";
 pub(crate) const DURABLE_LEADER_BATCH_QUEUE_CURRENT: &str =
     "/internal/do/leader_batch_queue/current";
 pub(crate) const DURABLE_LEADER_BATCH_QUEUE_REMOVE: &str = "/internal/do/leader_batch_queue/remove";
+pub(crate) const DURABLE_LEADER_BATCH_QUEUE_LIST: &str = "/internal/do/leader_batch_queue/list";
+pub(crate) const DURABLE_LEADER_BATCH_QUEUE_STATE: &str = "/internal/do/leader_batch_queue/state";
 
 const CURRENT: &str = "current";
 const PENDING_PREFIX: &str = "pending";
+const LIMITS: &str = "limits";
+const DISCARDED: &str = "discarded";
 
 #[derive(Clone, Deserialize, Serialize)]
 pub(crate) struct BatchCount {
     pub(crate) batch_id: BatchId,
     pub(crate) report_count: usize,
+
+    /// Time at which the batch was created. This is not set for batches created before batch time
+    /// limits were supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<Time>,
+}
+
+/// Limits on how long the current batch may be filled. These are taken from the task config and
+/// stored so that they can be enforced by the alarm handler.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub(crate) struct BatchLimits {
+    /// Close the current batch once it is older than this many seconds.
+    pub(crate) batch_time_limit: Option<Duration>,
+
+    /// Minimum number of reports for a batch closed due to its age to remain collectable.
+    /// Batches with fewer reports are discarded.
+    pub(crate) min_batch_size: usize,
+}
+
+impl BatchLimits {
+    /// Construct the limits from the batch time limit and minimum batch size of a task.
+    pub(crate) fn new(
+        batch_time_limit: Option<Duration>,
+        min_batch_size: u64,
+    ) -> std::result::Result<Self, std::num::TryFromIntError> {
+        Ok(Self {
+            batch_time_limit,
+            min_batch_size: min_batch_size.try_into()?,
+        })
+    }
+}
+
+/// Input of `DURABLE_LEADER_BATCH_QUEUE_ASSIGN`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct BatchAssignRequest {
+    /// Number of reports after which a batch is saturated.
+    pub(crate) batch_size: usize,
+
+    /// Number of reports to assign to batches.
+    pub(crate) num_unassigned: usize,
+
+    pub(crate) limits: BatchLimits,
+}
+
+/// Output of `DURABLE_LEADER_BATCH_QUEUE_ASSIGN`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct BatchAssignResult {
+    /// The number of reports assigned to each batch.
+    pub(crate) assignments: Vec<BatchCount>,
+
+    /// Batches discarded since the last call, because they exceeded the batch time limit without
+    /// reaching the minimum batch size.
+    pub(crate) discarded: Vec<BatchCount>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -44,8 +107,16 @@ pub(crate) enum LeaderBatchQueueResult {
 /// This object implements the following API endpoints:
 ///
 /// - `DURABLE_LEADER_BATCH_QUEUE_ASSIGN`: Assign the requested number of reports to batches.
+///   If the task has a batch time limit, then the current batch is closed once it exceeds the
+///   limit. A closed batch remains in the queue if it has enough reports to be collected and is
+///   discarded otherwise. An alarm is used to close the current batch even if no more reports
+///   are assigned.
 /// - `DURABLE_LEADER_BATCH_QUEUE_CURRENT`: Return the ID of the oldest, non-yet-collected batch.
 /// - `DURABLE_LEADER_BATCH_QUEUE_REMOVE`: Remove the given batch from the queue.
+/// - `DURABLE_LEADER_BATCH_QUEUE_LIST`: Return the IDs of the batches in the queue, including the
+///   batch currently being filled.
+/// - `DURABLE_LEADER_BATCH_QUEUE_STATE`: Return the state of the given batch, or nothing if the
+///   batch is not in the queue.
 ///
 /// The schema for data stored in instances of this DO is as follows:
 ///
@@ -54,6 +125,9 @@ pub(crate) enum LeaderBatchQueueResult {
 /// [Pending queue]     pending/next_ordinal -> u64
 /// [Pending queue]     pending/item/order/<order> -> BatchCount
 /// [Current batch]     current -> BatchCount (the batch currently being filled)
+/// [Batch limits]      limits -> BatchLimits
+/// [Discarded batches] discarded -> Vec<BatchCount> (not yet reported to the caller)
+/// [Schema version]    schema_version -> u32
 /// ```
 ///
 /// Note that the queue ordinal format is inherited from [`DurableOrdered::new_strictly_ordered`].
@@ -64,6 +138,8 @@ pub struct LeaderBatchQueue {
     env: Env,
     config: DaphneWorkerConfig,
     touched: bool,
+    alarmed: bool,
+    schema_version_state: SchemaVersionState,
 }
 
 impl LeaderBatchQueue {
@@ -75,6 +151,7 @@ impl LeaderBatchQueue {
             BatchCount {
                 batch_id: BatchId(rng.gen()),
                 report_count: 0,
+                created_at: Some(now()),
             },
             PENDING_PREFIX,
         )
@@ -92,6 +169,54 @@ impl LeaderBatchQueue {
         debug!("LeaderBatchQueue: created batch {batch_id_hex}");
         Ok(queued.into_item())
     }
+
+    /// Return the time at which the batch exceeds the batch time limit, if any.
+    pub(crate) fn batch_deadline(batch: &BatchCount, limits: &BatchLimits) -> Option<Time> {
+        Some(batch.created_at?.saturating_add(limits.batch_time_limit?))
+    }
+
+    /// Close the current batch if it has exceeded the batch time limit. Returns `true` if the batch
+    /// was closed, in which case the caller is responsible for replacing it.
+    async fn close_if_expired(&self, curr: &BatchCount, limits: &BatchLimits) -> Result<bool> {
+        match Self::batch_deadline(curr, limits) {
+            Some(deadline) if deadline <= now() => (),
+            _ => return Ok(false),
+        }
+
+        let batch_id_hex = curr.batch_id.to_hex();
+        if curr.report_count >= limits.min_batch_size {
+            debug!("LeaderBatchQueue: closed batch {batch_id_hex} after reaching time limit");
+            return Ok(true);
+        }
+
+        // The batch can never be collected, so remove it from the queue. Otherwise it would block
+        // the batches behind it.
+        let lookup_key = lookup_key(&batch_id_hex);
+        if let Some(lookup_val) = state_get::<String>(&self.state, &lookup_key).await? {
+            self.state.storage().delete(&lookup_val).await?;
+        }
+        self.state.storage().delete(&lookup_key).await?;
+
+        let mut discarded: Vec<BatchCount> =
+            state_get(&self.state, DISCARDED).await?.unwrap_or_default();
+        discarded.push(curr.clone());
+        self.state.storage().put(DISCARDED, &discarded).await?;
+        info!(
+            "LeaderBatchQueue: discarded batch {batch_id_hex} with {} reports after reaching time limit",
+            curr.report_count
+        );
+        Ok(true)
+    }
+
+    /// Schedule an alarm for when the current batch exceeds the batch time limit.
+    async fn alarm_at_deadline(&mut self, curr: &BatchCount, limits: &BatchLimits) -> Result<()> {
+        if let Some(deadline) = Self::batch_deadline(curr, limits) {
+            let offset = deadline.saturating_sub(now());
+            self.ensure_alarmed(std::time::Duration::from_secs(offset))
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 #[durable_object]
@@ -105,12 +230,34 @@ impl DurableObject for LeaderBatchQueue {
             env,
             config,
             touched: false,
+            alarmed: false,
+            schema_version_state: SchemaVersionState::Unchecked,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        let task = durable_task(&req)?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version_for_task(task, BINDING_DAP_LEADER_BATCH_QUEUE)
+            .await?;
+        resp
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        self.alarmed = false;
+        let limits: BatchLimits = state_get(&self.state, LIMITS).await?.unwrap_or_default();
+        if let Some(curr) = state_get::<BatchCount>(&self.state, CURRENT).await? {
+            if self.close_if_expired(&curr, &limits).await? {
+                // Don't create a new batch until there are reports to assign to it.
+                self.state.storage().delete(CURRENT).await?;
+            } else {
+                self.alarm_at_deadline(&curr, &limits).await?;
+            }
+        }
+        Response::from_json(&())
     }
 }
 
@@ -122,7 +269,7 @@ impl LeaderBatchQueue {
         {
             ControlFlow::Continue(req) => req,
             // This req was a GC request and as such we must return from this function.
-            ControlFlow::Break(_) => return Response::from_json(&()),
+            ControlFlow::Break(resp) => return Ok(resp),
         };
 
         match (req.path().as_ref(), req.method()) {
@@ -144,25 +291,31 @@ impl LeaderBatchQueue {
             // Assign the requested number of reports to a sequence of batch IDs. For each batch
             // ID, return the number of reports assigned to the batch.
             //
-            // Input: `(batch_size, num_unassigned): (usize, usize)`
-            // Output: `Vec<BatchCount>`
+            // Input: `BatchAssignRequest`
+            // Output: `BatchAssignResult`
             (DURABLE_LEADER_BATCH_QUEUE_ASSIGN, Method::Post) => {
-                let (batch_size, mut num_unassigned): (usize, usize) = req_parse(&mut req).await?;
+                let BatchAssignRequest {
+                    batch_size,
+                    mut num_unassigned,
+                    limits,
+                } = req_parse(&mut req).await?;
                 if batch_size == 0 {
                     return Err(int_err("LeaderBatchQueue: called with batch_size is 0"));
                 }
+                self.state.storage().put(LIMITS, &limits).await?;
 
                 // Read the batch that is currently being filled from storage, or, if this is the
-                // first time this LeaderBatchQueue instance has been touched, create a new batch.
-                let mut curr = if let Some(curr) = state_get(&self.state, CURRENT).await? {
-                    curr
-                } else {
-                    self.create_batch().await?
+                // first time this LeaderBatchQueue instance has been touched (or the previous
+                // batch was closed), create a new batch.
+                let mut curr = match state_get::<BatchCount>(&self.state, CURRENT).await? {
+                    Some(curr) if !self.close_if_expired(&curr, &limits).await? => curr,
+                    _ => self.create_batch().await?,
                 };
 
                 let mut batch_assignments = vec![BatchCount {
                     batch_id: curr.batch_id.clone(),
                     report_count: 0,
+                    created_at: curr.created_at,
                 }];
 
                 while num_unassigned > 0 {
@@ -182,7 +335,19 @@ impl LeaderBatchQueue {
 
                 // Write the current batch to storage.
                 self.state.storage().put(CURRENT, &curr).await?;
-                Response::from_json(&batch_assignments)
+                self.alarm_at_deadline(&curr, &limits).await?;
+
+                // Report the batches that were discarded since the last call.
+                let discarded: Vec<BatchCount> =
+                    state_get(&self.state, DISCARDED).await?.unwrap_or_default();
+                if !discarded.is_empty() {
+                    self.state.storage().delete(DISCARDED).await?;
+                }
+
+                Response::from_json(&BatchAssignResult {
+                    assignments: batch_assignments,
+                    discarded,
+                })
             }
 
             // Remove the indicated batch (i.e., the hex-encoded batch ID) from the queue. This is
@@ -201,6 +366,49 @@ impl LeaderBatchQueue {
                 Response::from_json(&())
             }
 
+            // Return the IDs of the batches that have not been collected, oldest first, followed
+            // by the batch currently being filled.
+            //
+            // Output: `Vec<BatchId>`
+            (DURABLE_LEADER_BATCH_QUEUE_LIST, Method::Get) => {
+                let mut batch_ids: Vec<BatchId> =
+                    DurableOrdered::<BatchCount>::get_all(&self.state, PENDING_PREFIX)
+                        .await?
+                        .into_iter()
+                        .map(|queued| queued.into_item().batch_id)
+                        .collect();
+                if let Some(curr) = state_get::<BatchCount>(&self.state, CURRENT).await? {
+                    if !batch_ids.contains(&curr.batch_id) {
+                        batch_ids.push(curr.batch_id);
+                    }
+                }
+                Response::from_json(&batch_ids)
+            }
+
+            // Return the state of the indicated batch (i.e., the hex-encoded batch ID). The batch
+            // is ready unless it is the batch currently being filled.
+            //
+            // Input: `batch_id_hex: String`
+            // Output: `Option<DapBatchState>`
+            (DURABLE_LEADER_BATCH_QUEUE_STATE, Method::Post) => {
+                let batch_id_hex: String = req_parse(&mut req).await?;
+                if state_get::<String>(&self.state, &lookup_key(&batch_id_hex))
+                    .await?
+                    .is_none()
+                {
+                    return Response::from_json(&None::<DapBatchState>);
+                }
+
+                let filling = state_get::<BatchCount>(&self.state, CURRENT)
+                    .await?
+                    .is_some_and(|curr| curr.batch_id.to_hex() == batch_id_hex);
+                Response::from_json(&Some(if filling {
+                    DapBatchState::Filling
+                } else {
+                    DapBatchState::Ready
+                }))
+            }
+
             _ => Err(int_err(format!(
                 "LeaderBatchQueue: unexpected request: method={:?}; path={:?}",
                 req.method(),
@@ -226,6 +434,24 @@ impl DapDurableObject for LeaderBatchQueue {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for LeaderBatchQueue {
+    const CLASS: &'static str = "LeaderBatchQueue";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Alarmed for LeaderBatchQueue {
+    #[inline(always)]
+    fn alarmed(&mut self) -> &mut bool {
+        &mut self.alarmed
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl GarbageCollectable for LeaderBatchQueue {
     #[inline(always)]
@@ -237,4 +463,55 @@ impl GarbageCollectable for LeaderBatchQueue {
     fn env(&self) -> &Env {
         &self.env
     }
+    async fn virtual_alarm(&mut self) -> Result<bool> {
+        self.alarm_if_due().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BatchCount, BatchLimits, LeaderBatchQueue};
+    use daphne::messages::BatchId;
+
+    #[test]
+    fn batch_limits_new() {
+        let limits = BatchLimits::new(Some(3600), 10).unwrap();
+        assert_eq!(limits.batch_time_limit, Some(3600));
+        assert_eq!(limits.min_batch_size, 10);
+
+        #[cfg(target_pointer_width = "32")]
+        assert!(BatchLimits::new(None, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn batch_deadline() {
+        let batch = BatchCount {
+            batch_id: BatchId([0; 32]),
+            report_count: 0,
+            created_at: Some(1_000),
+        };
+        let limits = BatchLimits::new(Some(3600), 10).unwrap();
+        assert_eq!(
+            LeaderBatchQueue::batch_deadline(&batch, &limits),
+            Some(4_600)
+        );
+
+        // No deadline without a batch time limit.
+        let no_limit = BatchLimits::new(None, 10).unwrap();
+        assert_eq!(LeaderBatchQueue::batch_deadline(&batch, &no_limit), None);
+
+        // Batches created before batch time limits were supported have no deadline.
+        let legacy = BatchCount {
+            created_at: None,
+            ..batch.clone()
+        };
+        assert_eq!(LeaderBatchQueue::batch_deadline(&legacy, &limits), None);
+
+        // The deadline saturates rather than overflowing.
+        let huge_limit = BatchLimits::new(Some(u64::MAX), 10).unwrap();
+        assert_eq!(
+            LeaderBatchQueue::batch_deadline(&batch, &huge_limit),
+            Some(u64::MAX)
+        );
+    }
 }
@@ -1,29 +1,33 @@
 // Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::ops::ControlFlow;
+use std::{collections::HashMap, ops::ControlFlow};
 
 use crate::{
     config::DaphneWorkerConfig,
     durable::{
         create_span_from_request, state_get, state_get_or_default, DurableOrdered,
-        BINDING_DAP_LEADER_COL_JOB_QUEUE,
+        BINDING_DAP_LEADER_COL_JOB_QUEUE, MAX_KEYS,
     },
-    initialize_tracing, int_err,
+    initialize_tracing, int_err, now,
 };
 use daphne::{
     messages::{Collection, CollectionJobId, CollectionReq, TaskId},
-    DapCollectJob, DapVersion,
+    DapCollectJob, DapCollectJobStatus, DapCollectJobSummary, DapCollectPriority, DapVersion,
 };
 use prio::codec::ParameterizedEncode;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::Instrument;
 use worker::*;
 
-use super::{req_parse, DapDurableObject, GarbageCollectable};
+use super::{req_parse, DapDurableObject, GarbageCollectable, SchemaVersionState, SchemaVersioned};
 
 const PENDING_PREFIX: &str = "pending";
 const PROCESSED_PREFIX: &str = "processed";
+const EXPIRY_PREFIX: &str = "expiry";
+
+/// Maximum number of CollectResps kept in the in-memory cache.
+const MAX_PROCESSED_CACHE_ENTRIES: usize = 32;
 
 pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_PUT: &str = "/internal/do/leader_col_job_queue/put";
 pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_GET: &str = "/internal/do/leader_col_job_queue/get";
@@ -31,6 +35,9 @@ pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_FINISH: &str =
     "/internal/do/leader_col_job_queue/finish";
 pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_GET_RESULT: &str =
     "/internal/do/leader_col_job_queue/get_result";
+pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_LIST: &str = "/internal/do/leader_col_job_queue/list";
+pub(crate) const DURABLE_LEADER_COL_JOB_QUEUE_PURGE: &str =
+    "/internal/do/leader_col_job_queue/purge";
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -38,6 +45,8 @@ pub(crate) struct CollectQueueRequest {
     pub collect_req: CollectionReq,
     pub task_id: TaskId,
     pub collect_job_id: Option<CollectionJobId>,
+    #[serde(default)]
+    pub priority: DapCollectPriority,
 }
 
 /// Durable Object (DO) for storing the Leader's state for a given task.
@@ -49,17 +58,32 @@ pub(crate) struct CollectQueueRequest {
 /// - `DURABLE_LEADER_COL_JOB_QUEUE_FINISH`: Complete a collection job and store the CollectResp.
 /// - `DURABLE_LEADER_COL_JOB_QUEUE_GET_RESULT`: Poll the queue to see if a collect job is
 ///   complete.
+/// - `DURABLE_LEADER_COL_JOB_QUEUE_LIST`: List the pending and completed collection jobs for a
+///   task.
+/// - `DURABLE_LEADER_COL_JOB_QUEUE_PURGE`: Delete the pending and completed collection jobs for a
+///   task.
 ///
 /// The schema for data stored in instances of this DO is as follows:
 ///
 /// ```text
 /// [Pending Lookup ID] pending/id/<collection_job_id> -> String (reference to queue element)
 /// [Pending queue]     pending/next_ordinal -> u64
-/// [Pending queue]     pending/item/order/<order> -> (CollectionJobId, CollectReq)
+/// [Pending queue]     pending/item/priority/<rank>/order/<order> -> (CollectionJobId, CollectReq)
 /// [Processed]         processed/<collection_job_id> -> CollectResp
+/// [Processed expiry]  expiry/<collection_job_id> -> u64 (time after which CollectResp is deleted)
+/// [Schema version]    schema_version -> u32
 /// ```
 ///
-/// Note that the queue ordinal format is inherited from [`DurableOrdered::new_strictly_ordered`].
+/// Completed CollectResps are retained so that the Collector can poll for them repeatedly. If
+/// `DAP_COLLECTION_RESULT_TTL_SECS` is configured, then they are deleted once the TTL has elapsed;
+/// otherwise they are retained for the lifetime of the DO. Up to `MAX_PROCESSED_CACHE_ENTRIES`
+/// retained responses are also cached in memory so that re-polls do not need to read storage.
+///
+/// Pending collection jobs are ordered by priority class (see [`DapCollectPriority`]), then by
+/// arrival, so that urgent collections jump ahead of backfill jobs. Note that the queue ordinal
+/// format is inherited from [`DurableOrdered::new_strictly_ordered_with_priority`]. Jobs queued
+/// before priorities were introduced use the format of [`DurableOrdered::new_strictly_ordered`]
+/// and are handled first.
 //
 // TODO Implement collection job deletion per the DAP-02.
 #[durable_object]
@@ -69,6 +93,8 @@ pub struct LeaderCollectionJobQueue {
     env: Env,
     config: DaphneWorkerConfig,
     touched: bool,
+    processed_cache: HashMap<String, (Collection, Option<u64>)>,
+    schema_version_state: SchemaVersionState,
 }
 
 #[durable_object]
@@ -82,12 +108,17 @@ impl DurableObject for LeaderCollectionJobQueue {
             env,
             config,
             touched: false,
+            processed_cache: HashMap::new(),
+            schema_version_state: SchemaVersionState::Unchecked,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version().await?;
+        resp
     }
 }
 
@@ -99,7 +130,7 @@ impl LeaderCollectionJobQueue {
         {
             ControlFlow::Continue(req) => req,
             // This req was a GC request and as such we must return from this function.
-            ControlFlow::Break(_) => return Response::from_json(&()),
+            ControlFlow::Break(resp) => return Ok(resp),
         };
         match (req.path().as_ref(), req.method()) {
             // Create a collect job for a collect request issued by the Collector.
@@ -140,11 +171,12 @@ impl LeaderCollectionJobQueue {
 
                 // If the the request is new, then put it in the job queue.
                 let pending_key = pending_key(&collect_queue_req.task_id, &collection_job_id);
-                let processed_key = processed_key(&collect_queue_req.task_id, &collection_job_id);
                 let pending: bool = state_get_or_default(&self.state, &pending_key).await?;
-                let processed: Option<Collection> = state_get(&self.state, &processed_key).await?;
+                let processed = self
+                    .get_processed(&collect_queue_req.task_id, &collection_job_id)
+                    .await?;
                 if processed.is_none() && !pending {
-                    let queued = DurableOrdered::new_strictly_ordered(
+                    let queued = DurableOrdered::new_strictly_ordered_with_priority(
                         &self.state,
                         (
                             collect_queue_req.task_id,
@@ -152,6 +184,7 @@ impl LeaderCollectionJobQueue {
                             collect_queue_req.collect_req,
                         ),
                         PENDING_PREFIX,
+                        collect_queue_req.priority as u8,
                     )
                     .await?;
                     queued.put(&self.state).await?;
@@ -163,7 +196,7 @@ impl LeaderCollectionJobQueue {
                 Response::from_json(&collection_job_id.to_hex())
             }
 
-            // Get the list of pending collection jobs (oldest jobs first).
+            // Get the list of pending collection jobs (highest priority first, then oldest first).
             //
             // Output: `Vec<(Id, CollectReq)>`
             (DURABLE_LEADER_COL_JOB_QUEUE_GET, Method::Get) => {
@@ -186,7 +219,7 @@ impl LeaderCollectionJobQueue {
                     Collection,
                 ) = req_parse(&mut req).await?;
                 let processed_key = processed_key(&task_id, &collection_job_id);
-                let processed: Option<Collection> = state_get(&self.state, &processed_key).await?;
+                let processed = self.get_processed(&task_id, &collection_job_id).await?;
                 if processed.is_some() {
                     return Err(int_err(
                         "LeaderCollectionJobQueue: tried to overwrite collect response",
@@ -202,11 +235,22 @@ impl LeaderCollectionJobQueue {
                 let mut storage = self.state.storage();
                 let f = storage.delete(&pending_key);
 
-                // Store the CollectResp.
+                // Store the CollectResp, along with the time after which it may be deleted.
+                let expiry = self
+                    .config
+                    .collection_result_ttl
+                    .map(|ttl| now() + ttl.as_secs());
+                if let Some(expiry) = expiry {
+                    self.state
+                        .storage()
+                        .put(&expiry_key(&task_id, &collection_job_id), expiry)
+                        .await?;
+                }
                 self.state
                     .storage()
-                    .put(&processed_key, collect_resp)
+                    .put(&processed_key, &collect_resp)
                     .await?;
+                self.cache_processed(processed_key, collect_resp, expiry);
 
                 // Remove the lookup key.
                 f.await?;
@@ -224,8 +268,7 @@ impl LeaderCollectionJobQueue {
                 let pending = state_get::<String>(&self.state, &pending_key)
                     .await?
                     .is_some();
-                let processed_key = processed_key(&task_id, &collection_job_id);
-                let processed: Option<Collection> = state_get(&self.state, &processed_key).await?;
+                let processed = self.get_processed(&task_id, &collection_job_id).await?;
                 if let Some(collect_resp) = processed {
                     if pending {
                         self.state.storage().delete(&pending_key).await?;
@@ -238,6 +281,87 @@ impl LeaderCollectionJobQueue {
                 }
             }
 
+            // List the collection jobs for a task that are pending or whose CollectResp is still
+            // retained.
+            //
+            // Input: `task_id: TaskId`
+            // Output: `Vec<DapCollectJobSummary>`
+            (DURABLE_LEADER_COL_JOB_QUEUE_LIST, Method::Post) => {
+                let task_id: TaskId = req_parse(&mut req).await?;
+                let mut summaries = Vec::new();
+                for collection_job_id in self
+                    .list_collection_job_ids::<Collection>(PROCESSED_PREFIX, &task_id)
+                    .await?
+                {
+                    // Skip CollectResps that have expired.
+                    if self
+                        .get_processed(&task_id, &collection_job_id)
+                        .await?
+                        .is_some()
+                    {
+                        summaries.push(DapCollectJobSummary {
+                            collect_job_id: collection_job_id,
+                            status: DapCollectJobStatus::Done,
+                        });
+                    }
+                }
+                for collection_job_id in self
+                    .list_collection_job_ids::<String>(PENDING_PREFIX, &task_id)
+                    .await?
+                {
+                    // A job's pending lookup ID may outlive its completion.
+                    if !summaries
+                        .iter()
+                        .any(|summary| summary.collect_job_id == collection_job_id)
+                    {
+                        summaries.push(DapCollectJobSummary {
+                            collect_job_id: collection_job_id,
+                            status: DapCollectJobStatus::Pending,
+                        });
+                    }
+                }
+                Response::from_json(&summaries)
+            }
+
+            // Delete the collection jobs for a task, whether pending or completed.
+            //
+            // Input: `task_id: TaskId`
+            // Output: `u64` (number of deleted collection jobs)
+            (DURABLE_LEADER_COL_JOB_QUEUE_PURGE, Method::Post) => {
+                let task_id: TaskId = req_parse(&mut req).await?;
+                let mut collection_job_ids = self
+                    .list_collection_job_ids::<Collection>(PROCESSED_PREFIX, &task_id)
+                    .await?;
+                let mut keys = Vec::new();
+                for collection_job_id in &collection_job_ids {
+                    let processed_key = processed_key(&task_id, collection_job_id);
+                    self.processed_cache.remove(&processed_key);
+                    keys.push(processed_key);
+                    keys.push(expiry_key(&task_id, collection_job_id));
+                }
+                for collection_job_id in self
+                    .list_collection_job_ids::<String>(PENDING_PREFIX, &task_id)
+                    .await?
+                {
+                    // Remove the collection job from the pending queue.
+                    let pending_key = pending_key(&task_id, &collection_job_id);
+                    if let Some(lookup_val) = state_get::<String>(&self.state, &pending_key).await?
+                    {
+                        keys.push(lookup_val);
+                    }
+                    keys.push(pending_key);
+                    if !collection_job_ids.contains(&collection_job_id) {
+                        collection_job_ids.push(collection_job_id);
+                    }
+                }
+
+                // Storage limits the number of keys that can be deleted at once.
+                for chunk in keys.chunks(MAX_KEYS) {
+                    self.state.storage().delete_multiple(chunk.to_vec()).await?;
+                }
+                Response::from_json(&u64::try_from(collection_job_ids.len()).unwrap())
+            }
+
             _ => Err(int_err(format!(
                 "LeaderCollectionJobQueue: unexpected request: method={:?}; path={:?}",
                 req.method(),
@@ -245,6 +369,89 @@ impl LeaderCollectionJobQueue {
             ))),
         }
     }
+
+    /// Look up the CollectResp of a completed collection job, first in the in-memory cache, then
+    /// in storage. If the response has expired, it is deleted and `None` is returned.
+    async fn get_processed(
+        &mut self,
+        task_id: &TaskId,
+        collection_job_id: &CollectionJobId,
+    ) -> Result<Option<Collection>> {
+        let processed_key = processed_key(task_id, collection_job_id);
+        let expiry_key = expiry_key(task_id, collection_job_id);
+        let (collect_resp, expiry) = match self.processed_cache.get(&processed_key) {
+            Some(cached) => cached.clone(),
+            None => {
+                let Some(collect_resp) =
+                    state_get::<Collection>(&self.state, &processed_key).await?
+                else {
+                    return Ok(None);
+                };
+                let expiry = state_get::<u64>(&self.state, &expiry_key).await?;
+                self.cache_processed(processed_key.clone(), collect_resp.clone(), expiry);
+                (collect_resp, expiry)
+            }
+        };
+
+        match expiry {
+            Some(expiry) if now() >= expiry => {
+                self.processed_cache.remove(&processed_key);
+                self.state
+                    .storage()
+                    .delete_multiple(vec![processed_key, expiry_key])
+                    .await?;
+                Ok(None)
+            }
+            _ => Ok(Some(collect_resp)),
+        }
+    }
+
+    /// Add a CollectResp to the in-memory cache. If the cache is full, then an arbitrary entry is
+    /// evicted to make room for it.
+    fn cache_processed(
+        &mut self,
+        processed_key: String,
+        collect_resp: Collection,
+        expiry: Option<u64>,
+    ) {
+        if self.processed_cache.len() >= MAX_PROCESSED_CACHE_ENTRIES
+            && !self.processed_cache.contains_key(&processed_key)
+        {
+            if let Some(evicted_key) = self.processed_cache.keys().next().cloned() {
+                self.processed_cache.remove(&evicted_key);
+            }
+        }
+        self.processed_cache
+            .insert(processed_key, (collect_resp, expiry));
+    }
+
+    /// List the IDs of the collection jobs for a task that have a key with the given prefix.
+    async fn list_collection_job_ids<T: DeserializeOwned>(
+        &self,
+        prefix: &str,
+        task_id: &TaskId,
+    ) -> Result<Vec<CollectionJobId>> {
+        let key_prefix = format!("{prefix}/tasks/{}/collection_jobs/", task_id.to_base64url());
+        let iter = self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix(&key_prefix))
+            .await?
+            .entries();
+        let mut js_item = iter.next()?;
+        let mut res = Vec::new();
+        while !js_item.done() {
+            let (key, _item): (String, T) =
+                serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
+            let collection_job_id = key
+                .strip_prefix(&key_prefix)
+                .and_then(CollectionJobId::try_from_base64url)
+                .ok_or_else(|| int_err("collection job key is improperly formatted"))?;
+            res.push(collection_job_id);
+            js_item = iter.next()?;
+        }
+        Ok(res)
+    }
 }
 
 fn pending_key(task_id: &TaskId, collection_job_id: &CollectionJobId) -> String {
@@ -263,6 +470,14 @@ fn processed_key(task_id: &TaskId, collection_job_id: &CollectionJobId) -> Strin
     )
 }
 
+fn expiry_key(task_id: &TaskId, collection_job_id: &CollectionJobId) -> String {
+    format!(
+        "{EXPIRY_PREFIX}/tasks/{}/collection_jobs/{}",
+        task_id.to_base64url(),
+        collection_job_id.to_base64url()
+    )
+}
+
 impl DapDurableObject for LeaderCollectionJobQueue {
     #[inline(always)]
     fn state(&self) -> &State {
@@ -275,6 +490,16 @@ impl DapDurableObject for LeaderCollectionJobQueue {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for LeaderCollectionJobQueue {
+    const CLASS: &'static str = "LeaderCollectionJobQueue";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl GarbageCollectable for LeaderCollectionJobQueue {
     #[inline(always)]
@@ -2,26 +2,44 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 pub(crate) mod aggregate_store;
+pub(crate) mod command_nonce_store;
 pub(crate) mod garbage_collector;
 pub(crate) mod helper_state_store;
 pub(crate) mod leader_agg_job_queue;
 pub(crate) mod leader_batch_queue;
 pub(crate) mod leader_col_job_queue;
+pub(crate) mod rate_limiter;
 pub(crate) mod reports_pending;
 pub(crate) mod reports_processed;
 
 use crate::{
-    int_err, now,
+    int_err,
+    metrics::DaphneWorkerMetrics,
+    now,
     tracing_utils::{shorten_paths, DaphneSubscriber, JsonFields},
+    virtual_clock,
+};
+use daphne::{
+    messages::{ReportId, TaskId, Time},
+    DapBatchBucket, DapTaskConfig, DapVersion, MetaAggregationJobId,
 };
-use daphne::{messages::TaskId, DapBatchBucket, DapVersion};
 use rand::prelude::*;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{cmp::min, time::Duration};
-use tracing::{info_span, warn};
+use tracing::{error, info, info_span, warn};
 use worker::{js_sys::Uint8Array, *};
 
 pub(crate) const DURABLE_DELETE_ALL: &str = "/internal/do/delete_all";
+pub(crate) const DURABLE_PURGE: &str = "/internal/do/purge";
+pub(crate) const DURABLE_ALARM_IF_DUE: &str = "/internal/do/alarm_if_due";
+
+/// Storage key under which a DO instance records the deadline of its alarm according to the
+/// [virtual clock](crate::virtual_clock). This is only written in the "dev" deployment.
+const VIRTUAL_ALARM_KEY: &str = "virtual_alarm";
+
+/// Header carrying the name of the DO instance to which a request is addressed, if the instance
+/// is addressed by name. DO instances can't otherwise learn their own names.
+const DURABLE_NAME_HEADER: &str = "x-daphne-durable-name";
 
 pub(crate) const BINDING_DAP_REPORTS_PENDING: &str = "DAP_REPORTS_PENDING";
 pub(crate) const BINDING_DAP_REPORTS_PROCESSED: &str = "DAP_REPORTS_PROCESSED";
@@ -31,6 +49,8 @@ pub(crate) const BINDING_DAP_LEADER_BATCH_QUEUE: &str = "DAP_LEADER_BATCH_QUEUE"
 pub(crate) const BINDING_DAP_LEADER_COL_JOB_QUEUE: &str = "DAP_LEADER_COL_JOB_QUEUE";
 pub(crate) const BINDING_DAP_HELPER_STATE_STORE: &str = "DAP_HELPER_STATE_STORE";
 pub(crate) const BINDING_DAP_GARBAGE_COLLECTOR: &str = "DAP_GARBAGE_COLLECTOR";
+pub(crate) const BINDING_DAP_RATE_LIMITER: &str = "DAP_RATE_LIMITER";
+pub(crate) const BINDING_DAP_COMMAND_NONCE_STORE: &str = "DAP_COMMAND_NONCE_STORE";
 
 const ERR_NO_VALUE: &str = "No such value in storage.";
 
@@ -48,30 +68,86 @@ const ERR_NO_VALUE: &str = "No such value in storage.";
 // TODO(bhalley) does this need to be configurable?
 const MAX_KEYS: usize = 128;
 
-const RETRY_DELAYS: &[Duration] = &[
-    Duration::from_millis(100),
-    Duration::from_millis(500),
-    Duration::from_millis(1_000),
-    Duration::from_millis(3_000),
-];
+/// How a [`DurableConnector`] retries failed requests. Configured by `durable.retry` in the
+/// configuration document.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DurableRetryPolicy {
+    /// Number of times a failed request is retried. If 0, then requests are not retried.
+    pub(crate) max_retries: usize,
+
+    /// Delay before the first retry, in milliseconds. The delay doubles with each retry after
+    /// that.
+    pub(crate) base_delay_ms: u64,
+
+    /// Upper bound on the random jitter, in milliseconds, added to each delay.
+    pub(crate) max_jitter_ms: u64,
+}
+
+impl Default for DurableRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay_ms: 250,
+            max_jitter_ms: 100,
+        }
+    }
+}
+
+impl DurableRetryPolicy {
+    /// Return how long to wait before the given retry (numbered from 1).
+    fn delay(&self, retry: usize, rng: &mut impl Rng) -> Duration {
+        let backoff = u32::try_from(retry.saturating_sub(1))
+            .ok()
+            .and_then(|exp| 2_u64.checked_pow(exp))
+            .unwrap_or(u64::MAX);
+        let jitter = rng.gen_range(0..=self.max_jitter_ms);
+        Duration::from_millis(
+            self.base_delay_ms
+                .saturating_mul(backoff)
+                .saturating_add(jitter),
+        )
+    }
+}
 
 /// Used to send HTTP requests to a durable object (DO) instance.
 pub(crate) struct DurableConnector<'srv> {
     env: &'srv Env,
-    retry: bool,
+    retry_policy: Option<&'srv DurableRetryPolicy>,
+    metrics: Option<(&'srv DaphneWorkerMetrics, &'srv str)>,
 }
 
 impl<'srv> DurableConnector<'srv> {
     pub(crate) fn new(env: &'srv Env) -> Self {
-        DurableConnector { env, retry: false }
+        DurableConnector {
+            env,
+            retry_policy: None,
+            metrics: None,
+        }
+    }
+
+    /// Configure the connector to retry failed requests according to the given policy.
+    pub(crate) fn with_retry(self, retry_policy: &'srv DurableRetryPolicy) -> Self {
+        Self {
+            retry_policy: Some(retry_policy),
+            ..self
+        }
+    }
+
+    /// Configure the connector not to retry failed requests. This method must be used for
+    /// requests that are not idempotent.
+    pub(crate) fn without_retry(self) -> Self {
+        Self {
+            retry_policy: None,
+            ..self
+        }
     }
 
-    /// Configure the connector to retry requests a few times on failure. This method should only
-    /// be used for idempotent requests.
-    pub(crate) fn with_retry(self) -> Self {
+    /// Configure the connector to record the latency and failures of each request attempt.
+    pub(crate) fn with_metrics(self, metrics: &'srv DaphneWorkerMetrics, host: &'srv str) -> Self {
         Self {
-            env: self.env,
-            retry: true,
+            metrics: Some((metrics, host)),
+            ..self
         }
     }
 
@@ -87,6 +163,7 @@ impl<'srv> DurableConnector<'srv> {
         let stub = namespace.id_from_name(&durable_name)?.get_stub()?;
         self.durable_request(
             stub,
+            Some(&durable_name),
             durable_binding,
             durable_path,
             Method::Get,
@@ -137,6 +214,7 @@ impl<'srv> DurableConnector<'srv> {
         let stub = namespace.id_from_name(&durable_name)?.get_stub()?;
         self.durable_request(
             stub,
+            Some(&durable_name),
             durable_binding,
             durable_path,
             Method::Post,
@@ -165,6 +243,7 @@ impl<'srv> DurableConnector<'srv> {
         let stub = namespace.id_from_string(&durable_id_hex)?.get_stub()?;
         self.durable_request(
             stub,
+            None,
             durable_binding,
             durable_path,
             Method::Post,
@@ -182,6 +261,7 @@ impl<'srv> DurableConnector<'srv> {
     async fn durable_request<I, O1, O2, H>(
         &self,
         durable_stub: Stub,
+        durable_name: Option<&str>,
         durable_binding: &str,
         durable_path: &'static str,
         method: Method,
@@ -193,13 +273,16 @@ impl<'srv> DurableConnector<'srv> {
         O1: for<'a> Deserialize<'a>,
         H: FnOnce(O1, bool) -> O2 + Sized,
     {
-        let attempts = if self.retry {
-            RETRY_DELAYS.len() + 1
-        } else {
-            1
-        };
-
-        let tracing_headers = span_to_headers();
+        let attempts = self
+            .retry_policy
+            .map_or(0, |retry_policy| retry_policy.max_retries)
+            .saturating_add(1);
+
+        let mut tracing_headers = span_to_headers();
+        if let Some(durable_name) = durable_name {
+            tracing_headers.set(DURABLE_NAME_HEADER, durable_name)?;
+        }
+        virtual_clock::set_header(&mut tracing_headers)?;
 
         let mut attempt = 1;
         loop {
@@ -231,26 +314,71 @@ impl<'srv> DurableConnector<'srv> {
                 }
             };
 
+            let start = Date::now().as_millis();
             match durable_stub.fetch_with_request(req).await {
-                Ok(mut resp) => return Ok(handler(resp.json().await?, attempt > 1)),
+                Ok(mut resp) => {
+                    let output = resp.json().await;
+                    self.observe(
+                        durable_binding,
+                        durable_path,
+                        attempt,
+                        start,
+                        output.is_ok(),
+                    );
+                    return Ok(handler(output?, attempt > 1));
+                }
                 Err(err) => {
-                    if attempt < attempts {
-                        warn!("DO {durable_binding}: post {durable_path}: attempt #{attempt} failed: {err}");
-                        Delay::from(RETRY_DELAYS[attempt - 1]).await;
-                        attempt += 1;
-                    } else {
-                        return Err(err);
+                    self.observe(durable_binding, durable_path, attempt, start, false);
+                    match self.retry_policy {
+                        Some(retry_policy) if attempt < attempts => {
+                            warn!("DO {durable_binding}: post {durable_path}: attempt #{attempt} failed: {err}");
+                            Delay::from(retry_policy.delay(attempt, &mut thread_rng())).await;
+                            attempt += 1;
+                        }
+                        _ => return Err(err),
                     }
                 }
             }
         }
     }
+
+    /// Record an attempt that started at `start` (in milliseconds), if metrics are configured.
+    fn observe(
+        &self,
+        durable_binding: &str,
+        durable_path: &str,
+        attempt: usize,
+        start: u64,
+        ok: bool,
+    ) {
+        if let Some((metrics, host)) = self.metrics {
+            metrics.observe_durable_request(
+                host,
+                durable_binding,
+                durable_path,
+                attempt > 1,
+                Date::now().as_millis().saturating_sub(start),
+                ok,
+            );
+        }
+    }
 }
 
 trait DapDurableObject {
     fn state(&self) -> &State;
 
     fn deployment(&self) -> crate::config::DaphneWorkerDeployment;
+
+    /// In the "dev" deployment, adopt the virtual clock of the sender of the request.
+    fn observe_virtual_clock(&self, req: &Request) -> Result<()> {
+        if matches!(
+            self.deployment(),
+            crate::config::DaphneWorkerDeployment::Dev
+        ) {
+            virtual_clock::observe_header(&req.headers())?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait(?Send)]
@@ -259,8 +387,28 @@ trait Alarmed: DapDurableObject {
     fn alarmed(&mut self) -> &mut bool;
 
     /// Ensure the alarm call is setup for this DO.
-    async fn ensure_alarmed<L: Into<ScheduledTime>>(&mut self, lifetime: L) -> Result<()> {
+    ///
+    /// In the "dev" deployment, the deadline of the alarm is also recorded according to the
+    /// virtual clock, so that tests can run the alarm handler without waiting for the runtime.
+    async fn ensure_alarmed(&mut self, lifetime: Duration) -> Result<()> {
         if !*self.alarmed() {
+            if matches!(
+                self.deployment(),
+                crate::config::DaphneWorkerDeployment::Dev
+            ) {
+                // Replace the recorded deadline if the alarm has gone off since it was recorded.
+                let now = now();
+                match state_get::<Time>(self.state(), VIRTUAL_ALARM_KEY).await? {
+                    Some(deadline) if deadline > now => (),
+                    _ => {
+                        self.state()
+                            .storage()
+                            .put(VIRTUAL_ALARM_KEY, now.saturating_add(lifetime.as_secs()))
+                            .await?;
+                    }
+                }
+            }
+
             let result = self.state().storage().get_alarm().await;
             match result {
                 Ok(None) => {
@@ -286,30 +434,332 @@ trait Alarmed: DapDurableObject {
         }
         Ok(())
     }
+
+    /// Run the alarm handler if the deadline recorded by [`Self::ensure_alarmed`] has passed
+    /// according to the virtual clock. Returns `true` if the handler was run.
+    async fn alarm_if_due(&mut self) -> Result<bool>
+    where
+        Self: DurableObject,
+    {
+        match state_get::<Time>(self.state(), VIRTUAL_ALARM_KEY).await? {
+            Some(deadline) if deadline <= now() => {
+                self.state().storage().delete(VIRTUAL_ALARM_KEY).await?;
+                DurableObject::alarm(self).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Storage key under which a DO instance records the version of its storage schema.
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Schema version of data written before DO instances recorded their schema version.
+const SCHEMA_VERSION_INITIAL: u32 = 1;
+
+/// A migration of the storage of a DO instance from schema version `from` to `from + 1`.
+pub(crate) struct SchemaMigration {
+    pub(crate) from: u32,
+    pub(crate) migrate: for<'a> fn(&'a State) -> futures::future::LocalBoxFuture<'a, Result<()>>,
+}
+
+/// Whether a DO instance has checked and recorded its schema version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum SchemaVersionState {
+    /// The schema version has not been checked since the instance was loaded.
+    #[default]
+    Unchecked,
+
+    /// The storage was empty when checked. The schema version is recorded on the first write.
+    Unrecorded,
+
+    /// The schema version is recorded and matches [`SchemaVersioned::SCHEMA_VERSION`].
+    Recorded,
+}
+
+/// Return the migrations needed to bring storage of the given schema version up to `current`, in
+/// the order in which they are to be run.
+fn schema_migrations_for<'a>(
+    class: &str,
+    stored: u32,
+    current: u32,
+    migrations: &'a [SchemaMigration],
+) -> Result<Vec<&'a SchemaMigration>> {
+    if stored > current {
+        return Err(Error::RustError(format!(
+            "{class}: storage has schema version {stored}, but this deployment only supports \
+            versions up to {current}; refusing to load data written by a newer deployment"
+        )));
+    }
+    (stored..current)
+        .map(|from| {
+            migrations
+                .iter()
+                .find(|migration| migration.from == from)
+                .ok_or_else(|| {
+                    Error::RustError(format!(
+                        "{class}: storage has schema version {stored}, but no migration is \
+                        registered from version {from} to {}",
+                        from + 1
+                    ))
+                })
+        })
+        .collect()
+}
+
+/// A DO class whose storage is tagged with the version of its schema. Each instance records the
+/// version upon its first write and checks it when it is loaded, so that a deployment that changes
+/// the format of stored data fails fast instead of mis-decoding data written by another one.
+///
+/// When changing the format of stored data, bump [`Self::SCHEMA_VERSION`] and register a
+/// migration from the previous version in [`Self::SCHEMA_MIGRATIONS`].
+#[async_trait::async_trait(?Send)]
+trait SchemaVersioned: DapDurableObject {
+    /// Name of the DO class, used in error messages.
+    const CLASS: &'static str;
+
+    /// The schema version written by this deployment.
+    const SCHEMA_VERSION: u32 = SCHEMA_VERSION_INITIAL;
+
+    /// Migrations from previous schema versions.
+    const SCHEMA_MIGRATIONS: &'static [SchemaMigration] = &[];
+
+    /// A mutable property used to track whether the schema version has been checked.
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState;
+
+    /// Check the schema version of the storage, running migrations if the storage was written with
+    /// an older schema. This is called before handling each request.
+    async fn ensure_schema_version(&mut self) -> Result<()> {
+        if *self.schema_version_state() != SchemaVersionState::Unchecked {
+            return Ok(());
+        }
+
+        let (stored, recorded) = match state_get(self.state(), SCHEMA_VERSION_KEY).await? {
+            Some(stored) => (stored, true),
+            None if storage_is_empty(self.state()).await? => {
+                *self.schema_version_state() = SchemaVersionState::Unrecorded;
+                return Ok(());
+            }
+            // The data was written before the schema version was recorded.
+            None => (SCHEMA_VERSION_INITIAL, false),
+        };
+
+        let migrations = schema_migrations_for(
+            Self::CLASS,
+            stored,
+            Self::SCHEMA_VERSION,
+            Self::SCHEMA_MIGRATIONS,
+        )
+        .map_err(|e| {
+            error!("{e}");
+            e
+        })?;
+        for migration in migrations {
+            (migration.migrate)(self.state()).await?;
+            self.state()
+                .storage()
+                .put(SCHEMA_VERSION_KEY, migration.from + 1)
+                .await?;
+            info!(
+                "{}: migrated instance {} to schema version {}",
+                Self::CLASS,
+                self.state().id().to_string(),
+                migration.from + 1
+            );
+        }
+        if !recorded && stored == Self::SCHEMA_VERSION {
+            self.state()
+                .storage()
+                .put(SCHEMA_VERSION_KEY, stored)
+                .await?;
+        }
+        *self.schema_version_state() = SchemaVersionState::Recorded;
+        Ok(())
+    }
+
+    /// Record the schema version if the instance has written anything to storage since it was
+    /// found empty. This is called after handling each request.
+    async fn record_schema_version(&mut self) -> Result<()> {
+        if *self.schema_version_state() == SchemaVersionState::Unrecorded
+            && !storage_is_empty(self.state()).await?
+        {
+            self.state()
+                .storage()
+                .put(SCHEMA_VERSION_KEY, Self::SCHEMA_VERSION)
+                .await?;
+            *self.schema_version_state() = SchemaVersionState::Recorded;
+        }
+        Ok(())
+    }
+}
+
+/// Delete the keys with the given prefix, one page of `MAX_KEYS` keys at a time. Returns the
+/// number of deleted keys.
+async fn delete_prefix(state: &State, prefix: &str) -> Result<u64> {
+    let mut count = 0;
+    loop {
+        let opt = ListOptions::new().prefix(prefix).limit(MAX_KEYS);
+        let iter = state.storage().list_with_options(opt).await?.keys();
+        let mut item = iter.next()?;
+        let mut keys = Vec::new();
+        while !item.done() {
+            let key: String = serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
+            keys.push(key);
+            item = iter.next()?;
+        }
+        let listed = keys.len();
+        if listed > 0 {
+            state.storage().delete_multiple(keys).await?;
+            count += u64::try_from(listed).unwrap();
+        }
+        if listed < MAX_KEYS {
+            return Ok(count);
+        }
+    }
+}
+
+/// Return the DAP version and ID of the task the DO instance a request is addressed to pertains
+/// to, if any.
+fn durable_task(req: &Request) -> Result<Option<(DapVersion, TaskId)>> {
+    Ok(req
+        .headers()
+        .get(DURABLE_NAME_HEADER)?
+        .and_then(|name| StorageKey::parse(StorageKeySchema::CURRENT, &name))
+        .and_then(|key| {
+            key.task()
+                .map(|(version, task_id)| (version, task_id.clone()))
+        }))
+}
+
+async fn storage_is_empty(state: &State) -> Result<bool> {
+    let opt = ListOptions::new().limit(1);
+    Ok(state.storage().list_with_options(opt).await?.size() == 0)
 }
 
 #[async_trait::async_trait(?Send)]
-trait GarbageCollectable: DapDurableObject {
+trait GarbageCollectable: SchemaVersioned {
     /// A mutable property used to track whether this DO has been touched.
     fn touched(&mut self) -> &mut bool;
 
     fn env(&self) -> &Env;
 
+    /// Handle `DURABLE_ALARM_IF_DUE`. DO classes that use alarms run their alarm handler if it is
+    /// due (see [`Alarmed::alarm_if_due`]); the others have nothing to do.
+    async fn virtual_alarm(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Drop any state cached in memory after the storage of this instance was deleted.
+    fn forget_cached_state(&mut self) {}
+
+    /// Record the schema version (see [`SchemaVersioned::record_schema_version`]). In the "dev"
+    /// deployment, if the instance pertains to a task and has just begun to hold data, then also
+    /// register it with the task's registry, so that tests can purge the data of instances whose
+    /// names cannot be derived from the task. This is called after handling each request by the
+    /// classes that hold the data of a task; `task` is the task of the instance the request was
+    /// addressed to (see [`durable_task`]).
+    async fn record_schema_version_for_task(
+        &mut self,
+        task: Option<(DapVersion, TaskId)>,
+        binding: &'static str,
+    ) -> Result<()> {
+        let unrecorded = *self.schema_version_state() == SchemaVersionState::Unrecorded;
+        self.record_schema_version().await?;
+        if !unrecorded || *self.schema_version_state() != SchemaVersionState::Recorded {
+            return Ok(());
+        }
+        if !matches!(
+            self.deployment(),
+            crate::config::DaphneWorkerDeployment::Dev
+        ) {
+            return Ok(());
+        }
+        let Some((version, task_id)) = task else {
+            return Ok(());
+        };
+        let durable = DurableConnector::new(self.env());
+        durable
+            .post(
+                BINDING_DAP_GARBAGE_COLLECTOR,
+                garbage_collector::DURABLE_GARBAGE_COLLECTOR_PUT,
+                durable_name_task(&version, &task_id),
+                &DurableReference {
+                    binding: binding.to_string(),
+                    id_hex: self.state().id().to_string(),
+                    task_id: Some(task_id),
+                    version: Some(version),
+                },
+            )
+            .await
+    }
+
+    /// In the "dev" deployment, remove this instance from the garbage collector's registries. This
+    /// is called by alarm handlers that delete all of the instance's storage, so that the
+    /// registries only refer to instances that hold data.
+    async fn unregister(&self, binding: &'static str) -> Result<()> {
+        if !matches!(
+            self.deployment(),
+            crate::config::DaphneWorkerDeployment::Dev
+        ) {
+            return Ok(());
+        }
+        let durable = DurableConnector::new(self.env());
+        durable
+            .post(
+                BINDING_DAP_GARBAGE_COLLECTOR,
+                garbage_collector::DURABLE_GARBAGE_COLLECTOR_FORGET,
+                StorageKey::GarbageCollector.to_string(),
+                &DurableReference {
+                    binding: binding.to_string(),
+                    id_hex: self.state().id().to_string(),
+                    task_id: None,
+                    version: None,
+                },
+            )
+            .await
+    }
+
     /// Run garbage collection requests.
     ///
     /// If a garbage collection request is handled no further processing needs to be done, as such,
-    /// this function will consume the request, otherwise it will return the passed in request for
-    /// further handling.
+    /// this function will consume the request and return the response, otherwise it will return
+    /// the passed in request for further handling.
     async fn schedule_for_garbage_collection(
         &mut self,
-        req: Request,
+        mut req: Request,
         binding: &'static str,
-    ) -> Result<std::ops::ControlFlow<(), Request>> {
+    ) -> Result<std::ops::ControlFlow<Response, Request>> {
+        self.observe_virtual_clock(&req)?;
         match (req.path().as_str(), req.method()) {
+            // Run the alarm handler if it is due according to the virtual clock.
+            //
+            // Output: `bool` (whether the alarm handler was run)
+            (DURABLE_ALARM_IF_DUE, Method::Post) => {
+                let fired = self.virtual_alarm().await?;
+                return Ok(std::ops::ControlFlow::Break(Response::from_json(&fired)?));
+            }
             (DURABLE_DELETE_ALL, Method::Post) => {
                 self.state().storage().delete_all().await?;
+                self.forget_cached_state();
                 *self.touched() = false;
-                return Ok(std::ops::ControlFlow::Break(()));
+                *self.schema_version_state() = SchemaVersionState::Unrecorded;
+                return Ok(std::ops::ControlFlow::Break(Response::from_json(&())?));
+            }
+            // Delete the keys with the given prefix. Used to purge the data of a task. If the
+            // prefix is empty, then the instance is left empty.
+            //
+            // Input: `prefix: String`
+            // Output: `u64` (number of deleted keys)
+            (DURABLE_PURGE, Method::Post) => {
+                let prefix: String = req_parse(&mut req).await?;
+                let count = delete_prefix(self.state(), &prefix).await?;
+                self.forget_cached_state();
+                if prefix.is_empty() {
+                    *self.touched() = false;
+                    *self.schema_version_state() = SchemaVersionState::Unrecorded;
+                }
+                return Ok(std::ops::ControlFlow::Break(Response::from_json(&count)?));
             }
             _ if !*self.touched() => {
                 // The GarbageCollector should only be used when running tests. In production, the DO->DO
@@ -323,16 +773,18 @@ trait GarbageCollectable: DapDurableObject {
                         .await?
                         .unwrap_or(false);
                     if !touched {
+                        let task = durable_task(&req)?;
                         let durable = crate::durable::DurableConnector::new(self.env());
                         durable
                             .post(
                                 crate::durable::BINDING_DAP_GARBAGE_COLLECTOR,
                                 crate::durable::garbage_collector::DURABLE_GARBAGE_COLLECTOR_PUT,
-                                "garbage_collector".to_string(),
+                                StorageKey::GarbageCollector.to_string(),
                                 &crate::durable::DurableReference {
                                     binding: binding.to_string(),
                                     id_hex: self.state().id().to_string(),
-                                    task_id: None,
+                                    task_id: task.as_ref().map(|(_, task_id)| task_id.clone()),
+                                    version: task.map(|(version, _)| version),
                                 },
                             )
                             .await?;
@@ -390,49 +842,392 @@ pub(crate) async fn state_set_if_not_exists<T: for<'a> Deserialize<'a> + Seriali
     Ok(None)
 }
 
+/// Estimated storage usage of a DO instance. The estimate covers the key/value pairs that grow
+/// with load (e.g., pending reports). The size of a pair is approximated by the length of the key
+/// plus the length of the JSON-serialized value.
+///
+/// The estimate is returned along with the responses of the instances that track it and recorded
+/// in metrics, so that operators can see instances approaching the platform's limits before they
+/// are throttled.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub(crate) struct DurableStorageStats {
+    /// Number of tracked keys.
+    pub(crate) keys: u64,
+
+    /// Estimated number of bytes used by the tracked key/value pairs.
+    pub(crate) bytes: u64,
+}
+
+impl DurableStorageStats {
+    /// Estimate the number of bytes used to store the given key/value pair.
+    pub(crate) fn entry_size<T: Serialize>(key: &str, val: &T) -> u64 {
+        let val_len = serde_json::to_vec(val).map_or(0, |val| val.len());
+        u64::try_from(key.len() + val_len).unwrap()
+    }
+
+    /// Account for a key/value pair of the given size being added to storage.
+    pub(crate) fn add(&mut self, size: u64) {
+        self.keys += 1;
+        self.bytes += size;
+    }
+
+    /// Account for a key/value pair of the given size being removed from storage.
+    pub(crate) fn remove(&mut self, size: u64) {
+        self.keys = self.keys.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(size);
+    }
+
+    /// Return the component-wise maximum of two estimates.
+    pub(crate) fn max(self, other: Self) -> Self {
+        Self {
+            keys: self.keys.max(other.keys),
+            bytes: self.bytes.max(other.bytes),
+        }
+    }
+}
+
+/// Layout of the names of DO instances. The storage of a DO instance is addressed by its name, so
+/// renaming an instance orphans its storage. The layout can therefore only be changed by adding a
+/// new schema; a migration translates the name of each instance by parsing it with the old schema
+/// and rendering it with the new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StorageKeySchema {
+    /// Slash-separated paths, e.g., "v07/task/<task ID>/window/<batch window>".
+    V1,
+}
+
+impl StorageKeySchema {
+    /// The schema used to name new instances.
+    pub(crate) const CURRENT: Self = Self::V1;
+}
+
+/// The name of a DO instance.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum StorageKey {
+    /// The singleton instance of a queue, e.g.,
+    /// [`leader_agg_job_queue::LeaderAggregationJobQueue`].
+    Queue { shard: u64 },
+
+    /// An instance that pertains to a whole task, e.g.,
+    /// [`leader_batch_queue::LeaderBatchQueue`].
+    Task {
+        version: DapVersion,
+        task_id: TaskId,
+    },
+
+    /// A [`reports_pending::ReportsPending`] or [`reports_processed::ReportsProcessed`] instance.
+    ReportStore {
+        version: DapVersion,
+        task_id: TaskId,
+        epoch: u64,
+        shard: u64,
+    },
+
+    /// An [`aggregate_store::AggregateStore`] instance.
+    AggStore {
+        version: DapVersion,
+        task_id: TaskId,
+        bucket: DapBatchBucket,
+    },
+
+    /// A [`helper_state_store::HelperStateStore`] instance.
+    HelperState {
+        version: DapVersion,
+        task_id: TaskId,
+        agg_job_id: MetaAggregationJobId<'static>,
+    },
+
+    /// The singleton [`garbage_collector::GarbageCollector`] instance.
+    GarbageCollector,
+
+    /// A [`rate_limiter::RateLimiter`] instance, identified by the SHA-256 hash of the peer's
+    /// identity.
+    RateLimiter { peer_hash: [u8; 32] },
+
+    /// The singleton [`command_nonce_store::CommandNonceStore`] instance.
+    CommandNonceStore,
+}
+
+impl StorageKey {
+    /// Return the DAP version and task ID of the instance, if it pertains to a task.
+    pub(crate) fn task(&self) -> Option<(DapVersion, &TaskId)> {
+        match self {
+            Self::Task { version, task_id }
+            | Self::ReportStore {
+                version, task_id, ..
+            }
+            | Self::AggStore {
+                version, task_id, ..
+            }
+            | Self::HelperState {
+                version, task_id, ..
+            } => Some((*version, task_id)),
+            Self::Queue { .. }
+            | Self::GarbageCollector
+            | Self::RateLimiter { .. }
+            | Self::CommandNonceStore => None,
+        }
+    }
+
+    /// Render the name using the given schema.
+    pub(crate) fn render(&self, schema: StorageKeySchema) -> String {
+        match schema {
+            StorageKeySchema::V1 => match self {
+                Self::Queue { shard } => format!("queue/{shard}"),
+                Self::Task { version, task_id } => {
+                    format!("{}/task/{}", version.as_ref(), task_id.to_hex())
+                }
+                Self::ReportStore {
+                    version,
+                    task_id,
+                    epoch,
+                    shard,
+                } => format!(
+                    "{}/task/{}/epoch/{:020}/shard/{}",
+                    version.as_ref(),
+                    task_id.to_hex(),
+                    epoch,
+                    shard
+                ),
+                Self::AggStore {
+                    version,
+                    task_id,
+                    bucket,
+                } => format!("{}/task/{}/{}", version.as_ref(), task_id.to_hex(), bucket),
+                Self::HelperState {
+                    version,
+                    task_id,
+                    agg_job_id,
+                } => format!(
+                    "{}/task/{}/agg_job/{}",
+                    version.as_ref(),
+                    task_id.to_hex(),
+                    agg_job_id.to_hex()
+                ),
+                Self::GarbageCollector => "garbage_collector".to_string(),
+                Self::RateLimiter { peer_hash } => {
+                    format!("rate_limiter/{}", hex::encode(peer_hash))
+                }
+                Self::CommandNonceStore => "command_nonce_store".to_string(),
+            },
+        }
+    }
+
+    /// Parse a name rendered with the given schema. The return value is `None` if the name is
+    /// malformed.
+    pub(crate) fn parse(schema: StorageKeySchema, name: &str) -> Option<Self> {
+        match schema {
+            StorageKeySchema::V1 => {
+                let parts = name.split('/').collect::<Vec<_>>();
+                match parts.as_slice() {
+                    ["garbage_collector"] => Some(Self::GarbageCollector),
+                    ["command_nonce_store"] => Some(Self::CommandNonceStore),
+                    ["queue", shard] => Some(Self::Queue {
+                        shard: shard.parse().ok()?,
+                    }),
+                    ["rate_limiter", peer_hash] => Some(Self::RateLimiter {
+                        peer_hash: hex::decode(peer_hash).ok()?.try_into().ok()?,
+                    }),
+                    [version, "task", task_id_hex, rest @ ..] => {
+                        let version = match DapVersion::from(*version) {
+                            DapVersion::Unknown => return None,
+                            version => version,
+                        };
+                        let task_id = TaskId::try_from_hex(task_id_hex)?;
+                        match rest {
+                            [] => Some(Self::Task { version, task_id }),
+                            ["epoch", epoch, "shard", shard] => Some(Self::ReportStore {
+                                version,
+                                task_id,
+                                epoch: epoch.parse().ok()?,
+                                shard: shard.parse().ok()?,
+                            }),
+                            ["agg_job", agg_job_id_hex] => Some(Self::HelperState {
+                                version,
+                                task_id,
+                                agg_job_id: MetaAggregationJobId::try_from_hex(
+                                    &version,
+                                    agg_job_id_hex,
+                                )?,
+                            }),
+                            [_, _] | [_, _, "sub", _] => Some(Self::AggStore {
+                                version,
+                                task_id,
+                                bucket: rest.join("/").parse().ok()?,
+                            }),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for StorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.render(StorageKeySchema::CURRENT))
+    }
+}
+
 pub(crate) fn durable_name_queue(shard: u64) -> String {
-    format!("queue/{shard}")
+    StorageKey::Queue { shard }.to_string()
+}
+
+pub(crate) fn durable_name_rate_limiter(peer_identity: &str) -> String {
+    let hash = ring::digest::digest(&ring::digest::SHA256, peer_identity.as_bytes());
+    StorageKey::RateLimiter {
+        peer_hash: hash.as_ref().try_into().unwrap(),
+    }
+    .to_string()
+}
+
+pub(crate) fn durable_name_command_nonce_store() -> String {
+    StorageKey::CommandNonceStore.to_string()
 }
 
 pub(crate) fn durable_name_report_store(
     version: &DapVersion,
-    task_id_hex: &str,
+    task_id: &TaskId,
     epoch: u64,
     shard: u64,
 ) -> String {
-    format!(
-        "{}/epoch/{:020}/shard/{}",
-        durable_name_task(version, task_id_hex),
+    StorageKey::ReportStore {
+        version: *version,
+        task_id: task_id.clone(),
         epoch,
-        shard
-    )
+        shard,
+    }
+    .to_string()
+}
+
+/// Method for assigning reports to the shards of a report storage epoch. The default is the
+/// legacy modulo assignment, so that existing deployments keep their assignment until they
+/// explicitly migrate away from it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReportShardScheme {
+    /// The shard is the keyed hash of the report ID modulo the shard count. Changing the shard
+    /// count reassigns nearly every report.
+    #[default]
+    Modulo,
+
+    /// Rendezvous (highest random weight) hashing: each shard is scored by the keyed hash of the
+    /// report ID and the shard number, and the report is assigned to the highest scoring shard.
+    /// Adding a shard only reassigns the reports that move to the new shard, i.e., about
+    /// `1 / shard_count` of them.
+    Rendezvous,
+}
+
+/// Compute the shard to which a report is assigned.
+pub(crate) fn report_shard(
+    scheme: ReportShardScheme,
+    shard_key: &[u8; 32],
+    shard_count: u64,
+    report_id: &ReportId,
+) -> u64 {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, shard_key);
+    let score = |shard: Option<u64>| {
+        let mut ctx = ring::hmac::Context::with_key(&key);
+        ctx.update(report_id.as_ref());
+        if let Some(shard) = shard {
+            ctx.update(&shard.to_be_bytes());
+        }
+        let tag = ctx.sign();
+        u64::from_be_bytes(
+            tag.as_ref()[..std::mem::size_of::<u64>()]
+                .try_into()
+                .unwrap(),
+        )
+    };
+
+    match scheme {
+        ReportShardScheme::Modulo => score(None) % shard_count,
+        ReportShardScheme::Rendezvous => (0..shard_count)
+            .max_by_key(|shard| score(Some(*shard)))
+            .unwrap_or_default(),
+    }
+}
+
+/// Compute the shard of the Leader's collection job queue to which a task is assigned. All of the
+/// collection jobs of a task are assigned to the same shard, which preserves their order. Tasks
+/// are assigned with rendezvous hashing, so that adding a shard only moves tasks to the new shard.
+pub(crate) fn collect_job_queue_shard(task_id: &TaskId, shard_count: u64) -> u64 {
+    let score = |shard: u64| {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(task_id.as_ref());
+        ctx.update(&shard.to_be_bytes());
+        let digest = ctx.finish();
+        u64::from_be_bytes(
+            digest.as_ref()[..std::mem::size_of::<u64>()]
+                .try_into()
+                .unwrap(),
+        )
+    };
+    (0..shard_count)
+        .max_by_key(|shard| score(*shard))
+        .unwrap_or_default()
+}
+
+/// Return the report storage epoch of a report with the given timestamp, followed by each adjacent
+/// epoch whose boundary is within `boundary_window` seconds of the timestamp.
+///
+/// Replay protection is partitioned by epoch, but the same report ID may arrive with timestamps on
+/// either side of an epoch boundary. Replays of a report near a boundary are only caught if the
+/// adjacent epochs are checked as well.
+pub(crate) fn report_store_epochs(
+    report_time: Time,
+    epoch_duration: u64,
+    boundary_window: u64,
+) -> (u64, Vec<u64>) {
+    let epoch = report_time - (report_time % epoch_duration);
+    let mut adjacent = Vec::new();
+    if report_time - epoch < boundary_window {
+        if let Some(prev_epoch) = epoch.checked_sub(epoch_duration) {
+            adjacent.push(prev_epoch);
+        }
+    }
+    if let Some(next_epoch) = epoch.checked_add(epoch_duration) {
+        if next_epoch - report_time <= boundary_window {
+            adjacent.push(next_epoch);
+        }
+    }
+    (epoch, adjacent)
 }
 
 pub(crate) fn durable_name_agg_store(
     version: &DapVersion,
-    task_id_hex: &str,
+    task_id: &TaskId,
     bucket: &DapBatchBucket,
 ) -> String {
-    format!(
-        "{}/{}",
-        durable_name_task(version, task_id_hex),
-        durable_name_bucket(bucket),
-    )
+    StorageKey::AggStore {
+        version: *version,
+        task_id: task_id.clone(),
+        bucket: bucket.clone(),
+    }
+    .to_string()
 }
 
-pub(crate) fn durable_name_task(version: &DapVersion, task_id_hex: &str) -> String {
-    format!("{}/task/{}", version.as_ref(), task_id_hex)
+/// Names of the `AggregateStore` instances for the given bucket, one for each version of the task.
+/// Reports are aggregated in the version in which they were uploaded, so the aggregate share of
+/// the bucket is the sum of the aggregate shares stored by these instances.
+pub(crate) fn durable_names_agg_store<'a>(
+    task_config: &'a DapTaskConfig,
+    task_id: &'a TaskId,
+    bucket: &'a DapBatchBucket,
+) -> impl Iterator<Item = String> + 'a {
+    task_config
+        .versions()
+        .map(move |version| durable_name_agg_store(&version, task_id, bucket))
 }
 
-fn durable_name_bucket(bucket: &DapBatchBucket) -> String {
-    match bucket {
-        DapBatchBucket::TimeInterval { batch_window } => {
-            format!("window/{batch_window}")
-        }
-        DapBatchBucket::FixedSize { batch_id } => {
-            format!("batch/{}", batch_id.to_hex())
-        }
+pub(crate) fn durable_name_task(version: &DapVersion, task_id: &TaskId) -> String {
+    StorageKey::Task {
+        version: *version,
+        task_id: task_id.clone(),
     }
+    .to_string()
 }
 
 /// Reference to a DO instance, used by the garbage collector.
@@ -446,6 +1241,29 @@ pub(crate) struct DurableReference {
 
     /// If applicable, the DAP task ID to which the DO instance is associated.
     pub(crate) task_id: Option<TaskId>,
+
+    /// If applicable, the DAP version of the task to which the DO instance is associated.
+    #[serde(default)]
+    pub(crate) version: Option<DapVersion>,
+}
+
+/// Selects the DO instances to delete from the garbage collector's registry. An instance matches
+/// if it is associated with the given task and version, where either may be omitted. Instances
+/// that aren't associated with a task only match the empty filter.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct DurableReferenceFilter {
+    pub(crate) task_id: Option<TaskId>,
+    pub(crate) version: Option<DapVersion>,
+}
+
+impl DurableReferenceFilter {
+    pub(crate) fn matches(&self, durable_ref: &DurableReference) -> bool {
+        self.task_id.as_ref().map_or(true, |task_id| {
+            durable_ref.task_id.as_ref() == Some(task_id)
+        }) && self
+            .version
+            .map_or(true, |version| durable_ref.version == Some(version))
+    }
 }
 
 /// An element of a queue stored in a DO instance.
@@ -500,6 +1318,28 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
         }
     }
 
+    /// Create a new element identified by the given key rather than by its time of creation. (Use
+    /// `put()` to store it.) Putting an element with the same key replaces the previous one. The
+    /// format of the ordinal is:
+    ///
+    /// ```text
+    ///     key/<key>
+    /// ```
+    pub(crate) fn new_keyed(item: T, prefix: &str, key: &str) -> Self {
+        Self {
+            item,
+            prefix: prefix.to_string(),
+            ordinal: format!("key/{key}"),
+        }
+    }
+
+    /// Return the element with the given key (see [`Self::new_keyed`]), if it exists.
+    pub(crate) async fn get_keyed(state: &State, prefix: &str, key: &str) -> Result<Option<Self>> {
+        Ok(state_get(state, &format!("{prefix}/item/key/{key}"))
+            .await?
+            .map(|item| Self::new_keyed(item, prefix, key)))
+    }
+
     /// Create a new element for a strictly ordered queue. (Use `put()` to store it.)
     ///
     /// Items in this queue are handled in order of creation (i.e., the time at which this method
@@ -519,22 +1359,42 @@ impl<T: for<'a> Deserialize<'a> + Serialize> DurableOrdered<T> {
     ///
     /// where `<ordinal>` is the value of the counter at the time of creation.
     pub(crate) async fn new_strictly_ordered(state: &State, item: T, prefix: &str) -> Result<Self> {
-        // Get the next ordinal.
-        let next_ordinal_key = format!("{prefix}/next_ordinal");
-        let mut next_ordinal: u64 = state_get_or_default(state, &next_ordinal_key).await?;
-        let ordinal = format!("order/{next_ordinal}");
-
-        // Update the next ordinal.
-        next_ordinal += 1;
-        state
-            .storage()
-            .put(&next_ordinal_key, &next_ordinal)
-            .await?;
+        let next_ordinal = next_ordinal(state, prefix).await?;
+        Ok(Self {
+            item,
+            prefix: prefix.to_string(),
+            ordinal: format!("order/{next_ordinal}"),
+        })
+    }
 
+    /// Create a new element for a strictly ordered queue with priorities. (Use `put()` to store
+    /// it.)
+    ///
+    /// Items in this queue are handled in order of priority (highest first), then in order of
+    /// creation. The counter is the same as for [`Self::new_strictly_ordered`]. The format of each
+    /// ordinal is:
+    ///
+    /// ```text
+    ///     priority/<rank>/order/<ordinal>
+    /// ```
+    ///
+    /// where `<rank>` is `255 - priority` and both `<rank>` and `<ordinal>` are padded with 0s so
+    /// that the keys sort in the order in which the items are handled. Note that these keys sort
+    /// after those created by [`Self::new_strictly_ordered`] under the same prefix.
+    pub(crate) async fn new_strictly_ordered_with_priority(
+        state: &State,
+        item: T,
+        prefix: &str,
+        priority: u8,
+    ) -> Result<Self> {
+        let next_ordinal = next_ordinal(state, prefix).await?;
         Ok(Self {
             item,
             prefix: prefix.to_string(),
-            ordinal,
+            ordinal: format!(
+                "priority/{:03}/order/{next_ordinal:020}",
+                u8::MAX - priority
+            ),
         })
     }
 
@@ -570,6 +1430,18 @@ impl<T> AsRef<T> for DurableOrdered<T> {
     }
 }
 
+/// Get the next ordinal of the strictly ordered queue with the given prefix and increment the
+/// counter stored under `<prefix>/next_ordinal`.
+async fn next_ordinal(state: &State, prefix: &str) -> Result<u64> {
+    let next_ordinal_key = format!("{prefix}/next_ordinal");
+    let next_ordinal: u64 = state_get_or_default(state, &next_ordinal_key).await?;
+    state
+        .storage()
+        .put(&next_ordinal_key, &(next_ordinal + 1))
+        .await?;
+    Ok(next_ordinal)
+}
+
 async fn get_front<T: for<'a> Deserialize<'a> + Serialize>(
     state: &State,
     prefix: &str,
@@ -674,15 +1546,81 @@ fn create_span_from_request(req: &Request) -> tracing::Span {
 #[cfg(test)]
 mod test {
     use super::{
-        durable_name_agg_store, durable_name_queue, durable_name_report_store,
-        reports_pending::PendingReport,
+        collect_job_queue_shard, durable_name_agg_store, durable_name_queue,
+        durable_name_report_store, report_shard, report_store_epochs,
+        reports_pending::{ExpiredScan, PendingReport},
+        schema_migrations_for, DurableReference, DurableReferenceFilter, DurableRetryPolicy,
+        DurableStorageStats, ReportShardScheme, SchemaMigration, StorageKey, StorageKeySchema,
+        MAX_KEYS,
     };
     use daphne::{
-        messages::{BatchId, Report, ReportId, ReportMetadata, TaskId},
-        test_versions, DapBatchBucket, DapVersion,
+        messages::{AggregationJobId, BatchId, Report, ReportId, ReportMetadata, TaskId},
+        test_versions, DapBatchBucket, DapVersion, MetaAggregationJobId,
     };
+    use futures::future::LocalBoxFuture;
     use prio::codec::{ParameterizedDecode, ParameterizedEncode};
     use rand::prelude::*;
+    use std::{borrow::Cow, time::Duration};
+    use worker::{Result, State};
+
+    fn noop_migration(_state: &State) -> LocalBoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    #[test]
+    fn schema_migrations() {
+        let migrations = [
+            SchemaMigration {
+                from: 2,
+                migrate: noop_migration,
+            },
+            SchemaMigration {
+                from: 1,
+                migrate: noop_migration,
+            },
+        ];
+        let plan = |stored, current| {
+            schema_migrations_for("Test", stored, current, &migrations)
+                .map(|plan| plan.iter().map(|m| m.from).collect::<Vec<_>>())
+        };
+
+        assert_eq!(plan(3, 3).unwrap(), Vec::<u32>::new());
+        assert_eq!(plan(1, 3).unwrap(), vec![1, 2]);
+        assert_eq!(plan(2, 3).unwrap(), vec![2]);
+
+        // Data written by a newer deployment.
+        assert!(plan(4, 3).is_err());
+
+        // Missing migration.
+        assert!(plan(0, 3).is_err());
+    }
+
+    #[test]
+    fn retry_policy_delay() {
+        let retry_policy = DurableRetryPolicy {
+            max_retries: 3,
+            base_delay_ms: 100,
+            max_jitter_ms: 0,
+        };
+        let mut rng = thread_rng();
+        assert_eq!(retry_policy.delay(1, &mut rng), Duration::from_millis(100));
+        assert_eq!(retry_policy.delay(2, &mut rng), Duration::from_millis(200));
+        assert_eq!(retry_policy.delay(3, &mut rng), Duration::from_millis(400));
+        assert_eq!(
+            retry_policy.delay(100, &mut rng),
+            Duration::from_millis(u64::MAX)
+        );
+
+        let retry_policy = DurableRetryPolicy {
+            max_jitter_ms: 50,
+            ..retry_policy
+        };
+        for _ in 0..10 {
+            let delay = retry_policy.delay(2, &mut rng);
+            assert!(delay >= Duration::from_millis(200));
+            assert!(delay <= Duration::from_millis(250));
+        }
+    }
 
     #[test]
     fn durable_name() {
@@ -694,21 +1632,275 @@ mod test {
         assert_eq!(durable_name_queue(shard), "queue/1234");
 
         assert_eq!(
-        durable_name_report_store(&DapVersion::Draft02, &id1.to_hex(), time, shard),
+        durable_name_report_store(&DapVersion::Draft02, &id1, time, shard),
         "v02/task/1111111111111111111111111111111111111111111111111111111111111111/epoch/00000000001664850074/shard/1234",
     );
 
         assert_eq!(
-        durable_name_agg_store(&DapVersion::Draft02, &id1.to_hex(), &DapBatchBucket::FixedSize{ batch_id: id2 }),
+        durable_name_agg_store(&DapVersion::Draft02, &id1, &DapBatchBucket::FixedSize{ batch_id: id2 }),
         "v02/task/1111111111111111111111111111111111111111111111111111111111111111/batch/2222222222222222222222222222222222222222222222222222222222222222",
     );
 
         assert_eq!(
-        durable_name_agg_store(&DapVersion::Draft02, &id1.to_hex(), &DapBatchBucket::TimeInterval{ batch_window: time }),
+        durable_name_agg_store(&DapVersion::Draft02, &id1, &DapBatchBucket::TimeInterval{ batch_window: time }),
         "v02/task/1111111111111111111111111111111111111111111111111111111111111111/window/1664850074",
     );
     }
 
+    #[test]
+    fn collect_job_queue_shard_rendezvous() {
+        let mut rng = thread_rng();
+        let task_ids = (0..100).map(|_| TaskId(rng.gen())).collect::<Vec<_>>();
+        for task_id in &task_ids {
+            // With a single shard, every task is assigned to the queue used before sharding.
+            assert_eq!(collect_job_queue_shard(task_id, 1), 0);
+            // The assignment is stable.
+            assert_eq!(
+                collect_job_queue_shard(task_id, 4),
+                collect_job_queue_shard(task_id, 4)
+            );
+            // Adding a shard only moves tasks to the new shard.
+            let before = collect_job_queue_shard(task_id, 4);
+            let after = collect_job_queue_shard(task_id, 5);
+            assert!(after == before || after == 4);
+        }
+    }
+
+    #[test]
+    fn report_shard_rendezvous() {
+        let mut rng = thread_rng();
+        let shard_key: [u8; 32] = rng.gen();
+        let report_ids = (0..1000).map(|_| ReportId(rng.gen())).collect::<Vec<_>>();
+
+        // The modulo scheme matches the assignment used before rendezvous hashing.
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &shard_key);
+        for report_id in &report_ids {
+            let tag = ring::hmac::sign(&key, report_id.as_ref());
+            assert_eq!(
+                report_shard(ReportShardScheme::Modulo, &shard_key, 7, report_id),
+                u64::from_be_bytes(tag.as_ref()[..8].try_into().unwrap()) % 7
+            );
+        }
+
+        // Adding a shard only moves reports to the new shard.
+        let mut moved = 0;
+        for report_id in &report_ids {
+            let before = report_shard(ReportShardScheme::Rendezvous, &shard_key, 4, report_id);
+            let after = report_shard(ReportShardScheme::Rendezvous, &shard_key, 5, report_id);
+            assert!(before < 4);
+            if before != after {
+                assert_eq!(after, 4);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0);
+        assert!(moved < report_ids.len() / 2);
+    }
+
+    #[test]
+    fn storage_stats() {
+        let size = DurableStorageStats::entry_size("pending/0123", &"report");
+        assert_eq!(size, 12 + 8); // key plus the JSON string, including quotes
+
+        let mut stats = DurableStorageStats::default();
+        stats.add(size);
+        stats.add(100);
+        assert_eq!(
+            stats,
+            DurableStorageStats {
+                keys: 2,
+                bytes: 120
+            }
+        );
+
+        stats.remove(size);
+        assert_eq!(
+            stats,
+            DurableStorageStats {
+                keys: 1,
+                bytes: 100
+            }
+        );
+
+        // The estimate never underflows, even if an untracked pair is removed.
+        stats.remove(1000);
+        stats.remove(1000);
+        assert_eq!(stats, DurableStorageStats::default());
+
+        assert_eq!(
+            DurableStorageStats { keys: 3, bytes: 10 }
+                .max(DurableStorageStats { keys: 1, bytes: 20 }),
+            DurableStorageStats { keys: 3, bytes: 20 }
+        );
+    }
+
+    #[test]
+    fn durable_reference_filter() {
+        let task_id = TaskId([17; 32]);
+        let task = StorageKey::AggStore {
+            version: DapVersion::Draft07,
+            task_id: task_id.clone(),
+            bucket: DapBatchBucket::TimeInterval {
+                batch_window: 1664850074,
+            },
+        }
+        .task()
+        .map(|(version, task_id)| (version, task_id.clone()));
+        assert_eq!(task, Some((DapVersion::Draft07, task_id.clone())));
+        assert_eq!(StorageKey::Queue { shard: 1 }.task(), None);
+
+        let durable_ref = |task: Option<(DapVersion, TaskId)>| DurableReference {
+            binding: "DAP_AGGREGATE_STORE".into(),
+            id_hex: "00".into(),
+            task_id: task.as_ref().map(|(_, task_id)| task_id.clone()),
+            version: task.map(|(version, _)| version),
+        };
+        let by_task = DurableReferenceFilter {
+            task_id: Some(task_id.clone()),
+            version: None,
+        };
+        let by_version = DurableReferenceFilter {
+            task_id: None,
+            version: Some(DapVersion::Draft02),
+        };
+        assert!(by_task.matches(&durable_ref(task.clone())));
+        assert!(!by_version.matches(&durable_ref(task.clone())));
+        assert!(by_version.matches(&durable_ref(Some((DapVersion::Draft02, task_id)))));
+        assert!(!by_task.matches(&durable_ref(Some((DapVersion::Draft07, TaskId([18; 32]))))));
+        assert!(!by_task.matches(&durable_ref(None)));
+        assert!(DurableReferenceFilter::default().matches(&durable_ref(None)));
+    }
+
+    #[test]
+    fn storage_key_round_trip() {
+        let task_id = TaskId([17; 32]);
+        for key in [
+            StorageKey::Queue { shard: 1234 },
+            StorageKey::Task {
+                version: DapVersion::Draft07,
+                task_id: task_id.clone(),
+            },
+            StorageKey::ReportStore {
+                version: DapVersion::Draft02,
+                task_id: task_id.clone(),
+                epoch: 1664850074,
+                shard: 3,
+            },
+            StorageKey::AggStore {
+                version: DapVersion::Draft07,
+                task_id: task_id.clone(),
+                bucket: DapBatchBucket::FixedSize {
+                    batch_id: BatchId([34; 32]),
+                },
+            },
+            StorageKey::AggStore {
+                version: DapVersion::Draft07,
+                task_id: task_id.clone(),
+                bucket: DapBatchBucket::TimeInterval {
+                    batch_window: 1664850074,
+                },
+            },
+            StorageKey::AggStore {
+                version: DapVersion::Draft07,
+                task_id: task_id.clone(),
+                bucket: DapBatchBucket::TimeIntervalSub {
+                    batch_window: 1664848800,
+                    sub_window: 1664850000,
+                },
+            },
+            StorageKey::HelperState {
+                version: DapVersion::Draft07,
+                task_id: task_id.clone(),
+                agg_job_id: MetaAggregationJobId::Draft07(Cow::Owned(AggregationJobId([51; 16]))),
+            },
+            StorageKey::GarbageCollector,
+            StorageKey::RateLimiter {
+                peer_hash: [68; 32],
+            },
+            StorageKey::CommandNonceStore,
+        ] {
+            let name = key.render(StorageKeySchema::V1);
+            assert_eq!(name, key.to_string());
+            assert_eq!(StorageKey::parse(StorageKeySchema::V1, &name), Some(key));
+        }
+
+        for name in [
+            "",
+            "queue",
+            "queue/x",
+            "rate_limiter/11",
+            "v99/task/1111111111111111111111111111111111111111111111111111111111111111",
+            "v07/task/11",
+            "v07/task/1111111111111111111111111111111111111111111111111111111111111111/window",
+            "v07/task/1111111111111111111111111111111111111111111111111111111111111111/bucket/1",
+            "v07/task/1111111111111111111111111111111111111111111111111111111111111111/window/1/sub",
+            "v07/task/1111111111111111111111111111111111111111111111111111111111111111/window/1/part/2",
+            "v07/task/1111111111111111111111111111111111111111111111111111111111111111/agg_job/11",
+        ] {
+            assert_eq!(
+                StorageKey::parse(StorageKeySchema::V1, name),
+                None,
+                "{name}"
+            );
+        }
+    }
+
+    // Test that a report whose timestamp is near an epoch boundary is checked for replays in the
+    // epoch on the other side of the boundary.
+    #[test]
+    fn report_store_epochs_near_boundary() {
+        let epoch_duration = 1000;
+        let window = 10;
+
+        // Far from either boundary.
+        assert_eq!(
+            report_store_epochs(5500, epoch_duration, window),
+            (5000, vec![])
+        );
+
+        // Just after the start of the epoch.
+        assert_eq!(
+            report_store_epochs(5000, epoch_duration, window),
+            (5000, vec![4000])
+        );
+        assert_eq!(
+            report_store_epochs(5009, epoch_duration, window),
+            (5000, vec![4000])
+        );
+        assert_eq!(
+            report_store_epochs(5010, epoch_duration, window),
+            (5000, vec![])
+        );
+
+        // Just before the end of the epoch.
+        assert_eq!(
+            report_store_epochs(5990, epoch_duration, window),
+            (5000, vec![6000])
+        );
+        assert_eq!(
+            report_store_epochs(5999, epoch_duration, window),
+            (5000, vec![6000])
+        );
+        assert_eq!(
+            report_store_epochs(5989, epoch_duration, window),
+            (5000, vec![])
+        );
+
+        // A report replayed with a timestamp on the other side of the boundary shares an epoch
+        // with the original.
+        let (orig_epoch, orig_adjacent) = report_store_epochs(5995, epoch_duration, window);
+        let (replay_epoch, replay_adjacent) = report_store_epochs(6003, epoch_duration, window);
+        assert_ne!(orig_epoch, replay_epoch);
+        assert!(orig_adjacent.contains(&replay_epoch));
+        assert!(replay_adjacent.contains(&orig_epoch));
+
+        // The first epoch has no predecessor.
+        assert_eq!(report_store_epochs(3, epoch_duration, window), (0, vec![]));
+
+        // Both boundaries are in the window.
+        assert_eq!(report_store_epochs(5005, 10, 10), (5000, vec![4990, 5010]));
+    }
+
     // Test that the `PendingReport.report_id_hex()` method properly extracts the report ID from the
     // hex-encoded report. This helps ensure that changes to the `Report` wire format don't cause any
     // regressions to `ReportStore`.
@@ -724,12 +1916,16 @@ mod test {
             },
             public_share: Vec::default(),
             encrypted_input_shares: Vec::default(),
+            annotation: None,
         };
 
         let pending_report = PendingReport {
             task_id,
             version,
             report_hex: hex::encode(report.get_encoded_with_param(&version)),
+            expires_at: None,
+            annotation: None,
+            batch_bucket: None,
         };
 
         let got = ReportId::get_decoded_with_param(
@@ -746,4 +1942,45 @@ mod test {
     }
 
     test_versions! {parse_report_id_hex_from_report}
+
+    // Test that scanning pending reports for expired ones tracks the next expiration across every
+    // report visited, so that the alarm is only re-armed for when a report actually expires.
+    #[test]
+    fn expired_scan() {
+        let pending_report = |expires_at| PendingReport {
+            task_id: TaskId([17; 32]),
+            version: DapVersion::Draft07,
+            report_hex: String::new(),
+            expires_at,
+            annotation: None,
+            batch_bucket: None,
+        };
+        let now = 1000;
+
+        let mut scan = ExpiredScan::default();
+        // A full page of reports, none of which has expired.
+        for i in 0..MAX_KEYS {
+            scan.observe(
+                &format!("pending/{i:032x}"),
+                &pending_report(Some(now + 100 + u64::try_from(i).unwrap())),
+                now,
+            );
+        }
+        assert!(scan.expired_keys.is_empty());
+        assert_eq!(scan.next_expiration, Some(now + 100));
+
+        // The next page.
+        scan.observe("pending/a", &pending_report(Some(now)), now);
+        scan.observe("pending/b", &pending_report(None), now);
+        scan.observe("pending/c", &pending_report(Some(now + 10)), now);
+        scan.observe("pending/d", &pending_report(Some(now - 10)), now);
+        assert_eq!(scan.expired_keys, ["pending/a", "pending/d"]);
+        assert_eq!(scan.live_stats.keys, u64::try_from(MAX_KEYS).unwrap() + 2);
+        assert_eq!(scan.next_expiration, Some(now + 10));
+
+        // No alarm is needed if no report expires.
+        let mut scan = ExpiredScan::default();
+        scan.observe("pending/a", &pending_report(None), now);
+        assert_eq!(scan.next_expiration, None);
+    }
 }
@@ -0,0 +1,197 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::ops::ControlFlow;
+
+use crate::{
+    config::{DaphneWorkerConfig, DaphneWorkerQuota},
+    durable::{create_span_from_request, state_get, BINDING_DAP_RATE_LIMITER},
+    initialize_tracing, int_err, now,
+};
+use daphne::messages::Time;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, Instrument};
+use worker::*;
+
+use super::{req_parse, DapDurableObject, GarbageCollectable, SchemaVersionState, SchemaVersioned};
+
+pub(crate) const DURABLE_RATE_LIMITER_ACQUIRE: &str = "/internal/do/rate_limiter/acquire";
+
+const WINDOW: &str = "window";
+
+/// Length of each window, in seconds.
+pub(crate) const WINDOW_DURATION: Time = 60;
+
+/// Input of `DURABLE_RATE_LIMITER_ACQUIRE`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct RateLimiterAcquireRequest {
+    /// Number of reports carried by the request.
+    pub(crate) reports: u64,
+
+    /// The peer's quota.
+    pub(crate) quota: DaphneWorkerQuota,
+}
+
+/// Output of `DURABLE_RATE_LIMITER_ACQUIRE`.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RateLimiterAcquireResult {
+    Ok,
+    RequestsExceeded,
+    ReportsExceeded,
+}
+
+/// Usage of the quota in the current window.
+#[derive(Default, Deserialize, Serialize)]
+struct RateLimiterWindow {
+    start: Time,
+    requests: u64,
+    reports: u64,
+}
+
+/// Durable Object (DO) for enforcing a peer's quota on the Helper's aggregation endpoints. There
+/// is one instance per peer identity.
+///
+/// This object implements the following API endpoints:
+///
+/// - `DURABLE_RATE_LIMITER_ACQUIRE`: Count a request and the reports it carries against the quota
+///   for the current one-minute window. If either limit would be exceeded, then nothing is counted
+///   and the result indicates which limit was hit.
+///
+/// The schema for data stored in instances of this DO is as follows:
+///
+/// ```text
+/// [Current window] window -> RateLimiterWindow
+/// [Schema version] schema_version -> u32
+/// ```
+#[durable_object]
+pub struct RateLimiter {
+    state: State,
+    env: Env,
+    config: DaphneWorkerConfig,
+    touched: bool,
+    schema_version_state: SchemaVersionState,
+}
+
+#[durable_object]
+impl DurableObject for RateLimiter {
+    fn new(state: State, env: Env) -> Self {
+        initialize_tracing(&env);
+        let config =
+            DaphneWorkerConfig::from_worker_env(&env).expect("failed to load configuration");
+        Self {
+            state,
+            env,
+            config,
+            touched: false,
+            schema_version_state: SchemaVersionState::Unchecked,
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        let span = create_span_from_request(&req);
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version().await?;
+        resp
+    }
+}
+
+impl RateLimiter {
+    async fn handle(&mut self, req: Request) -> Result<Response> {
+        let mut req = match self
+            .schedule_for_garbage_collection(req, BINDING_DAP_RATE_LIMITER)
+            .await?
+        {
+            ControlFlow::Continue(req) => req,
+            // This req was a GC request and as such we must return from this function.
+            ControlFlow::Break(resp) => return Ok(resp),
+        };
+
+        match (req.path().as_ref(), req.method()) {
+            // Count a request against the quota.
+            //
+            // Non-idempotent
+            // Input: `RateLimiterAcquireRequest`
+            // Output: `RateLimiterAcquireResult`
+            (DURABLE_RATE_LIMITER_ACQUIRE, Method::Post) => {
+                let RateLimiterAcquireRequest { reports, quota } = req_parse(&mut req).await?;
+
+                let now = now();
+                let start = now - now % WINDOW_DURATION;
+                let mut window = match state_get::<RateLimiterWindow>(&self.state, WINDOW).await? {
+                    Some(window) if window.start == start => window,
+                    _ => RateLimiterWindow {
+                        start,
+                        ..Default::default()
+                    },
+                };
+
+                let result = if exceeds(quota.requests_per_minute, window.requests, 1) {
+                    RateLimiterAcquireResult::RequestsExceeded
+                } else if exceeds(quota.reports_per_minute, window.reports, reports) {
+                    RateLimiterAcquireResult::ReportsExceeded
+                } else {
+                    window.requests += 1;
+                    window.reports = window.reports.saturating_add(reports);
+                    self.state.storage().put(WINDOW, &window).await?;
+                    RateLimiterAcquireResult::Ok
+                };
+
+                if !matches!(result, RateLimiterAcquireResult::Ok) {
+                    debug!(
+                        "RateLimiter: rejected request with {reports} reports (used {} requests, {} reports)",
+                        window.requests, window.reports
+                    );
+                }
+                Response::from_json(&result)
+            }
+
+            _ => Err(int_err(format!(
+                "RateLimiter: unexpected request: method={:?}; path={:?}",
+                req.method(),
+                req.path()
+            ))),
+        }
+    }
+}
+
+/// Check if using `requested` more units on top of `used` would exceed `limit`.
+fn exceeds(limit: Option<u64>, used: u64, requested: u64) -> bool {
+    matches!(limit, Some(limit) if used.saturating_add(requested) > limit)
+}
+
+impl DapDurableObject for RateLimiter {
+    #[inline(always)]
+    fn state(&self) -> &State {
+        &self.state
+    }
+
+    #[inline(always)]
+    fn deployment(&self) -> crate::config::DaphneWorkerDeployment {
+        self.config.deployment
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for RateLimiter {
+    const CLASS: &'static str = "RateLimiter";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl GarbageCollectable for RateLimiter {
+    #[inline(always)]
+    fn touched(&mut self) -> &mut bool {
+        &mut self.touched
+    }
+
+    #[inline(always)]
+    fn env(&self) -> &Env {
+        &self.env
+    }
+}
@@ -6,23 +6,43 @@ use crate::{
     durable::{
         create_span_from_request, durable_name_queue,
         leader_agg_job_queue::{
-            DURABLE_LEADER_AGG_JOB_QUEUE_FINISH, DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
+            agg_job_queue_prefix, AggJobQueuePutRequest, DURABLE_LEADER_AGG_JOB_QUEUE_FINISH,
+            DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
         },
-        req_parse, state_get, state_set_if_not_exists, DurableConnector, DurableOrdered,
-        BINDING_DAP_LEADER_AGG_JOB_QUEUE, BINDING_DAP_REPORTS_PENDING, MAX_KEYS,
+        req_parse, state_get, state_get_or_default, state_set_if_not_exists, DurableConnector,
+        DurableOrdered, DurableStorageStats, BINDING_DAP_LEADER_AGG_JOB_QUEUE,
+        BINDING_DAP_REPORTS_PENDING, MAX_KEYS,
     },
-    initialize_tracing, int_err,
+    initialize_tracing, int_err, now,
+};
+use daphne::{
+    messages::{ReportId, TaskId, Time},
+    DapPrecomputedBatchBucket, DapVersion,
 };
-use daphne::{messages::TaskId, DapVersion};
 use serde::{Deserialize, Serialize};
-use std::{cmp::min, ops::ControlFlow};
-use tracing::{debug, Instrument};
+use std::{cmp::min, ops::ControlFlow, time::Duration};
+use tracing::{debug, info, Instrument};
 use worker::*;
 
-use super::{DapDurableObject, GarbageCollectable};
+use super::{
+    durable_task, Alarmed, DapDurableObject, GarbageCollectable, SchemaVersionState,
+    SchemaVersioned,
+};
 
 pub(crate) const DURABLE_REPORTS_PENDING_GET: &str = "/internal/do/reports_pending/get";
+pub(crate) const DURABLE_REPORTS_PENDING_PEEK: &str = "/internal/do/reports_pending/peek";
 pub(crate) const DURABLE_REPORTS_PENDING_PUT: &str = "/internal/do/reports_pending/put";
+pub(crate) const DURABLE_REPORTS_PENDING_CHECK_EXISTS: &str =
+    "/internal/do/reports_pending/check_exists";
+pub(crate) const DURABLE_REPORTS_PENDING_CHECK_IDENTICAL: &str =
+    "/internal/do/reports_pending/check_identical";
+
+/// Storage key under which the estimated storage usage is written back by the alarm.
+const STORAGE_STATS_KEY: &str = "storage_stats";
+
+/// How long an update of the estimated storage usage is kept only in memory before the alarm
+/// writes it back.
+const STORAGE_STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(300);
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -42,9 +62,32 @@ pub(crate) struct PendingReport {
     // TODO(cjpatton) Consider changing the type to `Report`. If I recall correctly, this triggers
     // the serde-wasm-bindgen bug we saw in workers-rs 0.0.12, which should be fixed as of 0.0.15.
     pub(crate) report_hex: String,
+
+    /// Time after which the report is dropped if it has not yet been drained. If not set, then the
+    /// report is kept until it is drained.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<Time>,
+
+    /// Annotation attached to the report upon upload, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) annotation: Option<String>,
+
+    /// Batch bucket of the report, if it was computed upon upload (see
+    /// `DapTaskConfig::precompute_batch_bucket`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) batch_bucket: Option<DapPrecomputedBatchBucket>,
 }
 
 impl PendingReport {
+    /// Return the hex-encoded SHA-256 digest of the serialized report.
+    pub(crate) fn report_digest_hex(&self) -> Option<String> {
+        let report = hex::decode(&self.report_hex).ok()?;
+        Some(hex::encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            &report,
+        )))
+    }
+
     pub(crate) fn report_id_hex(&self) -> Option<&str> {
         match self.version {
             DapVersion::Draft02 if self.report_hex.len() >= 96 => Some(&self.report_hex[64..96]),
@@ -53,6 +96,62 @@ impl PendingReport {
             _ => None,
         }
     }
+
+    fn is_expired(&self, now: Time) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Pending reports visited by `ReportsPending::drop_expired()`: the keys of those that have
+/// expired, the estimated storage usage of the rest, and the earliest expiration time among the
+/// rest.
+#[derive(Debug, Default)]
+pub(crate) struct ExpiredScan {
+    pub(crate) expired_keys: Vec<String>,
+    pub(crate) live_stats: DurableStorageStats,
+    pub(crate) next_expiration: Option<Time>,
+}
+
+impl ExpiredScan {
+    pub(crate) fn observe(&mut self, key: &str, pending_report: &PendingReport, now: Time) {
+        if pending_report.is_expired(now) {
+            self.expired_keys.push(key.to_string());
+            return;
+        }
+        self.live_stats
+            .add(DurableStorageStats::entry_size(key, pending_report));
+        if let Some(expires_at) = pending_report.expires_at {
+            self.next_expiration = Some(
+                self.next_expiration
+                    .map_or(expires_at, |t| t.min(expires_at)),
+            );
+        }
+    }
+}
+
+/// Input of `DURABLE_REPORTS_PENDING_CHECK_IDENTICAL`.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct ReportsPendingCheckIdenticalReq {
+    pub(crate) report_id: ReportId,
+
+    /// Hex-encoded SHA-256 digest of the serialized report (see
+    /// [`PendingReport::report_digest_hex`]).
+    pub(crate) report_digest_hex: String,
+}
+
+/// Response to `DURABLE_REPORTS_PENDING_GET`.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct ReportsPendingGetResp {
+    pub(crate) reports: Vec<PendingReport>,
+
+    /// The number of reports dropped because they expired before they could be drained.
+    pub(crate) dropped_expired: u64,
+
+    /// Estimated storage usage of the instance after the reports were drained.
+    #[serde(default)]
+    pub(crate) storage_stats: DurableStorageStats,
 }
 
 /// Durable Object (DO) for storing reports waiting to be processed.
@@ -68,11 +167,26 @@ impl PendingReport {
 ///   aggregated. Whenever the instance becomes empty, the aggregation job is removed from
 ///   `LeadeerAggregationJobQueue`.
 ///
+/// - `DURABLE_REPORTS_PENDING_PEEK`: Used to read reports without removing them from storage, e.g.,
+///   for a dry run of the aggregation flow. Expired reports are skipped.
+///
+/// Reports that have an expiration time (see `DapTaskConfig::report_ttl`) are dropped by an alarm
+/// once they expire. The number of reports dropped this way is returned by the next call to
+/// `DURABLE_REPORTS_PENDING_GET`.
+///
+/// The estimated storage usage of the instance (see [`DurableStorageStats`]) covers the pending
+/// reports and the aggregation job and is returned by `DURABLE_REPORTS_PENDING_GET`. It is updated
+/// in memory only; the alarm recomputes it from the stored reports and writes it back, which also
+/// corrects for updates lost when the instance is evicted before the alarm goes off.
+///
 /// The schema for stored reports is as follows:
 ///
 /// ```text
 /// [Pending report]  pending/<report_id> -> PendingReport
 /// [Aggregation job] agg_job -> DurableOrdered<PendingReport>
+/// [Dropped count]   dropped_expired -> u64
+/// [Storage stats]   storage_stats -> DurableStorageStats
+/// [Schema version]  schema_version -> u32
 /// ```
 ///
 /// where `<report_id>` is the ID of the report. The value is the hex-encoded report. The
@@ -85,6 +199,11 @@ pub struct ReportsPending {
     env: Env,
     config: DaphneWorkerConfig,
     touched: bool,
+    alarmed: bool,
+    schema_version_state: SchemaVersionState,
+
+    /// Estimated storage usage, read from storage upon first use.
+    storage_stats: Option<DurableStorageStats>,
 }
 
 #[durable_object]
@@ -98,16 +217,127 @@ impl DurableObject for ReportsPending {
             env,
             config,
             touched: false,
+            alarmed: false,
+            schema_version_state: SchemaVersionState::Unchecked,
+            storage_stats: None,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        let task = durable_task(&req)?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version_for_task(task, BINDING_DAP_REPORTS_PENDING)
+            .await?;
+        resp
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        self.alarmed = false;
+        self.drop_expired().await?;
+        Response::from_json(&())
     }
 }
 
 impl ReportsPending {
+    /// Delete the reports that have expired, write back the estimated storage usage, and schedule
+    /// an alarm for when the next report expires. The aggregation job is left in place, so that
+    /// the next drain reports the number of reports that were dropped.
+    ///
+    /// Every pending report is visited, one page of `MAX_KEYS` reports at a time, so that the next
+    /// expiration and the storage usage are known exactly.
+    async fn drop_expired(&mut self) -> Result<()> {
+        let now = now();
+        let mut scan = ExpiredScan::default();
+        let mut start: Option<String> = None;
+        loop {
+            let mut opt = ListOptions::new().prefix("pending/").limit(MAX_KEYS);
+            if let Some(start) = &start {
+                opt = opt.start(start);
+            }
+            let iter = self.state.storage().list_with_options(opt).await?.entries();
+            let mut item = iter.next()?;
+            let mut listed = 0;
+            let mut last_key = None;
+            while !item.done() {
+                let (key, pending_report): (String, PendingReport) =
+                    serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
+                listed += 1;
+                // The start of the page is inclusive, so the last report of the previous page is
+                // listed again.
+                if start.as_ref() != Some(&key) {
+                    scan.observe(&key, &pending_report, now);
+                }
+                last_key = Some(key);
+                item = iter.next()?;
+            }
+            if listed < MAX_KEYS {
+                break;
+            }
+            start = last_key;
+        }
+
+        let ExpiredScan {
+            expired_keys,
+            live_stats: mut storage_stats,
+            next_expiration,
+        } = scan;
+        if !expired_keys.is_empty() {
+            let dropped = u64::try_from(expired_keys.len()).unwrap();
+            for expired_keys in expired_keys.chunks(MAX_KEYS) {
+                self.state
+                    .storage()
+                    .delete_multiple(expired_keys.to_vec())
+                    .await?;
+            }
+            let dropped_expired: u64 = state_get(&self.state, "dropped_expired")
+                .await?
+                .unwrap_or_default();
+            self.state
+                .storage()
+                .put("dropped_expired", dropped_expired + dropped)
+                .await?;
+            info!(
+                "ReportsPending: dropped {dropped} expired reports from bucket {}",
+                self.state.id().to_string()
+            );
+        }
+
+        let agg_job: Option<DurableOrdered<String>> = state_get(&self.state, "agg_job").await?;
+        if let Some(agg_job) = agg_job {
+            storage_stats.add(DurableStorageStats::entry_size("agg_job", &agg_job));
+        }
+        self.state
+            .storage()
+            .put(STORAGE_STATS_KEY, storage_stats)
+            .await?;
+        self.storage_stats = Some(storage_stats);
+
+        if let Some(expires_at) = next_expiration {
+            self.ensure_alarmed(Duration::from_secs(expires_at.saturating_sub(now)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Update the estimated storage usage in memory and make sure the alarm is set to write it
+    /// back. Returns the new estimate.
+    async fn update_storage_stats(
+        &mut self,
+        update: impl FnOnce(&mut DurableStorageStats),
+    ) -> Result<DurableStorageStats> {
+        let mut storage_stats = match self.storage_stats {
+            Some(storage_stats) => storage_stats,
+            None => state_get_or_default(&self.state, STORAGE_STATS_KEY).await?,
+        };
+        update(&mut storage_stats);
+        self.storage_stats = Some(storage_stats);
+        self.ensure_alarmed(STORAGE_STATS_FLUSH_INTERVAL).await?;
+        Ok(storage_stats)
+    }
+
     async fn handle(&mut self, req: Request) -> Result<Response> {
         let id_hex = self.state.id().to_string();
 
@@ -117,16 +347,14 @@ impl ReportsPending {
         {
             ControlFlow::Continue(req) => req,
             // This req was a GC request and as such we must return from this function.
-            ControlFlow::Break(_) => return Response::from_json(&()),
+            ControlFlow::Break(resp) => return Ok(resp),
         };
 
-        let durable = DurableConnector::new(&self.env);
-
         match (req.path().as_ref(), req.method()) {
-            // Drain the requested number of reports from storage.
+            // Drain the requested number of reports from storage. Expired reports are dropped.
             //
             // Input: `reports_requested: usize`
-            // Output: `Vec<PendingReport>`
+            // Output: `ReportsPendingGetResp`
             (DURABLE_REPORTS_PENDING_GET, Method::Post) => {
                 let reports_requested: usize = req_parse(&mut req).await?;
                 // Note we impose an upper limit on the user's specified limit.
@@ -137,18 +365,38 @@ impl ReportsPending {
                 let mut item = iter.next()?;
                 let mut reports = Vec::with_capacity(reports_requested);
                 let mut keys = Vec::with_capacity(reports_requested);
+                let mut sizes = Vec::with_capacity(reports_requested);
+                let mut dropped_expired: u64 = state_get(&self.state, "dropped_expired")
+                    .await?
+                    .unwrap_or_default();
+                let now = now();
                 while !item.done() {
                     let (key, pending_report): (String, PendingReport) =
                         serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
-                    reports.push(pending_report);
+                    sizes.push(DurableStorageStats::entry_size(&key, &pending_report));
+                    if pending_report.is_expired(now) {
+                        dropped_expired += 1;
+                    } else {
+                        reports.push(pending_report);
+                    }
                     keys.push(key);
                     item = iter.next()?;
                 }
+                if dropped_expired > 0 {
+                    self.state.storage().delete("dropped_expired").await?;
+                }
 
                 // NOTE In order to support DAP tasks that require longer batch lifetimes, it will
                 // necessary to check if the lifetime has been reached before removing reports from
                 // storage. We might consider putting reports in KV instead.
                 self.state.storage().delete_multiple(keys).await?;
+                let mut storage_stats = self
+                    .update_storage_stats(|stats| {
+                        for size in sizes {
+                            stats.remove(size);
+                        }
+                    })
+                    .await?;
 
                 // Check if this bucket is now empty, and if so, remove it from the agg job queue.
                 let empty = self
@@ -171,9 +419,14 @@ impl ReportsPending {
                         // new job will have a different name due to the timestamp and nonce that
                         // new_roughly_ordered() adds when constructing the name.
                         self.state.storage().delete("agg_job").await?;
+                        storage_stats = self
+                            .update_storage_stats(|stats| {
+                                stats.remove(DurableStorageStats::entry_size("agg_job", &agg_job))
+                            })
+                            .await?;
                         // NOTE There is only one agg job queue for now. In the future, work will
                         // be sharded across multiple queues.
-                        durable
+                        DurableConnector::new(&self.env)
                             .post(
                                 BINDING_DAP_LEADER_AGG_JOB_QUEUE,
                                 DURABLE_LEADER_AGG_JOB_QUEUE_FINISH,
@@ -189,6 +442,35 @@ impl ReportsPending {
                     reports.len(),
                     self.state.id().to_string()
                 );
+                Response::from_json(&ReportsPendingGetResp {
+                    reports,
+                    dropped_expired,
+                    storage_stats,
+                })
+            }
+
+            // Read the requested number of reports without removing them from storage.
+            //
+            // Idempotent
+            // Input: `reports_requested: usize`
+            // Output: `Vec<PendingReport>`
+            (DURABLE_REPORTS_PENDING_PEEK, Method::Post) => {
+                let reports_requested: usize = req_parse(&mut req).await?;
+                let opt = ListOptions::new()
+                    .prefix("pending/")
+                    .limit(min(reports_requested, MAX_KEYS));
+                let iter = self.state.storage().list_with_options(opt).await?.entries();
+                let mut item = iter.next()?;
+                let mut reports = Vec::with_capacity(reports_requested);
+                let now = now();
+                while !item.done() {
+                    let (_key, pending_report): (String, PendingReport) =
+                        serde_wasm_bindgen::from_value(item.value()).map_err(int_err)?;
+                    if !pending_report.is_expired(now) {
+                        reports.push(pending_report);
+                    }
+                    item = iter.next()?;
+                }
                 Response::from_json(&reports)
             }
 
@@ -208,30 +490,92 @@ impl ReportsPending {
                 if exists {
                     return Response::from_json(&ReportsPendingResult::ErrReportExists);
                 }
+                self.update_storage_stats(|stats| {
+                    stats.add(DurableStorageStats::entry_size(&key, &pending_report))
+                })
+                .await?;
+
+                if let Some(expires_at) = pending_report.expires_at {
+                    self.ensure_alarmed(Duration::from_secs(expires_at.saturating_sub(now())))
+                        .await?;
+                }
 
                 // Check if processing for this bucket of reports has been scheduled. If not, add
                 // this bucket to the aggregation job queue.
                 let agg_job: Option<DurableOrdered<String>> =
                     state_get(&self.state, "agg_job").await?;
                 if agg_job.is_none() {
-                    let agg_job = DurableOrdered::new_roughly_ordered(id_hex, "agg_job");
+                    let put_req = AggJobQueuePutRequest {
+                        task_id: pending_report.task_id.clone(),
+                        agg_job: DurableOrdered::new_roughly_ordered(
+                            id_hex,
+                            &agg_job_queue_prefix(&pending_report.task_id),
+                        ),
+                    };
 
                     // TODO Shard the work across multiple job queues rather than just one. (See
                     // issue #25.) For now there is jsut one job queue.
-                    durable
+                    DurableConnector::new(&self.env)
                         .post(
                             BINDING_DAP_LEADER_AGG_JOB_QUEUE,
                             DURABLE_LEADER_AGG_JOB_QUEUE_PUT,
                             durable_name_queue(0),
-                            &agg_job,
+                            &put_req,
                         )
                         .await?;
+                    let agg_job = put_req.agg_job;
+                    let size = DurableStorageStats::entry_size("agg_job", &agg_job);
                     self.state.storage().put("agg_job", agg_job).await?;
+                    self.update_storage_stats(|stats| stats.add(size)).await?;
                 }
 
                 Response::from_json(&ReportsPendingResult::Ok)
             }
 
+            // Check which of a set of reports are stored in this instance. The reports are looked
+            // up in chunks of at most `MAX_KEYS`.
+            //
+            // Idempotent
+            // Input: `Vec<ReportId>`
+            // Output: `Vec<ReportId>`
+            (DURABLE_REPORTS_PENDING_CHECK_EXISTS, Method::Post) => {
+                let report_ids: Vec<ReportId> = req_parse(&mut req).await?;
+                let mut existing = Vec::new();
+                for chunk in report_ids.chunks(MAX_KEYS) {
+                    let keys = chunk
+                        .iter()
+                        .map(|report_id| format!("pending/{}", report_id.to_hex()))
+                        .collect::<Vec<_>>();
+                    let values = self.state.storage().get_multiple(keys.clone()).await?;
+                    existing.extend(
+                        chunk
+                            .iter()
+                            .zip(keys)
+                            .filter(|(_, key)| values.has(&key.as_str().into()))
+                            .map(|(report_id, _)| report_id.clone()),
+                    );
+                }
+                Response::from_json(&existing)
+            }
+
+            // Check whether a report is stored in this instance and is identical to the given one,
+            // as indicated by the digest of the serialized report.
+            //
+            // Idempotent
+            // Input: `ReportsPendingCheckIdenticalReq`
+            // Output: `bool`
+            (DURABLE_REPORTS_PENDING_CHECK_IDENTICAL, Method::Post) => {
+                let ReportsPendingCheckIdenticalReq {
+                    report_id,
+                    report_digest_hex,
+                } = req_parse(&mut req).await?;
+                let pending_report: Option<PendingReport> =
+                    state_get(&self.state, &format!("pending/{}", report_id.to_hex())).await?;
+                Response::from_json(&pending_report.is_some_and(|pending_report| {
+                    pending_report.report_digest_hex().as_ref() == Some(&report_digest_hex)
+                }))
+            }
+
             _ => Err(int_err(format!(
                 "ReportsPending: unexpected request: method={:?}; path={:?}",
                 req.method(),
@@ -253,6 +597,24 @@ impl DapDurableObject for ReportsPending {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for ReportsPending {
+    const CLASS: &'static str = "ReportsPending";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Alarmed for ReportsPending {
+    #[inline(always)]
+    fn alarmed(&mut self) -> &mut bool {
+        &mut self.alarmed
+    }
+}
+
 #[async_trait::async_trait(?Send)]
 impl GarbageCollectable for ReportsPending {
     #[inline(always)]
@@ -264,4 +626,11 @@ impl GarbageCollectable for ReportsPending {
     fn env(&self) -> &Env {
         &self.env
     }
+    async fn virtual_alarm(&mut self) -> Result<bool> {
+        self.alarm_if_due().await
+    }
+
+    fn forget_cached_state(&mut self) {
+        self.storage_stats = None;
+    }
 }
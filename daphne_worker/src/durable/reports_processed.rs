@@ -7,7 +7,7 @@ use crate::{
     initialize_tracing, int_err,
 };
 use daphne::{
-    messages::{ReportId, ReportMetadata, TransitionFailure},
+    messages::{ReportId, ReportMetadata, TaskId, TransitionFailure},
     vdaf::{
         EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized, VdafPrepMessage,
         VdafPrepState, VdafVerifyKey,
@@ -24,12 +24,17 @@ use std::{borrow::Cow, collections::HashSet, ops::ControlFlow, time::Duration};
 use tracing::Instrument;
 use worker::*;
 
-use super::{req_parse, Alarmed, DapDurableObject, GarbageCollectable};
+use super::{
+    durable_task, req_parse, Alarmed, DapDurableObject, GarbageCollectable, SchemaVersionState,
+    SchemaVersioned,
+};
 
 pub(crate) const DURABLE_REPORTS_PROCESSED_INITIALIZE: &str =
     "/internal/do/reports_processed/initialize";
 pub(crate) const DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED: &str =
     "/internal/do/reports_processed/mark_aggregated";
+pub(crate) const DURABLE_REPORTS_PROCESSED_CHECK_REPLAYED: &str =
+    "/internal/do/reports_processed/check_replayed";
 
 /// Durable Object (DO) for tracking which reports have been processed.
 ///
@@ -41,9 +46,14 @@ pub(crate) const DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED: &str =
 ///
 /// ```text
 ///     processed/<report_id> -> bool
+///     schema_version -> u32
 /// ```
 ///
 /// where `<report_id>` is the hex-encoded report ID.
+///
+/// The instance is deleted by an alarm once reports in its epoch can no longer be accepted. The
+/// alarm is set when reports are first marked as aggregated, which is the only time anything is
+/// stored, to go off after the task's epoch duration plus `processed_alarm_safety_interval`.
 #[durable_object]
 pub struct ReportsProcessed {
     #[allow(dead_code)]
@@ -52,6 +62,7 @@ pub struct ReportsProcessed {
     config: DaphneWorkerConfig,
     touched: bool,
     alarmed: bool,
+    schema_version_state: SchemaVersionState,
 }
 
 #[derive(Debug, Clone)]
@@ -132,18 +143,26 @@ impl DurableObject for ReportsProcessed {
             config,
             touched: false,
             alarmed: false,
+            schema_version_state: SchemaVersionState::Unchecked,
         }
     }
 
     async fn fetch(&mut self, req: Request) -> Result<Response> {
+        self.ensure_schema_version().await?;
+        let task = durable_task(&req)?;
         let span = create_span_from_request(&req);
-        self.handle(req).instrument(span).await
+        let resp = self.handle(req).instrument(span).await;
+        self.record_schema_version_for_task(task, BINDING_DAP_REPORTS_PROCESSED)
+            .await?;
+        resp
     }
 
     async fn alarm(&mut self) -> Result<Response> {
         self.state.storage().delete_all().await?;
+        self.unregister(BINDING_DAP_REPORTS_PROCESSED).await?;
         self.alarmed = false;
         self.touched = false;
+        self.schema_version_state = SchemaVersionState::Unrecorded;
         Response::from_json(&())
     }
 }
@@ -156,15 +175,9 @@ impl ReportsProcessed {
         {
             ControlFlow::Continue(req) => req,
             // This req was a GC request and as such we must return from this function.
-            ControlFlow::Break(_) => return Response::from_json(&()),
+            ControlFlow::Break(resp) => return Ok(resp),
         };
 
-        self.ensure_alarmed(
-            Duration::from_secs(self.config.global.report_storage_epoch_duration)
-                .saturating_add(self.config.processed_alarm_safety_interval),
-        )
-        .await?;
-
         match (req.path().as_ref(), req.method()) {
             // Initialize a report:
             //  * Ensure the report wasn't replayed
@@ -206,10 +219,13 @@ impl ReportsProcessed {
                             Ok(EarlyReportStateInitialized::Rejected {
                                 metadata: Cow::Owned(consumed_report.metadata().clone()),
                                 failure: TransitionFailure::ReportReplayed,
+                                panicked: false,
                             })
                         } else {
                             EarlyReportStateInitialized::initialize(
                                 reports_processed_request.is_leader,
+                                &self.config.global,
+                                &reports_processed_request.task_id,
                                 &reports_processed_request.vdaf_verify_key,
                                 &reports_processed_request.vdaf_config,
                                 consumed_report,
@@ -235,10 +251,18 @@ impl ReportsProcessed {
             // If there are any replays, no reports are marked as aggregated.
             //
             // Idempotent
-            // Input: `Vec<ReportId>`
+            // Input: `ReportsProcessedMarkAggregatedReq`
             // Output: `Vec<ReportId>`
             (DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED, Method::Post) => {
-                let report_ids: Vec<ReportId> = req_parse(&mut req).await?;
+                let ReportsProcessedMarkAggregatedReq {
+                    epoch_duration,
+                    report_ids,
+                } = req_parse(&mut req).await?;
+                self.ensure_alarmed(
+                    Duration::from_secs(epoch_duration)
+                        .saturating_add(self.config.processed_alarm_safety_interval),
+                )
+                .await?;
                 match self.check_replays(&report_ids).await? {
                     CheckedReplays::SomeReplayed(report_ids) => Response::from_json(&report_ids),
                     CheckedReplays::AllFresh(report_ids) => {
@@ -255,6 +279,20 @@ impl ReportsProcessed {
                 }
             }
 
+            // Check which of a set of reports have been marked as aggregated, without marking any.
+            // This is used to check for replays of reports stored in an adjacent epoch.
+            //
+            // Idempotent
+            // Input: `Vec<ReportId>`
+            // Output: `Vec<ReportId>`
+            (DURABLE_REPORTS_PROCESSED_CHECK_REPLAYED, Method::Post) => {
+                let report_ids: Vec<ReportId> = req_parse(&mut req).await?;
+                match self.check_replays(&report_ids).await? {
+                    CheckedReplays::SomeReplayed(report_ids) => Response::from_json(&report_ids),
+                    CheckedReplays::AllFresh(_) => Response::from_json(&[(); 0]),
+                }
+            }
+
             _ => Err(int_err(format!(
                 "ReportsProcessed: unexpected request: method={:?}; path={:?}",
                 req.method(),
@@ -276,6 +314,16 @@ impl DapDurableObject for ReportsProcessed {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl SchemaVersioned for ReportsProcessed {
+    const CLASS: &'static str = "ReportsProcessed";
+
+    #[inline(always)]
+    fn schema_version_state(&mut self) -> &mut SchemaVersionState {
+        &mut self.schema_version_state
+    }
+}
+
 #[async_trait::async_trait]
 impl Alarmed for ReportsProcessed {
     #[inline(always)]
@@ -295,16 +343,29 @@ impl GarbageCollectable for ReportsProcessed {
     fn env(&self) -> &Env {
         &self.env
     }
+    async fn virtual_alarm(&mut self) -> Result<bool> {
+        self.alarm_if_due().await
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct ReportsProcessedReq<'req> {
     pub(crate) is_leader: bool,
+    pub(crate) task_id: TaskId,
     pub(crate) vdaf_verify_key: VdafVerifyKey,
     pub(crate) vdaf_config: VdafConfig,
     pub(crate) consumed_reports: Vec<EarlyReportStateConsumed<'req>>,
 }
 
+/// Input of `DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ReportsProcessedMarkAggregatedReq {
+    /// The task's report storage epoch duration, in seconds. This determines how long the
+    /// instance is kept.
+    pub(crate) epoch_duration: u64,
+    pub(crate) report_ids: Vec<ReportId>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(try_from = "ShadowReportsProcessedResp")]
 pub(crate) struct ReportsProcessedResp<'req> {
@@ -337,6 +398,8 @@ pub enum EarlyReportStateInitializedOwned {
     Rejected {
         metadata: ReportMetadata,
         failure: TransitionFailure,
+        #[serde(default)]
+        panicked: bool,
     },
 }
 
@@ -367,12 +430,15 @@ impl TryFrom<ShadowReportsProcessedResp> for ReportsProcessedResp<'_> {
                         message,
                     })
                 }
-                EarlyReportStateInitializedOwned::Rejected { metadata, failure } => {
-                    Ok(EarlyReportStateInitialized::Rejected {
-                        metadata: Cow::Owned(metadata),
-                        failure,
-                    })
-                }
+                EarlyReportStateInitializedOwned::Rejected {
+                    metadata,
+                    failure,
+                    panicked,
+                } => Ok(EarlyReportStateInitialized::Rejected {
+                    metadata: Cow::Owned(metadata),
+                    failure,
+                    panicked,
+                }),
             })
             .collect::<std::result::Result<Vec<EarlyReportStateInitialized>, CodecError>>()?;
         Ok(Self {
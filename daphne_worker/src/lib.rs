@@ -28,8 +28,9 @@
 //! while they wait to be aggregated. In order to allow the Leader to horizontally scale with the
 //! upload rate, reports are mapped to one of a number of DO instances running on the Workers
 //! platform simultaneously. Reports are partitioned first by task, then by report storage epoch
-//! (see `report_storage_epoch_duration` in [`DapGlobalConfig`](daphne::DapGlobalConfig)), then
-//! into one of a number of shards. The naming scheme for `ReportsPending` instances is as follows:
+//! (see `report_storage_epoch_duration` in [`DapGlobalConfig`](daphne::DapGlobalConfig), which
+//! may be overridden by the task), then into one of a number of shards. The naming scheme for
+//! `ReportsPending` instances is as follows:
 //!
 //! ```text
 //!    <version>/task/<task_id>/epoch/<epoch>/shard/<shard>
@@ -63,13 +64,15 @@
 //!
 //! ```text
 //! [Time-interval tasks] <version>/task/<task_id>/window/<window>
+//!                       <version>/task/<task_id>/window/<window>/sub/<sub_window>
 //! [Fixed-size tasks]    <version>/task/<task_id>/batch/<batch_id>
 //! ```
 //!
 //! where `<version>` is the DAP version, `<task_id>` is the task ID, `<window>` is a batch window,
 //! and `<batch_id>` is a batch ID. A batch window is a UNIX timestamp (in seconds) truncated by
 //! the time precision for the task. (See the `time_precision` paramaeter of
-//! [`DapTaskConfig`](daphne::DapTaskConfig).)
+//! [`DapTaskConfig`](daphne::DapTaskConfig).) If the task has a sub-bucket precision, then reports
+//! are aggregated by `<sub_window>`, the timestamp truncated by the sub-bucket precision.
 //!
 //! ## Aggregation Jobs (Leader-only)
 //!
@@ -85,18 +88,22 @@
 //! Daphne-Worker, [`DaphneWorkerReportSelector`], indicates the number of jobs to fetch at once
 //! (`max_agg_jobs`) and the number of reports to drain per job (`max_reports`).
 //!
-//! Jobs are handled roughly in order of creation (oldest jobs are handled first). The time at
-//! which an aggregation job was created is used determine the order in which it was processed.
-//! Timestamps are truncated to the second; ties are broken by a nonce generated at creation time.
+//! Jobs are queued per task and the queues are drained in turn, so that a task with many jobs does
+//! not starve the others; the number of jobs fetched at once for any one task may also be limited
+//! (`max_agg_jobs_per_task_per_fetch`). Within a task, jobs are handled roughly in order of
+//! creation (oldest jobs are handled first). The time at which an aggregation job was created is used determine the
+//! order in which it was processed. Timestamps are truncated to the second; ties are broken by a
+//! nonce generated at creation time.
 //!
 //! ## Collection Jobs (Leader-only)
 //!
 //! > NOTE: This scheme is not expected to scale well. Currently it is only suited for driving
 //! end-to-end tests.
 //!
-//! The `LeaderCollectionJobQueue` DO is used by the Leader to queue collection jobs. There is just
-//! one instance of this DO; once the Leader gets a collect request from the Collector, it adds a
-//! job to the queue.
+//! The `LeaderCollectionJobQueue` DO is used by the Leader to queue collection jobs. Once the
+//! Leader gets a collect request from the Collector, it adds a job to the queue. The queue may be
+//! split into several instances of this DO (`durable.collect_job_queue_shard_count`); each task is
+//! assigned to one of them, so that the jobs of a task are handled in order.
 //!
 //! A "collection job ID" is computed for each job and incorporated into the collect URI for the
 //! Collector to poll later on. The job ID Is derived by applying a keyed hash to the serialized
@@ -154,6 +161,7 @@
 //! | `DAP_REPORT_SHARD_COUNT` | `u64` | no | Number of report shards per storage epoch. |
 //! | `DAP_REPORT_SHARD_KEY` | `String` | yes | Hex-encoded key used to hash a report into one of the report shards. |
 
+mod audit_log;
 mod auth;
 mod config;
 mod durable;
@@ -162,11 +170,16 @@ mod metrics;
 mod roles;
 mod router;
 mod tracing_utils;
+mod virtual_clock;
 
+pub use crate::audit_log::HttpAuditLogSink;
 use crate::config::{DaphneWorkerIsolateState, DaphneWorkerRequestState};
 pub use crate::tracing_utils::initialize_tracing;
 use daphne::{
     audit_log::{AuditLog, NoopAuditLog},
+    hpke::HpkePrivateKeyStore,
+    messages::Time,
+    time::{MonotonicTimeSource, TimeSource},
     DapError, DapRequest,
 };
 pub use error_reporting::ErrorReporter;
@@ -184,11 +197,22 @@ pub struct DaphneWorkerReportSelector {
 
     /// Maximum number of reports to drain for each aggregation job.
     pub max_reports: u64,
+
+    /// Maximum number of aggregation jobs fetched for any one task by a single call to
+    /// [`DapLeader::process()`](daphne::roles::DapLeader::process). If not set, then a single task
+    /// may take up to `max_agg_jobs`.
+    ///
+    /// This is not a limit on the number of jobs in flight for the task: jobs fetched by other
+    /// calls that are still running are not counted.
+    #[serde(default, alias = "max_agg_jobs_per_task")]
+    pub max_agg_jobs_per_task_per_fetch: Option<u64>,
 }
 
 /// HTTP request handler for Daphne-Worker.
 pub struct DaphneWorkerRouter<'srv> {
-    /// If true, then enable internal test endpoints. These should not be enabled in production.
+    /// If true, then enable internal test endpoints. These should not be enabled in production. In
+    /// a production deployment, they are only reachable if `DAP_INTERNAL_COMMAND_KEYS` is set, in
+    /// which case each request must be an authenticated command.
     pub enable_internal_test: bool,
 
     /// If true, then respond to unhandled requests with 200 OK instead of 404 Not Found. The
@@ -198,8 +222,20 @@ pub struct DaphneWorkerRouter<'srv> {
     /// Error reporting for Daphne. By default is a no-op.
     pub error_reporter: &'srv dyn error_reporting::ErrorReporter,
 
-    /// Audit log, used to record statistics of tasks processed.
+    /// Audit log, used to record statistics of tasks processed. The log is given a chance to
+    /// flush buffered events after each request (see [`AuditLog::flush_if_due`]). By default is a
+    /// no-op.
     pub audit_log: &'srv dyn AuditLog,
+
+    /// Source of the current time, used to validate reports and to expire state. By default is the
+    /// clock of the Workers runtime, adjusted so that it never goes backwards within an isolate.
+    pub time_source: &'srv dyn TimeSource,
+
+    /// Store of HPKE private keys, e.g., an [`HpkeKmsKeyStore`](daphne::hpke::HpkeKmsKeyStore).
+    /// It is used to decrypt ciphertexts for HPKE configs whose entry in KV omits the private key,
+    /// so that a deployment can keep its private keys out of Worker storage entirely. By default
+    /// is not set.
+    pub hpke_private_key_store: Option<&'srv dyn HpkePrivateKeyStore>,
 }
 
 impl<'srv> Default for DaphneWorkerRouter<'srv> {
@@ -207,6 +243,8 @@ impl<'srv> Default for DaphneWorkerRouter<'srv> {
         Self {
             error_reporter: &error_reporting::NoopErrorReporter {},
             audit_log: &NoopAuditLog,
+            time_source: &WORKER_TIME_SOURCE,
+            hpke_private_key_store: None,
             enable_internal_test: false,
             enable_default_response: false,
         }
@@ -219,6 +257,9 @@ pub const DEFAULT_RESPONSE_HTML: &str = "<body>Daphne-Worker</body>";
 
 static ISOLATE_STATE: OnceCell<DaphneWorkerIsolateState> = OnceCell::new();
 
+static WORKER_TIME_SOURCE: MonotonicTimeSource<WorkerTimeSource> =
+    MonotonicTimeSource::new(WorkerTimeSource);
+
 impl DaphneWorkerRouter<'_> {
     /// HTTP request handler for Daphne-Worker.
     ///
@@ -252,8 +293,14 @@ impl DaphneWorkerRouter<'_> {
         } else {
             ISOLATE_STATE.get_or_try_init(|| DaphneWorkerIsolateState::from_worker_env(&env))?
         };
-        let state =
-            DaphneWorkerRequestState::new(shared_state, &req, self.error_reporter, self.audit_log)?;
+        let state = DaphneWorkerRequestState::new(
+            shared_state,
+            &req,
+            self.error_reporter,
+            self.audit_log,
+            self.time_source,
+            self.hpke_private_key_store,
+        )?;
 
         let router = router::create_router(
             &state,
@@ -268,7 +315,30 @@ impl DaphneWorkerRouter<'_> {
         // reports the same times as the span covering the specific API entry point that the
         // router creates. If curious, you can add .instrument(info_span!("http")) just before
         // the await and see.
-        let result = router.run(req, env).await;
+        let rejected = match router::reject_unsupported_version(&state, &req)? {
+            Some(resp) => Some(resp),
+            None => router::reject_invalid_content_type(&state, &req)?,
+        };
+        let rejected = match rejected {
+            Some(resp) => Some(resp),
+            None => router::reject_malformed_resource(&state, &req)?,
+        };
+        let rejected = match rejected {
+            Some(resp) => Some(resp),
+            None => {
+                router::test_routes::reject_unauthenticated_command(
+                    &state,
+                    &env,
+                    &req,
+                    self.enable_internal_test,
+                )
+                .await?
+            }
+        };
+        let result = match rejected {
+            Some(resp) => Ok(resp),
+            None => router.run(req, env).await,
+        };
 
         state
             .metrics
@@ -286,14 +356,35 @@ impl DaphneWorkerRouter<'_> {
         // in theory, but I don't know if workers-rs supports it.
         state.maybe_push_metrics().await?;
 
+        // Write out buffered audit log events, if any are due. Failures are counted as dropped
+        // events by the audit log rather than failing the request.
+        self.audit_log.flush_if_due(self.time_source.now()).await;
+
         result
     }
 }
 
+/// The current time, including the offset of the [virtual clock](virtual_clock) used by tests.
 pub(crate) fn now() -> u64 {
+    runtime_now().saturating_add(virtual_clock::offset())
+}
+
+/// The current time according to the Workers runtime, ignoring the virtual clock. This is used
+/// where the time is compared with timestamps produced outside of Daphne-Worker, e.g., to
+/// authenticate internal commands.
+pub(crate) fn runtime_now() -> u64 {
     Date::now().as_millis() / 1000
 }
 
+/// The clock of the Workers runtime.
+pub struct WorkerTimeSource;
+
+impl TimeSource for WorkerTimeSource {
+    fn now(&self) -> Time {
+        now()
+    }
+}
+
 pub(crate) fn int_err<S: ToString>(s: S) -> Error {
     error!("internal error: {}", s.to_string());
     Error::RustError("internalError".to_string())
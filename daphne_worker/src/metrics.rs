@@ -3,9 +3,12 @@
 
 //! Daphne-Worker metrics.
 
-use crate::DapError;
+use crate::{durable::DurableStorageStats, DapError};
 use daphne::{fatal_error, metrics::DaphneMetrics};
-use prometheus::{register_int_counter_vec_with_registry, IntCounterVec, Registry};
+use prometheus::{
+    register_histogram_vec_with_registry, register_int_counter_vec_with_registry,
+    register_int_gauge_vec_with_registry, HistogramVec, IntCounterVec, IntGaugeVec, Registry,
+};
 
 pub struct DaphneWorkerMetrics {
     /// Daphne metrics.
@@ -14,8 +17,27 @@ pub struct DaphneWorkerMetrics {
     /// HTTP response status.
     pub(crate) http_status_code_counter: IntCounterVec,
 
-    /// DAP aborts.
+    /// DAP aborts. Superseded by Daphne's `abort_counter`, which also breaks aborts down by the
+    /// sender's role, but kept so that existing dashboards and alerts continue to work.
     pub(crate) dap_abort_counter: IntCounterVec,
+
+    /// Leader: Number of aggregated reports with each annotation.
+    pub(crate) report_annotation_counter: IntCounterVec,
+
+    /// Largest estimated storage usage, in bytes, among the DO instances of each binding observed
+    /// while handling the request.
+    durable_storage_bytes: IntGaugeVec,
+
+    /// Largest number of tracked keys among the DO instances of each binding observed while
+    /// handling the request.
+    durable_storage_keys: IntGaugeVec,
+
+    /// Time taken by each attempt of a request to a DO instance, broken down by binding, path,
+    /// and whether the attempt was a retry.
+    durable_request_time_histogram: HistogramVec,
+
+    /// Number of failed attempts of requests to DO instances, broken down as above.
+    durable_request_error_counter: IntCounterVec,
 }
 
 impl DaphneWorkerMetrics {
@@ -42,12 +64,103 @@ impl DaphneWorkerMetrics {
         )
         .map_err(|e| fatal_error!(err = ?e, "failed to register dap_abort"))?;
 
+        let report_annotation_counter = register_int_counter_vec_with_registry!(
+            format!("{front}report_annotation"),
+            "Number of aggregated reports with each annotation.",
+            &["host", "annotation"],
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register report_annotation"))?;
+
+        let durable_storage_bytes = register_int_gauge_vec_with_registry!(
+            format!("{front}durable_storage_bytes"),
+            "Estimated storage usage of the largest DO instance observed, in bytes.",
+            &["host", "binding"],
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register durable_storage_bytes"))?;
+
+        let durable_storage_keys = register_int_gauge_vec_with_registry!(
+            format!("{front}durable_storage_keys"),
+            "Number of tracked keys of the largest DO instance observed.",
+            &["host", "binding"],
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register durable_storage_keys"))?;
+
+        let durable_request_time_histogram = register_histogram_vec_with_registry!(
+            format!("{front}durable_request_times"),
+            "Time taken by requests to DO instances, in milliseconds.",
+            &["host", "binding", "path", "attempt"],
+            prometheus::DEFAULT_BUCKETS
+                .iter()
+                .map(|i| i * 1000.0)
+                .collect(),
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register durable_request_times"))?;
+
+        let durable_request_error_counter = register_int_counter_vec_with_registry!(
+            format!("{front}durable_request_error"),
+            "Number of failed requests to DO instances.",
+            &["host", "binding", "path", "attempt"],
+            registry
+        )
+        .map_err(|e| fatal_error!(err = ?e, "failed to register durable_request_error"))?;
+
         let daphne = DaphneMetrics::register(registry, prefix)?;
 
         Ok(Self {
             daphne,
             http_status_code_counter,
             dap_abort_counter,
+            report_annotation_counter,
+            durable_storage_bytes,
+            durable_storage_keys,
+            durable_request_time_histogram,
+            durable_request_error_counter,
         })
     }
+
+    /// Record the estimated storage usage of a DO instance with the given binding.
+    pub(crate) fn observe_durable_storage(
+        &self,
+        host: &str,
+        binding: &str,
+        stats: DurableStorageStats,
+    ) {
+        for (gauge, val) in [
+            (&self.durable_storage_bytes, stats.bytes),
+            (&self.durable_storage_keys, stats.keys),
+        ] {
+            let gauge = gauge.with_label_values(&[host, binding]);
+            let val = i64::try_from(val).unwrap_or(i64::MAX);
+            if val > gauge.get() {
+                gauge.set(val);
+            }
+        }
+    }
+
+    /// Record the outcome of an attempt of a request to a DO instance. The path is one of the
+    /// `DURABLE_*` constants, so the number of label values is bounded.
+    pub(crate) fn observe_durable_request(
+        &self,
+        host: &str,
+        binding: &str,
+        path: &str,
+        retried: bool,
+        millis: u64,
+        ok: bool,
+    ) {
+        let attempt = if retried { "retry" } else { "first" };
+        let labels = [host, binding, path, attempt];
+        self.durable_request_time_histogram
+            .with_label_values(&labels)
+            .observe(millis as f64);
+        if !ok {
+            self.durable_request_error_counter
+                .with_label_values(&labels)
+                .inc();
+        }
+    }
 }
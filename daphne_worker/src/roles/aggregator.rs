@@ -8,38 +8,96 @@ use crate::{
     config::{DapTaskConfigKvPair, DaphneWorker},
     durable::{
         aggregate_store::{
-            DURABLE_AGGREGATE_STORE_CHECK_COLLECTED, DURABLE_AGGREGATE_STORE_GET,
-            DURABLE_AGGREGATE_STORE_MARK_COLLECTED, DURABLE_AGGREGATE_STORE_MERGE,
+            AggregateStoreMergeManyReq, DURABLE_AGGREGATE_STORE_CHECK_COLLECTED,
+            DURABLE_AGGREGATE_STORE_CHECK_COLLECTED_AGG_PARAM, DURABLE_AGGREGATE_STORE_GET,
+            DURABLE_AGGREGATE_STORE_GET_META, DURABLE_AGGREGATE_STORE_MARK_COLLECTED,
+            DURABLE_AGGREGATE_STORE_MERGE_MANY,
         },
-        durable_name_agg_store,
+        durable_name_agg_store, durable_name_task, durable_names_agg_store,
+        garbage_collector::DURABLE_GARBAGE_COLLECTOR_PURGE_TASK,
+        leader_batch_queue::DURABLE_LEADER_BATCH_QUEUE_LIST,
+        leader_col_job_queue::DURABLE_LEADER_COL_JOB_QUEUE_PURGE,
         reports_processed::{
-            ReportsProcessedReq, ReportsProcessedResp, DURABLE_REPORTS_PROCESSED_INITIALIZE,
+            ReportsProcessedMarkAggregatedReq, ReportsProcessedReq, ReportsProcessedResp,
+            DURABLE_REPORTS_PROCESSED_CHECK_REPLAYED, DURABLE_REPORTS_PROCESSED_INITIALIZE,
             DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED,
         },
-        BINDING_DAP_AGGREGATE_STORE, BINDING_DAP_REPORTS_PROCESSED,
+        DurableStorageStats, BINDING_DAP_AGGREGATE_STORE, BINDING_DAP_GARBAGE_COLLECTOR,
+        BINDING_DAP_LEADER_BATCH_QUEUE, BINDING_DAP_LEADER_COL_JOB_QUEUE,
+        BINDING_DAP_REPORTS_PENDING, BINDING_DAP_REPORTS_PROCESSED, DURABLE_PURGE,
     },
-    now,
 };
 use async_trait::async_trait;
 use daphne::{
     audit_log::AuditLog,
     auth::BearerTokenProvider,
+    extensions::DapExtensionRegistry,
     fatal_error,
     hpke::HpkeConfig,
-    messages::{BatchId, BatchSelector, PartialBatchSelector, ReportId, TaskId, TransitionFailure},
+    messages::{
+        BatchId, BatchSelector, PartialBatchSelector, ReportId, TaskId, Time, TransitionFailure,
+    },
     metrics::DaphneMetrics,
     roles::{early_metadata_check, DapAggregator, DapReportInitializer},
-    vdaf::{EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized},
-    DapAggregateShare, DapAggregateShareSpan, DapBatchBucket, DapError, DapGlobalConfig,
-    DapRequest, DapSender, DapTaskConfig,
+    taskprov::TaskprovRejectionCache,
+    time::TimeSource,
+    vdaf::{
+        EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized, VdafVerifyKey,
+    },
+    DapAggregateShare, DapAggregateShareMeta, DapAggregateShareSpan, DapAggregationParamHash,
+    DapBatchBucket, DapError, DapGlobalConfig, DapQueryConfig, DapRequest, DapSender,
+    DapTaskConfig, DapTaskPurgeSummary,
 };
-use futures::{future::try_join_all, StreamExt, TryStreamExt};
+use futures::future::try_join_all;
 use std::{
     borrow::Cow,
     collections::{hash_map::Entry, HashMap, HashSet},
-    future::ready,
 };
 
+impl DaphneWorker<'_> {
+    /// Return the IDs of the reports that have already been aggregated in a report store other
+    /// than their own: either in an adjacent report storage epoch (only checked for reports with
+    /// timestamps near an epoch boundary) or in the shard assigned by a previous shard assignment
+    /// (only checked during a shard migration).
+    pub(crate) async fn find_replays_in_other_report_stores<'a>(
+        &self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        reports: impl Iterator<Item = (&'a ReportId, Time)>,
+    ) -> std::result::Result<HashSet<ReportId>, DapError> {
+        let now = self.get_current_time();
+        let mut reports_processed_request_data: HashMap<String, Vec<ReportId>> = HashMap::new();
+        for (id, time) in reports {
+            for durable_name in
+                self.config()
+                    .durable_names_other_report_stores(task_config, task_id, id, time, now)
+            {
+                reports_processed_request_data
+                    .entry(durable_name)
+                    .or_default()
+                    .push(id.clone());
+            }
+        }
+
+        let durable = self.durable();
+        Ok(try_join_all(reports_processed_request_data.into_iter().map(
+            |(durable_name, report_ids)| {
+                durable.post::<_, Vec<ReportId>>(
+                    BINDING_DAP_REPORTS_PROCESSED,
+                    DURABLE_REPORTS_PROCESSED_CHECK_REPLAYED,
+                    durable_name,
+                    report_ids,
+                )
+            },
+        ))
+        .await
+        .map_err(|e| fatal_error!(err = ?e, code = Storage))?
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+}
+
 #[async_trait(?Send)]
 impl DapReportInitializer for DaphneWorker<'_> {
     async fn initialize_reports<'req>(
@@ -47,17 +105,22 @@ impl DapReportInitializer for DaphneWorker<'_> {
         is_leader: bool,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        vdaf_verify_key: &VdafVerifyKey,
         part_batch_sel: &PartialBatchSelector,
         consumed_reports: Vec<EarlyReportStateConsumed<'req>>,
     ) -> std::result::Result<Vec<EarlyReportStateInitialized<'req>>, DapError> {
         let current_time = self.get_current_time();
-        let min_time = self.least_valid_report_time(current_time);
+        let min_time = self.least_valid_report_time(task_config, current_time);
         let max_time = self.greatest_valid_report_time(current_time);
-        let durable = self.durable().with_retry();
-        let task_id_hex = task_id.to_hex();
-        let span = task_config
-            .as_ref()
-            .batch_span_for_meta(part_batch_sel, consumed_reports.iter())?;
+        let durable = self.durable();
+        let span = {
+            let precomputed_batch_buckets = self.state.precomputed_batch_buckets.borrow();
+            task_config.as_ref().batch_span_for_meta_precomputed(
+                part_batch_sel,
+                consumed_reports.iter(),
+                |metadata| precomputed_batch_buckets.get(&metadata.id).cloned(),
+            )?
+        };
 
         // Coalesce reports pertaining to the same ReportsProcessed or AggregateStore instance.
         let mut reports_processed_request_data: HashMap<String, ReportsProcessedReq> =
@@ -67,14 +130,14 @@ impl DapReportInitializer for DaphneWorker<'_> {
         for (bucket, consumed_reports_per_bucket) in span.iter() {
             agg_store_request_name.push(durable_name_agg_store(
                 &task_config.version,
-                &task_id_hex,
+                task_id,
                 bucket,
             ));
             agg_store_request_bucket.push(bucket);
             for consumed_report in consumed_reports_per_bucket.iter() {
                 let durable_name = self.config().durable_name_report_store(
                     task_config.as_ref(),
-                    &task_id_hex,
+                    task_id,
                     &consumed_report.metadata().id,
                     consumed_report.metadata().time,
                 );
@@ -83,7 +146,8 @@ impl DapReportInitializer for DaphneWorker<'_> {
                     .entry(durable_name)
                     .or_insert(ReportsProcessedReq {
                         is_leader,
-                        vdaf_verify_key: task_config.vdaf_verify_key.clone(),
+                        task_id: task_id.clone(),
+                        vdaf_verify_key: vdaf_verify_key.clone(),
                         vdaf_config: task_config.vdaf.clone(),
                         consumed_reports: Vec::default(),
                     })
@@ -105,7 +169,25 @@ impl DapReportInitializer for DaphneWorker<'_> {
         let reports_processed_responses: Vec<ReportsProcessedResp> =
             try_join_all(reports_processed_requests)
                 .await
-                .map_err(|e| fatal_error!(err = ?e))?;
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        // Reports near an epoch boundary may have been aggregated in the adjacent epoch, and
+        // reports may have been aggregated in another shard during a shard migration.
+        let replayed_in_other_report_stores = self
+            .find_replays_in_other_report_stores(
+                task_id,
+                task_config,
+                consumed_reports
+                    .iter()
+                    .filter(|consumed_report| consumed_report.is_ready())
+                    .map(|consumed_report| {
+                        (
+                            &consumed_report.metadata().id,
+                            consumed_report.metadata().time,
+                        )
+                    }),
+            )
+            .await?;
 
         // Flatten the responses from ReportsProcessed into a hash map.
         let mut initialized_reports = HashMap::new();
@@ -127,7 +209,7 @@ impl DapReportInitializer for DaphneWorker<'_> {
         }
         let agg_store_responses: Vec<bool> = try_join_all(agg_store_requests)
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
         // Reject reports that have been collected.
         for (bucket, collected) in agg_store_request_bucket
@@ -142,7 +224,9 @@ impl DapReportInitializer for DaphneWorker<'_> {
             {
                 if let Some(initialized_report) = initialized_reports.get_mut(&metadata.id) {
                     let processed = match initialized_report {
-                        EarlyReportStateInitialized::Ready { .. } => false,
+                        EarlyReportStateInitialized::Ready { .. } => {
+                            replayed_in_other_report_stores.contains(&metadata.id)
+                        }
                         EarlyReportStateInitialized::Rejected {
                             failure: TransitionFailure::ReportReplayed,
                             ..
@@ -158,6 +242,7 @@ impl DapReportInitializer for DaphneWorker<'_> {
                         *initialized_report = EarlyReportStateInitialized::Rejected {
                             metadata: Cow::Owned(metadata.clone()),
                             failure,
+                            panicked: false,
                         };
                     }
                 }
@@ -177,6 +262,10 @@ impl DapReportInitializer for DaphneWorker<'_> {
             })
             .collect::<std::result::Result<Vec<_>, DapError>>()?)
     }
+
+    fn extension_registry(&self) -> &DapExtensionRegistry {
+        &self.config().extension_registry
+    }
 }
 
 #[async_trait(?Send)]
@@ -221,7 +310,7 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
             }
 
             // Resolve the trusted certificate issuers and subjects for this request.
-            let sender = req.media_type.sender();
+            let sender = req.sender();
             let trusted_certs = if let (Some(DapSender::Leader), Some(ref trusted_certs)) =
                 (sender, &taskprov_config.leader_auth.cf_tls_client_auth)
             {
@@ -277,6 +366,10 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
             .map(|config| &config.hpke_collector_config)
     }
 
+    fn taskprov_rejection_cache(&self) -> &TaskprovRejectionCache {
+        &self.isolate_state().taskprov_rejections
+    }
+
     fn taskprov_opt_out_reason(
         &self,
         _task_config: &DapTaskConfig,
@@ -305,12 +398,10 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
         // mandatory in a future draft.
         if !self.config().is_leader && req.taskprov.is_some() {
             // Store the task config in Worker memory, but don't write it through to KV.
-            let mut guarded_tasks = self
-                .isolate_state()
+            self.isolate_state()
                 .tasks
-                .write()
-                .map_err(|e| fatal_error!(err = ?e, "failed to lock tasks for writing"))?;
-            guarded_tasks.insert(task_id.clone(), task_config);
+                .insert(task_id.clone(), Some(task_config))
+                .map_err(|e| fatal_error!(err = ?e, "failed to cache task config"))?;
 
             if let Some(ref leader_bearer_token) = taskprov.leader_auth.bearer_token {
                 let mut guarded_leader_bearer_tokens = self
@@ -323,15 +414,16 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
                 guarded_leader_bearer_tokens.insert(task_id.clone(), leader_bearer_token.clone());
             }
         } else {
-            // Write the task config through to KV.
-            self.set_task_config(task_id, &task_config)
+            // Write the task config through to KV. If another request defined the task first, then
+            // its config is kept.
+            self.put_task_config(task_id, task_config, None, "taskprov")
                 .await
-                .map_err(|e| fatal_error!(err = ?e))?;
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
             if let Some(ref leader_bearer_token) = taskprov.leader_auth.bearer_token {
                 self.set_leader_bearer_token(task_id, leader_bearer_token)
                     .await
-                    .map_err(|e| fatal_error!(err = ?e))?;
+                    .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
             }
         }
 
@@ -344,38 +436,48 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
     ) -> std::result::Result<Option<Self::WrappedDapTaskConfig<'req>>, DapError> {
         self.get_task_config(task_id)
             .await
-            .map_err(|e| fatal_error!(err = ?e))
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
     }
 
-    fn get_current_time(&self) -> u64 {
-        now()
+    fn time_source(&self) -> &dyn TimeSource {
+        self.state.time_source
+    }
+
+    async fn get_collector_hpke_config_for(
+        &self,
+        task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+    ) -> std::result::Result<Option<HpkeConfig>, DapError> {
+        self.get_collector_hpke_config_override(task_id).await
     }
 
     async fn is_batch_overlapping(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param_hash: &DapAggregationParamHash,
     ) -> std::result::Result<bool, DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
 
         // Check whether the request overlaps with previous requests. This is done by
         // checking the AggregateStore and seeing whether it requests for aggregate
-        // shares that have already been marked collected.
-        let durable = self.durable().with_retry();
+        // shares that have already been marked collected with the same aggregation parameter.
+        let durable = self.durable();
         let mut requests = Vec::new();
         for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
-            let durable_name =
-                durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), &bucket);
-            requests.push(durable.get(
-                BINDING_DAP_AGGREGATE_STORE,
-                DURABLE_AGGREGATE_STORE_CHECK_COLLECTED,
-                durable_name,
-            ));
+            for durable_name in durable_names_agg_store(task_config.as_ref(), task_id, &bucket) {
+                requests.push(durable.post(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_AGGREGATE_STORE_CHECK_COLLECTED_AGG_PARAM,
+                    durable_name,
+                    agg_param_hash,
+                ));
+            }
         }
 
         let responses: Vec<bool> = try_join_all(requests)
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
         for collected in responses {
             if collected {
@@ -393,24 +495,25 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
     ) -> std::result::Result<bool, DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
 
-        let agg_share: DapAggregateShare = self
-            .durable()
-            .with_retry()
-            .get(
-                BINDING_DAP_AGGREGATE_STORE,
-                DURABLE_AGGREGATE_STORE_GET,
-                durable_name_agg_store(
-                    &task_config.as_ref().version,
-                    &task_id.to_hex(),
-                    &DapBatchBucket::FixedSize {
-                        batch_id: batch_id.clone(),
-                    },
-                ),
-            )
-            .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+        let durable = self.durable();
+        let bucket = DapBatchBucket::FixedSize {
+            batch_id: batch_id.clone(),
+        };
+        for durable_name in durable_names_agg_store(task_config.as_ref(), task_id, &bucket) {
+            let meta: DapAggregateShareMeta = durable
+                .get(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_AGGREGATE_STORE_GET_META,
+                    durable_name,
+                )
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+            if meta.report_count > 0 {
+                return Ok(true);
+            }
+        }
 
-        Ok(!agg_share.empty())
+        Ok(false)
     }
 
     async fn try_put_agg_share_span(
@@ -419,25 +522,33 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
         task_config: &DapTaskConfig,
         agg_share_span: DapAggregateShareSpan,
     ) -> std::result::Result<Option<HashSet<ReportId>>, DapError> {
-        let task_id_hex = task_id.to_hex();
-        let durable = self.durable();
+        let durable = self.durable().without_retry();
+        let now = self.get_current_time();
+        // Stores of the previous epoch duration must not expire before their epochs have passed.
+        let epoch_duration = task_config
+            .epoch_duration(&self.config().global)
+            .max(task_config.previous_epoch_duration(now).unwrap_or_default());
         let mut agg_store_request_data: HashMap<String, DapAggregateShare> = HashMap::new();
         let mut reports_processed_request_data: HashMap<String, Vec<ReportId>> = HashMap::new();
         for (bucket, (agg_share, report_metadatas)) in agg_share_span {
+            // Each report is marked as aggregated in every report store that is checked for replays
+            // of it, so that a replay with a timestamp in another store is caught atomically by the
+            // ReportsProcessed instance they have in common.
             for (id, time) in report_metadatas {
-                let reports_processed_name = self.config().durable_name_report_store(
+                for reports_processed_name in self.config().durable_names_report_stores_to_mark(
                     task_config.as_ref(),
-                    &task_id_hex,
+                    task_id,
                     &id,
                     time,
-                );
-                reports_processed_request_data
-                    .entry(reports_processed_name)
-                    .or_default()
-                    .push(id.clone());
+                    now,
+                ) {
+                    reports_processed_request_data
+                        .entry(reports_processed_name)
+                        .or_default()
+                        .push(id.clone());
+                }
             }
-            let agg_store_name =
-                durable_name_agg_store(&task_config.version, &task_id_hex, &bucket);
+            let agg_store_name = durable_name_agg_store(&task_config.version, task_id, &bucket);
 
             match agg_store_request_data.entry(agg_store_name) {
                 Entry::Occupied(mut current_agg_share) => {
@@ -459,36 +570,57 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
                         BINDING_DAP_REPORTS_PROCESSED,
                         DURABLE_REPORTS_PROCESSED_MARK_AGGREGATED,
                         durable_name,
-                        report_ids,
+                        ReportsProcessedMarkAggregatedReq {
+                            epoch_duration,
+                            report_ids,
+                        },
                     )
                     .await
             },
         ))
         .await
-        .map_err(|e| fatal_error!(err = ?e))?
+        .map_err(|e| fatal_error!(err = ?e, code = Storage))?
         .into_iter()
         .flatten()
         .collect::<HashSet<ReportId>>();
 
         // Only aggregate the output shares if none are replayed
         if replayed.is_empty() {
-            futures::stream::iter(agg_store_request_data)
-                .map(|(agg_store_name, agg_share)| async {
-                    durable
-                        .post::<_, ()>(
-                            BINDING_DAP_AGGREGATE_STORE,
-                            DURABLE_AGGREGATE_STORE_MERGE,
-                            agg_store_name,
-                            agg_share,
-                        )
-                        .await
-                })
-                .buffer_unordered(usize::MAX)
-                // poll the iterator to compleation, short
-                // circuiting on error
-                .try_for_each(|()| ready(Ok(())))
-                .await
-                .map_err(|e| fatal_error!(err = ?e))?;
+            for agg_share in agg_store_request_data.values() {
+                for (annotation, count) in &agg_share.annotations {
+                    self.state
+                        .metrics
+                        .report_annotation_counter
+                        .with_label_values(&[
+                            &self.state.host,
+                            self.config().report_annotation_label(annotation),
+                        ])
+                        .inc_by(*count);
+                }
+            }
+
+            // Send all of the deltas to one of the buckets' instances, which merges its own delta
+            // and forwards the rest.
+            let mut agg_store_request_data = agg_store_request_data.into_iter();
+            if let Some((agg_store_name, agg_share_delta)) = agg_store_request_data.next() {
+                let storage_stats = durable
+                    .post::<_, DurableStorageStats>(
+                        BINDING_DAP_AGGREGATE_STORE,
+                        DURABLE_AGGREGATE_STORE_MERGE_MANY,
+                        agg_store_name,
+                        AggregateStoreMergeManyReq {
+                            agg_share_delta,
+                            forward: agg_store_request_data.collect(),
+                        },
+                    )
+                    .await
+                    .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+                self.state.metrics.observe_durable_storage(
+                    &self.state.host,
+                    BINDING_DAP_AGGREGATE_STORE,
+                    storage_stats,
+                );
+            }
 
             Ok(None)
         } else {
@@ -503,20 +635,20 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
     ) -> std::result::Result<DapAggregateShare, DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
 
-        let durable = self.durable().with_retry();
+        let durable = self.durable();
         let mut requests = Vec::new();
         for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
-            let durable_name =
-                durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), &bucket);
-            requests.push(durable.get(
-                BINDING_DAP_AGGREGATE_STORE,
-                DURABLE_AGGREGATE_STORE_GET,
-                durable_name,
-            ));
+            for durable_name in durable_names_agg_store(task_config.as_ref(), task_id, &bucket) {
+                requests.push(durable.get(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_AGGREGATE_STORE_GET,
+                    durable_name,
+                ));
+            }
         }
         let responses: Vec<DapAggregateShare> = try_join_all(requests)
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         let mut agg_share = DapAggregateShare::default();
         for agg_share_delta in responses {
             agg_share.merge(agg_share_delta)?;
@@ -525,32 +657,265 @@ impl<'srv> DapAggregator<DaphneWorkerAuth> for DaphneWorker<'srv> {
         Ok(agg_share)
     }
 
+    async fn get_agg_share_meta(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> std::result::Result<DapAggregateShareMeta, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+
+        let durable = self.durable();
+        let mut requests = Vec::new();
+        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
+            for durable_name in durable_names_agg_store(task_config.as_ref(), task_id, &bucket) {
+                requests.push(durable.get(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_AGGREGATE_STORE_GET_META,
+                    durable_name,
+                ));
+            }
+        }
+        let responses: Vec<DapAggregateShareMeta> = try_join_all(requests)
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+        let mut meta = DapAggregateShareMeta::default();
+        for meta_delta in responses {
+            meta.merge(meta_delta);
+        }
+
+        Ok(meta)
+    }
+
+    async fn get_agg_share_per_bucket(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> std::result::Result<Vec<(DapBatchBucket, DapAggregateShare)>, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+
+        let durable = self.durable();
+        let buckets = task_config
+            .as_ref()
+            .batch_span_for_sel(batch_sel)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        let num_versions = task_config.as_ref().versions().count();
+        let mut requests = Vec::new();
+        for bucket in &buckets {
+            for durable_name in durable_names_agg_store(task_config.as_ref(), task_id, bucket) {
+                requests.push(durable.get(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_AGGREGATE_STORE_GET,
+                    durable_name,
+                ));
+            }
+        }
+        let responses: Vec<DapAggregateShare> = try_join_all(requests)
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        // The responses are in order of bucket, then version.
+        let mut responses = responses.into_iter();
+        let mut agg_shares = Vec::with_capacity(buckets.len());
+        for bucket in buckets {
+            let mut agg_share = DapAggregateShare::default();
+            for agg_share_delta in responses.by_ref().take(num_versions) {
+                agg_share.merge(agg_share_delta)?;
+            }
+            agg_shares.push((bucket, agg_share));
+        }
+        Ok(agg_shares)
+    }
+
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param_hash: &DapAggregationParamHash,
     ) -> std::result::Result<(), DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
 
         let durable = self.durable();
         let mut requests = Vec::new();
         for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
-            let durable_name =
-                durable_name_agg_store(&task_config.as_ref().version, &task_id.to_hex(), &bucket);
-            requests.push(durable.post::<_, ()>(
-                BINDING_DAP_AGGREGATE_STORE,
-                DURABLE_AGGREGATE_STORE_MARK_COLLECTED,
-                durable_name,
-                &(),
-            ));
+            for durable_name in durable_names_agg_store(task_config.as_ref(), task_id, &bucket) {
+                requests.push(durable.post::<_, ()>(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_AGGREGATE_STORE_MARK_COLLECTED,
+                    durable_name,
+                    agg_param_hash,
+                ));
+            }
         }
 
         try_join_all(requests)
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         Ok(())
     }
 
+    /// The DO instances that hold the data of the task are found by name: the report stores that
+    /// may still hold data, the batch windows that can still be collected and, on the Leader, the
+    /// fixed-size batches that have not been collected. Helper states are named by aggregation
+    /// job, so they cannot be found this way; they are deleted by their alarm.
+    ///
+    /// In the "dev" deployment, each instance that holds the data of the task is also registered
+    /// with the task's registry (see
+    /// [`GarbageCollector`](crate::durable::garbage_collector::GarbageCollector)), so tests can
+    /// purge all of them, including Helper states. Instances purged through the registry have
+    /// nothing left to delete, so nothing is counted twice.
+    async fn purge_task(
+        &self,
+        task_id: &TaskId,
+    ) -> std::result::Result<DapTaskPurgeSummary, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        let task_config = task_config.as_ref();
+        let is_leader = self.config().is_leader;
+        let durable = self.durable();
+        let now = self.get_current_time();
+
+        let registered: Vec<DapTaskPurgeSummary> =
+            try_join_all(task_config.versions().map(|version| {
+                durable.post(
+                    BINDING_DAP_GARBAGE_COLLECTOR,
+                    DURABLE_GARBAGE_COLLECTOR_PURGE_TASK,
+                    durable_name_task(&version, task_id),
+                    &(),
+                )
+            }))
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        let mut buckets = Vec::new();
+        match task_config.query {
+            DapQueryConfig::TimeInterval => {
+                let global = &self.config().global;
+                let end = now.saturating_add(global.max_batch_interval_end);
+                let mut batch_window = task_config.quantized_time_lower_bound(
+                    now.saturating_sub(global.min_batch_interval_start),
+                );
+                while batch_window < end {
+                    buckets.push(DapBatchBucket::TimeInterval { batch_window });
+                    if let Some(precision) = task_config.sub_bucket_precision() {
+                        let window_end = batch_window + task_config.time_precision;
+                        let mut sub_window = batch_window;
+                        while sub_window < window_end {
+                            buckets.push(DapBatchBucket::TimeIntervalSub {
+                                batch_window,
+                                sub_window,
+                            });
+                            sub_window += precision;
+                        }
+                    }
+                    batch_window += task_config.time_precision;
+                }
+            }
+            DapQueryConfig::FixedSize { .. } if is_leader => {
+                for version in task_config.versions() {
+                    let durable_name = durable_name_task(&version, task_id);
+                    let batch_ids: Vec<BatchId> = durable
+                        .get(
+                            BINDING_DAP_LEADER_BATCH_QUEUE,
+                            DURABLE_LEADER_BATCH_QUEUE_LIST,
+                            durable_name.clone(),
+                        )
+                        .await
+                        .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+                    durable
+                        .post::<_, u64>(
+                            BINDING_DAP_LEADER_BATCH_QUEUE,
+                            DURABLE_PURGE,
+                            durable_name,
+                            &"",
+                        )
+                        .await
+                        .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+                    buckets.extend(
+                        batch_ids
+                            .into_iter()
+                            .map(|batch_id| DapBatchBucket::FixedSize { batch_id }),
+                    );
+                }
+            }
+            // The Helper does not keep track of fixed-size batches.
+            DapQueryConfig::FixedSize { .. } => (),
+        }
+
+        let agg_share_requests = buckets
+            .iter()
+            .flat_map(|bucket| durable_names_agg_store(task_config, task_id, bucket))
+            .map(|durable_name| {
+                durable.post::<_, u64>(
+                    BINDING_DAP_AGGREGATE_STORE,
+                    DURABLE_PURGE,
+                    durable_name,
+                    &"",
+                )
+            });
+
+        let report_store_names =
+            self.config()
+                .durable_names_report_stores_for_task(task_config, task_id, now);
+        let reports_processed_requests = report_store_names.iter().map(|durable_name| {
+            durable.post::<_, u64>(
+                BINDING_DAP_REPORTS_PROCESSED,
+                DURABLE_PURGE,
+                durable_name.clone(),
+                &"processed/",
+            )
+        });
+        let reports_pending_requests =
+            report_store_names
+                .iter()
+                .filter(|_| is_leader)
+                .map(|durable_name| {
+                    durable.post::<_, u64>(
+                        BINDING_DAP_REPORTS_PENDING,
+                        DURABLE_PURGE,
+                        durable_name.clone(),
+                        &"pending/",
+                    )
+                });
+
+        let collect_jobs_request = async {
+            if !is_leader {
+                return Ok(0);
+            }
+            durable
+                .post::<_, u64>(
+                    BINDING_DAP_LEADER_COL_JOB_QUEUE,
+                    DURABLE_LEADER_COL_JOB_QUEUE_PURGE,
+                    self.config().durable_name_collect_job_queue(task_id),
+                    task_id,
+                )
+                .await
+        };
+
+        let (agg_shares, reports_processed, reports_pending, collect_jobs) = futures::try_join!(
+            try_join_all(agg_share_requests),
+            try_join_all(reports_processed_requests),
+            try_join_all(reports_pending_requests),
+            collect_jobs_request,
+        )
+        .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        let mut summary = DapTaskPurgeSummary {
+            reports_pending: reports_pending.into_iter().sum(),
+            reports_processed: reports_processed.into_iter().sum(),
+            // Each aggregate share is stored under several keys.
+            agg_shares: agg_shares.into_iter().filter(|count| *count > 0).count() as u64,
+            helper_states: 0,
+            collect_jobs,
+        };
+        for registered in registered {
+            summary.reports_pending += registered.reports_pending;
+            summary.reports_processed += registered.reports_processed;
+            summary.agg_shares += registered.agg_shares;
+            summary.helper_states += registered.helper_states;
+        }
+        Ok(summary)
+    }
+
     async fn current_batch(&self, task_id: &TaskId) -> std::result::Result<BatchId, DapError> {
         self.internal_current_batch(task_id).await
     }
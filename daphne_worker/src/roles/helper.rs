@@ -7,22 +7,41 @@ use crate::{
     auth::DaphneWorkerAuth,
     config::DaphneWorker,
     durable::{
+        durable_name_rate_limiter,
         helper_state_store::{
-            durable_helper_state_name, DURABLE_HELPER_STATE_GET,
-            DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS,
+            decode_helper_state, durable_helper_state_name, encode_helper_state, AggJobContResp,
+            HelperStateClaim, DURABLE_HELPER_STATE_ACK, DURABLE_HELPER_STATE_CLAIM,
+            DURABLE_HELPER_STATE_DELETE, DURABLE_HELPER_STATE_GET_AGG_JOB_CONT_RESP,
+            DURABLE_HELPER_STATE_PUT_AGG_JOB_CONT_RESP, DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS,
+            HELPER_STATE_CLAIM_TIMEOUT_SECS,
         },
-        BINDING_DAP_HELPER_STATE_STORE,
+        rate_limiter::{
+            RateLimiterAcquireRequest, RateLimiterAcquireResult, DURABLE_RATE_LIMITER_ACQUIRE,
+            WINDOW_DURATION,
+        },
+        BINDING_DAP_HELPER_STATE_STORE, BINDING_DAP_RATE_LIMITER,
     },
 };
 use async_trait::async_trait;
 use daphne::{
-    error::DapAbort, fatal_error, messages::TaskId, roles::DapHelper, DapError, DapHelperState,
-    MetaAggregationJobId,
+    error::DapAbort,
+    fatal_error,
+    memory_budget::MemoryBudget,
+    messages::{AggregationJobResp, TaskId},
+    roles::DapHelper,
+    DapError, DapHelperState, DapRequest, MetaAggregationJobId,
 };
-use prio::codec::Encode;
+use prio::codec::{Decode, Encode};
+
+/// Memory reserved by the aggregation jobs in flight in this isolate.
+static AGG_JOB_MEMORY_BUDGET: MemoryBudget = MemoryBudget::new();
 
 #[async_trait(?Send)]
 impl<'srv> DapHelper<DaphneWorkerAuth> for DaphneWorker<'srv> {
+    fn agg_job_memory_budget(&self) -> &MemoryBudget {
+        &AGG_JOB_MEMORY_BUDGET
+    }
+
     async fn put_helper_state_if_not_exists(
         &self,
         task_id: &TaskId,
@@ -30,47 +49,218 @@ impl<'srv> DapHelper<DaphneWorkerAuth> for DaphneWorker<'srv> {
         helper_state: &DapHelperState,
     ) -> std::result::Result<bool, DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
-        let helper_state_hex = hex::encode(helper_state.get_encoded());
+        let durable_name =
+            durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id);
+        let helper_state_hex = encode_helper_state(
+            self.config().helper_state_keys.as_ref(),
+            &durable_name,
+            &helper_state.get_encoded_versioned(&task_config.as_ref().vdaf),
+        );
         Ok(self
             .durable()
-            .with_retry()
             .post(
                 BINDING_DAP_HELPER_STATE_STORE,
                 DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS,
-                durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id),
+                durable_name,
                 helper_state_hex,
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?)
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?)
     }
 
-    async fn get_helper_state(
+    async fn claim_helper_state(
         &self,
         task_id: &TaskId,
         agg_job_id: &MetaAggregationJobId,
     ) -> std::result::Result<Option<DapHelperState>, DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
-        // TODO(cjpatton) Figure out if retry is safe, since the request is not actually
-        // idempotent. (It removes the helper's state from storage if it exists.)
-        let res: Option<String> = self
+        let durable_name =
+            durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id);
+        // NOTE The claim is not idempotent: If the response were lost and the request retried, then
+        // the retry would find the state in-flight. Hence the request is not retried; instead the
+        // Leader is asked to retry the AggregationJobContinueReq once the claim has expired.
+        let res: HelperStateClaim = self
             .durable()
-            .with_retry()
-            .get(
+            .without_retry()
+            .post(
                 BINDING_DAP_HELPER_STATE_STORE,
-                DURABLE_HELPER_STATE_GET,
-                durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id),
+                DURABLE_HELPER_STATE_CLAIM,
+                durable_name.clone(),
+                &(),
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
         match res {
-            Some(helper_state_hex) => {
-                let data = hex::decode(helper_state_hex)
-                    .map_err(|e| DapAbort::from_hex_error(e, task_id.clone()))?;
+            HelperStateClaim::Claimed(helper_state_hex) => {
+                let data = decode_helper_state(
+                    self.config().helper_state_keys.as_ref(),
+                    &durable_name,
+                    &helper_state_hex,
+                )?;
                 let helper_state = DapHelperState::get_decoded(&task_config.as_ref().vdaf, &data)?;
                 Ok(Some(helper_state))
             }
+            HelperStateClaim::InFlight => Err(DapAbort::TooManyRequests {
+                detail: "The aggregation job is being processed by another request.".into(),
+                retry_after: Some(HELPER_STATE_CLAIM_TIMEOUT_SECS),
+            }
+            .into()),
+            HelperStateClaim::NotFound => Ok(None),
+        }
+    }
+
+    async fn ack_helper_state(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> std::result::Result<(), DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        self.durable()
+            .post(
+                BINDING_DAP_HELPER_STATE_STORE,
+                DURABLE_HELPER_STATE_ACK,
+                durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id),
+                &(),
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
+    }
+
+    async fn put_agg_job_cont_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        round: u16,
+        req_digest: &[u8; 32],
+        agg_job_resp: &AggregationJobResp,
+    ) -> std::result::Result<(), DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        self.durable()
+            .post(
+                BINDING_DAP_HELPER_STATE_STORE,
+                DURABLE_HELPER_STATE_PUT_AGG_JOB_CONT_RESP,
+                durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id),
+                AggJobContResp {
+                    round,
+                    req_digest_hex: hex::encode(req_digest),
+                    agg_job_resp_hex: hex::encode(agg_job_resp.get_encoded()),
+                },
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
+    }
+
+    async fn get_agg_job_cont_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> std::result::Result<Option<(u16, [u8; 32], AggregationJobResp)>, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        let res: Option<AggJobContResp> = self
+            .durable()
+            .get(
+                BINDING_DAP_HELPER_STATE_STORE,
+                DURABLE_HELPER_STATE_GET_AGG_JOB_CONT_RESP,
+                durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id),
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        match res {
+            Some(AggJobContResp {
+                round,
+                req_digest_hex,
+                agg_job_resp_hex,
+            }) => {
+                let mut req_digest = [0; 32];
+                hex::decode_to_slice(req_digest_hex, &mut req_digest).map_err(
+                    |e| fatal_error!(err = ?e, code = Storage, "malformed stored request digest"),
+                )?;
+                let data = hex::decode(agg_job_resp_hex).map_err(
+                    |e| fatal_error!(err = ?e, code = Storage, "malformed stored AggregationJobResp"),
+                )?;
+                let agg_job_resp = AggregationJobResp::get_decoded(&data).map_err(
+                    |e| fatal_error!(err = ?e, code = Storage, "malformed stored AggregationJobResp"),
+                )?;
+                Ok(Some((round, req_digest, agg_job_resp)))
+            }
             None => Ok(None),
         }
     }
+
+    async fn delete_helper_state(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> std::result::Result<(), DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        self.durable()
+            .post(
+                BINDING_DAP_HELPER_STATE_STORE,
+                DURABLE_HELPER_STATE_DELETE,
+                durable_helper_state_name(&task_config.as_ref().version, task_id, agg_job_id),
+                &(),
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
+    }
+
+    async fn check_quota(
+        &self,
+        req: &DapRequest<DaphneWorkerAuth>,
+        reports: u64,
+    ) -> std::result::Result<(), DapAbort> {
+        let Some(ref helper_quotas) = self.config().helper_quotas else {
+            return Ok(());
+        };
+        let Some(peer_identity) = req
+            .sender_auth
+            .as_ref()
+            .and_then(|auth| auth.peer_identity())
+        else {
+            return Ok(());
+        };
+        let Some(quota) = helper_quotas.for_peer(&peer_identity) else {
+            return Ok(());
+        };
+
+        // A request with more reports than fit in a window would never be accepted, so retrying
+        // it is pointless.
+        if let Some(limit) = quota.reports_per_minute.filter(|limit| reports > *limit) {
+            return Err(DapAbort::BadRequest(format!(
+                "The request carries {reports} reports, but the report quota for this peer is \
+                 {limit} reports per minute."
+            )));
+        }
+
+        let result: RateLimiterAcquireResult = self
+            .durable()
+            .without_retry()
+            .post(
+                BINDING_DAP_RATE_LIMITER,
+                DURABLE_RATE_LIMITER_ACQUIRE,
+                durable_name_rate_limiter(&peer_identity),
+                &RateLimiterAcquireRequest {
+                    reports,
+                    quota: quota.clone(),
+                },
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        match result {
+            RateLimiterAcquireResult::Ok => Ok(()),
+            RateLimiterAcquireResult::RequestsExceeded => Err(DapAbort::TooManyRequests {
+                detail: "The request quota for this peer is exhausted; retry in a minute.".into(),
+                retry_after: Some(WINDOW_DURATION),
+            }),
+            RateLimiterAcquireResult::ReportsExceeded => Err(DapAbort::TooManyRequests {
+                detail: format!(
+                    "The report quota for this peer is exhausted; the request carries {reports} reports."
+                ),
+                retry_after: Some(WINDOW_DURATION),
+            }),
+        }
+    }
 }
@@ -8,21 +8,27 @@ use crate::{
     config::DaphneWorker,
     durable::{
         durable_name_queue, durable_name_task,
-        leader_agg_job_queue::DURABLE_LEADER_AGG_JOB_QUEUE_GET,
+        leader_agg_job_queue::{AggJobQueueGetRequest, DURABLE_LEADER_AGG_JOB_QUEUE_GET},
         leader_batch_queue::{
-            BatchCount, DURABLE_LEADER_BATCH_QUEUE_ASSIGN, DURABLE_LEADER_BATCH_QUEUE_REMOVE,
+            BatchAssignRequest, BatchAssignResult, BatchCount, BatchLimits,
+            DURABLE_LEADER_BATCH_QUEUE_ASSIGN, DURABLE_LEADER_BATCH_QUEUE_REMOVE,
+            DURABLE_LEADER_BATCH_QUEUE_STATE,
         },
         leader_col_job_queue::{
             CollectQueueRequest, DURABLE_LEADER_COL_JOB_QUEUE_FINISH,
             DURABLE_LEADER_COL_JOB_QUEUE_GET, DURABLE_LEADER_COL_JOB_QUEUE_GET_RESULT,
-            DURABLE_LEADER_COL_JOB_QUEUE_PUT,
+            DURABLE_LEADER_COL_JOB_QUEUE_LIST, DURABLE_LEADER_COL_JOB_QUEUE_PUT,
         },
         reports_pending::{
-            PendingReport, ReportsPendingResult, DURABLE_REPORTS_PENDING_GET,
-            DURABLE_REPORTS_PENDING_PUT,
+            PendingReport, ReportsPendingCheckIdenticalReq, ReportsPendingGetResp,
+            ReportsPendingResult, DURABLE_REPORTS_PENDING_CHECK_EXISTS,
+            DURABLE_REPORTS_PENDING_CHECK_IDENTICAL, DURABLE_REPORTS_PENDING_GET,
+            DURABLE_REPORTS_PENDING_PEEK, DURABLE_REPORTS_PENDING_PUT,
         },
+        reports_processed::DURABLE_REPORTS_PROCESSED_CHECK_REPLAYED,
         BINDING_DAP_LEADER_AGG_JOB_QUEUE, BINDING_DAP_LEADER_BATCH_QUEUE,
         BINDING_DAP_LEADER_COL_JOB_QUEUE, BINDING_DAP_REPORTS_PENDING,
+        BINDING_DAP_REPORTS_PROCESSED,
     },
     DaphneWorkerReportSelector,
 };
@@ -33,16 +39,24 @@ use daphne::{
     error::DapAbort,
     fatal_error,
     messages::{
-        Collection, CollectionJobId, CollectionReq, PartialBatchSelector, Report, TaskId,
-        TransitionFailure,
+        BatchId, Collection, CollectionJobId, CollectionReq, PartialBatchSelector, Report,
+        ReportId, ReportMetadata, TaskId, TransitionFailure,
     },
-    roles::{DapAuthorizedSender, DapLeader},
-    DapCollectJob, DapError, DapQueryConfig, DapRequest, DapResponse, DapTaskConfig,
+    roles::{DapAggregator, DapAuthorizedSender, DapLeader},
+    DapBatchState, DapCollectJob, DapCollectJobSummary, DapCollectPriority, DapError,
+    DapQueryConfig, DapRequest, DapResponse, DapTaskConfig, DapVersion,
 };
+use futures::{future::try_join_all, StreamExt};
 use prio::codec::{ParameterizedDecode, ParameterizedEncode};
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 use tracing::debug;
 
+/// Maximum number of reports of a bulk upload that are stored concurrently.
+const PUT_REPORT_MANY_CONCURRENCY: usize = 32;
+
 #[async_trait(?Send)]
 impl DapAuthorizedSender<DaphneWorkerAuth> for DaphneWorker<'_> {
     async fn authorize(
@@ -76,50 +90,184 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
         task_id: &TaskId,
     ) -> std::result::Result<(), DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
-        let task_id_hex = task_id.to_hex();
         let version = task_config.as_ref().version;
         let pending_report = PendingReport {
             version,
             task_id: task_id.clone(),
             report_hex: hex::encode(report.get_encoded_with_param(&version)),
+            expires_at: task_config
+                .as_ref()
+                .report_ttl
+                .map(|ttl| self.get_current_time().saturating_add(ttl)),
+            annotation: report
+                .annotation
+                .clone()
+                .or_else(|| self.state.report_annotation.borrow().clone()),
+            batch_bucket: task_config
+                .as_ref()
+                .precompute_batch_bucket(report.report_metadata.time),
         };
+        // Not retried: a retry after a lost response would be rejected as a replay.
         let res: ReportsPendingResult = self
             .durable()
+            .without_retry()
             .post(
                 BINDING_DAP_REPORTS_PENDING,
                 DURABLE_REPORTS_PENDING_PUT,
                 self.config().durable_name_report_store(
                     task_config.as_ref(),
-                    &task_id_hex,
+                    task_id,
                     &report.report_metadata.id,
                     report.report_metadata.time,
                 ),
                 &pending_report,
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
         match res {
             ReportsPendingResult::Ok => Ok(()),
             ReportsPendingResult::ErrReportExists => {
                 // NOTE This check for report replay is not definitive. It's possible for two
-                // reports with the same ID to appear in two different ReportsPending instances.
-                // The definitive check is performed by DapAggregator::check_early_reject(), which
-                // tracks all report IDs consumed for the task in ReportsProcessed. This check
-                // would be too expensive to do during the upload sub-protocol.
+                // reports with the same ID to appear in two different ReportsPending instances,
+                // for an aggregated report to be uploaded again (batch uploads are checked against
+                // ReportsProcessed by `find_duplicate_reports()`, single uploads are not), or for
+                // a report to be uploaded concurrently with that check. The definitive check is
+                // performed during aggregation, which tracks all report IDs consumed for the task
+                // in ReportsProcessed.
                 Err(DapError::Transition(TransitionFailure::ReportReplayed))
             }
         }
     }
 
+    async fn put_report_many(
+        &self,
+        reports: &[Report],
+        task_id: &TaskId,
+    ) -> std::result::Result<Vec<std::result::Result<(), DapError>>, DapError> {
+        // Each report is stored in the ReportsPending instance determined by its ID and
+        // timestamp, so the requests are independent. Issue a bounded number of them at once so
+        // that large batches don't exhaust the subrequest limit.
+        Ok(futures::stream::iter(
+            reports
+                .iter()
+                .map(|report| self.put_report(report, task_id)),
+        )
+        .buffered(PUT_REPORT_MANY_CONCURRENCY)
+        .collect()
+        .await)
+    }
+
+    async fn find_duplicate_reports(
+        &self,
+        task_id: &TaskId,
+        reports: &[&ReportMetadata],
+    ) -> std::result::Result<HashSet<ReportId>, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+
+        // Coalesce the reports pertaining to the same report store. A report is pending in the
+        // ReportsPending instance of its report store until it is drained, after which it is
+        // marked as aggregated by the ReportsProcessed instance with the same name.
+        let mut report_ids_per_store: HashMap<String, Vec<ReportId>> = HashMap::new();
+        for metadata in reports {
+            report_ids_per_store
+                .entry(self.config().durable_name_report_store(
+                    task_config.as_ref(),
+                    task_id,
+                    &metadata.id,
+                    metadata.time,
+                ))
+                .or_default()
+                .push(metadata.id.clone());
+        }
+
+        let durable = self.durable();
+        let (pending, processed) = futures::try_join!(
+            try_join_all(
+                report_ids_per_store
+                    .iter()
+                    .map(|(durable_name, report_ids)| {
+                        durable.post::<_, Vec<ReportId>>(
+                            BINDING_DAP_REPORTS_PENDING,
+                            DURABLE_REPORTS_PENDING_CHECK_EXISTS,
+                            durable_name.clone(),
+                            report_ids,
+                        )
+                    })
+            ),
+            try_join_all(
+                report_ids_per_store
+                    .iter()
+                    .map(|(durable_name, report_ids)| {
+                        durable.post::<_, Vec<ReportId>>(
+                            BINDING_DAP_REPORTS_PROCESSED,
+                            DURABLE_REPORTS_PROCESSED_CHECK_REPLAYED,
+                            durable_name.clone(),
+                            report_ids,
+                        )
+                    })
+            ),
+        )
+        .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+
+        let replayed_in_other_report_stores = self
+            .find_replays_in_other_report_stores(
+                task_id,
+                task_config.as_ref(),
+                reports.iter().map(|metadata| (&metadata.id, metadata.time)),
+            )
+            .await?;
+
+        Ok(pending
+            .into_iter()
+            .chain(processed)
+            .flatten()
+            .chain(replayed_in_other_report_stores)
+            .collect())
+    }
+
+    async fn is_identical_report_pending(
+        &self,
+        report: &Report,
+        task_id: &TaskId,
+    ) -> std::result::Result<bool, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        let version = task_config.as_ref().version;
+        let report_digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            &report.get_encoded_with_param(&version),
+        );
+        self.durable()
+            .post(
+                BINDING_DAP_REPORTS_PENDING,
+                DURABLE_REPORTS_PENDING_CHECK_IDENTICAL,
+                self.config().durable_name_report_store(
+                    task_config.as_ref(),
+                    task_id,
+                    &report.report_metadata.id,
+                    report.report_metadata.time,
+                ),
+                &ReportsPendingCheckIdenticalReq {
+                    report_id: report.report_metadata.id.clone(),
+                    report_digest_hex: hex::encode(report_digest),
+                },
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
+    }
+
     async fn get_reports(
         &self,
         report_sel: &DaphneWorkerReportSelector,
-    ) -> std::result::Result<HashMap<TaskId, HashMap<PartialBatchSelector, Vec<Report>>>, DapError>
-    {
-        let durable = self.durable();
-        // Read at most `report_sel.max_buckets` buckets from the agg job queue. The result is ordered
-        // from oldest to newest.
+    ) -> std::result::Result<
+        HashMap<(TaskId, DapVersion), HashMap<PartialBatchSelector, Vec<Report>>>,
+        DapError,
+    > {
+        // Not retried, since draining the queues is not idempotent.
+        let durable = self.durable().without_retry();
+        // Read at most `report_sel.max_agg_jobs` buckets from the agg job queue, and at most
+        // `report_sel.max_agg_jobs_per_task_per_fetch` for any one task. The buckets are taken from
+        // each task in turn, so that a task with many buckets does not starve the others.
         //
         // NOTE There is only one agg job queue for now (`queue_num == 0`). In the future, work
         // will be sharded across multiple queues.
@@ -128,18 +276,27 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
                 BINDING_DAP_LEADER_AGG_JOB_QUEUE,
                 DURABLE_LEADER_AGG_JOB_QUEUE_GET,
                 durable_name_queue(0),
-                &report_sel.max_agg_jobs,
+                &AggJobQueueGetRequest {
+                    max_agg_jobs: usize::try_from(report_sel.max_agg_jobs).unwrap_or(usize::MAX),
+                    max_agg_jobs_per_task_per_fetch: report_sel
+                        .max_agg_jobs_per_task_per_fetch
+                        .map(|max| usize::try_from(max).unwrap_or(usize::MAX)),
+                },
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
 
         // Drain at most `report_sel.max_reports` from each ReportsPending instance and group them
-        // by task.
+        // by task and by the version in which they were uploaded.
         //
         // TODO Figure out if we can safely handle each instance in parallel.
-        let mut reports_per_task: HashMap<TaskId, Vec<Report>> = HashMap::new();
+        let mut reports_per_task: HashMap<(TaskId, DapVersion), Vec<Report>> = HashMap::new();
         for reports_pending_id_hex in res.into_iter() {
-            let reports_from_durable: Vec<PendingReport> = durable
+            let ReportsPendingGetResp {
+                reports: reports_from_durable,
+                dropped_expired,
+                storage_stats,
+            } = durable
                 .post_by_id_hex(
                     BINDING_DAP_REPORTS_PENDING,
                     DURABLE_REPORTS_PENDING_GET,
@@ -147,58 +304,102 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
                     &report_sel.max_reports,
                 )
                 .await
-                .map_err(|e| fatal_error!(err = ?e))?;
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+            self.state.metrics.observe_durable_storage(
+                &self.state.host,
+                BINDING_DAP_REPORTS_PENDING,
+                storage_stats,
+            );
+            if dropped_expired > 0 {
+                self.state
+                    .metrics
+                    .daphne
+                    .with_host(&self.state.host)
+                    .report_inc_by("dropped_expired", dropped_expired);
+            }
 
             for pending_report in reports_from_durable {
                 let report_bytes = hex::decode(&pending_report.report_hex)
                     .map_err(|e| DapAbort::from_hex_error(e, pending_report.task_id.clone()))?;
 
-                let version = self
-                    .try_get_task_config(&pending_report.task_id)
-                    .await?
-                    .as_ref()
-                    .version;
-                let report = Report::get_decoded_with_param(&version, &report_bytes)
-                    .map_err(|e| DapAbort::from_codec_error(e, pending_report.task_id.clone()))?;
-                if let Some(reports) = reports_per_task.get_mut(&pending_report.task_id) {
-                    reports.push(report);
-                } else {
-                    reports_per_task.insert(pending_report.task_id.clone(), vec![report]);
+                let mut report =
+                    Report::get_decoded_with_param(&pending_report.version, &report_bytes)
+                        .map_err(|e| {
+                            DapAbort::from_codec_error(e, pending_report.task_id.clone())
+                        })?;
+                report.annotation = pending_report.annotation;
+                if let Some(batch_bucket) = pending_report.batch_bucket {
+                    self.state
+                        .precomputed_batch_buckets
+                        .borrow_mut()
+                        .insert(report.report_metadata.id.clone(), batch_bucket);
                 }
+                reports_per_task
+                    .entry((pending_report.task_id, pending_report.version))
+                    .or_default()
+                    .push(report);
             }
         }
 
-        let mut reports_per_task_part: HashMap<TaskId, HashMap<PartialBatchSelector, Vec<Report>>> =
-            HashMap::new();
-        for (task_id, mut reports) in reports_per_task.into_iter() {
+        let mut reports_per_task_part: HashMap<
+            (TaskId, DapVersion),
+            HashMap<PartialBatchSelector, Vec<Report>>,
+        > = HashMap::new();
+        for ((task_id, version), mut reports) in reports_per_task.into_iter() {
             let task_config = self
                 .get_task_config(Cow::Owned(task_id))
                 .await
-                .map_err(|e| fatal_error!(err = ?e))?
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?
                 .ok_or_else(|| fatal_error!(err = "unrecognized task"))?;
-            let task_id_hex = task_config.key().to_hex();
             let reports_per_part = reports_per_task_part
-                .entry(task_config.key().clone())
+                .entry((task_config.key().clone(), version))
                 .or_default();
             match task_config.as_ref().query {
                 DapQueryConfig::TimeInterval => {
                     reports_per_part.insert(PartialBatchSelector::TimeInterval, reports);
                 }
-                DapQueryConfig::FixedSize { .. } => {
+                DapQueryConfig::FixedSize {
+                    batch_time_limit, ..
+                } => {
                     let num_unassigned = reports.len();
-                    let batch_assignments: Vec<BatchCount> = durable
+                    let limits = BatchLimits::new(
+                        batch_time_limit,
+                        task_config.as_ref().min_batch_size,
+                    )
+                    .map_err(
+                        |e| fatal_error!(err = ?e, code = Config, "min_batch_size is too large"),
+                    )?;
+                    let BatchAssignResult {
+                        assignments,
+                        discarded,
+                    } = durable
                         .post(
                             BINDING_DAP_LEADER_BATCH_QUEUE,
                             DURABLE_LEADER_BATCH_QUEUE_ASSIGN,
-                            durable_name_task(&task_config.as_ref().version, &task_id_hex),
-                            &(task_config.as_ref().min_batch_size, num_unassigned),
+                            durable_name_task(&version, task_config.key()),
+                            &BatchAssignRequest {
+                                batch_size: limits.min_batch_size,
+                                num_unassigned,
+                                limits,
+                            },
                         )
                         .await
-                        .map_err(|e| fatal_error!(err = ?e))?;
-                    for batch_count in batch_assignments.into_iter() {
+                        .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+                    for batch_count in discarded {
+                        self.state
+                            .metrics
+                            .daphne
+                            .with_host(&self.state.host)
+                            .report_inc_by(
+                                "dropped_batch_time_limit",
+                                batch_count.report_count.try_into().unwrap(),
+                            );
+                    }
+                    for batch_count in assignments.into_iter() {
                         let BatchCount {
                             batch_id,
                             report_count,
+                            ..
                         } = batch_count;
                         reports_per_part.insert(
                             PartialBatchSelector::FixedSizeByBatchId { batch_id },
@@ -216,13 +417,13 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
             };
         }
 
-        for (task_id, reports) in reports_per_task_part.iter() {
+        for ((task_id, version), reports) in reports_per_task_part.iter() {
             let mut report_count = 0;
             for reports in reports.values() {
                 report_count += reports.len();
             }
             debug!(
-                "got {} reports for task {}",
+                "got {} reports for task {} ({version})",
                 report_count,
                 task_id.to_base64url()
             );
@@ -230,11 +431,89 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
         Ok(reports_per_task_part)
     }
 
+    async fn peek_reports(
+        &self,
+        task_id: &TaskId,
+        max_reports: u64,
+    ) -> std::result::Result<HashMap<DapVersion, Vec<Report>>, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        let durable = self.durable();
+        let now = self.get_current_time();
+
+        // Read from each ReportsPending instance that may hold reports for the task until enough
+        // reports have been read.
+        let mut reports: HashMap<DapVersion, Vec<Report>> = HashMap::new();
+        let mut remaining = usize::try_from(max_reports).unwrap_or(usize::MAX);
+        for durable_name in
+            self.config()
+                .durable_names_report_stores_for_task(task_config.as_ref(), task_id, now)
+        {
+            if remaining == 0 {
+                break;
+            }
+            let pending_reports: Vec<PendingReport> = durable
+                .post(
+                    BINDING_DAP_REPORTS_PENDING,
+                    DURABLE_REPORTS_PENDING_PEEK,
+                    durable_name,
+                    &remaining,
+                )
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+            remaining = remaining.saturating_sub(pending_reports.len());
+
+            for pending_report in pending_reports {
+                let report_bytes = hex::decode(&pending_report.report_hex)
+                    .map_err(|e| DapAbort::from_hex_error(e, pending_report.task_id.clone()))?;
+                let mut report =
+                    Report::get_decoded_with_param(&pending_report.version, &report_bytes)
+                        .map_err(|e| {
+                            DapAbort::from_codec_error(e, pending_report.task_id.clone())
+                        })?;
+                report.annotation = pending_report.annotation;
+                reports
+                    .entry(pending_report.version)
+                    .or_default()
+                    .push(report);
+            }
+        }
+        Ok(reports)
+    }
+
+    async fn batch_state(
+        &self,
+        task_id: &TaskId,
+        batch_id: &BatchId,
+    ) -> std::result::Result<Option<DapBatchState>, DapError> {
+        let task_config = self.try_get_task_config(task_id).await?;
+        let durable = self.durable();
+
+        // Each version of the task has its own batch queue. Batch IDs are random, so the batch is
+        // in at most one of them.
+        for version in task_config.as_ref().versions() {
+            let batch_state: Option<DapBatchState> = durable
+                .post(
+                    BINDING_DAP_LEADER_BATCH_QUEUE,
+                    DURABLE_LEADER_BATCH_QUEUE_STATE,
+                    durable_name_task(&version, task_id),
+                    batch_id.to_hex(),
+                )
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+            if batch_state.is_some() {
+                return Ok(batch_state);
+            }
+        }
+
+        Ok(None)
+    }
+
     async fn init_collect_job(
         &self,
         task_id: &TaskId,
         collect_job_id: &Option<CollectionJobId>,
         collect_req: &CollectionReq,
+        priority: DapCollectPriority,
     ) -> std::result::Result<worker::Url, DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
         // Try to put the request into collection job queue. If the request is overlapping
@@ -243,17 +522,19 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
             collect_req: collect_req.clone(),
             task_id: task_id.clone(),
             collect_job_id: collect_job_id.clone(),
+            priority,
         };
         let collect_id: CollectionJobId = self
             .durable()
+            .without_retry()
             .post(
                 BINDING_DAP_LEADER_COL_JOB_QUEUE,
                 DURABLE_LEADER_COL_JOB_QUEUE_PUT,
-                durable_name_queue(0),
+                self.config().durable_name_collect_job_queue(task_id),
                 &collect_queue_req,
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         debug!("assigned collect_id {collect_id}");
 
         let url = task_config.as_ref().leader_url.clone();
@@ -265,7 +546,7 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
                 task_id.to_base64url(),
                 collect_id.to_base64url(),
             ))
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Config))?;
 
         Ok(collect_uri)
     }
@@ -280,29 +561,52 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
             .post(
                 BINDING_DAP_LEADER_COL_JOB_QUEUE,
                 DURABLE_LEADER_COL_JOB_QUEUE_GET_RESULT,
-                durable_name_queue(0),
+                self.config().durable_name_collect_job_queue(task_id),
                 (&task_id, &collect_id),
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         Ok(res)
     }
 
-    async fn get_pending_collect_jobs(
+    async fn list_collect_jobs(
         &self,
-    ) -> std::result::Result<Vec<(TaskId, CollectionJobId, CollectionReq)>, DapError> {
-        let res: Vec<(TaskId, CollectionJobId, CollectionReq)> = self
+        task_id: &TaskId,
+    ) -> std::result::Result<Vec<DapCollectJobSummary>, DapError> {
+        let res: Vec<DapCollectJobSummary> = self
             .durable()
-            .get(
+            .post(
                 BINDING_DAP_LEADER_COL_JOB_QUEUE,
-                DURABLE_LEADER_COL_JOB_QUEUE_GET,
-                durable_name_queue(0),
+                DURABLE_LEADER_COL_JOB_QUEUE_LIST,
+                self.config().durable_name_collect_job_queue(task_id),
+                task_id,
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         Ok(res)
     }
 
+    async fn get_pending_collect_jobs(
+        &self,
+    ) -> std::result::Result<Vec<(TaskId, CollectionJobId, CollectionReq)>, DapError> {
+        // Each shard of the queue holds the jobs of a subset of the tasks, in order.
+        let durable = self.durable();
+        let res: Vec<Vec<(TaskId, CollectionJobId, CollectionReq)>> = try_join_all(
+            self.config()
+                .durable_names_collect_job_queue()
+                .map(|durable_name| {
+                    durable.get(
+                        BINDING_DAP_LEADER_COL_JOB_QUEUE,
+                        DURABLE_LEADER_COL_JOB_QUEUE_GET,
+                        durable_name,
+                    )
+                }),
+        )
+        .await
+        .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+        Ok(res.into_iter().flatten().collect())
+    }
+
     async fn finish_collect_job(
         &self,
         task_id: &TaskId,
@@ -310,30 +614,34 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
         collect_resp: &Collection,
     ) -> std::result::Result<(), DapError> {
         let task_config = self.try_get_task_config(task_id).await?;
-        let durable = self.durable();
+        let durable = self.durable().without_retry();
         if let PartialBatchSelector::FixedSizeByBatchId { ref batch_id } =
             collect_resp.part_batch_sel
         {
-            durable
-                .post(
-                    BINDING_DAP_LEADER_BATCH_QUEUE,
-                    DURABLE_LEADER_BATCH_QUEUE_REMOVE,
-                    durable_name_task(&task_config.as_ref().version, &task_id.to_hex()),
-                    batch_id.to_hex(),
-                )
-                .await
-                .map_err(|e| fatal_error!(err = ?e))?;
+            // Each version of the task has its own batch queue. Batch IDs are random, so the batch
+            // is in at most one of them.
+            for version in task_config.as_ref().versions() {
+                durable
+                    .post(
+                        BINDING_DAP_LEADER_BATCH_QUEUE,
+                        DURABLE_LEADER_BATCH_QUEUE_REMOVE,
+                        durable_name_task(&version, task_id),
+                        batch_id.to_hex(),
+                    )
+                    .await
+                    .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
+            }
         }
 
         durable
             .post(
                 BINDING_DAP_LEADER_COL_JOB_QUEUE,
                 DURABLE_LEADER_COL_JOB_QUEUE_FINISH,
-                durable_name_queue(0),
+                self.config().durable_name_collect_job_queue(task_id),
                 (task_id, collect_id, collect_resp),
             )
             .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?;
         Ok(())
     }
 
@@ -341,13 +649,24 @@ impl<'srv> DapLeader<DaphneWorkerAuth> for DaphneWorker<'srv> {
         &self,
         req: DapRequest<DaphneWorkerAuth>,
     ) -> std::result::Result<DapResponse, DapError> {
-        self.send_http(req, false).await
+        self.send_http(req, reqwest_wasm::Method::POST).await
     }
 
     async fn send_http_put(
         &self,
         req: DapRequest<DaphneWorkerAuth>,
     ) -> std::result::Result<DapResponse, DapError> {
-        self.send_http(req, true).await
+        self.send_http(req, reqwest_wasm::Method::PUT).await
+    }
+
+    async fn send_http_delete(
+        &self,
+        req: DapRequest<DaphneWorkerAuth>,
+    ) -> std::result::Result<DapResponse, DapError> {
+        self.send_http(req, reqwest_wasm::Method::DELETE).await
+    }
+
+    async fn sleep(&self, duration: std::time::Duration) {
+        worker::Delay::from(duration).await
     }
 }
@@ -10,13 +10,13 @@ mod aggregator;
 mod helper;
 mod leader;
 
-use crate::config::{BearerTokenKvPair, DaphneWorker};
+use crate::config::{BearerTokenKvPair, DaphneWorker, HpkeReceiverConfigEntry};
 use async_trait::async_trait;
 use daphne::{
     auth::BearerTokenProvider,
     fatal_error,
-    hpke::{HpkeConfig, HpkeDecrypter},
-    messages::{HpkeCiphertext, TaskId, TransitionFailure},
+    hpke::{HpkeConfig, HpkeDecrypter, HpkePrivateKeyStore},
+    messages::{HpkeCiphertextRef, TaskId, TransitionFailure},
     roles::DapAggregator,
     DapError, DapTaskConfig, DapVersion,
 };
@@ -34,13 +34,32 @@ impl<'srv> HpkeDecrypter for DaphneWorker<'srv> {
         self.get_hpke_receiver_config(version, |receiver_config_list| {
             // Assume the first HPKE config in the receiver list has the highest preference.
             //
-            // NOTE draft02 compatibility: The spec allows us to return multiple configs, but
-            // draft02 does not. In order to keep things imple we preserve the semantics of the old
-            // version for now.
+            // NOTE draft02 compatibility: draft02 only allows us to advertise a single config.
+            // Later versions use `get_hpke_config_list_for()` instead.
             receiver_config_list
                 .iter()
                 .next()
-                .map(|receiver| receiver.config.clone())
+                .map(|receiver| receiver.config().clone())
+        })
+        .await
+        .map_err(|e| fatal_error!(err = ?e, "failed to get list of hpke key configs in kv"))?
+        .ok_or_else(|| fatal_error!(err = "there are no hpke configs in kv!!", %version))
+    }
+
+    async fn get_hpke_config_list_for(
+        &self,
+        version: DapVersion,
+        _task_id: Option<&TaskId>,
+    ) -> std::result::Result<Vec<HpkeConfig>, DapError> {
+        // Advertise every config in the receiver list, in order of preference, so that Clients
+        // can continue to use an old config while a new one is being rolled out.
+        self.get_hpke_receiver_config(version, |receiver_config_list| {
+            (!receiver_config_list.is_empty()).then(|| {
+                receiver_config_list
+                    .iter()
+                    .map(|receiver| receiver.config().clone())
+                    .collect()
+            })
         })
         .await
         .map_err(|e| fatal_error!(err = ?e, "failed to get list of hpke key configs in kv"))?
@@ -57,11 +76,11 @@ impl<'srv> HpkeDecrypter for DaphneWorker<'srv> {
             .get_hpke_receiver_config(version, |config_list| {
                 config_list
                     .iter()
-                    .find(|receiver| receiver.config.id == config_id)
+                    .find(|receiver| receiver.config().id == config_id)
                     .map(|_| ())
             })
             .await
-            .map_err(|e| fatal_error!(err = ?e))?
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?
             .is_some())
     }
 
@@ -70,18 +89,47 @@ impl<'srv> HpkeDecrypter for DaphneWorker<'srv> {
         task_id: &TaskId,
         info: &[u8],
         aad: &[u8],
-        ciphertext: &HpkeCiphertext,
+        ciphertext: HpkeCiphertextRef<'_>,
     ) -> std::result::Result<Vec<u8>, DapError> {
         let version = self.try_get_task_config(task_id).await?.as_ref().version;
-        self.get_hpke_receiver_config(version, |config_list| {
-            config_list
-                .iter()
-                .find(|receiver| receiver.config.id == ciphertext.config_id)
-                .map(|receiver| receiver.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload))
-        })
-        .await
-        .map_err(|e| fatal_error!(err = ?e))?
-        .ok_or_else(|| DapError::Transition(TransitionFailure::HpkeUnknownConfigId))?
+        let config = match self
+            .get_hpke_receiver_config(version, |config_list| {
+                config_list
+                    .iter()
+                    .find(|receiver| receiver.config().id == ciphertext.config_id)
+                    .map(|receiver| match receiver {
+                        HpkeReceiverConfigEntry::WithPrivateKey(receiver) => {
+                            Ok(receiver.decrypt(info, aad, ciphertext.enc, ciphertext.payload))
+                        }
+                        HpkeReceiverConfigEntry::PublicOnly { config } => Err(config.clone()),
+                    })
+            })
+            .await
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))?
+            .ok_or_else(|| DapError::Transition(TransitionFailure::HpkeUnknownConfigId))?
+        {
+            Ok(plaintext) => return plaintext,
+            Err(config) => config,
+        };
+
+        // The private key is not stored in KV.
+        let stores = [
+            self.state.hpke_private_key_store,
+            Some(&self.config().hpke_private_keys as &dyn HpkePrivateKeyStore),
+        ];
+        for store in stores.into_iter().flatten() {
+            if let Some(plaintext) = store
+                .hpke_decrypt_with(&config, info, aad, ciphertext.enc, ciphertext.payload)
+                .await?
+            {
+                return Ok(plaintext);
+            }
+        }
+        Err(fatal_error!(
+            err = "no private key for HPKE config",
+            config_id = config.id,
+            code = Config
+        ))
     }
 }
 
@@ -106,7 +154,7 @@ impl<'srv> BearerTokenProvider for DaphneWorker<'srv> {
 
         self.get_leader_bearer_token(task_id)
             .await
-            .map_err(|e| fatal_error!(err = ?e))
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
     }
 
     async fn get_collector_bearer_token_for<'s>(
@@ -129,6 +177,6 @@ impl<'srv> BearerTokenProvider for DaphneWorker<'srv> {
 
         self.get_collector_bearer_token(task_id)
             .await
-            .map_err(|e| fatal_error!(err = ?e))
+            .map_err(|e| fatal_error!(err = ?e, code = Storage))
     }
 }
@@ -1,20 +1,237 @@
-use daphne::roles::DapAggregator;
-use tracing::Instrument;
+use daphne::{
+    auth::BearerToken,
+    error::DapAbort,
+    messages::TaskId,
+    postprocess::{
+        export_result, DapEnrichedAggregateResult, DapFixedPointToFloat, DapLabelBuckets,
+        DapResultExportFormat, DapResultTransform,
+    },
+    roles::DapAggregator,
+    DapAggregateResult, DapTaskConfig,
+};
+use serde::Deserialize;
+use tracing::{info, info_span, Instrument};
+use worker::{Headers, Request, Response, Result, RouteContext};
 
-use crate::info_span_from_dap_request;
+use crate::{
+    config::{DaphneWorker, DaphneWorkerConfig, DaphneWorkerRequestState, TaskConfigPutResult},
+    info_span_from_dap_request,
+};
 
 use super::{dap_response_to_worker, DapRouter};
 
 pub(super) fn add_aggregator_routes(router: DapRouter<'_>) -> DapRouter<'_> {
-    router.get_async("/:version/hpke_config", |req, ctx| async move {
-        let daph = ctx.data.handler(&ctx.env);
-        let req = daph.worker_request_to_dap(req, &ctx).await?;
+    router
+        .get_async("/:version/hpke_config", |req, ctx| async move {
+            let daph = ctx.data.handler(&ctx.env);
+            let req = match daph.worker_request_to_dap(req, &ctx).await? {
+                Ok(req) => req,
+                Err(e) => return daph.state.dap_abort_to_worker_response(e),
+            };
 
-        let span = info_span_from_dap_request!("hpke_config", req);
+            let span = info_span_from_dap_request!("hpke_config", req);
 
-        match daph.handle_hpke_config_req(&req).instrument(span).await {
-            Ok(req) => dap_response_to_worker(req),
-            Err(e) => daph.state.dap_abort_to_worker_response(e),
-        }
-    })
+            match daph.handle_hpke_config_req(&req).instrument(span).await {
+                Ok(req) => dap_response_to_worker(daph.state, req),
+                Err(e) => daph.state.dap_abort_to_worker_response(e),
+            }
+        })
+        .get_async("/:version/capabilities", |_req, ctx| async move {
+            // Unknown and disabled versions are rejected before the request is routed.
+            let daph = ctx.data.handler(&ctx.env);
+            Response::from_json(&daph.config().global.capabilities())
+        })
+        .post_async("/internal/purge/task/:task_id", |req, ctx| async move {
+            // Delete all data stored for the task (but not the task itself). The request must
+            // carry the admin bearer token.
+            let daph = ctx.data.handler(&ctx.env);
+            let task_id = match admin_task_id(&daph, &req, &ctx)? {
+                Ok(task_id) => task_id,
+                Err(resp) => return Ok(resp),
+            };
+
+            match daph
+                .purge_task(&task_id)
+                .instrument(info_span!("purge_task", dap.task_id = %task_id))
+                .await
+            {
+                Ok(summary) => {
+                    info!(?summary, "purged task {task_id}");
+                    Response::from_json(&summary)
+                }
+                Err(e) => daph.state.dap_abort_to_worker_response(e.into()),
+            }
+        })
+        .get_async("/internal/task_config/:task_id", |req, ctx| async move {
+            // Get the current revision of the task's configuration.
+            let daph = ctx.data.handler(&ctx.env);
+            let task_id = match admin_task_id(&daph, &req, &ctx)? {
+                Ok(task_id) => task_id,
+                Err(resp) => return Ok(resp),
+            };
+            match daph.get_task_config_record(&task_id).await? {
+                Some(record) => Response::from_json(&record),
+                None => daph
+                    .state
+                    .dap_abort_to_worker_response(DapAbort::UnrecognizedTask),
+            }
+        })
+        .put_async(
+            "/internal/task_config/:task_id",
+            |mut req, ctx| async move {
+                // Write a new revision of the task's configuration. If the current revision is not
+                // the expected one, then the current revision is returned with status 409.
+                let daph = ctx.data.handler(&ctx.env);
+                let task_id = match admin_task_id(&daph, &req, &ctx)? {
+                    Ok(task_id) => task_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let cmd: TaskConfigPutRequest = req.json().await?;
+                match daph
+                    .put_task_config(&task_id, cmd.task_config, cmd.expected_revision, "admin")
+                    .await?
+                {
+                    TaskConfigPutResult::Ok(record) => {
+                        info!(
+                            revision = record.revision,
+                            "updated config of task {task_id}"
+                        );
+                        Response::from_json(&record)
+                    }
+                    TaskConfigPutResult::Conflict(current) => {
+                        Ok(Response::from_json(&current)?.with_status(409))
+                    }
+                }
+            },
+        )
+        .get_async(
+            "/internal/task_config/:task_id/history",
+            |req, ctx| async move {
+                // List the revisions of the task's configuration, oldest first.
+                let daph = ctx.data.handler(&ctx.env);
+                let task_id = match admin_task_id(&daph, &req, &ctx)? {
+                    Ok(task_id) => task_id,
+                    Err(resp) => return Ok(resp),
+                };
+                Response::from_json(&daph.get_task_config_history(&task_id).await?)
+            },
+        )
+        .post_async(
+            "/internal/export_result/:task_id",
+            |mut req, ctx| async move {
+                // Render a decrypted aggregate result for the task in the format indicated by the
+                // "format" query parameter (JSON by default).
+                let daph = ctx.data.handler(&ctx.env);
+                let task_id = match admin_task_id(&daph, &req, &ctx)? {
+                    Ok(task_id) => task_id,
+                    Err(resp) => return Ok(resp),
+                };
+                let format = match req
+                    .url()?
+                    .query_pairs()
+                    .find(|(k, _)| k == "format")
+                    .map(|(_, v)| v.parse::<DapResultExportFormat>())
+                    .transpose()
+                {
+                    Ok(format) => format.unwrap_or_default(),
+                    Err(e) => {
+                        return daph
+                            .state
+                            .dap_abort_to_worker_response(DapAbort::BadRequest(e.to_string()))
+                    }
+                };
+                let cmd: ExportResultRequest = req.json().await?;
+                let Some(record) = daph.get_task_config_record(&task_id).await? else {
+                    return daph
+                        .state
+                        .dap_abort_to_worker_response(DapAbort::UnrecognizedTask);
+                };
+                let vdaf = &record.task_config.vdaf;
+
+                let mut result = DapEnrichedAggregateResult::from(cmd.result);
+                let exported = cmd
+                    .labels
+                    .map(|labels| DapLabelBuckets(labels).apply(&mut result))
+                    .transpose()
+                    .and_then(|_| {
+                        cmd.frac_bits
+                            .map(|frac_bits| DapFixedPointToFloat { frac_bits }.apply(&mut result))
+                            .transpose()
+                    })
+                    .and_then(|_| export_result(&task_id, vdaf, cmd.report_count, &result, format));
+                match exported {
+                    Ok(body) => {
+                        let mut headers = Headers::new();
+                        headers.set("Content-Type", format.content_type())?;
+                        Ok(Response::ok(body)?.with_headers(headers))
+                    }
+                    Err(e) => daph
+                        .state
+                        .dap_abort_to_worker_response(DapAbort::BadRequest(e.to_string())),
+                }
+            },
+        )
+}
+
+/// Body of a request to export an aggregate result.
+#[derive(Deserialize)]
+struct ExportResultRequest {
+    /// Number of reports aggregated into the result.
+    report_count: u64,
+    result: DapAggregateResult,
+    /// Label for each entry of the result. By default, entries are labeled by their index.
+    #[serde(default)]
+    labels: Option<Vec<String>>,
+    /// If set, then each entry is also converted from fixed-point with this many fractional bits.
+    #[serde(default)]
+    frac_bits: Option<u32>,
+}
+
+/// Body of a request to write a new revision of a task's configuration.
+#[derive(Deserialize)]
+struct TaskConfigPutRequest {
+    /// The revision the writer last read, or `None` if the task is being created.
+    expected_revision: Option<u64>,
+    task_config: DapTaskConfig,
+}
+
+/// Parse the task ID from the path of an administrative request and check that the request
+/// carries the admin bearer token. If either check fails, then the response to send is returned.
+fn admin_task_id(
+    daph: &DaphneWorker<'_>,
+    req: &Request,
+    ctx: &RouteContext<&DaphneWorkerRequestState<'_>>,
+) -> Result<std::result::Result<TaskId, Response>> {
+    let Some(task_id) = ctx.param("task_id").and_then(TaskId::try_from_base64url) else {
+        return daph
+            .state
+            .dap_abort_to_worker_response(DapAbort::BadRequest(
+                "missing or malformed task ID".into(),
+            ))
+            .map(Err);
+    };
+    if !is_admin_authorized(daph.config(), req)? {
+        return daph
+            .state
+            .dap_abort_to_worker_response(DapAbort::UnauthorizedRequest {
+                detail: "missing or invalid admin bearer token".into(),
+                task_id,
+            })
+            .map(Err);
+    }
+    Ok(Ok(task_id))
+}
+
+/// Check that the request carries the admin bearer token in its "Authorization" header. Requests
+/// are never authorized if no admin token is configured.
+fn is_admin_authorized(config: &DaphneWorkerConfig, req: &Request) -> Result<bool> {
+    let Some(expected) = &config.admin_bearer_token else {
+        return Ok(false);
+    };
+    Ok(req
+        .headers()
+        .get("Authorization")?
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|got| BearerToken::from(got).ct_eq(expected)))
 }
@@ -22,6 +22,10 @@ pub(super) fn add_helper_routes(router: DapRouter<'_>) -> DapRouter<'_> {
             "/:version/tasks/:task_id/aggregation_jobs/:agg_job_id",
             handle_agg_job,
         )
+        .delete_async(
+            "/:version/tasks/:task_id/aggregation_jobs/:agg_job_id",
+            handle_agg_job_delete,
+        )
         .post_async(
             "/:version/tasks/:task_id/aggregate_shares",
             handle_agg_share_req,
@@ -33,7 +37,10 @@ async fn handle_agg_job(
     ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
 ) -> Result<Response> {
     let daph = ctx.data.handler(&ctx.env);
-    let req = daph.worker_request_to_dap(req, &ctx).await?;
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
 
     let span = match req.media_type {
         DapMediaType::AggregationJobInitReq => {
@@ -46,7 +53,27 @@ async fn handle_agg_job(
     };
 
     match daph.handle_agg_job_req(&req).instrument(span).await {
-        Ok(resp) => dap_response_to_worker(resp),
+        Ok(resp) => dap_response_to_worker(daph.state, resp),
+        Err(e) => daph.state.dap_abort_to_worker_response(e),
+    }
+}
+
+/// Handle a DELETE request for an aggregation job. Like abandoning an aggregation job, this only
+/// frees resources, so it is not charged to the quota (see [`DapHelper::check_quota`]).
+async fn handle_agg_job_delete(
+    req: Request,
+    ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
+) -> Result<Response> {
+    let daph = ctx.data.handler(&ctx.env);
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
+
+    let span = info_span_from_dap_request!("aggregate_delete", req);
+
+    match daph.handle_agg_job_delete_req(&req).instrument(span).await {
+        Ok(..) => Ok(Response::empty()?.with_status(204)),
         Err(e) => daph.state.dap_abort_to_worker_response(e),
     }
 }
@@ -56,12 +83,15 @@ async fn handle_agg_share_req(
     ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
 ) -> Result<Response> {
     let daph = ctx.data.handler(&ctx.env);
-    let req = daph.worker_request_to_dap(req, &ctx).await?;
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
 
     let span = info_span_from_dap_request!(MeasuredSpanName::AggregateShares.as_str(), req);
 
     match daph.handle_agg_share_req(&req).instrument(span).await {
-        Ok(resp) => dap_response_to_worker(resp),
+        Ok(resp) => dap_response_to_worker(daph.state, resp),
         Err(e) => daph.state.dap_abort_to_worker_response(e),
     }
 }
@@ -6,7 +6,7 @@ use daphne::{
     error::DapAbort,
     messages::{CollectionJobId, TaskId},
     roles::DapLeader,
-    DapCollectJob, DapResponse, DapVersion,
+    DapCollectJob, DapResource, DapResponse, DapVersion,
 };
 use prio::codec::ParameterizedEncode;
 use tracing::{info_span, Instrument};
@@ -20,9 +20,32 @@ pub(super) fn add_leader_routes(router: DapRouter<'_>) -> DapRouter<'_> {
     router
         .post_async("/v02/upload", put_report_into_task) // draft02
         .put_async("/:version/tasks/:task_id/reports", put_report_into_task)
+        .put_async(
+            "/:version/tasks/:task_id/reports/:report_id",
+            put_report_with_id_into_task,
+        )
+        // Custom methods, e.g., "reports:batch", can't be registered as routes of their own, since
+        // the router treats ":" as the start of a parameter. They are matched here instead.
+        .post_async(
+            "/:version/tasks/:task_id/:custom_method",
+            |req, ctx| async move {
+                match ctx.param("custom_method").map(String::as_str) {
+                    Some("reports:batch") => put_report_batch_into_task(req, ctx).await,
+                    Some("collection_jobs:precheck") => precheck_collection_job(req, ctx).await,
+                    _ => Response::error("Not Found", 404),
+                }
+            },
+        )
+        .get_async(
+            "/:version/tasks/:task_id/collection_jobs",
+            list_collection_jobs,
+        )
         .post_async("/v02/collect", |req, ctx| async move {
             let daph = ctx.data.handler(&ctx.env);
-            let req = daph.worker_request_to_dap(req, &ctx).await?;
+            let req = match daph.worker_request_to_dap(req, &ctx).await? {
+                Ok(req) => req,
+                Err(e) => return daph.state.dap_abort_to_worker_response(e),
+            };
 
             let span = info_span_from_dap_request!("collect", req);
 
@@ -67,11 +90,14 @@ pub(super) fn add_leader_routes(router: DapRouter<'_>) -> DapRouter<'_> {
                     .instrument(info_span!("poll_collect_job (draft02)"))
                     .await
                 {
-                    Ok(DapCollectJob::Done(collect_resp)) => dap_response_to_worker(DapResponse {
-                        version: DapVersion::Draft02,
-                        media_type: DapMediaType::Collection,
-                        payload: collect_resp.get_encoded_with_param(&version),
-                    }),
+                    Ok(DapCollectJob::Done(collect_resp)) => dap_response_to_worker(
+                        daph.state,
+                        DapResponse {
+                            version: DapVersion::Draft02,
+                            media_type: DapMediaType::Collection,
+                            payload: collect_resp.get_encoded_with_param(&version).into(),
+                        },
+                    ),
                     Ok(DapCollectJob::Pending) => Ok(Response::empty().unwrap().with_status(202)),
                     // TODO spec: Decide whether to define this behavior.
                     Ok(DapCollectJob::Unknown) => {
@@ -88,7 +114,10 @@ pub(super) fn add_leader_routes(router: DapRouter<'_>) -> DapRouter<'_> {
             "/:version/tasks/:task_id/collection_jobs/:collect_job_id",
             |req, ctx| async move {
                 let daph = ctx.data.handler(&ctx.env);
-                let req = daph.worker_request_to_dap(req, &ctx).await?;
+                let req = match daph.worker_request_to_dap(req, &ctx).await? {
+                    Ok(req) => req,
+                    Err(e) => return daph.state.dap_abort_to_worker_response(e),
+                };
 
                 let span = info_span_from_dap_request!("collect (PUT)", req);
 
@@ -102,27 +131,18 @@ pub(super) fn add_leader_routes(router: DapRouter<'_>) -> DapRouter<'_> {
             "/:version/tasks/:task_id/collection_jobs/:collect_job_id",
             |req, ctx| async move {
                 let daph = ctx.data.handler(&ctx.env);
-                let req = daph.worker_request_to_dap(req, &ctx).await?;
+                let req = match daph.worker_request_to_dap(req, &ctx).await? {
+                    Ok(req) => req,
+                    Err(e) => return daph.state.dap_abort_to_worker_response(e),
+                };
                 let task_id = match req.task_id() {
                     Ok(id) => id,
                     Err(e) => return daph.state.dap_abort_to_worker_response(e),
                 };
-                // We cannot check a resource here as the resource is set via
-                // media type, and there is no media type when polling.
-                //
-                // We can unwrap() here as the parameter really must exist.
-                let collect_job_id_base64url = ctx.param("collect_job_id").unwrap();
-                let collect_job_id =
-                    match CollectionJobId::try_from_base64url(collect_job_id_base64url) {
-                        Some(id) => id,
-                        None => {
-                            return daph
-                                .state
-                                .dap_abort_to_worker_response(DapAbort::BadRequest(
-                                    "malformed collect id".into(),
-                                ))
-                        }
-                    };
+                let collect_job_id = match req.collection_job_id() {
+                    Ok(id) => id,
+                    Err(e) => return daph.state.dap_abort_to_worker_response(e),
+                };
 
                 let span = info_span!(
                     "poll_collect_job",
@@ -131,15 +151,18 @@ pub(super) fn add_leader_routes(router: DapRouter<'_>) -> DapRouter<'_> {
                 );
 
                 match daph
-                    .poll_collect_job(task_id, &collect_job_id)
+                    .poll_collect_job(task_id, collect_job_id)
                     .instrument(span)
                     .await
                 {
-                    Ok(DapCollectJob::Done(collect_resp)) => dap_response_to_worker(DapResponse {
-                        version: req.version,
-                        media_type: DapMediaType::Collection,
-                        payload: collect_resp.get_encoded_with_param(&req.version),
-                    }),
+                    Ok(DapCollectJob::Done(collect_resp)) => dap_response_to_worker(
+                        daph.state,
+                        DapResponse {
+                            version: req.version,
+                            media_type: DapMediaType::Collection,
+                            payload: collect_resp.get_encoded_with_param(&req.version).into(),
+                        },
+                    ),
                     Ok(DapCollectJob::Pending) => Ok(Response::empty().unwrap().with_status(202)),
                     // TODO spec: Decide whether to define this behavior.
                     Ok(DapCollectJob::Unknown) => {
@@ -159,12 +182,105 @@ async fn put_report_into_task(
     ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
 ) -> Result<Response> {
     let daph = ctx.data.handler(&ctx.env);
-    let req = daph.worker_request_to_dap(req, &ctx).await?;
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
 
     let span = info_span_from_dap_request!("upload", req);
 
     match daph.handle_upload_req(&req).instrument(span).await {
-        Ok(()) => Response::empty(),
+        Ok(Some(receipt_resp)) => dap_response_to_worker(daph.state, receipt_resp),
+        Ok(None) => Response::empty(),
+        Err(e) => daph.state.dap_abort_to_worker_response(e),
+    }
+}
+
+async fn put_report_with_id_into_task(
+    req: Request,
+    ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
+) -> Result<Response> {
+    let daph = ctx.data.handler(&ctx.env);
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
+    if !matches!(req.resource, DapResource::Report(..)) {
+        return daph
+            .state
+            .dap_abort_to_worker_response(DapAbort::BadRequest("malformed report id".into()));
+    }
+
+    let span = info_span_from_dap_request!("upload (PUT)", req);
+
+    match daph.handle_upload_req(&req).instrument(span).await {
+        Ok(Some(receipt_resp)) => dap_response_to_worker(daph.state, receipt_resp),
+        Ok(None) => Response::empty(),
+        Err(e) => daph.state.dap_abort_to_worker_response(e),
+    }
+}
+
+async fn put_report_batch_into_task(
+    req: Request,
+    ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
+) -> Result<Response> {
+    let daph = ctx.data.handler(&ctx.env);
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
+
+    let span = info_span_from_dap_request!("upload_batch", req);
+
+    match daph.handle_upload_batch_req(&req).instrument(span).await {
+        Ok(resp) => dap_response_to_worker(daph.state, resp),
+        Err(e) => daph.state.dap_abort_to_worker_response(e),
+    }
+}
+
+async fn precheck_collection_job(
+    req: Request,
+    ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
+) -> Result<Response> {
+    let daph = ctx.data.handler(&ctx.env);
+    let req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
+
+    let span = info_span_from_dap_request!("collect_precheck", req);
+
+    match daph
+        .handle_collect_job_precheck_req(&req)
+        .instrument(span)
+        .await
+    {
+        Ok(precheck) => Response::from_json(&precheck),
+        Err(e) => daph.state.dap_abort_to_worker_response(e),
+    }
+}
+
+async fn list_collection_jobs(
+    req: Request,
+    ctx: RouteContext<&DaphneWorkerRequestState<'_>>,
+) -> Result<Response> {
+    let daph = ctx.data.handler(&ctx.env);
+    let mut req = match daph.worker_request_to_dap(req, &ctx).await? {
+        Ok(req) => req,
+        Err(e) => return daph.state.dap_abort_to_worker_response(e),
+    };
+
+    // The request has no payload, so authorize it as coming from the Collector.
+    req.media_type = DapMediaType::CollectReq;
+
+    let span = info_span_from_dap_request!("list_collect_jobs", req);
+
+    match daph
+        .handle_list_collect_jobs_req(&req)
+        .instrument(span)
+        .await
+    {
+        Ok(summaries) => Response::from_json(&summaries),
         Err(e) => daph.state.dap_abort_to_worker_response(e),
     }
 }
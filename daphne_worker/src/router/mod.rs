@@ -8,9 +8,12 @@ pub mod test_routes;
 
 use std::str::FromStr;
 
-use daphne::DapResponse;
+use daphne::{
+    constants::DapMediaType, error::DapAbort, DapResource, DapResponse, DapResponsePayload,
+    DapVersion,
+};
 use serde::Deserialize;
-use worker::{Error, Headers, Response, Result, Router};
+use worker::{Error, Headers, Request, Response, Result, Router};
 
 use crate::{config::DaphneWorkerRequestState, DEFAULT_RESPONSE_HTML};
 
@@ -88,8 +91,108 @@ pub(super) fn create_router<'s>(
     Ok(router)
 }
 
-fn dap_response_to_worker(resp: DapResponse) -> Result<Response> {
+/// The path prefix under which each DAP version is served.
+const VERSION_PATH_PREFIXES: [(&str, DapVersion); 2] =
+    [("v02", DapVersion::Draft02), ("v07", DapVersion::Draft07)];
+
+/// Resolve the DAP version selected by the first segment of a request path. Returns `None` if the
+/// path is not versioned and `Some(DapVersion::Unknown)` if the segment looks like a version (i.e.,
+/// "v" followed by digits) but no version is registered for it.
+pub(crate) fn version_from_path(path: &str) -> Option<DapVersion> {
+    let segment = path.trim_start_matches('/').split('/').next()?;
+    if let Some((_, version)) = VERSION_PATH_PREFIXES
+        .iter()
+        .find(|(prefix, _)| *prefix == segment)
+    {
+        return Some(*version);
+    }
+
+    segment
+        .strip_prefix('v')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        .then_some(DapVersion::Unknown)
+}
+
+/// Indicates if a request path is for an internal endpoint rather than a DAP endpoint, i.e., if its
+/// first segment, or its second segment if the first selects a version, is "internal".
+pub(crate) fn is_internal_path(path: &str) -> bool {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match segments.next() {
+        Some("internal") => true,
+        Some(..) if version_from_path(path).is_some() => segments.next() == Some("internal"),
+        _ => false,
+    }
+}
+
+/// Reject a request for a DAP version that is unknown or that has been disabled by the deployment.
+/// If the request should be rejected, then the return value is the problem document to respond
+/// with. Requests for unversioned paths are passed through to the router.
+pub(super) fn reject_unsupported_version(
+    state: &DaphneWorkerRequestState<'_>,
+    req: &Request,
+) -> Result<Option<Response>> {
+    let abort = match version_from_path(req.url()?.path()) {
+        Some(DapVersion::Unknown) => DapAbort::version_unknown(),
+        Some(version)
+            if !state
+                .isolate_state
+                .config
+                .global
+                .is_version_enabled(version) =>
+        {
+            DapAbort::version_disabled(version)
+        }
+        _ => return Ok(None),
+    };
+    state.dap_abort_to_worker_response(abort).map(Some)
+}
+
+/// Reject a request to a versioned path whose content-type is malformed or is not defined for the
+/// version. If the request should be rejected, then the return value is the problem document to
+/// respond with. A missing content-type is left for the handler to reject, as some requests don't
+/// have a body. Internal endpoints (see [`is_internal_path`]) are not DAP endpoints and are exempt.
+pub(super) fn reject_invalid_content_type(
+    state: &DaphneWorkerRequestState<'_>,
+    req: &Request,
+) -> Result<Option<Response>> {
+    let url = req.url()?;
+    if is_internal_path(url.path()) {
+        return Ok(None);
+    }
+    let Some(version) = version_from_path(url.path()) else {
+        return Ok(None);
+    };
+    let content_type = req.headers().get("Content-Type")?;
+    match DapMediaType::parse(version, content_type.as_deref()) {
+        Ok(..) => Ok(None),
+        Err(abort) => state.dap_abort_to_worker_response(abort).map(Some),
+    }
+}
+
+/// Reject a request whose path indicates a task resource with a malformed task ID or resource ID
+/// (see [`DapResource::parse_path`]). If the request should be rejected, then the return value is
+/// the problem document to respond with.
+pub(super) fn reject_malformed_resource(
+    state: &DaphneWorkerRequestState<'_>,
+    req: &Request,
+) -> Result<Option<Response>> {
+    match DapResource::parse_path(req.url()?.path()) {
+        Ok(..) => Ok(None),
+        Err(abort) => state.dap_abort_to_worker_response(abort).map(Some),
+    }
+}
+
+fn dap_response_to_worker(
+    state: &DaphneWorkerRequestState<'_>,
+    resp: DapResponse,
+) -> Result<Response> {
     let mut headers = Headers::new();
+    if let Some(encoding) = state.response_encoding.get() {
+        // The Workers runtime compresses the body of a response according to its
+        // "content-encoding" header, so setting the header is all that is needed here.
+        headers.set("Content-Encoding", encoding.as_str())?;
+        headers.set("Vary", "Accept-Encoding")?;
+    }
     headers.set(
         "Content-Type",
         resp.media_type
@@ -101,8 +204,15 @@ fn dap_response_to_worker(resp: DapResponse) -> Result<Response> {
                 ))
             })?,
     )?;
-    let worker_resp = Response::from_bytes(resp.payload)?.with_headers(headers);
-    Ok(worker_resp)
+    let worker_resp = match resp.payload {
+        DapResponsePayload::Bytes(payload) => Response::from_bytes(payload)?,
+        // Hand each chunk to the runtime as the body is read, so that a very large response (e.g.,
+        // an `AggregationJobResp`) is never held both here and by the runtime.
+        DapResponsePayload::Chunks(chunks) => Response::from_stream(futures::stream::iter(
+            chunks.into_iter().map(Ok::<_, Error>),
+        ))?,
+    };
+    Ok(worker_resp.with_headers(headers))
 }
 
 #[macro_export]
@@ -122,3 +232,36 @@ macro_rules! info_span_from_dap_request {
         )
     }};
 }
+
+#[cfg(test)]
+mod test {
+    use super::{is_internal_path, version_from_path};
+    use daphne::DapVersion;
+
+    #[test]
+    fn version_from_path_resolves_prefix() {
+        assert_eq!(version_from_path("/v02/upload"), Some(DapVersion::Draft02));
+        assert_eq!(
+            version_from_path("/v07/tasks/abc/reports"),
+            Some(DapVersion::Draft07)
+        );
+        assert_eq!(
+            version_from_path("/v99/hpke_config"),
+            Some(DapVersion::Unknown)
+        );
+        assert_eq!(version_from_path("/internal/test/ready"), None);
+        assert_eq!(version_from_path("/v"), None);
+        assert_eq!(version_from_path("/"), None);
+    }
+
+    #[test]
+    fn is_internal_path_with_optional_version() {
+        assert!(is_internal_path("/internal/test/ready"));
+        assert!(is_internal_path("/v07/internal/test/add_task"));
+        assert!(is_internal_path("/v02/internal/test/add_hpke_config"));
+        assert!(!is_internal_path("/v07/tasks/abc/reports"));
+        assert!(!is_internal_path("/v07/upload/internal"));
+        assert!(!is_internal_path("/tasks/internal/test"));
+        assert!(!is_internal_path("/"));
+    }
+}
@@ -1,19 +1,31 @@
 // Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use std::collections::HashMap;
+
 use daphne::{
     error::DapAbort,
     hpke::HpkeReceiverConfig,
-    messages::{Duration, TaskId, Time},
+    messages::{TaskId, Time},
+    provisioning::DapTaskDescription,
     roles::DapLeader,
+    DapVersion,
 };
-use serde::Deserialize;
-use tracing::{debug, info_span, Instrument};
-use worker::{Response, Url};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info_span, warn, Instrument};
+use worker::{Env, Error, Request, Response, Result};
 
-use crate::DaphneWorkerReportSelector;
+use crate::{
+    config::{DaphneWorker, DaphneWorkerDeployment, DaphneWorkerRequestState},
+    durable::{
+        command_nonce_store::{CommandNonceClaimRequest, DURABLE_COMMAND_NONCE_STORE_CLAIM},
+        durable_name_command_nonce_store, DurableReferenceFilter, BINDING_DAP_COMMAND_NONCE_STORE,
+    },
+    runtime_now, DaphneWorkerReportSelector,
+};
 
-use super::{DapRouter, Role};
+use super::{is_internal_path, DapRouter, Role};
 
 pub(super) fn add_internal_test_routes(router: DapRouter<'_>, role: Role) -> DapRouter<'_> {
     let router = if role.is_leader() {
@@ -60,6 +72,32 @@ pub(super) fn add_internal_test_routes(router: DapRouter<'_>, role: Role) -> Dap
                     }
                 },
             )
+            .post_async(
+                "/internal/dry_run/task/:task_id",
+                |mut req, ctx| async move {
+                    // Run the aggregation flow for a sample of the task's pending reports without
+                    // committing anything. The task ID is encoded in URL-safe base64.
+                    let daph = ctx.data.handler(&ctx.env);
+                    let task_id =
+                        match ctx.param("task_id").and_then(TaskId::try_from_base64url) {
+                            Some(id) => id,
+                            None => {
+                                return daph.state.dap_abort_to_worker_response(
+                                    DapAbort::BadRequest("missing or malformed task ID".into()),
+                                )
+                            }
+                        };
+                    let cmd: InternalTestDryRun = req.json().await?;
+                    match daph
+                        .dry_run_agg_job(&task_id, cmd.max_reports, &daph.state.host)
+                        .instrument(info_span!("dry_run", dap.task_id = %task_id))
+                        .await
+                    {
+                        Ok(dry_run) => Response::from_json(&dry_run),
+                        Err(e) => daph.state.dap_abort_to_worker_response(e),
+                    }
+                },
+            )
     } else {
         router
     };
@@ -75,6 +113,87 @@ pub(super) fn add_internal_test_routes(router: DapRouter<'_>, role: Role) -> Dap
                 Err(e) => daph.state.dap_abort_to_worker_response(e.into()),
             }
         })
+        .post_async("/internal/delete/task/:task_id", |_req, ctx| async move {
+            // Delete the durable objects storage of a single task. The task ID is encoded in
+            // URL-safe base64.
+            let daph = ctx.data.handler(&ctx.env);
+            let Some(task_id) = ctx.param("task_id").and_then(TaskId::try_from_base64url) else {
+                return daph
+                    .state
+                    .dap_abort_to_worker_response(DapAbort::BadRequest(
+                        "missing or malformed task ID".into(),
+                    ));
+            };
+            let filter = DurableReferenceFilter {
+                task_id: Some(task_id.clone()),
+                version: None,
+            };
+            match daph
+                .internal_delete_matching(filter)
+                .instrument(info_span!("delete_task", dap.task_id = %task_id))
+                .await
+            {
+                Ok(count) => Response::from_json(&count),
+                Err(e) => daph.state.dap_abort_to_worker_response(e.into()),
+            }
+        })
+        .post_async(
+            "/internal/delete/version/:version",
+            |_req, ctx| async move {
+                // Delete the durable objects storage of every task in the given DAP version, e.g.,
+                // "v07".
+                let daph = ctx.data.handler(&ctx.env);
+                let version = match ctx.param("version").map(|v| DapVersion::from(v.as_str())) {
+                    Some(DapVersion::Unknown) | None => {
+                        return daph
+                            .state
+                            .dap_abort_to_worker_response(DapAbort::BadRequest(
+                                "missing or unrecognized DAP version".into(),
+                            ))
+                    }
+                    Some(version) => version,
+                };
+                let filter = DurableReferenceFilter {
+                    task_id: None,
+                    version: Some(version),
+                };
+                match daph
+                    .internal_delete_matching(filter)
+                    .instrument(info_span!("delete_version"))
+                    .await
+                {
+                    Ok(count) => Response::from_json(&count),
+                    Err(e) => daph.state.dap_abort_to_worker_response(e.into()),
+                }
+            },
+        )
+        .post_async("/internal/advance_time", |mut req, ctx| async move {
+            // Move the virtual clock forward and run the alarms of the durable objects that are
+            // then due.
+            let daph = ctx.data.handler(&ctx.env);
+            let cmd: InternalTestAdvanceTime = req.json().await?;
+            match daph
+                .internal_advance_time(cmd.secs)
+                .instrument(info_span!("advance_time"))
+                .await
+            {
+                Ok((now, alarms_fired)) => {
+                    Response::from_json(&InternalTestAdvanceTimeResult { now, alarms_fired })
+                }
+                Err(e) => daph.state.dap_abort_to_worker_response(e.into()),
+            }
+        })
+        .post_async(
+            "/internal/task_config_cache/bust",
+            |mut req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                let cmd: InternalTestBustTaskConfigCache = req.json().await?;
+                match daph.internal_bust_task_config_cache(cmd) {
+                    Ok(()) => Response::empty(),
+                    Err(e) => daph.state.dap_abort_to_worker_response(e.into()),
+                }
+            },
+        )
         // Endpoints for draft-dcook-ppm-dap-interop-test-design-02
         .post_async("/internal/test/ready", |_req, _ctx| async move {
             Response::from_json(&())
@@ -83,98 +202,926 @@ pub(super) fn add_internal_test_routes(router: DapRouter<'_>, role: Role) -> Dap
             "/internal/test/endpoint_for_task",
             |mut req, ctx| async move {
                 let daph = ctx.data.handler(&ctx.env);
-                let cmd: InternalTestEndpointForTask = req.json().await?;
-                daph.internal_endpoint_for_task(daph.config().default_version, cmd)
+                let version = daph.config().default_version;
+                interop_response(
+                    async {
+                        let cmd: InternalTestEndpointForTask = req.json().await?;
+                        let endpoint = daph.internal_endpoint_for_task(version, cmd).await?;
+                        Ok(serde_json::json!({ "endpoint": endpoint }))
+                    }
                     .instrument(info_span!("endpoint_for_task"))
-                    .await
+                    .await,
+                )
             },
         )
         .post_async(
             "/:version/internal/test/endpoint_for_task",
             |mut req, ctx| async move {
                 let daph = ctx.data.handler(&ctx.env);
-                let cmd: InternalTestEndpointForTask = req.json().await?;
-                let version = daph.extract_version_parameter(&req)?;
-                daph.internal_endpoint_for_task(version, cmd)
+                interop_response(
+                    async {
+                        let version = daph.extract_version_parameter(&req)?;
+                        let cmd: InternalTestEndpointForTask = req.json().await?;
+                        let endpoint = daph.internal_endpoint_for_task(version, cmd).await?;
+                        Ok(serde_json::json!({ "endpoint": endpoint }))
+                    }
                     .instrument(info_span!("endpoint_for_task"))
-                    .await
+                    .await,
+                )
             },
         )
-        .post_async("/internal/test/add_task", |mut req, ctx| async move {
+        .post_async("/internal/test/add_task", |req, ctx| async move {
+            let daph = ctx.data.handler(&ctx.env);
+            let version = daph.config().default_version;
+            interop_response(
+                add_task(&daph, version, req)
+                    .instrument(info_span!("add_task"))
+                    .await,
+            )
+        })
+        .post_async("/:version/internal/test/add_task", |req, ctx| async move {
             let daph = ctx.data.handler(&ctx.env);
-            let cmd: InternalTestAddTask = req.json().await?;
-            daph.internal_add_task(daph.config().default_version, cmd)
+            interop_response(
+                async {
+                    let version = daph.extract_version_parameter(&req)?;
+                    add_task(&daph, version, req).await
+                }
                 .instrument(info_span!("add_task"))
-                .await?;
-            Response::from_json(&serde_json::json!({
-                "status": "success",
-            }))
+                .await,
+            )
         })
-        .post_async(
-            "/:version/internal/test/add_task",
-            |mut req, ctx| async move {
-                let daph = ctx.data.handler(&ctx.env);
-                let cmd: InternalTestAddTask = req.json().await?;
-                let version = daph.extract_version_parameter(&req)?;
-                daph.internal_add_task(version, cmd)
-                    .instrument(info_span!("add_task"))
-                    .await?;
-                Response::from_json(&serde_json::json!({
-                    "status": "success",
-                }))
-            },
-        )
         .post_async(
             "/:version/internal/test/add_hpke_config",
             |mut req, ctx| async move {
                 let daph = ctx.data.handler(&ctx.env);
-                let hpke: HpkeReceiverConfig = req.json().await?;
-                let version = daph.extract_version_parameter(&req)?;
-                daph.internal_add_hpke_config(version, hpke)
+                interop_response(
+                    async {
+                        let version = daph.extract_version_parameter(&req)?;
+                        let hpke: HpkeReceiverConfig = req.json().await?;
+                        daph.internal_add_hpke_config(version, hpke).await?;
+                        Ok(serde_json::json!({}))
+                    }
                     .instrument(info_span!("add_hpke_config"))
-                    .await?;
-                Response::from_json(&serde_json::json!({
-                    "status": "success",
-                }))
+                    .await,
+                )
             },
-        )
+        );
+    #[cfg(feature = "interop-test")]
+    let router = interop::add_client_routes(router);
+    #[cfg(feature = "interop-test")]
+    let router = interop::add_collector_routes(router);
+    router
+}
+
+/// Respond to a command of the interop test API. The API reports failures in the response body
+/// rather than with an error status, so an error is turned into a response with status "error".
+/// On success, the status is "success" unless `body`, which must be an object, sets one.
+fn interop_response(result: Result<serde_json::Value>) -> Result<Response> {
+    let body = match result {
+        Ok(mut body) => {
+            if body.get("status").is_none() {
+                body["status"] = "success".into();
+            }
+            body
+        }
+        Err(e) => serde_json::json!({
+            "status": "error",
+            "error": e.to_string(),
+        }),
+    };
+    Response::from_json(&body)
+}
+
+/// Add the task described by the body of `req`. With the "interop-test" feature, a description
+/// without a role is a task for which the worker stands in for the Collector.
+async fn add_task(
+    daph: &DaphneWorker<'_>,
+    version: DapVersion,
+    mut req: Request,
+) -> Result<serde_json::Value> {
+    let cmd: serde_json::Value = req.json().await?;
+    #[cfg(feature = "interop-test")]
+    if cmd.get("role").is_none() {
+        return interop::add_collector_task(daph, version, cmd).await;
+    }
+    let cmd: DapTaskDescription =
+        serde_json::from_value(cmd).map_err(|e| Error::RustError(e.to_string()))?;
+    daph.internal_add_task(version, cmd).await?;
+    Ok(serde_json::json!({}))
+}
+
+/// Endpoints of the interop test API for the Client and the Collector. These allow the worker to
+/// stand in for the Client and Collector containers of draft-dcook-ppm-dap-interop-test-design-02.
+#[cfg(feature = "interop-test")]
+mod interop {
+    use async_trait::async_trait;
+    use daphne::{
+        client::{parse_retry_after, DapClient, DapClientResponse, DapClientTransport},
+        constants::DapMediaType,
+        fatal_error,
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{
+            decode_base64url_vec, encode_base64url, BatchId, BatchSelector, Collection,
+            CollectionJobId, CollectionReq, Duration, Interval, PartialBatchSelector, Query,
+            TaskId, Time,
+        },
+        provisioning::{DapTaskDescriptionVdaf, DapUploadDescription},
+        roles::DapAggregator,
+        DapAggregateResult, DapError, DapVersion, VdafConfig,
+    };
+    use prio::codec::{Encode, ParameterizedDecode, ParameterizedEncode};
+    use reqwest_wasm::header::{CONTENT_TYPE, RETRY_AFTER};
+    use serde::{Deserialize, Serialize};
+    use tracing::{info_span, Instrument};
+    use worker::{Delay, Error, Response, Result, Url};
+
+    use super::{interop_response, DapRouter};
+    use crate::config::DaphneWorker;
+
+    /// KV key prefix of a task for which the worker stands in for the Collector.
+    const KV_KEY_PREFIX_COLLECTOR_TASK: &str = "interop/collector_task";
+
+    /// KV key prefix of a collection job started by the Collector. The key is suffixed with the
+    /// handle of the job.
+    const KV_KEY_PREFIX_COLLECTION_JOB: &str = "interop/collection_job";
+
+    pub(super) fn add_client_routes(router: DapRouter<'_>) -> DapRouter<'_> {
+        router
+            .post_async("/internal/test/upload", |mut req, ctx| async move {
+                let daph = ctx.data.handler(&ctx.env);
+                let cmd: DapUploadDescription = req.json().await?;
+                upload(daph.config().default_version, cmd, daph.get_current_time()).await
+            })
+            .post_async(
+                "/:version/internal/test/upload",
+                |mut req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    let cmd: DapUploadDescription = req.json().await?;
+                    let version = daph.extract_version_parameter(&req)?;
+                    upload(version, cmd, daph.get_current_time()).await
+                },
+            )
+    }
+
+    /// Routes of the Collector. The Collector's tasks are added by "add_task" (see
+    /// [`add_collector_task`]). The DAP version is that of the task, so the versioned and
+    /// unversioned routes are the same.
+    pub(super) fn add_collector_routes(router: DapRouter<'_>) -> DapRouter<'_> {
+        router
+            .post_async(
+                "/internal/test/collection_start",
+                |mut req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    interop_response(
+                        async { collection_start(&daph, req.json().await?).await }
+                            .instrument(info_span!("collection_start"))
+                            .await,
+                    )
+                },
+            )
+            .post_async(
+                "/:version/internal/test/collection_start",
+                |mut req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    interop_response(
+                        async { collection_start(&daph, req.json().await?).await }
+                            .instrument(info_span!("collection_start"))
+                            .await,
+                    )
+                },
+            )
+            .post_async(
+                "/internal/test/collection_poll",
+                |mut req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    interop_response(
+                        async { collection_poll(&daph, req.json().await?).await }
+                            .instrument(info_span!("collection_poll"))
+                            .await,
+                    )
+                },
+            )
+            .post_async(
+                "/:version/internal/test/collection_poll",
+                |mut req, ctx| async move {
+                    let daph = ctx.data.handler(&ctx.env);
+                    interop_response(
+                        async { collection_poll(&daph, req.json().await?).await }
+                            .instrument(info_span!("collection_poll"))
+                            .await,
+                    )
+                },
+            )
+    }
+
+    /// Shard the measurement and upload the report to the Leader. Failures are reported in the
+    /// response body, as the interop test API requires.
+    async fn upload(version: DapVersion, cmd: DapUploadDescription, now: Time) -> Result<Response> {
+        let result = async {
+            let (config, measurement) = cmd.import(version).map_err(dap_err)?;
+            let client = DapClient::new(WorkerClientTransport::default(), config);
+            let report_id = client
+                .upload(measurement, cmd.time.unwrap_or(now))
+                .await
+                .map_err(dap_err)?;
+            Ok(serde_json::json!({ "report_id": report_id.to_base64url() }))
+        }
+        .instrument(info_span!("upload", dap.task_id = %cmd.task_id))
+        .await;
+        interop_response(result)
+    }
+
+    /// Body of "add_task" for the Collector.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    struct CollectorTaskDescription {
+        task_id: String, // base64url
+        leader: Url,
+        vdaf: DapTaskDescriptionVdaf,
+        collector_authentication_token: String,
+        /// 1 for time-interval and 2 for fixed-size.
+        query_type: u8,
+    }
+
+    /// A task of the Collector, as stored in KV.
+    #[derive(Deserialize, Serialize)]
+    struct CollectorTask {
+        version: DapVersion,
+        leader: Url,
+        vdaf: VdafConfig,
+        collector_authentication_token: String,
+        query_type: u8,
+        hpke_receiver_config: HpkeReceiverConfig,
+    }
+
+    /// Body of "collection_start".
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    struct CollectionStart {
+        task_id: String, // base64url
+        #[serde(default)]
+        agg_param: String, // base64url
+        query: CollectionStartQuery,
+    }
+
+    /// Query of "collection_start". The fields that are required depend on the query type.
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    struct CollectionStartQuery {
+        /// 1 for time-interval and 2 for fixed-size.
+        #[serde(rename = "type")]
+        typ: u8,
+        #[serde(default)]
+        batch_interval_start: Option<Time>,
+        #[serde(default)]
+        batch_interval_duration: Option<Duration>,
+        /// Fixed-size: 0 to collect the batch with the given ID and 1 for the current batch.
+        #[serde(default)]
+        subtype: Option<u8>,
+        #[serde(default)]
+        batch_id: Option<String>, // base64url
+    }
+
+    /// Body of "collection_poll".
+    #[derive(Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    struct CollectionPoll {
+        handle: String,
+    }
+
+    /// A collection job started by the Collector, as stored in KV.
+    #[derive(Deserialize, Serialize)]
+    struct CollectionJob {
+        task_id: TaskId,
+        query: Query,
+        /// The URI at which the Leader is polled for the result.
+        uri: Url,
+    }
+
+    /// Store a task for which the worker stands in for the Collector. The response carries the
+    /// HPKE config, generated for the task, to which the Aggregators encrypt their aggregate
+    /// shares.
+    pub(super) async fn add_collector_task(
+        daph: &DaphneWorker<'_>,
+        version: DapVersion,
+        cmd: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        let cmd: CollectorTaskDescription =
+            serde_json::from_value(cmd).map_err(|e| Error::RustError(e.to_string()))?;
+        let task_id = parse_task_id(&cmd.task_id)?;
+        if !matches!(cmd.query_type, 1 | 2) {
+            return Err(Error::RustError(format!(
+                "unrecognized query type: {}",
+                cmd.query_type
+            )));
+        }
+
+        let hpke_receiver_config =
+            HpkeReceiverConfig::gen(rand::random(), HpkeKemId::X25519HkdfSha256)
+                .map_err(dap_err)?;
+        let collector_hpke_config = encode_base64url(hpke_receiver_config.config.get_encoded());
+        let task = CollectorTask {
+            version,
+            leader: cmd.leader,
+            vdaf: cmd.vdaf.parse().map_err(dap_err)?,
+            collector_authentication_token: cmd.collector_authentication_token,
+            query_type: cmd.query_type,
+            hpke_receiver_config,
+        };
+        daph.kv()?
+            .put(&format!("{KV_KEY_PREFIX_COLLECTOR_TASK}/{task_id}"), &task)?
+            .execute()
+            .await?;
+
+        Ok(serde_json::json!({ "collector_hpke_config": collector_hpke_config }))
+    }
+
+    /// Send a collect request to the Leader. The response carries the handle with which the
+    /// result is polled.
+    async fn collection_start(
+        daph: &DaphneWorker<'_>,
+        cmd: CollectionStart,
+    ) -> Result<serde_json::Value> {
+        let task_id = parse_task_id(&cmd.task_id)?;
+        let task = get_collector_task(daph, &task_id).await?;
+        let query = cmd.query.parse()?;
+        let query_type = match query {
+            Query::TimeInterval { .. } => 1,
+            Query::FixedSizeByBatchId { .. } | Query::FixedSizeCurrentBatch => 2,
+        };
+        if query_type != task.query_type {
+            return Err(Error::RustError("query does not match the task".into()));
+        }
+
+        let collect_req = CollectionReq {
+            draft02_task_id: (task.version == DapVersion::Draft02).then(|| task_id.clone()),
+            query: query.clone(),
+            agg_param: decode_base64url_vec(&cmd.agg_param).ok_or_else(|| {
+                Error::RustError("aggregation parameter is not valid URL-safe base64".into())
+            })?,
+        };
+        let payload = collect_req.get_encoded_with_param(&task.version);
+
+        let http_client = reqwest_wasm::Client::new();
+        let collection_job_id = CollectionJobId(rand::random());
+        let uri = if task.version == DapVersion::Draft02 {
+            // The Leader redirects the request to the collect URI. The redirect is followed by
+            // `fetch()`, so the URL of the response is the collect URI.
+            let resp = send_to_leader(
+                &task,
+                http_client
+                    .post(join(&task.leader, "collect")?)
+                    .body(payload),
+            )
+            .await?;
+            resp.url().clone()
+        } else {
+            let uri = join(
+                &task.leader,
+                &format!(
+                    "tasks/{}/collection_jobs/{}",
+                    task_id.to_base64url(),
+                    collection_job_id.to_base64url()
+                ),
+            )?;
+            send_to_leader(&task, http_client.put(uri.clone()).body(payload)).await?;
+            uri
+        };
+
+        let handle = collection_job_id.to_base64url();
+        daph.kv()?
+            .put(
+                &format!("{KV_KEY_PREFIX_COLLECTION_JOB}/{handle}"),
+                &CollectionJob {
+                    task_id,
+                    query,
+                    uri,
+                },
+            )?
+            .execute()
+            .await?;
+
+        Ok(serde_json::json!({ "handle": handle }))
+    }
+
+    /// Poll the Leader for the result of a collection job. Once the job is complete, the
+    /// aggregate shares are decrypted and unsharded.
+    async fn collection_poll(
+        daph: &DaphneWorker<'_>,
+        cmd: CollectionPoll,
+    ) -> Result<serde_json::Value> {
+        let job: CollectionJob = daph
+            .kv()?
+            .get(&format!("{KV_KEY_PREFIX_COLLECTION_JOB}/{}", cmd.handle))
+            .json()
+            .await?
+            .ok_or_else(|| Error::RustError("unrecognized handle".into()))?;
+        let task = get_collector_task(daph, &job.task_id).await?;
+
+        let http_client = reqwest_wasm::Client::new();
+        let req = if task.version == DapVersion::Draft02 {
+            http_client.get(job.uri)
+        } else {
+            http_client.post(job.uri)
+        };
+        let resp = send_to_leader(&task, req).await?;
+        if resp.status() == 202 {
+            return Ok(serde_json::json!({ "status": "in progress" }));
+        }
+
+        let payload = resp
+            .bytes()
+            .await
+            .map_err(|e| Error::RustError(format!("failed to read response: {e}")))?;
+        let collection = Collection::get_decoded_with_param(&task.version, &payload)
+            .map_err(|e| Error::RustError(format!("failed to decode collection: {e}")))?;
+        let (batch_sel, interval) = match (job.query, collection.part_batch_sel) {
+            (Query::TimeInterval { batch_interval }, PartialBatchSelector::TimeInterval) => (
+                BatchSelector::TimeInterval {
+                    batch_interval: batch_interval.clone(),
+                },
+                // Not set in draft02, in which case the interval is that of the query.
+                collection.interval.unwrap_or(batch_interval),
+            ),
+            (
+                Query::FixedSizeByBatchId { .. } | Query::FixedSizeCurrentBatch,
+                PartialBatchSelector::FixedSizeByBatchId { batch_id },
+            ) => (
+                BatchSelector::FixedSizeByBatchId { batch_id },
+                collection.interval.unwrap_or_default(),
+            ),
+            _ => {
+                return Err(Error::RustError(
+                    "collection does not match the query".into(),
+                ))
+            }
+        };
+        let agg_res = task
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &task.hpke_receiver_config,
+                &job.task_id,
+                &batch_sel,
+                collection.report_count,
+                collection.encrypted_agg_shares,
+                task.version,
+            )
+            .await
+            .map_err(dap_err)?;
+
+        // Integers are encoded as decimal strings.
+        let result: serde_json::Value = match agg_res {
+            DapAggregateResult::U64(res) => res.to_string().into(),
+            DapAggregateResult::U128(res) => res.to_string().into(),
+            DapAggregateResult::U32Vec(res) => res.iter().map(ToString::to_string).collect(),
+            DapAggregateResult::U128Vec(res) => res.iter().map(ToString::to_string).collect(),
+        };
+        let mut body = serde_json::json!({
+            "status": "complete",
+            "report_count": collection.report_count,
+            "interval_start": interval.start,
+            "interval_duration": interval.duration,
+            "result": result,
+        });
+        if let BatchSelector::FixedSizeByBatchId { batch_id } = batch_sel {
+            body["batch_id"] = batch_id.to_base64url().into();
+        }
+        Ok(body)
+    }
+
+    impl CollectionStartQuery {
+        fn parse(&self) -> Result<Query> {
+            let missing = |name: &str| Error::RustError(format!("query is missing {name}"));
+            match (self.typ, self.subtype) {
+                (1, _) => Ok(Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: self
+                            .batch_interval_start
+                            .ok_or_else(|| missing("batch_interval_start"))?,
+                        duration: self
+                            .batch_interval_duration
+                            .ok_or_else(|| missing("batch_interval_duration"))?,
+                    },
+                }),
+                (2, Some(0)) => Ok(Query::FixedSizeByBatchId {
+                    batch_id: self
+                        .batch_id
+                        .as_ref()
+                        .and_then(BatchId::try_from_base64url)
+                        .ok_or_else(|| missing("a valid batch_id"))?,
+                }),
+                (2, Some(1)) => Ok(Query::FixedSizeCurrentBatch),
+                (typ, subtype) => Err(Error::RustError(format!(
+                    "unrecognized query type: {typ} (subtype {subtype:?})"
+                ))),
+            }
+        }
+    }
+
+    async fn get_collector_task(
+        daph: &DaphneWorker<'_>,
+        task_id: &TaskId,
+    ) -> Result<CollectorTask> {
+        daph.kv()?
+            .get(&format!("{KV_KEY_PREFIX_COLLECTOR_TASK}/{task_id}"))
+            .json()
+            .await?
+            .ok_or_else(|| Error::RustError(format!("unrecognized task: {task_id}")))
+    }
+
+    /// Send a request of the Collector to the Leader, authenticated with the task's bearer token.
+    /// A response other than a success is an error.
+    async fn send_to_leader(
+        task: &CollectorTask,
+        req: reqwest_wasm::RequestBuilder,
+    ) -> Result<reqwest_wasm::Response> {
+        let content_type = DapMediaType::CollectReq
+            .as_str_for_version(task.version)
+            .ok_or_else(|| Error::RustError(format!("unsupported version: {}", task.version)))?;
+        let resp = req
+            .header(CONTENT_TYPE, content_type)
+            .header("DAP-Auth-Token", &task.collector_authentication_token)
+            .send()
+            .await
+            .map_err(|e| Error::RustError(format!("request to the Leader failed: {e}")))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let detail = resp.text().await.unwrap_or_default();
+            return Err(Error::RustError(format!(
+                "Leader responded with status {status}: {detail}"
+            )));
+        }
+        Ok(resp)
+    }
+
+    fn parse_task_id(task_id: &str) -> Result<TaskId> {
+        TaskId::try_from_base64url(task_id)
+            .ok_or_else(|| Error::RustError("task ID is not valid URL-safe base64".into()))
+    }
+
+    fn join(base: &Url, path: &str) -> Result<Url> {
+        base.join(path)
+            .map_err(|e| Error::RustError(format!("failed to construct URL: {e}")))
+    }
+
+    fn dap_err(e: DapError) -> Error {
+        Error::RustError(e.to_string())
+    }
+
+    /// [`DapClientTransport`] implemented with the worker's `fetch()`.
+    #[derive(Default)]
+    struct WorkerClientTransport {
+        http_client: reqwest_wasm::Client,
+    }
+
+    impl WorkerClientTransport {
+        async fn send(
+            &self,
+            req: reqwest_wasm::RequestBuilder,
+        ) -> std::result::Result<DapClientResponse, DapError> {
+            let resp = req
+                .send()
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Network, "request failed"))?;
+            let status = resp.status().as_u16();
+            let retry_after = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            let payload = resp
+                .bytes()
+                .await
+                .map_err(|e| fatal_error!(err = ?e, code = Network, "failed to read response"))?
+                .to_vec();
+            Ok(DapClientResponse {
+                status,
+                payload,
+                retry_after,
+            })
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl DapClientTransport for WorkerClientTransport {
+        async fn get(&self, url: Url) -> std::result::Result<DapClientResponse, DapError> {
+            self.send(self.http_client.get(url)).await
+        }
+
+        async fn post(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> std::result::Result<DapClientResponse, DapError> {
+            self.send(
+                self.http_client
+                    .post(url)
+                    .header(CONTENT_TYPE, content_type)
+                    .body(payload),
+            )
+            .await
+        }
+
+        async fn put(
+            &self,
+            url: Url,
+            content_type: &'static str,
+            payload: Vec<u8>,
+        ) -> std::result::Result<DapClientResponse, DapError> {
+            self.send(
+                self.http_client
+                    .put(url)
+                    .header(CONTENT_TYPE, content_type)
+                    .body(payload),
+            )
+            .await
+        }
+
+        async fn sleep(&self, duration: std::time::Duration) {
+            Delay::from(duration).await;
+        }
+    }
 }
 
+/// Request to evict a task from the task config cache. If `task_id` is not set, then the cache
+/// is cleared. The cache is per isolate, so this only affects the isolate that handles the
+/// request; other isolates keep serving their cached entries until the TTL elapses.
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
-pub(crate) struct InternalTestEndpointForTask {
-    pub role: super::Role,
+pub(crate) struct InternalTestBustTaskConfigCache {
+    #[serde(default)]
+    pub task_id: Option<String>, // base64url
+}
+
+/// Request for a dry run of the aggregation flow for a task.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct InternalTestDryRun {
+    /// Maximum number of pending reports to include in the sample.
+    pub max_reports: u64,
 }
 
+/// Request to move the virtual clock forward.
 #[derive(Deserialize)]
-pub(crate) struct InternalTestVdaf {
-    #[serde(rename = "type")]
-    pub typ: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub bits: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub length: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub chunk_length: Option<String>,
+#[serde(rename_all = "snake_case")]
+pub(crate) struct InternalTestAdvanceTime {
+    /// Number of seconds by which to move the clock forward.
+    pub secs: u64,
+}
+
+/// Response to [`InternalTestAdvanceTime`].
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) struct InternalTestAdvanceTimeResult {
+    /// The current time according to the virtual clock.
+    pub now: Time,
+
+    /// Number of durable object instances whose alarm handler was run.
+    pub alarms_fired: u64,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
-pub(crate) struct InternalTestAddTask {
-    pub task_id: String, // base64url
-    pub leader: Url,
-    pub helper: Url,
-    pub vdaf: InternalTestVdaf,
-    pub leader_authentication_token: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub collector_authentication_token: Option<String>,
+pub(crate) struct InternalTestEndpointForTask {
     pub role: super::Role,
-    pub vdaf_verify_key: String, // base64url
-    pub query_type: u8,
-    pub min_batch_size: u64,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub max_batch_size: Option<u64>,
-    pub time_precision: Duration,
-    pub collector_hpke_config: String, // base64url
-    pub task_expiration: Time,
+}
+
+/// Header carrying the ID of the key with which an internal command is authenticated.
+const COMMAND_KEY_ID_HEADER: &str = "X-Daphne-Command-Key-Id";
+
+/// Header carrying the time at which an internal command was issued, in seconds since the UNIX
+/// epoch.
+const COMMAND_TIMESTAMP_HEADER: &str = "X-Daphne-Command-Timestamp";
+
+/// Header carrying the hex-encoded, 16-byte nonce of an internal command.
+const COMMAND_NONCE_HEADER: &str = "X-Daphne-Command-Nonce";
+
+/// Header carrying the hex-encoded HMAC-SHA256 tag of an internal command.
+const COMMAND_SIGNATURE_HEADER: &str = "X-Daphne-Command-Signature";
+
+/// Maximum difference, in seconds, between the time at which an internal command was issued and
+/// the time at which it is received.
+const COMMAND_MAX_CLOCK_SKEW: Time = 300;
+
+/// Reject a request to an internal endpoint (any path under "/internal/", optionally preceded by a
+/// version, e.g., "/v07/internal/"; see [`is_internal_path`]) that is not an authenticated
+/// command. If the request should be rejected, then the return value is the
+/// response to send.
+///
+/// If `DAP_INTERNAL_COMMAND_KEYS` is configured, then the request must carry the following
+/// headers:
+///
+/// - `X-Daphne-Command-Key-Id`: the ID of one of the configured keys;
+/// - `X-Daphne-Command-Timestamp`: the current time, in seconds since the UNIX epoch;
+/// - `X-Daphne-Command-Nonce`: a fresh, hex-encoded, 16-byte random string; and
+/// - `X-Daphne-Command-Signature`: the hex-encoded HMAC-SHA256 tag, computed with the key, of
+///   the message encoded by [`command_message`].
+///
+/// A command is rejected if its timestamp is more than [`COMMAND_MAX_CLOCK_SKEW`] seconds away
+/// from the current time or if its nonce was already used.
+///
+/// If no keys are configured, then internal commands are not authenticated. In this case, if the
+/// internal test endpoints are enabled in a production deployment, then every internal command is
+/// rejected.
+pub(crate) async fn reject_unauthenticated_command(
+    state: &DaphneWorkerRequestState<'_>,
+    env: &Env,
+    req: &Request,
+    enable_internal_test: bool,
+) -> Result<Option<Response>> {
+    let url = req.url()?;
+    if !is_internal_path(url.path()) {
+        return Ok(None);
+    }
+
+    let config = &state.isolate_state.config;
+    let Some(keys) = &config.internal_command_keys else {
+        if enable_internal_test && matches!(config.deployment, DaphneWorkerDeployment::Prod) {
+            warn!("rejected internal command: internal command keys are not configured");
+            return Response::error("Unauthorized", 401).map(Some);
+        }
+        return Ok(None);
+    };
+
+    let headers = req.headers();
+    let Some(envelope) = CommandEnvelope::parse(|name| headers.get(name).ok().flatten()) else {
+        warn!("rejected internal command: missing or malformed authentication headers");
+        return Response::error("Unauthorized", 401).map(Some);
+    };
+
+    let target = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    let body = req.clone()?.bytes().await?;
+    if let Err(reason) = envelope.verify(keys, req.method().as_ref(), &target, &body, runtime_now())
+    {
+        warn!("rejected internal command: {reason}");
+        return Response::error("Unauthorized", 401).map(Some);
+    }
+
+    let claimed: bool = state
+        .handler(env)
+        .durable()
+        .without_retry()
+        .post(
+            BINDING_DAP_COMMAND_NONCE_STORE,
+            DURABLE_COMMAND_NONCE_STORE_CLAIM,
+            durable_name_command_nonce_store(),
+            &CommandNonceClaimRequest {
+                nonce: envelope.nonce,
+                expires: envelope.timestamp.saturating_add(COMMAND_MAX_CLOCK_SKEW),
+            },
+        )
+        .await?;
+    if !claimed {
+        warn!("rejected internal command: replayed nonce");
+        return Response::error("Unauthorized", 401).map(Some);
+    }
+
+    Ok(None)
+}
+
+/// The authentication envelope of an internal command, carried in the request headers.
+struct CommandEnvelope {
+    key_id: String,
+    timestamp: Time,
+    nonce: [u8; 16],
+    signature: Vec<u8>,
+}
+
+impl CommandEnvelope {
+    /// Parse the envelope from the headers looked up by `header`. The return value is `None` if a
+    /// header is missing or malformed.
+    fn parse(header: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        Some(Self {
+            key_id: header(COMMAND_KEY_ID_HEADER)?,
+            timestamp: header(COMMAND_TIMESTAMP_HEADER)?.parse().ok()?,
+            nonce: hex::decode(header(COMMAND_NONCE_HEADER)?)
+                .ok()?
+                .try_into()
+                .ok()?,
+            signature: hex::decode(header(COMMAND_SIGNATURE_HEADER)?).ok()?,
+        })
+    }
+
+    /// Check that the command is fresh and that its signature is valid for the request. If not,
+    /// then the error describes the problem.
+    fn verify(
+        &self,
+        keys: &HashMap<String, Vec<u8>>,
+        method: &str,
+        target: &str,
+        body: &[u8],
+        now: Time,
+    ) -> std::result::Result<(), &'static str> {
+        let key = keys.get(&self.key_id).ok_or("unrecognized key ID")?;
+        if self.timestamp.abs_diff(now) > COMMAND_MAX_CLOCK_SKEW {
+            return Err("timestamp is out of range");
+        }
+        let message = command_message(method, target, self.timestamp, &self.nonce, body);
+        hmac::verify(
+            &hmac::Key::new(hmac::HMAC_SHA256, key),
+            &message,
+            &self.signature,
+        )
+        .map_err(|_| "invalid signature")
+    }
+}
+
+/// Encode the message authenticated by an internal command: the request method, the path and
+/// query of the request URL, the timestamp (in decimal), and the hex-encoded nonce, each followed
+/// by a newline, and then the request body.
+fn command_message(
+    method: &str,
+    target: &str,
+    timestamp: Time,
+    nonce: &[u8; 16],
+    body: &[u8],
+) -> Vec<u8> {
+    let mut message =
+        format!("{method}\n{target}\n{timestamp}\n{}\n", hex::encode(nonce)).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use ring::hmac;
+
+    use super::{command_message, is_internal_path, CommandEnvelope};
+
+    fn envelope(key: &[u8], timestamp: u64, body: &[u8]) -> CommandEnvelope {
+        let nonce = [7; 16];
+        let message = command_message("POST", "/internal/delete_all", timestamp, &nonce, body);
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), &message);
+        CommandEnvelope::parse(|name| match name {
+            "X-Daphne-Command-Key-Id" => Some("k1".into()),
+            "X-Daphne-Command-Timestamp" => Some(timestamp.to_string()),
+            "X-Daphne-Command-Nonce" => Some(hex::encode(nonce)),
+            "X-Daphne-Command-Signature" => Some(hex::encode(tag)),
+            _ => None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_command() {
+        let keys = HashMap::from([("k1".to_string(), vec![1; 32])]);
+        let now = 1_700_000_000;
+
+        let cmd = envelope(&[1; 32], now - 10, b"body");
+        assert_eq!(
+            cmd.verify(&keys, "POST", "/internal/delete_all", b"body", now),
+            Ok(())
+        );
+
+        // Tampered request.
+        assert!(cmd
+            .verify(&keys, "POST", "/internal/delete_all", b"other", now)
+            .is_err());
+        assert!(cmd
+            .verify(&keys, "POST", "/internal/process", b"body", now)
+            .is_err());
+
+        // Stale command.
+        assert!(cmd
+            .verify(&keys, "POST", "/internal/delete_all", b"body", now + 3600)
+            .is_err());
+
+        // Wrong key.
+        let cmd = envelope(&[2; 32], now, b"body");
+        assert!(cmd
+            .verify(&keys, "POST", "/internal/delete_all", b"body", now)
+            .is_err());
+        assert!(cmd
+            .verify(
+                &HashMap::new(),
+                "POST",
+                "/internal/delete_all",
+                b"body",
+                now
+            )
+            .is_err());
+    }
+
+    // Test that a command to a versioned internal path is authenticated and that a request without
+    // an envelope is rejected.
+    #[test]
+    fn versioned_internal_path_requires_envelope() {
+        assert!(is_internal_path("/v07/internal/test/add_task"));
+        assert!(CommandEnvelope::parse(|_name| None).is_none());
+    }
+
+    #[test]
+    fn parse_command_rejects_malformed_nonce() {
+        assert!(CommandEnvelope::parse(|name| match name {
+            "X-Daphne-Command-Nonce" => Some("0102".into()),
+            _ => Some("1".into()),
+        })
+        .is_none());
+    }
 }
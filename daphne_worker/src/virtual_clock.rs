@@ -0,0 +1,98 @@
+// Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Virtual time for tests. The clock of the Workers runtime can't be controlled by a test harness,
+//! so tests that exercise time-dependent behavior of durable objects (e.g., garbage collection or
+//! closing batches that reached their time limit) would have to sleep until alarms go off.
+//!
+//! Instead, [`now()`](crate::now) runs ahead of the runtime's clock by an offset that the test
+//! harness can move forward with the "/internal/advance_time" test route. The offset is passed to
+//! durable objects with each request, and in the "dev" deployment the deadline of each alarm is
+//! also recorded in storage. After moving the clock forward, the route asks each durable object to
+//! run its alarm handler if its deadline has passed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use worker::{Headers, Result};
+
+/// Header with which the offset of the virtual clock is passed to durable objects.
+pub(crate) const VIRTUAL_CLOCK_OFFSET_HEADER: &str = "x-daphne-virtual-clock-offset";
+
+/// Number of seconds by which the virtual clock of this isolate runs ahead of the runtime's clock.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Return the offset of the virtual clock.
+pub(crate) fn offset() -> u64 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+/// Move the virtual clock forward by `secs` seconds. Returns the new offset.
+pub(crate) fn advance(secs: u64) -> u64 {
+    let prev = OFFSET
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |offset| {
+            Some(offset.saturating_add(secs))
+        })
+        .unwrap();
+    prev.saturating_add(secs)
+}
+
+/// Set the virtual clock back to the runtime's clock.
+pub(crate) fn reset() {
+    OFFSET.store(0, Ordering::Relaxed);
+}
+
+/// Pass the offset of the virtual clock along with a request to a durable object.
+pub(crate) fn set_header(headers: &mut Headers) -> Result<()> {
+    headers.set(VIRTUAL_CLOCK_OFFSET_HEADER, &offset().to_string())
+}
+
+/// Adopt the virtual clock of the sender of a request, if it passed one along.
+pub(crate) fn observe_header(headers: &Headers) -> Result<()> {
+    if let Some(offset) = headers
+        .get(VIRTUAL_CLOCK_OFFSET_HEADER)?
+        .and_then(|offset| offset.parse::<u64>().ok())
+    {
+        observe(offset);
+    }
+    Ok(())
+}
+
+/// Move the virtual clock forward to `offset`, unless it is already ahead. A request sent before
+/// the clock was last moved forward carries a stale offset, which must not move it back.
+fn observe(offset: u64) {
+    OFFSET.fetch_max(offset, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{advance, observe, offset, reset};
+    use std::sync::{Mutex, PoisonError};
+
+    // The virtual clock is global to the process, so tests that move it must not run concurrently.
+    static CLOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn advance_and_reset() {
+        let _guard = CLOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        reset();
+        assert_eq!(offset(), 0);
+        assert_eq!(advance(3600), 3600);
+        assert_eq!(advance(1), 3601);
+        assert_eq!(offset(), 3601);
+        assert_eq!(advance(u64::MAX), u64::MAX);
+        reset();
+        assert_eq!(offset(), 0);
+    }
+
+    #[test]
+    fn observe_never_moves_backward() {
+        let _guard = CLOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        reset();
+        observe(3600);
+        assert_eq!(offset(), 3600);
+        observe(60);
+        assert_eq!(offset(), 3600);
+        observe(3601);
+        assert_eq!(offset(), 3601);
+        reset();
+    }
+}
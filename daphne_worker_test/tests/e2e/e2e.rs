@@ -14,7 +14,8 @@ use daphne::{
         Report, ReportId, ReportMetadata, TaskId,
     },
     taskprov::{compute_task_id, TaskprovVersion},
-    DapAggregateResult, DapMeasurement, DapTaskConfig, DapVersion,
+    DapAggregateResult, DapAggregationDryRun, DapCapabilities, DapMeasurement, DapTaskConfig,
+    DapVersion,
 };
 use daphne_worker::DaphneWorkerReportSelector;
 use prio::codec::{ParameterizedDecode, ParameterizedEncode};
@@ -130,6 +131,24 @@ async fn helper_endpoint_for_task_prefixed(version: DapVersion) {
 
 async_test_versions! { helper_endpoint_for_task_prefixed }
 
+// Test that a failed command of the interop test API is reported in the response body.
+async fn leader_add_task_malformed(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let res: InternalTestEndpointForTaskResult = t
+        .leader_post_internal(
+            format!("/{}/internal/test/add_task", version.as_ref()).as_ref(),
+            &json!({
+                "task_id": "blah blah ignored",
+                "role": "leader",
+            }),
+        )
+        .await;
+    assert_eq!(res.status, "error");
+    assert!(res.error.is_some());
+}
+
+async_test_versions! { leader_add_task_malformed }
+
 async fn leader_hpke_config(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
     let client = t.http_client();
@@ -162,6 +181,32 @@ async fn hpke_configs_are_cached(version: DapVersion) {
 
 async_test_versions! { hpke_configs_are_cached }
 
+async fn leader_capabilities(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let url = t.leader_url.join("capabilities").unwrap();
+    let resp = client.get(url.as_str()).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let capabilities: DapCapabilities = resp.json().await.unwrap();
+    assert!(capabilities.versions.contains(&version));
+    assert!(capabilities.query_types.contains(&"fixed_size".to_string()));
+}
+
+async_test_versions! { leader_capabilities }
+
+#[tokio::test]
+async fn leader_rejects_unknown_version() {
+    let t = TestRunner::default_with_version(DapVersion::Draft07).await;
+    let client = t.http_client();
+    let url = t.leader_url.join("/v99/hpke_config").unwrap();
+    let resp = client.get(url.as_str()).send().await.unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(
+        resp.headers().get("Content-Type").unwrap(),
+        "application/problem+json"
+    );
+}
+
 async fn leader_upload(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
     let mut rng = thread_rng();
@@ -324,6 +369,7 @@ async fn leader_upload(version: DapVersion) {
                         payload: b"ciphertext".to_vec(),
                     },
                 ],
+                annotation: None,
             }
             .get_encoded_with_param(&version),
         )
@@ -341,6 +387,42 @@ async fn leader_upload(version: DapVersion) {
 
 async_test_versions! { leader_upload }
 
+#[tokio::test]
+async fn leader_upload_with_report_id_in_path() {
+    let version = DapVersion::Draft07;
+    let t = TestRunner::default_with_version(version).await;
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+
+    let report = t
+        .task_config
+        .vdaf
+        .produce_report(
+            &hpke_config_list,
+            t.now,
+            &t.task_id,
+            DapMeasurement::U64(23),
+            version,
+        )
+        .unwrap();
+    let path = format!(
+        "{}/{}",
+        t.upload_path(),
+        report.report_metadata.id.to_base64url()
+    );
+
+    // Uploading the same report twice is idempotent.
+    for _ in 0..2 {
+        t.leader_put_expect_ok(
+            &client,
+            &path,
+            DapMediaType::Report,
+            report.get_encoded_with_param(&version),
+        )
+        .await;
+    }
+}
+
 #[tokio::test]
 #[cfg_attr(not(feature = "test_e2e"), ignore)]
 async fn leader_upload_taskprov() {
@@ -533,6 +615,7 @@ async fn internal_leader_process(version: DapVersion) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
         max_reports: t.task_config.min_batch_size,
+        max_agg_jobs_per_task_per_fetch: None,
     };
 
     let batch_interval = t.batch_interval();
@@ -582,6 +665,121 @@ async fn internal_leader_process(version: DapVersion) {
 
 async_test_versions! { internal_leader_process }
 
+async fn internal_leader_advance_time(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let path = t.upload_path();
+
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
+        max_reports: t.task_config.min_batch_size,
+        max_agg_jobs_per_task_per_fetch: None,
+    };
+
+    // Upload and aggregate some reports. Marking the reports as aggregated sets an alarm that
+    // deletes them from replay protection storage once the storage epoch has passed.
+    for _ in 0..report_sel.max_reports {
+        t.leader_put_expect_ok(
+            &client,
+            &path,
+            DapMediaType::Report,
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    t.now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap()
+                .get_encoded_with_param(&version),
+        )
+        .await;
+    }
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(
+        agg_telem.reports_aggregated, report_sel.max_reports,
+        "reports aggregated"
+    );
+
+    // No alarm is due yet.
+    let res = t.internal_advance_time(true /* is_leader */, 1).await;
+    assert!(res.now > t.now);
+    assert_eq!(res.alarms_fired, 0);
+
+    // Move past the end of the storage epoch (plus the alarm safety interval configured in
+    // wrangler.toml) without waiting for it.
+    let res = t
+        .internal_advance_time(
+            true, /* is_leader */
+            t.global_config.report_storage_epoch_duration + 300,
+        )
+        .await;
+    assert!(res.now > t.now + t.global_config.report_storage_epoch_duration);
+    assert!(res.alarms_fired > 0);
+
+    // The alarms have gone off and have not been rescheduled.
+    let res = t.internal_advance_time(true /* is_leader */, 0).await;
+    assert_eq!(res.alarms_fired, 0);
+}
+
+async_test_versions! { internal_leader_advance_time }
+
+async fn internal_leader_dry_run(version: DapVersion) {
+    let t = TestRunner::default_with_version(version).await;
+    let path = t.upload_path();
+
+    let client = t.http_client();
+    let hpke_config_list = t.get_hpke_configs(version, &client).await;
+
+    let batch_interval = t.batch_interval();
+    let mut rng = thread_rng();
+    for _ in 0..5 {
+        let now = rng.gen_range(t.report_interval(&batch_interval));
+        t.leader_put_expect_ok(
+            &client,
+            &path,
+            DapMediaType::Report,
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap()
+                .get_encoded_with_param(&version),
+        )
+        .await;
+    }
+
+    let dry_run: DapAggregationDryRun = t
+        .leader_post_internal(
+            &format!("internal/dry_run/task/{}", t.task_id.to_base64url()),
+            &json!({ "max_reports": 100 }),
+        )
+        .await;
+    assert_eq!(dry_run.reports, 5, "reports in sample");
+    assert_eq!(dry_run.accepted, 5, "reports accepted");
+    assert!(dry_run.rejected.is_empty(), "reports rejected");
+
+    // The dry run leaves the reports pending.
+    let report_sel = DaphneWorkerReportSelector {
+        max_agg_jobs: 100,
+        max_reports: 100,
+        max_agg_jobs_per_task_per_fetch: None,
+    };
+    let agg_telem = t.internal_process(&client, &report_sel).await;
+    assert_eq!(agg_telem.reports_aggregated, 5, "reports aggregated");
+}
+
+async_test_versions! { internal_leader_dry_run }
+
 // Test that all reports eventually get drained at minimum aggregation rate.
 async fn leader_process_min_agg_rate(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
@@ -617,6 +815,7 @@ async fn leader_process_min_agg_rate(version: DapVersion) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 1,
         max_reports: 1,
+        max_agg_jobs_per_task_per_fetch: None,
     };
 
     for i in 0..7 {
@@ -690,6 +889,7 @@ async fn leader_collect_ok(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                max_agg_jobs_per_task_per_fetch: None,
             },
         )
         .await;
@@ -809,6 +1009,7 @@ async fn leader_collect_ok_interleaved(version: DapVersion) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
         max_reports: 100,
+        max_agg_jobs_per_task_per_fetch: None,
     };
 
     // All reports for the task get processed ...
@@ -890,6 +1091,7 @@ async fn leader_collect_not_ready_min_batch_size(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                max_agg_jobs_per_task_per_fetch: None,
             },
         )
         .await;
@@ -1084,6 +1286,7 @@ async fn leader_collect_abort_overlapping_batch_interval(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                max_agg_jobs_per_task_per_fetch: None,
             },
         )
         .await;
@@ -1155,6 +1358,7 @@ async fn fixed_size(version: DapVersion, use_current: bool) {
     let report_sel = DaphneWorkerReportSelector {
         max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
         max_reports: 100,
+        max_agg_jobs_per_task_per_fetch: None,
     };
 
     let client = t.http_client();
@@ -1297,9 +1501,10 @@ async fn fixed_size(version: DapVersion, use_current: bool) {
     let batch_id = t.internal_current_batch(&t.task_id).await;
     assert_ne!(batch_id, prev_batch_id);
 
-    // Collector: Try CollectReq with out-dated batch ID.
+    // Collector: Try CollectReq with out-dated batch ID. The batch was already collected, which
+    // is signaled by an abort that is not part of DAP.
     if t.version == DapVersion::Draft02 {
-        t.leader_post_expect_abort(
+        t.leader_post_expect_problem(
             &client,
             Some(&t.collector_bearer_token),
             &t.collect_url_suffix(),
@@ -1313,11 +1518,11 @@ async fn fixed_size(version: DapVersion, use_current: bool) {
             }
             .get_encoded_with_param(&t.version),
             400,
-            "batchOverlap",
+            None,
         )
         .await;
     } else {
-        t.leader_put_expect_abort(
+        t.leader_put_expect_problem(
             &client,
             Some(&t.collector_bearer_token),
             &t.collect_url_suffix(),
@@ -1331,7 +1536,7 @@ async fn fixed_size(version: DapVersion, use_current: bool) {
             }
             .get_encoded_with_param(&t.version),
             400,
-            "batchOverlap",
+            None,
         )
         .await;
     }
@@ -1446,6 +1651,7 @@ async fn leader_collect_taskprov_ok(version: DapVersion) {
             &DaphneWorkerReportSelector {
                 max_agg_jobs: 100, // Needs to be sufficiently large to touch each bucket.
                 max_reports: 100,
+                max_agg_jobs_per_task_per_fetch: None,
             },
         )
         .await;
@@ -9,6 +9,7 @@ use daphne::{
     hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId, HpkeReceiverConfig},
     messages::{
         encode_base64url, BatchId, CollectionJobId, Duration, HpkeConfigList, Interval, TaskId,
+        Time,
     },
     taskprov::TaskprovVersion,
     DapGlobalConfig, DapLeaderProcessTelemetry, DapQueryConfig, DapTaskConfig, DapVersion,
@@ -36,6 +37,12 @@ struct InternalTestCommandResult {
     error: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct InternalTestAdvanceTimeResult {
+    pub now: Time,
+    pub alarms_fired: u64,
+}
+
 pub struct TestRunner {
     pub global_config: DapGlobalConfig,
     pub task_id: TaskId,
@@ -61,6 +68,7 @@ impl TestRunner {
             version,
             &DapQueryConfig::FixedSize {
                 max_batch_size: MAX_BATCH_SIZE,
+                batch_time_limit: None,
             },
         )
         .await
@@ -109,6 +117,17 @@ impl TestRunner {
             vdaf_verify_key: VDAF_CONFIG.gen_verify_key(),
             collector_hpke_config: collector_hpke_receiver.config.clone(),
             taskprov: false,
+            batch_policy: Default::default(),
+            upload_receipts: false,
+            unknown_extensions: Default::default(),
+            report_ttl: None,
+            collect_priority: Default::default(),
+            client_auth_key: None,
+            vdaf_verify_key_set: None,
+            alt_versions: Vec::new(),
+            report_storage_epoch_duration: None,
+            report_storage_epoch_migration: None,
+            sub_bucket_precision: None,
         };
 
         // This block needs to be kept in-sync with daphne_worker_test/wrangler.toml.
@@ -120,6 +139,16 @@ impl TestRunner {
             max_batch_interval_end: 259200,
             supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
             taskprov_version: Some(TaskprovVersion::Draft02),
+            agg_job_init_req_compression_threshold: None,
+            helper_request_retry: Default::default(),
+            upload_receipt_signing_key: None,
+            agg_job_min_acceptance_ratio: None,
+            enabled_versions: None,
+            reject_unquantized_report_times: false,
+            max_prio2_dimension: None,
+            taskprov_policy: Default::default(),
+            upload_overload_retry_after: None,
+            agg_job_memory_budget: None,
         };
         let taskprov_vdaf_verify_key_init =
             hex::decode("b029a72fa327931a5cb643dcadcaafa098fcbfac07d990cb9e7c9a8675fafb18")
@@ -169,7 +198,7 @@ impl TestRunner {
 
         let (query_type, max_batch_size) = match t.task_config.query {
             DapQueryConfig::TimeInterval => (1, None),
-            DapQueryConfig::FixedSize { max_batch_size } => (2, Some(max_batch_size)),
+            DapQueryConfig::FixedSize { max_batch_size, .. } => (2, Some(max_batch_size)),
         };
 
         // Configure the endpoints.
@@ -364,6 +393,31 @@ impl TestRunner {
         data: Vec<u8>,
         expected_status: u16,
         expected_err_type: &str,
+    ) {
+        self.leader_post_expect_problem(
+            client,
+            dap_auth_token,
+            path,
+            media_type,
+            data,
+            expected_status,
+            Some(expected_err_type),
+        )
+        .await
+    }
+
+    /// Like `leader_post_expect_abort()`, except that the expected problem type may be `None`, as
+    /// is the case for aborts that are not part of DAP.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn leader_post_expect_problem(
+        &self,
+        client: &reqwest::Client,
+        dap_auth_token: Option<&str>,
+        path: &str,
+        media_type: DapMediaType,
+        data: Vec<u8>,
+        expected_status: u16,
+        expected_err_type: Option<&str>,
     ) {
         let url = self.leader_url.join(path).unwrap();
 
@@ -404,10 +458,16 @@ impl TestRunner {
         );
 
         let problem_details: serde_json::Value = resp.json().await.unwrap();
-        let got = problem_details.as_object().unwrap().get("type").unwrap();
+        let got = problem_details
+            .as_object()
+            .unwrap()
+            .get("type")
+            .map(|typ| typ.as_str().unwrap());
         assert_eq!(
             got,
-            &format!("urn:ietf:params:ppm:dap:error:{}", expected_err_type)
+            expected_err_type
+                .map(|typ| format!("urn:ietf:params:ppm:dap:error:{typ}"))
+                .as_deref()
         );
     }
 
@@ -463,11 +523,36 @@ impl TestRunner {
         data: Vec<u8>,
         expected_status: u16,
         expected_err_type: &str,
+    ) {
+        self.leader_put_expect_problem(
+            client,
+            dap_auth_token,
+            path,
+            media_type,
+            data,
+            expected_status,
+            Some(expected_err_type),
+        )
+        .await
+    }
+
+    /// Like `leader_put_expect_abort()`, except that the expected problem type may be `None`, as
+    /// is the case for aborts that are not part of DAP.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn leader_put_expect_problem(
+        &self,
+        client: &reqwest::Client,
+        dap_auth_token: Option<&str>,
+        path: &str,
+        media_type: DapMediaType,
+        data: Vec<u8>,
+        expected_status: u16,
+        expected_err_type: Option<&str>,
     ) {
         // draft02 always POSTs
         if self.version == DapVersion::Draft02 {
             return self
-                .leader_post_expect_abort(
+                .leader_post_expect_problem(
                     client,
                     dap_auth_token,
                     path,
@@ -518,10 +603,16 @@ impl TestRunner {
         );
 
         let problem_details: serde_json::Value = resp.json().await.unwrap();
-        let got = problem_details.as_object().unwrap().get("type").unwrap();
+        let got = problem_details
+            .as_object()
+            .unwrap()
+            .get("type")
+            .map(|typ| typ.as_str().unwrap());
         assert_eq!(
             got,
-            &format!("urn:ietf:params:ppm:dap:error:{}", expected_err_type)
+            expected_err_type
+                .map(|typ| format!("urn:ietf:params:ppm:dap:error:{typ}"))
+                .as_deref()
         );
     }
 
@@ -655,6 +746,17 @@ impl TestRunner {
         post_internal_delete_all(&client, &self.helper_url, batch_interval).await;
     }
 
+    /// Move the virtual clock of the Leader (if `is_leader` is set) or the Helper forward by `secs`
+    /// seconds and run the durable object alarms that are then due.
+    pub async fn internal_advance_time(
+        &self,
+        is_leader: bool,
+        secs: u64,
+    ) -> InternalTestAdvanceTimeResult {
+        self.post_internal(is_leader, "internal/advance_time", &json!({ "secs": secs }))
+            .await
+    }
+
     pub async fn internal_current_batch(&self, task_id: &TaskId) -> BatchId {
         let client = self.http_client();
         let mut url = self.leader_url.clone();